@@ -87,6 +87,4 @@ irq_core:
   pop eax
   add esp, 4 # clear the irq number
 
-  # need to ack interrupt via port 0x20
-
   iretd
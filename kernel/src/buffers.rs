@@ -81,6 +81,40 @@ impl<'data> RingBuffer<'data> {
     tail - head
   }
 
+  /**
+   * Fetch the number of bytes that can be written before the buffer is full.
+   */
+  pub fn available_room(&self) -> usize {
+    self.data.len() - self.available_bytes()
+  }
+
+  /**
+   * Check whether a specific byte is waiting to be read, without consuming
+   * anything from the buffer.
+   */
+  pub fn contains(&self, value: u8) -> bool {
+    let len = self.data.len();
+    let tail = self.tail.load(Ordering::SeqCst);
+    let head = self.head.load(Ordering::SeqCst);
+    (head..tail).any(|i| self.data[i % len] == value)
+  }
+
+  /**
+   * Fetch the total number of bytes ever read from the buffer. Unlike an index
+   * into the data, this identifies a position in the stream that doesn't move
+   * as the buffer wraps around.
+   */
+  pub fn total_read(&self) -> usize {
+    self.head.load(Ordering::SeqCst)
+  }
+
+  /**
+   * Fetch the total number of bytes ever written to the buffer.
+   */
+  pub fn total_written(&self) -> usize {
+    self.tail.load(Ordering::SeqCst)
+  }
+
   /**
    * Empty all data from the buffer by moving the head up to meet the tail.
    */
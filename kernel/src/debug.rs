@@ -3,6 +3,7 @@ use crate::{devices, interrupts};
 
 #[cfg(not(feature = "testing"))]
 pub fn _kprint(args: fmt::Arguments) {
+  devices::kmsg::log_args(args);
   /*
   let int_reenable = interrupts::control::is_interrupt_enabled();
   interrupts::control::cli();
@@ -21,26 +22,60 @@ pub fn _kprint(args: fmt::Arguments) {
 
 #[cfg(feature = "testing")]
 pub fn _kprint(args: fmt::Arguments) {
+  devices::kmsg::log_args(args);
   unsafe {
     let serial = devices::get_raw_serial();
     serial.write_fmt(args).unwrap();
   }
 }
 
+/// Check the log filter for a message tagged like `Debug, Memory;`
+#[macro_export]
+macro_rules! log_enabled {
+  ($level:ident, $category:ident) => (
+    $crate::devices::kmsg::is_enabled(
+      $crate::devices::kmsg::LogLevel::$level,
+      $crate::devices::kmsg::LogCategory::$category,
+    )
+  );
+}
+
+/// Print to the serial port. Untagged messages are always printed; tagged
+/// ones, like `kprint!(Debug, Memory; "...")`, only if the filter allows them.
 #[macro_export]
 macro_rules! kprint {
+  ($level:ident, $category:ident; $($arg:tt)*) => (
+    if $crate::log_enabled!($level, $category) {
+      $crate::debug::_kprint(format_args!($($arg)*));
+    }
+  );
   ($($arg:tt)*) => ($crate::debug::_kprint(format_args!($($arg)*)));
 }
 
 #[macro_export]
 macro_rules! kprintln {
   () => ($crate::kprint!("\n"));
+  ($level:ident, $category:ident; $($arg:tt)*) => (
+    $crate::kprint!($level, $category; "{}\n", format_args!($($arg)*))
+  );
   ($($arg:tt)*) => ($crate::kprint!("{}\n", format_args!($($arg)*)));
 }
 
+/// Print to the console. Untagged messages are logged at the Info level.
 #[macro_export]
 macro_rules! klog {
-  ($($arg:tt)*) => ($crate::vterm::console_write(format_args!($($arg)*)));
+  ($level:ident, $category:ident; $($arg:tt)*) => (
+    if $crate::log_enabled!($level, $category) {
+      $crate::debug::_klog(format_args!($($arg)*));
+    }
+  );
+  ($($arg:tt)*) => ($crate::klog!(Info, General; $($arg)*));
+}
+
+/// Write a message to the console, keeping a copy in the kernel log
+pub fn _klog(args: fmt::Arguments) {
+  devices::kmsg::log_args(args);
+  crate::vterm::console_write(args);
 }
 
 pub fn log_dos_syscall(method: u8) {
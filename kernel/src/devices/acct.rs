@@ -0,0 +1,51 @@
+//! DEV:\ACCT exposes the process accounting log, with one line per process
+//! that has exited. Like DEV:\KMSG, each handle keeps its own position, and
+//! new readers start at the oldest record still held. The log is written only
+//! by the kernel.
+
+use crate::collections::SlotList;
+use crate::task::accounting::ACCOUNTING_LOG;
+use crate::locks::RwLock;
+use super::driver::{DeviceDriver, IOHandle};
+
+pub struct AcctDriver {
+  cursors: RwLock<SlotList<usize>>,
+}
+
+impl AcctDriver {
+  pub const fn new() -> Self {
+    Self {
+      cursors: RwLock::new(SlotList::new()),
+    }
+  }
+}
+
+impl DeviceDriver for AcctDriver {
+  fn open(&self) -> Result<IOHandle, ()> {
+    let start = ACCOUNTING_LOG.read().oldest();
+    let index = self.cursors.write().insert(start);
+    Ok(IOHandle::new(index))
+  }
+
+  fn close(&self, index: IOHandle) -> Result<(), ()> {
+    self.cursors.write().remove(index.as_usize()).map(|_| ()).ok_or(())
+  }
+
+  fn read(&self, index: IOHandle, buffer: &mut [u8]) -> Result<usize, ()> {
+    let mut cursors = self.cursors.write();
+    let cursor = cursors.get_mut(index.as_usize()).ok_or(())?;
+    let (bytes_read, next) = ACCOUNTING_LOG.read().read_from(*cursor, buffer);
+    *cursor = next;
+    Ok(bytes_read)
+  }
+
+  fn write(&self, _index: IOHandle, _buffer: &[u8]) -> Result<usize, ()> {
+    Err(())
+  }
+
+  fn reopen(&self, index: IOHandle, _id: crate::task::id::ProcessID) -> Result<IOHandle, ()> {
+    let mut cursors = self.cursors.write();
+    let cursor = *cursors.get(index.as_usize()).ok_or(())?;
+    Ok(IOHandle::new(cursors.insert(cursor)))
+  }
+}
@@ -0,0 +1,154 @@
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::files::cursor::SeekMethod;
+use alloc::sync::Arc;
+use crate::hardware::ata::{
+  AtaBus, AtaDrive, DriveInfo, DriveSelect, PortBus, Register, PRIMARY_CONTROL, PRIMARY_IO_BASE,
+  SECTOR_SIZE,
+};
+use crate::memory::address::VirtualAddress;
+use crate::task::id::ProcessID;
+use crate::locks::RwLock;
+use super::cache::{register_cache, SectorCache, SectorDevice};
+use super::super::driver::{DeviceDriver, IOHandle};
+
+/// Number of sectors of the hard disk kept in memory
+const CACHED_SECTORS: usize = 64;
+
+/// Acknowledges interrupts from the primary channel. Transfers are polled, so
+/// there is nothing to do beyond reading the status register.
+static PRIMARY_CHANNEL: PortBus = PortBus::new(PRIMARY_IO_BASE, PRIMARY_CONTROL);
+
+/// Probe the master drive on the primary IDE channel. If a hard disk is
+/// attached, a driver for it is returned.
+pub fn init() -> Option<AtaDriver> {
+  let drive = AtaDrive::new(PortBus::new(PRIMARY_IO_BASE, PRIMARY_CONTROL), DriveSelect::Master);
+  let info = match drive.identify() {
+    Ok(info) => info,
+    Err(_) => return None,
+  };
+  let install_result = crate::interrupts::handlers::install_handler(
+    14,
+    ProcessID::new(0),
+    VirtualAddress::new(int_ata_primary as *const fn () -> () as usize),
+    VirtualAddress::new(0),
+  );
+  if let Err(_) = install_result {
+    crate::kprintln!("Failed to install IRQ14");
+  }
+  let model = core::str::from_utf8(&info.model).unwrap_or("").trim_end();
+  crate::klog!("Hard disk: \x1b[97m{}\x1b[m, {} sectors\n", model, info.sectors);
+  Some(AtaDriver::new(drive, info))
+}
+
+pub extern "C" fn int_ata_primary() {
+  PRIMARY_CHANNEL.read_register(Register::StatusCommand);
+  crate::interrupts::handlers::return_from_handler(14);
+}
+
+impl<B: AtaBus> SectorDevice for AtaDrive<B> {
+  fn read_sector(&self, lba: u32, buffer: &mut [u8]) -> Result<(), ()> {
+    AtaDrive::read_sector(self, lba, buffer).map_err(|_| ())
+  }
+
+  fn write_sector(&self, lba: u32, buffer: &[u8]) -> Result<(), ()> {
+    AtaDrive::write_sector(self, lba, buffer).map_err(|_| ())
+  }
+}
+
+/// Device driver exposing an ATA hard disk as a byte stream, like the floppy
+/// driver. Reads and writes that don't cover whole sectors are handled by
+/// reading the affected sectors first. Sectors pass through a write-through
+/// cache, so written data has reached the disk by the time a write returns.
+pub struct AtaDriver {
+  drive: Arc<SectorCache<AtaDrive<PortBus>>>,
+  info: DriveInfo,
+  next_handle: AtomicUsize,
+  open_handles: RwLock<BTreeMap<IOHandle, usize>>,
+}
+
+impl AtaDriver {
+  pub fn new(drive: AtaDrive<PortBus>, info: DriveInfo) -> Self {
+    let drive = Arc::new(SectorCache::new(drive, CACHED_SECTORS));
+    register_cache(drive.clone());
+    Self {
+      drive,
+      info,
+      next_handle: AtomicUsize::new(0),
+      open_handles: RwLock::new(BTreeMap::new()),
+    }
+  }
+
+  fn get_cursor(&self, index: IOHandle) -> Result<usize, ()> {
+    self.open_handles.read().get(&index).copied().ok_or(())
+  }
+
+  fn advance_cursor(&self, index: IOHandle, length: usize) -> Result<(), ()> {
+    let mut handles = self.open_handles.write();
+    let cursor = handles.get_mut(&index).ok_or(())?;
+    *cursor += length;
+    Ok(())
+  }
+
+  /// Limit a transfer so that it doesn't run past the end of the disk
+  fn clamp_length(&self, cursor: usize, length: usize) -> usize {
+    length.min(self.info.byte_size().saturating_sub(cursor))
+  }
+}
+
+impl DeviceDriver for AtaDriver {
+  fn open(&self) -> Result<IOHandle, ()> {
+    let handle = IOHandle::new(self.next_handle.fetch_add(1, Ordering::SeqCst));
+    self.open_handles.write().insert(handle, 0);
+    Ok(handle)
+  }
+
+  fn close(&self, index: IOHandle) -> Result<(), ()> {
+    self.open_handles.write().remove(&index).map(|_| ()).ok_or(())
+  }
+
+  fn read(&self, index: IOHandle, buffer: &mut [u8]) -> Result<usize, ()> {
+    let cursor = self.get_cursor(index)?;
+    let length = self.clamp_length(cursor, buffer.len());
+    let mut sector = [0u8; SECTOR_SIZE];
+    let mut copied = 0;
+    while copied < length {
+      let position = cursor + copied;
+      let offset = position % SECTOR_SIZE;
+      let count = (SECTOR_SIZE - offset).min(length - copied);
+      self.drive.read((position / SECTOR_SIZE) as u32, &mut sector)?;
+      buffer[copied..(copied + count)].copy_from_slice(&sector[offset..(offset + count)]);
+      copied += count;
+    }
+    self.advance_cursor(index, copied)?;
+    Ok(copied)
+  }
+
+  fn write(&self, index: IOHandle, buffer: &[u8]) -> Result<usize, ()> {
+    let cursor = self.get_cursor(index)?;
+    let length = self.clamp_length(cursor, buffer.len());
+    let mut sector = [0u8; SECTOR_SIZE];
+    let mut copied = 0;
+    while copied < length {
+      let position = cursor + copied;
+      let lba = (position / SECTOR_SIZE) as u32;
+      let offset = position % SECTOR_SIZE;
+      let count = (SECTOR_SIZE - offset).min(length - copied);
+      if count < SECTOR_SIZE {
+        self.drive.read(lba, &mut sector)?;
+      }
+      sector[offset..(offset + count)].copy_from_slice(&buffer[copied..(copied + count)]);
+      self.drive.write(lba, &sector)?;
+      copied += count;
+    }
+    self.advance_cursor(index, copied)?;
+    Ok(copied)
+  }
+
+  fn seek(&self, index: IOHandle, offset: SeekMethod) -> Result<usize, ()> {
+    let mut handles = self.open_handles.write();
+    let cursor = handles.get_mut(&index).ok_or(())?;
+    *cursor = offset.from_current_position(*cursor);
+    Ok(*cursor)
+  }
+}
@@ -0,0 +1,342 @@
+//! Block devices keep recently used sectors in memory, so that filesystems
+//! reading the same FAT or directory sectors over and over don't have to wait
+//! on the disk each time. Writes go straight through to the disk, and the
+//! cached copy is only updated once the disk has accepted them, so nothing is
+//! lost if a drive is unmounted or the system powers off.
+//! Drivers register their caches when they are created, so that the counters
+//! can be reported and the contents dropped from a single place.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::locks::{Mutex, RwLock};
+use syscall::files::CacheStats;
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// Direct access to the sectors of a disk, underneath the cache
+pub trait SectorDevice {
+  fn read_sector(&self, lba: u32, buffer: &mut [u8]) -> Result<(), ()>;
+
+  fn write_sector(&self, lba: u32, buffer: &[u8]) -> Result<(), ()>;
+
+  /// Read consecutive sectors into a buffer holding a whole number of them.
+  /// Devices that can transfer several sectors at once override this.
+  fn read_sectors(&self, lba: u32, buffer: &mut [u8]) -> Result<(), ()> {
+    for (index, sector) in buffer.chunks_mut(SECTOR_SIZE).enumerate() {
+      self.read_sector(lba + index as u32, sector)?;
+    }
+    Ok(())
+  }
+}
+
+struct CacheEntry {
+  data: Box<[u8; SECTOR_SIZE]>,
+  last_used: u32,
+}
+
+struct CacheState {
+  entries: BTreeMap<u32, CacheEntry>,
+  /// Incremented on every access, to find the least recently used entry
+  clock: u32,
+  stats: CacheStats,
+}
+
+/// An LRU write-through cache in front of a sector device. The device is only
+/// accessed while the cache lock is held, so the lock also serializes IO.
+pub struct SectorCache<D: SectorDevice> {
+  device: D,
+  capacity: usize,
+  state: Mutex<CacheState>,
+}
+
+impl<D: SectorDevice> SectorCache<D> {
+  pub fn new(device: D, capacity: usize) -> Self {
+    Self {
+      device,
+      capacity: capacity.max(1),
+      state: Mutex::new(CacheState {
+        entries: BTreeMap::new(),
+        clock: 0,
+        stats: CacheStats::empty(),
+      }),
+    }
+  }
+
+  pub fn get_device(&self) -> &D {
+    &self.device
+  }
+
+  /// Copy a whole sector into the buffer, from memory if possible
+  pub fn read(&self, lba: u32, buffer: &mut [u8]) -> Result<(), ()> {
+    self.read_sectors(lba, &mut buffer[..SECTOR_SIZE])
+  }
+
+  /// Copy consecutive whole sectors into the buffer. Each run of sectors
+  /// that isn't cached is fetched from the device in a single request.
+  pub fn read_sectors(&self, lba: u32, buffer: &mut [u8]) -> Result<(), ()> {
+    let count = buffer.len() / SECTOR_SIZE;
+    let mut guard = self.state.lock();
+    let state = &mut *guard;
+    let mut index = 0;
+    while index < count {
+      state.clock = state.clock.wrapping_add(1);
+      let now = state.clock;
+      let start = index * SECTOR_SIZE;
+      if let Some(entry) = state.entries.get_mut(&(lba + index as u32)) {
+        entry.last_used = now;
+        buffer[start..(start + SECTOR_SIZE)].copy_from_slice(&entry.data[..]);
+        state.stats.hits = state.stats.hits.wrapping_add(1);
+        index += 1;
+        continue;
+      }
+      let mut end = index + 1;
+      while end < count && !state.entries.contains_key(&(lba + end as u32)) {
+        end += 1;
+      }
+      self.device.read_sectors(lba + index as u32, &mut buffer[start..(end * SECTOR_SIZE)])?;
+      for missed in index..end {
+        let mut data = Box::new([0; SECTOR_SIZE]);
+        data.copy_from_slice(&buffer[(missed * SECTOR_SIZE)..((missed + 1) * SECTOR_SIZE)]);
+        state.stats.misses = state.stats.misses.wrapping_add(1);
+        self.insert(state, lba + missed as u32, CacheEntry { data, last_used: now });
+      }
+      index = end;
+    }
+    Ok(())
+  }
+
+  /// Replace a whole sector on the disk. If the disk rejects the write, the
+  /// cache keeps whatever it held before.
+  pub fn write(&self, lba: u32, buffer: &[u8]) -> Result<(), ()> {
+    let mut guard = self.state.lock();
+    let state = &mut *guard;
+    self.device.write_sector(lba, &buffer[..SECTOR_SIZE])?;
+    state.stats.write_backs = state.stats.write_backs.wrapping_add(1);
+    state.clock = state.clock.wrapping_add(1);
+    let now = state.clock;
+    if let Some(entry) = state.entries.get_mut(&lba) {
+      entry.data.copy_from_slice(&buffer[..SECTOR_SIZE]);
+      entry.last_used = now;
+      state.stats.hits = state.stats.hits.wrapping_add(1);
+      return Ok(());
+    }
+    state.stats.misses = state.stats.misses.wrapping_add(1);
+    let mut data = Box::new([0; SECTOR_SIZE]);
+    data.copy_from_slice(&buffer[..SECTOR_SIZE]);
+    self.insert(state, lba, CacheEntry { data, last_used: now });
+    Ok(())
+  }
+
+  /// Forget every cached sector, so that the next reads come from the disk
+  pub fn clear(&self) {
+    self.state.lock().entries.clear();
+  }
+
+  pub fn get_stats(&self) -> CacheStats {
+    self.state.lock().stats
+  }
+
+  pub fn reset_stats(&self) {
+    self.state.lock().stats = CacheStats::empty();
+  }
+
+  /// Add an entry, evicting the least recently used sector if the cache is
+  /// full. Every cached sector matches the disk, so eviction never writes.
+  fn insert(&self, state: &mut CacheState, lba: u32, entry: CacheEntry) {
+    if state.entries.len() >= self.capacity {
+      let oldest = state.entries
+        .iter()
+        .min_by_key(|(_, entry)| entry.last_used)
+        .map(|(lba, _)| *lba);
+      if let Some(oldest_lba) = oldest {
+        state.entries.remove(&oldest_lba);
+        state.stats.evictions = state.stats.evictions.wrapping_add(1);
+      }
+    }
+    state.entries.insert(lba, entry);
+  }
+}
+
+/// The operations needed to report on and drop a cache, without knowing what
+/// kind of device it belongs to
+pub trait BlockCache: Send + Sync {
+  fn drop_cache(&self);
+
+  fn get_stats(&self) -> CacheStats;
+
+  fn reset_stats(&self);
+}
+
+impl<D: SectorDevice + Send + Sync> BlockCache for SectorCache<D> {
+  fn drop_cache(&self) {
+    self.clear()
+  }
+
+  fn get_stats(&self) -> CacheStats {
+    SectorCache::get_stats(self)
+  }
+
+  fn reset_stats(&self) {
+    SectorCache::reset_stats(self)
+  }
+}
+
+static CACHES: RwLock<Vec<Arc<dyn BlockCache>>> = RwLock::new(Vec::new());
+
+pub fn register_cache(cache: Arc<dyn BlockCache>) {
+  CACHES.write().push(cache);
+}
+
+/// Empty every registered cache
+pub fn drop_caches() {
+  for cache in CACHES.read().iter() {
+    cache.drop_cache();
+  }
+}
+
+/// Sum the counters of every registered cache
+pub fn get_stats() -> CacheStats {
+  let mut total = CacheStats::empty();
+  for cache in CACHES.read().iter() {
+    let stats = cache.get_stats();
+    total.hits = total.hits.wrapping_add(stats.hits);
+    total.misses = total.misses.wrapping_add(stats.misses);
+    total.evictions = total.evictions.wrapping_add(stats.evictions);
+    total.write_backs = total.write_backs.wrapping_add(stats.write_backs);
+  }
+  total
+}
+
+pub fn reset_stats() {
+  for cache in CACHES.read().iter() {
+    cache.reset_stats();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::sync::Arc;
+  use alloc::vec;
+  use alloc::vec::Vec;
+  use crate::locks::Mutex;
+  use super::{SectorCache, SectorDevice, SECTOR_SIZE};
+
+  struct MemoryDisk {
+    sectors: Mutex<Vec<[u8; SECTOR_SIZE]>>,
+    reads: Mutex<usize>,
+  }
+
+  impl MemoryDisk {
+    fn new(count: usize) -> Self {
+      Self {
+        sectors: Mutex::new(vec![[0; SECTOR_SIZE]; count]),
+        reads: Mutex::new(0),
+      }
+    }
+  }
+
+  impl SectorDevice for MemoryDisk {
+    fn read_sector(&self, lba: u32, buffer: &mut [u8]) -> Result<(), ()> {
+      *self.reads.lock() += 1;
+      let sectors = self.sectors.lock();
+      buffer[..SECTOR_SIZE].copy_from_slice(sectors.get(lba as usize).ok_or(())?);
+      Ok(())
+    }
+
+    fn write_sector(&self, lba: u32, buffer: &[u8]) -> Result<(), ()> {
+      let mut sectors = self.sectors.lock();
+      sectors.get_mut(lba as usize).ok_or(())?.copy_from_slice(&buffer[..SECTOR_SIZE]);
+      Ok(())
+    }
+
+    /// Count a run of sectors as a single read, like a DMA transfer
+    fn read_sectors(&self, lba: u32, buffer: &mut [u8]) -> Result<(), ()> {
+      *self.reads.lock() += 1;
+      let sectors = self.sectors.lock();
+      for (index, sector) in buffer.chunks_mut(SECTOR_SIZE).enumerate() {
+        sector.copy_from_slice(sectors.get(lba as usize + index).ok_or(())?);
+      }
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn second_read_is_a_hit() {
+    let cache = SectorCache::new(MemoryDisk::new(8), 4);
+    cache.get_device().sectors.lock()[3][0] = 0x55;
+    let mut buffer = [0; SECTOR_SIZE];
+    cache.read(3, &mut buffer).unwrap();
+    cache.read(3, &mut buffer).unwrap();
+    assert_eq!(buffer[0], 0x55);
+    assert_eq!(*cache.get_device().reads.lock(), 1);
+    let stats = cache.get_stats();
+    assert_eq!({ stats.hits }, 1);
+    assert_eq!({ stats.misses }, 1);
+
+    cache.reset_stats();
+    assert_eq!({ cache.get_stats().hits }, 0);
+  }
+
+  #[test]
+  fn uncached_runs_are_read_together() {
+    let cache = SectorCache::new(MemoryDisk::new(8), 8);
+    for lba in 0..5 {
+      cache.get_device().sectors.lock()[lba][0] = lba as u8 + 1;
+    }
+    let mut buffer = [0; SECTOR_SIZE * 5];
+    cache.read(2, &mut buffer).unwrap();
+    // Sectors 0-1 and 3-4 are each fetched in one request around cached 2
+    cache.read_sectors(0, &mut buffer).unwrap();
+    assert_eq!(*cache.get_device().reads.lock(), 3);
+    for lba in 0..5 {
+      assert_eq!(buffer[lba * SECTOR_SIZE], lba as u8 + 1);
+    }
+    let stats = cache.get_stats();
+    assert_eq!({ stats.hits }, 1);
+    assert_eq!({ stats.misses }, 5);
+  }
+
+  #[test]
+  fn write_reaches_the_disk_immediately() {
+    let cache = SectorCache::new(MemoryDisk::new(8), 2);
+    let mut buffer = [0; SECTOR_SIZE];
+    cache.write(0, &[0xaa; SECTOR_SIZE]).unwrap();
+    assert_eq!(cache.get_device().sectors.lock()[0][0], 0xaa);
+    assert_eq!({ cache.get_stats().write_backs }, 1);
+    // The written sector is cached, so reading it doesn't touch the disk
+    cache.read(0, &mut buffer).unwrap();
+    assert_eq!(buffer[0], 0xaa);
+    assert_eq!(*cache.get_device().reads.lock(), 0);
+    // Sector 0 is the least recently used, and makes room for sector 2
+    cache.read(1, &mut buffer).unwrap();
+    cache.read(2, &mut buffer).unwrap();
+    assert_eq!({ cache.get_stats().evictions }, 1);
+    assert_eq!({ cache.get_stats().write_backs }, 1);
+  }
+
+  #[test]
+  fn failed_write_is_not_cached() {
+    let cache = SectorCache::new(MemoryDisk::new(8), 4);
+    assert!(cache.write(9, &[0x34; SECTOR_SIZE]).is_err());
+    assert_eq!({ cache.get_stats().write_backs }, 0);
+    let mut buffer = [0; SECTOR_SIZE];
+    assert!(cache.read(9, &mut buffer).is_err());
+  }
+
+  #[test]
+  fn drop_caches_empties_every_cache() {
+    let cache = Arc::new(SectorCache::new(MemoryDisk::new(8), 4));
+    super::register_cache(cache.clone());
+    cache.write(5, &[0x12; SECTOR_SIZE]).unwrap();
+
+    super::drop_caches();
+    // The cache is empty, so the sector comes from the disk again
+    let mut buffer = [0; SECTOR_SIZE];
+    cache.read(5, &mut buffer).unwrap();
+    assert_eq!(buffer[0], 0x12);
+    assert_eq!(*cache.get_device().reads.lock(), 1);
+    assert_eq!({ cache.get_stats().misses }, 2);
+  }
+}
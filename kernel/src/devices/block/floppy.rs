@@ -1,18 +1,24 @@
 use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use crate::files::cursor::SeekMethod;
-use crate::hardware::floppy::{DriveSelect, FloppyDiskController, Operation};
+use crate::hardware::floppy::{DriveSelect, FloppyDiskController, Operation, SystemHardware, ST3_WRITE_PROTECTED};
 use crate::memory::address::{PhysicalAddress, VirtualAddress};
 use crate::task::id::ProcessID;
 use crate::task::memory::MMapBacking;
-use spin::RwLock;
-use super::geometry::{SectorRange};
+use crate::locks::RwLock;
+use super::cache::{register_cache, SectorCache, SectorDevice, SECTOR_SIZE};
+use super::geometry::{DiskGeometry, SectorRange};
+use super::{IOCTL_GET_WRITE_PROTECT, IOCTL_SET_GEOMETRY};
 use super::super::driver::{DeviceDriver, IOHandle};
 
-static CONTROLLER: FloppyDiskController = FloppyDiskController::new();
+static CONTROLLER: FloppyDiskController<SystemHardware> = FloppyDiskController::new(SystemHardware);
 
 static DMA_ADDR: RwLock<Option<(PhysicalAddress, VirtualAddress)>> = RwLock::new(None);
 const DMA_SIZE: usize = 4096;
+/// Number of sectors of each floppy kept in memory, enough for two tracks
+const CACHED_SECTORS: usize = 36;
 
 pub fn init() -> (bool, bool) {
   crate::kprintln!("Install Floppy driver");
@@ -56,7 +62,7 @@ fn get_dma_addresses() -> (PhysicalAddress, VirtualAddress) {
   }
 }
 
-pub fn load_sectors_to_cache(drive: DriveSelect, sectors: &SectorRange, dma_mode: u8) -> Result<VirtualAddress, ()> {
+pub fn load_sectors_to_cache(drive: DriveSelect, geometry: &DiskGeometry, sectors: &SectorRange, dma_mode: u8) -> Result<VirtualAddress, ()> {
   let (dma_phys, dma_virt) = get_dma_addresses();
   {
     let channel = super::super::DMA.get_channel(2);
@@ -64,8 +70,8 @@ pub fn load_sectors_to_cache(drive: DriveSelect, sectors: &SectorRange, dma_mode
     channel.set_count(sectors.byte_length() - 1);
     channel.set_mode(dma_mode);
   }
-  let (c, h, s) = sectors.get_first_sector().to_chs();
-  CONTROLLER.add_operation(Operation::Read(drive, c, h, s));
+  let (c, h, s) = sectors.get_first_sector().to_chs(geometry);
+  CONTROLLER.add_operation(Operation::Read(drive, c, h, s)).map_err(|_| ())?;
   Ok(dma_virt)
 }
 
@@ -86,27 +92,83 @@ impl OpenInstance {
   }
 }
 
+/// A single floppy drive, read through the DMA area
+pub struct FloppyDisk {
+  drive_select: DriveSelect,
+  geometry: RwLock<DiskGeometry>,
+}
+
+impl SectorDevice for FloppyDisk {
+  fn read_sector(&self, lba: u32, buffer: &mut [u8]) -> Result<(), ()> {
+    self.read_sectors(lba, &mut buffer[..SECTOR_SIZE])
+  }
+
+  /// Each DMA transfer covers as many of the sectors as possible. A transfer
+  /// can't run past the end of a track or overflow the DMA area.
+  fn read_sectors(&self, lba: u32, buffer: &mut [u8]) -> Result<(), ()> {
+    let geometry = *self.geometry.read();
+    let mut lba = lba as usize;
+    let mut copied = 0;
+    while copied < buffer.len() {
+      let count = (geometry.sectors_per_track - lba % geometry.sectors_per_track)
+        .min(DMA_SIZE / SECTOR_SIZE)
+        .min((buffer.len() - copied) / SECTOR_SIZE);
+      let sectors = SectorRange::for_byte_range(lba * SECTOR_SIZE, count * SECTOR_SIZE);
+      let dma_src = load_sectors_to_cache(self.drive_select, &geometry, &sectors, 0x56)?;
+      let length = sectors.byte_length();
+      let dma_data = unsafe {
+        core::slice::from_raw_parts(dma_src.as_usize() as *const u8, length)
+      };
+      buffer[copied..(copied + length)].copy_from_slice(dma_data);
+      copied += length;
+      lba += count;
+    }
+    Ok(())
+  }
+
+  fn write_sector(&self, _lba: u32, _buffer: &[u8]) -> Result<(), ()> {
+    Err(())
+  }
+}
+
 /// Device driver for interacting with data on a floppy disk. It exposes the
 /// floppy disk as a byte stream, and can be used by a filesystem implementation
 /// to actually read data on a disk.
 /// The floppy driver allows artibrary reads and writes, but the floppy
 /// controller only operates at a sector granularity. To accomodate this, the
 /// driver maintains an internal LRU cache of sectors that have been read from
-/// the disk. Byte-level data can be copied from this in-memory cache.
+/// the disk. Byte-level data can be copied from this in-memory cache, which is
+/// emptied whenever the disk is swapped.
 pub struct FloppyDriver {
-  drive_select: DriveSelect,
+  disk: Arc<SectorCache<FloppyDisk>>,
   next_handle: AtomicUsize,
   open_handles: RwLock<BTreeMap<IOHandle, OpenInstance>>,
 }
 
 impl FloppyDriver {
   pub fn new(drive_select: DriveSelect) -> Self {
-    Self {
+    let disk = FloppyDisk {
       drive_select,
+      geometry: RwLock::new(DiskGeometry::floppy_1440k()),
+    };
+    let disk = Arc::new(SectorCache::new(disk, CACHED_SECTORS));
+    register_cache(disk.clone());
+    Self {
+      disk,
       next_handle: AtomicUsize::new(0),
       open_handles: RwLock::new(BTreeMap::new()),
     }
   }
+
+  /// Forget every cached sector if a different disk has been inserted
+  fn check_media_change(&self) -> Result<(), ()> {
+    let drive = self.disk.get_device().drive_select;
+    let changed = CONTROLLER.add_operation(Operation::CheckMediaChange(drive)).map_err(|_| ())?;
+    if changed != 0 {
+      self.disk.clear();
+    }
+    Ok(())
+  }
 }
 
 impl DeviceDriver for FloppyDriver {
@@ -128,15 +190,13 @@ impl DeviceDriver for FloppyDriver {
     }?;
 
     let length = buffer.len();
-    let sectors = SectorRange::for_byte_range(cursor, length);
-
-    let dma_src = load_sectors_to_cache(self.drive_select, &sectors, 0x56)?;
-    let local_offset = sectors.get_local_offset(cursor);
-    let dma_src_ptr = (dma_src.as_usize() + local_offset) as *const u8;
-    for i in 0..length {
-      unsafe {
-        buffer[i] = *dma_src_ptr.offset(i as isize);
-      }
+    if length > 0 {
+      self.check_media_change()?;
+      let sectors = SectorRange::for_byte_range(cursor, length);
+      let mut data = vec![0u8; sectors.byte_length()];
+      self.disk.read_sectors((cursor / SECTOR_SIZE) as u32, &mut data)?;
+      let offset = sectors.get_local_offset(cursor);
+      buffer.copy_from_slice(&data[offset..(offset + length)]);
     }
 
     match self.open_handles.write().get_mut(&index) {
@@ -148,8 +208,10 @@ impl DeviceDriver for FloppyDriver {
     }
   }
 
+  /// Writing to floppy disks is not supported yet. Failing outright keeps it
+  /// from being mistaken for a full disk.
   fn write(&self, index: IOHandle, buffer: &[u8]) -> Result<usize, ()> {
-    Ok(0)
+    Err(())
   }
 
   fn seek(&self, index: IOHandle, offset: SeekMethod) -> Result<usize, ()> {
@@ -162,4 +224,29 @@ impl DeviceDriver for FloppyDriver {
       None => Err(())
     }
   }
+
+  fn ioctl(&self, _index: IOHandle, command: u32, arg: u32) -> Result<u32, ()> {
+    match command {
+      IOCTL_SET_GEOMETRY => {
+        let heads = (arg >> 16) as usize;
+        let sectors_per_track = (arg & 0xffff) as usize;
+        if heads == 0 || sectors_per_track == 0 {
+          return Err(());
+        }
+        *self.disk.get_device().geometry.write() = DiskGeometry {
+          sectors_per_track,
+          heads,
+        };
+        // Cached sectors were addressed with the old geometry
+        self.disk.clear();
+        Ok(0)
+      },
+      IOCTL_GET_WRITE_PROTECT => {
+        let drive = self.disk.get_device().drive_select;
+        let st3 = CONTROLLER.add_operation(Operation::SenseStatus(drive)).map_err(|_| ())?;
+        Ok(if st3 & ST3_WRITE_PROTECTED != 0 { 1 } else { 0 })
+      },
+      _ => Err(()),
+    }
+  }
 }
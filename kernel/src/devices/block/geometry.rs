@@ -2,15 +2,32 @@
 #[derive(Copy, Clone)]
 pub struct Sector(usize);
 
-// These constants are for floppy disks, expand this later
-const SECTORS_PER_TRACK: usize = 18;
 const SECTOR_SIZE: usize = 512;
 
+/// Physical layout of a disk, needed to convert LBA sectors to CHS addresses
+#[derive(Copy, Clone)]
+pub struct DiskGeometry {
+  pub sectors_per_track: usize,
+  pub heads: usize,
+}
+
+impl DiskGeometry {
+  /// Geometry of a 1.44MB floppy, assumed until the disk's BPB has been read
+  pub const fn floppy_1440k() -> DiskGeometry {
+    DiskGeometry {
+      sectors_per_track: 18,
+      heads: 2,
+    }
+  }
+}
+
 impl Sector {
-  pub fn to_chs(&self) -> (usize, usize, usize) {
-    let c = self.0 / (2 * SECTORS_PER_TRACK);
-    let h = (self.0 % (2 * SECTORS_PER_TRACK)) / SECTORS_PER_TRACK;
-    let s = (self.0 % (2 * SECTORS_PER_TRACK)) % SECTORS_PER_TRACK + 1;
+  pub fn to_chs(&self, geometry: &DiskGeometry) -> (usize, usize, usize) {
+    let per_track = geometry.sectors_per_track;
+    let per_cylinder = geometry.heads * per_track;
+    let c = self.0 / per_cylinder;
+    let h = (self.0 % per_cylinder) / per_track;
+    let s = (self.0 % per_cylinder) % per_track + 1;
     (c, h, s)
   }
 }
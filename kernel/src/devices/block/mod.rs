@@ -1,4 +1,20 @@
+#[cfg(not(test))]
+pub mod ata;
+pub mod cache;
+#[cfg(not(test))]
 pub mod floppy;
 pub mod geometry;
 
+#[cfg(not(test))]
+pub use ata::AtaDriver;
+#[cfg(not(test))]
 pub use floppy::FloppyDriver;
+
+/// ioctl: set the disk geometry used to address sectors. The argument holds
+/// the number of heads in the high 16 bits, and sectors per track in the low
+/// 16 bits.
+pub const IOCTL_SET_GEOMETRY: u32 = 0x4701;
+
+/// ioctl: determine whether the media in the drive is write-protected.
+/// Returns 1 if it is, and 0 if it can be written.
+pub const IOCTL_GET_WRITE_PROTECT: u32 = 0x4702;
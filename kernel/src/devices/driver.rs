@@ -22,10 +22,24 @@ pub trait DeviceDriver {
 
   fn read(&self, index: IOHandle, buffer: &mut [u8]) -> Result<usize, ()>;
 
+  /// Accepting none of a non-empty buffer tells the caller that the device
+  /// is out of space
   fn write(&self, index: IOHandle, buffer: &[u8]) -> Result<usize, ()>;
 
   fn close(&self, index: IOHandle) -> Result<(), ()>;
 
+  /// Read without blocking. Devices that would put the caller to sleep while
+  /// waiting for input should return `None` instead.
+  fn read_nonblocking(&self, index: IOHandle, buffer: &mut [u8]) -> Result<Option<usize>, ()> {
+    self.read(index, buffer).map(|bytes| Some(bytes))
+  }
+
+  /// Write without blocking. Devices that cannot accept data right now should
+  /// return `None` instead of waiting.
+  fn write_nonblocking(&self, index: IOHandle, buffer: &[u8]) -> Result<Option<usize>, ()> {
+    self.write(index, buffer).map(|bytes| Some(bytes))
+  }
+
   fn seek(&self, index: IOHandle, offset: SeekMethod) -> Result<usize, ()> {
     Err(())
   }
@@ -33,6 +47,29 @@ pub trait DeviceDriver {
   fn reopen(&self, index: IOHandle, id: ProcessID) -> Result<IOHandle, ()> {
     Err(())
   }
+
+  /// Perform a device-specific operation. Command numbers and the meaning of
+  /// the argument depend on the device.
+  fn ioctl(&self, index: IOHandle, command: u32, arg: u32) -> Result<u32, ()> {
+    Err(())
+  }
+
+  /// Terminals override this, so that programs can tell when they are
+  /// connected to a TTY instead of a file or pipe
+  fn is_terminal(&self) -> bool {
+    false
+  }
+
+  /// Report whether a read would return without blocking. Devices that always
+  /// have data, like DEV:/ZERO, can rely on the default implementation.
+  fn poll_readable(&self, index: IOHandle) -> Result<bool, ()> {
+    Ok(true)
+  }
+
+  /// Report whether a write would accept data without blocking
+  fn poll_writable(&self, index: IOHandle) -> Result<bool, ()> {
+    Ok(true)
+  }
 }
 
 pub type DeviceDriverType = dyn DeviceDriver + Sync + Send;
@@ -0,0 +1,129 @@
+//! DEV:\FB0 gives programs direct access to a graphics framebuffer. A program
+//! picks a resolution with FBIOSET_MODE, and maps the framebuffer into its own
+//! memory with FBIOMAP. Pixels are drawn by writing to that memory, with no
+//! further syscalls.
+//! High resolutions use a VESA linear framebuffer. Machines without VBE 2.0
+//! get mode 13h instead, and FBIOSET_MODE reports the mode that was actually
+//! set.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::hardware::vga::{driver, vbe::{self, ModeInfo}};
+use crate::memory::physical::{self, frame_range::FrameRange};
+use crate::memory::virt::page_directory::{CurrentPageDirectory, PermissionFlags};
+use crate::task::memory::MMapBacking;
+use crate::locks::RwLock;
+use syscall::files::FramebufferInfo;
+use syscall::flags::{FBIOGET_INFO, FBIOMAP, FBIOSET_MODE};
+use super::driver::{DeviceDriver, IOHandle};
+
+pub struct FramebufferDriver {
+  next_handle: AtomicUsize,
+  /// The mode most recently set through this device
+  mode: RwLock<Option<ModeInfo>>,
+}
+
+impl FramebufferDriver {
+  pub const fn new() -> Self {
+    Self {
+      next_handle: AtomicUsize::new(1),
+      mode: RwLock::new(None),
+    }
+  }
+
+  /// Switch to a VBE mode matching the request, or to mode 13h if there is
+  /// none
+  fn set_mode(&self, width: u16, height: u16, bits_per_pixel: u8) -> ModeInfo {
+    let vbe_mode = driver::query_vbe_modes().and_then(|modes| {
+      let candidates = modes
+        .into_iter()
+        .filter_map(|mode| driver::query_vbe_mode(mode).map(|info| (mode, info)));
+      vbe::find_mode(candidates, width, height, bits_per_pixel)
+    });
+    let info = match vbe_mode {
+      Some((mode, info)) if driver::set_vbe_mode(mode).is_ok() => info,
+      _ => {
+        driver::request_mode_change(0x13);
+        ModeInfo::mode_13h()
+      },
+    };
+    // Keep the frame allocator from handing out any part of the framebuffer.
+    // Framebuffers above the end of RAM are outside the allocator's range,
+    // and never need to be reserved.
+    let range = FrameRange::new(info.framebuffer.as_usize(), info.framebuffer_size());
+    let _ = physical::allocate_range(range);
+    *self.mode.write() = Some(info);
+    info
+  }
+
+  /// Map the framebuffer into the current process. Its pages point straight
+  /// at the device, so they are never reclaimed when the process unmaps them.
+  fn map_into_current_process(&self) -> Result<u32, ()> {
+    let info = (*self.mode.read()).ok_or(())?;
+    let size = info.framebuffer_size();
+    let address = {
+      let process_lock = crate::task::get_current_process();
+      let mut process = process_lock.write();
+      process.memory.mmap(None, size, MMapBacking::Direct(info.framebuffer)).map_err(|_| ())?
+    };
+    let pagedir = CurrentPageDirectory::get();
+    for offset in (0..size).step_by(0x1000) {
+      pagedir.map_explicit(
+        info.framebuffer + offset,
+        address + offset,
+        PermissionFlags::new(PermissionFlags::USER_ACCESS | PermissionFlags::WRITE_ACCESS | PermissionFlags::NO_RECLAIM),
+      );
+    }
+    Ok(address.as_u32())
+  }
+}
+
+fn write_info(info: &ModeInfo, out: &mut FramebufferInfo) {
+  out.width = info.width as u32;
+  out.height = info.height as u32;
+  out.bits_per_pixel = info.bits_per_pixel as u32;
+  out.pitch = info.pitch as u32;
+  out.size = info.framebuffer_size() as u32;
+}
+
+impl DeviceDriver for FramebufferDriver {
+  fn open(&self) -> Result<IOHandle, ()> {
+    let handle = IOHandle::new(self.next_handle.fetch_add(1, Ordering::SeqCst));
+    Ok(handle)
+  }
+
+  fn close(&self, _index: IOHandle) -> Result<(), ()> {
+    Ok(())
+  }
+
+  /// The framebuffer is only accessed through memory
+  fn read(&self, _index: IOHandle, _buffer: &mut [u8]) -> Result<usize, ()> {
+    Err(())
+  }
+
+  fn write(&self, _index: IOHandle, _buffer: &[u8]) -> Result<usize, ()> {
+    Err(())
+  }
+
+  fn ioctl(&self, _index: IOHandle, command: u32, arg: u32) -> Result<u32, ()> {
+    match command {
+      FBIOGET_INFO => {
+        let info = (*self.mode.read()).ok_or(())?;
+        let out = unsafe { &mut *(arg as *mut FramebufferInfo) };
+        write_info(&info, out);
+        Ok(0)
+      },
+      FBIOSET_MODE => {
+        let request = unsafe { &mut *(arg as *mut FramebufferInfo) };
+        let (width, height, bits_per_pixel) = (request.width, request.height, request.bits_per_pixel);
+        if width > 0xffff || height > 0xffff || bits_per_pixel > 32 {
+          return Err(());
+        }
+        let info = self.set_mode(width as u16, height as u16, bits_per_pixel as u8);
+        write_info(&info, request);
+        Ok(0)
+      },
+      FBIOMAP => self.map_into_current_process(),
+      _ => Err(()),
+    }
+  }
+}
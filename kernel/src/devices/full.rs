@@ -0,0 +1,61 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::driver::{DeviceDriver, IOHandle};
+
+/// FULL behaves like a disk with no space left. Reads produce an endless
+/// stream of zeroes, like ZERO, but every write is refused without accepting
+/// any bytes. It is useful for testing how programs handle a full disk.
+pub struct FullDriver {
+  next_handle: AtomicUsize,
+}
+
+impl FullDriver {
+  pub const fn new() -> Self {
+    Self {
+      next_handle: AtomicUsize::new(1),
+    }
+  }
+}
+
+impl DeviceDriver for FullDriver {
+  fn open(&self) -> Result<IOHandle, ()> {
+    let handle = IOHandle::new(self.next_handle.fetch_add(1, Ordering::SeqCst));
+    Ok(handle)
+  }
+
+  fn close(&self, _index: IOHandle) -> Result<(), ()> {
+    Ok(())
+  }
+
+  fn read(&self, _index: IOHandle, buffer: &mut [u8]) -> Result<usize, ()> {
+    for i in 0..buffer.len() {
+      buffer[i] = 0;
+    }
+    Ok(buffer.len())
+  }
+
+  fn write(&self, _index: IOHandle, _buffer: &[u8]) -> Result<usize, ()> {
+    Ok(0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{DeviceDriver, FullDriver};
+  use crate::task::io::bytes_written;
+  use syscall::result::SystemError;
+
+  #[test]
+  fn reads_zeroes_and_refuses_writes() {
+    let full = FullDriver::new();
+    let handle = full.open().unwrap();
+    let mut buffer = [0xff; 8];
+    assert_eq!(full.read(handle, &mut buffer), Ok(8));
+    assert_eq!(buffer, [0; 8]);
+
+    let written = full.write(handle, b"data").unwrap();
+    assert_eq!(written, 0);
+    assert!(matches!(bytes_written(written, 4), Err(SystemError::NoSpace)));
+    // An empty write has nothing to store, so it isn't an error
+    assert!(matches!(bytes_written(full.write(handle, &[]).unwrap(), 0), Ok(0)));
+  }
+}
@@ -1,6 +1,7 @@
 use alloc::boxed::Box;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use syscall::files::{copy_listed_name, DeviceInfo};
 use super::driver::DeviceDriverType;
 
 /// Associates a unique device name with the device number
@@ -53,6 +54,16 @@ impl InstalledDevices {
     self.device_names.get(driver_number).map(|by_name| &by_name.name)
   }
 
+  /// Describe each installed device, in the order it was registered, filling
+  /// as many entries of `dest` as will fit. Returns the total number of
+  /// devices, so that a caller with a short buffer knows how much to allocate.
+  pub fn list_devices(&self, dest: &mut [DeviceInfo]) -> usize {
+    for (by_name, info) in self.device_names.iter().zip(dest.iter_mut()) {
+      info.name_length = copy_listed_name(&by_name.name, &mut info.name);
+    }
+    self.device_names.len()
+  }
+
   pub fn register_driver(&mut self, name: &str, driver: Arc<Box<DeviceDriverType>>) -> usize {
     self.drivers.push(driver);
     let number = self.drivers.len();
@@ -65,3 +76,28 @@ impl InstalledDevices {
     number
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use alloc::boxed::Box;
+  use alloc::sync::Arc;
+  use crate::devices::null::NullDriver;
+  use crate::devices::zero::ZeroDriver;
+  use syscall::files::DeviceInfo;
+  use super::InstalledDevices;
+
+  #[test]
+  fn list_registered_devices() {
+    let mut devices = InstalledDevices::new();
+    devices.register_driver("NULL", Arc::new(Box::new(NullDriver::new())));
+    devices.register_driver("ZERO", Arc::new(Box::new(ZeroDriver::new())));
+    let mut listing = [DeviceInfo::empty(), DeviceInfo::empty(), DeviceInfo::empty()];
+    assert_eq!(devices.list_devices(&mut listing), 2);
+    assert_eq!(listing[0].name_bytes(), b"NULL");
+    assert_eq!(listing[1].name_bytes(), b"ZERO");
+
+    let mut short = [DeviceInfo::empty()];
+    assert_eq!(devices.list_devices(&mut short), 2);
+    assert_eq!(short[0].name_bytes(), b"NULL");
+  }
+}
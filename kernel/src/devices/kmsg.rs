@@ -0,0 +1,373 @@
+//! In-memory ring buffer of kernel log output, readable through DEV:\KMSG.
+//! Everything printed with kprint! and klog! is also copied here, so messages
+//! from early boot or interrupt handlers can be inspected after the fact, even
+//! if the console wasn't ready when they were printed.
+//! The log may be written from interrupt context, so writers never wait on the
+//! lock. If it is already held, the message is dropped and counted, and a note
+//! about the dropped messages is added the next time a write succeeds.
+//! Messages can be tagged with a level and a category, and tagged messages are
+//! only printed if the log filter allows them. The filter is checked before
+//! any formatting happens, so suppressed debug output costs two atomic loads.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use crate::collections::SlotList;
+use crate::locks::RwLock;
+use syscall::flags::{
+  KLOG_SET_CATEGORIES, KLOG_SET_LEVEL, LOG_CATEGORY_ALL, LOG_CATEGORY_DRV, LOG_CATEGORY_FS,
+  LOG_CATEGORY_GENERAL, LOG_CATEGORY_MM, LOG_CATEGORY_SCHED,
+};
+use super::driver::{DeviceDriver, IOHandle};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+#[repr(u8)]
+pub enum LogLevel {
+  Error = 0,
+  Warn = 1,
+  Info = 2,
+  Debug = 3,
+}
+
+impl LogLevel {
+  pub fn from_u32(level: u32) -> Option<LogLevel> {
+    match level {
+      0 => Some(LogLevel::Error),
+      1 => Some(LogLevel::Warn),
+      2 => Some(LogLevel::Info),
+      3 => Some(LogLevel::Debug),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LogCategory {
+  General,
+  /// Memory management: paging, frames, and the heap
+  Memory,
+  Scheduler,
+  FileSystem,
+  Driver,
+}
+
+impl LogCategory {
+  pub fn mask(&self) -> u8 {
+    let mask = match self {
+      LogCategory::General => LOG_CATEGORY_GENERAL,
+      LogCategory::Memory => LOG_CATEGORY_MM,
+      LogCategory::Scheduler => LOG_CATEGORY_SCHED,
+      LogCategory::FileSystem => LOG_CATEGORY_FS,
+      LogCategory::Driver => LOG_CATEGORY_DRV,
+    };
+    mask as u8
+  }
+}
+
+/// Decides which tagged messages get printed: those at or above the
+/// configured level of detail, in an enabled category. Errors are always
+/// printed.
+pub struct LogFilter {
+  level: AtomicU8,
+  categories: AtomicU8,
+}
+
+impl LogFilter {
+  pub const fn new(level: LogLevel, categories: u8) -> LogFilter {
+    LogFilter {
+      level: AtomicU8::new(level as u8),
+      categories: AtomicU8::new(categories),
+    }
+  }
+
+  #[inline]
+  pub fn allows(&self, level: LogLevel, category: LogCategory) -> bool {
+    if level == LogLevel::Error {
+      return true;
+    }
+    level as u8 <= self.level.load(Ordering::Relaxed)
+      && self.categories.load(Ordering::Relaxed) & category.mask() != 0
+  }
+
+  /// Returns the previous level
+  pub fn set_level(&self, level: LogLevel) -> LogLevel {
+    let previous = self.level.swap(level as u8, Ordering::SeqCst);
+    LogLevel::from_u32(previous as u32).unwrap_or(LogLevel::Debug)
+  }
+
+  /// Returns the previously enabled categories
+  pub fn set_categories(&self, categories: u8) -> u8 {
+    self.categories.swap(categories, Ordering::SeqCst)
+  }
+}
+
+/// Debug messages are off until someone asks for them through DEV:\KMSG
+pub static LOG_FILTER: LogFilter = LogFilter::new(LogLevel::Info, LOG_CATEGORY_ALL as u8);
+
+pub fn is_enabled(level: LogLevel, category: LogCategory) -> bool {
+  LOG_FILTER.allows(level, category)
+}
+
+/// Must be a power of two, so that ring positions stay correct when the
+/// stream offset wraps around
+pub const LOG_SIZE: usize = 16 * 1024;
+
+/// A fixed-size log where new bytes overwrite the oldest ones
+pub struct LogRing {
+  data: [u8; LOG_SIZE],
+  /// Total number of bytes ever written, wrapping around on overflow. The
+  /// ring holds the most recent bytes of this stream.
+  written: usize,
+  /// How many bytes the ring currently holds, up to LOG_SIZE
+  stored: usize,
+}
+
+impl LogRing {
+  pub const fn new() -> LogRing {
+    LogRing {
+      data: [0; LOG_SIZE],
+      written: 0,
+      stored: 0,
+    }
+  }
+
+  pub fn write_bytes(&mut self, bytes: &[u8]) {
+    for byte in bytes {
+      self.data[self.written % LOG_SIZE] = *byte;
+      self.written = self.written.wrapping_add(1);
+    }
+    self.stored = (self.stored + bytes.len()).min(LOG_SIZE);
+  }
+
+  /// Offset of the oldest byte still held in the ring
+  pub fn oldest(&self) -> usize {
+    self.written.wrapping_sub(self.stored)
+  }
+
+  /// Copy bytes from the log stream, starting at an absolute offset. If that
+  /// data has already been overwritten, reading skips ahead to the oldest
+  /// available byte. Returns the offset after the last copied byte.
+  /// Offsets wrap around along with the stream, so an offset is compared by
+  /// how far behind the newest byte it is.
+  pub fn read_from(&self, offset: usize, dest: &mut [u8]) -> (usize, usize) {
+    let start = if self.written.wrapping_sub(offset) > self.stored {
+      self.oldest()
+    } else {
+      offset
+    };
+    let available = self.written.wrapping_sub(start);
+    let to_read = available.min(dest.len());
+    for i in 0..to_read {
+      dest[i] = self.data[start.wrapping_add(i) % LOG_SIZE];
+    }
+    (to_read, start.wrapping_add(to_read))
+  }
+}
+
+impl Write for LogRing {
+  fn write_str(&mut self, s: &str) -> fmt::Result {
+    self.write_bytes(s.as_bytes());
+    Ok(())
+  }
+}
+
+/// A log ring that can be written from interrupt context, along with counts
+/// of the messages that were dropped because it was busy
+pub struct KernelLog {
+  ring: RwLock<LogRing>,
+  /// Number of messages dropped because the log was busy, which haven't been
+  /// reported in the log yet
+  dropped: AtomicUsize,
+  /// Total number of dropped messages since boot
+  total_dropped: AtomicUsize,
+}
+
+impl KernelLog {
+  pub const fn new() -> KernelLog {
+    KernelLog {
+      ring: RwLock::new(LogRing::new()),
+      dropped: AtomicUsize::new(0),
+      total_dropped: AtomicUsize::new(0),
+    }
+  }
+
+  /// Append formatted output to the log. This is safe to call from an
+  /// interrupt handler: if the log is locked, the message is dropped.
+  pub fn log_args(&self, args: fmt::Arguments) {
+    let mut log = match self.ring.try_write() {
+      Some(log) => log,
+      None => {
+        self.dropped.fetch_add(1, Ordering::SeqCst);
+        self.total_dropped.fetch_add(1, Ordering::SeqCst);
+        return;
+      },
+    };
+    let dropped = self.dropped.swap(0, Ordering::SeqCst);
+    if dropped > 0 {
+      let _ = write!(log, "[{} log messages dropped]\n", dropped);
+    }
+    let _ = log.write_fmt(args);
+  }
+
+  pub fn get_dropped_count(&self) -> usize {
+    self.total_dropped.load(Ordering::SeqCst)
+  }
+}
+
+static KERNEL_LOG: KernelLog = KernelLog::new();
+
+/// Append formatted output to the kernel log. This is safe to call from an
+/// interrupt handler: if the log is locked, the message is dropped.
+pub fn log_args(args: fmt::Arguments) {
+  KERNEL_LOG.log_args(args);
+}
+
+pub fn get_dropped_count() -> usize {
+  KERNEL_LOG.get_dropped_count()
+}
+
+/// Each handle to DEV:\KMSG keeps its own position in the log stream. Reads
+/// return whatever has been logged since the last read, and 0 once caught up.
+pub struct KmsgDriver {
+  log: &'static KernelLog,
+  cursors: RwLock<SlotList<usize>>,
+}
+
+impl KmsgDriver {
+  pub fn new() -> Self {
+    Self::for_log(&KERNEL_LOG)
+  }
+
+  /// Read and write a log other than the kernel's own
+  pub const fn for_log(log: &'static KernelLog) -> Self {
+    Self {
+      log,
+      cursors: RwLock::new(SlotList::new()),
+    }
+  }
+}
+
+impl DeviceDriver for KmsgDriver {
+  fn open(&self) -> Result<IOHandle, ()> {
+    // New readers start at the oldest message still in the log
+    let start = self.log.ring.read().oldest();
+    let index = self.cursors.write().insert(start);
+    Ok(IOHandle::new(index))
+  }
+
+  fn close(&self, index: IOHandle) -> Result<(), ()> {
+    self.cursors.write().remove(index.as_usize()).map(|_| ()).ok_or(())
+  }
+
+  fn read(&self, index: IOHandle, buffer: &mut [u8]) -> Result<usize, ()> {
+    let mut cursors = self.cursors.write();
+    let cursor = cursors.get_mut(index.as_usize()).ok_or(())?;
+    let (bytes_read, next) = self.log.ring.read().read_from(*cursor, buffer);
+    *cursor = next;
+    Ok(bytes_read)
+  }
+
+  fn write(&self, _index: IOHandle, buffer: &[u8]) -> Result<usize, ()> {
+    // Allow userspace to add its own messages to the log
+    let mut log = self.log.ring.write();
+    log.write_bytes(buffer);
+    Ok(buffer.len())
+  }
+
+  fn reopen(&self, index: IOHandle, _id: crate::task::id::ProcessID) -> Result<IOHandle, ()> {
+    let mut cursors = self.cursors.write();
+    let cursor = *cursors.get(index.as_usize()).ok_or(())?;
+    Ok(IOHandle::new(cursors.insert(cursor)))
+  }
+
+  fn ioctl(&self, _index: IOHandle, command: u32, arg: u32) -> Result<u32, ()> {
+    match command {
+      KLOG_SET_LEVEL => {
+        let level = LogLevel::from_u32(arg).ok_or(())?;
+        Ok(LOG_FILTER.set_level(level) as u32)
+      },
+      KLOG_SET_CATEGORIES => {
+        if arg & !LOG_CATEGORY_ALL != 0 {
+          return Err(());
+        }
+        Ok(LOG_FILTER.set_categories(arg as u8) as u32)
+      },
+      _ => Err(()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+  use crate::devices::driver::DeviceDriver;
+  use super::{KernelLog, KmsgDriver, LogCategory, LogFilter, LogLevel, LogRing, LOG_SIZE};
+
+  #[test]
+  fn read_recent_messages() {
+    static LOG: KernelLog = KernelLog::new();
+    let driver = KmsgDriver::for_log(&LOG);
+    LOG.log_args(format_args!("first message {}\n", 1));
+    let handle = driver.open().unwrap();
+    LOG.log_args(format_args!("second message {}\n", 2));
+
+    let mut contents = Vec::new();
+    let mut buffer: [u8; 64] = [0; 64];
+    loop {
+      let len = driver.read(handle, &mut buffer).unwrap();
+      if len == 0 {
+        break;
+      }
+      contents.extend_from_slice(&buffer[..len]);
+    }
+    assert_eq!(contents, b"first message 1\nsecond message 2\n");
+    // Once caught up, there is nothing more to read
+    assert_eq!(driver.read(handle, &mut buffer), Ok(0));
+  }
+
+  #[test]
+  fn ring_keeps_most_recent_bytes() {
+    let mut ring = LogRing::new();
+    for i in 0..(LOG_SIZE + 10) {
+      ring.write_bytes(&[(i % 251) as u8]);
+    }
+    assert_eq!(ring.oldest(), 10);
+    let mut buffer: [u8; 4] = [0; 4];
+    // A reader that fell behind skips ahead to the oldest byte
+    assert_eq!(ring.read_from(0, &mut buffer), (4, 14));
+    assert_eq!(buffer, [10, 11, 12, 13]);
+    assert_eq!(ring.read_from(LOG_SIZE + 8, &mut buffer), (2, LOG_SIZE + 10));
+  }
+
+  #[test]
+  fn stream_offset_wraps_around() {
+    let mut ring = LogRing::new();
+    ring.written = usize::max_value() - 2;
+    ring.write_bytes(b"abcdef");
+    assert_eq!(ring.written, 3);
+    assert_eq!(ring.oldest(), usize::max_value() - 2);
+    let mut buffer: [u8; 8] = [0; 8];
+    assert_eq!(ring.read_from(ring.oldest(), &mut buffer), (6, 3));
+    assert_eq!(&buffer[..6], b"abcdef");
+    // An offset from before the wrap only reads what came after it
+    assert_eq!(ring.read_from(usize::max_value(), &mut buffer), (4, 3));
+    assert_eq!(&buffer[..4], b"cdef");
+    assert_eq!(ring.read_from(3, &mut buffer), (0, 3));
+  }
+
+  #[test]
+  fn filter_by_level_and_category() {
+    let filter = LogFilter::new(LogLevel::Warn, LogCategory::Memory.mask() | LogCategory::Driver.mask());
+    assert!(!filter.allows(LogLevel::Debug, LogCategory::Memory));
+    assert!(!filter.allows(LogLevel::Info, LogCategory::Memory));
+    assert!(filter.allows(LogLevel::Warn, LogCategory::Memory));
+    // Disabled categories only show errors
+    assert!(!filter.allows(LogLevel::Warn, LogCategory::Scheduler));
+    assert!(filter.allows(LogLevel::Error, LogCategory::Scheduler));
+
+    assert_eq!(filter.set_level(LogLevel::Debug), LogLevel::Warn);
+    assert!(filter.allows(LogLevel::Debug, LogCategory::Driver));
+    filter.set_categories(0);
+    assert!(!filter.allows(LogLevel::Warn, LogCategory::Driver));
+    assert!(filter.allows(LogLevel::Error, LogCategory::Driver));
+  }
+}
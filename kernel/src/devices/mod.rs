@@ -4,13 +4,18 @@ use crate::hardware::{dma, pic, pit, rtc};
 #[cfg(not(test))]
 use crate::hardware::floppy;
 use crate::hardware::vga::text_mode;
+use crate::locks::{ordered, LockLevel};
 use crate::memory::address::VirtualAddress;
-use spin::RwLock;
+use crate::locks::RwLock;
 
-#[cfg(not(test))]
+pub mod acct;
 pub mod block;
 pub mod driver;
+#[cfg(not(test))]
+pub mod framebuffer;
+pub mod full;
 pub mod installed;
+pub mod kmsg;
 pub mod null;
 pub mod queue;
 pub mod zero;
@@ -31,11 +36,13 @@ pub static DMA: dma::DMA = dma::DMA::new();
 pub static mut VGA_TEXT: text_mode::TextMode = text_mode::TextMode::new(VirtualAddress::new(0xc00b8000));
 
 pub fn get_device_number_by_name(filename: &str) -> Option<usize> {
+  let _order = ordered(LockLevel::Devices);
   let devices = DEVICES.read();
   devices.get_device_number_by_name(filename)
 }
 
 pub fn get_driver_for_device(number: usize) -> Option<Arc<Box<driver::DeviceDriverType>>> {
+  let _order = ordered(LockLevel::Devices);
   let devices = DEVICES.read();
   match devices.get_device(number) {
     Some(driver) => Some(driver.clone()),
@@ -51,6 +58,7 @@ pub fn init() {
   }
 
   {
+    let _order = ordered(LockLevel::Devices);
     let mut all_devices = DEVICES.write();
     all_devices.register_driver("KBD", Arc::new(Box::new(crate::input::keyboard::device::KeyboardDriver {})));
     crate::input::com::init();
@@ -58,6 +66,10 @@ pub fn init() {
     all_devices.register_driver("COM2", Arc::new(Box::new(crate::input::com::device::ComDriver::new(1))));
     all_devices.register_driver("NULL", Arc::new(Box::new(null::NullDriver::new())));
     all_devices.register_driver("ZERO", Arc::new(Box::new(zero::ZeroDriver::new())));
+    all_devices.register_driver("FULL", Arc::new(Box::new(full::FullDriver::new())));
+    all_devices.register_driver("KMSG", Arc::new(Box::new(kmsg::KmsgDriver::new())));
+    all_devices.register_driver("ACCT", Arc::new(Box::new(acct::AcctDriver::new())));
+    all_devices.register_driver("FB0", Arc::new(Box::new(framebuffer::FramebufferDriver::new())));
 
     let (has_primary_floppy, has_secondary_floppy) = block::floppy::init();
     if has_primary_floppy {
@@ -66,10 +78,14 @@ pub fn init() {
     if has_secondary_floppy {
       all_devices.register_driver("FD2", Arc::new(Box::new(block::FloppyDriver::new(floppy::DriveSelect::Secondary))));
     }
+    if let Some(hard_disk) = block::ata::init() {
+      all_devices.register_driver("HD0", Arc::new(Box::new(hard_disk)));
+    }
   }
 }
 
 pub fn create_tty(index: usize) {
+  let _order = ordered(LockLevel::Devices);
   let mut all_devices = DEVICES.write();
   let name: alloc::string::String = alloc::format!("TTY{}", index);
   all_devices.register_driver(&name, Arc::new(Box::new(crate::tty::device::TTYDevice::for_tty(index))));
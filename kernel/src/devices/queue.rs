@@ -1,7 +1,7 @@
 use alloc::collections::VecDeque;
 use crate::task::id::ProcessID;
 use crate::task::{get_process, get_current_process, yield_coop};
-use spin::RwLock;
+use crate::locks::RwLock;
 use super::driver::IOHandle;
 
 pub trait QueuedIO<T, IOResult> {
@@ -35,7 +35,9 @@ pub trait QueuedIO<T, IOResult> {
 
     let result = f();
 
-    self.get_io_queue().write().pop_front();
+    // Remove this handle rather than whatever is at the front, in case it was
+    // woken early because its handle was closed
+    self.get_io_queue().write().retain(|queued| *queued != handle);
     self.wake_front();
     result
   }
@@ -0,0 +1,74 @@
+//! DOS programs refer to drives by number, where 0 is A: and 25 is Z:. Kernel
+//! drives with single-letter names are visible to DOS under that letter; drives
+//! with longer names, like INIT: or DEV:, have no DOS number.
+
+use alloc::string::String;
+use crate::fs::{DRIVES, drive::DriveID};
+use crate::task::get_current_process;
+use super::registers::DosApiRegisters;
+
+/// Number of drive letters DOS programs can address
+pub const DOS_DRIVE_COUNT: u8 = 26;
+
+/// Reported as the current drive when it has no DOS letter
+pub const NO_DRIVE_NUMBER: u8 = 0xff;
+
+/// Get the kernel drive name for a zero-based DOS drive number
+pub fn drive_name_for_number(number: u8) -> Option<String> {
+  if number >= DOS_DRIVE_COUNT {
+    return None;
+  }
+  let mut name = String::with_capacity(1);
+  name.push((b'A' + number) as char);
+  Some(name)
+}
+
+/// Get the zero-based DOS drive number for a kernel drive name, if it has one
+pub fn number_for_drive_name(name: &str) -> Option<u8> {
+  match name.as_bytes() {
+    [letter] if letter.is_ascii_alphabetic() => Some(letter.to_ascii_uppercase() - b'A'),
+    _ => None,
+  }
+}
+
+pub fn drive_id_for_number(number: u8) -> Option<DriveID> {
+  let name = drive_name_for_number(number)?;
+  DRIVES.get_drive_number(&name)
+}
+
+/// AH=0Eh: Make the drive in DL the default. Drives that aren't mounted are
+/// ignored. AL is always set to the number of drive letters.
+pub fn select_disk(regs: &mut DosApiRegisters) {
+  if let Some(drive_id) = drive_id_for_number(regs.dl()) {
+    get_current_process().write().current_drive = drive_id;
+  }
+  regs.set_al(DOS_DRIVE_COUNT);
+}
+
+/// AH=19h: Set AL to the number of the default drive
+pub fn get_current_disk(regs: &mut DosApiRegisters) {
+  let drive_id = get_current_process().read().current_drive;
+  let number = DRIVES.get_drive_name(&drive_id)
+    .and_then(|name| number_for_drive_name(&name))
+    .unwrap_or(NO_DRIVE_NUMBER);
+  regs.set_al(number);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{drive_name_for_number, number_for_drive_name};
+
+  #[test]
+  fn drive_letters() {
+    assert_eq!(drive_name_for_number(0).unwrap(), "A");
+    assert_eq!(drive_name_for_number(2).unwrap(), "C");
+    assert_eq!(drive_name_for_number(25).unwrap(), "Z");
+    assert!(drive_name_for_number(26).is_none());
+
+    assert_eq!(number_for_drive_name("A"), Some(0));
+    assert_eq!(number_for_drive_name("c"), Some(2));
+    assert_eq!(number_for_drive_name("INIT"), None);
+    assert_eq!(number_for_drive_name("1"), None);
+    assert_eq!(number_for_drive_name(""), None);
+  }
+}
@@ -239,7 +239,7 @@ fn video_interrupt(regs: &mut DosApiRegisters) {
   let method = regs.ah();
   match method {
     0x00 => { // set video mode
-      crate::syscalls::hardware::change_video_mode(regs.al());
+      let _ = crate::syscalls::hardware::change_video_mode(regs.al());
     },
     0x01 => { // set cursor shape
     },
@@ -323,6 +323,9 @@ pub fn handle_page_fault(stack_frame: &StackFrame, address: usize) -> bool {
       vaddr.prev_page_barrier(),
       PermissionFlags::new(PermissionFlags::USER_ACCESS | PermissionFlags::WRITE_ACCESS),
     );
+    unsafe {
+      crate::memory::zero::zero_page(vaddr.prev_page_barrier());
+    }
     return true;
   }
   if address < 0xc0000 {
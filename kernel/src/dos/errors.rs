@@ -1,6 +1,7 @@
 use crate::interrupts::stack::StackFrame;
 use super::registers::{DosApiRegisters, VM86Frame};
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(u8)]
 pub enum DosError {
   InvalidFunction = 1,
@@ -1,9 +1,16 @@
+use super::drives::drive_id_for_number;
 use super::errors::DosError;
 use super::execution::{PSP, get_current_psp_segment};
 use super::memory::{SegmentedAddress, get_asciiz_string};
 use super::registers::{DosApiRegisters, VM86Frame};
+use super::state::DirectorySearches;
 use crate::files::handle::{FileHandle, Handle};
-use crate::task::io;
+use crate::files::wildcard::{FCB_NAME_LENGTH, WildcardPattern};
+use crate::task::{get_current_process, io};
+use crate::task::vm::Subsystem;
+use syscall::files::{DirEntryInfo, DirEntryType};
+use syscall::flags::FD_CLOEXEC;
+use syscall::result::SystemError;
 
 #[repr(C, packed)]
 pub struct FileControlBlock {
@@ -64,3 +71,229 @@ pub fn read_file(regs: &mut DosApiRegisters, segments: &mut VM86Frame) -> Result
   regs.ax = bytes_written as u32;
   Ok(())
 }
+
+/// Largest current directory DOS programs expect, including the terminator
+const MAX_DIRECTORY_LENGTH: usize = 64;
+
+/// Translate a failed directory operation into the closest DOS error
+fn directory_error(err: SystemError) -> DosError {
+  match err {
+    SystemError::NoSuchDrive => DosError::InvalidDrive,
+    SystemError::NoSuchEntity | SystemError::NotDirectory => DosError::PathNotFound,
+    SystemError::Busy => DosError::RemoveCurrentDir,
+    _ => DosError::AccessDenied,
+  }
+}
+
+fn path_from_ds_dx(regs: &DosApiRegisters, segments: &VM86Frame) -> &'static str {
+  let path_ptr = SegmentedAddress { segment: segments.ds as u16, offset: regs.dx as u16 };
+  unsafe { get_asciiz_string(path_ptr) }
+}
+
+/// AH=39h: Create the directory named by DS:DX
+pub fn make_directory(regs: &mut DosApiRegisters, segments: &mut VM86Frame) -> Result<(), DosError> {
+  io::make_directory(path_from_ds_dx(regs, segments)).map_err(directory_error)
+}
+
+/// AH=3Ah: Remove the empty directory named by DS:DX
+pub fn remove_directory(regs: &mut DosApiRegisters, segments: &mut VM86Frame) -> Result<(), DosError> {
+  io::remove_directory(path_from_ds_dx(regs, segments)).map_err(directory_error)
+}
+
+/// AH=3Bh: Change the current directory of the drive named by DS:DX
+pub fn change_directory(regs: &mut DosApiRegisters, segments: &mut VM86Frame) -> Result<(), DosError> {
+  io::change_directory(path_from_ds_dx(regs, segments)).map_err(directory_error)
+}
+
+/// AH=47h: Copy the current directory of the drive in DL (0 for the default,
+/// 1 for A:) to the 64-byte buffer at DS:SI. The path has no drive letter or
+/// leading backslash.
+pub fn get_current_directory(regs: &mut DosApiRegisters, segments: &mut VM86Frame) -> Result<(), DosError> {
+  let drive_id = match regs.dl() {
+    0 => crate::task::get_current_process().read().current_drive,
+    number => drive_id_for_number(number - 1).ok_or(DosError::InvalidDrive)?,
+  };
+  let directory = io::get_working_directory(drive_id).map_err(directory_error)?;
+  let dest_ptr = SegmentedAddress { segment: segments.ds as u16, offset: regs.si as u16 };
+  let dest = unsafe {
+    core::slice::from_raw_parts_mut(dest_ptr.as_address() as *mut u8, MAX_DIRECTORY_LENGTH)
+  };
+  let length = directory.len().min(MAX_DIRECTORY_LENGTH - 1);
+  for (dest_byte, source_byte) in dest.iter_mut().zip(directory.as_bytes()[..length].iter()) {
+    *dest_byte = source_byte.to_ascii_uppercase();
+  }
+  dest[length] = 0;
+  regs.ax = 0x0100;
+  Ok(())
+}
+
+fn get_dta() -> Result<SegmentedAddress, DosError> {
+  match &get_current_process().read().subsystem {
+    Subsystem::DOS(state) => Ok(state.dta),
+    Subsystem::Native => Err(DosError::InvalidEnvironment),
+  }
+}
+
+/// AH=1Ah: Point the Disk Transfer Area at DS:DX
+pub fn set_dta(regs: &mut DosApiRegisters, segments: &mut VM86Frame) -> Result<(), DosError> {
+  match &mut get_current_process().write().subsystem {
+    Subsystem::DOS(state) => {
+      state.dta = SegmentedAddress { segment: segments.ds as u16, offset: regs.dx as u16 };
+      Ok(())
+    },
+    Subsystem::Native => Err(DosError::InvalidEnvironment),
+  }
+}
+
+/// AH=2Fh: Return the address of the Disk Transfer Area in ES:BX
+pub fn get_dta_address(regs: &mut DosApiRegisters, segments: &mut VM86Frame) -> Result<(), DosError> {
+  let dta = get_dta()?;
+  segments.es = dta.segment as u32;
+  regs.bx = dta.offset as u32;
+  Ok(())
+}
+
+/// Attribute bit requesting that directories be included in a search
+const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+
+/// Layout of the DTA during a find first / find next search. The first 21
+/// bytes are reserved for DOS, and are used to carry the search from one call
+/// to the next: the expanded pattern, and whether it has run out. The open
+/// directory itself is tracked in the process's VM state.
+#[repr(C, packed)]
+pub struct FindData {
+  drive: u8,
+  template: [u8; FCB_NAME_LENGTH],
+  search_attributes: u8,
+  exhausted: u8,
+  reserved: [u8; 7],
+  attributes: u8,
+  time: u16,
+  date: u16,
+  size: u32,
+  name: [u8; 13],
+}
+
+impl FindData {
+  unsafe fn at_address(addr: SegmentedAddress) -> &'static mut FindData {
+    &mut *(addr.as_address() as *mut FindData)
+  }
+
+  fn set_entry(&mut self, entry: &DirEntryInfo) {
+    self.attributes = match entry.entry_type {
+      DirEntryType::Directory => ATTRIBUTE_DIRECTORY,
+      _ => 0,
+    };
+    self.time = 0;
+    self.date = 0;
+    self.size = entry.byte_size as u32;
+    // The name is written as ASCIIZ NAME.EXT, without any padding
+    let mut name = [0; 13];
+    let mut length = 0;
+    for ch in entry.file_name.iter().take_while(|ch| **ch != 0x20) {
+      name[length] = *ch;
+      length += 1;
+    }
+    if entry.file_ext[0] != 0x20 {
+      name[length] = b'.';
+      length += 1;
+      for ch in entry.file_ext.iter().take_while(|ch| **ch != 0x20) {
+        name[length] = *ch;
+        length += 1;
+      }
+    }
+    self.name = name;
+  }
+}
+
+/// Split a search path like `C:\DOS\*.COM` into the directory to open and the
+/// wildcard pattern to match against its entries
+fn split_search_path(path: &str) -> (&str, &str) {
+  match path.rfind(|ch| ch == '\\' || ch == '/' || ch == ':') {
+    Some(index) => {
+      let is_root = index == 0 || path.as_bytes()[index] == b':' || path.as_bytes()[index - 1] == b':';
+      let directory = if is_root { &path[..index + 1] } else { &path[..index] };
+      (directory, &path[index + 1..])
+    },
+    None => ("", path),
+  }
+}
+
+/// Run a function on the directory searches of the current DOS process
+fn with_searches<F, T>(f: F) -> Result<T, DosError>
+  where F: FnOnce(&mut DirectorySearches) -> T {
+  match &mut get_current_process().write().subsystem {
+    Subsystem::DOS(state) => Ok(f(&mut state.searches)),
+    Subsystem::Native => Err(DosError::InvalidEnvironment),
+  }
+}
+
+/// Continue the search using the DTA at `dta`, copying the next match into
+/// it. When the directory runs out, its handle is closed.
+fn find_next_entry(dta: SegmentedAddress) -> Result<(), DosError> {
+  let find_data = unsafe { FindData::at_address(dta) };
+  if find_data.exhausted != 0 {
+    return Err(DosError::NoMoreFiles);
+  }
+  // The search may have been closed to make room for newer ones
+  let handle = with_searches(|searches| searches.get(dta.as_address()))?
+    .ok_or(DosError::NoMoreFiles)?;
+  let pattern = WildcardPattern::from_fcb(find_data.template);
+  let include_directories = find_data.search_attributes & ATTRIBUTE_DIRECTORY != 0;
+  let mut entry = DirEntryInfo::empty();
+  let result = io::find_matching_entry(
+    handle,
+    &pattern,
+    |entry| match entry.entry_type {
+      DirEntryType::Directory => include_directories,
+      _ => true,
+    },
+    &mut entry,
+  );
+  let has_more = result.unwrap_or(false);
+  if !has_more {
+    find_data.exhausted = 1;
+    if let Some(finished) = with_searches(|searches| searches.finish(dta.as_address()))? {
+      let _ = io::close_file(finished);
+    }
+  }
+  if entry.is_empty() {
+    return Err(DosError::NoMoreFiles);
+  }
+  find_data.set_entry(&entry);
+  Ok(())
+}
+
+/// AH=4Eh: Begin a search for the files matching the wildcard path at DS:DX,
+/// with the attributes in CX. The first match is written to the DTA.
+/// Any search that was already using the same DTA is abandoned.
+pub fn find_first(regs: &mut DosApiRegisters, segments: &mut VM86Frame) -> Result<(), DosError> {
+  let dta = get_dta()?;
+  let (directory_path, pattern) = split_search_path(path_from_ds_dx(regs, segments));
+  let handle = io::open_directory(directory_path).map_err(directory_error)?;
+  // Search handles are private to DOS, and shouldn't outlive the program
+  get_current_process().write().set_descriptor_flags(handle, FD_CLOEXEC);
+  let replaced = with_searches(|searches| searches.start(dta.as_address(), handle));
+  let replaced = match replaced {
+    Ok(replaced) => replaced,
+    Err(err) => {
+      let _ = io::close_file(handle);
+      return Err(err);
+    },
+  };
+  for abandoned in replaced {
+    let _ = io::close_file(abandoned);
+  }
+  let find_data = unsafe { FindData::at_address(dta) };
+  find_data.drive = 0;
+  find_data.template = *WildcardPattern::parse(pattern).as_fcb();
+  find_data.search_attributes = regs.cl();
+  find_data.exhausted = 0;
+  find_next_entry(dta)
+}
+
+/// AH=4Fh: Find the next file matching the search started by find first, using
+/// the state stored in the DTA
+pub fn find_next(_regs: &mut DosApiRegisters, _segments: &mut VM86Frame) -> Result<(), DosError> {
+  find_next_entry(get_dta()?)
+}
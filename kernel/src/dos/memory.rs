@@ -1,3 +1,6 @@
+use super::errors::DosError;
+use super::execution::get_current_psp_segment;
+use super::registers::{DosApiRegisters, VM86Frame};
 
 /// 16-bit code addresses memory using segments
 #[repr(C, packed)]
@@ -45,4 +48,376 @@ pub unsafe fn get_asciiz_string(addr: SegmentedAddress) -> &'static str {
   }
   let slice = core::slice::from_raw_parts(start_ptr, length);
   core::str::from_utf8_unchecked(slice)
-}
\ No newline at end of file
+}
+
+/// Conventional memory ends where video memory begins
+pub const CONVENTIONAL_MEMORY_END: u16 = 0xa000;
+
+/// Signature of every MCB except the last one in the chain
+pub const MCB_MIDDLE: u8 = b'M';
+/// Signature of the final MCB in the chain
+pub const MCB_LAST: u8 = b'Z';
+
+/// DOS tracks conventional memory as a chain of Memory Control Blocks. Each
+/// MCB occupies the paragraph immediately before the block it describes, and
+/// the next MCB immediately follows the end of that block.
+#[repr(C, packed)]
+pub struct MemoryControlBlock {
+  pub signature: u8,
+  /// PSP segment of the owning program, or 0 if the block is free
+  pub owner: u16,
+  /// Size of the block in paragraphs, not counting the MCB itself
+  pub size: u16,
+  _reserved: [u8; 3],
+  pub name: [u8; 8],
+}
+
+impl MemoryControlBlock {
+  pub fn is_valid(&self) -> bool {
+    self.signature == MCB_MIDDLE || self.signature == MCB_LAST
+  }
+
+  pub fn is_last(&self) -> bool {
+    self.signature == MCB_LAST
+  }
+
+  pub fn is_free(&self) -> bool {
+    self.owner == 0
+  }
+}
+
+/// The region of conventional memory managed by an MCB chain. Blocks are
+/// handed out first-fit, and adjacent free blocks are merged as the chain is
+/// walked.
+pub struct MemoryArena {
+  /// Linear address of segment 0. Inside a DOS VM this is always 0.
+  base: usize,
+  /// Segment of the first MCB in the chain
+  first: u16,
+  /// First segment beyond the end of the arena
+  end: u16,
+}
+
+impl MemoryArena {
+  pub fn new(first: u16, end: u16) -> MemoryArena {
+    MemoryArena {
+      base: 0,
+      first,
+      end,
+    }
+  }
+
+  /// The arena for a top-level program begins with the MCB directly before
+  /// its PSP, and extends to the end of conventional memory. A PSP at segment
+  /// 0 leaves no room for that MCB.
+  pub fn for_psp(psp_segment: u16) -> Result<MemoryArena, DosError> {
+    let first = psp_segment.checked_sub(1).ok_or(DosError::InvalidEnvironment)?;
+    Ok(MemoryArena::new(first, CONVENTIONAL_MEMORY_END))
+  }
+
+  fn mcb_at(&self, segment: u16) -> &mut MemoryControlBlock {
+    let address = self.base + ((segment as usize) << 4);
+    unsafe { &mut *(address as *mut MemoryControlBlock) }
+  }
+
+  /// Fetch an MCB, making sure it hasn't been corrupted and fits in the arena
+  fn checked_mcb_at(&self, segment: u16) -> Result<&mut MemoryControlBlock, DosError> {
+    let mcb = self.mcb_at(segment);
+    let block_end = segment as usize + 1 + mcb.size as usize;
+    if !mcb.is_valid() || block_end > self.end as usize {
+      return Err(DosError::MCBDestroyed);
+    }
+    if !mcb.is_last() && block_end >= self.end as usize {
+      return Err(DosError::MCBDestroyed);
+    }
+    Ok(mcb)
+  }
+
+  fn write_mcb(&self, segment: u16, signature: u8, owner: u16, size: u16) {
+    let mcb = self.mcb_at(segment);
+    mcb.signature = signature;
+    mcb.owner = owner;
+    mcb.size = size;
+    mcb._reserved = [0; 3];
+    mcb.name = [0; 8];
+  }
+
+  /// Reset the chain to a single block spanning the whole arena. An owner of
+  /// 0 leaves it free; a newly loaded program is typically given everything.
+  pub fn init(&self, owner: u16) {
+    self.write_mcb(self.first, MCB_LAST, owner, self.end - self.first - 1);
+  }
+
+  /// Absorb any free blocks that directly follow a free block
+  fn merge_following_free(&self, segment: u16) -> Result<(), DosError> {
+    loop {
+      let mcb = self.checked_mcb_at(segment)?;
+      if !mcb.is_free() || mcb.is_last() {
+        return Ok(());
+      }
+      let next_segment = segment + mcb.size + 1;
+      let next = self.checked_mcb_at(next_segment)?;
+      if !next.is_free() {
+        return Ok(());
+      }
+      let (next_signature, next_size) = (next.signature, next.size);
+      let mcb = self.mcb_at(segment);
+      mcb.size += next_size + 1;
+      mcb.signature = next_signature;
+    }
+  }
+
+  /// Walk the chain, calling a function with the segment of each MCB. Free
+  /// blocks are merged before they are visited. Walking stops early if the
+  /// function returns Some.
+  fn walk<F, R>(&self, mut f: F) -> Result<Option<R>, DosError>
+    where F: FnMut(u16, &mut MemoryControlBlock) -> Option<R> {
+    let mut segment = self.first;
+    loop {
+      self.merge_following_free(segment)?;
+      let mcb = self.checked_mcb_at(segment)?;
+      if let Some(result) = f(segment, mcb) {
+        return Ok(Some(result));
+      }
+      if mcb.is_last() {
+        return Ok(None);
+      }
+      segment = segment + mcb.size + 1;
+    }
+  }
+
+  /// Split a block so that it is exactly `paragraphs` long, turning the
+  /// remainder into a new free block
+  fn split(&self, segment: u16, paragraphs: u16) {
+    let mcb = self.mcb_at(segment);
+    if mcb.size <= paragraphs {
+      return;
+    }
+    let remainder_segment = segment + paragraphs + 1;
+    let remainder_size = mcb.size - paragraphs - 1;
+    let signature = mcb.signature;
+    mcb.size = paragraphs;
+    mcb.signature = MCB_MIDDLE;
+    self.write_mcb(remainder_segment, signature, 0, remainder_size);
+  }
+
+  /// Size of the largest free block, in paragraphs
+  pub fn largest_free_block(&self) -> Result<u16, DosError> {
+    let mut largest = 0;
+    self.walk(|_, mcb| -> Option<()> {
+      if mcb.is_free() && mcb.size > largest {
+        largest = mcb.size;
+      }
+      None
+    })?;
+    Ok(largest)
+  }
+
+  /// Find the MCB describing the block that starts at a segment
+  fn find_block(&self, block_segment: u16) -> Result<u16, DosError> {
+    let found = self.walk(|segment, _| {
+      if segment + 1 == block_segment {
+        Some(segment)
+      } else {
+        None
+      }
+    })?;
+    found.ok_or(DosError::InvalidMemoryBlock)
+  }
+
+  /// Allocate a block of memory for an owner, returning the segment where the
+  /// block begins. On failure, the error is paired with the size of the
+  /// largest block that could have been allocated.
+  pub fn allocate(&self, owner: u16, paragraphs: u16) -> Result<u16, (DosError, u16)> {
+    let found = self.walk(|segment, mcb| {
+      if mcb.is_free() && mcb.size >= paragraphs {
+        Some(segment)
+      } else {
+        None
+      }
+    }).map_err(|err| (err, 0))?;
+    let segment = match found {
+      Some(segment) => segment,
+      None => {
+        let largest = self.largest_free_block().map_err(|err| (err, 0))?;
+        return Err((DosError::InsufficientMemory, largest));
+      },
+    };
+    self.split(segment, paragraphs);
+    self.mcb_at(segment).owner = owner;
+    Ok(segment + 1)
+  }
+
+  /// Release a block that was previously allocated
+  pub fn free(&self, block_segment: u16) -> Result<(), DosError> {
+    let segment = self.find_block(block_segment)?;
+    let mcb = self.mcb_at(segment);
+    if mcb.is_free() {
+      return Err(DosError::InvalidMemoryBlock);
+    }
+    mcb.owner = 0;
+    self.merge_following_free(segment)
+  }
+
+  /// Grow or shrink an allocated block in place. If it can't grow, the error
+  /// is paired with the largest size the block could be resized to.
+  pub fn resize(&self, block_segment: u16, paragraphs: u16) -> Result<(), (DosError, u16)> {
+    let segment = self.find_block(block_segment).map_err(|err| (err, 0))?;
+    let (size, is_last) = {
+      let mcb = self.mcb_at(segment);
+      (mcb.size, mcb.is_last())
+    };
+    if paragraphs <= size {
+      self.split(segment, paragraphs);
+      let remainder = segment + paragraphs + 1;
+      if paragraphs < size {
+        self.merge_following_free(remainder).map_err(|err| (err, 0))?;
+      }
+      return Ok(());
+    }
+    // Growing is only possible by absorbing a free block that follows
+    let mut available = size;
+    if !is_last {
+      let next_segment = segment + size + 1;
+      self.merge_following_free(next_segment).map_err(|err| (err, 0))?;
+      let next = self.checked_mcb_at(next_segment).map_err(|err| (err, 0))?;
+      if next.is_free() {
+        available += next.size + 1;
+        if available >= paragraphs {
+          let next_signature = next.signature;
+          let mcb = self.mcb_at(segment);
+          mcb.size = available;
+          mcb.signature = next_signature;
+          self.split(segment, paragraphs);
+          return Ok(());
+        }
+      }
+    }
+    Err((DosError::InsufficientMemory, available))
+  }
+}
+
+/// int 21, 48 - Allocate memory
+/// BX contains the number of paragraphs requested. On success, AX contains the
+/// segment of the new block. On failure, BX contains the largest block size
+/// available.
+pub fn allocate_memory(regs: &mut DosApiRegisters, _segments: &mut VM86Frame) -> Result<(), DosError> {
+  let psp_segment = get_current_psp_segment().ok_or(DosError::InvalidEnvironment)?;
+  let arena = MemoryArena::for_psp(psp_segment)?;
+  match arena.allocate(psp_segment, regs.bx as u16) {
+    Ok(block) => {
+      regs.ax = block as u32;
+      Ok(())
+    },
+    Err((err, largest)) => {
+      regs.bx = largest as u32;
+      Err(err)
+    },
+  }
+}
+
+/// int 21, 49 - Free memory
+/// ES contains the segment of the block to free
+pub fn free_memory(_regs: &mut DosApiRegisters, segments: &mut VM86Frame) -> Result<(), DosError> {
+  let psp_segment = get_current_psp_segment().ok_or(DosError::InvalidEnvironment)?;
+  let arena = MemoryArena::for_psp(psp_segment)?;
+  arena.free(segments.es as u16)
+}
+
+/// int 21, 4A - Modify allocated memory
+/// ES contains the segment of the block, and BX contains the new size in
+/// paragraphs. On failure, BX contains the largest size the block can take.
+pub fn resize_memory(regs: &mut DosApiRegisters, segments: &mut VM86Frame) -> Result<(), DosError> {
+  let psp_segment = get_current_psp_segment().ok_or(DosError::InvalidEnvironment)?;
+  let arena = MemoryArena::for_psp(psp_segment)?;
+  match arena.resize(segments.es as u16, regs.bx as u16) {
+    Ok(_) => Ok(()),
+    Err((err, largest)) => {
+      regs.bx = largest as u32;
+      Err(err)
+    },
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+  use super::{DosError, MemoryArena, MCB_LAST, MCB_MIDDLE};
+
+  /// Back an arena with heap memory. Segment 0 maps to the start of the
+  /// buffer, so it must cover every segment up to the end of the arena.
+  fn test_arena(memory: &mut Vec<u8>, first: u16, end: u16) -> MemoryArena {
+    memory.resize((end as usize) << 4, 0);
+    MemoryArena {
+      base: memory.as_ptr() as usize,
+      first,
+      end,
+    }
+  }
+
+  /// Copy the fields out of an MCB, since packed fields can't be borrowed
+  fn mcb_fields(arena: &MemoryArena, segment: u16) -> (u8, u16, u16) {
+    let mcb = arena.mcb_at(segment);
+    (mcb.signature, mcb.owner, mcb.size)
+  }
+
+  #[test]
+  fn allocate_resize_free() {
+    let mut memory = Vec::new();
+    let arena = test_arena(&mut memory, 0x10, 0x110);
+    arena.init(0);
+    assert_eq!(arena.largest_free_block(), Ok(0xff));
+
+    let first = arena.allocate(0x20, 0x40).unwrap();
+    assert_eq!(first, 0x11);
+    assert_eq!(mcb_fields(&arena, 0x10).0, MCB_MIDDLE);
+    assert_eq!(mcb_fields(&arena, 0x10).1, 0x20);
+    let second = arena.allocate(0x20, 0x10).unwrap();
+    assert_eq!(second, 0x52);
+    // The remainder stays at the end of the chain
+    assert_eq!(mcb_fields(&arena, 0x62).0, MCB_LAST);
+    assert_eq!(arena.largest_free_block(), Ok(0xad));
+
+    // Requests that are too large report the largest available block
+    assert_eq!(arena.allocate(0x20, 0xf0), Err((DosError::InsufficientMemory, 0xad)));
+
+    // The first block can't grow past the second one
+    assert_eq!(arena.resize(first, 0x50), Err((DosError::InsufficientMemory, 0x40)));
+    arena.resize(first, 0x20).unwrap();
+    assert_eq!(mcb_fields(&arena, 0x31).1, 0);
+    assert_eq!(mcb_fields(&arena, 0x31).2, 0x1f);
+    // It can grow back into the space it released, but no further
+    assert_eq!(arena.resize(first, 0x50), Err((DosError::InsufficientMemory, 0x40)));
+    arena.resize(first, 0x38).unwrap();
+    assert_eq!(mcb_fields(&arena, 0x49), (MCB_MIDDLE, 0, 7));
+
+    // The second block can grow into the free space after it
+    arena.resize(second, 0x80).unwrap();
+    assert_eq!(mcb_fields(&arena, 0xd2).0, MCB_LAST);
+
+    arena.free(second).unwrap();
+    arena.free(first).unwrap();
+    assert_eq!(arena.free(first), Err(DosError::InvalidMemoryBlock));
+    assert_eq!(arena.free(0x30), Err(DosError::InvalidMemoryBlock));
+    // Freeing everything merges the chain back into one block
+    assert_eq!(arena.largest_free_block(), Ok(0xff));
+    assert_eq!(mcb_fields(&arena, 0x10).0, MCB_LAST);
+  }
+
+  #[test]
+  fn psp_needs_room_for_mcb() {
+    assert_eq!(MemoryArena::for_psp(0).err(), Some(DosError::InvalidEnvironment));
+    assert_eq!(MemoryArena::for_psp(0x80).unwrap().first, 0x7f);
+  }
+
+  #[test]
+  fn detects_corrupt_chain() {
+    let mut memory = Vec::new();
+    let arena = test_arena(&mut memory, 0x10, 0x110);
+    arena.init(0);
+    let block = arena.allocate(0x20, 0x40).unwrap();
+    arena.mcb_at(0x51).signature = 0;
+    assert_eq!(arena.largest_free_block(), Err(DosError::MCBDestroyed));
+    assert_eq!(arena.free(block), Err(DosError::MCBDestroyed));
+  }
+}
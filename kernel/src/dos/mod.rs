@@ -2,6 +2,7 @@
 //! well as methods to manipulate the current VM process space.
 
 pub mod devices;
+pub mod drives;
 #[cfg(not(test))]
 pub mod emulation;
 pub mod errors;
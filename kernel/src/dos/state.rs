@@ -1,6 +1,16 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use crate::files::handle::FileHandle;
+use super::memory::SegmentedAddress;
+
 /// Stores the emulated state of a DOS VM
 pub struct VMState {
   pub current_psp: u16,
+  /// Disk Transfer Area, used by FCB IO and directory searches. It starts out
+  /// in the second half of the PSP.
+  pub dta: SegmentedAddress,
+  /// Directories being read by find first / find next
+  pub searches: DirectorySearches,
 }
 
 impl VMState {
@@ -8,6 +18,87 @@ impl VMState {
 
     Self {
       current_psp: 0x100,
+      dta: SegmentedAddress { segment: 0x100, offset: 0x80 },
+      searches: DirectorySearches::new(),
+    }
+  }
+}
+
+/// Most directory searches a program can have in progress at once. Programs
+/// are free to abandon a search without telling DOS, so once this many are
+/// open, the oldest one is closed to make room.
+pub const MAX_SEARCHES: usize = 8;
+
+/// The directory handles held open by find first / find next. Each search is
+/// identified by the address of the DTA it reports into, so starting a new
+/// search over the same DTA replaces the old one.
+pub struct DirectorySearches {
+  searches: VecDeque<(usize, FileHandle)>,
+}
+
+impl DirectorySearches {
+  pub fn new() -> Self {
+    Self {
+      searches: VecDeque::with_capacity(MAX_SEARCHES),
     }
   }
-}
\ No newline at end of file
+
+  /// Record a search reading `directory` into the DTA at `dta`. Returns the
+  /// directories of any searches it replaced, which the caller must close.
+  pub fn start(&mut self, dta: usize, directory: FileHandle) -> Vec<FileHandle> {
+    let mut replaced: Vec<FileHandle> = self.finish(dta).into_iter().collect();
+    if self.searches.len() >= MAX_SEARCHES {
+      replaced.extend(self.searches.pop_front().map(|(_, handle)| handle));
+    }
+    self.searches.push_back((dta, directory));
+    replaced
+  }
+
+  /// Find the directory being read by the search using the DTA at `dta`
+  pub fn get(&self, dta: usize) -> Option<FileHandle> {
+    self.searches.iter().find(|(address, _)| *address == dta).map(|(_, handle)| *handle)
+  }
+
+  /// Forget the search using the DTA at `dta`, returning its directory so that
+  /// it can be closed
+  pub fn finish(&mut self, dta: usize) -> Option<FileHandle> {
+    let index = self.searches.iter().position(|(address, _)| *address == dta)?;
+    self.searches.remove(index).map(|(_, handle)| handle)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::files::handle::{FileHandle, Handle};
+  use super::{DirectorySearches, MAX_SEARCHES};
+
+  #[test]
+  fn searches_are_replaced() {
+    let mut searches = DirectorySearches::new();
+    assert!(searches.start(0x1080, FileHandle::new(3)).is_empty());
+    assert!(searches.start(0x2000, FileHandle::new(4)).is_empty());
+    assert_eq!(searches.get(0x1080), Some(FileHandle::new(3)));
+
+    // Starting over in the same DTA closes the abandoned search
+    assert_eq!(searches.start(0x1080, FileHandle::new(5)), [FileHandle::new(3)]);
+    assert_eq!(searches.get(0x1080), Some(FileHandle::new(5)));
+
+    assert_eq!(searches.finish(0x2000), Some(FileHandle::new(4)));
+    assert_eq!(searches.get(0x2000), None);
+    assert_eq!(searches.finish(0x2000), None);
+  }
+
+  #[test]
+  fn abandoned_searches_are_bounded() {
+    let mut searches = DirectorySearches::new();
+    for i in 0..MAX_SEARCHES {
+      assert!(searches.start(0x1000 + i * 0x80, FileHandle::new(i as u32)).is_empty());
+    }
+    // The oldest search makes room for a new one
+    let replaced = searches.start(0x8000, FileHandle::new(20));
+    assert_eq!(replaced, [FileHandle::new(0)]);
+    assert_eq!(searches.get(0x1000), None);
+    assert_eq!(searches.get(0x1080), Some(FileHandle::new(1)));
+    assert_eq!(searches.get(0x8000), Some(FileHandle::new(20)));
+  }
+}
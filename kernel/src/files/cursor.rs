@@ -1,3 +1,6 @@
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 pub enum SeekMethod {
   Absolute(usize),
   Relative(isize),
@@ -11,3 +14,36 @@ impl SeekMethod {
     }
   }
 }
+
+/// The read/write position of an open file. A forked child's handles refer to
+/// the same open file as its parent's, so cloning a cursor shares it: reading
+/// or seeking through either handle moves both. Use `detach` to start an
+/// independent cursor at the same position.
+#[derive(Clone)]
+pub struct SharedCursor(Arc<AtomicUsize>);
+
+impl SharedCursor {
+  pub fn new(position: usize) -> SharedCursor {
+    SharedCursor(Arc::new(AtomicUsize::new(position)))
+  }
+
+  pub fn get(&self) -> usize {
+    self.0.load(Ordering::SeqCst)
+  }
+
+  pub fn set(&self, position: usize) {
+    self.0.store(position, Ordering::SeqCst);
+  }
+
+  pub fn seek(&self, method: SeekMethod) -> usize {
+    let position = method.from_current_position(self.get());
+    self.set(position);
+    position
+  }
+
+  /// Copy the current position into a new cursor that is not shared with
+  /// this one
+  pub fn detach(&self) -> SharedCursor {
+    SharedCursor::new(self.get())
+  }
+}
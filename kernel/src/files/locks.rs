@@ -0,0 +1,350 @@
+//! Advisory file locks, as used by `flock`. A lock belongs to an open file
+//! rather than to a process: descriptors duplicated with `dup` use the same
+//! local handle and share it automatically, and a forked child's copy of a
+//! handle is added to the same lock. The lock is released by an explicit
+//! unlock through any of them, or once every handle sharing it has closed.
+//! Locks are only advisory. Nothing stops a process from reading or writing a
+//! file without taking one first.
+//! Files are identified by their drive, and an identifier chosen by the
+//! filesystem that stays the same no matter how many times the file is
+//! opened.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::files::handle::LocalHandle;
+use crate::fs::drive::DriveID;
+use crate::task::id::ProcessID;
+use crate::locks::Mutex;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LockMode {
+  Shared,
+  Exclusive,
+}
+
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct FileKey {
+  pub drive: DriveID,
+  pub file: usize,
+}
+
+/// A handle that holds, or is waiting for, a lock
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct LockOwner {
+  pub drive: DriveID,
+  pub handle: LocalHandle,
+  pub process: ProcessID,
+}
+
+impl LockOwner {
+  fn is_handle(&self, drive: DriveID, handle: LocalHandle) -> bool {
+    self.drive == drive && self.handle == handle
+  }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LockError {
+  /// The lock is held by someone else, and the caller asked not to wait
+  WouldBlock,
+  /// Waiting would never finish, because a process holding the lock is
+  /// itself waiting on a lock held by the caller
+  Deadlock,
+}
+
+/// One lock on a file, shared by every handle to the same open file
+struct Holder {
+  owners: Vec<LockOwner>,
+  mode: LockMode,
+}
+
+struct Request {
+  file: FileKey,
+  owner: LockOwner,
+  mode: LockMode,
+}
+
+struct LockTable {
+  files: BTreeMap<FileKey, Vec<Holder>>,
+  /// The request each blocked process is waiting on
+  waiting: BTreeMap<ProcessID, Request>,
+}
+
+impl LockTable {
+  /// Find the processes whose locks prevent a request from being granted.
+  /// The owner's own lock never conflicts, so that it can be converted
+  /// between shared and exclusive.
+  fn blockers(&self, request: &Request) -> Vec<ProcessID> {
+    let holders = match self.files.get(&request.file) {
+      Some(holders) => holders,
+      None => return Vec::new(),
+    };
+    holders
+      .iter()
+      .filter(|holder| !holder.owners.iter().any(|o| o.is_handle(request.owner.drive, request.owner.handle)))
+      .filter(|holder| request.mode == LockMode::Exclusive || holder.mode == LockMode::Exclusive)
+      .flat_map(|holder| holder.owners.iter().map(|o| o.process))
+      .collect()
+  }
+
+  fn try_acquire(&mut self, request: &Request) -> Result<(), LockError> {
+    if !self.blockers(request).is_empty() {
+      return Err(LockError::WouldBlock);
+    }
+    let owner = request.owner;
+    let holders = self.files.entry(request.file).or_insert_with(Vec::new);
+    let existing = holders
+      .iter_mut()
+      .find(|holder| holder.owners.iter().any(|o| o.is_handle(owner.drive, owner.handle)));
+    match existing {
+      Some(holder) => holder.mode = request.mode,
+      None => holders.push(Holder { owners: vec![owner], mode: request.mode }),
+    }
+    Ok(())
+  }
+
+  /// Follow the chain of processes waiting on each other, starting from the
+  /// ones blocking this request. If it leads back to the requester, waiting
+  /// would deadlock.
+  fn would_deadlock(&self, request: &Request) -> bool {
+    let requester = request.owner.process;
+    let mut visited = BTreeSet::new();
+    let mut pending = self.blockers(request);
+    while let Some(process) = pending.pop() {
+      if process == requester {
+        return true;
+      }
+      if !visited.insert(process) {
+        continue;
+      }
+      if let Some(waiting_on) = self.waiting.get(&process) {
+        pending.extend(self.blockers(waiting_on));
+      }
+    }
+    false
+  }
+
+  /// Remove owners matching a predicate, dropping any lock left without one
+  fn remove_owners<F>(&mut self, matches: F)
+    where F: Fn(&LockOwner) -> bool {
+    for holders in self.files.values_mut() {
+      for holder in holders.iter_mut() {
+        holder.owners.retain(|owner| !matches(owner));
+      }
+      holders.retain(|holder| !holder.owners.is_empty());
+    }
+    self.files.retain(|_, holders| !holders.is_empty());
+  }
+}
+
+pub struct FileLocks {
+  table: Mutex<LockTable>,
+}
+
+impl FileLocks {
+  pub const fn new() -> Self {
+    Self {
+      table: Mutex::new(LockTable {
+        files: BTreeMap::new(),
+        waiting: BTreeMap::new(),
+      }),
+    }
+  }
+
+  /// Take a lock, or convert the owner's existing lock to a new mode, without
+  /// waiting
+  pub fn try_lock(&self, file: FileKey, owner: LockOwner, mode: LockMode) -> Result<(), LockError> {
+    self.table.lock().try_acquire(&Request { file, owner, mode })
+  }
+
+  /// Take a lock, calling `wait` to give up the CPU each time it is still
+  /// held by someone else. Fails instead of waiting if the holders are
+  /// themselves waiting on the caller. A shared lock being upgraded stays
+  /// shared while waiting.
+  pub fn lock<W>(&self, file: FileKey, owner: LockOwner, mode: LockMode, wait: W) -> Result<(), LockError>
+    where W: Fn() {
+    loop {
+      {
+        let mut table = self.table.lock();
+        let request = Request { file, owner, mode };
+        match table.try_acquire(&request) {
+          Ok(_) => {
+            table.waiting.remove(&owner.process);
+            return Ok(());
+          },
+          Err(_) => {
+            if table.would_deadlock(&request) {
+              table.waiting.remove(&owner.process);
+              return Err(LockError::Deadlock);
+            }
+            table.waiting.insert(owner.process, request);
+          },
+        }
+      }
+      wait();
+    }
+  }
+
+  /// Release the lock held through a handle, along with every other handle
+  /// sharing it
+  pub fn unlock(&self, file: FileKey, drive: DriveID, handle: LocalHandle) {
+    let mut table = self.table.lock();
+    if let Some(holders) = table.files.get_mut(&file) {
+      holders.retain(|holder| !holder.owners.iter().any(|o| o.is_handle(drive, handle)));
+      if holders.is_empty() {
+        table.files.remove(&file);
+      }
+    }
+  }
+
+  /// Give a copy of a handle, made for a forked child, a share of the
+  /// original handle's locks
+  pub fn share_with(&self, drive: DriveID, handle: LocalHandle, copy: LockOwner) {
+    let mut table = self.table.lock();
+    for holders in table.files.values_mut() {
+      for holder in holders.iter_mut() {
+        if holder.owners.iter().any(|o| o.is_handle(drive, handle)) {
+          holder.owners.push(copy);
+        }
+      }
+    }
+  }
+
+  /// Called when a handle is closed. Its locks are released, unless other
+  /// handles still share them.
+  pub fn release_handle(&self, drive: DriveID, handle: LocalHandle) {
+    self.table.lock().remove_owners(|owner| owner.is_handle(drive, handle));
+  }
+
+  /// Called when a process exits, releasing its share of every lock and
+  /// abandoning any lock it was waiting for
+  pub fn release_process(&self, process: ProcessID) {
+    let mut table = self.table.lock();
+    table.waiting.remove(&process);
+    table.remove_owners(|owner| owner.process == process);
+  }
+
+  /// Find the mode of the lock held through a handle
+  pub fn get_mode(&self, file: FileKey, drive: DriveID, handle: LocalHandle) -> Option<LockMode> {
+    let table = self.table.lock();
+    table.files.get(&file)?
+      .iter()
+      .find(|holder| holder.owners.iter().any(|o| o.is_handle(drive, handle)))
+      .map(|holder| holder.mode)
+  }
+}
+
+pub static LOCKS: FileLocks = FileLocks::new();
+
+#[cfg(test)]
+mod tests {
+  use crate::files::handle::{Handle, LocalHandle};
+  use crate::fs::drive::DriveID;
+  use crate::task::id::ProcessID;
+  use super::{FileKey, FileLocks, LockError, LockMode, LockOwner};
+
+  fn file(file: usize) -> FileKey {
+    FileKey { drive: DriveID::new(4), file }
+  }
+
+  fn owner(handle: u32, process: u32) -> LockOwner {
+    LockOwner {
+      drive: DriveID::new(4),
+      handle: LocalHandle::new(handle),
+      process: ProcessID::new(process),
+    }
+  }
+
+  #[test]
+  fn shared_and_exclusive() {
+    let locks = FileLocks::new();
+    let first = owner(1, 10);
+    let second = owner(2, 11);
+    locks.try_lock(file(12), first, LockMode::Shared).unwrap();
+    locks.try_lock(file(12), second, LockMode::Shared).unwrap();
+    // Upgrading has to wait for the other reader
+    assert_eq!(locks.try_lock(file(12), first, LockMode::Exclusive), Err(LockError::WouldBlock));
+    assert_eq!(locks.get_mode(file(12), first.drive, first.handle), Some(LockMode::Shared));
+    locks.unlock(file(12), second.drive, second.handle);
+    locks.try_lock(file(12), first, LockMode::Exclusive).unwrap();
+    assert_eq!(locks.try_lock(file(12), second, LockMode::Shared), Err(LockError::WouldBlock));
+    // Downgrading lets readers back in
+    locks.try_lock(file(12), first, LockMode::Shared).unwrap();
+    locks.try_lock(file(12), second, LockMode::Shared).unwrap();
+    // Other files are unaffected
+    locks.try_lock(file(13), second, LockMode::Exclusive).unwrap();
+  }
+
+  #[test]
+  fn fork_shares_lock() {
+    let locks = FileLocks::new();
+    let parent = owner(1, 10);
+    let child = owner(2, 11);
+    let stranger = owner(3, 12);
+    locks.try_lock(file(12), parent, LockMode::Exclusive).unwrap();
+    locks.share_with(parent.drive, parent.handle, child);
+    assert_eq!(locks.get_mode(file(12), child.drive, child.handle), Some(LockMode::Exclusive));
+
+    // The lock outlives the parent's handle, as long as the child's is open
+    locks.release_handle(parent.drive, parent.handle);
+    assert_eq!(locks.try_lock(file(12), stranger, LockMode::Shared), Err(LockError::WouldBlock));
+    locks.release_process(child.process);
+    locks.try_lock(file(12), stranger, LockMode::Shared).unwrap();
+  }
+
+  #[test]
+  fn exclusive_contention() {
+    extern crate std;
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    let locks = Arc::new(FileLocks::new());
+    let writer = owner(1, 10);
+    locks.try_lock(file(12), writer, LockMode::Exclusive).unwrap();
+    let acquired = Arc::new(AtomicBool::new(false));
+    let waiter = {
+      let locks = locks.clone();
+      let acquired = acquired.clone();
+      thread::spawn(move || {
+        let result = locks.lock(file(12), owner(2, 11), LockMode::Exclusive, || thread::yield_now());
+        acquired.store(true, Ordering::SeqCst);
+        result
+      })
+    };
+    // The second process stays blocked until the first lets go
+    while locks.table.lock().waiting.is_empty() {
+      thread::yield_now();
+    }
+    assert!(!acquired.load(Ordering::SeqCst));
+    locks.unlock(file(12), writer.drive, writer.handle);
+    assert_eq!(waiter.join().unwrap(), Ok(()));
+    assert_eq!(locks.try_lock(file(12), writer, LockMode::Shared), Err(LockError::WouldBlock));
+  }
+
+  #[test]
+  fn deadlock_is_refused() {
+    extern crate std;
+    use alloc::sync::Arc;
+    use std::thread;
+
+    let locks = Arc::new(FileLocks::new());
+    let first = owner(1, 10);
+    let second = owner(2, 11);
+    locks.try_lock(file(12), first, LockMode::Exclusive).unwrap();
+    locks.try_lock(file(13), second, LockMode::Exclusive).unwrap();
+    let waiter = {
+      let locks = locks.clone();
+      thread::spawn(move || {
+        locks.lock(file(13), first, LockMode::Exclusive, || thread::yield_now())
+      })
+    };
+    while locks.table.lock().waiting.is_empty() {
+      thread::yield_now();
+    }
+    // The first process waits on the second, so the second can't wait on it
+    assert_eq!(locks.lock(file(12), second, LockMode::Exclusive, || ()), Err(LockError::Deadlock));
+    locks.unlock(file(13), second.drive, second.handle);
+    assert_eq!(waiter.join().unwrap(), Ok(()));
+  }
+}
@@ -2,5 +2,7 @@ pub mod cursor;
 pub mod filename;
 pub mod ioctl;
 pub mod handle;
+pub mod locks;
 pub mod path;
-pub mod stat;
\ No newline at end of file
+pub mod stat;
+pub mod wildcard;
\ No newline at end of file
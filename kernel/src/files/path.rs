@@ -45,7 +45,7 @@ impl Path {
 
   pub fn add(&mut self, sub: &str) {
     match sub {
-      "." => (), // same dir, do nothing
+      "" | "." => (), // same dir, do nothing
       ".." => { // parent directory
         self.remove_last();
       },
@@ -91,5 +91,7 @@ mod tests {
       Path::resolve("aaa\\bbb\\ccc", "..\\..\\..\\..\\..\\..\\..\\foo.bar").as_str(),
       "foo.bar",
     );
+    assert_eq!(Path::resolve("games", "").as_str(), "games");
+    assert_eq!(Path::resolve("games", "doom\\").as_str(), "games\\doom");
   }
 }
@@ -0,0 +1,139 @@
+//! DOS wildcards are matched against the fixed 8.3 form of a filename, not
+//! against the name as a string. A pattern is first expanded into an 11-byte
+//! FCB-style template: the name and extension are padded with spaces, and a
+//! `*` fills the rest of its field with `?`. Anything after a `*` in the same
+//! field is ignored, so `*A.TXT` matches every `.TXT` file. A `?` matches any
+//! character, including the padding, so `FOO?.TXT` also matches `FOO.TXT`.
+//! Since the extension is padded too, a pattern without a dot like `*` only
+//! matches names that have no extension; `*.*` matches everything.
+
+/// Length of a filename in 8.3 form, without the dot
+pub const FCB_NAME_LENGTH: usize = 11;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct WildcardPattern([u8; FCB_NAME_LENGTH]);
+
+impl WildcardPattern {
+  /// Expand a DOS wildcard pattern into its FCB-style template. Any directory
+  /// components should be removed before calling this.
+  pub fn parse(pattern: &str) -> WildcardPattern {
+    WildcardPattern(to_fcb_name(pattern.as_bytes(), true))
+  }
+
+  pub fn from_fcb(template: [u8; FCB_NAME_LENGTH]) -> WildcardPattern {
+    WildcardPattern(template)
+  }
+
+  pub fn as_fcb(&self) -> &[u8; FCB_NAME_LENGTH] {
+    &self.0
+  }
+
+  /// Match the space-padded name and extension of a directory entry
+  pub fn matches(&self, name: &[u8; 8], ext: &[u8; 3]) -> bool {
+    let candidate = name.iter().chain(ext.iter());
+    self.0.iter().zip(candidate).all(|(pattern, ch)| {
+      *pattern == b'?' || *pattern == ch.to_ascii_uppercase()
+    })
+  }
+
+  pub fn matches_filename(&self, filename: &str) -> bool {
+    let fcb = to_fcb_name(filename.as_bytes(), false);
+    let mut name = [0x20; 8];
+    let mut ext = [0x20; 3];
+    name.copy_from_slice(&fcb[..8]);
+    ext.copy_from_slice(&fcb[8..]);
+    self.matches(&name, &ext)
+  }
+}
+
+/// Convert a filename to its uppercase, space-padded 8.3 form. The special
+/// `.` and `..` entries are kept as names. When expanding a pattern, `*`
+/// fills the remainder of the name or extension with `?`.
+fn to_fcb_name(filename: &[u8], expand_stars: bool) -> [u8; FCB_NAME_LENGTH] {
+  let mut fcb = [0x20; FCB_NAME_LENGTH];
+  if filename == b"." || filename == b".." {
+    fcb[..filename.len()].copy_from_slice(filename);
+    return fcb;
+  }
+  let (name, ext) = match filename.iter().position(|ch| *ch == b'.') {
+    Some(dot) => (&filename[..dot], &filename[dot + 1..]),
+    None => (filename, &filename[0..0]),
+  };
+  fill_field(&mut fcb[..8], name, expand_stars);
+  fill_field(&mut fcb[8..], ext, expand_stars);
+  fcb
+}
+
+fn fill_field(field: &mut [u8], source: &[u8], expand_stars: bool) {
+  for (index, ch) in source.iter().take(field.len()).enumerate() {
+    if expand_stars && *ch == b'*' {
+      for rest in field[index..].iter_mut() {
+        *rest = b'?';
+      }
+      return;
+    }
+    field[index] = ch.to_ascii_uppercase();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::WildcardPattern;
+
+  #[test]
+  fn dos_wildcard_cases() {
+    let cases: [(&str, &str, bool); 26] = [
+      ("*.*", "COMMAND.COM", true),
+      ("*.*", "README", true),
+      ("*.*", "..", true),
+      ("*.COM", "COMMAND.COM", true),
+      ("*.COM", "COMMAND.EXE", false),
+      ("*.com", "command.com", true),
+      ("*.COM", "COM", false),
+      // A star ends its field, anything after it is ignored
+      ("*A.TXT", "NOTES.TXT", true),
+      ("FOO*BAR.TXT", "FOOLISH.TXT", true),
+      ("FOO*.TXT", "FOO.TXT", true),
+      ("FOO*.TXT", "FO.TXT", false),
+      // Without a dot, the extension must be blank
+      ("*", "README", true),
+      ("*", "README.TXT", false),
+      ("README", "README", true),
+      ("README", "README.TXT", false),
+      ("README.", "README", true),
+      // A question mark also matches the padding at the end of a field
+      ("FOO?.TXT", "FOO1.TXT", true),
+      ("FOO?.TXT", "FOO.TXT", true),
+      ("FOO?.TXT", "FOO12.TXT", false),
+      ("????????.???", "A.B", true),
+      ("?.TXT", "AB.TXT", false),
+      ("*.?", "FILE.C", true),
+      ("*.?", "FILE.CC", false),
+      ("*.T*", "FILE.TXT", true),
+      // Names are compared in their truncated 8.3 form
+      ("LONGFILENAME.TXT", "LONGFILE.TXT", true),
+      (".", ".", true),
+    ];
+    for (pattern, filename, expected) in cases.iter() {
+      assert_eq!(
+        WildcardPattern::parse(pattern).matches_filename(filename),
+        *expected,
+        "{} against {}",
+        pattern,
+        filename,
+      );
+    }
+  }
+
+  #[test]
+  fn templates() {
+    assert_eq!(WildcardPattern::parse("*.*").as_fcb(), b"???????????");
+    assert_eq!(WildcardPattern::parse("foo*.c").as_fcb(), b"FOO?????C  ");
+    assert_eq!(WildcardPattern::parse("*").as_fcb(), b"????????   ");
+    assert_eq!(WildcardPattern::parse("a?.b").as_fcb(), b"A?      B  ");
+    // Directory entries are already in padded form
+    let pattern = WildcardPattern::from_fcb(*b"????????EXE");
+    assert!(pattern.matches(b"SETUP   ", b"EXE"));
+    assert!(!pattern.matches(b"SETUP   ", b"COM"));
+  }
+}
@@ -4,7 +4,7 @@ use alloc::vec::Vec;
 use core::any::Any;
 use crate::devices;
 use crate::files::{handle::{Handle, HandleAllocator, LocalHandle}, cursor::SeekMethod};
-use spin::RwLock;
+use crate::locks::RwLock;
 use super::filesystem::FileSystem;
 use syscall::files::DirEntryInfo;
 
@@ -1,4 +0,0 @@
-pub enum FatError {
-  /// The disk does not contain the specified fat table
-  InvalidFatTable,
-}
\ No newline at end of file
@@ -1,7 +1,7 @@
 use alloc::collections::BTreeMap;
 use crate::files::{cursor::SeekMethod, handle::{HandleAllocator, LocalHandle}};
 use crate::memory::address::VirtualAddress;
-use spin::RwLock;
+use crate::locks::RwLock;
 use super::filesystem::FileSystem;
 use syscall::files::DirEntryInfo;
 
@@ -1,14 +1,13 @@
 use alloc::boxed::Box;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-use spin::RwLock;
+use crate::locks::RwLock;
 
 //#[cfg(not(test))]
 //pub mod dev;
 #[cfg(not(test))]
 pub mod init;
 
-//pub mod fat12;
 pub mod filesystem;
 
 pub type FileSystemType = dyn filesystem::FileSystem + Send + Sync;
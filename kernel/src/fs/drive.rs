@@ -13,9 +13,12 @@ use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::cmp::{Ord, PartialOrd};
 use core::sync::atomic::{AtomicUsize, Ordering};
-use spin::RwLock;
+use crate::locks::{ordered, LockLevel};
+use crate::locks::RwLock;
+use syscall::files::{copy_listed_name, DriveInfo};
 use super::filesystem::{FileSystemCategory, FileSystemInstance, FileSystemType};
 
 /// A DriveID is a unique numeric reference to a drive. Drive names shouldn't be
@@ -40,6 +43,35 @@ impl DriveID {
   }
 }
 
+#[derive(Debug, Eq, PartialEq)]
+pub enum UnmountError {
+  /// No drive is mounted with that name
+  NoSuchDrive,
+  /// The filesystem could not flush its buffers to the device
+  SyncFailed,
+}
+
+/// Settings chosen when a drive is mounted
+#[derive(Copy, Clone, Debug)]
+pub struct MountOptions {
+  /// Refuse writes, file creation, deletion, and resizing on the drive
+  pub read_only: bool,
+}
+
+impl MountOptions {
+  pub const fn new() -> MountOptions {
+    MountOptions {
+      read_only: false,
+    }
+  }
+
+  pub const fn read_only() -> MountOptions {
+    MountOptions {
+      read_only: true,
+    }
+  }
+}
+
 pub struct DriveMap {
   next_id: AtomicUsize,
   drives: RwLock<BTreeMap<DriveID, FileSystemInstance>>,
@@ -59,18 +91,76 @@ impl DriveMap {
   }
 
   pub fn mount_drive(&self, name: &str, category: FileSystemCategory, instance: Arc<Box<FileSystemType>>) -> DriveID {
+    self.mount_drive_with_options(name, category, instance, MountOptions::new())
+  }
+
+  pub fn mount_drive_with_options(
+    &self,
+    name: &str,
+    category: FileSystemCategory,
+    instance: Arc<Box<FileSystemType>>,
+    options: MountOptions,
+  ) -> DriveID {
     let entry = FileSystemInstance {
       category,
       name: Box::from(name),
       instance,
+      read_only: options.read_only,
     };
     let id = self.next_drive_id();
+    let _order = ordered(LockLevel::Drives);
     self.drives.write().insert(id, entry);
 
     id
   }
 
+  /// Flush a mounted drive and remove it from the map. After this, the drive
+  /// name no longer resolves, and its ID will not be reused. Callers are
+  /// responsible for making sure no files are still open on the drive.
+  /// If the filesystem fails to sync, the drive stays mounted.
+  pub fn unmount_drive(&self, name: &str) -> Result<DriveID, UnmountError> {
+    let id = self.get_drive_number(name).ok_or(UnmountError::NoSuchDrive)?;
+    let entry = self.take_drive(&id).ok_or(UnmountError::NoSuchDrive)?;
+    self.sync_taken_drive(id, entry)
+  }
+
+  /// Remove a drive from the map without flushing it. Its name stops
+  /// resolving right away, so callers that checked the drive isn't in use can
+  /// take it before anything else opens it. The unmount is finished with
+  /// `sync_taken_drive`.
+  pub fn take_drive(&self, id: &DriveID) -> Option<FileSystemInstance> {
+    let _order = ordered(LockLevel::Drives);
+    self.drives.write().remove(id)
+  }
+
+  /// Flush a drive that was removed with `take_drive`. If the filesystem fails
+  /// to sync, the drive is put back under its old ID.
+  pub fn sync_taken_drive(&self, id: DriveID, entry: FileSystemInstance) -> Result<DriveID, UnmountError> {
+    if entry.get_fs().sync().is_err() {
+      let _order = ordered(LockLevel::Drives);
+      self.drives.write().insert(id, entry);
+      return Err(UnmountError::SyncFailed);
+    }
+    Ok(id)
+  }
+
+  /// Flush and remove every drive, starting with the most recently mounted.
+  /// Returns the names of any drives that failed to sync, which stay
+  /// mounted.
+  pub fn unmount_all(&self) -> Vec<String> {
+    let names: Vec<String> = {
+      let _order = ordered(LockLevel::Drives);
+      let drives = self.drives.read();
+      drives.values().rev().map(|entry| entry.name.clone().into_string()).collect()
+    };
+    names
+      .into_iter()
+      .filter(|name| self.unmount_drive(name).is_err())
+      .collect()
+  }
+
   pub fn get_drive_number(&self, name: &str) -> Option<DriveID> {
+    let _order = ordered(LockLevel::Drives);
     let drives = self.drives.read();
     for (id, instance) in drives.iter() {
       if instance.matches_name(name) {
@@ -81,6 +171,7 @@ impl DriveMap {
   }
 
   pub fn get_drive_name(&self, id: &DriveID) -> Option<String> {
+    let _order = ordered(LockLevel::Drives);
     let drives = self.drives.read();
     let entry = drives.get(id)?;
     let name = entry.name.clone().into_string();
@@ -88,8 +179,120 @@ impl DriveMap {
   }
 
   pub fn get_drive_instance(&self, id: &DriveID) -> Option<(FileSystemCategory, Arc<Box<FileSystemType>>)> {
+    let _order = ordered(LockLevel::Drives);
     let drives = self.drives.read();
     let entry = drives.get(id)?;
     Some((entry.get_category(), entry.get_fs()))
   }
+
+  /// Determine whether a drive was mounted read-only. Drives that aren't
+  /// mounted have nothing to write to, so they aren't considered read-only.
+  pub fn is_read_only(&self, id: &DriveID) -> bool {
+    let _order = ordered(LockLevel::Drives);
+    let drives = self.drives.read();
+    drives.get(id).map_or(false, |entry| entry.read_only)
+  }
+
+  /// Describe each mounted drive, in the order they were mounted, filling as
+  /// many entries of `dest` as will fit. The total number of drives is
+  /// returned, so a caller whose buffer was too small can retry with a larger
+  /// one. The listing comes from a single snapshot of the map; if drives are
+  /// mounted or unmounted before a retry, the count reflects the new state.
+  pub fn list_drives(&self, dest: &mut [DriveInfo]) -> usize {
+    let _order = ordered(LockLevel::Drives);
+    let drives = self.drives.read();
+    for (entry, info) in drives.values().zip(dest.iter_mut()) {
+      info.name_length = copy_listed_name(&entry.name, &mut info.name);
+      info.category = entry.get_category().as_u8();
+    }
+    drives.len()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::fs::drivers::testfs::TestFileSystem;
+  use crate::fs::filesystem::FileSystemCategory;
+  use syscall::files::{DriveInfo, DRIVE_CATEGORY_KERNEL_ASYNC, DRIVE_CATEGORY_KERNEL_SYNC};
+  use super::{DriveMap, MountOptions, UnmountError};
+
+  #[test]
+  fn unmount_drive() {
+    let drives = DriveMap::new();
+    let fs = TestFileSystem::new();
+    fs.add_file("FILE.TXT", b"");
+    let id = drives.mount_drive("A", FileSystemCategory::KernelSync, fs.mountable());
+    {
+      let (_, instance) = drives.get_drive_instance(&id).unwrap();
+      assert!(instance.open("FILE.TXT").is_ok());
+    }
+
+    assert_eq!(drives.unmount_drive("A"), Ok(id));
+    assert!(fs.was_synced());
+    // Files can no longer be opened on the drive
+    assert_eq!(drives.get_drive_number("A"), None);
+    assert!(drives.get_drive_instance(&id).is_none());
+
+    assert_eq!(drives.unmount_drive("A"), Err(UnmountError::NoSuchDrive));
+    assert_eq!(drives.unmount_drive("B"), Err(UnmountError::NoSuchDrive));
+  }
+
+  #[test]
+  fn failed_sync_keeps_drive() {
+    let drives = DriveMap::new();
+    let fs = TestFileSystem::new();
+    fs.fail_sync();
+    let id = drives.mount_drive("A", FileSystemCategory::KernelSync, fs.mountable());
+    assert_eq!(drives.unmount_drive("A"), Err(UnmountError::SyncFailed));
+    assert_eq!(drives.get_drive_number("A"), Some(id));
+
+    // A taken drive can't be found until it is put back
+    let entry = drives.take_drive(&id).unwrap();
+    assert_eq!(drives.get_drive_number("A"), None);
+    assert!(drives.take_drive(&id).is_none());
+    assert_eq!(drives.sync_taken_drive(id, entry), Err(UnmountError::SyncFailed));
+    assert_eq!(drives.get_drive_number("A"), Some(id));
+  }
+
+  #[test]
+  fn list_built_in_drives() {
+    let drives = DriveMap::new();
+    let make_fs = || TestFileSystem::new().mountable();
+    drives.mount_drive("INIT", FileSystemCategory::KernelSync, make_fs());
+    drives.mount_drive("DEV", FileSystemCategory::KernelAsync, make_fs());
+    drives.mount_drive("A", FileSystemCategory::KernelAsync, make_fs());
+
+    let mut listing = [DriveInfo::empty(), DriveInfo::empty(), DriveInfo::empty(), DriveInfo::empty()];
+    assert_eq!(drives.list_drives(&mut listing), 3);
+    assert_eq!(listing[0].name_bytes(), b"INIT");
+    assert_eq!(listing[0].category, DRIVE_CATEGORY_KERNEL_SYNC);
+    assert_eq!(listing[1].name_bytes(), b"DEV");
+    assert_eq!(listing[1].category, DRIVE_CATEGORY_KERNEL_ASYNC);
+    assert_eq!(listing[2].name_bytes(), b"A");
+    assert_eq!(listing[3].name_bytes(), b"");
+
+    // A buffer that is too small still reports how many entries are needed
+    let mut short = [DriveInfo::empty()];
+    assert_eq!(drives.list_drives(&mut short), 3);
+    assert_eq!(short[0].name_bytes(), b"INIT");
+    assert_eq!(drives.list_drives(&mut []), 3);
+
+    // Drives removed between calls disappear from the next listing
+    drives.unmount_drive("DEV").unwrap();
+    assert_eq!(drives.list_drives(&mut listing), 2);
+    assert_eq!(listing[1].name_bytes(), b"A");
+  }
+
+  #[test]
+  fn read_only_mount() {
+    let drives = DriveMap::new();
+    let make_fs = || TestFileSystem::new().mountable();
+    let writable = drives.mount_drive("C", FileSystemCategory::KernelSync, make_fs());
+    let read_only = drives.mount_drive_with_options("A", FileSystemCategory::KernelAsync, make_fs(), MountOptions::read_only());
+    assert!(!drives.is_read_only(&writable));
+    assert!(drives.is_read_only(&read_only));
+
+    drives.unmount_drive("A").unwrap();
+    assert!(!drives.is_read_only(&read_only));
+  }
 }
@@ -7,8 +7,9 @@ use crate::collections::SlotList;
 use crate::devices::{get_driver_for_device, get_device_number_by_name, driver::{DeviceDriverType, IOHandle}};
 use crate::files::{cursor::SeekMethod, handle::{Handle, LocalHandle}};
 use crate::fs::KernelFileSystem;
+use crate::fs::filesystem::FileAccess;
 use crate::task::id::ProcessID;
-use spin::RwLock;
+use crate::locks::RwLock;
 use syscall::files::{DirEntryInfo, FileStatus};
 
 #[derive(Copy, Clone)]
@@ -106,6 +107,24 @@ impl KernelFileSystem for DevFileSystem {
     )
   }
 
+  fn read_nonblocking(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<Option<usize>, ()> {
+    let device_handle = self.get_device_handle(handle).ok_or(())?;
+
+    self.run_device_operation(
+      device_handle.device_number,
+      |driver| driver.read_nonblocking(device_handle.io_handle, buffer),
+    )
+  }
+
+  fn write_nonblocking(&self, handle: LocalHandle, buffer: &[u8]) -> Result<Option<usize>, ()> {
+    let device_handle = self.get_device_handle(handle).ok_or(())?;
+
+    self.run_device_operation(
+      device_handle.device_number,
+      |driver| driver.write_nonblocking(device_handle.io_handle, buffer),
+    )
+  }
+
   fn close(&self, handle: LocalHandle) -> Result<(), ()> {
     let device_handle = self.get_device_handle(handle).ok_or(())?;
 
@@ -158,6 +177,7 @@ impl KernelFileSystem for DevFileSystem {
   fn read_dir(&self, handle: LocalHandle, info: &mut DirEntryInfo) -> Result<bool, ()> {
     match self.open_handles.write().get_mut(handle.as_usize()) {
       Some(OpenHandle::Directory(open_dir)) => {
+        let _order = crate::locks::ordered(crate::locks::LockLevel::Devices);
         let devices = crate::devices::DEVICES.read();
         let name = match devices.get_device_name(open_dir.cursor) {
           Some(name) => name,
@@ -187,8 +207,60 @@ impl KernelFileSystem for DevFileSystem {
     }
   }
 
-  fn ioctl(&self, _handle: LocalHandle, _command: u32, _arg: u32) -> Result<u32, ()> {
-    Err(())
+  fn ioctl(&self, handle: LocalHandle, command: u32, arg: u32) -> Result<u32, ()> {
+    let device_handle = self.get_device_handle(handle).ok_or(())?;
+
+    self.run_device_operation(
+      device_handle.device_number,
+      |driver| driver.ioctl(device_handle.io_handle, command, arg),
+    )
+  }
+
+  /// A device handle is a terminal if its driver says so. Directory handles
+  /// never are.
+  fn is_terminal(&self, handle: LocalHandle) -> Result<bool, ()> {
+    match self.get_handle(handle).ok_or(())? {
+      OpenHandle::Device(device) => {
+        let driver = get_driver_for_device(device.device_number).ok_or(())?;
+        Ok(driver.is_terminal())
+      },
+      OpenHandle::Directory(_) => Ok(false),
+    }
+  }
+
+  /// Directories can always be read, so only devices need to be asked
+  fn poll_readable(&self, handle: LocalHandle) -> Result<bool, ()> {
+    match self.get_handle(handle).ok_or(())? {
+      OpenHandle::Device(device) => {
+        let driver = get_driver_for_device(device.device_number).ok_or(())?;
+        driver.poll_readable(device.io_handle)
+      },
+      OpenHandle::Directory(_) => Ok(true),
+    }
+  }
+
+  fn poll_writable(&self, handle: LocalHandle) -> Result<bool, ()> {
+    match self.get_handle(handle).ok_or(())? {
+      OpenHandle::Device(device) => {
+        let driver = get_driver_for_device(device.device_number).ok_or(())?;
+        driver.poll_writable(device.io_handle)
+      },
+      OpenHandle::Directory(_) => Ok(true),
+    }
+  }
+
+  /// Looking up a device by name doesn't open it, so probing a device with
+  /// a single reader doesn't disturb that reader
+  fn access(&self, path: &str) -> Result<FileAccess, ()> {
+    let local_path = if path.starts_with('\\') {
+      &path[1..]
+    } else {
+      path
+    };
+    if !local_path.is_empty() {
+      get_device_number_by_name(local_path).ok_or(())?;
+    }
+    Ok(FileAccess::read_write())
   }
 
   fn stat(&self, _handle: LocalHandle, _status: &mut FileStatus) -> Result<(), ()> {
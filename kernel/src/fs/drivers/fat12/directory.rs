@@ -1,3 +1,4 @@
+use crate::fs::filesystem::FileAccess;
 use crate::memory::address::VirtualAddress;
 use super::fat::{Cluster, ClusterChain};
 use super::file::{FileDate, FileTime, FileType, name_character_matches};
@@ -56,6 +57,33 @@ impl DirectoryEntry {
     }
   }
 
+  /// Copy an entry out of a buffer read from disk
+  pub fn from_bytes(bytes: &[u8]) -> Option<DirectoryEntry> {
+    if bytes.len() < core::mem::size_of::<DirectoryEntry>() {
+      return None;
+    }
+    let ptr = bytes.as_ptr() as *const DirectoryEntry;
+    Some(unsafe { core::ptr::read_unaligned(ptr) })
+  }
+
+  /// Construct an entry for a new file or directory, with no timestamps
+  pub fn new(name: &[u8; 8], ext: &[u8; 3], attributes: u8, first_cluster: Cluster) -> DirectoryEntry {
+    let mut bytes = [0u8; 32];
+    bytes[11] = attributes;
+    let mut entry = DirectoryEntry::from_bytes(&bytes).unwrap();
+    entry.set_name(name, ext);
+    entry.set_first_cluster(first_cluster);
+    entry
+  }
+
+  /// Copy the entry into a buffer, in its on-disk format
+  pub fn to_bytes(&self) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    let ptr = bytes.as_mut_ptr() as *mut DirectoryEntry;
+    unsafe { core::ptr::write_unaligned(ptr, *self) };
+    bytes
+  }
+
   pub fn get_name(&self) -> &[u8] {
     &self.file_name
   }
@@ -74,14 +102,58 @@ impl DirectoryEntry {
     }
   }
 
+  pub fn get_attributes(&self) -> u8 {
+    self.attributes
+  }
+
+  pub fn set_attributes(&mut self, attributes: u8) {
+    self.attributes = attributes;
+  }
+
+  /// DOS refuses to modify files with the read-only attribute set
+  pub fn is_read_only(&self) -> bool {
+    self.attributes & 0x01 == 0x01
+  }
+
+  /// How the file can be accessed, based on its attributes. FAT has no
+  /// notion of unreadable files, so only the read-only bit matters.
+  pub fn get_access(&self) -> FileAccess {
+    if self.is_read_only() {
+      FileAccess::read_only()
+    } else {
+      FileAccess::read_write()
+    }
+  }
+
   pub fn get_first_cluster(&self) -> Cluster {
     Cluster::new(self.first_file_cluster as usize)
   }
 
+  pub fn set_first_cluster(&mut self, cluster: Cluster) {
+    self.first_file_cluster = cluster.as_usize() as u16;
+  }
+
+  /// Replace the name and extension, which should already be padded with
+  /// spaces
+  pub fn set_name(&mut self, name: &[u8; 8], ext: &[u8; 3]) {
+    self.file_name = *name;
+    self.ext = *ext;
+  }
+
+  /// Mark the entry as deleted, leaving the slot free for a new entry
+  pub fn mark_deleted(&mut self) {
+    self.file_name[0] = 0xe5;
+  }
+
   pub fn is_empty(&self) -> bool {
     self.file_name[0] == 0
   }
 
+  /// Deleted files leave their entry behind, marked with 0xe5
+  pub fn is_deleted(&self) -> bool {
+    self.file_name[0] == 0xe5
+  }
+
   pub fn copy_name(&self, buffer: &mut [u8; 8]) {
     for i in 0..8 {
       buffer[i] = self.file_name[i];
@@ -165,3 +237,35 @@ impl<'a> Iterator for DirectoryEntryIterator<'a> {
 pub struct FileReference {
   dir_entry: DirectoryEntry,
 }
+
+#[cfg(test)]
+mod tests {
+  use syscall::flags::{F_OK, R_OK, W_OK};
+  use super::DirectoryEntry;
+
+  fn entry_with_attributes(attributes: u8) -> DirectoryEntry {
+    let mut bytes = [0u8; 32];
+    bytes[0..11].copy_from_slice(b"CONFIG  SYS");
+    bytes[11] = attributes;
+    DirectoryEntry::from_bytes(&bytes).unwrap()
+  }
+
+  #[test]
+  fn read_only_attribute_denies_writes() {
+    let normal = entry_with_attributes(0x20);
+    assert!(!normal.is_read_only());
+    assert_eq!(normal.get_access().permits(F_OK), Ok(true));
+    assert_eq!(normal.get_access().permits(R_OK | W_OK), Ok(true));
+
+    // Read-only, hidden, and system
+    let protected = entry_with_attributes(0x07);
+    assert!(protected.is_read_only());
+    assert_eq!(protected.get_access().permits(F_OK), Ok(true));
+    assert_eq!(protected.get_access().permits(R_OK), Ok(true));
+    assert_eq!(protected.get_access().permits(W_OK), Ok(false));
+    assert_eq!(protected.get_access().permits(R_OK | W_OK), Ok(false));
+
+    // Unknown mode bits are an error, rather than silently ignored
+    assert_eq!(normal.get_access().permits(0x10), Err(()));
+  }
+}
@@ -0,0 +1,341 @@
+use super::errors::FatError;
+use super::fat::Cluster;
+
+pub const DIRECTORY_ENTRY_SIZE: usize = 32;
+pub const BOOT_SECTOR_SIZE: usize = 512;
+
+/// FAT12 volumes can address at most this many data clusters. Anything larger
+/// is formatted as FAT16.
+pub const MAX_FAT12_CLUSTERS: usize = 4084;
+
+/// Represent a contiguous block of sectors on a disk
+pub struct SectorRange {
+  first: usize,
+  count: usize,
+}
+
+impl SectorRange {
+  pub fn new(first: usize, count: usize) -> SectorRange {
+    SectorRange {
+      first,
+      count,
+    }
+  }
+
+  pub fn get_first_sector(&self) -> usize {
+    self.first
+  }
+
+  pub fn get_sector_count(&self) -> usize {
+    self.count
+  }
+}
+
+/// Layout of a FAT12 volume, derived from its BIOS Parameter Block
+pub struct DiskConfig {
+  bytes_per_sector: usize,
+  sectors_per_cluster: usize,
+  reserved_sectors: usize,
+  fat_count: usize,
+  root_directory_entries: usize,
+  sectors_per_fat: usize,
+  total_sectors: usize,
+  sectors_per_track: usize,
+  heads: usize,
+}
+
+impl DiskConfig {
+  pub fn from_bpb(bpb: &BiosParamBlock) -> DiskConfig {
+    DiskConfig {
+      bytes_per_sector: bpb.bytes_per_sector as usize,
+      sectors_per_cluster: bpb.sectors_per_cluster as usize,
+      reserved_sectors: bpb.reserved_sectors as usize,
+      fat_count: bpb.fat_count as usize,
+      root_directory_entries: bpb.root_directory_entries as usize,
+      sectors_per_fat: bpb.sectors_per_fat as usize,
+      total_sectors: bpb.get_total_sectors(),
+      sectors_per_track: bpb.sectors_per_track as usize,
+      heads: bpb.heads as usize,
+    }
+  }
+
+  pub fn get_sectors_per_cluster(&self) -> usize {
+    self.sectors_per_cluster
+  }
+
+  pub fn get_bytes_per_cluster(&self) -> usize {
+    self.sectors_per_cluster * self.bytes_per_sector
+  }
+
+  /// Determine which disk sectors correspond with a given cluster. The data
+  /// area begins with cluster 2; clusters 0 and 1 are reserved, and have no
+  /// sectors, so they fail as an InvalidCluster along with any cluster past
+  /// the end of the disk.
+  pub fn get_sectors_for_cluster(&self, cluster: Cluster) -> Result<SectorRange, FatError> {
+    let index = cluster.as_usize();
+    if index < 2 || index - 2 >= self.get_cluster_count() {
+      return Err(FatError::InvalidCluster);
+    }
+    let first = self.get_data_sectors().get_first_sector()
+      + (index - 2) * self.sectors_per_cluster;
+    Ok(SectorRange::new(first, self.sectors_per_cluster))
+  }
+
+  /// Get the sector range associated with a specific FAT table. If that table
+  /// does not exist on the disk, a FatError will be returned instead.
+  pub fn get_fat_sectors(&self, fat_table: usize) -> Result<SectorRange, FatError> {
+    if fat_table >= self.fat_count {
+      return Err(FatError::InvalidFatTable);
+    }
+    let mut fat_start = self.reserved_sectors;
+    fat_start += fat_table * self.sectors_per_fat;
+
+    Ok(SectorRange::new(fat_start, self.sectors_per_fat))
+  }
+
+  pub fn get_root_directory_size(&self) -> usize {
+    self.root_directory_entries * DIRECTORY_ENTRY_SIZE
+  }
+
+  pub fn get_root_directory_entries(&self) -> usize {
+    self.root_directory_entries
+  }
+
+  pub fn get_bytes_per_sector(&self) -> usize {
+    self.bytes_per_sector
+  }
+
+  pub fn get_sectors_per_fat(&self) -> usize {
+    self.sectors_per_fat
+  }
+
+  pub fn get_total_sectors(&self) -> usize {
+    self.total_sectors
+  }
+
+  /// Physical layout of the disk, as (sectors per track, heads). Block devices
+  /// need this to convert sector numbers to CHS addresses.
+  pub fn get_geometry(&self) -> (usize, usize) {
+    (self.sectors_per_track, self.heads)
+  }
+
+  pub fn get_root_directory_sectors(&self) -> SectorRange {
+    let size = self.get_root_directory_size();
+    let sector_count = (size + self.bytes_per_sector - 1) / self.bytes_per_sector;
+    let first_sector = self.reserved_sectors + (self.fat_count * self.sectors_per_fat);
+    SectorRange::new(first_sector, sector_count)
+  }
+
+  pub fn get_data_sectors(&self) -> SectorRange {
+    let root = self.get_root_directory_sectors();
+    let first_sector = root.get_first_sector() + root.get_sector_count();
+    let count = self.total_sectors.saturating_sub(first_sector);
+    SectorRange::new(first_sector, count)
+  }
+
+  /// Number of clusters available for file data. Valid cluster numbers run
+  /// from 2 to this value + 1.
+  pub fn get_cluster_count(&self) -> usize {
+    self.get_data_sectors().get_sector_count() / self.sectors_per_cluster
+  }
+
+  pub fn get_directory_index_location(&self, index: usize) -> (usize, usize) {
+    let entries_per_sector = self.bytes_per_sector / DIRECTORY_ENTRY_SIZE;
+    let absolute_sector = index / entries_per_sector;
+    let local_index = index % entries_per_sector;
+    (absolute_sector, local_index)
+  }
+}
+
+/// The BIOS Parameter Block, found at offset 0x0b of the boot sector, describes
+/// the layout of a FAT volume.
+pub struct BiosParamBlock {
+  pub bytes_per_sector: u16,
+  pub sectors_per_cluster: u8,
+  pub reserved_sectors: u16,
+  pub fat_count: u8,
+  pub root_directory_entries: u16,
+  pub total_sectors: u16,
+  pub media_desc: u8,
+  pub sectors_per_fat: u16,
+  pub sectors_per_track: u16,
+  pub heads: u16,
+  pub hidden_sectors: u32,
+  pub large_total_sectors: u32,
+}
+
+fn read_u16(sector: &[u8], offset: usize) -> u16 {
+  (sector[offset] as u16) | ((sector[offset + 1] as u16) << 8)
+}
+
+fn read_u32(sector: &[u8], offset: usize) -> u32 {
+  (read_u16(sector, offset) as u32) | ((read_u16(sector, offset + 2) as u32) << 16)
+}
+
+impl BiosParamBlock {
+  /// Parse and validate the BPB from the contents of a boot sector
+  pub fn from_boot_sector(sector: &[u8]) -> Result<BiosParamBlock, FatError> {
+    if sector.len() < BOOT_SECTOR_SIZE {
+      return Err(FatError::NoDisk);
+    }
+    if sector[510] != 0x55 || sector[511] != 0xaa {
+      return Err(FatError::MissingBootSignature);
+    }
+    let bpb = BiosParamBlock {
+      bytes_per_sector: read_u16(sector, 0x0b),
+      sectors_per_cluster: sector[0x0d],
+      reserved_sectors: read_u16(sector, 0x0e),
+      fat_count: sector[0x10],
+      root_directory_entries: read_u16(sector, 0x11),
+      total_sectors: read_u16(sector, 0x13),
+      media_desc: sector[0x15],
+      sectors_per_fat: read_u16(sector, 0x16),
+      sectors_per_track: read_u16(sector, 0x18),
+      heads: read_u16(sector, 0x1a),
+      hidden_sectors: read_u32(sector, 0x1c),
+      large_total_sectors: read_u32(sector, 0x20),
+    };
+    if bpb.is_valid() {
+      Ok(bpb)
+    } else {
+      Err(FatError::InvalidParamBlock)
+    }
+  }
+
+  pub fn get_total_sectors(&self) -> usize {
+    if self.total_sectors == 0 {
+      self.large_total_sectors as usize
+    } else {
+      self.total_sectors as usize
+    }
+  }
+
+  fn is_valid(&self) -> bool {
+    match self.bytes_per_sector {
+      512 | 1024 | 2048 | 4096 => (),
+      _ => return false,
+    }
+    if !self.sectors_per_cluster.is_power_of_two() {
+      return false;
+    }
+    if self.reserved_sectors == 0 || self.fat_count == 0 || self.sectors_per_fat == 0 {
+      return false;
+    }
+    if self.root_directory_entries == 0 {
+      return false;
+    }
+    if self.media_desc != 0xf0 && self.media_desc < 0xf8 {
+      return false;
+    }
+    if self.sectors_per_track == 0 || self.sectors_per_track > 63 {
+      return false;
+    }
+    if self.heads == 0 || self.heads > 255 {
+      return false;
+    }
+    let config = DiskConfig::from_bpb(self);
+    let data_start = config.get_data_sectors().get_first_sector();
+    if data_start >= config.get_total_sectors() {
+      return false;
+    }
+    let clusters = config.get_cluster_count();
+    // The FAT needs 1.5 bytes for each cluster, plus the two reserved entries
+    let fat_bytes = config.get_sectors_per_fat() * config.get_bytes_per_sector();
+    clusters > 0 && clusters <= MAX_FAT12_CLUSTERS && (clusters + 2) * 3 / 2 <= fat_bytes
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+  use super::{BiosParamBlock, Cluster, DiskConfig, BOOT_SECTOR_SIZE};
+  use super::super::errors::FatError;
+
+  /// Build the boot sector of a freshly formatted 1.44MB floppy
+  fn floppy_boot_sector() -> Vec<u8> {
+    let mut sector = Vec::with_capacity(BOOT_SECTOR_SIZE);
+    sector.resize(BOOT_SECTOR_SIZE, 0);
+    sector[0..3].copy_from_slice(&[0xeb, 0x3c, 0x90]);
+    sector[3..11].copy_from_slice(b"MSDOS5.0");
+    sector[0x0b..0x0d].copy_from_slice(&512u16.to_le_bytes());
+    sector[0x0d] = 1;
+    sector[0x0e..0x10].copy_from_slice(&1u16.to_le_bytes());
+    sector[0x10] = 2;
+    sector[0x11..0x13].copy_from_slice(&224u16.to_le_bytes());
+    sector[0x13..0x15].copy_from_slice(&2880u16.to_le_bytes());
+    sector[0x15] = 0xf0;
+    sector[0x16..0x18].copy_from_slice(&9u16.to_le_bytes());
+    sector[0x18..0x1a].copy_from_slice(&18u16.to_le_bytes());
+    sector[0x1a..0x1c].copy_from_slice(&2u16.to_le_bytes());
+    sector[510] = 0x55;
+    sector[511] = 0xaa;
+    sector
+  }
+
+  #[test]
+  fn parse_floppy_boot_sector() {
+    let bpb = BiosParamBlock::from_boot_sector(&floppy_boot_sector()).unwrap();
+    let config = DiskConfig::from_bpb(&bpb);
+    assert_eq!(config.get_geometry(), (18, 2));
+    assert_eq!(config.get_total_sectors(), 2880);
+    assert_eq!(config.get_fat_sectors(1).unwrap().get_first_sector(), 10);
+    assert!(config.get_fat_sectors(2).is_err());
+    let root = config.get_root_directory_sectors();
+    assert_eq!((root.get_first_sector(), root.get_sector_count()), (19, 14));
+    assert_eq!(config.get_data_sectors().get_first_sector(), 33);
+    assert_eq!(config.get_cluster_count(), 2847);
+  }
+
+  #[test]
+  fn reserved_clusters_have_no_sectors() {
+    let bpb = BiosParamBlock::from_boot_sector(&floppy_boot_sector()).unwrap();
+    let config = DiskConfig::from_bpb(&bpb);
+    assert_eq!(config.get_sectors_for_cluster(Cluster::new(0)).err(), Some(FatError::InvalidCluster));
+    assert_eq!(config.get_sectors_for_cluster(Cluster::new(1)).err(), Some(FatError::InvalidCluster));
+    assert_eq!(config.get_sectors_for_cluster(Cluster::new(2)).unwrap().get_first_sector(), 33);
+    assert_eq!(config.get_sectors_for_cluster(Cluster::new(2848)).unwrap().get_first_sector(), 2879);
+    assert_eq!(config.get_sectors_for_cluster(Cluster::new(2849)).err(), Some(FatError::InvalidCluster));
+  }
+
+  #[test]
+  fn geometry_comes_from_bpb() {
+    // A 720KB disk has 9 sectors per track
+    let mut sector = floppy_boot_sector();
+    sector[0x13..0x15].copy_from_slice(&1440u16.to_le_bytes());
+    sector[0x15] = 0xf9;
+    sector[0x16..0x18].copy_from_slice(&3u16.to_le_bytes());
+    sector[0x18..0x1a].copy_from_slice(&9u16.to_le_bytes());
+    sector[0x11..0x13].copy_from_slice(&112u16.to_le_bytes());
+    sector[0x0d] = 2;
+    let bpb = BiosParamBlock::from_boot_sector(&sector).unwrap();
+    let config = DiskConfig::from_bpb(&bpb);
+    assert_eq!(config.get_geometry(), (9, 2));
+    assert_eq!(config.get_data_sectors().get_first_sector(), 14);
+  }
+
+  #[test]
+  fn reject_invalid_boot_sector() {
+    let mut unsigned = floppy_boot_sector();
+    unsigned[511] = 0;
+    assert_eq!(BiosParamBlock::from_boot_sector(&unsigned).err(), Some(FatError::MissingBootSignature));
+
+    let mut bad_sector_size = floppy_boot_sector();
+    bad_sector_size[0x0b..0x0d].copy_from_slice(&500u16.to_le_bytes());
+    assert_eq!(BiosParamBlock::from_boot_sector(&bad_sector_size).err(), Some(FatError::InvalidParamBlock));
+
+    let mut no_fats = floppy_boot_sector();
+    no_fats[0x10] = 0;
+    assert_eq!(BiosParamBlock::from_boot_sector(&no_fats).err(), Some(FatError::InvalidParamBlock));
+
+    // Too many clusters to be FAT12
+    let mut too_large = floppy_boot_sector();
+    too_large[0x13..0x15].copy_from_slice(&0u16.to_le_bytes());
+    too_large[0x20..0x24].copy_from_slice(&65536u32.to_le_bytes());
+    assert_eq!(BiosParamBlock::from_boot_sector(&too_large).err(), Some(FatError::InvalidParamBlock));
+
+    // A blank, unformatted disk
+    let blank = [0u8; BOOT_SECTOR_SIZE];
+    assert!(BiosParamBlock::from_boot_sector(&blank).is_err());
+    assert_eq!(BiosParamBlock::from_boot_sector(&blank[..16]).err(), Some(FatError::NoDisk));
+  }
+}
@@ -0,0 +1,18 @@
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FatError {
+  /// The disk does not contain the specified fat table
+  InvalidFatTable,
+  /// The boot sector could not be read, usually because no disk is inserted
+  NoDisk,
+  /// The boot sector does not end with the 0x55AA signature
+  MissingBootSignature,
+  /// The BIOS Parameter Block contains values that cannot describe a FAT12
+  /// volume
+  InvalidParamBlock,
+  /// A FAT entry points at a cluster outside of the data area
+  InvalidCluster,
+  /// A cluster chain links back into itself, and would never end
+  ClusterLoop,
+  /// A sector of the FAT could not be read
+  ReadFailed,
+}
@@ -0,0 +1,473 @@
+use alloc::collections::BTreeSet;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use super::disk::{DiskConfig, SectorRange};
+use super::errors::FatError;
+
+/// Wrapper type representing a cluster index
+/// Clusters typically have a 1-1 relationship with sectors, but they may differ
+/// so we want to have a special data type for them.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Cluster(usize);
+
+impl Cluster {
+  pub fn new(index: usize) -> Cluster {
+    Cluster(index)
+  }
+
+  pub fn as_usize(&self) -> usize {
+    self.0
+  }
+}
+
+#[derive(Clone)]
+pub struct ClusterChain {
+  pub clusters: Arc<Vec<Cluster>>,
+}
+
+impl ClusterChain {
+  pub fn empty() -> ClusterChain {
+    ClusterChain {
+      clusters: Arc::new(Vec::new()),
+    }
+  }
+
+  pub fn from_vec(v: Vec<Cluster>) -> ClusterChain {
+    ClusterChain {
+      clusters: Arc::new(v),
+    }
+  }
+
+  pub fn sector_iter(&self, disk_config: &DiskConfig) -> ChainSectorIterator {
+    ChainSectorIterator::new(Arc::clone(&self.clusters), disk_config)
+  }
+}
+
+/// Follow a chain of FAT entries from its first cluster, using `get_entry` to
+/// read the table. A corrupt table can point outside the data area, or link a
+/// chain back into itself; rather than walking forever, each cluster is
+/// remembered, and visiting one twice is reported as a loop.
+pub fn follow_chain<F>(first_cluster: Cluster, max_cluster: usize, mut get_entry: F) -> Result<Vec<Cluster>, FatError>
+  where F: FnMut(Cluster) -> Result<FatEntry, ()> {
+  let mut clusters = Vec::with_capacity(1);
+  let mut visited: Vec<u32> = Vec::new();
+  visited.resize(max_cluster / 32 + 1, 0);
+  let mut next = FatEntry::NextCluster(first_cluster);
+  while let FatEntry::NextCluster(cluster) = next {
+    let index = cluster.as_usize();
+    if index < 2 || index > max_cluster {
+      return Err(FatError::InvalidCluster);
+    }
+    let mask = 1 << (index & 31);
+    if visited[index / 32] & mask != 0 {
+      return Err(FatError::ClusterLoop);
+    }
+    visited[index / 32] |= mask;
+    clusters.push(cluster);
+    next = get_entry(cluster).map_err(|_| FatError::ReadFailed)?;
+  }
+  Ok(clusters)
+}
+
+impl core::fmt::Debug for ClusterChain {
+  fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+    f.debug_list().entries(self.clusters.iter()).finish()
+  }
+}
+
+pub struct ChainSectorIterator {
+  clusters: Arc<Vec<Cluster>>,
+  cluster_index: usize,
+  sector_index: usize,
+  sectors_per_cluster: usize,
+  root_dir_sectors: SectorRange,
+  data_sectors: SectorRange,
+}
+
+impl ChainSectorIterator {
+  pub fn new(clusters: Arc<Vec<Cluster>>, disk_config: &DiskConfig) -> ChainSectorIterator {
+    ChainSectorIterator {
+      clusters,
+      cluster_index: 0,
+      sector_index: 0,
+      sectors_per_cluster: disk_config.get_sectors_per_cluster(),
+      root_dir_sectors: disk_config.get_root_directory_sectors(),
+      data_sectors: disk_config.get_data_sectors(),
+    }
+  }
+}
+
+impl Iterator for ChainSectorIterator {
+  type Item = usize;
+
+  fn next(&mut self) -> Option<usize> {
+    let cluster_count = self.clusters.len();
+    if cluster_count == 0 {
+      // No clusters means we're iterating over the root directory
+      if self.sector_index >= self.root_dir_sectors.get_sector_count() {
+        return None;
+      }
+      let sector = self.root_dir_sectors.get_first_sector() + self.sector_index;
+      self.sector_index += 1;
+      return Some(sector);
+    }
+
+    if self.cluster_index >= cluster_count {
+      return None;
+    }
+    let current_cluster = self.clusters[self.cluster_index].as_usize();
+    if current_cluster < 2 {
+      return None;
+    }
+    let cluster_start =
+      self.data_sectors.get_first_sector() +
+      (current_cluster - 2) * self.sectors_per_cluster;
+    let sector = cluster_start + self.sector_index;
+    self.sector_index += 1;
+    if self.sector_index >= self.sectors_per_cluster {
+      self.cluster_index += 1;
+      self.sector_index = 0;
+    }
+    Some(sector)
+  }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FatEntry {
+  NextCluster(Cluster),
+  EndOfChain,
+  Free,
+  BadSector,
+  Reserved,
+  TemporaryAllocation,
+}
+
+impl FatEntry {
+  pub fn from_value(value: u16) -> FatEntry {
+    match value {
+      0 => FatEntry::Free,
+      1 => FatEntry::TemporaryAllocation,
+      0xff0..=0xff5 => FatEntry::EndOfChain,
+      0xff6 => FatEntry::Reserved,
+      0xff7 => FatEntry::BadSector,
+      0xff8..=0xfff => FatEntry::EndOfChain,
+      _ => FatEntry::NextCluster(Cluster::new(value as usize)),
+    }
+  }
+
+  /// The 12-bit value stored in the table for this entry
+  pub fn to_value(&self) -> u16 {
+    match self {
+      FatEntry::NextCluster(cluster) => cluster.as_usize() as u16 & 0xfff,
+      FatEntry::EndOfChain => 0xfff,
+      FatEntry::Free => 0,
+      FatEntry::BadSector => 0xff7,
+      FatEntry::Reserved => 0xff6,
+      FatEntry::TemporaryAllocation => 1,
+    }
+  }
+
+  pub fn has_next(&self) -> bool {
+    match self {
+      FatEntry::NextCluster(_) => true,
+      _ => false,
+    }
+  }
+}
+
+/// Byte offset of a cluster's entry from the start of a FAT table. Each entry
+/// is 12 bits long, so it may straddle a sector boundary.
+pub fn get_entry_offset(cluster: Cluster) -> usize {
+  cluster.as_usize() * 3 / 2
+}
+
+/// Decode a cluster's entry from the two bytes starting at its offset
+pub fn entry_from_bytes(cluster: Cluster, bytes: [u8; 2]) -> FatEntry {
+  let value = (bytes[0] as u16) | ((bytes[1] as u16) << 8);
+  if cluster.as_usize() & 1 == 0 {
+    FatEntry::from_value(value & 0xfff)
+  } else {
+    FatEntry::from_value(value >> 4)
+  }
+}
+
+/// Encode a cluster's entry into the two bytes starting at its offset. Each
+/// byte pair is shared with a neighboring entry, so the bytes currently on disk
+/// are needed to preserve the other half.
+pub fn entry_to_bytes(cluster: Cluster, current: [u8; 2], entry: FatEntry) -> [u8; 2] {
+  let existing = (current[0] as u16) | ((current[1] as u16) << 8);
+  let value = entry.to_value();
+  let updated = if cluster.as_usize() & 1 == 0 {
+    (existing & 0xf000) | value
+  } else {
+    (existing & 0x000f) | (value << 4)
+  };
+  [updated as u8, (updated >> 8) as u8]
+}
+
+/// Most clusters the free cluster cache remembers at once
+pub const FREE_CLUSTER_CACHE_SIZE: usize = 32;
+
+/// Clusters known to be free, so that allocating one doesn't mean scanning
+/// the FAT from the start each time. The cache is filled by scanning forward
+/// from where the previous scan stopped, wrapping around at the end of the
+/// disk. Freed clusters are added back as they're released, and the lowest
+/// known cluster is always handed out first, keeping files near the start of
+/// the disk.
+/// The cache is only a hint: an entry can go stale if the table changes
+/// behind its back, so callers check each cluster before claiming it.
+pub struct FreeClusters {
+  known: BTreeSet<usize>,
+  /// Where the next scan picks up
+  scan_from: usize,
+}
+
+impl FreeClusters {
+  pub fn new() -> FreeClusters {
+    FreeClusters {
+      known: BTreeSet::new(),
+      scan_from: 2,
+    }
+  }
+
+  /// Remove and return the lowest cluster believed to be free
+  pub fn take(&mut self) -> Option<Cluster> {
+    let lowest = *self.known.iter().next()?;
+    self.known.remove(&lowest);
+    Some(Cluster::new(lowest))
+  }
+
+  /// Remember a cluster that was just freed. Once the cache is full, the
+  /// highest cluster is forgotten; a later scan will find it again.
+  pub fn add(&mut self, cluster: Cluster) {
+    self.known.insert(cluster.as_usize());
+    if self.known.len() > FREE_CLUSTER_CACHE_SIZE {
+      let highest = *self.known.iter().next_back().unwrap();
+      self.known.remove(&highest);
+    }
+  }
+
+  /// Scan the table for more free clusters until the cache is full, or every
+  /// cluster on the disk has been looked at once
+  pub fn refill<F>(&mut self, max_cluster: usize, mut is_free: F) -> Result<(), ()>
+    where F: FnMut(Cluster) -> Result<bool, ()> {
+    let mut index = self.scan_from;
+    for _ in 2..=max_cluster {
+      if self.known.len() >= FREE_CLUSTER_CACHE_SIZE {
+        break;
+      }
+      if index < 2 || index > max_cluster {
+        index = 2;
+      }
+      if is_free(Cluster::new(index))? {
+        self.known.insert(index);
+      }
+      index += 1;
+    }
+    self.scan_from = index;
+    Ok(())
+  }
+}
+
+pub struct FatSection<'table> {
+  /// Pointer to a FAT table currently cached in memory
+  section: &'table mut [u8],
+  /// Offset of the first cluster in the table. FAT12 tables are not sector-
+  /// aligned, so some sectors may start with the end of a previous cluster
+  byte_offset: usize,
+  /// Cluster ID of the first entry after byte_offset
+  first_cluster: Cluster,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FatValueResult {
+  /// Indicates the requested cluster comes before the start of the table
+  OutOfBoundsBefore,
+  /// Indicates the requested cluster comes after the end of the table
+  OutOfBoundsAfter,
+  /// Indicates the value wraps beyond the end of the table, and returns the
+  /// lowest 8 bits of the entry
+  Partial8(u8),
+  /// Similar to Partial8, but returns the lower 4 bits of the entry
+  Partial4(u8),
+  /// Returns a FatEntry that was fully contained within the table
+  Success(FatEntry), 
+}
+
+impl<'table> FatSection<'table> {
+  pub fn at_slice(section: &'table mut [u8], byte_offset: usize, first_cluster: Cluster) -> FatSection<'table> {
+    FatSection {
+      section,
+      byte_offset,
+      first_cluster,
+    }
+  }
+
+  pub fn get_value(&self, cluster: Cluster) -> FatValueResult {
+    let target_cluster = cluster.as_usize();
+    let first_cluster = self.first_cluster.as_usize();
+    if target_cluster < first_cluster {
+      return FatValueResult::OutOfBoundsBefore;
+    }
+    let distance = target_cluster - first_cluster;
+    let triad_start = (distance / 2) * 3 + self.byte_offset;
+    if triad_start >= self.section.len() {
+      return FatValueResult::OutOfBoundsAfter;
+    }
+    let triad_offset = distance & 1;
+    let byte_addr = triad_start + triad_offset;
+    if self.section.len() - byte_addr < 2 {
+      if triad_offset == 0 {
+        return FatValueResult::Partial8(self.section[byte_addr]);
+      }
+      return FatValueResult::Partial4(self.section[byte_addr] >> 4);
+    }
+
+    let low = self.section[byte_addr];
+    let high = self.section[byte_addr + 1];
+    let mut value = (low as u16) | ((high as u16) << 8);
+    value >>= triad_offset * 4;
+    value &= 0xfff;
+
+    FatValueResult::Success(FatEntry::from_value(value))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+  use super::super::errors::FatError;
+  use super::{
+    Cluster, FatEntry, FatSection, FatValueResult, FreeClusters, FREE_CLUSTER_CACHE_SIZE, entry_from_bytes,
+    entry_to_bytes, follow_chain, get_entry_offset,
+  };
+
+  #[test]
+  fn simple_fetch() {
+    let mut mem = [0xf0, 0xff, 0xff, 0x03, 0x40, 0x00, 0x05, 0xf0, 0xff, 0x00];
+    let section = FatSection::at_slice(&mut mem, 0, Cluster::new(0));
+    assert_eq!(section.get_value(Cluster::new(0)), FatValueResult::Success(FatEntry::EndOfChain));
+    assert_eq!(section.get_value(Cluster::new(1)), FatValueResult::Success(FatEntry::EndOfChain));
+    assert_eq!(section.get_value(Cluster::new(2)), FatValueResult::Success(FatEntry::NextCluster(Cluster::new(3))));
+    assert_eq!(section.get_value(Cluster::new(3)), FatValueResult::Success(FatEntry::NextCluster(Cluster::new(4))));
+    assert_eq!(section.get_value(Cluster::new(4)), FatValueResult::Success(FatEntry::NextCluster(Cluster::new(5))));
+    assert_eq!(section.get_value(Cluster::new(5)), FatValueResult::Success(FatEntry::EndOfChain));
+    assert_eq!(section.get_value(Cluster::new(6)), FatValueResult::Partial8(0));
+  }
+
+  #[test]
+  fn offset_table() {
+    let mut offset_one = [0x6f, 0x08, 0x90, 0x00, 0xff, 0x0f, 0x00, 0x0c, 0xf0, 0x00];
+    let section_one = FatSection::at_slice(&mut offset_one, 1, Cluster::new(7));
+    assert_eq!(section_one.get_value(Cluster::new(6)), FatValueResult::OutOfBoundsBefore);
+    assert_eq!(section_one.get_value(Cluster::new(7)), FatValueResult::Success(FatEntry::NextCluster(Cluster::new(8))));
+    assert_eq!(section_one.get_value(Cluster::new(8)), FatValueResult::Success(FatEntry::NextCluster(Cluster::new(9))));
+    assert_eq!(section_one.get_value(Cluster::new(9)), FatValueResult::Success(FatEntry::EndOfChain));
+    assert_eq!(section_one.get_value(Cluster::new(0xa)), FatValueResult::Success(FatEntry::Free));
+    assert_eq!(section_one.get_value(Cluster::new(0xb)), FatValueResult::Success(FatEntry::NextCluster(Cluster::new(0xc))));
+    assert_eq!(section_one.get_value(Cluster::new(0xc)), FatValueResult::Success(FatEntry::NextCluster(Cluster::new(0xf))));
+
+    let mut offset_two = [0x6f, 0xff, 0xf7, 0xaf, 0x10, 0x00, 0x00, 0x00, 0x1f, 0x23];
+    let section_two = FatSection::at_slice(&mut offset_two, 2, Cluster::new(0x10));
+    assert_eq!(section_two.get_value(Cluster::new(0x10)), FatValueResult::Success(FatEntry::BadSector));
+    assert_eq!(section_two.get_value(Cluster::new(0x11)), FatValueResult::Success(FatEntry::NextCluster(Cluster::new(0x10a))));
+    assert_eq!(section_two.get_value(Cluster::new(0x14)), FatValueResult::Success(FatEntry::NextCluster(Cluster::new(0x31f))));
+    assert_eq!(section_two.get_value(Cluster::new(0x15)), FatValueResult::Partial4(2));
+
+  }
+
+  #[test]
+  fn entry_at_offset() {
+    let mem = [0xf0, 0xff, 0xff, 0x03, 0x40, 0x00, 0x05, 0xf0, 0xff, 0x00];
+    let lookup = |index: usize| {
+      let cluster = Cluster::new(index);
+      let offset = get_entry_offset(cluster);
+      entry_from_bytes(cluster, [mem[offset], mem[offset + 1]])
+    };
+    assert_eq!(lookup(1), FatEntry::EndOfChain);
+    assert_eq!(lookup(2), FatEntry::NextCluster(Cluster::new(3)));
+    assert_eq!(lookup(3), FatEntry::NextCluster(Cluster::new(4)));
+    assert_eq!(lookup(4), FatEntry::NextCluster(Cluster::new(5)));
+    assert_eq!(lookup(5), FatEntry::EndOfChain);
+  }
+
+  #[test]
+  fn update_entry_preserves_neighbors() {
+    let mut mem = [0xf0, 0xff, 0xff, 0x03, 0x40, 0x00, 0x05, 0xf0, 0xff, 0x00];
+    let mut update = |index: usize, entry: FatEntry| {
+      let cluster = Cluster::new(index);
+      let offset = get_entry_offset(cluster);
+      let bytes = entry_to_bytes(cluster, [mem[offset], mem[offset + 1]], entry);
+      mem[offset] = bytes[0];
+      mem[offset + 1] = bytes[1];
+    };
+    update(3, FatEntry::Free);
+    update(4, FatEntry::EndOfChain);
+    update(2, FatEntry::NextCluster(Cluster::new(0x123)));
+    let lookup = |index: usize| {
+      let cluster = Cluster::new(index);
+      let offset = get_entry_offset(cluster);
+      entry_from_bytes(cluster, [mem[offset], mem[offset + 1]])
+    };
+    assert_eq!(lookup(1), FatEntry::EndOfChain);
+    assert_eq!(lookup(2), FatEntry::NextCluster(Cluster::new(0x123)));
+    assert_eq!(lookup(3), FatEntry::Free);
+    assert_eq!(lookup(4), FatEntry::EndOfChain);
+    assert_eq!(lookup(5), FatEntry::EndOfChain);
+  }
+
+  #[test]
+  fn cyclic_chain() {
+    // 2 -> 3 -> 4 -> 3
+    let table = [0, 0, 3, 4, 3, 0xfff];
+    let lookup = |cluster: Cluster| Ok(FatEntry::from_value(table[cluster.as_usize()]));
+    assert_eq!(follow_chain(Cluster::new(2), 5, lookup), Err(FatError::ClusterLoop));
+    // A cluster pointing at itself
+    assert_eq!(follow_chain(Cluster::new(4), 5, |_| Ok(FatEntry::NextCluster(Cluster::new(4)))), Err(FatError::ClusterLoop));
+    // Pointing past the end of the disk
+    assert_eq!(follow_chain(Cluster::new(2), 3, lookup), Err(FatError::InvalidCluster));
+    assert_eq!(follow_chain(Cluster::new(2), 5, |_| Err(())), Err(FatError::ReadFailed));
+
+    let chain = follow_chain(Cluster::new(5), 5, lookup).unwrap();
+    assert_eq!(chain, [Cluster::new(5)]);
+  }
+
+  #[test]
+  fn free_cluster_scan_wraps() {
+    let mut table = [true; 50];
+    table[3] = false;
+    let mut free = FreeClusters::new();
+    free.refill(49, |cluster| Ok(table[cluster.as_usize()])).unwrap();
+    assert_eq!(free.take(), Some(Cluster::new(2)));
+    assert_eq!(free.take(), Some(Cluster::new(4)));
+    // The first scan stopped once the cache was full
+    let mut taken = 2;
+    while free.take().is_some() {
+      taken += 1;
+    }
+    assert_eq!(taken, FREE_CLUSTER_CACHE_SIZE);
+
+    // The next scan continues from there, and wraps to the start of the disk
+    table[2] = false;
+    free.refill(49, |cluster| Ok(table[cluster.as_usize()])).unwrap();
+    let mut clusters = Vec::new();
+    while let Some(cluster) = free.take() {
+      clusters.push(cluster.as_usize());
+    }
+    assert_eq!(clusters.first(), Some(&4));
+    assert_eq!(clusters.last(), Some(&49));
+    assert_eq!(clusters.len(), FREE_CLUSTER_CACHE_SIZE);
+
+    // Freed clusters are remembered, pushing out the highest
+    free.refill(49, |_| Ok(true)).unwrap();
+    free.add(Cluster::new(10));
+    assert_eq!(free.known.len(), FREE_CLUSTER_CACHE_SIZE);
+    assert!(!free.known.contains(&49));
+    assert_eq!(free.take(), Some(Cluster::new(2)));
+
+    // A full disk leaves the cache empty
+    let mut free = FreeClusters::new();
+    free.refill(49, |_| Ok(false)).unwrap();
+    assert_eq!(free.take(), None);
+  }
+}
@@ -116,9 +116,49 @@ pub fn file_name_components_from_string(s: &str) -> ([u8; 8], [u8; 3]) {
   return (name, ext);
 }
 
+/// Characters that DOS does not allow in short file names
+const ILLEGAL_NAME_CHARACTERS: &[u8] = b"\"*+,/:;<=>?[\\]|. ";
+
+/// Convert a name into the padded, upper-case form stored in a directory
+/// entry. Unlike `file_name_components_from_string`, which is used for
+/// searching, the name is rejected if it doesn't fit the 8.3 format exactly or
+/// contains characters that can't be stored on disk.
+pub fn short_name_from_string(s: &str) -> Option<([u8; 8], [u8; 3])> {
+  let mut parts = s.splitn(2, '.');
+  let base = parts.next()?.as_bytes();
+  let extension = parts.next().unwrap_or("").as_bytes();
+  if base.is_empty() || base.len() > 8 || extension.len() > 3 {
+    return None;
+  }
+  let mut name: [u8; 8] = [0x20; 8];
+  let mut ext: [u8; 3] = [0x20; 3];
+  for (dest, source) in [(&mut name[..], base), (&mut ext[..], extension)].iter_mut() {
+    for (index, ch) in source.iter().enumerate() {
+      if *ch < 0x21 || *ch > 0x7e || ILLEGAL_NAME_CHARACTERS.contains(ch) {
+        return None;
+      }
+      dest[index] = ch.to_ascii_uppercase();
+    }
+  }
+  Some((name, ext))
+}
+
 #[cfg(test)]
 mod tests {
-  use super::file_name_components_from_string;
+  use super::{file_name_components_from_string, short_name_from_string};
+
+  #[test]
+  fn short_names_for_new_entries() {
+    assert_eq!(short_name_from_string("readme.txt"), Some((*b"README  ", *b"TXT")));
+    assert_eq!(short_name_from_string("COMMAND"), Some((*b"COMMAND ", *b"   ")));
+    assert_eq!(short_name_from_string("toolongname.txt"), None);
+    assert_eq!(short_name_from_string("file.text"), None);
+    assert_eq!(short_name_from_string("a.b.c"), None);
+    assert_eq!(short_name_from_string(".txt"), None);
+    assert_eq!(short_name_from_string("what?.txt"), None);
+    assert_eq!(short_name_from_string("two words"), None);
+    assert_eq!(short_name_from_string(""), None);
+  }
 
   #[test]
   fn file_name_from_string() {
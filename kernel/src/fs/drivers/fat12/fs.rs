@@ -0,0 +1,1473 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::collections::SlotList;
+use crate::devices::block::IOCTL_SET_GEOMETRY;
+use crate::devices::driver::DeviceDriverType;
+use crate::files::cursor::{SeekMethod, SharedCursor};
+use crate::files::handle::{Handle, LocalHandle};
+use crate::fs::KernelFileSystem;
+use crate::fs::filesystem::FileAccess;
+use crate::task::id::ProcessID;
+use crate::locks::{Mutex, RwLock};
+use super::directory::DirectoryEntry;
+use super::disk::{BiosParamBlock, DiskConfig, BOOT_SECTOR_SIZE, DIRECTORY_ENTRY_SIZE};
+use super::errors::FatError;
+use super::fat::{
+  entry_from_bytes, entry_to_bytes, follow_chain, get_entry_offset, Cluster, ClusterChain, FatEntry, FreeClusters,
+};
+use super::file::{FileType, file_name_components_from_string, short_name_from_string};
+use syscall::files::{DirEntryInfo, DirEntryType, FileStatus};
+use syscall::flags::{
+  FAT_ATTR_ARCHIVE, FAT_ATTR_HIDDEN, FAT_ATTR_READ_ONLY, FAT_ATTR_SYSTEM, FAT_IOCTL_GET_ATTRIBUTES,
+  FAT_IOCTL_SET_ATTRIBUTES,
+};
+
+/// Attribute bit marking an entry as a subdirectory
+const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+/// Attribute bit set on files that have changed since they were backed up
+const ATTRIBUTE_ARCHIVE: u8 = 0x20;
+/// Attribute bits that can be changed on a file through an ioctl. Whether an
+/// entry is a directory or a volume label is fixed when it's created.
+const CHANGEABLE_ATTRIBUTES: u8 = FAT_ATTR_READ_ONLY | FAT_ATTR_HIDDEN | FAT_ATTR_SYSTEM | FAT_ATTR_ARCHIVE;
+/// Directories nested deeper than this are assumed to be a loop on a corrupt
+/// disk
+const MAX_DIRECTORY_DEPTH: usize = 64;
+
+#[derive(Clone)]
+struct OpenFile {
+  cursor: SharedCursor,
+  byte_size: usize,
+  clusters: ClusterChain,
+  /// The directory containing the file's entry, and the entry's index
+  /// within it
+  directory: ClusterChain,
+  entry_index: usize,
+}
+
+struct OpenDirectory {
+  /// Index of the next directory entry to read
+  index: usize,
+  /// An empty chain refers to the root directory
+  clusters: ClusterChain,
+}
+
+enum OpenHandle {
+  File(OpenFile),
+  Directory(OpenDirectory),
+}
+
+/// Where an entry was found: the directory containing it, and its index within
+/// that directory
+struct EntryLocation {
+  directory: ClusterChain,
+  index: usize,
+  entry: DirectoryEntry,
+}
+
+/// FAT12 filesystem, backed by a block device like a floppy drive. All disk
+/// access goes through the device driver, which exposes the disk as a stream
+/// of bytes. Files can't be written yet, but the directory tree can be
+/// modified.
+pub struct Fat12FileSystem {
+  driver: Arc<Box<DeviceDriverType>>,
+  config: DiskConfig,
+  open_handles: RwLock<SlotList<OpenHandle>>,
+  /// Held while directories or the FAT are modified, so that two changes
+  /// can't both claim the same free slot or cluster
+  modification: Mutex<()>,
+  /// First clusters of files that were deleted while still open. Their
+  /// clusters stay allocated until the last handle is closed.
+  unlinked: RwLock<Vec<Cluster>>,
+  /// Clusters that were free the last time the FAT was checked, filled when
+  /// the disk is mounted
+  free_clusters: Mutex<FreeClusters>,
+}
+
+impl Fat12FileSystem {
+  /// Read the boot sector from a block device, and construct a filesystem
+  /// from its BIOS Parameter Block. The device is configured with the
+  /// geometry described by the BPB.
+  pub fn mount(driver: Arc<Box<DeviceDriverType>>) -> Result<Fat12FileSystem, FatError> {
+    let mut boot_sector = [0u8; BOOT_SECTOR_SIZE];
+    read_from_device(&driver, 0, &mut boot_sector).map_err(|_| FatError::NoDisk)?;
+    let bpb = BiosParamBlock::from_boot_sector(&boot_sector)?;
+    let config = DiskConfig::from_bpb(&bpb);
+
+    let (sectors_per_track, heads) = config.get_geometry();
+    let geometry = ((heads as u32) << 16) | (sectors_per_track as u32);
+    let handle = driver.open().map_err(|_| FatError::NoDisk)?;
+    let result = driver.ioctl(handle, IOCTL_SET_GEOMETRY, geometry);
+    let _ = driver.close(handle);
+    result.map_err(|_| FatError::InvalidParamBlock)?;
+
+    let fs = Fat12FileSystem {
+      driver,
+      config,
+      open_handles: RwLock::new(SlotList::new()),
+      modification: Mutex::new(()),
+      unlinked: RwLock::new(Vec::new()),
+      free_clusters: Mutex::new(FreeClusters::new()),
+    };
+    // If the table can't be read yet, the cache starts out empty and is
+    // filled on the first allocation instead
+    let _ = fs.refill_free_clusters(&mut fs.free_clusters.lock());
+    Ok(fs)
+  }
+
+  /// Copy bytes from the disk, starting at an absolute byte offset. Reads are
+  /// split at sector boundaries, so the device never has to fetch more than
+  /// one sector at a time.
+  fn read_bytes(&self, position: usize, buffer: &mut [u8]) -> Result<(), ()> {
+    let sector_size = self.config.get_bytes_per_sector();
+    let mut copied = 0;
+    while copied < buffer.len() {
+      let current = position + copied;
+      let to_sector_end = sector_size - (current % sector_size);
+      let length = to_sector_end.min(buffer.len() - copied);
+      read_from_device(&self.driver, current, &mut buffer[copied..(copied + length)])?;
+      copied += length;
+    }
+    Ok(())
+  }
+
+  /// Copy bytes to the disk, starting at an absolute byte offset. Like reads,
+  /// writes are split at sector boundaries.
+  fn write_bytes(&self, position: usize, buffer: &[u8]) -> Result<(), ()> {
+    let sector_size = self.config.get_bytes_per_sector();
+    let mut copied = 0;
+    while copied < buffer.len() {
+      let current = position + copied;
+      let to_sector_end = sector_size - (current % sector_size);
+      let length = to_sector_end.min(buffer.len() - copied);
+      write_to_device(&self.driver, current, &buffer[copied..(copied + length)])?;
+      copied += length;
+    }
+    Ok(())
+  }
+
+  fn get_fat_entry(&self, cluster: Cluster) -> Result<FatEntry, ()> {
+    let fat_start = self.config.get_fat_sectors(0).map_err(|_| ())?.get_first_sector();
+    let position = fat_start * self.config.get_bytes_per_sector() + get_entry_offset(cluster);
+    let mut bytes = [0u8; 2];
+    self.read_bytes(position, &mut bytes)?;
+    Ok(entry_from_bytes(cluster, bytes))
+  }
+
+  /// Update a cluster's entry in every copy of the FAT
+  fn set_fat_entry(&self, cluster: Cluster, entry: FatEntry) -> Result<(), ()> {
+    let mut table = 0;
+    while let Ok(sectors) = self.config.get_fat_sectors(table) {
+      let position = sectors.get_first_sector() * self.config.get_bytes_per_sector() + get_entry_offset(cluster);
+      let mut bytes = [0u8; 2];
+      self.read_bytes(position, &mut bytes)?;
+      self.write_bytes(position, &entry_to_bytes(cluster, bytes, entry))?;
+      table += 1;
+    }
+    Ok(())
+  }
+
+  /// Mark every cluster belonging to a file as free. Files with no data have
+  /// a first cluster of 0, and own nothing.
+  fn free_cluster_chain(&self, first_cluster: Cluster) -> Result<(), ()> {
+    if first_cluster.as_usize() == 0 {
+      return Ok(());
+    }
+    let chain = self.get_cluster_chain(first_cluster)?;
+    let mut free = self.free_clusters.lock();
+    for cluster in chain.clusters.iter() {
+      self.set_fat_entry(*cluster, FatEntry::Free)?;
+      free.add(*cluster);
+    }
+    Ok(())
+  }
+
+  fn refill_free_clusters(&self, free: &mut FreeClusters) -> Result<(), ()> {
+    let max_cluster = self.config.get_cluster_count() + 1;
+    free.refill(max_cluster, |cluster| Ok(self.get_fat_entry(cluster)? == FatEntry::Free))
+  }
+
+  /// Claim a free cluster, marking it as the end of a chain. Candidates come
+  /// from the free cluster cache, and the table is only scanned once the
+  /// cache runs dry. Each candidate is checked before it's used, in case the
+  /// table was changed without going through the cache. Returns None if the
+  /// disk is full.
+  fn allocate_cluster(&self) -> Result<Option<Cluster>, ()> {
+    let mut free = self.free_clusters.lock();
+    loop {
+      let candidate = match free.take() {
+        Some(cluster) => cluster,
+        None => {
+          self.refill_free_clusters(&mut free)?;
+          match free.take() {
+            Some(cluster) => cluster,
+            None => return Ok(None),
+          }
+        },
+      };
+      if self.get_fat_entry(candidate)? == FatEntry::Free {
+        self.set_fat_entry(candidate, FatEntry::EndOfChain)?;
+        return Ok(Some(candidate));
+      }
+    }
+  }
+
+  /// Find the lowest-numbered cluster that isn't allocated to any file, by
+  /// scanning the whole table. New clusters are claimed through
+  /// `allocate_cluster`, which avoids the scan.
+  pub fn first_free_cluster(&self) -> Result<Option<Cluster>, ()> {
+    let max_cluster = self.config.get_cluster_count() + 1;
+    for index in 2..=max_cluster {
+      let cluster = Cluster::new(index);
+      if let FatEntry::Free = self.get_fat_entry(cluster)? {
+        return Ok(Some(cluster));
+      }
+    }
+    Ok(None)
+  }
+
+  /// Chains that loop or leave the disk can only come from a corrupt table,
+  /// and are treated like any other unreadable file
+  fn get_cluster_chain(&self, first_cluster: Cluster) -> Result<ClusterChain, ()> {
+    let max_cluster = self.config.get_cluster_count() + 1;
+    follow_chain(first_cluster, max_cluster, |cluster| self.get_fat_entry(cluster))
+      .map(ClusterChain::from_vec)
+      .map_err(|_| ())
+  }
+
+  fn get_directory_clusters(&self, entry: &DirectoryEntry) -> Result<ClusterChain, ()> {
+    if entry.get_first_cluster().as_usize() == 0 {
+      return Ok(ClusterChain::empty());
+    }
+    self.get_cluster_chain(entry.get_first_cluster())
+  }
+
+  /// Find the absolute byte position of an entry slot, or None if the index
+  /// is past the end of the directory's storage
+  fn get_directory_entry_position(&self, directory: &ClusterChain, index: usize) -> Option<usize> {
+    let (sector_index, local_index) = self.config.get_directory_index_location(index);
+    let sector = directory.sector_iter(&self.config).nth(sector_index)?;
+    Some(sector * self.config.get_bytes_per_sector() + local_index * DIRECTORY_ENTRY_SIZE)
+  }
+
+  /// Read the raw contents of a directory slot, including unused ones
+  fn read_directory_slot(&self, directory: &ClusterChain, index: usize) -> Result<Option<DirectoryEntry>, ()> {
+    let position = match self.get_directory_entry_position(directory, index) {
+      Some(position) => position,
+      None => return Ok(None),
+    };
+    let mut bytes = [0u8; DIRECTORY_ENTRY_SIZE];
+    self.read_bytes(position, &mut bytes)?;
+    DirectoryEntry::from_bytes(&bytes).ok_or(()).map(Some)
+  }
+
+  fn write_directory_slot(&self, directory: &ClusterChain, index: usize, entry: &DirectoryEntry) -> Result<(), ()> {
+    let position = self.get_directory_entry_position(directory, index).ok_or(())?;
+    self.write_bytes(position, &entry.to_bytes())
+  }
+
+  /// Read an entry from a directory. Returns None once the end of the
+  /// directory has been reached.
+  fn get_directory_entry(&self, directory: &ClusterChain, index: usize) -> Result<Option<DirectoryEntry>, ()> {
+    match self.read_directory_slot(directory, index)? {
+      Some(entry) if !entry.is_empty() => Ok(Some(entry)),
+      _ => Ok(None),
+    }
+  }
+
+  /// Find a slot that a new entry can be written to, reusing deleted entries
+  /// before the end of the directory. Directories are never grown, so a full
+  /// directory is an error.
+  fn find_free_slot(&self, directory: &ClusterChain) -> Result<usize, ()> {
+    let mut index = 0;
+    while let Some(entry) = self.read_directory_slot(directory, index)? {
+      if entry.is_empty() || entry.is_deleted() {
+        return Ok(index);
+      }
+      index += 1;
+    }
+    Err(())
+  }
+
+  fn find_entry_in_directory(&self, name: &[u8; 8], ext: &[u8; 3], directory: &ClusterChain) -> Result<Option<(usize, DirectoryEntry)>, ()> {
+    let mut index = 0;
+    while let Some((found_index, entry)) = self.find_listed_entry(directory, index)? {
+      if entry.name_matches_search(name, ext) {
+        return Ok(Some((found_index, entry)));
+      }
+      index = found_index + 1;
+    }
+    Ok(None)
+  }
+
+  /// Find the first entry at or after an index that should appear in a
+  /// directory listing, skipping deleted files and volume labels
+  fn find_listed_entry(&self, directory: &ClusterChain, start: usize) -> Result<Option<(usize, DirectoryEntry)>, ()> {
+    let mut index = start;
+    while let Some(entry) = self.get_directory_entry(directory, index)? {
+      let hidden = match entry.get_file_type() {
+        FileType::VolumeLabel => true,
+        _ => entry.is_deleted(),
+      };
+      if !hidden {
+        return Ok(Some((index, entry)));
+      }
+      index += 1;
+    }
+    Ok(None)
+  }
+
+  /// Walk a path from the root directory, returning the entry it points to
+  /// along with where it was found. An empty path refers to the root itself,
+  /// which has no entry.
+  fn find_path_location(&self, path: &str) -> Result<Option<EntryLocation>, ()> {
+    let mut found: Option<EntryLocation> = None;
+    for part in path.split('\\').filter(|part| !part.is_empty()) {
+      let directory = match found {
+        Some(location) => {
+          if !location.entry.get_file_type().is_directory() {
+            return Err(());
+          }
+          self.get_directory_clusters(&location.entry)?
+        },
+        None => ClusterChain::empty(),
+      };
+      let (name, ext) = file_name_components_from_string(part);
+      let (index, entry) = self.find_entry_in_directory(&name, &ext, &directory)?.ok_or(())?;
+      found = Some(EntryLocation { directory, index, entry });
+    }
+    Ok(found)
+  }
+
+  fn find_path(&self, path: &str) -> Result<Option<DirectoryEntry>, ()> {
+    self.find_path_location(path).map(|found| found.map(|location| location.entry))
+  }
+
+  /// Get the contents of the directory at a path, which may be the root
+  fn find_directory(&self, path: &str) -> Result<ClusterChain, ()> {
+    match self.find_path(path)? {
+      None => Ok(ClusterChain::empty()),
+      Some(entry) if entry.get_file_type().is_directory() => self.get_directory_clusters(&entry),
+      Some(_) => Err(()),
+    }
+  }
+
+  /// Check whether a directory contains anything besides its "." and ".."
+  /// entries
+  fn has_contents(&self, directory: &ClusterChain) -> Result<bool, ()> {
+    let mut index = 0;
+    while let Some((found_index, entry)) = self.find_listed_entry(directory, index)? {
+      if !is_dot_entry(&entry) {
+        return Ok(true);
+      }
+      index = found_index + 1;
+    }
+    Ok(false)
+  }
+
+  /// Check whether any open file is reading from a chain of clusters
+  fn is_chain_open(&self, first_cluster: Cluster) -> bool {
+    self.open_handles.read().iter().any(|handle| match handle {
+      OpenHandle::File(open_file) => open_file.clusters.clusters.first() == Some(&first_cluster),
+      _ => false,
+    })
+  }
+
+  /// Remove a file that is about to be replaced, releasing its clusters
+  fn remove_replaced_file(&self, location: &EntryLocation) -> Result<(), ()> {
+    if !location.entry.get_file_type().is_file() {
+      return Err(());
+    }
+    let first_cluster = location.entry.get_first_cluster();
+    if first_cluster.as_usize() != 0 && self.is_chain_open(first_cluster) {
+      return Err(());
+    }
+    let mut removed = location.entry;
+    removed.mark_deleted();
+    self.write_directory_slot(&location.directory, location.index, &removed)?;
+    self.free_cluster_chain(first_cluster)
+  }
+
+  /// Build the path of a directory by following its ".." entries up to the
+  /// root, and finding its name in each parent along the way. If any step is
+  /// missing, the directory has been removed.
+  fn find_directory_path(&self, directory: &ClusterChain) -> Result<Option<String>, ()> {
+    let mut names = Vec::new();
+    let mut current = directory.clone();
+    while let Some(&cluster) = current.clusters.first() {
+      if names.len() >= MAX_DIRECTORY_DEPTH {
+        return Err(());
+      }
+      // A directory that still exists starts with its "." and ".." entries,
+      // and the "." entry points back at the directory itself
+      let self_link = self.read_directory_slot(&current, 0)?;
+      let parent_link = self.read_directory_slot(&current, 1)?;
+      let parent_link = match (self_link, parent_link) {
+        (Some(self_link), Some(parent_link)) => {
+          let linked = self_link.get_name() == b".       " && self_link.get_first_cluster() == cluster;
+          if !linked || parent_link.get_name() != b"..      " {
+            return Ok(None);
+          }
+          parent_link
+        },
+        _ => return Ok(None),
+      };
+      let parent = self.get_directory_clusters(&parent_link)?;
+      let mut index = 0;
+      let mut name = None;
+      while let Some((found_index, entry)) = self.find_listed_entry(&parent, index)? {
+        let is_link = entry.get_file_type().is_directory() && !is_dot_entry(&entry);
+        if is_link && entry.get_first_cluster() == cluster {
+          name = Some(display_name(&entry));
+          break;
+        }
+        index = found_index + 1;
+      }
+      match name {
+        Some(name) => names.push(name),
+        None => return Ok(None),
+      }
+      current = parent;
+    }
+    let mut path = String::new();
+    for name in names.iter().rev() {
+      if !path.is_empty() {
+        path.push('\\');
+      }
+      path.push_str(name);
+    }
+    Ok(Some(path))
+  }
+
+  /// Read the directory entry of an open file. If the entry has since been
+  /// deleted or moved elsewhere, it no longer belongs to the handle.
+  fn read_open_file_entry(&self, open_file: &OpenFile) -> Result<DirectoryEntry, ()> {
+    let entry = self.read_directory_slot(&open_file.directory, open_file.entry_index)?.ok_or(())?;
+    if entry.is_empty() || entry.is_deleted() || !entry.get_file_type().is_file() {
+      return Err(());
+    }
+    if let Some(first_cluster) = open_file.clusters.clusters.first() {
+      if entry.get_first_cluster() != *first_cluster {
+        return Err(());
+      }
+    }
+    Ok(entry)
+  }
+
+  fn get_open_file(&self, handle: LocalHandle) -> Result<OpenFile, ()> {
+    match self.open_handles.read().get(handle.as_usize()) {
+      Some(OpenHandle::File(open_file)) => Ok(open_file.clone()),
+      _ => Err(()),
+    }
+  }
+
+  /// Get the attribute bits of an open file
+  pub fn get_attributes(&self, handle: LocalHandle) -> Result<u8, ()> {
+    let open_file = self.get_open_file(handle)?;
+    Ok(self.read_open_file_entry(&open_file)?.get_attributes())
+  }
+
+  /// Replace the read-only, hidden, system, and archive bits of an open file,
+  /// returning the previous attributes. Any other bit is an error, since a
+  /// file can't be turned into a directory or volume label.
+  pub fn set_attributes(&self, handle: LocalHandle, attributes: u8) -> Result<u8, ()> {
+    if attributes & !CHANGEABLE_ATTRIBUTES != 0 {
+      return Err(());
+    }
+    let open_file = self.get_open_file(handle)?;
+    let _modifying = self.modification.lock();
+    let mut entry = self.read_open_file_entry(&open_file)?;
+    let previous = entry.get_attributes();
+    entry.set_attributes((previous & !CHANGEABLE_ATTRIBUTES) | attributes);
+    self.write_directory_slot(&open_file.directory, open_file.entry_index, &entry)?;
+    Ok(previous)
+  }
+
+  fn insert_handle(&self, open_handle: OpenHandle) -> LocalHandle {
+    let index = self.open_handles.write().insert(open_handle);
+    LocalHandle::new(index as u32)
+  }
+}
+
+/// Split a path into its parent directory and the name of the final entry
+fn split_path(path: &str) -> (&str, &str) {
+  let trimmed = path.trim_end_matches('\\');
+  match trimmed.rfind('\\') {
+    Some(index) => (&trimmed[..index], &trimmed[(index + 1)..]),
+    None => ("", trimmed),
+  }
+}
+
+/// Check whether `path` is `ancestor` or somewhere inside of it
+fn is_within(path: &str, ancestor: &str) -> bool {
+  let path = path.trim_matches('\\');
+  let ancestor = ancestor.trim_matches('\\');
+  if path.len() < ancestor.len() || !path[..ancestor.len()].eq_ignore_ascii_case(ancestor) {
+    return false;
+  }
+  path.len() == ancestor.len() || path.as_bytes()[ancestor.len()] == b'\\'
+}
+
+/// The name of an entry as it would appear in a path, like "NOTE.TXT"
+fn display_name(entry: &DirectoryEntry) -> String {
+  let mut name = String::new();
+  for &byte in entry.get_name().iter().take_while(|&&byte| byte != b' ') {
+    name.push(byte as char);
+  }
+  let ext = entry.get_ext();
+  if ext[0] != b' ' {
+    name.push('.');
+    for &byte in ext.iter().take_while(|&&byte| byte != b' ') {
+      name.push(byte as char);
+    }
+  }
+  name
+}
+
+fn is_dot_entry(entry: &DirectoryEntry) -> bool {
+  entry.get_name() == b".       " || entry.get_name() == b"..      "
+}
+
+fn is_same_directory(a: &ClusterChain, b: &ClusterChain) -> bool {
+  a.clusters.first() == b.clusters.first()
+}
+
+/// Read from an absolute position on a device, using a handle that only lives
+/// for this read. The device cursor is never shared between callers, so
+/// concurrent reads cannot move each other's position.
+fn read_from_device(driver: &Arc<Box<DeviceDriverType>>, position: usize, buffer: &mut [u8]) -> Result<(), ()> {
+  let handle = driver.open()?;
+  let result = driver.seek(handle, SeekMethod::Absolute(position))
+    .and_then(|_| driver.read(handle, buffer));
+  let _ = driver.close(handle);
+  match result {
+    Ok(read) if read == buffer.len() => Ok(()),
+    _ => Err(()),
+  }
+}
+
+/// Write to an absolute position on a device, using a handle that only lives
+/// for this write
+fn write_to_device(driver: &Arc<Box<DeviceDriverType>>, position: usize, buffer: &[u8]) -> Result<(), ()> {
+  let handle = driver.open()?;
+  let result = driver.seek(handle, SeekMethod::Absolute(position))
+    .and_then(|_| driver.write(handle, buffer));
+  let _ = driver.close(handle);
+  match result {
+    Ok(written) if written == buffer.len() => Ok(()),
+    _ => Err(()),
+  }
+}
+
+impl KernelFileSystem for Fat12FileSystem {
+  fn open(&self, path: &str) -> Result<LocalHandle, ()> {
+    let EntryLocation { directory, index, entry } = self.find_path_location(path)?.ok_or(())?;
+    if !entry.get_file_type().is_file() {
+      return Err(());
+    }
+    let byte_size = entry.get_byte_size();
+    let clusters = if byte_size == 0 {
+      ClusterChain::from_vec(Vec::new())
+    } else {
+      self.get_cluster_chain(entry.get_first_cluster())?
+    };
+    let open_file = OpenFile {
+      cursor: SharedCursor::new(0),
+      byte_size,
+      clusters,
+      directory,
+      entry_index: index,
+    };
+    Ok(self.insert_handle(OpenHandle::File(open_file)))
+  }
+
+  /// Files are created empty, since this driver can't write file contents
+  /// yet. If the file already exists, it is opened instead.
+  /// New files start with the archive bit set. Once contents can be written,
+  /// every write needs to set it again, so that backup programs know the
+  /// file changed.
+  fn create(&self, path: &str) -> Result<LocalHandle, ()> {
+    match self.create_new(path)? {
+      Some(handle) => Ok(handle),
+      None => self.open(path),
+    }
+  }
+
+  /// The new entry has no clusters until data is written to it. Holding the
+  /// modification lock from the lookup until the entry is written keeps two
+  /// creates of the same name from both finding it missing.
+  fn create_new(&self, path: &str) -> Result<Option<LocalHandle>, ()> {
+    let _modifying = self.modification.lock();
+    let (parent_path, new_name) = split_path(path);
+    let (name, ext) = short_name_from_string(new_name).ok_or(())?;
+    let parent = self.find_directory(parent_path)?;
+    if self.find_entry_in_directory(&name, &ext, &parent)?.is_some() {
+      return Ok(None);
+    }
+    let slot = self.find_free_slot(&parent)?;
+    self.write_directory_slot(&parent, slot, &DirectoryEntry::new(&name, &ext, ATTRIBUTE_ARCHIVE, Cluster::new(0)))?;
+    let open_file = OpenFile {
+      cursor: SharedCursor::new(0),
+      byte_size: 0,
+      clusters: ClusterChain::from_vec(Vec::new()),
+      directory: parent,
+      entry_index: slot,
+    };
+    Ok(Some(self.insert_handle(OpenHandle::File(open_file))))
+  }
+
+  fn read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()> {
+    let open_file = match self.open_handles.read().get(handle.as_usize()) {
+      Some(OpenHandle::File(open_file)) => open_file.clone(),
+      _ => return Err(()),
+    };
+    let start = open_file.cursor.get();
+    let to_read = buffer.len().min(open_file.byte_size.saturating_sub(start));
+    let cluster_size = self.config.get_bytes_per_cluster();
+    let mut copied = 0;
+    while copied < to_read {
+      let offset = start + copied;
+      let cluster = open_file.clusters.clusters.get(offset / cluster_size).ok_or(())?;
+      let cluster_offset = offset % cluster_size;
+      let length = (cluster_size - cluster_offset).min(to_read - copied);
+      let first_sector = self.config.get_sectors_for_cluster(*cluster).map_err(|_| ())?.get_first_sector();
+      let position = first_sector * self.config.get_bytes_per_sector() + cluster_offset;
+      self.read_bytes(position, &mut buffer[copied..(copied + length)])?;
+      copied += length;
+    }
+    open_file.cursor.set(start + copied);
+    Ok(copied)
+  }
+
+  fn write(&self, _handle: LocalHandle, _buffer: &[u8]) -> Result<usize, ()> {
+    Err(())
+  }
+
+  fn close(&self, handle: LocalHandle) -> Result<(), ()> {
+    let closed = self.open_handles
+      .write()
+      .remove(handle.as_usize())
+      .ok_or(())?;
+    let first_cluster = match closed {
+      OpenHandle::File(open_file) => match open_file.clusters.clusters.first() {
+        Some(cluster) => *cluster,
+        None => return Ok(()),
+      },
+      OpenHandle::Directory(_) => return Ok(()),
+    };
+    // If this was the last handle to a deleted file, its clusters can
+    // finally be released
+    let _modifying = self.modification.lock();
+    if self.is_chain_open(first_cluster) {
+      return Ok(());
+    }
+    let was_unlinked = {
+      let mut unlinked = self.unlinked.write();
+      let position = unlinked.iter().position(|cluster| *cluster == first_cluster);
+      position.map(|index| unlinked.remove(index)).is_some()
+    };
+    if was_unlinked {
+      self.free_cluster_chain(first_cluster)?;
+    }
+    Ok(())
+  }
+
+  fn reopen(&self, handle: LocalHandle, _id: ProcessID) -> Result<LocalHandle, ()> {
+    let mut reopened_file = match self.open_handles.read().get(handle.as_usize()) {
+      Some(OpenHandle::File(open_file)) => open_file.clone(),
+      _ => return Err(()),
+    };
+    reopened_file.cursor = reopened_file.cursor.detach();
+    Ok(self.insert_handle(OpenHandle::File(reopened_file)))
+  }
+
+  fn reopen_shared(&self, handle: LocalHandle, _id: ProcessID) -> Result<LocalHandle, ()> {
+    let reopened_file = match self.open_handles.read().get(handle.as_usize()) {
+      Some(OpenHandle::File(open_file)) => open_file.clone(),
+      _ => return Err(()),
+    };
+    Ok(self.insert_handle(OpenHandle::File(reopened_file)))
+  }
+
+  fn seek(&self, handle: LocalHandle, offset: SeekMethod) -> Result<usize, ()> {
+    match self.open_handles.read().get(handle.as_usize()) {
+      Some(OpenHandle::File(open_file)) => Ok(open_file.cursor.seek(offset)),
+      _ => Err(()),
+    }
+  }
+
+  fn open_dir(&self, path: &str) -> Result<LocalHandle, ()> {
+    let clusters = match self.find_path(path)? {
+      None => ClusterChain::empty(),
+      Some(entry) => {
+        if !entry.get_file_type().is_directory() {
+          return Err(());
+        }
+        self.get_directory_clusters(&entry)?
+      },
+    };
+    let open_dir = OpenDirectory {
+      index: 0,
+      clusters,
+    };
+    Ok(self.insert_handle(OpenHandle::Directory(open_dir)))
+  }
+
+  fn directory_path(&self, handle: LocalHandle) -> Result<Option<String>, ()> {
+    let clusters = match self.open_handles.read().get(handle.as_usize()) {
+      Some(OpenHandle::Directory(open_dir)) => open_dir.clusters.clone(),
+      _ => return Err(()),
+    };
+    self.find_directory_path(&clusters)
+  }
+
+  fn read_dir(&self, handle: LocalHandle, info: &mut DirEntryInfo) -> Result<bool, ()> {
+    let (index, clusters) = match self.open_handles.read().get(handle.as_usize()) {
+      Some(OpenHandle::Directory(open_dir)) => (open_dir.index, open_dir.clusters.clone()),
+      _ => return Err(()),
+    };
+    let (found_index, entry) = self.find_listed_entry(&clusters, index)?.ok_or(())?;
+    entry.copy_name(&mut info.file_name);
+    entry.copy_ext(&mut info.file_ext);
+    info.entry_type = if entry.get_file_type().is_directory() {
+      DirEntryType::Directory
+    } else {
+      DirEntryType::File
+    };
+    info.byte_size = entry.get_byte_size();
+    match self.open_handles.write().get_mut(handle.as_usize()) {
+      Some(OpenHandle::Directory(open_dir)) => open_dir.index = found_index + 1,
+      _ => return Err(()),
+    }
+    let has_more = self.find_listed_entry(&clusters, found_index + 1)?.is_some();
+    Ok(has_more)
+  }
+
+  /// FAT_IOCTL_GET_ATTRIBUTES returns a file's attribute bits, and
+  /// FAT_IOCTL_SET_ATTRIBUTES replaces them and returns the previous bits
+  fn ioctl(&self, handle: LocalHandle, command: u32, arg: u32) -> Result<u32, ()> {
+    match command {
+      FAT_IOCTL_GET_ATTRIBUTES => self.get_attributes(handle).map(|attributes| attributes as u32),
+      FAT_IOCTL_SET_ATTRIBUTES => {
+        if arg > 0xff {
+          return Err(());
+        }
+        self.set_attributes(handle, arg as u8).map(|previous| previous as u32)
+      },
+      _ => Err(()),
+    }
+  }
+
+  fn access(&self, path: &str) -> Result<FileAccess, ()> {
+    let access = match self.find_path(path)? {
+      Some(entry) => entry.get_access(),
+      // The root directory has no entry, and no attributes
+      None => FileAccess::read_write(),
+    };
+    // This driver can't modify file contents yet, so nothing is writable
+    // regardless of its attributes
+    Ok(FileAccess {
+      writable: false,
+      ..access
+    })
+  }
+
+  /// A new directory takes a single cluster, which starts out with its "."
+  /// and ".." entries
+  fn mkdir(&self, path: &str) -> Result<(), ()> {
+    let _modifying = self.modification.lock();
+    let (parent_path, new_name) = split_path(path);
+    let (name, ext) = short_name_from_string(new_name).ok_or(())?;
+    let parent = self.find_directory(parent_path)?;
+    if self.find_entry_in_directory(&name, &ext, &parent)?.is_some() {
+      return Err(());
+    }
+    let slot = self.find_free_slot(&parent)?;
+    let cluster = self.allocate_cluster()?.ok_or(())?;
+
+    let first_sector = self.config.get_sectors_for_cluster(cluster).map_err(|_| ())?.get_first_sector();
+    let mut empty = Vec::new();
+    empty.resize(self.config.get_bytes_per_cluster(), 0);
+    self.write_bytes(first_sector * self.config.get_bytes_per_sector(), &empty)?;
+    let contents = ClusterChain::from_vec(alloc::vec![cluster]);
+    let parent_cluster = parent.clusters.first().copied().unwrap_or(Cluster::new(0));
+    self.write_directory_slot(&contents, 0, &DirectoryEntry::new(b".       ", b"   ", ATTRIBUTE_DIRECTORY, cluster))?;
+    self.write_directory_slot(&contents, 1, &DirectoryEntry::new(b"..      ", b"   ", ATTRIBUTE_DIRECTORY, parent_cluster))?;
+
+    self.write_directory_slot(&parent, slot, &DirectoryEntry::new(&name, &ext, ATTRIBUTE_DIRECTORY, cluster))
+  }
+
+  fn rmdir(&self, path: &str) -> Result<(), ()> {
+    let _modifying = self.modification.lock();
+    let location = self.find_path_location(path)?.ok_or(())?;
+    if !location.entry.get_file_type().is_directory() || location.entry.is_read_only() {
+      return Err(());
+    }
+    let contents = self.get_directory_clusters(&location.entry)?;
+    if self.has_contents(&contents)? {
+      return Err(());
+    }
+    let first_cluster = location.entry.get_first_cluster();
+    let is_open = self.open_handles.read().iter().any(|handle| match handle {
+      OpenHandle::Directory(open_dir) => open_dir.clusters.clusters.first() == Some(&first_cluster),
+      _ => false,
+    });
+    if is_open {
+      return Err(());
+    }
+    let mut removed = location.entry;
+    removed.mark_deleted();
+    self.write_directory_slot(&location.directory, location.index, &removed)?;
+    self.free_cluster_chain(first_cluster)
+  }
+
+  /// Entries are renamed in place, or moved to a free slot in their new
+  /// directory. File data is never copied; only directory entries change.
+  fn rename(&self, old_path: &str, new_path: &str, replace: bool) -> Result<(), ()> {
+    let _modifying = self.modification.lock();
+    let source = self.find_path_location(old_path)?.ok_or(())?;
+    let is_directory = source.entry.get_file_type().is_directory();
+    if is_directory && is_within(new_path, old_path) {
+      // A directory can't be moved inside of itself
+      return Err(());
+    }
+    let (new_parent, new_name) = split_path(new_path);
+    let (name, ext) = short_name_from_string(new_name).ok_or(())?;
+    let destination = self.find_directory(new_parent)?;
+
+    if let Some((index, entry)) = self.find_entry_in_directory(&name, &ext, &destination)? {
+      let same_entry = is_same_directory(&destination, &source.directory) && index == source.index;
+      if !same_entry {
+        // Only files can replace other files
+        if !replace || is_directory {
+          return Err(());
+        }
+        self.remove_replaced_file(&EntryLocation { directory: destination.clone(), index, entry })?;
+      }
+    }
+
+    let mut renamed = source.entry;
+    renamed.set_name(&name, &ext);
+    if is_same_directory(&destination, &source.directory) {
+      return self.write_directory_slot(&source.directory, source.index, &renamed);
+    }
+    // Write the new entry before removing the old one, so that an
+    // interrupted move leaves two links rather than none
+    let slot = self.find_free_slot(&destination)?;
+    self.write_directory_slot(&destination, slot, &renamed)?;
+    let mut removed = source.entry;
+    removed.mark_deleted();
+    self.write_directory_slot(&source.directory, source.index, &removed)?;
+
+    if is_directory {
+      // The ".." entry needs to point at the new parent
+      let contents = self.get_directory_clusters(&renamed)?;
+      if let Some(mut parent_link) = self.read_directory_slot(&contents, 1)? {
+        if parent_link.get_name() == b"..      " {
+          let parent_cluster = destination.clusters.first().copied().unwrap_or(Cluster::new(0));
+          parent_link.set_first_cluster(parent_cluster);
+          self.write_directory_slot(&contents, 1, &parent_link)?;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// The entry is marked as deleted and its clusters are freed in every copy
+  /// of the FAT. Directories and read-only files can't be removed.
+  fn unlink(&self, path: &str) -> Result<(), ()> {
+    let _modifying = self.modification.lock();
+    let location = self.find_path_location(path)?.ok_or(())?;
+    if !location.entry.get_file_type().is_file() || location.entry.is_read_only() {
+      return Err(());
+    }
+    let mut removed = location.entry;
+    removed.mark_deleted();
+    self.write_directory_slot(&location.directory, location.index, &removed)?;
+    let first_cluster = location.entry.get_first_cluster();
+    if first_cluster.as_usize() != 0 && self.is_chain_open(first_cluster) {
+      self.unlinked.write().push(first_cluster);
+      return Ok(());
+    }
+    self.free_cluster_chain(first_cluster)
+  }
+
+  fn stat(&self, handle: LocalHandle, status: &mut FileStatus) -> Result<(), ()> {
+    match self.open_handles.read().get(handle.as_usize()) {
+      Some(OpenHandle::File(open_file)) => {
+        status.byte_size = open_file.byte_size;
+        Ok(())
+      },
+      Some(OpenHandle::Directory(_)) => Ok(()),
+      None => Err(()),
+    }
+  }
+
+  /// Every handle to a file was opened through its directory entry, so the
+  /// entry's position on disk identifies the file. Unlike the first cluster,
+  /// it exists for empty files, and survives truncating and rewriting them.
+  fn file_identity(&self, handle: LocalHandle) -> Result<usize, ()> {
+    match self.open_handles.read().get(handle.as_usize()) {
+      Some(OpenHandle::File(open_file)) => {
+        self.get_directory_entry_position(&open_file.directory, open_file.entry_index).ok_or(())
+      },
+      _ => Err(()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::boxed::Box;
+  use alloc::string::String;
+  use alloc::sync::Arc;
+  use alloc::vec::Vec;
+  use core::sync::atomic::{AtomicUsize, Ordering};
+  use crate::collections::SlotList;
+  use crate::devices::block::IOCTL_SET_GEOMETRY;
+  use crate::devices::driver::{DeviceDriver, IOHandle};
+  use crate::files::cursor::SeekMethod;
+  use crate::fs::KernelFileSystem;
+  use crate::locks::RwLock;
+  use syscall::flags::{
+    FAT_ATTR_ARCHIVE, FAT_ATTR_DIRECTORY, FAT_ATTR_READ_ONLY, FAT_IOCTL_GET_ATTRIBUTES,
+    FAT_IOCTL_SET_ATTRIBUTES,
+  };
+  use super::super::fat::{entry_from_bytes, entry_to_bytes, get_entry_offset, Cluster, FatEntry};
+  use super::Fat12FileSystem;
+
+  const SECTOR_SIZE: usize = 512;
+  /// Boot sector, two one-sector FATs, and a one-sector root directory
+  const DATA_START: usize = 4;
+
+  /// A disk image held in memory, standing in for a floppy drive
+  struct MemoryDisk {
+    data: Arc<RwLock<Vec<u8>>>,
+    cursors: RwLock<SlotList<usize>>,
+    /// Number of reads the filesystem has made
+    reads: Arc<AtomicUsize>,
+  }
+
+  impl DeviceDriver for MemoryDisk {
+    fn open(&self) -> Result<IOHandle, ()> {
+      Ok(IOHandle::new(self.cursors.write().insert(0)))
+    }
+
+    fn read(&self, index: IOHandle, buffer: &mut [u8]) -> Result<usize, ()> {
+      let mut cursors = self.cursors.write();
+      let cursor = cursors.get_mut(index.as_usize()).ok_or(())?;
+      self.reads.fetch_add(1, Ordering::SeqCst);
+      let data = self.data.read();
+      let length = buffer.len().min(data.len().saturating_sub(*cursor));
+      buffer[..length].copy_from_slice(&data[*cursor..(*cursor + length)]);
+      *cursor += length;
+      Ok(length)
+    }
+
+    fn write(&self, index: IOHandle, buffer: &[u8]) -> Result<usize, ()> {
+      let mut cursors = self.cursors.write();
+      let cursor = cursors.get_mut(index.as_usize()).ok_or(())?;
+      let mut data = self.data.write();
+      let length = buffer.len().min(data.len().saturating_sub(*cursor));
+      data[*cursor..(*cursor + length)].copy_from_slice(&buffer[..length]);
+      *cursor += length;
+      Ok(length)
+    }
+
+    fn close(&self, index: IOHandle) -> Result<(), ()> {
+      self.cursors.write().remove(index.as_usize()).map(|_| ()).ok_or(())
+    }
+
+    fn seek(&self, index: IOHandle, offset: SeekMethod) -> Result<usize, ()> {
+      let mut cursors = self.cursors.write();
+      let cursor = cursors.get_mut(index.as_usize()).ok_or(())?;
+      *cursor = offset.from_current_position(*cursor);
+      Ok(*cursor)
+    }
+
+    fn ioctl(&self, _index: IOHandle, command: u32, _arg: u32) -> Result<u32, ()> {
+      match command {
+        IOCTL_SET_GEOMETRY => Ok(0),
+        _ => Err(()),
+      }
+    }
+  }
+
+  fn set_fat_entry(image: &mut [u8], cluster: usize, entry: FatEntry) {
+    let cluster = Cluster::new(cluster);
+    for table in 1..=2 {
+      let offset = table * SECTOR_SIZE + get_entry_offset(cluster);
+      let bytes = entry_to_bytes(cluster, [image[offset], image[offset + 1]], entry);
+      image[offset..(offset + 2)].copy_from_slice(&bytes);
+    }
+  }
+
+  fn add_entry(image: &mut [u8], sector: usize, index: usize, name: &[u8; 11], attributes: u8, cluster: u16, size: u32) {
+    let offset = sector * SECTOR_SIZE + index * 32;
+    image[offset..(offset + 11)].copy_from_slice(name);
+    image[offset + 11] = attributes;
+    image[(offset + 26)..(offset + 28)].copy_from_slice(&cluster.to_le_bytes());
+    image[(offset + 28)..(offset + 32)].copy_from_slice(&size.to_le_bytes());
+  }
+
+  fn cluster_sector(cluster: usize) -> usize {
+    DATA_START + cluster - 2
+  }
+
+  /// Build a 40-sector volume containing:
+  ///   HELLO.TXT, one cluster
+  ///   DATA.BIN, two clusters
+  ///   DOCS\NOTE.TXT
+  ///   OLD\
+  fn build_image() -> Vec<u8> {
+    let mut image = Vec::new();
+    image.resize(40 * SECTOR_SIZE, 0);
+    image[0x0b..0x0d].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+    image[0x0d] = 1;
+    image[0x0e] = 1;
+    image[0x10] = 2;
+    image[0x11] = 16;
+    image[0x13] = 40;
+    image[0x15] = 0xf8;
+    image[0x16] = 1;
+    image[0x18] = 8;
+    image[0x1a] = 2;
+    image[510] = 0x55;
+    image[511] = 0xaa;
+    for table in 1..=2 {
+      image[(table * SECTOR_SIZE)..(table * SECTOR_SIZE + 3)].copy_from_slice(&[0xf8, 0xff, 0xff]);
+    }
+
+    add_entry(&mut image, 3, 0, b"HELLO   TXT", 0x20, 2, 5);
+    set_fat_entry(&mut image, 2, FatEntry::EndOfChain);
+    let hello = cluster_sector(2) * SECTOR_SIZE;
+    image[hello..(hello + 5)].copy_from_slice(b"hello");
+
+    add_entry(&mut image, 3, 1, b"DATA    BIN", 0x20, 3, 600);
+    set_fat_entry(&mut image, 3, FatEntry::NextCluster(Cluster::new(4)));
+    set_fat_entry(&mut image, 4, FatEntry::EndOfChain);
+
+    add_entry(&mut image, 3, 2, b"DOCS       ", 0x10, 5, 0);
+    set_fat_entry(&mut image, 5, FatEntry::EndOfChain);
+    add_entry(&mut image, cluster_sector(5), 0, b".          ", 0x10, 5, 0);
+    add_entry(&mut image, cluster_sector(5), 1, b"..         ", 0x10, 0, 0);
+    add_entry(&mut image, cluster_sector(5), 2, b"NOTE    TXT", 0x20, 6, 4);
+    set_fat_entry(&mut image, 6, FatEntry::EndOfChain);
+    let note = cluster_sector(6) * SECTOR_SIZE;
+    image[note..(note + 4)].copy_from_slice(b"note");
+
+    add_entry(&mut image, 3, 3, b"OLD        ", 0x10, 7, 0);
+    set_fat_entry(&mut image, 7, FatEntry::EndOfChain);
+    add_entry(&mut image, cluster_sector(7), 0, b".          ", 0x10, 7, 0);
+    add_entry(&mut image, cluster_sector(7), 1, b"..         ", 0x10, 0, 0);
+    image
+  }
+
+  fn mount_image() -> (Fat12FileSystem, Arc<RwLock<Vec<u8>>>) {
+    let (fs, data, _) = mount_counting_reads();
+    (fs, data)
+  }
+
+  fn mount_counting_reads() -> (Fat12FileSystem, Arc<RwLock<Vec<u8>>>, Arc<AtomicUsize>) {
+    let data = Arc::new(RwLock::new(build_image()));
+    let reads = Arc::new(AtomicUsize::new(0));
+    let disk = MemoryDisk {
+      data: data.clone(),
+      cursors: RwLock::new(SlotList::new()),
+      reads: reads.clone(),
+    };
+    let fs = Fat12FileSystem::mount(Arc::new(Box::new(disk))).ok().unwrap();
+    (fs, data, reads)
+  }
+
+  fn read_file(fs: &Fat12FileSystem, path: &str) -> Option<Vec<u8>> {
+    let handle = fs.open(path).ok()?;
+    let mut buffer = [0u8; 16];
+    let length = fs.read(handle, &mut buffer).ok()?;
+    fs.close(handle).ok()?;
+    Some(buffer[..length].to_vec())
+  }
+
+  fn fat_entry(image: &[u8], table: usize, cluster: usize) -> FatEntry {
+    let cluster = Cluster::new(cluster);
+    let offset = (table + 1) * SECTOR_SIZE + get_entry_offset(cluster);
+    entry_from_bytes(cluster, [image[offset], image[offset + 1]])
+  }
+
+  #[test]
+  fn rename_in_place() {
+    let (fs, _) = mount_image();
+    assert!(fs.rename("HELLO.TXT", "greet.txt", false).is_ok());
+    assert_eq!(read_file(&fs, "GREET.TXT").unwrap(), b"hello");
+    assert!(read_file(&fs, "HELLO.TXT").is_none());
+    // Names that don't fit in a directory entry are rejected
+    assert!(fs.rename("GREET.TXT", "GREETINGS.TEXT", false).is_err());
+    assert!(fs.rename("MISSING.TXT", "OTHER.TXT", false).is_err());
+  }
+
+  #[test]
+  fn move_between_directories() {
+    let (fs, image) = mount_image();
+    assert!(fs.rename("HELLO.TXT", "DOCS\\HELLO.TXT", false).is_ok());
+    assert_eq!(read_file(&fs, "DOCS\\HELLO.TXT").unwrap(), b"hello");
+    assert!(read_file(&fs, "HELLO.TXT").is_none());
+    assert!(fs.rename("DOCS\\NOTE.TXT", "NOTE.TXT", false).is_ok());
+    assert_eq!(read_file(&fs, "NOTE.TXT").unwrap(), b"note");
+    // The freed slot in the root is reused, and no data was copied
+    let root = 3 * SECTOR_SIZE;
+    assert_eq!(&image.read()[root..(root + 11)], b"NOTE    TXT");
+    assert_eq!(fat_entry(&image.read(), 0, 6), FatEntry::EndOfChain);
+  }
+
+  #[test]
+  fn move_directory() {
+    let (fs, image) = mount_image();
+    assert!(fs.rename("DOCS", "OLD\\DOCS", false).is_ok());
+    assert_eq!(read_file(&fs, "OLD\\DOCS\\NOTE.TXT").unwrap(), b"note");
+    // The moved directory's parent link points at its new parent
+    let parent_link = cluster_sector(5) * SECTOR_SIZE + 32;
+    assert_eq!(image.read()[parent_link + 26], 7);
+    assert!(fs.rename("OLD\\DOCS", "", false).is_err());
+    assert!(fs.rename("OLD", "OLD\\DOCS\\OLD", false).is_err());
+    assert!(fs.rename("OLD\\DOCS", "DOCS", false).is_ok());
+    assert_eq!(image.read()[parent_link + 26], 0);
+  }
+
+  #[test]
+  fn existing_destination() {
+    let (fs, image) = mount_image();
+    assert!(fs.rename("HELLO.TXT", "DATA.BIN", false).is_err());
+    assert_eq!(read_file(&fs, "HELLO.TXT").unwrap(), b"hello");
+    // Directories are never replaced, and never replace files
+    assert!(fs.rename("HELLO.TXT", "OLD", true).is_err());
+    assert!(fs.rename("OLD", "HELLO.TXT", true).is_err());
+    // An open file can't be replaced out from under its reader
+    let handle = fs.open("DATA.BIN").unwrap();
+    assert!(fs.rename("HELLO.TXT", "DATA.BIN", true).is_err());
+    fs.close(handle).unwrap();
+
+    assert!(fs.rename("HELLO.TXT", "DATA.BIN", true).is_ok());
+    assert_eq!(read_file(&fs, "DATA.BIN").unwrap(), b"hello");
+    assert!(read_file(&fs, "HELLO.TXT").is_none());
+    // The replaced file's clusters are released in both FATs
+    for table in 0..2 {
+      assert_eq!(fat_entry(&image.read(), table, 3), FatEntry::Free);
+      assert_eq!(fat_entry(&image.read(), table, 4), FatEntry::Free);
+      assert_eq!(fat_entry(&image.read(), table, 2), FatEntry::EndOfChain);
+    }
+  }
+
+  #[test]
+  fn unlinked_clusters_are_reusable() {
+    let (fs, image) = mount_image();
+    assert_eq!(fs.first_free_cluster(), Ok(Some(Cluster::new(8))));
+    assert!(fs.unlink("DATA.BIN").is_ok());
+    assert!(read_file(&fs, "DATA.BIN").is_none());
+    assert_eq!(image.read()[3 * SECTOR_SIZE + 32], 0xe5);
+    for table in 0..2 {
+      assert_eq!(fat_entry(&image.read(), table, 3), FatEntry::Free);
+      assert_eq!(fat_entry(&image.read(), table, 4), FatEntry::Free);
+    }
+    assert_eq!(fs.first_free_cluster(), Ok(Some(Cluster::new(3))));
+
+    assert!(fs.unlink("DATA.BIN").is_err());
+    assert!(fs.unlink("DOCS").is_err());
+    assert!(fs.unlink("DOCS\\NOTE.TXT").is_ok());
+    assert_eq!(fat_entry(&image.read(), 1, 6), FatEntry::Free);
+
+    // Read-only files are protected
+    image.write()[3 * SECTOR_SIZE + 11] = 0x21;
+    assert!(fs.unlink("HELLO.TXT").is_err());
+    assert_eq!(read_file(&fs, "HELLO.TXT").unwrap(), b"hello");
+  }
+
+  #[test]
+  fn sequential_allocation_skips_rescans() {
+    // Grow a file by eight clusters, first by scanning the FAT from the start
+    // for each one
+    let (fs, _, reads) = mount_counting_reads();
+    let before = reads.load(Ordering::SeqCst);
+    for _ in 0..8 {
+      let cluster = fs.first_free_cluster().unwrap().unwrap();
+      fs.set_fat_entry(cluster, FatEntry::EndOfChain).unwrap();
+    }
+    let scanning = reads.load(Ordering::SeqCst) - before;
+
+    // ...and then through the cache
+    let (fs, image, reads) = mount_counting_reads();
+    let before = reads.load(Ordering::SeqCst);
+    let mut clusters = Vec::new();
+    for _ in 0..8 {
+      clusters.push(fs.allocate_cluster().unwrap().unwrap().as_usize());
+    }
+    let cached = reads.load(Ordering::SeqCst) - before;
+    assert_eq!(clusters, [8, 9, 10, 11, 12, 13, 14, 15]);
+    assert_eq!(fat_entry(&image.read(), 1, 15), FatEntry::EndOfChain);
+    assert_eq!(scanning, 100);
+    assert_eq!(cached, 24);
+  }
+
+  #[test]
+  fn free_cluster_cache_follows_changes() {
+    let (fs, image) = mount_image();
+    // A cluster taken without going through the cache is skipped
+    set_fat_entry(&mut image.write(), 8, FatEntry::EndOfChain);
+    assert_eq!(fs.allocate_cluster(), Ok(Some(Cluster::new(9))));
+    // Deleted files give their clusters back
+    assert!(fs.unlink("DATA.BIN").is_ok());
+    assert_eq!(fs.allocate_cluster(), Ok(Some(Cluster::new(3))));
+    assert_eq!(fs.allocate_cluster(), Ok(Some(Cluster::new(4))));
+    assert_eq!(fs.allocate_cluster(), Ok(Some(Cluster::new(10))));
+
+    // Use up the rest of the disk
+    let mut remaining = 0;
+    while let Ok(Some(_)) = fs.allocate_cluster() {
+      remaining += 1;
+    }
+    assert_eq!(remaining, 27);
+    assert_eq!(fs.allocate_cluster(), Ok(None));
+    assert!(fs.mkdir("NEW").is_err());
+
+    assert!(fs.unlink("HELLO.TXT").is_ok());
+    assert_eq!(fs.allocate_cluster(), Ok(Some(Cluster::new(2))));
+  }
+
+  #[test]
+  fn cyclic_chain_is_rejected() {
+    let (fs, image) = mount_image();
+    // DATA.BIN's second cluster links back to its first
+    set_fat_entry(&mut image.write(), 4, FatEntry::NextCluster(Cluster::new(3)));
+    assert!(fs.open("DATA.BIN").is_err());
+    // A directory that contains itself can't be listed forever either
+    set_fat_entry(&mut image.write(), 5, FatEntry::NextCluster(Cluster::new(5)));
+    assert!(fs.open_dir("DOCS").is_err());
+    assert_eq!(read_file(&fs, "HELLO.TXT").unwrap(), b"hello");
+  }
+
+  #[test]
+  fn change_attributes() {
+    let (fs, image) = mount_image();
+    let entry = 3 * SECTOR_SIZE + 11;
+    let handle = fs.open("HELLO.TXT").unwrap();
+    assert_eq!(fs.ioctl(handle, FAT_IOCTL_GET_ATTRIBUTES, 0), Ok(0x20));
+    let read_only = (FAT_ATTR_READ_ONLY | FAT_ATTR_ARCHIVE) as u32;
+    assert_eq!(fs.ioctl(handle, FAT_IOCTL_SET_ATTRIBUTES, read_only), Ok(0x20));
+    assert_eq!(image.read()[entry], 0x21);
+    // Changes to the read-only file are now refused
+    assert!(fs.write(handle, b"bye").is_err());
+    assert!(fs.unlink("HELLO.TXT").is_err());
+
+    // A file can't become a directory
+    let directory = (FAT_ATTR_DIRECTORY | FAT_ATTR_ARCHIVE) as u32;
+    assert!(fs.ioctl(handle, FAT_IOCTL_SET_ATTRIBUTES, directory).is_err());
+    assert_eq!(image.read()[entry], 0x21);
+
+    // Backup programs clear the archive bit once the file is copied
+    assert_eq!(fs.ioctl(handle, FAT_IOCTL_SET_ATTRIBUTES, 0), Ok(0x21));
+    assert!(fs.unlink("HELLO.TXT").is_ok());
+    // The handle's entry is gone
+    assert!(fs.ioctl(handle, FAT_IOCTL_GET_ATTRIBUTES, 0).is_err());
+    fs.close(handle).unwrap();
+
+    let dir = fs.open_dir("DOCS").unwrap();
+    assert!(fs.ioctl(dir, FAT_IOCTL_GET_ATTRIBUTES, 0).is_err());
+  }
+
+  #[test]
+  fn unlink_open_file() {
+    let (fs, image) = mount_image();
+    let handle = fs.open("HELLO.TXT").unwrap();
+    let copy = fs.reopen(handle, crate::task::id::ProcessID::new(1)).unwrap();
+    assert!(fs.unlink("HELLO.TXT").is_ok());
+    assert!(fs.open("HELLO.TXT").is_err());
+
+    // Existing handles keep working, and the data isn't released yet
+    let mut buffer = [0u8; 8];
+    assert_eq!(fs.read(handle, &mut buffer), Ok(5));
+    assert_eq!(&buffer[..5], b"hello");
+    assert_eq!(fat_entry(&image.read(), 0, 2), FatEntry::EndOfChain);
+    fs.close(handle).unwrap();
+    assert_eq!(fat_entry(&image.read(), 0, 2), FatEntry::EndOfChain);
+    assert_eq!(fs.read(copy, &mut buffer), Ok(5));
+
+    fs.close(copy).unwrap();
+    for table in 0..2 {
+      assert_eq!(fat_entry(&image.read(), table, 2), FatEntry::Free);
+    }
+    assert_eq!(fs.first_free_cluster(), Ok(Some(Cluster::new(2))));
+  }
+
+  #[test]
+  fn empty_files_have_an_identity() {
+    let (fs, _image) = mount_image();
+    let created = fs.create_new("LOCK.TMP").unwrap().unwrap();
+    let opened = fs.open("LOCK.TMP").unwrap();
+    let identity = fs.file_identity(created).unwrap();
+    assert_eq!(fs.file_identity(opened), Ok(identity));
+    let other = fs.open("HELLO.TXT").unwrap();
+    assert_ne!(fs.file_identity(other), Ok(identity));
+    let dir = fs.open_dir("DOCS").unwrap();
+    assert!(fs.file_identity(dir).is_err());
+  }
+
+  #[test]
+  fn forked_handles_share_cursor() {
+    let (fs, _image) = mount_image();
+    let parent = fs.open("HELLO.TXT").unwrap();
+    let child = fs.reopen_shared(parent, crate::task::id::ProcessID::new(2)).unwrap();
+
+    // Reading alternately through either handle advances a single position
+    let mut buffer = [0u8; 1];
+    let mut contents = Vec::new();
+    for turn in 0..5 {
+      let handle = if turn % 2 == 0 { parent } else { child };
+      assert_eq!(fs.read(handle, &mut buffer), Ok(1));
+      contents.push(buffer[0]);
+    }
+    assert_eq!(contents, b"hello");
+    assert_eq!(fs.read(parent, &mut buffer), Ok(0));
+    assert_eq!(fs.read(child, &mut buffer), Ok(0));
+    assert_eq!(fs.seek(child, SeekMethod::Absolute(1)), Ok(1));
+    assert_eq!(fs.seek(parent, SeekMethod::Relative(1)), Ok(2));
+
+    // Opening the file again, or a plain reopen, gets an independent cursor
+    let fresh = fs.open("HELLO.TXT").unwrap();
+    let copy = fs.reopen(parent, crate::task::id::ProcessID::new(2)).unwrap();
+    assert_eq!(fs.read(fresh, &mut buffer), Ok(1));
+    assert_eq!(&buffer, b"h");
+    assert_eq!(fs.read(copy, &mut buffer), Ok(1));
+    assert_eq!(&buffer, b"l");
+    assert_eq!(fs.read(parent, &mut buffer), Ok(1));
+    assert_eq!(&buffer, b"l");
+    assert_eq!(fs.read(child, &mut buffer), Ok(1));
+    assert_eq!(&buffer, b"l");
+
+    // Closing the parent's handle leaves the child's cursor in place
+    fs.close(parent).unwrap();
+    assert_eq!(fs.read(child, &mut buffer), Ok(1));
+    assert_eq!(&buffer, b"o");
+    for handle in [child, fresh, copy].iter() {
+      fs.close(*handle).unwrap();
+    }
+  }
+
+  #[test]
+  fn exclusive_create() {
+    let (fs, image) = mount_image();
+    assert!(fs.create_new("HELLO.TXT").unwrap().is_none());
+    assert!(fs.create_new("hello.txt").unwrap().is_none());
+    assert!(fs.create_new("DOCS").unwrap().is_none());
+    assert!(fs.create_new("MISSING\\NEW.TXT").is_err());
+
+    let handle = fs.create_new("DOCS\\NEW.TXT").unwrap().unwrap();
+    let mut buffer = [0u8; 4];
+    assert_eq!(fs.read(handle, &mut buffer), Ok(0));
+    fs.close(handle).unwrap();
+    // The entry takes the next slot, and has no clusters
+    let entry = cluster_sector(5) * SECTOR_SIZE + 3 * 32;
+    assert_eq!(&image.read()[entry..(entry + 12)], b"NEW     TXT\x20");
+    assert_eq!(&image.read()[(entry + 26)..(entry + 32)], &[0; 6]);
+    assert!(fs.create_new("docs\\new.txt").unwrap().is_none());
+    assert_eq!(read_file(&fs, "DOCS\\NEW.TXT").unwrap(), b"");
+
+    // Without O_EXCL, an existing file is opened as-is
+    let handle = fs.create("HELLO.TXT").unwrap();
+    fs.close(handle).unwrap();
+    assert_eq!(read_file(&fs, "HELLO.TXT").unwrap(), b"hello");
+    assert!(fs.create("DOCS").is_err());
+  }
+
+  #[test]
+  fn exclusive_create_race() {
+    extern crate std;
+    use std::sync::Barrier;
+    use std::thread;
+
+    let (fs, _) = mount_image();
+    let fs = Arc::new(fs);
+    // Each thread stands in for a process trying to take the same lock file
+    for _ in 0..20 {
+      let barrier = Arc::new(Barrier::new(2));
+      let racers: Vec<_> = (0..2).map(|_| {
+        let fs = fs.clone();
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+          barrier.wait();
+          fs.create_new("RACE.LCK").unwrap()
+        })
+      }).collect();
+      let winners: Vec<_> = racers.into_iter().filter_map(|racer| racer.join().unwrap()).collect();
+      assert_eq!(winners.len(), 1);
+      fs.close(winners[0]).unwrap();
+      fs.unlink("RACE.LCK").unwrap();
+    }
+  }
+
+  #[test]
+  fn make_and_remove_directories() {
+    let (fs, image) = mount_image();
+    assert!(fs.mkdir("GAMES").is_ok());
+    assert!(fs.mkdir("games").is_err());
+    assert!(fs.mkdir("MISSING\\SUB").is_err());
+    // The first free cluster holds the new directory's entries
+    assert_eq!(fat_entry(&image.read(), 1, 8), FatEntry::EndOfChain);
+    let contents = cluster_sector(8) * SECTOR_SIZE;
+    assert_eq!(&image.read()[contents..(contents + 11)], b".          ");
+    assert_eq!(&image.read()[(contents + 32)..(contents + 43)], b"..         ");
+    assert_eq!(image.read()[contents + 26], 8);
+    assert_eq!(image.read()[contents + 32 + 26], 0);
+
+    assert!(fs.mkdir("GAMES\\DOOM").is_ok());
+    let nested = cluster_sector(9) * SECTOR_SIZE;
+    assert_eq!(image.read()[nested + 32 + 26], 8);
+    assert!(fs.rename("HELLO.TXT", "GAMES\\DOOM\\README.TXT", false).is_ok());
+    assert_eq!(read_file(&fs, "GAMES\\DOOM\\README.TXT").unwrap(), b"hello");
+
+    // Only empty directories can be removed
+    assert!(fs.rmdir("GAMES").is_err());
+    assert!(fs.rmdir("GAMES\\DOOM").is_err());
+    assert!(fs.unlink("GAMES\\DOOM\\README.TXT").is_ok());
+    let handle = fs.open_dir("GAMES\\DOOM").unwrap();
+    assert!(fs.rmdir("GAMES\\DOOM").is_err());
+    fs.close(handle).unwrap();
+    assert!(fs.rmdir("GAMES\\DOOM").is_ok());
+    assert_eq!(fat_entry(&image.read(), 0, 9), FatEntry::Free);
+    assert!(fs.rmdir("GAMES").is_ok());
+    assert!(fs.open_dir("GAMES").is_err());
+    assert!(fs.rmdir("DATA.BIN").is_err());
+    assert!(fs.rmdir("").is_err());
+  }
+
+  #[test]
+  fn open_directory_paths() {
+    use crate::files::path::Path;
+
+    let (fs, image) = mount_image();
+    let root = fs.open_dir("").unwrap();
+    assert_eq!(fs.directory_path(root), Ok(Some(String::from(""))));
+    let file = fs.open("HELLO.TXT").unwrap();
+    assert!(fs.directory_path(file).is_err());
+
+    // Relative paths resolve from the directory's path
+    let docs = fs.open_dir("docs").unwrap();
+    let path = fs.directory_path(docs).unwrap().unwrap();
+    assert_eq!(path, "DOCS");
+    let note = Path::resolve(&path, "NOTE.TXT");
+    assert_eq!(read_file(&fs, note.as_str()).unwrap(), b"note");
+
+    // The path follows the directory when it moves
+    assert!(fs.rename("DOCS", "OLD\\PAPERS", false).is_ok());
+    let path = fs.directory_path(docs).unwrap().unwrap();
+    assert_eq!(path, "OLD\\PAPERS");
+    let note = Path::resolve(&path, "NOTE.TXT");
+    assert_eq!(read_file(&fs, note.as_str()).unwrap(), b"note");
+
+    // A directory removed behind the handle's back has no path
+    let old = fs.open_dir("OLD").unwrap();
+    assert_eq!(fs.directory_path(old), Ok(Some(String::from("OLD"))));
+    image.write()[3 * SECTOR_SIZE + 3 * 32] = 0xe5;
+    assert_eq!(fs.directory_path(old), Ok(None));
+    assert_eq!(fs.directory_path(docs), Ok(None));
+  }
+}
@@ -0,0 +1,13 @@
+//! FAT12 is the filesystem used on DOS floppy disks. The boot sector of each
+//! disk contains a BIOS Parameter Block describing the layout of the volume,
+//! followed by one or more copies of the File Allocation Table, a fixed-size
+//! root directory, and the data region.
+
+pub mod directory;
+pub mod disk;
+pub mod errors;
+pub mod fat;
+pub mod file;
+pub mod fs;
+
+pub use fs::Fat12FileSystem;
@@ -1,28 +1,54 @@
 //! InitFS is a simple in-memory file archive based on the CPIO format.
-//! Files are read-only and stored linearly. Finding a file within the archive
-//! is an O(n) operation that traverses each item until a matching filename is
-//! found.
-
+//! Files in the archive are read-only and stored linearly. Finding a file
+//! within the archive is an O(n) operation that traverses each item until a
+//! matching filename is found.
+//! To allow boot files to be patched at runtime, writes go to an in-memory
+//! overlay. The first write to an archived file copies its contents into the
+//! overlay, and from then on every handle to that file reads the overlay copy.
+//! Files that aren't in the archive can be created directly in the overlay.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use crate::collections::SlotList;
-use crate::files::{cursor::SeekMethod, filename::copy_filename_to_dos_style, handle::{Handle, LocalHandle}};
+use crate::files::{cursor::{SeekMethod, SharedCursor}, filename::copy_filename_to_dos_style, handle::{Handle, LocalHandle}};
 use crate::memory::address::VirtualAddress;
-use spin::RwLock;
+use crate::locks::RwLock;
 use crate::fs::KernelFileSystem;
+use crate::fs::filesystem::FileAccess;
 use crate::task::id::ProcessID;
 use syscall::files::{DirEntryInfo, DirEntryType, FileStatus};
 
+type OverlayContents = Arc<RwLock<Vec<u8>>>;
+
+/// Where the contents of an open file currently live
+#[derive(Clone)]
+enum FileSource {
+  /// An untouched file in the CPIO archive
+  Archive {
+    header_start: usize,
+    file_start: usize,
+    length: usize,
+  },
+  /// A writable copy in the overlay, shared by all handles to the file
+  Overlay(OverlayContents),
+}
+
 #[derive(Clone)]
 struct OpenFile {
-  pub cursor: usize,
-  pub length: usize,
-  pub header_start: usize,
-  pub file_start: usize,
+  pub cursor: SharedCursor,
+  pub name: String,
+  source: FileSource,
 }
 
 /// The InitFS archive is designed to be flat, without subdirs. Therefore the
 /// only valid directory is the root.
 struct OpenDirectory {
   cursor: usize,
+  /// Once the archive entries are exhausted, the index of the next file that
+  /// only exists in the overlay
+  created_index: usize,
 }
 
 enum OpenHandle {
@@ -30,10 +56,25 @@ enum OpenHandle {
   Directory(OpenDirectory),
 }
 
+struct OverlayFile {
+  contents: OverlayContents,
+  /// Set if the file was created at runtime, and has no archive entry
+  created: bool,
+}
+
 pub struct InitFileSystem {
   cpio_archive_address: VirtualAddress,
   archive_size: usize,
   open_handles: RwLock<SlotList<OpenHandle>>,
+  overlay: RwLock<BTreeMap<String, OverlayFile>>,
+}
+
+fn strip_root(path: &str) -> &str {
+  if path.starts_with('\\') {
+    &path[1..]
+  } else {
+    path
+  }
 }
 
 impl InitFileSystem {
@@ -45,65 +86,179 @@ impl InitFileSystem {
       cpio_archive_address: addr,
       archive_size: size,
       open_handles: RwLock::new(SlotList::new()),
+      overlay: RwLock::new(BTreeMap::new()),
     }
   }
+
+  fn find_archive_entry(&self, name: &str) -> Option<&'static CpioHeader> {
+    CpioIterator::new(self.cpio_archive_address.as_usize(), self.archive_size)
+      .find(|entry| entry.get_filename_str() == name)
+  }
+
+  fn insert_file(&self, name: &str, source: FileSource) -> LocalHandle {
+    let open_file = OpenFile {
+      cursor: SharedCursor::new(0),
+      name: String::from(name),
+      source,
+    };
+    let index = self.open_handles.write().insert(OpenHandle::File(open_file));
+    LocalHandle::new(index as u32)
+  }
+
+  /// If another handle has written to an archived file, switch this handle
+  /// over to the overlay copy
+  fn refresh_source(&self, open_file: &mut OpenFile) {
+    if let FileSource::Archive { .. } = open_file.source {
+      if let Some(entry) = self.overlay.read().get(&open_file.name) {
+        open_file.source = FileSource::Overlay(entry.contents.clone());
+      }
+    }
+  }
+
+  /// Get the writable copy of a file, copying it out of the archive if this is
+  /// the first time it has been modified
+  fn get_writable_contents(&self, open_file: &mut OpenFile) -> OverlayContents {
+    self.refresh_source(open_file);
+    let (file_start, length) = match open_file.source {
+      FileSource::Overlay(ref contents) => return contents.clone(),
+      FileSource::Archive { file_start, length, .. } => (file_start, length),
+    };
+    let archived = unsafe {
+      core::slice::from_raw_parts(file_start as *const u8, length)
+    };
+    let contents = Arc::new(RwLock::new(archived.to_vec()));
+    self.overlay.write().insert(
+      open_file.name.clone(),
+      OverlayFile {
+        contents: contents.clone(),
+        created: false,
+      },
+    );
+    open_file.source = FileSource::Overlay(contents.clone());
+    contents
+  }
+
+  /// Names of files that only exist in the overlay, in sorted order
+  fn created_file_names(&self) -> Vec<String> {
+    self.overlay
+      .read()
+      .iter()
+      .filter_map(|(name, entry)| if entry.created { Some(name.clone()) } else { None })
+      .collect()
+  }
 }
 
 impl KernelFileSystem for InitFileSystem {
   fn open(&self, path: &str) -> Result<LocalHandle, ()> {
-    let local_path = if path.starts_with('\\') {
-      &path[1..]
-    } else {
-      path
+    let local_path = strip_root(path);
+
+    // Modified and created files take precedence over the archive
+    let overlay_contents = self.overlay
+      .read()
+      .get(local_path)
+      .map(|entry| entry.contents.clone());
+    if let Some(contents) = overlay_contents {
+      return Ok(self.insert_file(local_path, FileSource::Overlay(contents)));
+    }
+
+    let entry = self.find_archive_entry(local_path).ok_or(())?;
+    let source = FileSource::Archive {
+      header_start: entry as *const CpioHeader as usize,
+      file_start: entry.get_content_ptr() as usize,
+      length: entry.get_file_size(),
     };
+    Ok(self.insert_file(local_path, source))
+  }
 
-    let iter = CpioIterator::new(self.cpio_archive_address.as_usize());
-    for entry in iter {
-      if entry.get_filename_str() == local_path {
-        let open_file = OpenFile {
-          header_start: entry as *const CpioHeader as usize,
-          file_start: entry.get_content_ptr() as usize,
-          length: entry.get_file_size(),
-          cursor: 0,
-        };
-        let index = self.open_handles.write().insert(OpenHandle::File(open_file));
-        return Ok(LocalHandle::new(index as u32));
-      }
+  /// Create a new, empty file in the overlay. If the file already exists,
+  /// it is opened instead.
+  fn create(&self, path: &str) -> Result<LocalHandle, ()> {
+    match self.create_new(path)? {
+      Some(handle) => Ok(handle),
+      None => self.open(path),
     }
+  }
 
-    Err(())
+  /// The overlay stays locked from the existence check until the new file has
+  /// been added to it. Archive entries can't be removed, so checking them
+  /// under the same lock is enough.
+  fn create_new(&self, path: &str) -> Result<Option<LocalHandle>, ()> {
+    let name = strip_root(path);
+    if name.len() == 0 || name.contains('\\') {
+      return Err(());
+    }
+    let contents = Arc::new(RwLock::new(Vec::new()));
+    {
+      let mut overlay = self.overlay.write();
+      if overlay.contains_key(name) || self.find_archive_entry(name).is_some() {
+        return Ok(None);
+      }
+      overlay.insert(
+        String::from(name),
+        OverlayFile {
+          contents: contents.clone(),
+          created: true,
+        },
+      );
+    }
+    Ok(Some(self.insert_file(name, FileSource::Overlay(contents))))
   }
 
   fn read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()> {
-    let (address, to_read) = match self.open_handles.write().get_mut(handle.as_usize()) {
-      Some(OpenHandle::File(open_file)) => {
+    let mut handles = self.open_handles.write();
+    let open_file = match handles.get_mut(handle.as_usize()) {
+      Some(OpenHandle::File(open_file)) => open_file,
+      Some(OpenHandle::Directory(_)) => return Err(()),
+      None => return Err(()),
+    };
+    self.refresh_source(open_file);
+    let cursor = open_file.cursor.get();
+    let to_read = match open_file.source {
+      FileSource::Archive { file_start, length, .. } => {
         let mut to_read = buffer.len();
-        let bytes_left_in_file = open_file.length - open_file.cursor;
+        let bytes_left_in_file = length.saturating_sub(cursor);
         if bytes_left_in_file < to_read {
           to_read = bytes_left_in_file;
         }
-        let prev_cursor = open_file.cursor;
-        open_file.cursor += to_read;
-        Ok((open_file.file_start + prev_cursor, to_read))
+        let start = (file_start + cursor) as *const u8;
+        unsafe {
+          for offset in 0..to_read {
+            let ptr = start.offset(offset as isize);
+            buffer[offset] = *ptr;
+          }
+        }
+        to_read
       },
-      Some(OpenHandle::Directory(_)) => {
-        Err(())
+      FileSource::Overlay(ref contents) => {
+        let data = contents.read();
+        let start = cursor.min(data.len());
+        let to_read = buffer.len().min(data.len() - start);
+        buffer[..to_read].copy_from_slice(&data[start..(start + to_read)]);
+        to_read
       },
-      None => Err(()),
-    }?;
-
-    let start = address as *const u8;
-    unsafe {
-      for offset in 0..to_read {
-        let ptr = start.offset(offset as isize);
-        buffer[offset] = *ptr;
-      }
-    }
+    };
+    open_file.cursor.set(cursor + to_read);
     Ok(to_read)
   }
 
-  fn write(&self, _handle: LocalHandle, _buffer: &[u8]) -> Result<usize, ()> {
-    Ok(0)
+  fn write(&self, handle: LocalHandle, buffer: &[u8]) -> Result<usize, ()> {
+    let mut handles = self.open_handles.write();
+    let open_file = match handles.get_mut(handle.as_usize()) {
+      Some(OpenHandle::File(open_file)) => open_file,
+      Some(OpenHandle::Directory(_)) => return Err(()),
+      None => return Err(()),
+    };
+    let contents = self.get_writable_contents(open_file);
+    let mut data = contents.write();
+    let start = open_file.cursor.get();
+    let end = start + buffer.len();
+    if data.len() < end {
+      // Writing past the end of the file fills any gap with zeroes
+      data.resize(end, 0);
+    }
+    data[start..end].copy_from_slice(buffer);
+    open_file.cursor.set(end);
+    Ok(buffer.len())
   }
 
   fn close(&self, handle: LocalHandle) -> Result<(), ()> {
@@ -114,8 +269,16 @@ impl KernelFileSystem for InitFileSystem {
       .map_or(Err(()), |_| Ok(()))
   }
 
-  fn reopen(&self, handle: LocalHandle, _id: ProcessID) -> Result<LocalHandle, ()> {
-    let reopened_file= match self.open_handles.write().get_mut(handle.as_usize()) {
+  fn reopen(&self, handle: LocalHandle, id: ProcessID) -> Result<LocalHandle, ()> {
+    let reopened = self.reopen_shared(handle, id)?;
+    if let Some(OpenHandle::File(open_file)) = self.open_handles.write().get_mut(reopened.as_usize()) {
+      open_file.cursor = open_file.cursor.detach();
+    }
+    Ok(reopened)
+  }
+
+  fn reopen_shared(&self, handle: LocalHandle, _id: ProcessID) -> Result<LocalHandle, ()> {
+    let reopened_file = match self.open_handles.read().get(handle.as_usize()) {
       Some(OpenHandle::File(open_file)) => Ok(open_file.clone()),
       Some(OpenHandle::Directory(_)) => Err(()),
       None => Err(()),
@@ -130,11 +293,7 @@ impl KernelFileSystem for InitFileSystem {
 
   fn seek(&self, handle: LocalHandle, offset: SeekMethod) -> Result<usize, ()> {
     match self.open_handles.write().get_mut(handle.as_usize()) {
-      Some(OpenHandle::File(open_file)) => {
-        let new_cursor = offset.from_current_position(open_file.cursor);
-        open_file.cursor = new_cursor;
-        Ok(new_cursor)
-      },
+      Some(OpenHandle::File(open_file)) => Ok(open_file.cursor.seek(offset)),
       Some(OpenHandle::Directory(_)) => Err(()),
       None => Err(())
     }
@@ -146,37 +305,49 @@ impl KernelFileSystem for InitFileSystem {
     }
     let open_dir = OpenDirectory {
       cursor: 0,
+      created_index: 0,
     };
     let index = self.open_handles.write().insert(OpenHandle::Directory(open_dir));
     return Ok(LocalHandle::new(index as u32));
   }
 
   fn read_dir(&self, handle: LocalHandle, info: &mut DirEntryInfo) -> Result<bool, ()> {
+    let created = self.created_file_names();
     match self.open_handles.write().get_mut(handle.as_usize()) {
       Some(OpenHandle::Directory(open_dir)) => {
         // InitFS interprets the directory cursor as a byte offset from the
         // start of the archive. It points to the next CPIO entry header that
         // should be read.
+        let archive_end = self.cpio_archive_address.as_usize() + self.archive_size;
         let address = self.cpio_archive_address + open_dir.cursor;
-        let header: &CpioHeader = unsafe { &*(address.as_usize() as *const CpioHeader) };
-        if !header.is_valid() {
-          return Err(());
-        }
+        let header = CpioHeader::within(address.as_usize(), archive_end).ok_or(())?;
         if header.is_trailer() {
-          return Ok(false);
+          // The archive has been listed, continue with files that only exist
+          // in the overlay
+          let name = match created.get(open_dir.created_index) {
+            Some(name) => name,
+            None => return Ok(false),
+          };
+          copy_filename_to_dos_style(name.as_bytes(), &mut info.file_name, &mut info.file_ext);
+          info.entry_type = DirEntryType::File;
+          info.byte_size = self.overlay.read().get(name).map_or(0, |entry| entry.contents.read().len());
+          open_dir.created_index += 1;
+          return Ok(open_dir.created_index < created.len());
         }
         // copy the filename and extension
         copy_filename_to_dos_style(header.get_filename(), &mut info.file_name, &mut info.file_ext);
         info.entry_type = DirEntryType::File;
-        info.byte_size = header.get_file_size();
+        info.byte_size = match self.overlay.read().get(header.get_filename_str()) {
+          Some(entry) => entry.contents.read().len(),
+          None => header.get_file_size(),
+        };
 
         open_dir.cursor += header.length();
 
-        let next_header: &CpioHeader = unsafe { &*((address.as_usize() + header.length()) as *const CpioHeader) };
-        if !next_header.is_valid() || next_header.is_trailer() {
-          Ok(false)
-        } else {
-          Ok(true)
+        match CpioHeader::within(address.as_usize() + header.length(), archive_end) {
+          None => Ok(false),
+          Some(next_header) if next_header.is_trailer() => Ok(created.len() > 0),
+          Some(_) => Ok(true),
         }
       },
       Some(OpenHandle::File(_)) => Err(()),
@@ -184,12 +355,43 @@ impl KernelFileSystem for InitFileSystem {
     }
   }
 
+  /// Every file in the archive can be written, since changes go to the
+  /// overlay. The root is the only directory.
+  fn access(&self, path: &str) -> Result<FileAccess, ()> {
+    let local_path = strip_root(path);
+    if local_path.is_empty() || self.overlay.read().contains_key(local_path) {
+      return Ok(FileAccess::read_write());
+    }
+    self.find_archive_entry(local_path).ok_or(())?;
+    Ok(FileAccess::read_write())
+  }
+
+  /// Only files created at runtime can be removed; the archive itself can't
+  /// be modified. Open handles share the file's contents, so its buffer is
+  /// freed when the last of them closes.
+  fn unlink(&self, path: &str) -> Result<(), ()> {
+    let local_path = strip_root(path);
+    let mut overlay = self.overlay.write();
+    match overlay.get(local_path) {
+      Some(entry) if entry.created => {
+        overlay.remove(local_path);
+        Ok(())
+      },
+      _ => Err(()),
+    }
+  }
+
   fn stat(&self, handle: LocalHandle, status: &mut FileStatus) -> Result<(), ()> {
-    match self.open_handles.read().get(handle.as_usize()) {
+    match self.open_handles.write().get_mut(handle.as_usize()) {
       Some(OpenHandle::File(open_file)) => {
-        let start = open_file.header_start;
-        let header: &CpioHeader = unsafe { &*(start as *const CpioHeader) };
-        status.byte_size = header.get_file_size();
+        self.refresh_source(open_file);
+        status.byte_size = match open_file.source {
+          FileSource::Archive { header_start, .. } => {
+            let header: &CpioHeader = unsafe { &*(header_start as *const CpioHeader) };
+            header.get_file_size()
+          },
+          FileSource::Overlay(ref contents) => contents.read().len(),
+        };
       },
       Some(OpenHandle::Directory(_dir)) => {
 
@@ -202,6 +404,9 @@ impl KernelFileSystem for InitFileSystem {
 
 const TRAILER: &[u8] = "TRAILER!!!".as_bytes();
 
+/// Size of a header, not including the filename that follows it
+const HEADER_SIZE: usize = 26;
+
 /// CPIO archives consist of a series of files with headers using this format.
 #[repr(packed)]
 pub struct CpioHeader {
@@ -226,6 +431,23 @@ impl CpioHeader {
     }
   }
 
+  /// Get the header at an address, but only if it is valid and its entire
+  /// entry ends before `end`. A corrupt or truncated archive can't lead a
+  /// reader past the end of its memory.
+  pub fn within(addr: usize, end: usize) -> Option<&'static CpioHeader> {
+    if addr.checked_add(HEADER_SIZE)? > end {
+      return None;
+    }
+    let header = CpioHeader::at_offset(addr);
+    if !header.is_valid() || header.name_size == 0 {
+      return None;
+    }
+    if addr.checked_add(header.length())? > end {
+      return None;
+    }
+    Some(header)
+  }
+
   pub fn is_valid(&self) -> bool {
     self.magic == 0x71c7
   }
@@ -235,7 +457,7 @@ impl CpioHeader {
   }
 
   pub fn get_filename_ptr(&self) -> *const u8 {
-    unsafe { self.get_header_ptr().offset(26) }
+    unsafe { self.get_header_ptr().offset(HEADER_SIZE as isize) }
   }
 
   pub fn get_file_size(&self) -> usize {
@@ -271,10 +493,7 @@ impl CpioHeader {
   }
 
   pub fn is_trailer(&self) -> bool {
-    let filename = unsafe {
-      core::slice::from_raw_parts(self.get_filename_ptr(), TRAILER.len())
-    };
-    filename == TRAILER
+    self.get_filename() == TRAILER
   }
 
   pub fn length(&self) -> usize {
@@ -286,18 +505,24 @@ impl CpioHeader {
     if file_length & 1 != 0 {
       file_length += 1;
     }
-    26 + filename_length + file_length
+    HEADER_SIZE + filename_length + file_length
   }
 }
 
+/// Walks the entries of an archive until the trailer. Iteration also stops at
+/// the end of the archive's memory, or at an entry that doesn't fit in it, so
+/// an archive without a trailer can't send the iterator off into whatever
+/// follows it.
 pub struct CpioIterator {
   address: usize,
+  end: usize,
 }
 
 impl CpioIterator {
-  pub fn new(address: usize) -> CpioIterator {
+  pub fn new(address: usize, size: usize) -> CpioIterator {
     CpioIterator {
-      address
+      address,
+      end: address + size,
     }
   }
 }
@@ -306,7 +531,7 @@ impl Iterator for CpioIterator {
   type Item = &'static CpioHeader;
 
   fn next(&mut self) -> Option<Self::Item> {
-    let entry = CpioHeader::at_offset(self.address);
+    let entry = CpioHeader::within(self.address, self.end)?;
     if entry.is_trailer() {
       None
     } else {
@@ -315,3 +540,208 @@ impl Iterator for CpioIterator {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+  use crate::files::cursor::SeekMethod;
+  use crate::fs::KernelFileSystem;
+  use crate::memory::address::VirtualAddress;
+  use syscall::files::DirEntryInfo;
+  use super::{CpioIterator, FileAccess, InitFileSystem};
+
+  fn push_u16(archive: &mut Vec<u8>, value: u16) {
+    archive.push((value & 0xff) as u8);
+    archive.push((value >> 8) as u8);
+  }
+
+  fn push_entry(archive: &mut Vec<u8>, name: &str, contents: &[u8]) {
+    push_u16(archive, 0x71c7);
+    for _ in 0..7 {
+      push_u16(archive, 0);
+    }
+    // modification time
+    push_u16(archive, 0);
+    push_u16(archive, 0);
+    push_u16(archive, name.len() as u16 + 1);
+    push_u16(archive, (contents.len() >> 16) as u16);
+    push_u16(archive, (contents.len() & 0xffff) as u16);
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    if archive.len() & 1 != 0 {
+      archive.push(0);
+    }
+    archive.extend_from_slice(contents);
+    if archive.len() & 1 != 0 {
+      archive.push(0);
+    }
+  }
+
+  fn build_archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut archive = Vec::new();
+    for (name, contents) in files {
+      push_entry(&mut archive, name, contents);
+    }
+    push_entry(&mut archive, "TRAILER!!!", &[]);
+    archive
+  }
+
+  fn read_all(fs: &InitFileSystem, path: &str) -> Vec<u8> {
+    let handle = fs.open(path).unwrap();
+    let mut buffer: [u8; 32] = [0; 32];
+    let len = fs.read(handle, &mut buffer).unwrap();
+    fs.close(handle).unwrap();
+    buffer[..len].to_vec()
+  }
+
+  #[test]
+  fn reads_fall_through_to_archive() {
+    let archive = build_archive(&[("BOOT.BAT", b"echo hi"), ("OTHER.TXT", b"untouched")]);
+    let fs = InitFileSystem::new(VirtualAddress::new(archive.as_ptr() as usize), archive.len());
+    assert_eq!(read_all(&fs, "\\BOOT.BAT"), b"echo hi");
+
+    let handle = fs.open("BOOT.BAT").unwrap();
+    fs.write(handle, b"ECHO").unwrap();
+    // Untouched files still come from the archive
+    assert_eq!(read_all(&fs, "OTHER.TXT"), b"untouched");
+    assert!(fs.open("MISSING.TXT").is_err());
+    // The archive itself is never modified
+    assert!(archive.windows(7).any(|w| w == b"echo hi"));
+  }
+
+  #[test]
+  fn overlay_overrides_archive() {
+    let archive = build_archive(&[("BOOT.BAT", b"echo hello")]);
+    let fs = InitFileSystem::new(VirtualAddress::new(archive.as_ptr() as usize), archive.len());
+    // A handle opened before the write sees the new contents too
+    let early = fs.open("BOOT.BAT").unwrap();
+    let writer = fs.open("BOOT.BAT").unwrap();
+    fs.seek(writer, SeekMethod::Absolute(5)).unwrap();
+    assert_eq!(fs.write(writer, b"HELLO, WORLD"), Ok(12));
+    let mut buffer: [u8; 32] = [0; 32];
+    assert_eq!(fs.read(early, &mut buffer), Ok(17));
+    assert_eq!(&buffer[..17], b"echo HELLO, WORLD");
+    assert_eq!(read_all(&fs, "BOOT.BAT"), b"echo HELLO, WORLD");
+  }
+
+  #[test]
+  fn create_new_file() {
+    let archive = build_archive(&[("BOOT.BAT", b"echo hi")]);
+    let fs = InitFileSystem::new(VirtualAddress::new(archive.as_ptr() as usize), archive.len());
+    assert!(fs.open("NEW.TXT").is_err());
+    let handle = fs.create("NEW.TXT").unwrap();
+    fs.write(handle, b"new data").unwrap();
+    fs.close(handle).unwrap();
+    assert_eq!(read_all(&fs, "NEW.TXT"), b"new data");
+  }
+
+  #[test]
+  fn exclusive_create() {
+    let archive = build_archive(&[("BOOT.BAT", b"echo hi")]);
+    let fs = InitFileSystem::new(VirtualAddress::new(archive.as_ptr() as usize), archive.len());
+    assert_eq!(fs.create_new("BOOT.BAT"), Ok(None));
+    let handle = fs.create_new("\\LOCK").unwrap().unwrap();
+    fs.write(handle, b"1").unwrap();
+    fs.close(handle).unwrap();
+    assert_eq!(fs.create_new("LOCK"), Ok(None));
+    // A plain create opens the existing file without truncating it
+    let handle = fs.create("LOCK").unwrap();
+    fs.close(handle).unwrap();
+    assert_eq!(read_all(&fs, "LOCK"), b"1");
+    // Once removed, the name can be claimed again
+    fs.unlink("LOCK").unwrap();
+    assert!(fs.create_new("LOCK").unwrap().is_some());
+    assert!(fs.create_new("SUB\\FILE").is_err());
+  }
+
+  #[test]
+  fn exclusive_create_race() {
+    extern crate std;
+    use alloc::sync::Arc;
+    use std::sync::Barrier;
+    use std::thread;
+
+    let archive = build_archive(&[("BOOT.BAT", b"echo hi")]);
+    let fs = Arc::new(InitFileSystem::new(VirtualAddress::new(archive.as_ptr() as usize), archive.len()));
+    // Each thread stands in for a process trying to take the same lock file
+    for _ in 0..50 {
+      let barrier = Arc::new(Barrier::new(2));
+      let racers: Vec<_> = (0..2).map(|_| {
+        let fs = fs.clone();
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+          barrier.wait();
+          fs.create_new("RACE.LCK").unwrap()
+        })
+      }).collect();
+      let winners: Vec<_> = racers.into_iter().filter_map(|racer| racer.join().unwrap()).collect();
+      assert_eq!(winners.len(), 1);
+      fs.close(winners[0]).unwrap();
+      fs.unlink("RACE.LCK").unwrap();
+    }
+  }
+
+  #[test]
+  fn access_checks_archive_and_overlay() {
+    let archive = build_archive(&[("BOOT.BAT", b"echo hi")]);
+    let fs = InitFileSystem::new(VirtualAddress::new(archive.as_ptr() as usize), archive.len());
+    assert_eq!(fs.access("\\BOOT.BAT"), Ok(FileAccess::read_write()));
+    assert_eq!(fs.access(""), Ok(FileAccess::read_write()));
+    assert!(fs.access("MISSING.TXT").is_err());
+    // Files created at runtime only exist in the overlay
+    let handle = fs.create("MISSING.TXT").unwrap();
+    fs.close(handle).unwrap();
+    assert_eq!(fs.access("MISSING.TXT"), Ok(FileAccess::read_write()));
+  }
+
+  #[test]
+  fn unlink_created_files() {
+    let archive = build_archive(&[("BOOT.BAT", b"echo hi")]);
+    let fs = InitFileSystem::new(VirtualAddress::new(archive.as_ptr() as usize), archive.len());
+    let handle = fs.create("TEMP.TXT").unwrap();
+    fs.write(handle, b"scratch").unwrap();
+    assert!(fs.unlink("TEMP.TXT").is_ok());
+    assert!(fs.access("TEMP.TXT").is_err());
+    assert!(fs.unlink("TEMP.TXT").is_err());
+    // The open handle still sees the contents
+    fs.seek(handle, SeekMethod::Absolute(0)).unwrap();
+    let mut buffer = [0u8; 16];
+    assert_eq!(fs.read(handle, &mut buffer), Ok(7));
+    assert_eq!(&buffer[..7], b"scratch");
+    fs.close(handle).unwrap();
+
+    // Archived files, even modified ones, can't be removed
+    assert!(fs.unlink("BOOT.BAT").is_err());
+    let handle = fs.open("BOOT.BAT").unwrap();
+    fs.write(handle, b"ECHO").unwrap();
+    fs.close(handle).unwrap();
+    assert!(fs.unlink("BOOT.BAT").is_err());
+    assert!(fs.unlink("MISSING.TXT").is_err());
+  }
+
+  #[test]
+  fn unterminated_archive() {
+    let mut archive = Vec::new();
+    push_entry(&mut archive, "A.TXT", b"first");
+    push_entry(&mut archive, "B.TXT", b"second");
+    let start = archive.as_ptr() as usize;
+    // Without a trailer, iteration stops at the end of the archive
+    assert_eq!(CpioIterator::new(start, archive.len()).count(), 2);
+    let fs = InitFileSystem::new(VirtualAddress::new(start), archive.len());
+    assert_eq!(read_all(&fs, "B.TXT"), b"second");
+    assert!(fs.open("C.TXT").is_err());
+    let dir = fs.open_dir("").unwrap();
+    let mut info = DirEntryInfo::empty();
+    assert_eq!(fs.read_dir(dir, &mut info), Ok(true));
+    assert_eq!(fs.read_dir(dir, &mut info), Ok(false));
+    assert!(fs.read_dir(dir, &mut info).is_err());
+
+    // An entry claiming more data than the archive holds is never returned
+    let truncated = archive.len() - 4;
+    assert_eq!(CpioIterator::new(start, truncated).count(), 1);
+    let fs = InitFileSystem::new(VirtualAddress::new(start), truncated);
+    assert!(fs.open("B.TXT").is_err());
+    // Neither is a partial header
+    assert_eq!(CpioIterator::new(start, 10).count(), 0);
+  }
+}
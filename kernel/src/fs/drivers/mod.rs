@@ -1,3 +1,8 @@
 #[cfg(not(test))]
 pub mod devfs;
-pub mod initfs;
\ No newline at end of file
+pub mod fat12;
+pub mod initfs;
+#[cfg(test)]
+pub mod testfs;
+pub mod timerfs;
+pub mod tmpfs;
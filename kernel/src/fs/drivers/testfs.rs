@@ -0,0 +1,196 @@
+//! TestFS is an in-memory filesystem shared by unit tests that need a drive
+//! to open, load, rename, or sync files on. Files are added before the drive
+//! is mounted, and names are matched without regard to case, like on a FAT
+//! disk. Cloning a TestFileSystem shares its files and state, so a test can
+//! keep one copy to inspect after mounting the other.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::collections::SlotList;
+use crate::files::cursor::SeekMethod;
+use crate::files::handle::{Handle, LocalHandle};
+use crate::fs::filesystem::{FileAccess, FileSystemType, KernelFileSystem};
+use crate::task::id::ProcessID;
+use crate::locks::RwLock;
+use syscall::files::{DirEntryInfo, FileStatus};
+
+/// The contents of a file, which a test can modify after it has been added
+pub type FileContents = Arc<RwLock<Vec<u8>>>;
+
+struct TestFile {
+  contents: FileContents,
+  access: FileAccess,
+}
+
+struct OpenFile {
+  contents: FileContents,
+  cursor: usize,
+}
+
+struct SharedState {
+  files: RwLock<BTreeMap<String, TestFile>>,
+  open_files: RwLock<SlotList<OpenFile>>,
+  sync_fails: AtomicBool,
+  synced: AtomicBool,
+}
+
+#[derive(Clone)]
+pub struct TestFileSystem {
+  state: Arc<SharedState>,
+}
+
+fn normalize(path: &str) -> String {
+  path.trim_start_matches('\\').to_ascii_uppercase()
+}
+
+impl TestFileSystem {
+  pub fn new() -> TestFileSystem {
+    TestFileSystem {
+      state: Arc::new(SharedState {
+        files: RwLock::new(BTreeMap::new()),
+        open_files: RwLock::new(SlotList::new()),
+        sync_fails: AtomicBool::new(false),
+        synced: AtomicBool::new(false),
+      }),
+    }
+  }
+
+  /// Box up a copy of this filesystem, ready to be mounted as a drive
+  pub fn mountable(&self) -> Arc<Box<FileSystemType>> {
+    Arc::new(Box::new(self.clone()))
+  }
+
+  /// Add a readable and writable file, returning its shared contents
+  pub fn add_file(&self, path: &str, contents: &[u8]) -> FileContents {
+    self.add_file_with_access(path, contents, FileAccess::read_write())
+  }
+
+  /// Add a file that can't be written or deleted
+  pub fn add_read_only_file(&self, path: &str, contents: &[u8]) -> FileContents {
+    self.add_file_with_access(path, contents, FileAccess::read_only())
+  }
+
+  fn add_file_with_access(&self, path: &str, contents: &[u8], access: FileAccess) -> FileContents {
+    let contents = Arc::new(RwLock::new(contents.to_vec()));
+    let file = TestFile {
+      contents: contents.clone(),
+      access,
+    };
+    self.state.files.write().insert(normalize(path), file);
+    contents
+  }
+
+  /// Make every following sync fail
+  pub fn fail_sync(&self) {
+    self.state.sync_fails.store(true, Ordering::SeqCst);
+  }
+
+  pub fn was_synced(&self) -> bool {
+    self.state.synced.load(Ordering::SeqCst)
+  }
+
+  /// Number of file handles that have been opened and not yet closed
+  pub fn open_count(&self) -> usize {
+    self.state.open_files.read().iter().count()
+  }
+}
+
+impl KernelFileSystem for TestFileSystem {
+  fn open(&self, path: &str) -> Result<LocalHandle, ()> {
+    let contents = self.state.files.read().get(&normalize(path)).ok_or(())?.contents.clone();
+    let index = self.state.open_files.write().insert(OpenFile { contents, cursor: 0 });
+    Ok(LocalHandle::new(index as u32))
+  }
+
+  fn read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()> {
+    let mut open_files = self.state.open_files.write();
+    let open_file = open_files.get_mut(handle.as_usize()).ok_or(())?;
+    let contents = open_file.contents.read();
+    let remaining = &contents[open_file.cursor.min(contents.len())..];
+    let length = remaining.len().min(buffer.len());
+    buffer[..length].copy_from_slice(&remaining[..length]);
+    open_file.cursor += length;
+    Ok(length)
+  }
+
+  fn write(&self, handle: LocalHandle, buffer: &[u8]) -> Result<usize, ()> {
+    let mut open_files = self.state.open_files.write();
+    let open_file = open_files.get_mut(handle.as_usize()).ok_or(())?;
+    let mut contents = open_file.contents.write();
+    let end = open_file.cursor + buffer.len();
+    if contents.len() < end {
+      contents.resize(end, 0);
+    }
+    contents[open_file.cursor..end].copy_from_slice(buffer);
+    open_file.cursor = end;
+    Ok(buffer.len())
+  }
+
+  fn close(&self, handle: LocalHandle) -> Result<(), ()> {
+    self.state.open_files.write().remove(handle.as_usize()).map(|_| ()).ok_or(())
+  }
+
+  fn reopen(&self, _handle: LocalHandle, _id: ProcessID) -> Result<LocalHandle, ()> {
+    Err(())
+  }
+
+  fn seek(&self, handle: LocalHandle, offset: SeekMethod) -> Result<usize, ()> {
+    let mut open_files = self.state.open_files.write();
+    let open_file = open_files.get_mut(handle.as_usize()).ok_or(())?;
+    open_file.cursor = offset.from_current_position(open_file.cursor);
+    Ok(open_file.cursor)
+  }
+
+  fn open_dir(&self, _path: &str) -> Result<LocalHandle, ()> {
+    Err(())
+  }
+
+  fn read_dir(&self, _handle: LocalHandle, _info: &mut DirEntryInfo) -> Result<bool, ()> {
+    Err(())
+  }
+
+  fn access(&self, path: &str) -> Result<FileAccess, ()> {
+    self.state.files.read().get(&normalize(path)).map(|file| file.access).ok_or(())
+  }
+
+  fn rename(&self, old_path: &str, new_path: &str, replace: bool) -> Result<(), ()> {
+    let mut files = self.state.files.write();
+    let (old_name, new_name) = (normalize(old_path), normalize(new_path));
+    if old_name != new_name && files.contains_key(&new_name) && !replace {
+      return Err(());
+    }
+    let file = files.remove(&old_name).ok_or(())?;
+    files.insert(new_name, file);
+    Ok(())
+  }
+
+  fn unlink(&self, path: &str) -> Result<(), ()> {
+    let mut files = self.state.files.write();
+    let name = normalize(path);
+    match files.get(&name) {
+      Some(file) if file.access.writable => (),
+      _ => return Err(()),
+    }
+    files.remove(&name);
+    Ok(())
+  }
+
+  fn stat(&self, handle: LocalHandle, status: &mut FileStatus) -> Result<(), ()> {
+    let open_files = self.state.open_files.read();
+    let open_file = open_files.get(handle.as_usize()).ok_or(())?;
+    status.byte_size = open_file.contents.read().len();
+    Ok(())
+  }
+
+  fn sync(&self) -> Result<(), ()> {
+    if self.state.sync_fails.load(Ordering::SeqCst) {
+      return Err(());
+    }
+    self.state.synced.store(true, Ordering::SeqCst);
+    Ok(())
+  }
+}
@@ -0,0 +1,343 @@
+//! Timer handles let an event loop wait on time the same way it waits on
+//! files, without needing signals. A timer becomes readable once its interval
+//! has elapsed, and reading it returns the number of expirations since the
+//! previous read as a little-endian u32. Periodic timers keep counting while
+//! nobody reads them, so a slow reader learns how many intervals it missed.
+//! One-shot timers expire only once. After that expiration has been read, the
+//! timer is disarmed, and reads return no data instead of waiting forever.
+//! Timers don't need to be updated on every tick. Expirations are computed
+//! from the system tick counter whenever a timer is polled or read.
+
+use alloc::sync::Arc;
+use crate::collections::SlotList;
+use crate::files::cursor::SeekMethod;
+use crate::files::handle::{Handle, LocalHandle};
+use crate::fs::filesystem::KernelFileSystem;
+use crate::task::id::ProcessID;
+use crate::time::ticks::tick_reached;
+use crate::locks::RwLock;
+use syscall::files::{DirEntryInfo, FileStatus};
+
+/// Reads must have room for the expiration count
+pub const EXPIRATION_SIZE: usize = 4;
+
+struct Timer {
+  /// The tick that expirations are counted from. Periodic timers move it
+  /// forward as their expirations are read.
+  base: u32,
+  interval: u32,
+  periodic: bool,
+  /// Set once a one-shot timer's expiration has been read
+  fired: bool,
+  /// Number of open handles pointing to this timer
+  references: usize,
+}
+
+impl Timer {
+  fn pending_expirations(&self, now: u32) -> u32 {
+    if !tick_reached(now, self.base) {
+      return 0;
+    }
+    let elapsed = now.wrapping_sub(self.base) / self.interval;
+    if self.periodic {
+      elapsed
+    } else if self.fired {
+      0
+    } else {
+      elapsed.min(1)
+    }
+  }
+
+  fn is_disarmed(&self) -> bool {
+    !self.periodic && self.fired
+  }
+
+  fn consume_expirations(&mut self, now: u32) -> u32 {
+    let expirations = self.pending_expirations(now);
+    if self.periodic {
+      self.base = self.base.wrapping_add(expirations.wrapping_mul(self.interval));
+    } else if expirations > 0 {
+      self.fired = true;
+    }
+    expirations
+  }
+}
+
+/// Every live timer, shared between the mounted drive and the syscall that
+/// creates new timers
+pub struct Timers {
+  timers: RwLock<SlotList<Timer>>,
+  /// Maps each open handle to the timer it refers to
+  open_handles: RwLock<SlotList<usize>>,
+}
+
+impl Timers {
+  pub const fn new() -> Timers {
+    Timers {
+      timers: RwLock::new(SlotList::new()),
+      open_handles: RwLock::new(SlotList::new()),
+    }
+  }
+
+  fn get_timer_index(&self, handle: LocalHandle) -> Result<usize, ()> {
+    self.open_handles.read().get(handle.as_usize()).copied().ok_or(())
+  }
+
+  /// Start a timer that first expires `interval` ticks after `now`, returning
+  /// the only handle that refers to it. Periodic timers expire again after
+  /// each further interval.
+  pub fn create(&self, now: u32, interval: u32, periodic: bool) -> Result<LocalHandle, ()> {
+    if interval == 0 {
+      return Err(());
+    }
+    let timer = self.timers.write().insert(
+      Timer {
+        base: now,
+        interval,
+        periodic,
+        fired: false,
+        references: 1,
+      }
+    );
+    let index = self.open_handles.write().insert(timer);
+    Ok(LocalHandle::new(index as u32))
+  }
+
+  /// Count the expirations that a read would return, without consuming them
+  pub fn pending_expirations(&self, handle: LocalHandle, now: u32) -> Result<u32, ()> {
+    let index = self.get_timer_index(handle)?;
+    let timers = self.timers.read();
+    Ok(timers.get(index).ok_or(())?.pending_expirations(now))
+  }
+
+  /// Take the expirations since the last read. Returns zero if the timer
+  /// hasn't expired again yet.
+  pub fn consume_expirations(&self, handle: LocalHandle, now: u32) -> Result<u32, ()> {
+    let index = self.get_timer_index(handle)?;
+    let mut timers = self.timers.write();
+    Ok(timers.get_mut(index).ok_or(())?.consume_expirations(now))
+  }
+
+  /// A one-shot timer is disarmed once its expiration has been read
+  pub fn is_disarmed(&self, handle: LocalHandle) -> Result<bool, ()> {
+    let index = self.get_timer_index(handle)?;
+    let timers = self.timers.read();
+    Ok(timers.get(index).ok_or(())?.is_disarmed())
+  }
+
+  /// Create another handle to the same timer. Both handles consume the same
+  /// expirations.
+  pub fn reopen(&self, handle: LocalHandle) -> Result<LocalHandle, ()> {
+    let index = self.get_timer_index(handle)?;
+    self.timers.write().get_mut(index).ok_or(())?.references += 1;
+    let new_index = self.open_handles.write().insert(index);
+    Ok(LocalHandle::new(new_index as u32))
+  }
+
+  /// Close a handle. Once the last handle to a timer is closed, the timer is
+  /// stopped.
+  pub fn close(&self, handle: LocalHandle) -> Result<(), ()> {
+    let index = self.open_handles.write().remove(handle.as_usize()).ok_or(())?;
+    let mut timers = self.timers.write();
+    let remaining = {
+      let timer = timers.get_mut(index).ok_or(())?;
+      timer.references -= 1;
+      timer.references
+    };
+    if remaining == 0 {
+      timers.remove(index);
+    }
+    Ok(())
+  }
+}
+
+pub struct TimerFileSystem {
+  timers: Arc<Timers>,
+  /// Reads the current system tick
+  clock: fn() -> u32,
+}
+
+impl TimerFileSystem {
+  pub fn new(timers: &Arc<Timers>, clock: fn() -> u32) -> TimerFileSystem {
+    TimerFileSystem {
+      timers: Arc::clone(timers),
+      clock,
+    }
+  }
+}
+
+impl KernelFileSystem for TimerFileSystem {
+  /// Timers have no paths, so nothing can be opened
+  fn open(&self, _path: &str) -> Result<LocalHandle, ()> {
+    Err(())
+  }
+
+  /// Wait until the timer has expired at least once, and return how many
+  /// times it has expired since the last read. A signal ends the wait early.
+  /// A disarmed timer will never expire again, so it returns 0 right away.
+  fn read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()> {
+    loop {
+      if let Some(read) = self.read_nonblocking(handle, buffer)? {
+        return Ok(read);
+      }
+      crate::task::yield_interruptible()?;
+    }
+  }
+
+  fn read_nonblocking(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<Option<usize>, ()> {
+    if buffer.len() < EXPIRATION_SIZE {
+      return Err(());
+    }
+    let expirations = self.timers.consume_expirations(handle, (self.clock)())?;
+    if expirations == 0 {
+      if self.timers.is_disarmed(handle)? {
+        return Ok(Some(0));
+      }
+      return Ok(None);
+    }
+    buffer[..EXPIRATION_SIZE].copy_from_slice(&expirations.to_le_bytes());
+    Ok(Some(EXPIRATION_SIZE))
+  }
+
+  fn write(&self, _handle: LocalHandle, _buffer: &[u8]) -> Result<usize, ()> {
+    Err(())
+  }
+
+  fn close(&self, handle: LocalHandle) -> Result<(), ()> {
+    self.timers.close(handle)
+  }
+
+  /// A disarmed timer is always readable, like a file at its end
+  fn poll_readable(&self, handle: LocalHandle) -> Result<bool, ()> {
+    Ok(self.timers.pending_expirations(handle, (self.clock)())? > 0 || self.timers.is_disarmed(handle)?)
+  }
+
+  fn poll_writable(&self, _handle: LocalHandle) -> Result<bool, ()> {
+    Ok(false)
+  }
+
+  fn reopen(&self, handle: LocalHandle, _id: ProcessID) -> Result<LocalHandle, ()> {
+    self.timers.reopen(handle)
+  }
+
+  fn seek(&self, _handle: LocalHandle, _offset: SeekMethod) -> Result<usize, ()> {
+    Err(())
+  }
+
+  fn open_dir(&self, _path: &str) -> Result<LocalHandle, ()> {
+    Err(())
+  }
+
+  fn read_dir(&self, _handle: LocalHandle, _info: &mut DirEntryInfo) -> Result<bool, ()> {
+    Err(())
+  }
+
+  fn stat(&self, handle: LocalHandle, status: &mut FileStatus) -> Result<(), ()> {
+    self.timers.get_timer_index(handle)?;
+    status.byte_size = 0;
+    Ok(())
+  }
+}
+
+#[cfg(not(test))]
+static mut TIMERS: Option<Arc<Timers>> = None;
+
+#[cfg(not(test))]
+pub fn create_fs() -> alloc::boxed::Box<crate::fs::filesystem::FileSystemType> {
+  use alloc::boxed::Box;
+
+  unsafe {
+    let timers = Arc::new(Timers::new());
+    let timer_fs = Box::new(TimerFileSystem::new(&timers, crate::time::system::get_system_ticks));
+    TIMERS = Some(timers);
+    timer_fs
+  }
+}
+
+/// Start a timer on the system clock, returning a handle on the TIMER drive
+#[cfg(not(test))]
+pub fn create_timer(interval: u32, periodic: bool) -> Result<LocalHandle, ()> {
+  let timers = match unsafe { &TIMERS } {
+    Some(timers) => timers,
+    None => panic!("TimerFS was not created"),
+  };
+  timers.create(crate::time::system::get_system_ticks(), interval, periodic)
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::sync::Arc;
+  use core::sync::atomic::{AtomicU32, Ordering};
+  use crate::fs::filesystem::KernelFileSystem;
+  use super::{TimerFileSystem, Timers};
+
+  static NOW: AtomicU32 = AtomicU32::new(100);
+
+  fn clock() -> u32 {
+    NOW.load(Ordering::SeqCst)
+  }
+
+  #[test]
+  fn periodic_timer() {
+    let timers = Arc::new(Timers::new());
+    let fs = TimerFileSystem::new(&timers, clock);
+    let handle = timers.create(clock(), 10, true).unwrap();
+    let mut buffer = [0; 4];
+    assert_eq!(fs.poll_readable(handle), Ok(false));
+    assert_eq!(fs.read_nonblocking(handle, &mut buffer), Ok(None));
+
+    NOW.fetch_add(10, Ordering::SeqCst);
+    assert_eq!(fs.poll_readable(handle), Ok(true));
+    assert_eq!(fs.read_nonblocking(handle, &mut buffer), Ok(Some(4)));
+    assert_eq!(u32::from_le_bytes(buffer), 1);
+    assert_eq!(fs.poll_readable(handle), Ok(false));
+
+    // Intervals that pass without a read are added up
+    NOW.fetch_add(35, Ordering::SeqCst);
+    assert_eq!(fs.read(handle, &mut buffer), Ok(4));
+    assert_eq!(u32::from_le_bytes(buffer), 3);
+    // The partial interval still counts toward the next expiration
+    NOW.fetch_add(5, Ordering::SeqCst);
+    assert_eq!(fs.poll_readable(handle), Ok(true));
+
+    // Reads need room for the count
+    assert_eq!(fs.read_nonblocking(handle, &mut buffer[..2]), Err(()));
+    fs.close(handle).unwrap();
+    assert_eq!(fs.poll_readable(handle), Err(()));
+  }
+
+  #[test]
+  fn one_shot_timer() {
+    let timers = Timers::new();
+    let handle = timers.create(50, 20, false).unwrap();
+    let copy = timers.reopen(handle).unwrap();
+    assert_eq!(timers.consume_expirations(handle, 69), Ok(0));
+    assert_eq!(timers.pending_expirations(copy, 200), Ok(1));
+    assert_eq!(timers.consume_expirations(copy, 200), Ok(1));
+    // Once read through either handle, it never expires again
+    assert_eq!(timers.consume_expirations(handle, 1000), Ok(0));
+
+    assert_eq!(timers.is_disarmed(handle), Ok(true));
+
+    // The timer lives until both handles are closed
+    timers.close(handle).unwrap();
+    assert_eq!(timers.pending_expirations(copy, 1000), Ok(0));
+    timers.close(copy).unwrap();
+    assert_eq!(timers.timers.read().iter().count(), 0);
+    assert!(timers.create(0, 0, true).is_err());
+  }
+
+  #[test]
+  fn disarmed_timer_read_returns() {
+    let timers = Arc::new(Timers::new());
+    let fs = TimerFileSystem::new(&timers, clock);
+    let handle = timers.create(0, 1, false).unwrap();
+    let mut buffer = [0; 4];
+    assert_eq!(fs.read(handle, &mut buffer), Ok(4));
+    assert_eq!(u32::from_le_bytes(buffer), 1);
+    // The timer won't fire again, so waiting on it would never end
+    assert_eq!(fs.poll_readable(handle), Ok(true));
+    assert_eq!(fs.read(handle, &mut buffer), Ok(0));
+    assert_eq!(fs.read_nonblocking(handle, &mut buffer), Ok(Some(0)));
+  }
+}
@@ -0,0 +1,561 @@
+//! TmpFS is a RAM disk for scratch storage. Its files are anonymous: they have
+//! no directory entry and can't be opened by path. A file only exists as long
+//! as some handle refers to it. Handles can be duplicated by dup or fork, and
+//! once the last of them is closed, the file's memory is released.
+//! A file can be sealed against writing, growing, or shrinking. Seals can be
+//! added but never removed, so a process that receives a handle to a sealed
+//! file knows its contents won't change underneath it. Files sealed against
+//! writing can also be mapped read-only into a process's memory.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::collections::SlotList;
+use crate::files::cursor::{SeekMethod, SharedCursor};
+use crate::files::handle::{Handle, LocalHandle};
+use crate::fs::filesystem::{FileSystemType, KernelFileSystem};
+use crate::task::id::ProcessID;
+use crate::locks::RwLock;
+use syscall::files::{DirEntryInfo, FileStatus};
+use syscall::flags::{F_SEAL_GROW, F_SEAL_SHRINK, F_SEAL_WRITE};
+
+/// Every seal a file can carry
+const ALL_SEALS: u32 = F_SEAL_SHRINK | F_SEAL_GROW | F_SEAL_WRITE;
+
+/// Largest a single file can grow. Files live in kernel memory, so without a
+/// limit one seek and write far past the end would exhaust the heap.
+pub const MAX_FILE_SIZE: usize = 0x100000;
+
+struct AnonymousFile {
+  contents: Vec<u8>,
+  /// Number of open handles pointing to this file
+  references: usize,
+  /// F_SEAL_* bits restricting how the file can change
+  seals: u32,
+}
+
+impl AnonymousFile {
+  /// Check a new length for the file against its seals and the size limit
+  fn can_resize(&self, size: usize) -> bool {
+    if size > MAX_FILE_SIZE {
+      false
+    } else if size < self.contents.len() {
+      self.seals & F_SEAL_SHRINK == 0
+    } else if size > self.contents.len() {
+      self.seals & F_SEAL_GROW == 0
+    } else {
+      true
+    }
+  }
+
+  /// Check that `length` bytes can be written at `start`, and make room for
+  /// them. Writing past the end of the file fills any gap with zeroes. A write
+  /// that would grow the file past its size limit is cut short there, so the
+  /// number of bytes that fit is returned. If none do, the write accepts
+  /// nothing, which is reported as a full drive.
+  fn prepare_write(&mut self, start: usize, length: usize) -> Result<usize, ()> {
+    let end = start.checked_add(length).ok_or(())?;
+    if self.seals & F_SEAL_WRITE != 0 {
+      return Err(());
+    }
+    let end = end.min(MAX_FILE_SIZE);
+    if end <= start {
+      return Ok(0);
+    }
+    if self.contents.len() < end {
+      if !self.can_resize(end) {
+        return Err(());
+      }
+      self.contents.resize(end, 0);
+    }
+    Ok(end - start)
+  }
+}
+
+#[derive(Clone)]
+struct OpenFile {
+  file: usize,
+  cursor: SharedCursor,
+}
+
+/// Storage for every anonymous file, shared between the mounted drive and the
+/// syscall that creates new files
+pub struct AnonymousFiles {
+  files: RwLock<SlotList<AnonymousFile>>,
+  open_handles: RwLock<SlotList<OpenFile>>,
+}
+
+impl AnonymousFiles {
+  pub const fn new() -> AnonymousFiles {
+    AnonymousFiles {
+      files: RwLock::new(SlotList::new()),
+      open_handles: RwLock::new(SlotList::new()),
+    }
+  }
+
+  fn get_open_file(&self, handle: LocalHandle) -> Result<OpenFile, ()> {
+    self.open_handles.read().get(handle.as_usize()).cloned().ok_or(())
+  }
+
+  /// Find which file a handle points to. Handles duplicated from one another
+  /// share the same index.
+  pub fn get_file_index(&self, handle: LocalHandle) -> Result<usize, ()> {
+    Ok(self.get_open_file(handle)?.file)
+  }
+
+  /// Create an empty file, returning the only handle that refers to it
+  pub fn create(&self) -> LocalHandle {
+    let file = self.files.write().insert(
+      AnonymousFile {
+        contents: Vec::new(),
+        references: 1,
+        seals: 0,
+      }
+    );
+    let index = self.open_handles.write().insert(
+      OpenFile {
+        file,
+        cursor: SharedCursor::new(0),
+      }
+    );
+    LocalHandle::new(index as u32)
+  }
+
+  pub fn read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()> {
+    let open_file = self.get_open_file(handle)?;
+    let files = self.files.read();
+    let contents = &files.get(open_file.file).ok_or(())?.contents;
+    let start = open_file.cursor.get().min(contents.len());
+    let to_read = buffer.len().min(contents.len() - start);
+    buffer[..to_read].copy_from_slice(&contents[start..start + to_read]);
+    open_file.cursor.set(start + to_read);
+    Ok(to_read)
+  }
+
+  pub fn write(&self, handle: LocalHandle, buffer: &[u8]) -> Result<usize, ()> {
+    let open_file = self.get_open_file(handle)?;
+    let mut files = self.files.write();
+    let file = files.get_mut(open_file.file).ok_or(())?;
+    let start = open_file.cursor.get();
+    let length = file.prepare_write(start, buffer.len())?;
+    if length == 0 {
+      return Ok(0);
+    }
+    file.contents[start..(start + length)].copy_from_slice(&buffer[..length]);
+    open_file.cursor.set(start + length);
+    Ok(length)
+  }
+
+  /// Copy up to `count` bytes from one file to another, or within the same
+  /// file, directly between their contents. Copying stops at the end of the
+  /// source.
+  pub fn copy_range(&self, source: LocalHandle, dest: LocalHandle, count: usize) -> Result<usize, ()> {
+    let source_file = self.get_open_file(source)?;
+    let dest_file = self.get_open_file(dest)?;
+    let mut files = self.files.write();
+    let source_length = files.get(source_file.file).ok_or(())?.contents.len();
+    let start = source_file.cursor.get().min(source_length);
+    let length = count.min(source_length - start);
+    let dest_start = dest_file.cursor.get();
+    let length = files.get_mut(dest_file.file).ok_or(())?.prepare_write(dest_start, length)?;
+    if length == 0 {
+      return Ok(0);
+    }
+    let dest_end = dest_start + length;
+    if source_file.file == dest_file.file {
+      let contents = &mut files.get_mut(dest_file.file).ok_or(())?.contents;
+      contents.copy_within(start..(start + length), dest_start);
+    } else {
+      // Take the destination's contents out, so that the source can be
+      // borrowed while they are written
+      let mut contents = core::mem::take(&mut files.get_mut(dest_file.file).ok_or(())?.contents);
+      let source_contents = &files.get(source_file.file).ok_or(())?.contents;
+      contents[dest_start..dest_end].copy_from_slice(&source_contents[start..(start + length)]);
+      files.get_mut(dest_file.file).ok_or(())?.contents = contents;
+    }
+    source_file.cursor.set(start + length);
+    dest_file.cursor.set(dest_end);
+    Ok(length)
+  }
+
+  pub fn seek(&self, handle: LocalHandle, offset: SeekMethod) -> Result<usize, ()> {
+    let open_file = self.get_open_file(handle)?;
+    Ok(open_file.cursor.seek(offset))
+  }
+
+  /// Create another handle to the same file, starting at the same cursor.
+  /// If `shared` is set, the two handles keep moving that cursor together.
+  pub fn reopen(&self, handle: LocalHandle, shared: bool) -> Result<LocalHandle, ()> {
+    let mut open_file = self.get_open_file(handle)?;
+    if !shared {
+      open_file.cursor = open_file.cursor.detach();
+    }
+    self.files.write().get_mut(open_file.file).ok_or(())?.references += 1;
+    let index = self.open_handles.write().insert(open_file);
+    Ok(LocalHandle::new(index as u32))
+  }
+
+  /// Close a handle. If it was the last reference to its file, the file's
+  /// contents are freed.
+  pub fn close(&self, handle: LocalHandle) -> Result<(), ()> {
+    let open_file = self.open_handles.write().remove(handle.as_usize()).ok_or(())?;
+    let mut files = self.files.write();
+    let remaining = {
+      let file = files.get_mut(open_file.file).ok_or(())?;
+      file.references -= 1;
+      file.references
+    };
+    if remaining == 0 {
+      files.remove(open_file.file);
+    }
+    Ok(())
+  }
+
+  pub fn get_size(&self, handle: LocalHandle) -> Result<usize, ()> {
+    let open_file = self.get_open_file(handle)?;
+    let files = self.files.read();
+    files.get(open_file.file).map(|file| file.contents.len()).ok_or(())
+  }
+
+  /// Change the length of a file. Growing it fills the new space with zeroes.
+  pub fn set_size(&self, handle: LocalHandle, size: usize) -> Result<(), ()> {
+    let open_file = self.get_open_file(handle)?;
+    let mut files = self.files.write();
+    let file = files.get_mut(open_file.file).ok_or(())?;
+    if file.seals & F_SEAL_WRITE != 0 || !file.can_resize(size) {
+      return Err(());
+    }
+    file.contents.resize(size, 0);
+    Ok(())
+  }
+
+  /// Add F_SEAL_* bits to a file, returning every seal it now has. Sealing
+  /// applies to the file itself, so it affects every handle pointing to it.
+  pub fn add_seals(&self, handle: LocalHandle, seals: u32) -> Result<u32, ()> {
+    if seals & !ALL_SEALS != 0 {
+      return Err(());
+    }
+    let open_file = self.get_open_file(handle)?;
+    let mut files = self.files.write();
+    let file = files.get_mut(open_file.file).ok_or(())?;
+    file.seals |= seals;
+    Ok(file.seals)
+  }
+
+  pub fn get_seals(&self, handle: LocalHandle) -> Result<u32, ()> {
+    let open_file = self.get_open_file(handle)?;
+    let files = self.files.read();
+    files.get(open_file.file).map(|file| file.seals).ok_or(())
+  }
+
+  /// Copy out the contents of a file that has been sealed against writing, so
+  /// that they can be mapped into memory. Since the file can't be written
+  /// anymore, the copy always matches it. Unsealed files are refused, since
+  /// a mapping wouldn't see later writes.
+  pub fn sealed_contents(&self, handle: LocalHandle) -> Result<Vec<u8>, ()> {
+    let open_file = self.get_open_file(handle)?;
+    let files = self.files.read();
+    let file = files.get(open_file.file).ok_or(())?;
+    if file.seals & F_SEAL_WRITE == 0 {
+      return Err(());
+    }
+    Ok(file.contents.clone())
+  }
+
+  /// The number of files that are still alive
+  pub fn file_count(&self) -> usize {
+    self.files.read().iter().count()
+  }
+
+  /// The total size of all live files, in bytes
+  pub fn allocated_bytes(&self) -> usize {
+    self.files.read().iter().map(|file| file.contents.capacity()).sum()
+  }
+}
+
+pub struct TmpFileSystem {
+  files: Arc<AnonymousFiles>,
+}
+
+impl TmpFileSystem {
+  pub fn new(files: &Arc<AnonymousFiles>) -> TmpFileSystem {
+    TmpFileSystem {
+      files: Arc::clone(files),
+    }
+  }
+}
+
+impl KernelFileSystem for TmpFileSystem {
+  /// Anonymous files have no paths, so nothing can be opened
+  fn open(&self, _path: &str) -> Result<LocalHandle, ()> {
+    Err(())
+  }
+
+  fn read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()> {
+    self.files.read(handle, buffer)
+  }
+
+  fn write(&self, handle: LocalHandle, buffer: &[u8]) -> Result<usize, ()> {
+    self.files.write(handle, buffer)
+  }
+
+  fn close(&self, handle: LocalHandle) -> Result<(), ()> {
+    self.files.close(handle)
+  }
+
+  fn reopen(&self, handle: LocalHandle, _id: ProcessID) -> Result<LocalHandle, ()> {
+    self.files.reopen(handle, false)
+  }
+
+  fn reopen_shared(&self, handle: LocalHandle, _id: ProcessID) -> Result<LocalHandle, ()> {
+    self.files.reopen(handle, true)
+  }
+
+  fn seek(&self, handle: LocalHandle, offset: SeekMethod) -> Result<usize, ()> {
+    self.files.seek(handle, offset)
+  }
+
+  fn open_dir(&self, _path: &str) -> Result<LocalHandle, ()> {
+    Err(())
+  }
+
+  fn read_dir(&self, _handle: LocalHandle, _info: &mut DirEntryInfo) -> Result<bool, ()> {
+    Err(())
+  }
+
+  fn stat(&self, handle: LocalHandle, status: &mut FileStatus) -> Result<(), ()> {
+    status.byte_size = self.files.get_size(handle)?;
+    Ok(())
+  }
+
+  fn copy_file_range(&self, source: LocalHandle, dest: LocalHandle, count: usize) -> Result<Option<usize>, ()> {
+    self.files.copy_range(source, dest, count).map(Some)
+  }
+
+  fn file_identity(&self, handle: LocalHandle) -> Result<usize, ()> {
+    self.files.get_file_index(handle)
+  }
+
+  fn set_size(&self, handle: LocalHandle, size: usize) -> Result<(), ()> {
+    self.files.set_size(handle, size)
+  }
+
+  fn add_seals(&self, handle: LocalHandle, seals: u32) -> Result<u32, ()> {
+    self.files.add_seals(handle, seals)
+  }
+
+  fn get_seals(&self, handle: LocalHandle) -> Result<u32, ()> {
+    self.files.get_seals(handle)
+  }
+
+  fn sealed_contents(&self, handle: LocalHandle) -> Result<Vec<u8>, ()> {
+    self.files.sealed_contents(handle)
+  }
+}
+
+static TMP_FILES: RwLock<Option<Arc<AnonymousFiles>>> = RwLock::new(None);
+
+pub fn create_fs() -> Box<FileSystemType> {
+  let files = Arc::new(AnonymousFiles::new());
+  let tmp_fs = Box::new(TmpFileSystem::new(&files));
+  *TMP_FILES.write() = Some(files);
+  tmp_fs
+}
+
+/// Create a file on the TMP drive. Fails if the drive hasn't been created yet.
+pub fn create_anonymous_file() -> Result<LocalHandle, ()> {
+  let files = TMP_FILES.read().clone().ok_or(())?;
+  Ok(files.create())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{AnonymousFiles, KernelFileSystem, SeekMethod, TmpFileSystem, MAX_FILE_SIZE};
+  use alloc::sync::Arc;
+  use alloc::vec::Vec;
+  use crate::fs::filesystem::transfer_through_buffer;
+  use crate::task::id::ProcessID;
+  use syscall::files::FileStatus;
+  use syscall::flags::{F_SEAL_GROW, F_SEAL_SHRINK, F_SEAL_WRITE};
+
+  #[test]
+  fn read_and_write() {
+    let files = AnonymousFiles::new();
+    let handle = files.create();
+    assert_eq!(files.write(handle, b"scratch"), Ok(7));
+    files.seek(handle, SeekMethod::Absolute(10)).unwrap();
+    assert_eq!(files.write(handle, b"!"), Ok(1));
+    assert_eq!(files.get_size(handle), Ok(11));
+    files.seek(handle, SeekMethod::Absolute(0)).unwrap();
+    let mut buffer = [0xff; 16];
+    assert_eq!(files.read(handle, &mut buffer), Ok(11));
+    assert_eq!(&buffer[..11], b"scratch\0\0\0!");
+    assert_eq!(files.read(handle, &mut buffer), Ok(0));
+  }
+
+  #[test]
+  fn freed_after_last_close() {
+    let files = Arc::new(AnonymousFiles::new());
+    let fs = TmpFileSystem::new(&files);
+    let handle = files.create();
+    fs.write(handle, &[0xaa; 1000]).unwrap();
+    assert!(fs.open("").is_err());
+    assert_eq!(files.file_count(), 1);
+    assert!(files.allocated_bytes() >= 1000);
+
+    // A forked child holds its own reference, which keeps the file alive
+    // after the parent closes its handle
+    let child_handle = fs.reopen_shared(handle, ProcessID::new(2)).unwrap();
+    fs.close(handle).unwrap();
+    assert_eq!(files.file_count(), 1);
+    let mut status = FileStatus::empty();
+    fs.stat(child_handle, &mut status).unwrap();
+    assert_eq!({ status.byte_size }, 1000);
+    fs.seek(child_handle, SeekMethod::Absolute(998)).unwrap();
+    let mut buffer = [0; 4];
+    assert_eq!(fs.read(child_handle, &mut buffer), Ok(2));
+    assert_eq!(&buffer[..2], &[0xaa, 0xaa]);
+
+    fs.close(child_handle).unwrap();
+    assert_eq!(files.file_count(), 0);
+    assert_eq!(files.allocated_bytes(), 0);
+    assert!(fs.close(child_handle).is_err());
+
+    // Files are independent of each other
+    let first = files.create();
+    let second = files.create();
+    files.write(first, b"one").unwrap();
+    assert_eq!(files.get_size(second), Ok(0));
+    files.close(first).unwrap();
+    assert_eq!(files.file_count(), 1);
+  }
+
+  #[test]
+  fn copy_between_files() {
+    let files = Arc::new(AnonymousFiles::new());
+    let fs = TmpFileSystem::new(&files);
+    let source = files.create();
+    let dest = files.create();
+    let data: Vec<u8> = (0..6000).map(|i| i as u8).collect();
+    fs.write(source, &data).unwrap();
+    fs.seek(source, SeekMethod::Absolute(1000)).unwrap();
+    assert_eq!(fs.copy_file_range(source, dest, 3000), Ok(Some(3000)));
+    // Asking for more than remains copies the rest
+    assert_eq!(fs.copy_file_range(source, dest, 10000), Ok(Some(2000)));
+    assert_eq!(fs.copy_file_range(source, dest, 10), Ok(Some(0)));
+    let mut copied = [0; 6000];
+    fs.seek(dest, SeekMethod::Absolute(0)).unwrap();
+    assert_eq!(fs.read(dest, &mut copied), Ok(5000));
+    assert_eq!(&copied[..5000], &data[1000..]);
+
+    // Copying within a single file
+    fs.seek(source, SeekMethod::Absolute(0)).unwrap();
+    let copy = fs.reopen(source, crate::task::id::ProcessID::new(1)).unwrap();
+    fs.seek(copy, SeekMethod::Absolute(5990)).unwrap();
+    assert_eq!(fs.copy_file_range(source, copy, 20), Ok(Some(20)));
+    assert_eq!(files.get_size(source), Ok(6010));
+
+    // Files on another drive are copied through a buffer, in chunks
+    let other_files = Arc::new(AnonymousFiles::new());
+    let other_fs = TmpFileSystem::new(&other_files);
+    let other = other_files.create();
+    fs.seek(dest, SeekMethod::Absolute(0)).unwrap();
+    assert_eq!(transfer_through_buffer(&fs, dest, &other_fs, other, 8000), Ok(5000));
+    assert_eq!(transfer_through_buffer(&fs, dest, &other_fs, other, 8000), Ok(0));
+    other_fs.seek(other, SeekMethod::Absolute(0)).unwrap();
+    assert_eq!(other_fs.read(other, &mut copied), Ok(5000));
+    assert_eq!(&copied[..5000], &data[1000..]);
+  }
+
+  #[test]
+  fn sealed_file() {
+    let files = Arc::new(AnonymousFiles::new());
+    let fs = TmpFileSystem::new(&files);
+    let handle = files.create();
+    fs.write(handle, b"config").unwrap();
+    // Nothing can be mapped until writing is sealed
+    assert!(fs.sealed_contents(handle).is_err());
+    assert_eq!(fs.add_seals(handle, F_SEAL_GROW | F_SEAL_SHRINK), Ok(F_SEAL_GROW | F_SEAL_SHRINK));
+    // Writes within the current length still work, but the size is fixed
+    fs.seek(handle, SeekMethod::Absolute(0)).unwrap();
+    assert_eq!(fs.write(handle, b"C"), Ok(1));
+    assert!(fs.write(handle, b"onfig!").is_err());
+    assert!(fs.set_size(handle, 2).is_err());
+    assert!(fs.set_size(handle, 20).is_err());
+    assert_eq!(fs.set_size(handle, 6), Ok(()));
+
+    assert_eq!(fs.add_seals(handle, F_SEAL_WRITE), Ok(F_SEAL_GROW | F_SEAL_SHRINK | F_SEAL_WRITE));
+    fs.seek(handle, SeekMethod::Absolute(0)).unwrap();
+    assert!(fs.write(handle, b"c").is_err());
+    assert!(fs.add_seals(handle, 0x100).is_err());
+
+    // The handle is passed to another process, which sees the same seals and
+    // can map the contents, but can't write them either
+    let received = fs.reopen(handle, ProcessID::new(7)).unwrap();
+    fs.close(handle).unwrap();
+    assert_eq!(fs.get_seals(received), Ok(F_SEAL_GROW | F_SEAL_SHRINK | F_SEAL_WRITE));
+    assert_eq!(fs.sealed_contents(received).unwrap(), b"Config");
+    assert!(fs.write(received, b"c").is_err());
+    let other = files.create();
+    fs.write(other, b"c").unwrap();
+    fs.seek(other, SeekMethod::Absolute(0)).unwrap();
+    assert!(fs.copy_file_range(other, received, 1).is_err());
+  }
+
+  #[test]
+  fn size_is_capped() {
+    let files = Arc::new(AnonymousFiles::new());
+    let fs = TmpFileSystem::new(&files);
+    let handle = files.create();
+    // Seeking far past the limit doesn't allocate anything by itself, and a
+    // write there accepts nothing, which is reported as a full drive
+    fs.seek(handle, SeekMethod::Absolute(0x7fffffff)).unwrap();
+    assert_eq!(fs.write(handle, b"data"), Ok(0));
+    assert_eq!(files.get_size(handle), Ok(0));
+    // A write that crosses the limit is cut short there
+    fs.seek(handle, SeekMethod::Absolute(MAX_FILE_SIZE - 2)).unwrap();
+    assert_eq!(fs.write(handle, b"data"), Ok(2));
+    assert_eq!(files.get_size(handle), Ok(MAX_FILE_SIZE));
+    assert_eq!(fs.write(handle, b"data"), Ok(0));
+    assert!(fs.set_size(handle, MAX_FILE_SIZE + 1).is_err());
+
+    let other = files.create();
+    fs.seek(handle, SeekMethod::Absolute(0)).unwrap();
+    fs.seek(other, SeekMethod::Absolute(MAX_FILE_SIZE - 8)).unwrap();
+    assert_eq!(fs.copy_file_range(handle, other, 16), Ok(Some(8)));
+    assert_eq!(files.get_size(other), Ok(MAX_FILE_SIZE));
+  }
+
+  #[test]
+  fn exec_keeps_files_unless_closed_on_exec() {
+    use crate::fs::drive::DriveID;
+    use crate::task::process::Process;
+    use syscall::flags::{FD_CLOEXEC, FD_KEEPEXEC};
+
+    let files = Arc::new(AnonymousFiles::new());
+    let fs = TmpFileSystem::new(&files);
+    let drive = DriveID::new(4);
+    let mut process = Process::initial(0);
+    let kept_local = files.create();
+    let closed_local = files.create();
+    fs.write(kept_local, b"kept").unwrap();
+    fs.write(closed_local, b"closed").unwrap();
+    let kept = process.open_file(drive, kept_local).unwrap();
+    let closed = process.open_file(drive, closed_local).unwrap();
+    process.set_descriptor_flags(kept, FD_KEEPEXEC);
+    process.set_descriptor_flags(closed, FD_CLOEXEC | FD_KEEPEXEC);
+
+    // The files dropped by exec are closed in their drive, like exec does
+    for file in process.prepare_for_exec() {
+      fs.close(file.local_handle).unwrap();
+    }
+    assert!(process.get_open_file_info(closed).is_none());
+    assert_eq!(files.file_count(), 1);
+
+    let local = process.get_open_file_info(kept).unwrap().local_handle;
+    fs.seek(local, SeekMethod::Absolute(0)).unwrap();
+    let mut buffer = [0; 8];
+    assert_eq!(fs.read(local, &mut buffer), Ok(4));
+    assert_eq!(&buffer[..4], b"kept");
+  }
+}
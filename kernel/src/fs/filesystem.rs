@@ -34,11 +34,14 @@
 //! same approach taken by Filesystem in Userspace (FUSE) on Unix systems.
 
 use alloc::boxed::Box;
+use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use crate::files::cursor::SeekMethod;
 use crate::files::handle::LocalHandle;
 use crate::task::id::ProcessID;
 use syscall::files::{DirEntryInfo, FileStatus};
+use syscall::flags::{F_OK, R_OK, W_OK};
 
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub enum FileSystemCategory {
@@ -47,6 +50,52 @@ pub enum FileSystemCategory {
   Userspace,
 }
 
+impl FileSystemCategory {
+  /// The value used to describe this category to userspace
+  pub fn as_u8(&self) -> u8 {
+    match self {
+      FileSystemCategory::KernelSync => syscall::files::DRIVE_CATEGORY_KERNEL_SYNC,
+      FileSystemCategory::KernelAsync => syscall::files::DRIVE_CATEGORY_KERNEL_ASYNC,
+      FileSystemCategory::Userspace => syscall::files::DRIVE_CATEGORY_USERSPACE,
+    }
+  }
+}
+
+/// What a program is allowed to do with an existing file or directory, as
+/// reported by `KernelFileSystem::access`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FileAccess {
+  pub readable: bool,
+  pub writable: bool,
+}
+
+impl FileAccess {
+  pub const fn read_only() -> FileAccess {
+    FileAccess {
+      readable: true,
+      writable: false,
+    }
+  }
+
+  pub const fn read_write() -> FileAccess {
+    FileAccess {
+      readable: true,
+      writable: true,
+    }
+  }
+
+  /// Check a combination of R_OK and W_OK bits against this access. F_OK only
+  /// asks whether the file exists, which is always true by this point.
+  /// Unknown bits are rejected.
+  pub fn permits(&self, mode: u32) -> Result<bool, ()> {
+    if mode & !(F_OK | R_OK | W_OK) != 0 {
+      return Err(());
+    }
+    let denied = (mode & R_OK != 0 && !self.readable) || (mode & W_OK != 0 && !self.writable);
+    Ok(!denied)
+  }
+}
+
 /// All filesystems compiled into the kernel need to implement this trait to
 /// support the standard set of file operations.
 pub trait KernelFileSystem {
@@ -57,20 +106,60 @@ pub trait KernelFileSystem {
   /// file depend upon this handle.
   fn open(&self, path: &str) -> Result<LocalHandle, ()>;
 
+  /// Create a new, empty file and open it. If the file already exists, it is
+  /// opened as-is. Read-only filesystems can rely on the default
+  /// implementation, which always fails.
+  fn create(&self, path: &str) -> Result<LocalHandle, ()> {
+    Err(())
+  }
+
+  /// Create a new, empty file and open it, but only if nothing exists at the
+  /// path yet. If something does, it resolves with `None`. The check and the
+  /// creation must happen under the same lock, so that when two processes
+  /// race to create the same file, exactly one of them gets a handle.
+  /// Read-only filesystems can rely on the default implementation, which
+  /// always fails.
+  fn create_new(&self, path: &str) -> Result<Option<LocalHandle>, ()> {
+    Err(())
+  }
+
   /// Copy bytes from the file to a local buffer. On success, it will return the
   /// number of bytes copied.
   fn read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, ()>;
 
   /// Copy bytes from a local buffer into a file. On success, it will return the
-  /// number of bytes copied.
+  /// number of bytes copied. Copying none of a non-empty buffer means the drive
+  /// is out of space.
   fn write(&self, handle: LocalHandle, buffer: &[u8]) -> Result<usize, ()>;
 
+  /// Non-blocking variant of `read`, used when a handle has been opened with
+  /// O_NONBLOCK. If the read would need to wait for more data, it resolves
+  /// with `None` instead of blocking. Filesystems whose reads always complete
+  /// immediately can rely on the default implementation.
+  fn read_nonblocking(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<Option<usize>, ()> {
+    self.read(handle, buffer).map(|bytes| Some(bytes))
+  }
+
+  /// Non-blocking variant of `write`. If there is no room to accept any data,
+  /// it resolves with `None` instead of blocking.
+  fn write_nonblocking(&self, handle: LocalHandle, buffer: &[u8]) -> Result<Option<usize>, ()> {
+    self.write(handle, buffer).map(|bytes| Some(bytes))
+  }
+
   /// Close out a reference to a file. The handle will no longer be usable.
   fn close(&self, handle: LocalHandle) -> Result<(), ()>;
 
   /// Create a duplicate reference to an existing handle.
   fn reopen(&self, handle: LocalHandle, id: ProcessID) -> Result<LocalHandle, ()>;
 
+  /// Create a duplicate reference for a forked child. Unlike `reopen`, the
+  /// new handle shares the original's cursor, so reads and seeks through one
+  /// are seen by the other. Filesystems without per-handle cursors can rely
+  /// on the default implementation.
+  fn reopen_shared(&self, handle: LocalHandle, id: ProcessID) -> Result<LocalHandle, ()> {
+    self.reopen(handle, id)
+  }
+
   /// Update the cursor that determines the starting point for reads and writes.
   /// On success, it returns the new cursor location.
   fn seek(&self, handle: LocalHandle, offset: SeekMethod) -> Result<usize, ()>;
@@ -80,6 +169,17 @@ pub trait KernelFileSystem {
   /// through the entries.
   fn open_dir(&self, path: &str) -> Result<LocalHandle, ()>;
 
+  /// Find where an open directory currently lives, as a path from the root
+  /// of the drive. The path is looked up from the handle rather than
+  /// remembered from when it was opened, so it follows the directory through
+  /// renames. Resolves with `None` if the directory has been removed since it
+  /// was opened, and fails if the handle isn't a directory. Filesystems
+  /// without directories can rely on the default implementation, which always
+  /// fails.
+  fn directory_path(&self, _handle: LocalHandle) -> Result<Option<String>, ()> {
+    Err(())
+  }
+
   /// Read information about the next entry in an open directory. Fields are
   /// copied into a DirEntryInfo struct. If the entry was copied, the method
   /// resolves with `true`. If there are no more entries, the method resolves
@@ -93,17 +193,161 @@ pub trait KernelFileSystem {
     Err(())
   }
 
+  /// Report whether an open handle is backed by a terminal. Only devices can
+  /// be terminals, so most filesystems never need to override this.
+  fn is_terminal(&self, handle: LocalHandle) -> Result<bool, ()> {
+    Ok(false)
+  }
+
+  /// Report whether a read from the handle would return right away, without
+  /// performing it. This never blocks, so the kernel can check a whole set of
+  /// handles at once to implement poll / select. A handle at end-of-file
+  /// counts as readable, since the read completes immediately with zero bytes.
+  /// Regular files never wait, so the default implementation always reports
+  /// them as ready.
+  fn poll_readable(&self, handle: LocalHandle) -> Result<bool, ()> {
+    Ok(true)
+  }
+
+  /// Report whether a write to the handle would accept data right away. A
+  /// write that is guaranteed to fail, like one to a pipe with no readers,
+  /// also counts as ready. Regular files are always writable: a full disk is
+  /// reported by the write itself rather than by waiting.
+  fn poll_writable(&self, handle: LocalHandle) -> Result<bool, ()> {
+    Ok(true)
+  }
+
+  /// Check whether a path exists without opening it, and report how it can be
+  /// accessed. Missing paths return an Err value. Filesystems that can't look
+  /// up paths, like pipes, can rely on the default implementation, which
+  /// reports every path as missing.
+  fn access(&self, path: &str) -> Result<FileAccess, ()> {
+    Err(())
+  }
+
+  /// Create a new, empty directory. Read-only filesystems can rely on the
+  /// default implementation, which always fails.
+  fn mkdir(&self, path: &str) -> Result<(), ()> {
+    Err(())
+  }
+
+  /// Remove an empty directory. Directories that still have contents are
+  /// refused.
+  fn rmdir(&self, path: &str) -> Result<(), ()> {
+    Err(())
+  }
+
+  /// Move an existing file or directory to a new path on the same drive. If
+  /// something already exists at the new path, it is replaced only when
+  /// `replace` is set. Read-only filesystems can rely on the default
+  /// implementation, which always fails.
+  fn rename(&self, old_path: &str, new_path: &str, replace: bool) -> Result<(), ()> {
+    Err(())
+  }
+
+  /// Remove a file. Handles that already have the file open can keep using
+  /// it; the file's storage is released once the last of them is closed.
+  /// Read-only filesystems can rely on the default implementation, which
+  /// always fails.
+  fn unlink(&self, path: &str) -> Result<(), ()> {
+    Err(())
+  }
+
   /// Fetch status information about an open file. If successful, the data will
   /// be copied into a FileStatus struct.
   fn stat(&self, handle: LocalHandle, status: &mut FileStatus) -> Result<(), ()>;
+
+  /// Copy up to `count` bytes between two open files on this filesystem,
+  /// starting at each handle's cursor and advancing both, without going
+  /// through an intermediate buffer. Resolves with `None` if the filesystem
+  /// has no faster way than reading and writing, which is the default.
+  fn copy_file_range(&self, source: LocalHandle, dest: LocalHandle, count: usize) -> Result<Option<usize>, ()> {
+    Ok(None)
+  }
+
+  /// Identify the file behind an open handle, for advisory locking. Every
+  /// handle to the same file must produce the same value, no matter how it
+  /// was opened. Filesystems that can't identify their files rely on the
+  /// default implementation, and their files can't be locked.
+  fn file_identity(&self, handle: LocalHandle) -> Result<usize, ()> {
+    Err(())
+  }
+
+  /// Change the length of an open file, truncating it or filling the new
+  /// space with zeroes. Filesystems that can't resize files rely on the
+  /// default implementation.
+  fn set_size(&self, handle: LocalHandle, size: usize) -> Result<(), ()> {
+    Err(())
+  }
+
+  /// Add F_SEAL_* restrictions to an open file, returning every seal it now
+  /// has. Only filesystems that support sealing need to implement this, or
+  /// the two methods below.
+  fn add_seals(&self, handle: LocalHandle, seals: u32) -> Result<u32, ()> {
+    Err(())
+  }
+
+  fn get_seals(&self, handle: LocalHandle) -> Result<u32, ()> {
+    Err(())
+  }
+
+  /// Copy out the contents of a file that has been sealed against writing,
+  /// so that they can be mapped read-only into a process
+  fn sealed_contents(&self, handle: LocalHandle) -> Result<Vec<u8>, ()> {
+    Err(())
+  }
+
+  /// Flush any buffered writes to the underlying device. This is called before
+  /// a drive is unmounted. Filesystems that don't buffer data can rely on the
+  /// default implementation.
+  fn sync(&self) -> Result<(), ()> {
+    Ok(())
+  }
 }
 
 pub type FileSystemType = dyn KernelFileSystem + Send + Sync;
 
+/// Size of the kernel buffer used to move data between files that can't be
+/// copied directly
+pub const TRANSFER_BUFFER_SIZE: usize = 4096;
+
+/// Move up to `count` bytes from one open file to another by reading into a
+/// kernel buffer and writing it back out. The transfer stops early once the
+/// source runs out of data, or the destination stops accepting it. If the
+/// destination takes only part of a chunk, the source is moved back so the
+/// rest can be read again. Returns the number of bytes transferred.
+pub fn transfer_through_buffer(
+  source: &FileSystemType,
+  source_handle: LocalHandle,
+  dest: &FileSystemType,
+  dest_handle: LocalHandle,
+  count: usize,
+) -> Result<usize, ()> {
+  let mut buffer = Vec::new();
+  buffer.resize(TRANSFER_BUFFER_SIZE.min(count), 0);
+  let mut transferred = 0;
+  while transferred < count {
+    let chunk = buffer.len().min(count - transferred);
+    let read = source.read(source_handle, &mut buffer[..chunk])?;
+    if read == 0 {
+      break;
+    }
+    let written = dest.write(dest_handle, &buffer[..read])?;
+    transferred += written;
+    if written < read {
+      let _ = source.seek(source_handle, SeekMethod::Relative(written as isize - read as isize));
+      break;
+    }
+  }
+  Ok(transferred)
+}
+
 pub struct FileSystemInstance {
   pub category: FileSystemCategory,
   pub name: Box<str>,
   pub instance: Arc<Box<FileSystemType>>,
+  /// Read-only drives refuse anything that would change their contents
+  pub read_only: bool,
 }
 
 impl FileSystemInstance {
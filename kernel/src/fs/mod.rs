@@ -15,4 +15,48 @@ pub fn init_system_drives(initfs_location: VirtualAddress, initfs_size: usize) {
   DRIVES.mount_drive("INIT", FileSystemCategory::KernelSync, Arc::new(Box::new(initfs)));
   let devfs = drivers::devfs::DevFileSystem::new();
   DRIVES.mount_drive("DEV", FileSystemCategory::KernelAsync, Arc::new(Box::new(devfs)));
+  DRIVES.mount_drive("PIPE", FileSystemCategory::KernelSync, Arc::new(crate::pipes::create_fs()));
+  DRIVES.mount_drive("TMP", FileSystemCategory::KernelSync, Arc::new(drivers::tmpfs::create_fs()));
+  DRIVES.mount_drive("TIMER", FileSystemCategory::KernelSync, Arc::new(drivers::timerfs::create_fs()));
+}
+
+/// Mount the disk in the primary floppy drive as A:, if it contains a valid
+/// FAT12 filesystem. This needs to run after devices have been initialized.
+#[cfg(not(test))]
+pub fn mount_boot_drive() {
+  let device = match crate::devices::get_device_number_by_name("FD1") {
+    Some(number) => number,
+    None => return,
+  };
+  let driver = match crate::devices::get_driver_for_device(device) {
+    Some(driver) => driver,
+    None => return,
+  };
+  let read_only = is_write_protected(&driver);
+  match drivers::fat12::Fat12FileSystem::mount(driver) {
+    Ok(fat_fs) => {
+      let options = drive::MountOptions { read_only };
+      DRIVES.mount_drive_with_options("A", FileSystemCategory::KernelAsync, Arc::new(Box::new(fat_fs)), options);
+      if read_only {
+        crate::klog!("Mounted write-protected floppy disk as A: (read-only)\n");
+      } else {
+        crate::klog!("Mounted floppy disk as A:\n");
+      }
+    },
+    Err(drivers::fat12::errors::FatError::NoDisk) => (),
+    Err(err) => crate::klog!(Warn, FileSystem; "Floppy disk is not a FAT12 volume: {:?}\n", err),
+  }
+}
+
+/// Ask a block device whether its media is write-protected. Devices that
+/// can't tell are treated as writable.
+#[cfg(not(test))]
+fn is_write_protected(driver: &Arc<Box<crate::devices::driver::DeviceDriverType>>) -> bool {
+  let handle = match driver.open() {
+    Ok(handle) => handle,
+    Err(_) => return false,
+  };
+  let result = driver.ioctl(handle, crate::devices::block::IOCTL_GET_WRITE_PROTECT, 0);
+  let _ = driver.close(handle);
+  result.map_or(false, |protected| protected != 0)
 }
@@ -0,0 +1,148 @@
+//! Advanced Power Management lets the BIOS turn the machine off. Without ACPI
+//! support, it is the only way to power off real hardware. APM's real-mode
+//! interface is reached through INT 15h, which the VGA driver runs in VM86
+//! mode on the kernel's behalf.
+//! Emulators without APM can still be powered off through the port that their
+//! power management device listens on: 0x604 on QEMU, or 0xB004 on Bochs and
+//! older versions of QEMU. If nothing works, the CPU is halted and the
+//! machine has to be switched off by hand.
+
+const APM_INSTALLATION_CHECK: u16 = 0x5300;
+const APM_CONNECT_REAL_MODE: u16 = 0x5301;
+const APM_SET_POWER_STATE: u16 = 0x5307;
+const APM_DRIVER_VERSION: u16 = 0x530e;
+
+/// Device ID of the APM BIOS itself
+const DEVICE_BIOS: u16 = 0x0000;
+/// Device ID covering every device the BIOS manages
+const DEVICE_ALL: u16 = 0x0001;
+const POWER_STATE_OFF: u16 = 0x0003;
+/// The kernel implements the APM 1.2 driver interface
+const DRIVER_VERSION: u16 = 0x0102;
+
+/// "PM", returned in BX by the installation check
+const APM_SIGNATURE: u16 = 0x504d;
+/// Connecting fails with this code if the interface is already connected,
+/// like when the boot loader connected it first
+const ERROR_ALREADY_CONNECTED: u8 = 0x02;
+
+const CARRY_FLAG: u16 = 1;
+
+/// Writing this value to one of the emulator ports powers off the machine
+const EMULATOR_POWER_OFF: u16 = 0x2000;
+const EMULATOR_POWER_PORTS: [u16; 2] = [0x604, 0xb004];
+
+/// The registers a BIOS call returned with
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct BiosResult {
+  pub ax: u16,
+  pub bx: u16,
+  pub flags: u16,
+}
+
+impl BiosResult {
+  /// APM calls set the carry flag when they fail
+  pub fn failed(&self) -> bool {
+    self.flags & CARRY_FLAG != 0
+  }
+
+  /// Failed calls leave an error code in AH
+  pub fn error_code(&self) -> u8 {
+    (self.ax >> 8) as u8
+  }
+}
+
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct ApmVersion {
+  pub major: u8,
+  pub minor: u8,
+}
+
+/// Determine which version of APM the BIOS supports, if any
+pub fn parse_installation_check(result: BiosResult) -> Option<ApmVersion> {
+  if result.failed() || result.bx != APM_SIGNATURE {
+    return None;
+  }
+  Some(ApmVersion {
+    major: (result.ax >> 8) as u8,
+    minor: result.ax as u8,
+  })
+}
+
+/// Determine whether the real-mode interface can be used after a connect
+pub fn is_connected(result: BiosResult) -> bool {
+  !result.failed() || result.error_code() == ERROR_ALREADY_CONNECTED
+}
+
+#[cfg(not(test))]
+fn call_apm(ax: u16, bx: u16, cx: u16) -> Result<BiosResult, ()> {
+  let (ax, bx, flags) = super::vga::driver::call_int_15(ax, bx, cx).ok_or(())?;
+  Ok(BiosResult { ax, bx, flags })
+}
+
+/// Ask the BIOS to turn the machine off. If this returns, APM is missing or
+/// refused to power off.
+#[cfg(not(test))]
+fn apm_power_off() -> Result<(), ()> {
+  let version = parse_installation_check(call_apm(APM_INSTALLATION_CHECK, DEVICE_BIOS, 0)?).ok_or(())?;
+  crate::klog!(Info, Driver; "APM {}.{} BIOS found\n", version.major, version.minor);
+  if !is_connected(call_apm(APM_CONNECT_REAL_MODE, DEVICE_BIOS, 0)?) {
+    return Err(());
+  }
+  // Version negotiation was added in APM 1.1. Older BIOSes keep using the
+  // 1.0 interface.
+  if version >= (ApmVersion { major: 1, minor: 1 }) {
+    call_apm(APM_DRIVER_VERSION, DEVICE_BIOS, DRIVER_VERSION)?;
+  }
+  call_apm(APM_SET_POWER_STATE, DEVICE_ALL, POWER_STATE_OFF)?;
+  Err(())
+}
+
+/// Power off the machine, trying APM first, then the emulator ports. If
+/// neither works, the CPU is halted.
+#[cfg(not(test))]
+pub fn power_off() -> ! {
+  if apm_power_off().is_err() {
+    crate::klog!(Warn, Driver; "APM power-off unavailable, trying emulator ports\n");
+  }
+  for port in EMULATOR_POWER_PORTS.iter() {
+    unsafe {
+      crate::x86::io::outw(*port, EMULATOR_POWER_OFF);
+    }
+  }
+  crate::kprintln!("It is now safe to turn off your computer");
+  loop {
+    unsafe {
+      asm!(
+        "cli
+        hlt"
+      );
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{is_connected, parse_installation_check, ApmVersion, BiosResult};
+
+  #[test]
+  fn installation_check() {
+    let found = BiosResult { ax: 0x0102, bx: 0x504d, flags: 0x0002 };
+    assert_eq!(parse_installation_check(found), Some(ApmVersion { major: 1, minor: 2 }));
+    assert!(parse_installation_check(found).unwrap() >= ApmVersion { major: 1, minor: 1 });
+    // No APM BIOS: the carry flag is set, with error 86h in AH
+    let missing = BiosResult { ax: 0x8600, bx: 0, flags: 0x0003 };
+    assert_eq!(parse_installation_check(missing), None);
+    // A driver that never ran leaves zeroed registers behind
+    assert_eq!(parse_installation_check(BiosResult { ax: 0, bx: 0, flags: 0 }), None);
+  }
+
+  #[test]
+  fn connecting() {
+    assert!(is_connected(BiosResult { ax: 0x5301, bx: 0, flags: 0 }));
+    // Connected earlier, by the boot loader
+    assert!(is_connected(BiosResult { ax: 0x0200, bx: 0, flags: 1 }));
+    // Unrecognized device ID
+    assert!(!is_connected(BiosResult { ax: 0x0900, bx: 0, flags: 1 }));
+  }
+}
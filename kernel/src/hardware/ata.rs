@@ -0,0 +1,468 @@
+//! An interface to ATA hard disks attached to an IDE channel, using PIO mode.
+//!
+//! Each channel has a block of eight IO registers, plus a control register.
+//! Commands are sent by programming the sector address and count, and writing
+//! a command byte. The drive signals that it is working by setting BSY in the
+//! status register, and once data is ready to be transferred it sets DRQ. In
+//! PIO mode the driver polls these bits, copying each sector through the data
+//! register one 16-bit word at a time.
+//!
+//! A channel with no drive attached usually reads back 0xff from every
+//! register, and a drive that has failed may never clear BSY. All polling is
+//! bounded, so that probing for a missing drive cannot hang the kernel.
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// LBA28 addressing can reach this many sectors
+pub const MAX_LBA28_SECTORS: u32 = 1 << 28;
+
+/// Number of status reads before giving up on a drive
+const POLL_LIMIT: usize = 100000;
+
+pub const PRIMARY_IO_BASE: u16 = 0x1f0;
+pub const PRIMARY_CONTROL: u16 = 0x3f6;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AtaError {
+  /// Nothing responded on the channel
+  NoDevice,
+  /// The device is not an ATA hard disk, like an ATAPI CD drive
+  NotAta,
+  /// The drive never finished a command
+  Timeout,
+  /// The drive reported an error, with the contents of its error register
+  DeviceError(u8),
+  /// The sector is beyond the end of the disk, or the buffer is too small
+  OutOfRange,
+}
+
+/// Offsets of registers from the IO base of a channel
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u16)]
+pub enum Register {
+  Data = 0,
+  Error = 1,
+  SectorCount = 2,
+  LbaLow = 3,
+  LbaMid = 4,
+  LbaHigh = 5,
+  DriveHead = 6,
+  /// Reads return the status, writes issue a command
+  StatusCommand = 7,
+}
+
+pub const STATUS_ERR: u8 = 0x01;
+pub const STATUS_DRQ: u8 = 0x08;
+pub const STATUS_DF: u8 = 0x20;
+pub const STATUS_BSY: u8 = 0x80;
+
+pub const COMMAND_READ_SECTORS: u8 = 0x20;
+pub const COMMAND_WRITE_SECTORS: u8 = 0x30;
+pub const COMMAND_CACHE_FLUSH: u8 = 0xe7;
+pub const COMMAND_IDENTIFY: u8 = 0xec;
+
+/// Access to the registers of an IDE channel. Real hardware is reached through
+/// IO ports; tests substitute a simulated drive.
+pub trait AtaBus {
+  fn read_register(&self, register: Register) -> u8;
+
+  fn write_register(&self, register: Register, value: u8);
+
+  fn read_data(&self) -> u16;
+
+  fn write_data(&self, value: u16);
+
+  /// Read the alternate status register, which does not acknowledge a
+  /// pending interrupt
+  fn read_alt_status(&self) -> u8;
+}
+
+/// IDE channel accessed through IO ports
+#[cfg(not(test))]
+pub struct PortBus {
+  io_base: u16,
+  control: u16,
+}
+
+#[cfg(not(test))]
+impl PortBus {
+  pub const fn new(io_base: u16, control: u16) -> PortBus {
+    PortBus {
+      io_base,
+      control,
+    }
+  }
+}
+
+#[cfg(not(test))]
+impl AtaBus for PortBus {
+  fn read_register(&self, register: Register) -> u8 {
+    unsafe { crate::x86::io::inb(self.io_base + register as u16) }
+  }
+
+  fn write_register(&self, register: Register, value: u8) {
+    unsafe { crate::x86::io::outb(self.io_base + register as u16, value) }
+  }
+
+  fn read_data(&self) -> u16 {
+    unsafe { crate::x86::io::inw(self.io_base) }
+  }
+
+  fn write_data(&self, value: u16) {
+    unsafe { crate::x86::io::outw(self.io_base, value) }
+  }
+
+  fn read_alt_status(&self) -> u8 {
+    unsafe { crate::x86::io::inb(self.control) }
+  }
+}
+
+/// Information reported by the IDENTIFY command
+#[derive(Copy, Clone)]
+pub struct DriveInfo {
+  /// Total number of sectors addressable with LBA28
+  pub sectors: u32,
+  pub cylinders: u16,
+  pub heads: u16,
+  pub sectors_per_track: u16,
+  /// Model name, padded with spaces
+  pub model: [u8; 40],
+}
+
+impl DriveInfo {
+  pub fn from_identify(words: &[u16; 256]) -> DriveInfo {
+    let mut model = [0x20; 40];
+    // Each word of the model string stores its characters in swapped order
+    for i in 0..20 {
+      let word = words[27 + i];
+      model[i * 2] = (word >> 8) as u8;
+      model[i * 2 + 1] = word as u8;
+    }
+    DriveInfo {
+      sectors: (words[60] as u32) | ((words[61] as u32) << 16),
+      cylinders: words[1],
+      heads: words[3],
+      sectors_per_track: words[6],
+      model,
+    }
+  }
+
+  pub fn byte_size(&self) -> usize {
+    self.sectors as usize * SECTOR_SIZE
+  }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DriveSelect {
+  Master,
+  Slave,
+}
+
+/// A single drive on an IDE channel
+pub struct AtaDrive<B: AtaBus> {
+  bus: B,
+  select: DriveSelect,
+}
+
+impl<B: AtaBus> AtaDrive<B> {
+  pub fn new(bus: B, select: DriveSelect) -> AtaDrive<B> {
+    AtaDrive {
+      bus,
+      select,
+    }
+  }
+
+  /// Select this drive, along with the top four bits of an LBA28 address
+  fn select_drive(&self, lba_high: u8) {
+    let slave_bit = match self.select {
+      DriveSelect::Master => 0,
+      DriveSelect::Slave => 0x10,
+    };
+    self.bus.write_register(Register::DriveHead, 0xe0 | slave_bit | (lba_high & 0x0f));
+    // Give the drive 400ns to respond to the selection
+    for _ in 0..4 {
+      self.bus.read_alt_status();
+    }
+  }
+
+  /// Poll until the drive is no longer busy, returning the final status
+  fn wait_not_busy(&self) -> Result<u8, AtaError> {
+    for _ in 0..POLL_LIMIT {
+      let status = self.bus.read_register(Register::StatusCommand);
+      if status == 0xff {
+        // A floating bus, there is no drive here
+        return Err(AtaError::NoDevice);
+      }
+      if status & STATUS_BSY == 0 {
+        return Ok(status);
+      }
+    }
+    Err(AtaError::Timeout)
+  }
+
+  /// Poll until the drive is ready to transfer a sector of data
+  fn wait_for_data(&self) -> Result<(), AtaError> {
+    for _ in 0..POLL_LIMIT {
+      let status = self.wait_not_busy()?;
+      if status & (STATUS_ERR | STATUS_DF) != 0 {
+        return Err(AtaError::DeviceError(self.bus.read_register(Register::Error)));
+      }
+      if status & STATUS_DRQ != 0 {
+        return Ok(());
+      }
+    }
+    Err(AtaError::Timeout)
+  }
+
+  /// Program the address registers for a single-sector command
+  fn send_command(&self, lba: u32, command: u8) -> Result<(), AtaError> {
+    self.wait_not_busy()?;
+    self.select_drive((lba >> 24) as u8);
+    self.bus.write_register(Register::SectorCount, 1);
+    self.bus.write_register(Register::LbaLow, lba as u8);
+    self.bus.write_register(Register::LbaMid, (lba >> 8) as u8);
+    self.bus.write_register(Register::LbaHigh, (lba >> 16) as u8);
+    self.bus.write_register(Register::StatusCommand, command);
+    Ok(())
+  }
+
+  /// Determine whether a drive is attached, and fetch its size and geometry
+  pub fn identify(&self) -> Result<DriveInfo, AtaError> {
+    self.select_drive(0);
+    self.bus.write_register(Register::SectorCount, 0);
+    self.bus.write_register(Register::LbaLow, 0);
+    self.bus.write_register(Register::LbaMid, 0);
+    self.bus.write_register(Register::LbaHigh, 0);
+    self.bus.write_register(Register::StatusCommand, COMMAND_IDENTIFY);
+    let status = self.bus.read_register(Register::StatusCommand);
+    if status == 0 || status == 0xff {
+      return Err(AtaError::NoDevice);
+    }
+    self.wait_not_busy()?;
+    // ATAPI and SATA devices identify themselves by a signature in the LBA
+    // registers, and abort the command
+    let signature = (
+      self.bus.read_register(Register::LbaMid),
+      self.bus.read_register(Register::LbaHigh),
+    );
+    if signature != (0, 0) {
+      return Err(AtaError::NotAta);
+    }
+    self.wait_for_data()?;
+    let mut words = [0u16; 256];
+    for word in words.iter_mut() {
+      *word = self.bus.read_data();
+    }
+    Ok(DriveInfo::from_identify(&words))
+  }
+
+  pub fn read_sector(&self, lba: u32, buffer: &mut [u8]) -> Result<(), AtaError> {
+    if lba >= MAX_LBA28_SECTORS || buffer.len() < SECTOR_SIZE {
+      return Err(AtaError::OutOfRange);
+    }
+    self.send_command(lba, COMMAND_READ_SECTORS)?;
+    self.wait_for_data()?;
+    for i in 0..(SECTOR_SIZE / 2) {
+      let word = self.bus.read_data();
+      buffer[i * 2] = word as u8;
+      buffer[i * 2 + 1] = (word >> 8) as u8;
+    }
+    Ok(())
+  }
+
+  pub fn write_sector(&self, lba: u32, buffer: &[u8]) -> Result<(), AtaError> {
+    if lba >= MAX_LBA28_SECTORS || buffer.len() < SECTOR_SIZE {
+      return Err(AtaError::OutOfRange);
+    }
+    self.send_command(lba, COMMAND_WRITE_SECTORS)?;
+    self.wait_for_data()?;
+    for i in 0..(SECTOR_SIZE / 2) {
+      let word = (buffer[i * 2] as u16) | ((buffer[i * 2 + 1] as u16) << 8);
+      self.bus.write_data(word);
+    }
+    // Make sure the sector has left the drive's write cache
+    self.bus.write_register(Register::StatusCommand, COMMAND_CACHE_FLUSH);
+    let status = self.wait_not_busy()?;
+    if status & (STATUS_ERR | STATUS_DF) != 0 {
+      return Err(AtaError::DeviceError(self.bus.read_register(Register::Error)));
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::collections::VecDeque;
+  use alloc::vec::Vec;
+  use core::cell::RefCell;
+  use super::{
+    AtaBus, AtaDrive, AtaError, DriveSelect, Register, COMMAND_IDENTIFY, COMMAND_READ_SECTORS,
+    COMMAND_WRITE_SECTORS, MAX_LBA28_SECTORS, SECTOR_SIZE, STATUS_BSY, STATUS_DRQ,
+  };
+
+  /// Simulates a drive on an IDE channel, holding its sectors in memory
+  struct MockDisk {
+    registers: RefCell<[u8; 8]>,
+    /// Words waiting to be read from the data register
+    outgoing: RefCell<VecDeque<u16>>,
+    /// Words written to the data register for the current write command
+    incoming: RefCell<Vec<u16>>,
+    /// Set while a write command is waiting for data
+    writing: RefCell<bool>,
+    sectors: RefCell<Vec<[u8; SECTOR_SIZE]>>,
+    /// Number of status reads that report BSY before each command completes
+    busy_polls: usize,
+    busy_remaining: RefCell<usize>,
+    present: bool,
+  }
+
+  impl MockDisk {
+    fn new(sector_count: usize) -> MockDisk {
+      let mut sectors = Vec::new();
+      for i in 0..sector_count {
+        let mut sector = [0u8; SECTOR_SIZE];
+        for (j, byte) in sector.iter_mut().enumerate() {
+          *byte = (i + j) as u8;
+        }
+        sectors.push(sector);
+      }
+      MockDisk {
+        registers: RefCell::new([0; 8]),
+        outgoing: RefCell::new(VecDeque::new()),
+        incoming: RefCell::new(Vec::new()),
+        writing: RefCell::new(false),
+        sectors: RefCell::new(sectors),
+        busy_polls: 3,
+        busy_remaining: RefCell::new(0),
+        present: true,
+      }
+    }
+
+    fn get_lba(&self) -> usize {
+      let registers = self.registers.borrow();
+      (registers[3] as usize)
+        | ((registers[4] as usize) << 8)
+        | ((registers[5] as usize) << 16)
+        | (((registers[6] & 0x0f) as usize) << 24)
+    }
+  }
+
+  impl AtaBus for MockDisk {
+    fn read_register(&self, register: Register) -> u8 {
+      if !self.present {
+        return 0xff;
+      }
+      if register == Register::StatusCommand {
+        let mut busy = self.busy_remaining.borrow_mut();
+        if *busy > 0 {
+          *busy -= 1;
+          return STATUS_BSY;
+        }
+        let reading = !self.outgoing.borrow().is_empty();
+        let writing = *self.writing.borrow();
+        return if reading || writing { 0x40 | STATUS_DRQ } else { 0x40 };
+      }
+      self.registers.borrow()[register as usize]
+    }
+
+    fn write_register(&self, register: Register, value: u8) {
+      if register != Register::StatusCommand {
+        self.registers.borrow_mut()[register as usize] = value;
+        return;
+      }
+      *self.busy_remaining.borrow_mut() = self.busy_polls;
+      match value {
+        COMMAND_IDENTIFY => {
+          let mut words = [0u16; 256];
+          words[1] = 16;
+          words[3] = 4;
+          words[6] = 63;
+          let model = b"MOCK DISK                               ";
+          for i in 0..20 {
+            words[27 + i] = ((model[i * 2] as u16) << 8) | (model[i * 2 + 1] as u16);
+          }
+          let count = self.sectors.borrow().len() as u32;
+          words[60] = count as u16;
+          words[61] = (count >> 16) as u16;
+          self.outgoing.borrow_mut().extend(words.iter());
+        },
+        COMMAND_READ_SECTORS => {
+          let sector = self.sectors.borrow()[self.get_lba()];
+          for i in 0..(SECTOR_SIZE / 2) {
+            self.outgoing.borrow_mut().push_back((sector[i * 2] as u16) | ((sector[i * 2 + 1] as u16) << 8));
+          }
+        },
+        COMMAND_WRITE_SECTORS => {
+          *self.writing.borrow_mut() = true;
+        },
+        _ => (),
+      }
+    }
+
+    fn read_data(&self) -> u16 {
+      self.outgoing.borrow_mut().pop_front().unwrap_or(0)
+    }
+
+    fn write_data(&self, value: u16) {
+      let mut incoming = self.incoming.borrow_mut();
+      incoming.push(value);
+      if incoming.len() == 256 {
+        let lba = self.get_lba();
+        let mut sectors = self.sectors.borrow_mut();
+        for (i, word) in incoming.iter().enumerate() {
+          sectors[lba][i * 2] = *word as u8;
+          sectors[lba][i * 2 + 1] = (*word >> 8) as u8;
+        }
+        incoming.clear();
+        *self.writing.borrow_mut() = false;
+      }
+    }
+
+    fn read_alt_status(&self) -> u8 {
+      0x40
+    }
+  }
+
+  #[test]
+  fn identify_and_read_sector_zero() {
+    let drive = AtaDrive::new(MockDisk::new(4), DriveSelect::Master);
+    let info = drive.identify().unwrap();
+    assert_eq!(info.sectors, 4);
+    assert_eq!((info.cylinders, info.heads, info.sectors_per_track), (16, 4, 63));
+    assert_eq!(&info.model[0..9], b"MOCK DISK");
+
+    let mut buffer = [0u8; SECTOR_SIZE];
+    drive.read_sector(0, &mut buffer).unwrap();
+    assert_eq!(buffer[0], 0);
+    assert_eq!(buffer[1], 1);
+    assert_eq!(buffer[511], 0xff);
+    drive.read_sector(3, &mut buffer).unwrap();
+    assert_eq!(buffer[0], 3);
+    assert_eq!(drive.read_sector(MAX_LBA28_SECTORS, &mut buffer), Err(AtaError::OutOfRange));
+  }
+
+  #[test]
+  fn write_sector() {
+    let drive = AtaDrive::new(MockDisk::new(2), DriveSelect::Master);
+    let data = [0xa5u8; SECTOR_SIZE];
+    drive.write_sector(1, &data).unwrap();
+    let mut buffer = [0u8; SECTOR_SIZE];
+    drive.read_sector(1, &mut buffer).unwrap();
+    assert_eq!(&buffer[..], &data[..]);
+  }
+
+  #[test]
+  fn missing_and_hung_drives() {
+    let mut missing = MockDisk::new(1);
+    missing.present = false;
+    let drive = AtaDrive::new(missing, DriveSelect::Master);
+    assert_eq!(drive.identify().err(), Some(AtaError::NoDevice));
+
+    // A drive that stays busy forever times out instead of hanging
+    let mut hung = MockDisk::new(1);
+    hung.busy_polls = usize::MAX;
+    let drive = AtaDrive::new(hung, DriveSelect::Master);
+    let mut buffer = [0u8; SECTOR_SIZE];
+    assert_eq!(drive.read_sector(0, &mut buffer), Err(AtaError::Timeout));
+  }
+}
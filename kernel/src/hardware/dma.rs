@@ -1,6 +1,6 @@
 use crate::memory::address::PhysicalAddress;
 use crate::x86::io::Port;
-use spin::{Mutex, MutexGuard};
+use crate::locks::{Mutex, MutexGuard};
 
 /**
  * Interface with old-school ISA DMA. There are only two chips present in the
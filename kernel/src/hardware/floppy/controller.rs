@@ -0,0 +1,896 @@
+#[derive(Copy, Clone, Debug)]
+pub enum ControllerError {
+  InvalidResponse,
+  NotReadyForParam,
+  ReadyTimeout,
+  UnsupportedController,
+  /// The operation ran past its watchdog deadline, usually because IRQ 6
+  /// never arrived. The controller has been reset.
+  OperationTimeout,
+}
+
+use crate::task;
+use crate::task::id::ProcessID;
+use crate::locks::RwLock;
+use super::watchdog::{check_interrupt, InterruptSignal, InterruptWait, OperationQueue, Watchdog, OPERATION_TIMEOUT_MS};
+
+#[repr(u8)]
+pub enum Command {
+  ReadTrack = 0x02,
+  Specify = 0x03,
+  SenseDriveStatus = 0x04,
+  WriteData = 0x05 | 0x40,
+  ReadData = 0x06 | 0x40,
+  Recalibrate = 0x07,
+  SenseInterrupt = 0x08,
+  WriteDeletedData = 0x09,
+  ReadID = 0x0a,
+  Seek = 0x0f,
+  Version = 0x10,
+  Configure = 0x13,
+  Unlock = 0x14,
+  Lock = 0x94,
+}
+
+#[derive(Copy, Clone)]
+pub enum DriveSelect {
+  Primary,
+  Secondary,
+}
+
+impl DriveSelect {
+  pub fn get_number(&self) -> u8 {
+    match self {
+      DriveSelect::Primary => 0,
+      DriveSelect::Secondary => 1,
+    }
+  }
+}
+
+/// Set in ST3 when the media in the drive is write-protected
+pub const ST3_WRITE_PROTECTED: u8 = 0x40;
+
+#[derive(Copy, Clone)]
+pub enum Operation {
+  Read(DriveSelect, usize, usize, usize),
+  Write(DriveSelect, usize, usize, usize),
+  /// Read the drive's ST3 status byte
+  SenseStatus(DriveSelect),
+  /// Check whether the disk has been swapped since the last check
+  CheckMediaChange(DriveSelect),
+}
+
+impl Operation {
+  pub fn get_drive(&self) -> DriveSelect {
+    match self {
+      Operation::Read(drive, _, _, _) => *drive,
+      Operation::Write(drive, _, _, _) => *drive,
+      Operation::SenseStatus(drive) => *drive,
+      Operation::CheckMediaChange(drive) => *drive,
+    }
+  }
+}
+
+#[derive(Copy, Clone)]
+pub enum DriveType {
+  None,
+  Capacity360K,
+  Capacity1200K,
+  Capacity720K,
+  Capacity1440K,
+  Capacity2880K,
+}
+
+impl DriveType {
+  pub fn from_cmos(value: u8) -> Self {
+    match value {
+      1 => Self::Capacity360K,
+      2 => Self::Capacity1200K,
+      3 => Self::Capacity720K,
+      4 => Self::Capacity1440K,
+      5 => Self::Capacity2880K,
+      _ => Self::None,
+    }
+  }
+}
+
+impl core::fmt::Display for DriveType {
+  fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+    match self {
+      DriveType::None => f.write_str("Unavailable"),
+      DriveType::Capacity360K => f.write_str("360KB 5.25 Disk"),
+      DriveType::Capacity1200K => f.write_str("1.2MB 5.25 Disk"),
+      DriveType::Capacity720K => f.write_str("720KB 3.5 Disk"),
+      DriveType::Capacity1440K => f.write_str("1.44MB 3.5 Disk"),
+      DriveType::Capacity2880K => f.write_str("2.88MB 3.5 Disk"),
+    }
+  }
+}
+
+const DOR_PORT_NUMBER: u16 = 0x3F2;
+const MSR_PORT_NUMBER: u16  = 0x3f4;
+const FIFO_PORT_NUMBER: u16 = 0x3f5;
+const CCR_PORT_NUMBER: u16 = 0x3f7;
+/// The same port as the CCR, when read
+const DIR_PORT_NUMBER: u16 = 0x3f7;
+/// Set in the DIR while the selected drive's disk change line is active
+const DIR_DISK_CHANGED: u8 = 0x80;
+const CMOS_ADDRESS_PORT: u16 = 0x70;
+const CMOS_DATA_PORT: u16 = 0x71;
+
+/// Everything the controller needs from the rest of the machine: its IO
+/// ports, the system tick that watchdog deadlines are measured in, and the
+/// scheduler that blocks and resumes processes waiting on IRQ 6. The kernel
+/// uses the real hardware; tests substitute a simulated controller, so that
+/// timeouts and recovery run through the same code.
+pub trait FloppyHardware {
+  fn read_port(&self, port: u16) -> u8;
+
+  fn write_port(&self, port: u16, value: u8);
+
+  fn current_ticks(&self) -> u32;
+
+  fn current_id(&self) -> ProcessID;
+
+  /// Block the current process until it is resumed, or until the deadline
+  /// tick has been reached
+  fn block(&self, deadline: Option<u32>, interrupt: &InterruptSignal);
+
+  fn resume(&self, id: ProcessID);
+}
+
+#[cfg(not(test))]
+pub struct SystemHardware;
+
+#[cfg(not(test))]
+impl FloppyHardware for SystemHardware {
+  fn read_port(&self, port: u16) -> u8 {
+    unsafe { crate::x86::io::inb(port) }
+  }
+
+  fn write_port(&self, port: u16, value: u8) {
+    unsafe { crate::x86::io::outb(port, value) }
+  }
+
+  fn current_ticks(&self) -> u32 {
+    crate::time::system::get_system_ticks()
+  }
+
+  fn current_id(&self) -> ProcessID {
+    task::switching::get_current_id()
+  }
+
+  /// The process is marked as blocked before the interrupt is checked one
+  /// last time: an interrupt arriving before that point is seen by the check,
+  /// and one arriving after it resumes the process.
+  fn block(&self, deadline: Option<u32>, interrupt: &InterruptSignal) {
+    let current_process = task::switching::get_current_process();
+    current_process.write().hardware_block(deadline);
+    if interrupt.has_arrived() {
+      current_process.write().hardware_resume();
+      return;
+    }
+    task::yield_coop();
+  }
+
+  fn resume(&self, id: ProcessID) {
+    match task::switching::get_process(&id) {
+      Some(proc) => proc.write().hardware_resume(),
+      None => (),
+    }
+  }
+}
+
+pub struct FloppyDiskController<H: FloppyHardware> {
+  hardware: H,
+  operation_queue: RwLock<Option<OperationQueue>>,
+  /// Bounds the total time of the current operation
+  watchdog: RwLock<Watchdog>,
+  /// Armed before each command, and signaled every time an interrupt comes
+  /// in on IRQ 6. This accommodates ultra-fast floppy controllers, which may
+  /// interrupt before the driver starts waiting.
+  interrupt: InterruptSignal,
+  /// Which process to resume when an interrupt occurs
+  wake_on_interrupt: RwLock<Option<ProcessID>>,
+
+  primary_drive_type: RwLock<DriveType>,
+  secondary_drive_type: RwLock<DriveType>,
+}
+
+impl<H: FloppyHardware> FloppyDiskController<H> {
+  pub const fn new(hardware: H) -> Self {
+    Self {
+      hardware,
+      operation_queue: RwLock::new(None),
+      watchdog: RwLock::new(Watchdog::new()),
+      interrupt: InterruptSignal::new(),
+      wake_on_interrupt: RwLock::new(None),
+
+      primary_drive_type: RwLock::new(DriveType::None),
+      secondary_drive_type: RwLock::new(DriveType::None),
+    }
+  }
+
+  /// Triggered by IRQ 6, indicating some disk drive has an update
+  pub fn handle_interrupt(&self) {
+    self.interrupt.signal();
+    // Determine which process is executing
+    let blocked = self.wake_on_interrupt.try_read().and_then(|r| *r);
+    // Awaken the process
+    if let Some(id) = blocked {
+      self.hardware.resume(id);
+    }
+  }
+
+  /// Set up the controller for the first time
+  pub fn init(&self) -> Result<(), ControllerError> {
+    self.watchdog.write().arm(self.hardware.current_ticks(), OPERATION_TIMEOUT_MS);
+    let result = self.init_controller();
+    self.watchdog.write().disarm();
+    result
+  }
+
+  fn init_controller(&self) -> Result<(), ControllerError> {
+    // first, detect how many drives
+    {
+      // read from CMOS register 0x10
+      self.hardware.write_port(CMOS_ADDRESS_PORT, 0x10);
+      let cmos_value = self.hardware.read_port(CMOS_DATA_PORT);
+      let primary_drive = DriveType::from_cmos(cmos_value >> 4);
+      let secondary_drive = DriveType::from_cmos(cmos_value & 0x0f);
+      *(self.primary_drive_type.write()) = primary_drive;
+      *(self.secondary_drive_type.write()) = secondary_drive;
+      #[cfg(not(test))]
+      {
+        crate::klog!("Drives Detected:\n");
+        crate::klog!("  Primary Drive:   \x1b[97m{:}\x1b[m\n", primary_drive);
+        crate::klog!("  Secondary Drive: \x1b[97m{:}\x1b[m\n", secondary_drive);
+      }
+    }
+
+    self.send_command(Command::Version, &[])?;
+    let mut version_response = [0];
+    self.get_response(&mut version_response)?;
+    if version_response[0] != 0x90 {
+      return Err(ControllerError::UnsupportedController);
+    }
+    self.send_command(Command::Configure, &[0, 0x78, 0])?;
+    self.send_command(Command::Lock, &[])?;
+    let mut lock_response = [0];
+    self.get_response(&mut lock_response)?;
+    // Check if lock bit is set?
+    self.reset()?;
+    // Ensure motor is on
+    let mut motor_flag = 0;
+    if self.has_primary_drive() {
+      motor_flag |= 0x10;
+    }
+    if self.has_secondary_drive() {
+      motor_flag |= 0x20;
+    }
+    let dor = self.dor_read();
+    self.dor_write(dor | motor_flag);
+    task::sleep(300);
+
+    if self.has_primary_drive() {
+      self.select_drive(DriveSelect::Primary);
+      self.recalibrate()?;
+    }
+
+    if self.has_secondary_drive() {
+      self.select_drive(DriveSelect::Secondary);
+      self.recalibrate()?;
+    }
+
+    Ok(())
+  }
+
+  /// Enqueue an operation from a process, and block until it has been
+  /// performed. Status requests return the status byte, media change checks
+  /// return 1 if the disk was swapped, and reads and writes return 0. If the operation times out, the controller is reset before the
+  /// next queued process is woken.
+  pub fn add_operation(&self, op: Operation) -> Result<u8, ControllerError> {
+    let current_id = self.hardware.current_id();
+    // Push the process onto the end of the queue, and determine whether other
+    // processes are ahead of it
+    let must_wait = loop {
+      match self.operation_queue.try_write() {
+        Some(mut ops) => {
+          let q = ops.get_or_insert_with(OperationQueue::new);
+          break q.enqueue(current_id);
+        },
+        None => {
+          task::yield_coop();
+        },
+      }
+    };
+    if must_wait {
+      // Block until this process is front of the queue. The wake-up can be
+      // missed if the operation ahead finishes before this process blocks, so
+      // the queue is checked again periodically.
+      while !self.is_front_of_queue(current_id) {
+        let deadline = crate::time::ticks::target_tick(self.hardware.current_ticks(), OPERATION_TIMEOUT_MS);
+        self.hardware.block(Some(deadline), &self.interrupt);
+      }
+    }
+    // The operation is now first in the queue
+    self.watchdog.write().arm(self.hardware.current_ticks(), OPERATION_TIMEOUT_MS);
+    let result = match op {
+      Operation::Read(drive, c, h, s) => {
+        self.read(drive, c, h, s).map(|_| 0)
+      },
+      Operation::Write(drive, c, h, s) => {
+        self.write(drive, c, h, s).map(|_| 0)
+      },
+      Operation::SenseStatus(drive) => {
+        self.sense_drive_status(drive)
+      },
+      Operation::CheckMediaChange(drive) => {
+        self.check_media_change(drive).map(|changed| changed as u8)
+      },
+    };
+    if let Err(ControllerError::OperationTimeout) = result {
+      self.recover(op.get_drive());
+    }
+    self.watchdog.write().disarm();
+
+    // This operation is now complete, remove the operation from the queue.
+    // If there is another process waiting to read or write, wake it up.
+    let next: Option<ProcessID> = loop {
+      match self.operation_queue.try_write() {
+        Some(mut q) => {
+          break q.as_mut().and_then(|q| q.complete(current_id));
+        },
+        None => {
+          task::yield_coop();
+        },
+      }
+    };
+
+    if let Some(to_wake) = next {
+      self.hardware.resume(to_wake);
+    }
+    result
+  }
+
+  fn is_front_of_queue(&self, id: ProcessID) -> bool {
+    match *self.operation_queue.read() {
+      Some(ref q) => q.is_front(id),
+      None => false,
+    }
+  }
+
+  /// Bring a controller back after an operation timed out. The reset turns
+  /// off the motors and loses the head position, so both are restored before
+  /// the next operation runs. Recovery gets its own deadline, and a failure is
+  /// only logged; the next operation will try to reset the controller again.
+  fn recover(&self, drive: DriveSelect) {
+    #[cfg(not(test))]
+    crate::klog!(Warn, Driver; "Floppy operation timed out, resetting controller\n");
+    self.watchdog.write().arm(self.hardware.current_ticks(), OPERATION_TIMEOUT_MS);
+    let result = self.reset().and_then(|_| {
+      self.ensure_motor_on(drive);
+      self.select_drive(drive);
+      self.recalibrate()
+    });
+    if let Err(_e) = result {
+      #[cfg(not(test))]
+      crate::klog!(Error, Driver; "Floppy controller reset failed: {:?}\n", _e);
+    }
+  }
+
+  pub fn has_primary_drive(&self) -> bool {
+    match *self.primary_drive_type.read() {
+      DriveType::None => false,
+      _ => true,
+    }
+  }
+
+  pub fn has_secondary_drive(&self) -> bool {
+    match *self.secondary_drive_type.read() {
+      DriveType::None => false,
+      _ => true,
+    }
+  }
+
+
+  fn ensure_motor_on(&self, drive: DriveSelect) {
+    let dor = self.dor_read();
+    let flag = match drive {
+      DriveSelect::Primary => 0x10,
+      DriveSelect::Secondary => 0x20,
+    };
+    self.dor_write(dor | flag);
+    task::sleep(300);
+  }
+
+  fn select_drive(&self, drive: DriveSelect) {
+    let dor = self.dor_read();
+    let select = match drive {
+      DriveSelect::Primary => 0,
+      DriveSelect::Secondary => 1,
+    };
+    self.dor_write((dor & 0xfc) | select);
+  }
+
+  fn recalibrate(&self) -> Result<(), ControllerError> {
+    let mut st0 = [0, 0];
+    self.send_command(Command::Recalibrate, &[0])?;
+    self.wait_for_interrupt()?;
+    self.send_command(Command::SenseInterrupt, &[])?;
+    self.get_response(&mut st0)?;
+    if st0[0] & 0x20 != 0x20 {
+      // Retry command
+      self.send_command(Command::Recalibrate, &[0])?;
+      self.wait_for_interrupt()?;
+      self.send_command(Command::SenseInterrupt, &[])?;
+      self.get_response(&mut st0)?;
+    }
+    Ok(())
+  }
+
+  /// Wait until an IRQ 6 interrupt occurs
+  /// When the handler is triggered, it will resume this process. If the
+  /// watchdog deadline passes first, the wait fails with a timeout.
+  fn wait_for_interrupt(&self) -> Result<(), ControllerError> {
+    // Set this first
+    let pid = self.hardware.current_id();
+    *self.wake_on_interrupt.write() = Some(pid);
+
+    let result = loop {
+      let (state, deadline) = {
+        let watchdog = self.watchdog.read();
+        (check_interrupt(self.interrupt.has_arrived(), &watchdog, self.hardware.current_ticks()), watchdog.get_deadline())
+      };
+      match state {
+        InterruptWait::Received => break Ok(()),
+        InterruptWait::TimedOut => break Err(ControllerError::OperationTimeout),
+        InterruptWait::Pending => self.hardware.block(deadline, &self.interrupt),
+      }
+    };
+    *self.wake_on_interrupt.write() = None;
+    result
+  }
+
+  fn get_status(&self) -> u8 {
+    self.hardware.read_port(MSR_PORT_NUMBER)
+  }
+
+  fn fifo_write(&self, value: u8) {
+    self.hardware.write_port(FIFO_PORT_NUMBER, value);
+  }
+
+  fn fifo_read(&self) -> u8 {
+    self.hardware.read_port(FIFO_PORT_NUMBER)
+  }
+
+  fn dor_write(&self, value: u8) {
+    self.hardware.write_port(DOR_PORT_NUMBER, value);
+  }
+
+  fn dor_read(&self) -> u8 {
+    self.hardware.read_port(DOR_PORT_NUMBER)
+  }
+
+  /// The RQM bit indicates that a driver can now read or write data at the FIFO
+  /// register. Many procedures involve looping over status register reads,
+  /// waiting for the RQM bit to be set. This procedure will yield between reads
+  /// so as to not block other processes, and will timeout after a number of
+  /// attempts.
+  fn wait_for_rqm(&self) -> Result<(), ControllerError> {
+    let mut retry_count = 10;
+
+    let mut ready = false;
+    while !ready && retry_count > 0 {
+      ready = self.get_status() & 0x80 == 0x80;
+      retry_count -= 1;
+      if !ready {
+        task::yield_coop();
+      }
+    }
+    if !ready {
+      Err(ControllerError::ReadyTimeout)
+    } else {
+      Ok(())
+    }
+  }
+
+  /// Attempt to read response bytes and copy them to a mutable slice.
+  /// If it succeeds, it will return an `Ok` Response containing the number of
+  /// bytes copied to the `response` slice.
+  /// If it fails, it will return an `Err` response, and the entire command will
+  /// need to be retried.
+  pub fn get_response(&self, response: &mut [u8]) -> Result<usize, ControllerError> {
+    self.wait_for_rqm()?;
+    let mut has_response = self.get_status() & 0x50 == 0x50;
+    let mut response_index = 0;
+    while has_response {
+      if let Some(entry) = response.get_mut(response_index) {
+        *entry = self.fifo_read();
+        response_index += 1;
+      }
+      self.wait_for_rqm()?;
+      has_response = self.get_status() & 0x50 == 0x50;
+    }
+
+    if self.get_status() & 0xd0 == 0x80 {
+      Ok(response_index)
+    } else {
+      Err(ControllerError::InvalidResponse)
+    }
+  }
+
+  /// Reset an uninitialized or locked up controller
+  fn reset(&self) -> Result<(), ControllerError> {
+    self.dor_write(0);
+    // needs to sleep for 4 microseconds, a yield should cover that
+    task::yield_coop();
+    // Motors off, reset + IRQ enabled, select disk 0. Leaving reset raises
+    // an interrupt of its own.
+    self.interrupt.arm();
+    self.dor_write(0x0c);
+    self.wait_for_interrupt()?;
+
+    let mut sense = [0, 0];
+    for _ in 0..4 {
+      self.send_command(Command::SenseInterrupt, &[])?;
+      self.get_response(&mut sense)?;
+    }
+
+    // Start drive select
+    // Assume we're using a 1.44M disk
+    self.hardware.write_port(CCR_PORT_NUMBER, 0);
+    // SPECIFY, with "safe values" SRT=8, HUT=0, HLT=5, NDMA=0
+    self.send_command(Command::Specify, &[8 << 4, 5 << 1])?;
+    Ok(())
+  }
+
+  /// Issue a command to the floppy controller. If it succeeds, it will return
+  /// an Ok Result. Because not all commands have a response phase, handling
+  /// the response from a command is done in a different method.
+  fn send_command(&self, command: Command, params: &[u8]) -> Result<(), ControllerError> {
+    if self.get_status() & 0xc0 != 0x80 {
+      self.reset()?;
+    }
+
+    self.interrupt.arm();
+    self.fifo_write(command as u8);
+
+    // Commands have a variable set of parameters that need to be issued one by
+    // one. Loop through the set of parameters, waiting until the controller is
+    // ready to receive data, and sending it out byte-by-byte.
+    let mut param = 0;
+    while param < params.len() {
+      self.wait_for_rqm()?;
+      if self.get_status() & 0x40 != 0 {
+        return Err(ControllerError::NotReadyForParam);
+      }
+      self.fifo_write(params[param]);
+      param += 1;
+    }
+    self.wait_for_rqm()?;
+
+    Ok(())
+  }
+
+  fn read(&self, drive: DriveSelect, c: usize, h: usize, s: usize) -> Result<(), ControllerError> {
+    self.select_drive(drive);
+    self.dma(Command::ReadData, drive.get_number(), c, h, s)
+  }
+
+  fn write(&self, drive: DriveSelect, c: usize, h: usize, s: usize) -> Result<(), ControllerError> {
+    self.select_drive(drive);
+    self.dma(Command::WriteData, drive.get_number(), c, h, s)
+  }
+
+  /// SENSE DRIVE STATUS has no interrupt phase. Its single result byte, ST3,
+  /// reports the write-protect line, which the DIR register does not.
+  fn sense_drive_status(&self, drive: DriveSelect) -> Result<u8, ControllerError> {
+    self.select_drive(drive);
+    self.send_command(Command::SenseDriveStatus, &[drive.get_number()])?;
+    let mut st3 = [0];
+    self.get_response(&mut st3)?;
+    Ok(st3[0])
+  }
+
+  /// The disk change line goes active when a disk is removed, and stays that
+  /// way until the head is stepped with a disk in the drive. Seeking away from
+  /// cylinder 0 and back clears it, so each swap is only reported once.
+  fn check_media_change(&self, drive: DriveSelect) -> Result<bool, ControllerError> {
+    self.select_drive(drive);
+    if self.hardware.read_port(DIR_PORT_NUMBER) & DIR_DISK_CHANGED == 0 {
+      return Ok(false);
+    }
+    self.seek(drive, 1)?;
+    self.seek(drive, 0)?;
+    Ok(true)
+  }
+
+  fn seek(&self, drive: DriveSelect, cylinder: u8) -> Result<(), ControllerError> {
+    let mut st0 = [0, 0];
+    self.send_command(Command::Seek, &[drive.get_number(), cylinder])?;
+    self.wait_for_interrupt()?;
+    self.send_command(Command::SenseInterrupt, &[])?;
+    self.get_response(&mut st0)?;
+    Ok(())
+  }
+
+  fn dma(&self, command: Command, drive_number: u8, cylinder: usize, head: usize, sector: usize) -> Result<(), ControllerError> {
+    self.send_command(
+      command,
+      &[
+        (head << 2) as u8 | drive_number,
+        cylinder as u8,
+        head as u8,
+        sector as u8,
+        2,
+        18, // last sector on track
+        0x1b, // GAP1 default size
+        0xff,
+      ],
+    )?;
+    self.wait_for_interrupt()?;
+    let mut response = [0, 0, 0, 0, 0, 0, 0];
+    self.get_response(&mut response)?;
+    // Process response
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate std;
+
+  use alloc::collections::VecDeque;
+  use alloc::sync::Arc;
+  use alloc::vec::Vec;
+  use crate::task::id::ProcessID;
+  use crate::locks::Mutex;
+  use std::thread::{self, ThreadId};
+  use super::super::watchdog::InterruptSignal;
+  use super::{
+    ControllerError, DriveSelect, FloppyDiskController, FloppyHardware, Operation, CMOS_DATA_PORT, DIR_DISK_CHANGED,
+    DIR_PORT_NUMBER, DOR_PORT_NUMBER, FIFO_PORT_NUMBER, MSR_PORT_NUMBER,
+  };
+
+  struct SimulatedState {
+    ticks: u32,
+    dor: u8,
+    /// Bytes of the command currently being sent
+    command: Vec<u8>,
+    /// Result bytes waiting to be read from the FIFO
+    response: VecDeque<u8>,
+    /// Process that sent the most recent command, and owns the controller
+    owner: Option<ProcessID>,
+    irq_pending: bool,
+    /// Number of upcoming interrupts that never arrive
+    dropped_interrupts: usize,
+    resets: usize,
+    /// State of the disk change line, cleared by a seek
+    disk_changed: bool,
+    /// Processes that blocked while waiting for their turn
+    queued: Vec<ProcessID>,
+    woken: Vec<ProcessID>,
+  }
+
+  /// Simulates a controller with a 1.44MB primary drive. Each test thread acts
+  /// as a process. Time only passes while a process is blocked on an
+  /// interrupt that never arrives.
+  struct SimulatedController {
+    state: Mutex<SimulatedState>,
+    threads: Mutex<Vec<(ThreadId, ProcessID)>>,
+  }
+
+  impl SimulatedController {
+    fn new() -> SimulatedController {
+      SimulatedController {
+        state: Mutex::new(SimulatedState {
+          ticks: 100,
+          dor: 0x0c,
+          command: Vec::new(),
+          response: VecDeque::new(),
+          owner: None,
+          irq_pending: false,
+          dropped_interrupts: 0,
+          resets: 0,
+          disk_changed: false,
+          queued: Vec::new(),
+          woken: Vec::new(),
+        }),
+        threads: Mutex::new(Vec::new()),
+      }
+    }
+
+    fn run_as(&self, id: ProcessID) {
+      self.threads.lock().push((thread::current().id(), id));
+    }
+
+    fn raise_interrupt(state: &mut SimulatedState) {
+      if state.dropped_interrupts > 0 {
+        state.dropped_interrupts -= 1;
+      } else {
+        state.irq_pending = true;
+      }
+    }
+
+    fn execute(state: &mut SimulatedState) {
+      let result: &[u8] = match state.command[0] & 0x1f {
+        // Version, reporting an 82077AA
+        0x10 => &[0x90],
+        // Lock
+        0x14 => &[0x10],
+        // Sense Interrupt, after a completed seek to cylinder 0
+        0x08 => &[0x20, 0],
+        // Sense Drive Status
+        0x04 => &[0x20],
+        // Read Data and Write Data
+        0x05 | 0x06 => {
+          Self::raise_interrupt(state);
+          &[0, 0, 0, 0, 0, 1, 2]
+        },
+        // Recalibrate and Seek
+        0x07 | 0x0f => {
+          state.disk_changed = false;
+          Self::raise_interrupt(state);
+          &[]
+        },
+        _ => &[],
+      };
+      state.response.extend(result.iter().copied());
+      state.command.clear();
+    }
+  }
+
+  fn param_count(opcode: u8) -> usize {
+    match opcode & 0x1f {
+      0x02 | 0x05 | 0x06 => 8,
+      0x03 | 0x0f => 2,
+      0x04 | 0x07 => 1,
+      0x13 => 3,
+      _ => 0,
+    }
+  }
+
+  impl FloppyHardware for SimulatedController {
+    fn read_port(&self, port: u16) -> u8 {
+      let mut state = self.state.lock();
+      match port {
+        MSR_PORT_NUMBER if !state.response.is_empty() => 0xd0,
+        MSR_PORT_NUMBER if !state.command.is_empty() => 0x90,
+        MSR_PORT_NUMBER => 0x80,
+        FIFO_PORT_NUMBER => state.response.pop_front().unwrap_or(0),
+        DOR_PORT_NUMBER => state.dor,
+        CMOS_DATA_PORT => 0x40,
+        DIR_PORT_NUMBER if state.disk_changed => DIR_DISK_CHANGED,
+        _ => 0,
+      }
+    }
+
+    fn write_port(&self, port: u16, value: u8) {
+      let current_id = self.current_id();
+      let mut state = self.state.lock();
+      match port {
+        FIFO_PORT_NUMBER => {
+          state.owner = Some(current_id);
+          state.command.push(value);
+          if state.command.len() > param_count(state.command[0]) {
+            Self::execute(&mut state);
+          }
+        },
+        DOR_PORT_NUMBER => {
+          if value & 0x04 == 0 {
+            // Entering reset abandons any command in progress
+            state.command.clear();
+            state.response.clear();
+            state.irq_pending = false;
+          } else if state.dor & 0x04 == 0 {
+            state.resets += 1;
+            Self::raise_interrupt(&mut state);
+          }
+          state.dor = value;
+        },
+        _ => (),
+      }
+    }
+
+    fn current_ticks(&self) -> u32 {
+      self.state.lock().ticks
+    }
+
+    fn current_id(&self) -> ProcessID {
+      let current = thread::current().id();
+      self.threads.lock().iter().find(|(thread, _)| *thread == current).unwrap().1
+    }
+
+    fn block(&self, deadline: Option<u32>, interrupt: &InterruptSignal) {
+      let current_id = self.current_id();
+      {
+        let mut state = self.state.lock();
+        if state.owner != Some(current_id) {
+          if !state.queued.contains(&current_id) {
+            state.queued.push(current_id);
+          }
+          drop(state);
+          thread::yield_now();
+          return;
+        }
+        if state.irq_pending {
+          state.irq_pending = false;
+          interrupt.signal();
+          return;
+        }
+      }
+      // The interrupt is never coming. The controller stays hung until
+      // another process has queued up behind this one, and then the deadline
+      // passes.
+      while self.state.lock().queued.is_empty() {
+        thread::yield_now();
+      }
+      let mut state = self.state.lock();
+      state.ticks = deadline.unwrap();
+    }
+
+    fn resume(&self, id: ProcessID) {
+      self.state.lock().woken.push(id);
+    }
+  }
+
+  #[test]
+  fn hung_controller_is_reset() {
+    let controller = Arc::new(FloppyDiskController::new(SimulatedController::new()));
+    let first = ProcessID::new(1);
+    let second = ProcessID::new(2);
+    controller.hardware.run_as(first);
+    controller.init().unwrap();
+    assert!(controller.has_primary_drive());
+    assert!(!controller.has_secondary_drive());
+    assert_eq!(controller.hardware.state.lock().resets, 1);
+
+    // The first read never gets its interrupt
+    controller.hardware.state.lock().dropped_interrupts = 1;
+    let hung = {
+      let controller = controller.clone();
+      thread::spawn(move || {
+        controller.hardware.run_as(first);
+        controller.add_operation(Operation::Read(DriveSelect::Primary, 0, 0, 1))
+      })
+    };
+    while controller.hardware.state.lock().owner != Some(first) || controller.operation_queue.read().is_none() {
+      thread::yield_now();
+    }
+    let next = {
+      let controller = controller.clone();
+      thread::spawn(move || {
+        controller.hardware.run_as(second);
+        controller.add_operation(Operation::Read(DriveSelect::Primary, 0, 0, 2))
+      })
+    };
+
+    let start = 100;
+    assert!(matches!(hung.join().unwrap(), Err(ControllerError::OperationTimeout)));
+    // The queued read runs on the reset controller once the hung one fails
+    assert!(matches!(next.join().unwrap(), Ok(0)));
+    let state = controller.hardware.state.lock();
+    assert!(state.ticks > start);
+    assert_eq!(state.resets, 2);
+    assert_eq!(state.queued, [second]);
+    assert_eq!(state.woken, [second]);
+    assert_eq!(controller.operation_queue.read().as_ref().unwrap().len(), 0);
+    assert_eq!(controller.watchdog.read().get_deadline(), None);
+  }
+
+  #[test]
+  fn media_change_is_reported_once() {
+    let controller = FloppyDiskController::new(SimulatedController::new());
+    controller.hardware.run_as(ProcessID::new(1));
+    controller.init().unwrap();
+    let check = Operation::CheckMediaChange(DriveSelect::Primary);
+    assert!(matches!(controller.add_operation(check), Ok(0)));
+
+    controller.hardware.state.lock().disk_changed = true;
+    assert!(matches!(controller.add_operation(check), Ok(1)));
+    assert!(!controller.hardware.state.lock().disk_changed);
+    assert!(matches!(controller.add_operation(check), Ok(0)));
+  }
+}
@@ -0,0 +1,17 @@
+//! An interface to the low-level Floppy Disk Controller, allowing a driver to
+//! communicate with the disk drive hardware.
+//! 
+//! The controller chip is accessible through a series of registers
+//! 
+//! Disk access involves sending commands to the controller, and then waiting
+//! for an IRQ6 interrupt if the command returns a response. Sending commands
+//! involves looping and waiting for some result, and is frequently problematic.
+//! Drivers accessing the floppy controller should be aware of this.
+//!
+//! A wedged controller may also never raise the interrupt at all, so every
+//! operation runs under a watchdog that bounds its total time.
+
+mod controller;
+pub mod watchdog;
+
+pub use controller::*;
@@ -0,0 +1,270 @@
+//! Floppy operations are run one process at a time, in the order they were
+//! requested. The process at the front of the queue owns the controller; the
+//! rest are blocked until it finishes and wakes the next one.
+//!
+//! Polling for RQM gives up after a few attempts, but an operation can still
+//! hang forever if IRQ 6 never arrives. The watchdog records the tick by which
+//! the current operation must be done. A process waiting on the interrupt is
+//! blocked with that deadline, and if it wakes without an interrupt after the
+//! deadline has passed, the operation is failed and the controller is reset.
+//! The queue is left alone by a reset, so whoever is waiting next still gets
+//! its turn.
+//!
+//! Some controllers raise IRQ 6 before the driver has even started waiting
+//! for it. Interrupts are counted rather than flagged, and a command records
+//! the count before it is issued, so an interrupt that arrives early is still
+//! seen, and one left over from an earlier command is not.
+
+use alloc::collections::vec_deque::VecDeque;
+use core::sync::atomic::{AtomicU32, Ordering};
+use crate::task::id::ProcessID;
+use crate::time::ticks;
+
+/// Upper bound on the time a single read or write may take, including motor
+/// spin-up and seeking
+pub const OPERATION_TIMEOUT_MS: usize = 3000;
+
+pub struct Watchdog {
+  deadline: Option<u32>,
+}
+
+impl Watchdog {
+  pub const fn new() -> Self {
+    Self {
+      deadline: None,
+    }
+  }
+
+  /// Start timing a new operation
+  pub fn arm(&mut self, current_ticks: u32, timeout_ms: usize) {
+    self.deadline = Some(ticks::target_tick(current_ticks, timeout_ms));
+  }
+
+  pub fn disarm(&mut self) {
+    self.deadline = None;
+  }
+
+  pub fn get_deadline(&self) -> Option<u32> {
+    self.deadline
+  }
+
+  pub fn has_expired(&self, current_ticks: u32) -> bool {
+    match self.deadline {
+      Some(deadline) => ticks::tick_reached(current_ticks, deadline),
+      None => false,
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum InterruptWait {
+  Received,
+  /// Keep blocking until the interrupt or the deadline
+  Pending,
+  TimedOut,
+}
+
+/// Decide what a process waiting on IRQ 6 should do. An interrupt that came in
+/// is always accepted, even if it arrived after the deadline.
+pub fn check_interrupt(received: bool, watchdog: &Watchdog, current_ticks: u32) -> InterruptWait {
+  if received {
+    InterruptWait::Received
+  } else if watchdog.has_expired(current_ticks) {
+    InterruptWait::TimedOut
+  } else {
+    InterruptWait::Pending
+  }
+}
+
+/// Counts IRQ 6 interrupts, so that a waiting process can tell whether one
+/// arrived since its command was issued. Neither side ever waits on a lock,
+/// so the interrupt handler can't fail to record an interrupt.
+pub struct InterruptSignal {
+  /// Number of interrupts received, wrapping around
+  arrived: AtomicU32,
+  /// Value of `arrived` when the current command was issued
+  armed: AtomicU32,
+}
+
+impl InterruptSignal {
+  pub const fn new() -> Self {
+    Self {
+      arrived: AtomicU32::new(0),
+      armed: AtomicU32::new(0),
+    }
+  }
+
+  /// Called just before issuing a command. Interrupts that arrived earlier
+  /// belong to a previous command, and are ignored from now on.
+  pub fn arm(&self) {
+    self.armed.store(self.arrived.load(Ordering::SeqCst), Ordering::SeqCst);
+  }
+
+  /// Called by the IRQ 6 handler
+  pub fn signal(&self) {
+    self.arrived.fetch_add(1, Ordering::SeqCst);
+  }
+
+  /// Determine whether an interrupt has arrived since the command was
+  /// issued, no matter how soon after
+  pub fn has_arrived(&self) -> bool {
+    self.arrived.load(Ordering::SeqCst) != self.armed.load(Ordering::SeqCst)
+  }
+}
+
+/// Processes waiting to use the controller. The front of the queue is the one
+/// currently running an operation.
+pub struct OperationQueue {
+  waiting: VecDeque<ProcessID>,
+}
+
+impl OperationQueue {
+  pub fn new() -> Self {
+    Self {
+      waiting: VecDeque::with_capacity(2),
+    }
+  }
+
+  /// Add a process to the back of the queue. Returns true if it has to wait
+  /// for other operations to complete first.
+  pub fn enqueue(&mut self, id: ProcessID) -> bool {
+    self.waiting.push_back(id);
+    self.waiting.len() > 1
+  }
+
+  pub fn is_front(&self, id: ProcessID) -> bool {
+    self.waiting.front() == Some(&id)
+  }
+
+  /// Remove a process once its operation has finished, whether it succeeded
+  /// or not. If it was running, returns the next process to wake.
+  pub fn complete(&mut self, id: ProcessID) -> Option<ProcessID> {
+    let was_front = self.is_front(id);
+    self.waiting.retain(|waiting| *waiting != id);
+    if was_front {
+      self.waiting.front().copied()
+    } else {
+      None
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.waiting.len()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::task::id::ProcessID;
+  use crate::time::ticks::ms_to_ticks;
+  use super::{check_interrupt, InterruptSignal, InterruptWait, OperationQueue, Watchdog, OPERATION_TIMEOUT_MS};
+
+  #[test]
+  fn missing_interrupt_times_out() {
+    let first = ProcessID::new(3);
+    let second = ProcessID::new(4);
+    let mut queue = OperationQueue::new();
+    assert!(!queue.enqueue(first));
+    assert!(queue.enqueue(second));
+
+    let mut watchdog = Watchdog::new();
+    assert_eq!(check_interrupt(false, &watchdog, 100), InterruptWait::Pending);
+    watchdog.arm(100, OPERATION_TIMEOUT_MS);
+    let deadline = watchdog.get_deadline().unwrap();
+    assert_eq!(deadline, 100 + ms_to_ticks(OPERATION_TIMEOUT_MS));
+
+    // IRQ 6 never arrives; the first process keeps blocking until the deadline
+    assert_eq!(check_interrupt(false, &watchdog, 101), InterruptWait::Pending);
+    assert_eq!(check_interrupt(false, &watchdog, deadline - 1), InterruptWait::Pending);
+    assert_eq!(check_interrupt(false, &watchdog, deadline), InterruptWait::TimedOut);
+    // A late interrupt is still accepted
+    assert_eq!(check_interrupt(true, &watchdog, deadline + 5), InterruptWait::Received);
+
+    // The reset that follows does not touch the queue. Completing the failed
+    // operation hands the controller to the next process.
+    watchdog.disarm();
+    assert!(!watchdog.has_expired(deadline + 5));
+    assert_eq!(queue.len(), 2);
+    assert_eq!(queue.complete(first), Some(second));
+    assert!(queue.is_front(second));
+
+    // The next operation gets a fresh deadline and completes normally
+    watchdog.arm(deadline + 5, OPERATION_TIMEOUT_MS);
+    assert_eq!(check_interrupt(true, &watchdog, deadline + 6), InterruptWait::Received);
+    assert_eq!(queue.complete(second), None);
+    assert_eq!(queue.len(), 0);
+  }
+
+  #[test]
+  fn deadline_across_tick_wraparound() {
+    let mut watchdog = Watchdog::new();
+    watchdog.arm(u32::MAX - 1, OPERATION_TIMEOUT_MS);
+    assert!(!watchdog.has_expired(u32::MAX));
+    assert!(!watchdog.has_expired(5));
+    assert!(watchdog.has_expired(ms_to_ticks(OPERATION_TIMEOUT_MS)));
+  }
+
+  #[test]
+  fn interrupt_before_wait() {
+    let signal = InterruptSignal::new();
+    // An interrupt from before the command was issued doesn't count
+    signal.signal();
+    signal.arm();
+    assert!(!signal.has_arrived());
+    // A fast controller interrupts before the driver starts waiting. The
+    // interrupt is still there when the driver checks.
+    signal.signal();
+    let watchdog = Watchdog::new();
+    assert_eq!(check_interrupt(signal.has_arrived(), &watchdog, 0), InterruptWait::Received);
+    assert!(signal.has_arrived());
+    signal.arm();
+    assert!(!signal.has_arrived());
+  }
+
+  #[test]
+  fn interrupt_races_with_wait() {
+    extern crate std;
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    for _ in 0..100 {
+      let signal = Arc::new(InterruptSignal::new());
+      let blocked = Arc::new(AtomicBool::new(false));
+      signal.arm();
+      let irq = {
+        let signal = signal.clone();
+        let blocked = blocked.clone();
+        thread::spawn(move || {
+          signal.signal();
+          // Resuming the waiter, as the interrupt handler does
+          blocked.store(false, Ordering::SeqCst);
+        })
+      };
+      // The waiter marks itself blocked before checking, so the interrupt
+      // either shows up in the check or clears the block afterwards
+      loop {
+        blocked.store(true, Ordering::SeqCst);
+        if signal.has_arrived() {
+          blocked.store(false, Ordering::SeqCst);
+          break;
+        }
+        while blocked.load(Ordering::SeqCst) {
+          thread::yield_now();
+        }
+      }
+      irq.join().unwrap();
+    }
+  }
+
+  #[test]
+  fn queued_process_leaves_early() {
+    let mut queue = OperationQueue::new();
+    queue.enqueue(ProcessID::new(1));
+    queue.enqueue(ProcessID::new(2));
+    queue.enqueue(ProcessID::new(3));
+    // Removing a process that isn't running doesn't wake anyone
+    assert_eq!(queue.complete(ProcessID::new(2)), None);
+    assert_eq!(queue.complete(ProcessID::new(1)), Some(ProcessID::new(3)));
+  }
+}
@@ -1,5 +1,6 @@
+pub mod apm;
+pub mod ata;
 pub mod dma;
-#[cfg(not(test))]
 pub mod floppy;
 pub mod pic;
 pub mod pit;
@@ -0,0 +1,104 @@
+//! The VGA text mode font is code page 437: each byte in video memory selects
+//! one of 256 glyphs. The lower half matches ASCII, apart from the control
+//! codes, which have their own pictures like arrows and card suits. The upper
+//! half holds accented letters, Greek letters, math symbols, and the line and
+//! block characters used to draw boxes.
+//! Text sent to the console is Unicode, so each codepoint needs to be matched
+//! to the glyph that draws it. Codepoints with no glyph are shown as a
+//! placeholder instead.
+
+/// Drawn for any codepoint the font has no glyph for
+pub const PLACEHOLDER_GLYPH: u8 = b'?';
+
+/// The font has no replacement character, so invalid text is drawn as a
+/// small square instead. This keeps it distinct from unsupported characters.
+pub const REPLACEMENT_GLYPH: u8 = 0xfe;
+
+/// Glyphs 0x01-0x1F, which share their indices with the ASCII control codes
+const CONTROL_GLYPHS: [char; 31] = [
+  '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼',
+  '►', '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+/// Glyph 0x7F, in place of DEL
+const HOUSE_GLYPH: char = '⌂';
+
+/// Glyphs 0x80-0xFF
+const UPPER_GLYPHS: [char; 128] = [
+  'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+  'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+  'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+  '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+  '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+  '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+  'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+  '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+/// Find the glyph that draws a codepoint. Printable ASCII maps to itself, and
+/// so do the control codes, which the terminal handles before they get here.
+pub fn glyph_for(ch: char) -> u8 {
+  if (ch as u32) < 0x7f {
+    return ch as u8;
+  }
+  if ch == HOUSE_GLYPH {
+    return 0x7f;
+  }
+  if ch == core::char::REPLACEMENT_CHARACTER {
+    return REPLACEMENT_GLYPH;
+  }
+  if let Some(index) = UPPER_GLYPHS.iter().position(|glyph| *glyph == ch) {
+    return 0x80 + index as u8;
+  }
+  if let Some(index) = CONTROL_GLYPHS.iter().position(|glyph| *glyph == ch) {
+    return 1 + index as u8;
+  }
+  PLACEHOLDER_GLYPH
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{glyph_for, PLACEHOLDER_GLYPH, REPLACEMENT_GLYPH};
+
+  #[test]
+  fn codepoint_to_glyph() {
+    let table = [
+      ('A', 0x41),
+      ('~', 0x7e),
+      ('⌂', 0x7f),
+      ('Ç', 0x80),
+      ('é', 0x82),
+      ('ƒ', 0x9f),
+      ('½', 0xab),
+      ('░', 0xb0),
+      ('│', 0xb3),
+      ('┐', 0xbf),
+      ('└', 0xc0),
+      ('─', 0xc4),
+      ('╔', 0xc9),
+      ('═', 0xcd),
+      ('┘', 0xd9),
+      ('█', 0xdb),
+      ('ß', 0xe1),
+      ('µ', 0xe6),
+      ('°', 0xf8),
+      ('²', 0xfd),
+      ('\u{a0}', 0xff),
+      ('☺', 0x01),
+      ('♥', 0x03),
+      ('→', 0x1a),
+      ('▼', 0x1f),
+    ];
+    for (ch, glyph) in table.iter() {
+      assert_eq!(glyph_for(*ch), *glyph, "{:?}", ch);
+    }
+  }
+
+  #[test]
+  fn missing_glyphs() {
+    assert_eq!(glyph_for('€'), PLACEHOLDER_GLYPH);
+    assert_eq!(glyph_for('日'), PLACEHOLDER_GLYPH);
+    assert_eq!(glyph_for('\u{1f600}'), PLACEHOLDER_GLYPH);
+    assert_eq!(glyph_for(core::char::REPLACEMENT_CHARACTER), REPLACEMENT_GLYPH);
+  }
+}
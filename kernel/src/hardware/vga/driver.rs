@@ -1,11 +1,13 @@
-use core::sync::atomic::{AtomicU8, Ordering};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, AtomicU32, AtomicU8, Ordering};
 use crate::memory::address::{PhysicalAddress, SegmentedAddress, VirtualAddress};
 use crate::memory::physical::{self, frame::Frame};
 use crate::memory::virt::page_directory::{CurrentPageDirectory, PermissionFlags};
 use crate::task::id::ProcessID;
 use crate::task::ipc::{IPCMessage, IPCPacket};
 use crate::task::regs::EnvironmentRegisters;
-use spin::RwLock;
+use crate::locks::RwLock;
+use super::vbe::{self, ModeInfo, CONTROLLER_INFO_SIZE, USE_LINEAR_FRAMEBUFFER, VBE_SUCCESS};
 
 /// Stores the ProcessID of the VGA Driver once it is initialized
 pub static VGA_DRIVER_PID: RwLock<Option<ProcessID>> = RwLock::new(None);
@@ -14,8 +16,35 @@ pub static VGA_DRIVER_PID: RwLock<Option<ProcessID>> = RwLock::new(None);
 static CURRENT_REQUEST_PID: RwLock<Option<ProcessID>>  = RwLock::new(None);
 /// Stores the last confirmed setting of the video mode
 static CURRENT_VIDEO_MODE: AtomicU8 = AtomicU8::new(0x03);
+/// The kind of request the driver is currently handling
+static CURRENT_REQUEST_KIND: AtomicU32 = AtomicU32::new(0);
+
+/// Results of the most recent VBE call: the value of AX, a copy of the buffer
+/// that ES:DI pointed to, and for 4F00h, the supported mode numbers
+static VBE_STATUS: AtomicU32 = AtomicU32::new(0);
+static VBE_BUFFER: RwLock<[u8; CONTROLLER_INFO_SIZE]> = RwLock::new([0; CONTROLLER_INFO_SIZE]);
+static VBE_MODES: RwLock<Vec<u16>> = RwLock::new(Vec::new());
+/// VBE version reported by the controller, or 0 if VBE isn't supported
+static VBE_VERSION: AtomicU16 = AtomicU16::new(0);
+/// AX, BX, and FLAGS after the most recent INT 15h call
+static INT_15_RESULT: RwLock<(u16, u16, u16)> = RwLock::new((0, 0, 0));
 
 pub const MSG_MODE_SWITCH: u32 = 1;
+pub const MSG_VBE_CONTROLLER_INFO: u32 = 2;
+pub const MSG_VBE_MODE_INFO: u32 = 3;
+pub const MSG_VBE_SET_MODE: u32 = 4;
+pub const MSG_INT_15: u32 = 5;
+
+/// VBE calls write their results here, at 7F00:0000 in the VM86 stack page.
+/// The BIOS stack grows down from the top of the same page.
+const BIOS_BUFFER_SEGMENT: u32 = 0x7f00;
+const BIOS_BUFFER: usize = 0x7f000;
+/// BIOS calls return to a small stub that saves AX, BX, and FLAGS where the
+/// kernel can find them, and then exits VM86 mode
+const RESULT_STUB_OFFSET: u16 = 0x200;
+const RESULT_AX_OFFSET: u16 = 0x210;
+const RESULT_BX_OFFSET: u16 = 0x212;
+const RESULT_FLAGS_OFFSET: u16 = 0x214;
 
 /// The only reliable way to switch video modes is to use the code copied to
 /// BIOS for the installed video card. This is possible by spinning up a
@@ -61,10 +90,26 @@ pub fn send_request(message: IPCMessage, timeout: Option<usize>) {
     None => return,
   }
 
-  crate::task::get_current_process().write().hardware_block(timeout);
+  let deadline = timeout.map(|ms| {
+    crate::time::ticks::target_tick(crate::time::system::get_system_ticks(), ms)
+  });
+  crate::task::get_current_process().write().hardware_block(deadline);
   crate::task::yield_coop();
 }
 
+/// Run a BIOS INT 15h system service, like APM, with the given AX, BX, and
+/// CX. Returns AX, BX, and FLAGS as the BIOS left them, or None if the driver
+/// isn't running.
+pub fn call_int_15(ax: u16, bx: u16, cx: u16) -> Option<(u16, u16, u16)> {
+  if VGA_DRIVER_PID.read().is_none() {
+    return None;
+  }
+  *INT_15_RESULT.write() = (0, 0, 0);
+  let message = IPCMessage(MSG_INT_15, ax as u32, bx as u32, cx as u32);
+  send_request(message, None);
+  Some(*INT_15_RESULT.read())
+}
+
 /// Request a VGA graphics mode change
 pub fn request_mode_change(mode: u8) {
   let message = IPCMessage(MSG_MODE_SWITCH, mode as u32, 0, 0);
@@ -82,6 +127,35 @@ pub fn get_video_mode() -> u8 {
   CURRENT_VIDEO_MODE.load(Ordering::SeqCst)
 }
 
+/// Ask the BIOS which VBE modes it supports. Returns None if VBE 2.0 isn't
+/// available, in which case there is no linear framebuffer.
+pub fn query_vbe_modes() -> Option<Vec<u16>> {
+  send_request(IPCMessage(MSG_VBE_CONTROLLER_INFO, 0, 0, 0), None);
+  if VBE_VERSION.load(Ordering::SeqCst) == 0 {
+    return None;
+  }
+  Some(VBE_MODES.read().clone())
+}
+
+/// Fetch the description of a VBE mode, if it can be used with a linear
+/// framebuffer
+pub fn query_vbe_mode(mode: u16) -> Option<ModeInfo> {
+  send_request(IPCMessage(MSG_VBE_MODE_INFO, mode as u32, 0, 0), None);
+  if VBE_STATUS.load(Ordering::SeqCst) != VBE_SUCCESS {
+    return None;
+  }
+  vbe::parse_mode_info(&VBE_BUFFER.read()[..], VBE_VERSION.load(Ordering::SeqCst))
+}
+
+/// Switch to a VBE mode with its linear framebuffer enabled
+pub fn set_vbe_mode(mode: u16) -> Result<(), ()> {
+  send_request(IPCMessage(MSG_VBE_SET_MODE, (mode | USE_LINEAR_FRAMEBUFFER) as u32, 0, 0), None);
+  if VBE_STATUS.load(Ordering::SeqCst) != VBE_SUCCESS {
+    return Err(());
+  }
+  Ok(())
+}
+
 /// Internal logic for the graphics driver. It blocks on IPC requests until one
 /// is received, and parses that message to determine how to modify the VGA
 /// hardware.
@@ -89,11 +163,38 @@ extern "C" fn wait_for_message() {
   loop {
     let (ipc_packet, _) = crate::task::ipc_read(None);
     match ipc_packet {
-      Some(IPCPacket { from, message }) =>
+      Some(IPCPacket { from, message, .. }) =>
         match message {
           IPCMessage(MSG_MODE_SWITCH, mode, _, _) => {
             *CURRENT_REQUEST_PID.write() = Some(from);
-            change_mode(mode);
+            CURRENT_REQUEST_KIND.store(MSG_MODE_SWITCH, Ordering::SeqCst);
+            call_bios(0x10, mode, 0, 0);
+          },
+          IPCMessage(MSG_VBE_CONTROLLER_INFO, _, _, _) => {
+            *CURRENT_REQUEST_PID.write() = Some(from);
+            CURRENT_REQUEST_KIND.store(MSG_VBE_CONTROLLER_INFO, Ordering::SeqCst);
+            prepare_bios_buffer();
+            // Asking with a VBE2 signature returns the full 512-byte block
+            unsafe {
+              core::ptr::copy_nonoverlapping(b"VBE2".as_ptr(), BIOS_BUFFER as *mut u8, 4);
+            }
+            call_bios(0x10, 0x4f00, 0, 0);
+          },
+          IPCMessage(MSG_VBE_MODE_INFO, mode, _, _) => {
+            *CURRENT_REQUEST_PID.write() = Some(from);
+            CURRENT_REQUEST_KIND.store(MSG_VBE_MODE_INFO, Ordering::SeqCst);
+            prepare_bios_buffer();
+            call_bios(0x10, 0x4f01, 0, mode);
+          },
+          IPCMessage(MSG_VBE_SET_MODE, mode, _, _) => {
+            *CURRENT_REQUEST_PID.write() = Some(from);
+            CURRENT_REQUEST_KIND.store(MSG_VBE_SET_MODE, Ordering::SeqCst);
+            call_bios(0x10, 0x4f02, mode, 0);
+          },
+          IPCMessage(MSG_INT_15, ax, bx, cx) => {
+            *CURRENT_REQUEST_PID.write() = Some(from);
+            CURRENT_REQUEST_KIND.store(MSG_INT_15, Ordering::SeqCst);
+            call_bios(0x15, ax, bx, cx);
           },
           _ => {
             // unknown packet, just wake the caller
@@ -106,43 +207,75 @@ extern "C" fn wait_for_message() {
   }
 }
 
-extern "C" fn change_mode(mode: u32) {
-  let int_10_address: &SegmentedAddress = unsafe {
-    &*(0x40 as *const SegmentedAddress)
+/// Clear the VBE buffer, so that a call that fails without writing to it
+/// can't leave behind the results of an earlier call
+fn prepare_bios_buffer() {
+  unsafe {
+    core::ptr::write_bytes(BIOS_BUFFER as *mut u8, 0, CONTROLLER_INFO_SIZE);
+  }
+}
+
+fn push_u16(regs: &mut EnvironmentRegisters, value: u16) {
+  regs.esp -= 2;
+  unsafe {
+    *(((regs.ss << 4) + regs.esp) as *mut u16) = value;
+  }
+}
+
+/// Enter VM86 mode and run a BIOS interrupt handler with the given AX, BX,
+/// and CX. ES:DI points at the VBE buffer.
+extern "C" fn call_bios(vector: u8, eax: u32, ebx: u32, ecx: u32) {
+  let handler_address: &SegmentedAddress = unsafe {
+    &*((vector as usize * 4) as *const SegmentedAddress)
   };
-  // jump to INT 10h
+  // mov cs:[RESULT_AX_OFFSET], ax; mov cs:[RESULT_BX_OFFSET], bx; pushf;
+  // pop word cs:[RESULT_FLAGS_OFFSET]; iret
+  // Services like APM report failure in the carry flag, which they return
+  // with instead of the flags saved by the INT.
+  let [ax_low, ax_high] = RESULT_AX_OFFSET.to_le_bytes();
+  let [bx_low, bx_high] = RESULT_BX_OFFSET.to_le_bytes();
+  let [flags_low, flags_high] = RESULT_FLAGS_OFFSET.to_le_bytes();
+  let stub = [
+    0x2e, 0xa3, ax_low, ax_high,
+    0x2e, 0x89, 0x1e, bx_low, bx_high,
+    0x9c,
+    0x2e, 0x8f, 0x06, flags_low, flags_high,
+    0xcf,
+  ];
+  unsafe {
+    let stub_address = BIOS_BUFFER + RESULT_STUB_OFFSET as usize;
+    core::ptr::copy_nonoverlapping(stub.as_ptr(), stub_address as *mut u8, stub.len());
+  }
+  // jump to the interrupt handler
   let mut regs = EnvironmentRegisters {
-    eax: mode,
-    ecx: 0,
+    eax,
+    ecx,
     edx: 0,
-    ebx: 0,
+    ebx,
     ebp: 0,
     esi: 0,
     edi: 0,
 
-    eip: int_10_address.offset as u32,
-    cs: int_10_address.segment as u32,
+    eip: handler_address.offset as u32,
+    cs: handler_address.segment as u32,
     flags: 0x20200,
     esp: 0xfffe,
     ss: 0x7000,
 
-    es: 0x7000,
+    es: BIOS_BUFFER_SEGMENT,
     ds: 0x7000,
     fs: 0x7000,
     gs: 0x7000,
   };
-  // set up the stack
-  unsafe {
-    // push flags
-    *(0x7fffe as *mut u16) = 0;
-    regs.esp -= 2;
-    // push cs
-    *(0x7fffc as *mut u16) = 0x00;
-    regs.esp -= 2;
-    // push ip
-    *(0x7fffa as *mut u16) = 0;
-    regs.esp -= 2;
-  }
+  // set up the stack. The stub's IRET returns to 0000:0000, which exits VM86
+  // mode.
+  push_u16(&mut regs, 0);
+  push_u16(&mut regs, 0);
+  push_u16(&mut regs, 0);
+  // The interrupt handler returns into the stub
+  push_u16(&mut regs, 0);
+  push_u16(&mut regs, BIOS_BUFFER_SEGMENT as u16);
+  push_u16(&mut regs, RESULT_STUB_OFFSET);
 
   // copied from task::exec, can these be combined?
   unsafe {
@@ -171,18 +304,77 @@ extern "C" fn change_mode(mode: u32) {
   }
 }
 
+/// Copy the results of a VBE call out of VM86 memory. For 4F00h, the mode
+/// list is read as well, since its far pointer may lead anywhere in the low
+/// memory that only the driver has mapped.
+fn collect_vbe_results(kind: u32) {
+  let status = unsafe {
+    *((BIOS_BUFFER + RESULT_AX_OFFSET as usize) as *const u16)
+  };
+  VBE_STATUS.store(status as u32, Ordering::SeqCst);
+  let mut buffer = VBE_BUFFER.write();
+  unsafe {
+    core::ptr::copy_nonoverlapping(BIOS_BUFFER as *const u8, buffer.as_mut_ptr(), CONTROLLER_INFO_SIZE);
+  }
+  if kind != MSG_VBE_CONTROLLER_INFO {
+    return;
+  }
+  let controller = if status as u32 == VBE_SUCCESS {
+    vbe::parse_controller_info(&buffer[..])
+  } else {
+    None
+  };
+  let mut modes = VBE_MODES.write();
+  modes.clear();
+  match controller {
+    Some(info) => {
+      let length = mapped_bytes_at(info.mode_list).min(0x200);
+      let list = unsafe {
+        core::slice::from_raw_parts(info.mode_list as *const u8, length)
+      };
+      modes.extend(vbe::parse_mode_list(list));
+      VBE_VERSION.store(info.version, Ordering::SeqCst);
+    },
+    None => VBE_VERSION.store(0, Ordering::SeqCst),
+  }
+}
+
+/// Count the bytes that can be read from a low memory address before leaving
+/// the areas mapped by the driver process: the IVT and BIOS data, the VM86
+/// stack page, and the video and BIOS ROM area
+fn mapped_bytes_at(address: usize) -> usize {
+  let mapped = [0..0x1000, 0x7f000..0x80000, 0xa0000..0x100000];
+  mapped
+    .iter()
+    .find(|range| range.contains(&address))
+    .map(|range| range.end - address)
+    .unwrap_or(0)
+}
+
 extern "C" fn return_from_interrupt() {
+  let kind = CURRENT_REQUEST_KIND.load(Ordering::SeqCst);
   let current_video_mode = unsafe {
     *(0x449 as *const u8)
   };
-  CURRENT_VIDEO_MODE.store(current_video_mode, Ordering::SeqCst);
+  if kind == MSG_MODE_SWITCH {
+    CURRENT_VIDEO_MODE.store(current_video_mode, Ordering::SeqCst);
+    // The BIOS has reloaded the default palette and blink mode
+    super::text_mode::apply_text_palette();
+  } else if kind == MSG_INT_15 {
+    let read_result = |offset: u16| unsafe {
+      *((BIOS_BUFFER + offset as usize) as *const u16)
+    };
+    *INT_15_RESULT.write() = (read_result(RESULT_AX_OFFSET), read_result(RESULT_BX_OFFSET), read_result(RESULT_FLAGS_OFFSET));
+  } else {
+    collect_vbe_results(kind);
+  }
 
   let request_id = CURRENT_REQUEST_PID.write().take();
   request_id
     .and_then(|id| crate::task::switching::get_process(&id))
     .and_then(|proc| Some(proc.write().hardware_resume()));
 
-  if current_video_mode == 0x13 {
+  if kind == MSG_MODE_SWITCH && current_video_mode == 0x13 {
     unsafe {
       let base = 0xa0000 as *mut u8;
       for row in 0..8 {
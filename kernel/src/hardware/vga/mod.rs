@@ -1,3 +1,5 @@
+pub mod cp437;
 #[cfg(not(test))]
 pub mod driver;
-pub mod text_mode;
\ No newline at end of file
+pub mod text_mode;
+pub mod vbe;
@@ -1,6 +1,9 @@
 use core::fmt;
 use core::ptr::{read_volatile, write_volatile};
+use super::cp437::glyph_for;
 use crate::memory::address::VirtualAddress;
+use crate::locks::RwLock;
+use syscall::flags::CURSOR_HIDDEN;
 
 #[derive(Copy, Clone)]
 #[repr(u8)]
@@ -45,6 +48,259 @@ impl ColorCode {
   }
 }
 
+/// The CRT controller is programmed by writing a register index, followed by
+/// the value for that register
+const CRTC_INDEX_PORT: u16 = 0x3d4;
+const CRTC_DATA_PORT: u16 = 0x3d5;
+/// The low five bits hold the last scan line of each character, which is one
+/// less than the character height
+const CRTC_MAXIMUM_SCAN_LINE: u8 = 0x09;
+/// Bit 5 of the Cursor Start register disables the hardware cursor
+const CRTC_CURSOR_START: u8 = 0x0a;
+const CURSOR_DISABLE: u8 = 0x20;
+/// The upper bits of the Cursor End register hold the cursor skew
+const CRTC_CURSOR_END: u8 = 0x0b;
+/// Scan lines take up the low five bits of each of these registers
+const SCAN_LINE_MASK: u8 = 0x1f;
+/// The cursor location is a character offset, split across two registers
+const CRTC_CURSOR_LOCATION_HIGH: u8 = 0x0e;
+const CRTC_CURSOR_LOCATION_LOW: u8 = 0x0f;
+
+/// Compute the CRTC register writes that place the hardware cursor at a
+/// column and row of the 80x25 text screen
+pub fn cursor_location_registers(col: u8, row: u8) -> [(u8, u8); 2] {
+  let offset = row as u16 * 80 + col as u16;
+  [
+    (CRTC_CURSOR_LOCATION_HIGH, (offset >> 8) as u8),
+    (CRTC_CURSOR_LOCATION_LOW, (offset & 0xff) as u8),
+  ]
+}
+
+/// Move the blinking hardware cursor to a location on screen
+#[cfg(not(test))]
+pub fn set_hardware_cursor(col: u8, row: u8) {
+  use crate::x86::io::outb;
+  for (index, value) in cursor_location_registers(col, row).iter() {
+    unsafe {
+      outb(CRTC_INDEX_PORT, *index);
+      outb(CRTC_DATA_PORT, *value);
+    }
+  }
+}
+#[cfg(test)]
+pub fn set_hardware_cursor(_col: u8, _row: u8) {}
+
+/// The scan lines the text cursor covers, counted from the top of the
+/// character cell, and whether programs want it shown at all
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct CursorShape {
+  pub start: u8,
+  pub end: u8,
+  pub hidden: bool,
+}
+
+impl CursorShape {
+  /// The underline the BIOS sets up for the 16-line font of mode 3
+  pub const fn new() -> CursorShape {
+    CursorShape {
+      start: 13,
+      end: 14,
+      hidden: false,
+    }
+  }
+
+  /// Decode the argument of TIOCSCURSOR: the end scan line in the low byte,
+  /// the start scan line above it, and CURSOR_HIDDEN
+  pub fn from_ioctl(arg: u32) -> Result<CursorShape, ()> {
+    if arg & !(CURSOR_HIDDEN | 0xffff) != 0 {
+      return Err(());
+    }
+    Ok(CursorShape {
+      start: (arg >> 8) as u8,
+      end: arg as u8,
+      hidden: arg & CURSOR_HIDDEN != 0,
+    })
+  }
+
+  pub fn as_ioctl(&self) -> u32 {
+    let hidden = if self.hidden { CURSOR_HIDDEN } else { 0 };
+    hidden | ((self.start as u32) << 8) | self.end as u32
+  }
+}
+
+/// Program the cursor registers with a shape. Scan lines past the bottom of
+/// the current character cell are clamped to it. The cursor is disabled if
+/// the shape is hidden, or the caller doesn't want it visible right now.
+/// The other bits of each register are preserved.
+pub fn write_cursor_shape<P: VgaPorts>(ports: &P, shape: &CursorShape, visible: bool) {
+  ports.write(CRTC_INDEX_PORT, CRTC_MAXIMUM_SCAN_LINE);
+  let last_line = ports.read(CRTC_DATA_PORT) & SCAN_LINE_MASK;
+  let disable = if visible && !shape.hidden { 0 } else { CURSOR_DISABLE };
+
+  ports.write(CRTC_INDEX_PORT, CRTC_CURSOR_START);
+  let start = ports.read(CRTC_DATA_PORT) & !(SCAN_LINE_MASK | CURSOR_DISABLE);
+  ports.write(CRTC_DATA_PORT, start | disable | shape.start.min(last_line));
+
+  ports.write(CRTC_INDEX_PORT, CRTC_CURSOR_END);
+  let end = ports.read(CRTC_DATA_PORT) & !SCAN_LINE_MASK;
+  ports.write(CRTC_DATA_PORT, end | shape.end.min(last_line));
+}
+
+/// The cursor shape chosen by programs. Like the palette, it belongs to the
+/// VGA card, so every vterm shares it.
+pub static CURSOR_SHAPE: RwLock<CursorShape> = RwLock::new(CursorShape::new());
+
+/// Show or hide the hardware cursor. When shown, it takes the shape programs
+/// have chosen, and a cursor they have hidden stays hidden.
+#[cfg(not(test))]
+pub fn set_hardware_cursor_visible(visible: bool) {
+  write_cursor_shape(&HardwarePorts, &CURSOR_SHAPE.read(), visible);
+}
+#[cfg(test)]
+pub fn set_hardware_cursor_visible(_visible: bool) {}
+
+/// Program the stored cursor shape into the card, if it is currently in text
+/// mode. Other modes have no hardware cursor.
+#[cfg(not(test))]
+pub fn apply_cursor_shape() {
+  if super::driver::get_video_mode() == 0x03 {
+    set_hardware_cursor_visible(true);
+  }
+}
+#[cfg(test)]
+pub fn apply_cursor_shape() {}
+
+/// The attribute controller shares a single port for its index and data. A
+/// flip-flop decides which one the next write is; reading the input status
+/// register resets it to expect an index.
+const ATTRIBUTE_ADDRESS_PORT: u16 = 0x3c0;
+const ATTRIBUTE_DATA_READ_PORT: u16 = 0x3c1;
+const INPUT_STATUS_PORT: u16 = 0x3da;
+/// Set in every index written to the attribute controller. Clearing it hands
+/// the palette to the CPU, which blanks the screen.
+const ATTRIBUTE_PALETTE_SOURCE: u8 = 0x20;
+const ATTRIBUTE_MODE_CONTROL: u8 = 0x10;
+/// When set, the high bit of a character's attribute makes it blink. When
+/// cleared, it selects one of the bright background colors instead.
+const MODE_CONTROL_BLINK: u8 = 0x08;
+/// DAC entries are set by writing the first index, followed by the red,
+/// green, and blue components of each entry
+const DAC_WRITE_INDEX_PORT: u16 = 0x3c8;
+const DAC_DATA_PORT: u16 = 0x3c9;
+
+/// In text mode, the attribute controller maps the 16 text colors onto these
+/// DAC entries, for compatibility with the EGA
+pub const TEXT_PALETTE_DAC_INDEX: [u8; 16] = [
+  0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x14, 0x07,
+  0x38, 0x39, 0x3a, 0x3b, 0x3c, 0x3d, 0x3e, 0x3f,
+];
+
+/// The colors loaded by the BIOS, as 6-bit red, green, and blue components
+pub const DEFAULT_TEXT_PALETTE: [[u8; 3]; 16] = [
+  [0, 0, 0], [0, 0, 42], [0, 42, 0], [0, 42, 42],
+  [42, 0, 0], [42, 0, 42], [42, 21, 0], [42, 42, 42],
+  [21, 21, 21], [21, 21, 63], [21, 63, 21], [21, 63, 63],
+  [63, 21, 21], [63, 21, 63], [63, 63, 21], [63, 63, 63],
+];
+
+/// Access to the VGA registers used to configure text colors. Real hardware
+/// is reached through IO ports; tests substitute a recording.
+pub trait VgaPorts {
+  fn read(&self, port: u16) -> u8;
+
+  fn write(&self, port: u16, value: u8);
+}
+
+#[cfg(not(test))]
+pub struct HardwarePorts;
+
+#[cfg(not(test))]
+impl VgaPorts for HardwarePorts {
+  fn read(&self, port: u16) -> u8 {
+    unsafe { crate::x86::io::inb(port) }
+  }
+
+  fn write(&self, port: u16, value: u8) {
+    unsafe { crate::x86::io::outb(port, value) }
+  }
+}
+
+/// Choose between blinking text and bright backgrounds, by updating the mode
+/// control register of the attribute controller. The other mode bits are
+/// preserved.
+pub fn write_blink_mode<P: VgaPorts>(ports: &P, blink: bool) {
+  ports.read(INPUT_STATUS_PORT);
+  ports.write(ATTRIBUTE_ADDRESS_PORT, ATTRIBUTE_MODE_CONTROL | ATTRIBUTE_PALETTE_SOURCE);
+  let mode = ports.read(ATTRIBUTE_DATA_READ_PORT);
+  let updated = if blink { mode | MODE_CONTROL_BLINK } else { mode & !MODE_CONTROL_BLINK };
+  // Reading the data port leaves the flip-flop alone, so this write is data.
+  // Afterwards, the flip-flop expects an index again.
+  ports.write(ATTRIBUTE_ADDRESS_PORT, updated);
+}
+
+/// Program the DAC entry used by one of the 16 text colors. Components only
+/// have 6 bits.
+pub fn write_text_color<P: VgaPorts>(ports: &P, color: usize, rgb: [u8; 3]) {
+  ports.write(DAC_WRITE_INDEX_PORT, TEXT_PALETTE_DAC_INDEX[color]);
+  for component in rgb.iter() {
+    ports.write(DAC_DATA_PORT, component & 0x3f);
+  }
+}
+
+/// Text colors and blink mode chosen by the user. Setting a video mode through
+/// the BIOS reloads the defaults, so these are applied again whenever the card
+/// returns to text mode.
+pub struct TextPalette {
+  colors: [[u8; 3]; 16],
+  blink: bool,
+}
+
+impl TextPalette {
+  pub const fn new() -> TextPalette {
+    TextPalette {
+      colors: DEFAULT_TEXT_PALETTE,
+      blink: true,
+    }
+  }
+
+  /// Replace one of the 16 colors, returning the previous value
+  pub fn set_color(&mut self, color: usize, rgb: [u8; 3]) -> Result<[u8; 3], ()> {
+    let entry = self.colors.get_mut(color).ok_or(())?;
+    let previous = *entry;
+    *entry = [rgb[0] & 0x3f, rgb[1] & 0x3f, rgb[2] & 0x3f];
+    Ok(previous)
+  }
+
+  /// Enable or disable blinking, returning the previous setting
+  pub fn set_blink(&mut self, blink: bool) -> bool {
+    core::mem::replace(&mut self.blink, blink)
+  }
+
+  pub fn apply<P: VgaPorts>(&self, ports: &P) {
+    for (color, rgb) in self.colors.iter().enumerate() {
+      write_text_color(ports, color, *rgb);
+    }
+    write_blink_mode(ports, self.blink);
+  }
+}
+
+pub static TEXT_PALETTE: RwLock<TextPalette> = RwLock::new(TextPalette::new());
+
+/// Program the stored palette into the card, if it is currently in text mode.
+/// In other modes, it will be applied when text mode is restored.
+#[cfg(not(test))]
+pub fn apply_text_palette() {
+  if super::driver::get_video_mode() == 0x03 {
+    TEXT_PALETTE.read().apply(&HardwarePorts);
+  }
+}
+#[cfg(test)]
+pub fn apply_text_palette() {}
+
+/// Bytes in one row of the 80x25 text screen: a character and an attribute
+/// for each column
+const ROW_BYTES: usize = 80 * 2;
+
 pub struct TextMode {
   base_pointer: *mut u8,
 
@@ -52,6 +308,16 @@ pub struct TextMode {
   cursor_row: u8,
   
   current_color: ColorCode,
+
+  /// In buffered mode, printable characters are collected here instead of
+  /// being written to video memory one at a time. The run always covers
+  /// consecutive cells of a single row, starting at `pending_col` and
+  /// `pending_row`, and ends at the cursor.
+  buffered: bool,
+  pending: [u8; ROW_BYTES],
+  pending_len: usize,
+  pending_col: u8,
+  pending_row: u8,
 }
 
 impl TextMode {
@@ -61,8 +327,34 @@ impl TextMode {
       cursor_col: 0,
       cursor_row: 24,
       current_color: ColorCode::new(Color::LightGrey, Color::Black),
+      buffered: false,
+      pending: [0; ROW_BYTES],
+      pending_len: 0,
+      pending_col: 0,
+      pending_row: 0,
     }
   }
+
+  /// Enable or disable buffered output. When enabled, characters on the
+  /// current row are held back until a newline, a cursor movement, or any
+  /// other change to the screen, and then copied to video memory at once.
+  /// Callers need to `flush` before the screen is shown or copied elsewhere.
+  pub fn set_buffered(&mut self, buffered: bool) {
+    self.flush();
+    self.buffered = buffered;
+  }
+
+  /// Copy any characters held back by buffered mode to video memory
+  pub fn flush(&mut self) {
+    if self.pending_len == 0 {
+      return;
+    }
+    let offset = (self.pending_row as usize) * ROW_BYTES + (self.pending_col as usize) * 2;
+    unsafe {
+      core::ptr::copy_nonoverlapping(self.pending.as_ptr(), self.base_pointer.add(offset), self.pending_len);
+    }
+    self.pending_len = 0;
+  }
   
   pub fn set_fg_color(&mut self, color: Color) {
     self.current_color = self.current_color.set_fg(color);
@@ -77,6 +369,7 @@ impl TextMode {
   }
 
   pub fn clear_screen(&mut self) {
+    self.flush();
     let mut offset = 0;
     unsafe {
       while offset < 2 * 80 * 25 {
@@ -87,6 +380,7 @@ impl TextMode {
   }
 
   pub fn clear_screen_to_beginning(&mut self) {
+    self.flush();
     let mut offset = 0;
     let limit = (self.cursor_col as isize) + (self.cursor_row as isize * 80);
     unsafe {
@@ -98,6 +392,7 @@ impl TextMode {
   }
 
   pub fn clear_screen_to_end(&mut self) {
+    self.flush();
     let mut offset = (self.cursor_col as isize) + (self.cursor_row as isize * 80) * 2;
     unsafe {
       while offset < 2 * 80 * 25 {
@@ -108,6 +403,7 @@ impl TextMode {
   }
 
   pub fn clear_row(&mut self) {
+    self.flush();
     let mut offset = self.cursor_row as isize * 80 * 2;
     let limit = offset + 80 * 2;
     unsafe {
@@ -119,6 +415,7 @@ impl TextMode {
   }
 
   pub fn clear_row_to_beginning(&mut self) {
+    self.flush();
     let mut offset = self.cursor_row as isize * 80 * 2;
     let limit = offset + (self.cursor_col as isize) * 2;
     unsafe {
@@ -130,6 +427,7 @@ impl TextMode {
   }
 
   pub fn clear_row_to_end(&mut self) {
+    self.flush();
     let mut offset = (self.cursor_row as isize * 80 * 2) + (self.cursor_col as isize * 2);
     let limit = (self.cursor_row as isize + 1) * 80 * 2;
     unsafe {
@@ -140,7 +438,11 @@ impl TextMode {
     }
   }
 
+  /// Move the screen contents up, filling the new rows at the bottom with
+  /// blanks in the current color. The remaining rows are moved with a single
+  /// copy, rather than one cell at a time.
   pub fn scroll(&mut self, rows: u8) {
+    self.flush();
     if rows == 0 {
       return;
     }
@@ -148,30 +450,23 @@ impl TextMode {
       self.clear_screen();
       return;
     }
-    let mut dest = self.base_pointer;
-    let scroll_rows = 25 - rows;
-    let offset = (rows as isize) * 80 * 2;
+    let kept_bytes = (25 - rows as usize) * ROW_BYTES;
+    let mut blank_row = [0; ROW_BYTES];
+    for cell in blank_row.chunks_exact_mut(2) {
+      cell[0] = 0x20;
+      cell[1] = self.current_color.as_u8();
+    }
     unsafe {
-      for _i in 0..scroll_rows {
-        for _j in 0..80 {
-          let value = read_volatile(dest.offset(offset));
-          let color = read_volatile(dest.offset(offset + 1));
-          write_volatile(dest, value);
-          write_volatile(dest.offset(1), color);
-          dest = dest.offset(2);
-        }
-      }
-      for _i in 0..rows {
-        for _j in 0..80 {
-          write_volatile(dest, 0x20);
-          write_volatile(dest.offset(1), self.current_color.as_u8());
-          dest = dest.offset(2);
-        }
+      core::ptr::copy(self.base_pointer.add(rows as usize * ROW_BYTES), self.base_pointer, kept_bytes);
+      for row in 0..(rows as usize) {
+        let dest = self.base_pointer.add(kept_bytes + row * ROW_BYTES);
+        core::ptr::copy_nonoverlapping(blank_row.as_ptr(), dest, ROW_BYTES);
       }
     }
   }
 
   pub fn newline(&mut self) {
+    self.flush();
     self.cursor_col = 0;
     if self.cursor_row < 24 {
       self.cursor_row += 1;
@@ -189,6 +484,7 @@ impl TextMode {
   }
 
   pub fn backspace(&mut self) {
+    self.flush();
     if self.cursor_col > 0 {
       self.cursor_col -= 1;
       self.set_current_character(b' ');
@@ -199,14 +495,21 @@ impl TextMode {
     }
   }
 
-  pub fn set_current_character(&self, ch: u8) {
+  pub fn set_current_character(&mut self, ch: u8) {
+    self.flush();
     let offset = (self.cursor_row as isize) * 160 + (self.cursor_col as isize) * 2;
     unsafe {
       write_volatile(self.base_pointer.offset(offset), ch);
     }
   }
 
+  /// Current cursor location, as a (column, row) pair
+  pub fn get_cursor(&self) -> (u8, u8) {
+    (self.cursor_col, self.cursor_row)
+  }
+
   pub fn move_cursor(&mut self, col: u8, row: u8) {
+    self.flush();
     self.cursor_col = col;
     if self.cursor_col > 79 {
       self.cursor_col = 79;
@@ -218,6 +521,7 @@ impl TextMode {
   }
 
   pub fn move_cursor_relative(&mut self, dcol: isize, drow: isize) {
+    self.flush();
     let new_col = self.cursor_col as isize + dcol;
     self.cursor_col = if new_col < 0 {
       0
@@ -236,7 +540,8 @@ impl TextMode {
     };
   }
 
-  pub fn invert_cursor(&self) {
+  pub fn invert_cursor(&mut self) {
+    self.flush();
     let offset = (self.cursor_row as isize) * 160 + (self.cursor_col as isize) * 2;
     unsafe {
       let cursor_color_ptr = self.base_pointer.offset(offset + 1);
@@ -246,7 +551,8 @@ impl TextMode {
     }
   }
 
-  pub fn disable_cursor(&self) {
+  pub fn disable_cursor(&mut self) {
+    self.flush();
     let offset = (self.cursor_row as isize) * 160 + (self.cursor_col as isize) * 2;
     unsafe {
       let cursor_color_ptr = self.base_pointer.offset(offset + 1);
@@ -260,23 +566,49 @@ impl TextMode {
         self.disable_cursor();
         self.newline()
       },
-      0x20..=0x7e => unsafe {
-        let offset = (self.cursor_row as isize) * 160 + (self.cursor_col as isize) * 2;
-        write_volatile(self.base_pointer.offset(offset), byte);
-        write_volatile(self.base_pointer.offset(offset + 1), self.current_color.as_u8());
-        self.advance_cursor();
-      },
+      0x20..=0x7e | 0x80..=0xff => self.write_glyph(byte),
       _ => (),
     }
   }
 
+  /// Draw any glyph from the font at the cursor, and advance it. Unlike
+  /// `write_byte`, control codes are drawn rather than interpreted.
+  pub fn write_glyph(&mut self, glyph: u8) {
+    if self.buffered {
+      if self.pending_len == 0 {
+        self.pending_col = self.cursor_col;
+        self.pending_row = self.cursor_row;
+      }
+      self.pending[self.pending_len] = glyph;
+      self.pending[self.pending_len + 1] = self.current_color.as_u8();
+      self.pending_len += 2;
+    } else {
+      let offset = (self.cursor_row as isize) * 160 + (self.cursor_col as isize) * 2;
+      unsafe {
+        write_volatile(self.base_pointer.offset(offset), glyph);
+        write_volatile(self.base_pointer.offset(offset + 1), self.current_color.as_u8());
+      }
+    }
+    // Reaching the end of the row flushes the run before wrapping
+    self.advance_cursor();
+  }
+
+  /// Kernel strings are UTF-8, so anything beyond ASCII is drawn with its
+  /// code page glyph
   pub fn write_string(&mut self, s: &str) {
-    for byte in s.bytes() {
-      self.write_byte(byte);
+    for ch in s.chars() {
+      if ch.is_ascii() {
+        self.write_byte(ch as u8);
+      } else {
+        self.write_glyph(glyph_for(ch));
+      }
     }
   }
 
+  /// Point the screen at a different buffer. Pending characters are written
+  /// to the old one first.
   pub fn set_buffer_pointer(&mut self, ptr: usize) -> usize {
+    self.flush();
     let current_ptr = self.base_pointer as usize;
     self.base_pointer = ptr as *mut u8;
     current_ptr
@@ -289,3 +621,245 @@ impl fmt::Write for TextMode {
     Ok(())
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec;
+  use alloc::vec::Vec;
+  use core::cell::{Cell, RefCell};
+  use crate::memory::address::VirtualAddress;
+  use super::{
+    cursor_location_registers, write_blink_mode, write_cursor_shape, Color, CursorShape, TextMode, TextPalette,
+    VgaPorts, DEFAULT_TEXT_PALETTE,
+  };
+
+  #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+  enum Access {
+    Read(u16),
+    Write(u16, u8),
+  }
+
+  /// Records every port access, answering reads of the attribute data port
+  /// with a fixed mode control value, and reads of CRTC registers from a
+  /// table
+  struct RecordedPorts {
+    accesses: RefCell<Vec<Access>>,
+    mode_control: u8,
+    crtc_index: Cell<u8>,
+    crtc: [u8; 0x19],
+  }
+
+  impl RecordedPorts {
+    fn new(mode_control: u8) -> RecordedPorts {
+      RecordedPorts {
+        accesses: RefCell::new(Vec::new()),
+        mode_control,
+        crtc_index: Cell::new(0),
+        crtc: [0; 0x19],
+      }
+    }
+  }
+
+  impl VgaPorts for RecordedPorts {
+    fn read(&self, port: u16) -> u8 {
+      self.accesses.borrow_mut().push(Access::Read(port));
+      match port {
+        0x3c1 => self.mode_control,
+        0x3d5 => self.crtc[self.crtc_index.get() as usize],
+        _ => 0,
+      }
+    }
+
+    fn write(&self, port: u16, value: u8) {
+      self.accesses.borrow_mut().push(Access::Write(port, value));
+      if port == 0x3d4 {
+        self.crtc_index.set(value);
+      }
+    }
+  }
+
+  #[test]
+  fn cursor_register_values() {
+    assert_eq!(cursor_location_registers(0, 0), [(0x0e, 0), (0x0f, 0)]);
+    assert_eq!(cursor_location_registers(5, 0), [(0x0e, 0), (0x0f, 5)]);
+    // Row 3 starts at offset 240
+    assert_eq!(cursor_location_registers(0, 3), [(0x0e, 0), (0x0f, 240)]);
+    // 4 * 80 + 16 = 336 = 0x150
+    assert_eq!(cursor_location_registers(16, 4), [(0x0e, 0x01), (0x0f, 0x50)]);
+    // The bottom right cell, 1999 = 0x7cf
+    assert_eq!(cursor_location_registers(79, 24), [(0x0e, 0x07), (0x0f, 0xcf)]);
+  }
+
+  #[test]
+  fn cursor_shape_registers() {
+    let mut ports = RecordedPorts::new(0);
+    // Mode 3: 16-line characters, and the BIOS underline. The upper bits of
+    // the maximum scan line and cursor end registers are left alone.
+    ports.crtc[0x09] = 0x4f;
+    ports.crtc[0x0a] = 0x0d;
+    ports.crtc[0x0b] = 0x2e;
+    let block = CursorShape { start: 0, end: 15, hidden: false };
+    write_cursor_shape(&ports, &block, true);
+    assert_eq!(
+      *ports.accesses.borrow(),
+      [
+        Access::Write(0x3d4, 0x09),
+        Access::Read(0x3d5),
+        Access::Write(0x3d4, 0x0a),
+        Access::Read(0x3d5),
+        Access::Write(0x3d5, 0x00),
+        Access::Write(0x3d4, 0x0b),
+        Access::Read(0x3d5),
+        Access::Write(0x3d5, 0x2f),
+      ],
+    );
+
+    let written = |ports: &RecordedPorts| {
+      let accesses = ports.accesses.borrow();
+      (accesses[accesses.len() - 4], accesses[accesses.len() - 1])
+    };
+    let underline = CursorShape { start: 14, end: 15, hidden: false };
+    write_cursor_shape(&ports, &underline, true);
+    assert_eq!(written(&ports), (Access::Write(0x3d5, 0x0e), Access::Write(0x3d5, 0x2f)));
+
+    // Hidden by the program, or while the vterm isn't showing text
+    write_cursor_shape(&ports, &CursorShape { hidden: true, ..underline }, true);
+    assert_eq!(written(&ports).0, Access::Write(0x3d5, 0x2e));
+    write_cursor_shape(&ports, &underline, false);
+    assert_eq!(written(&ports).0, Access::Write(0x3d5, 0x2e));
+
+    // A block for 16-line characters is clamped to 8-line ones
+    ports.crtc[0x09] = 0x07;
+    write_cursor_shape(&ports, &block, true);
+    assert_eq!(written(&ports), (Access::Write(0x3d5, 0x00), Access::Write(0x3d5, 0x27)));
+  }
+
+  #[test]
+  fn cursor_shape_ioctl() {
+    let shape = CursorShape::from_ioctl(0x1_0e0f).unwrap();
+    assert_eq!(shape, CursorShape { start: 14, end: 15, hidden: true });
+    assert_eq!(shape.as_ioctl(), 0x1_0e0f);
+    assert!(CursorShape::from_ioctl(0x2_0000).is_err());
+  }
+
+  #[test]
+  fn attribute_controller_sequence() {
+    // Mode 3 starts with blinking and line graphics enabled
+    let ports = RecordedPorts::new(0x0c);
+    write_blink_mode(&ports, false);
+    assert_eq!(
+      *ports.accesses.borrow(),
+      [
+        // Reset the flip-flop, select the mode control register while
+        // keeping the screen enabled, then read and write it back
+        Access::Read(0x3da),
+        Access::Write(0x3c0, 0x30),
+        Access::Read(0x3c1),
+        Access::Write(0x3c0, 0x04),
+      ],
+    );
+
+    let ports = RecordedPorts::new(0x04);
+    write_blink_mode(&ports, true);
+    assert_eq!(ports.accesses.borrow()[3], Access::Write(0x3c0, 0x0c));
+  }
+
+  #[test]
+  fn palette_reapplied() {
+    let mut palette = TextPalette::new();
+    assert_eq!(palette.set_color(6, [63, 32, 0xff]), Ok(DEFAULT_TEXT_PALETTE[6]));
+    assert_eq!(palette.set_color(16, [0, 0, 0]), Err(()));
+    assert_eq!(palette.set_blink(false), true);
+
+    let ports = RecordedPorts::new(0x0c);
+    palette.apply(&ports);
+    let accesses = ports.accesses.borrow();
+    // Four writes for each color, followed by the blink mode
+    assert_eq!(accesses.len(), 16 * 4 + 4);
+    assert_eq!(accesses[0..4], [
+      Access::Write(0x3c8, 0),
+      Access::Write(0x3c9, 0),
+      Access::Write(0x3c9, 0),
+      Access::Write(0x3c9, 0),
+    ]);
+    // Brown is stored in DAC entry 0x14, and components are 6 bits
+    assert_eq!(accesses[24..28], [
+      Access::Write(0x3c8, 0x14),
+      Access::Write(0x3c9, 63),
+      Access::Write(0x3c9, 32),
+      Access::Write(0x3c9, 0x3f),
+    ]);
+    assert_eq!(accesses[60], Access::Write(0x3c8, 0x3f));
+    assert_eq!(accesses[67], Access::Write(0x3c0, 0x04));
+  }
+
+  /// Write the same output to an unbuffered and a buffered screen
+  fn write_both(direct: &mut TextMode, buffered: &mut TextMode, s: &str) {
+    direct.write_string(s);
+    buffered.write_string(s);
+  }
+
+  #[test]
+  fn buffered_output_matches_direct() {
+    let mut direct_screen = vec![0u8; 80 * 25 * 2];
+    let mut buffered_screen = vec![0u8; 80 * 25 * 2];
+    let mut direct = TextMode::new(VirtualAddress::new(direct_screen.as_mut_ptr() as usize));
+    let mut buffered = TextMode::new(VirtualAddress::new(buffered_screen.as_mut_ptr() as usize));
+    buffered.set_buffered(true);
+    direct.clear_screen();
+    buffered.clear_screen();
+
+    // Held back until the end of the line
+    write_both(&mut direct, &mut buffered, "hello");
+    assert_eq!(buffered_screen[24 * 160], 0x20);
+    assert_eq!(direct_screen[24 * 160], b'h');
+    write_both(&mut direct, &mut buffered, ", world\n");
+    assert_eq!(buffered_screen, direct_screen);
+
+    // Long lines wrap, and every wrap and newline scrolls
+    let long_line = "0123456789".repeat(17);
+    write_both(&mut direct, &mut buffered, &long_line);
+    direct.set_fg_color(Color::LightRed);
+    buffered.set_fg_color(Color::LightRed);
+    write_both(&mut direct, &mut buffered, "red\nline");
+    direct.move_cursor(10, 3);
+    buffered.move_cursor(10, 3);
+    write_both(&mut direct, &mut buffered, "moved");
+    direct.backspace();
+    buffered.backspace();
+    direct.scroll(2);
+    buffered.scroll(2);
+    write_both(&mut direct, &mut buffered, "tail");
+    assert_eq!(buffered.get_cursor(), direct.get_cursor());
+    buffered.flush();
+    assert_eq!(buffered_screen, direct_screen);
+    // The colored text followed the end of the long line, two rows up
+    assert_eq!(direct_screen[21 * 160 + 20], b'r');
+    assert_eq!(direct_screen[21 * 160 + 21], 0x0c);
+
+    // Switching buffers writes pending text to the old one
+    let mut other_screen = vec![0u8; 80 * 25 * 2];
+    buffered.write_string("!");
+    buffered.set_buffer_pointer(other_screen.as_mut_ptr() as usize);
+    assert_eq!(buffered_screen[3 * 160 + 36], b'!');
+    assert_eq!(other_screen[3 * 160 + 36], 0);
+  }
+
+  #[test]
+  fn code_page_glyphs() {
+    let mut screen = vec![0u8; 80 * 25 * 2];
+    let mut text = TextMode::new(VirtualAddress::new(screen.as_mut_ptr() as usize));
+    text.move_cursor(0, 0);
+    text.write_string("╔═ 25°C €");
+    let glyphs: Vec<u8> = screen[..18].iter().step_by(2).copied().collect();
+    assert_eq!(glyphs, [0xc9, 0xcd, b' ', b'2', b'5', 0xf8, b'C', b' ', b'?']);
+    // Code page bytes from the keyboard are drawn as-is, but control codes
+    // are only drawn when asked for directly
+    text.write_byte(0x82);
+    text.write_byte(0x01);
+    text.write_glyph(0x01);
+    assert_eq!(screen[18], 0x82);
+    assert_eq!(screen[20], 0x01);
+    assert_eq!(text.get_cursor(), (11, 0));
+  }
+}
@@ -0,0 +1,234 @@
+//! VESA BIOS Extensions describe graphics modes beyond what plain VGA offers,
+//! and let them be drawn through a linear framebuffer: a single range of
+//! physical memory covering the whole screen, instead of the 64KiB window at
+//! 0xA0000. The BIOS fills in a controller info block (INT 10h AX=4F00h),
+//! listing the mode numbers it supports, and a mode info block for each mode
+//! (AX=4F01h). This module parses those blocks once the VGA driver has copied
+//! them out of VM86 memory.
+//! Linear framebuffers need VBE 2.0. Machines without it fall back to mode
+//! 13h, whose 320x200 framebuffer sits at 0xA0000.
+
+use alloc::vec::Vec;
+use crate::memory::address::PhysicalAddress;
+
+/// Returned in AX by every VBE function that succeeds
+pub const VBE_SUCCESS: u32 = 0x004f;
+
+pub const CONTROLLER_INFO_SIZE: usize = 512;
+pub const MODE_INFO_SIZE: usize = 256;
+
+/// Set in the mode number passed to 4F02h to use the linear framebuffer
+pub const USE_LINEAR_FRAMEBUFFER: u16 = 0x4000;
+
+/// Marks the end of the controller's mode list
+const MODE_LIST_END: u16 = 0xffff;
+
+const ATTRIBUTE_SUPPORTED: u16 = 1;
+const ATTRIBUTE_GRAPHICS: u16 = 1 << 4;
+const ATTRIBUTE_LINEAR_FRAMEBUFFER: u16 = 1 << 7;
+
+/// Memory models that store each pixel in consecutive bytes
+const MODEL_PACKED_PIXEL: u8 = 4;
+const MODEL_DIRECT_COLOR: u8 = 6;
+
+fn read_u16(buffer: &[u8], offset: usize) -> u16 {
+  u16::from_le_bytes([buffer[offset], buffer[offset + 1]])
+}
+
+fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+  u32::from_le_bytes([buffer[offset], buffer[offset + 1], buffer[offset + 2], buffer[offset + 3]])
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ControllerInfo {
+  /// BCD version, like 0x0300 for VBE 3.0
+  pub version: u16,
+  /// Real-mode far pointer to the list of supported mode numbers, as a linear
+  /// address
+  pub mode_list: usize,
+  /// Video memory, in bytes
+  pub total_memory: usize,
+}
+
+/// Parse the block returned by 4F00h. Fails if the block doesn't carry the
+/// VESA signature, or describes a version without linear framebuffers.
+pub fn parse_controller_info(buffer: &[u8]) -> Option<ControllerInfo> {
+  if buffer.len() < CONTROLLER_INFO_SIZE || &buffer[0..4] != b"VESA" {
+    return None;
+  }
+  let version = read_u16(buffer, 0x04);
+  if version < 0x0200 {
+    return None;
+  }
+  let mode_list_offset = read_u16(buffer, 0x0e) as usize;
+  let mode_list_segment = read_u16(buffer, 0x10) as usize;
+  Some(ControllerInfo {
+    version,
+    mode_list: (mode_list_segment << 4) + mode_list_offset,
+    total_memory: read_u16(buffer, 0x12) as usize * 0x10000,
+  })
+}
+
+/// Collect mode numbers up to the end marker. The list is cut short if the
+/// marker never appears.
+pub fn parse_mode_list(buffer: &[u8]) -> Vec<u16> {
+  buffer
+    .chunks_exact(2)
+    .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+    .take_while(|mode| *mode != MODE_LIST_END)
+    .collect()
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ModeInfo {
+  pub width: u16,
+  pub height: u16,
+  pub bits_per_pixel: u8,
+  /// Bytes from the start of one row to the start of the next, which can be
+  /// more than the visible width
+  pub pitch: u16,
+  pub framebuffer: PhysicalAddress,
+}
+
+impl ModeInfo {
+  /// Mode 13h, which every VGA card supports
+  pub fn mode_13h() -> ModeInfo {
+    ModeInfo {
+      width: 320,
+      height: 200,
+      bits_per_pixel: 8,
+      pitch: 320,
+      framebuffer: PhysicalAddress::new(0xa0000),
+    }
+  }
+
+  /// Bytes of physical memory that need to be mapped to cover every row,
+  /// rounded up to a whole number of pages
+  pub fn framebuffer_size(&self) -> usize {
+    let bytes = self.pitch as usize * self.height as usize;
+    (bytes + 0xfff) & !0xfff
+  }
+}
+
+/// Parse the block returned by 4F01h. Only graphics modes with a linear
+/// framebuffer and a byte-addressable pixel format are accepted. From VBE 3.0
+/// on, linear modes can have a different pitch than banked ones, which is
+/// stored separately.
+pub fn parse_mode_info(buffer: &[u8], version: u16) -> Option<ModeInfo> {
+  if buffer.len() < MODE_INFO_SIZE {
+    return None;
+  }
+  let attributes = read_u16(buffer, 0x00);
+  let required = ATTRIBUTE_SUPPORTED | ATTRIBUTE_GRAPHICS | ATTRIBUTE_LINEAR_FRAMEBUFFER;
+  if attributes & required != required {
+    return None;
+  }
+  let memory_model = buffer[0x1b];
+  if memory_model != MODEL_PACKED_PIXEL && memory_model != MODEL_DIRECT_COLOR {
+    return None;
+  }
+  let framebuffer = read_u32(buffer, 0x28) as usize;
+  if framebuffer == 0 || framebuffer & 0xfff != 0 {
+    return None;
+  }
+  let pitch = if version >= 0x0300 {
+    read_u16(buffer, 0x32)
+  } else {
+    read_u16(buffer, 0x10)
+  };
+  let info = ModeInfo {
+    width: read_u16(buffer, 0x12),
+    height: read_u16(buffer, 0x14),
+    bits_per_pixel: buffer[0x19],
+    pitch,
+    framebuffer: PhysicalAddress::new(framebuffer),
+  };
+  let min_pitch = info.width as usize * ((info.bits_per_pixel as usize + 7) / 8);
+  if info.width == 0 || info.height == 0 || (info.pitch as usize) < min_pitch {
+    return None;
+  }
+  Some(info)
+}
+
+/// Pick the mode that matches the requested resolution and color depth
+pub fn find_mode<I>(modes: I, width: u16, height: u16, bits_per_pixel: u8) -> Option<(u16, ModeInfo)>
+  where I: Iterator<Item = (u16, ModeInfo)> {
+  modes
+    .filter(|(_, info)| info.width == width && info.height == height && info.bits_per_pixel == bits_per_pixel)
+    .next()
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec;
+  use crate::memory::address::PhysicalAddress;
+  use super::{find_mode, parse_controller_info, parse_mode_info, parse_mode_list, ModeInfo, MODE_INFO_SIZE};
+
+  fn mode_info_block(attributes: u16, width: u16, height: u16, bpp: u8, pitch: u16, linear_pitch: u16) -> [u8; MODE_INFO_SIZE] {
+    let mut block = [0; MODE_INFO_SIZE];
+    block[0x00..0x02].copy_from_slice(&attributes.to_le_bytes());
+    block[0x10..0x12].copy_from_slice(&pitch.to_le_bytes());
+    block[0x12..0x14].copy_from_slice(&width.to_le_bytes());
+    block[0x14..0x16].copy_from_slice(&height.to_le_bytes());
+    block[0x19] = bpp;
+    block[0x1b] = 6;
+    block[0x28..0x2c].copy_from_slice(&0xe0000000u32.to_le_bytes());
+    block[0x32..0x34].copy_from_slice(&linear_pitch.to_le_bytes());
+    block
+  }
+
+  #[test]
+  fn mode_info_parsing() {
+    let block = mode_info_block(0x9b, 1024, 768, 32, 4096, 4096);
+    let info = parse_mode_info(&block, 0x0200).unwrap();
+    assert_eq!(info.width, 1024);
+    assert_eq!(info.height, 768);
+    assert_eq!(info.bits_per_pixel, 32);
+    assert_eq!(info.framebuffer, PhysicalAddress::new(0xe0000000));
+    assert_eq!(info.framebuffer_size(), 4096 * 768);
+
+    // Padded rows count toward the size, which is rounded up to a page
+    let block = mode_info_block(0x9b, 800, 600, 24, 2400, 2432);
+    assert_eq!(parse_mode_info(&block, 0x0200).unwrap().pitch, 2400);
+    let info = parse_mode_info(&block, 0x0300).unwrap();
+    assert_eq!(info.pitch, 2432);
+    assert_eq!(info.framebuffer_size(), 0x165000);
+
+    // Modes without a linear framebuffer can't be mapped
+    let block = mode_info_block(0x1b, 1024, 768, 32, 4096, 4096);
+    assert_eq!(parse_mode_info(&block, 0x0300), None);
+    // A pitch too short for the width is rejected
+    let block = mode_info_block(0x9b, 1024, 768, 32, 1024, 1024);
+    assert_eq!(parse_mode_info(&block, 0x0300), None);
+
+    assert_eq!(ModeInfo::mode_13h().framebuffer_size(), 0x10000);
+  }
+
+  #[test]
+  fn controller_info_parsing() {
+    let mut block = vec![0u8; 512];
+    assert_eq!(parse_controller_info(&block), None);
+    block[0..4].copy_from_slice(b"VESA");
+    block[4..6].copy_from_slice(&0x0102u16.to_le_bytes());
+    // VBE 1.2 has no linear framebuffer
+    assert_eq!(parse_controller_info(&block), None);
+    block[4..6].copy_from_slice(&0x0300u16.to_le_bytes());
+    block[0x0e..0x10].copy_from_slice(&0x0022u16.to_le_bytes());
+    block[0x10..0x12].copy_from_slice(&0x7f00u16.to_le_bytes());
+    block[0x12..0x14].copy_from_slice(&0x0100u16.to_le_bytes());
+    let info = parse_controller_info(&block).unwrap();
+    assert_eq!(info.mode_list, 0x7f022);
+    assert_eq!(info.total_memory, 16 * 1024 * 1024);
+
+    let list = [0x01, 0x01, 0x18, 0x01, 0xff, 0xff, 0x05, 0x01];
+    let modes = parse_mode_list(&list);
+    assert_eq!(modes, [0x101, 0x118]);
+
+    let candidates = vec![
+      (0x101, ModeInfo { width: 640, height: 480, bits_per_pixel: 8, pitch: 640, framebuffer: PhysicalAddress::new(0xe0000000) }),
+      (0x118, ModeInfo { width: 1024, height: 768, bits_per_pixel: 32, pitch: 4096, framebuffer: PhysicalAddress::new(0xe0000000) }),
+    ];
+    assert_eq!(find_mode(candidates.iter().copied(), 1024, 768, 32).map(|(mode, _)| mode), Some(0x118));
+    assert_eq!(find_mode(candidates.iter().copied(), 1024, 768, 16), None);
+  }
+}
@@ -2,8 +2,9 @@
 //! DEV:/COM_ are single-reader devices, which can only hae one active reader at
 //! a time. Any successive readers will be blocked in a queue, until all prior
 //! readers have finished or aborted.
-//! When data arrives on the serial port, an interrupt is triggered telling the
-//! device driver to wake up the current reader.
+//! When data arrives on the serial port, an interrupt is triggered. The
+//! interrupt handler moves the data from the UART into a receive ring and
+//! wakes up the current reader, which reads from the ring.
 
 use alloc::collections::VecDeque;
 use core::sync::atomic::{AtomicUsize, Ordering};
@@ -11,9 +12,14 @@ use crate::collections::SlotList;
 use crate::devices::driver::{DeviceDriver, IOHandle};
 use crate::devices::queue::QueuedIO;
 use crate::task::id::ProcessID;
-use crate::task::switching::{get_current_id, get_current_process, yield_coop};
-use super::serial::SerialPort;
-use spin::RwLock;
+use crate::task::switching::{get_current_id, get_current_process};
+use crate::task::yield_interruptible;
+use crate::interrupts::control::{cli, is_interrupt_enabled, sti};
+use crate::syscalls::user::validate_user_range;
+use super::receive::ReceiveRing;
+use super::serial::{FifoTrigger, SerialPort};
+use crate::locks::RwLock;
+use syscall::flags::{TIOCMGET, TIOCMSET, TIOCSERGETLSR, TIOCSERGETOVERRUN, TIOCSERSETTRIGGER};
 
 pub static mut COM_DEVICES: [Option<ComDevice>; 2] = [None, None];
 
@@ -26,6 +32,7 @@ struct Descriptor {
 
 pub struct ComDevice {
   com: SerialPort,
+  received: ReceiveRing,
   next_handle: AtomicUsize,
   open_handles: RwLock<SlotList<Descriptor>>,
   readers: RwLock<VecDeque<IOHandle>>,
@@ -35,6 +42,7 @@ impl ComDevice {
   pub fn new(first_port: u16) -> Self {
     Self {
       com: SerialPort::new(first_port),
+      received: ReceiveRing::new(),
       next_handle: AtomicUsize::new(0),
       open_handles: RwLock::new(SlotList::new()),
       readers: RwLock::new(VecDeque::new()),
@@ -49,15 +57,37 @@ impl ComDevice {
     self.com.get_interrupt_id()
   }
 
+  /// Move everything waiting in the UART's FIFO into the receive ring. This
+  /// is the ring's only producer, so it must not be interrupted by the IRQ
+  /// handler.
+  pub fn receive_pending(&self) {
+    while let Some(data) = self.com.receive_byte() {
+      self.received.push(data);
+    }
+  }
+
   pub fn read_available_data(&self, dest: &mut [u8]) -> usize {
-    let mut read = 0;
-    while read < dest.len() && self.com.has_data() {
-      if let Some(data) = self.com.receive_byte() {
-        dest[read] = data;
-        read += 1;
-      }
+    // Pick up anything that arrived without an interrupt, such as bytes that
+    // were received before the handler was installed
+    let reenable = is_interrupt_enabled();
+    cli();
+    self.receive_pending();
+    if reenable {
+      sti();
     }
-    read
+    self.received.read(dest)
+  }
+
+  /// Data is ready if it has been received, or is still waiting in the UART.
+  /// A pending line error also makes a read return immediately.
+  pub fn poll_readable(&self) -> bool {
+    self.received.available_bytes() > 0 || self.com.has_data() || self.com.has_line_errors()
+  }
+
+  /// Writes send each byte as soon as the UART can accept it, so the port is
+  /// ready once its transmit buffer has emptied
+  pub fn poll_writable(&self) -> bool {
+    !self.com.is_transmitting()
   }
 
   pub fn open(&self) -> IOHandle {
@@ -71,21 +101,70 @@ impl ComDevice {
     id
   }
 
-  pub fn read(&self, handle: IOHandle, dest: &mut [u8]) -> usize {
+  /// Block until the buffer has been filled. If a line error occurs before
+  /// then, the read stops early, returning the bytes that arrived before the
+  /// error, or failing if there were none. The error remains pending until it
+  /// is collected with TIOCSERGETLSR.
+  pub fn read(&self, handle: IOHandle, dest: &mut [u8]) -> Result<usize, ()> {
     self.perform_io(handle, || {
       let mut bytes_read = 0;
       while bytes_read < dest.len() {
+        if self.com.has_line_errors() {
+          return if bytes_read > 0 { Ok(bytes_read) } else { Err(()) };
+        }
         let partial_read = self.read_available_data(&mut dest[bytes_read..]);
         bytes_read += partial_read;
         if bytes_read < dest.len() {
           get_current_process().write().io_block(None);
-          yield_coop();
+          if yield_interruptible().is_err() {
+            return if bytes_read > 0 { Ok(bytes_read) } else { Err(()) };
+          }
         }
       }
-      bytes_read
+      Ok(bytes_read)
     })
   }
 
+  /// Most commands pass a pointer to a u32 in the caller's memory as `arg`,
+  /// which is checked before it is used
+  pub fn ioctl(&self, command: u32, arg: u32) -> Result<u32, ()> {
+    match command {
+      TIOCMGET => {
+        let out_ptr = user_u32(arg)?;
+        unsafe {
+          *out_ptr = self.com.get_modem_lines();
+        }
+        Ok(0)
+      },
+      TIOCMSET => {
+        let in_ptr = user_u32(arg)?;
+        let lines = unsafe { *in_ptr };
+        self.com.set_modem_lines(lines);
+        Ok(0)
+      },
+      TIOCSERGETLSR => {
+        let out_ptr = user_u32(arg)?;
+        unsafe {
+          *out_ptr = self.com.take_line_status() as u32;
+        }
+        Ok(0)
+      },
+      TIOCSERGETOVERRUN => {
+        let out_ptr = user_u32(arg)?;
+        unsafe {
+          *out_ptr = self.received.take_overruns() as u32;
+        }
+        Ok(0)
+      },
+      TIOCSERSETTRIGGER => {
+        let trigger = FifoTrigger::from_bytes(arg).ok_or(())?;
+        self.com.set_fifo_trigger(trigger);
+        Ok(0)
+      },
+      _ => Err(()),
+    }
+  }
+
   pub fn write(&self, _handle: IOHandle, src: &[u8]) -> usize {
     // TODO: make this not blocking
     let mut written = 0;
@@ -111,7 +190,7 @@ impl ComDevice {
   }
 }
 
-impl QueuedIO<(), usize> for ComDevice {
+impl QueuedIO<(), Result<usize, ()>> for ComDevice {
   fn get_process_id_for_handle(&self, handle: IOHandle) -> Option<ProcessID> {
     self.open_handles
       .read()
@@ -124,6 +203,12 @@ impl QueuedIO<(), usize> for ComDevice {
   }
 }
 
+/// Make sure an ioctl argument points to a u32 in userspace
+fn user_u32(arg: u32) -> Result<*mut u32, ()> {
+  validate_user_range(arg as usize, core::mem::size_of::<u32>()).map_err(|_| ())?;
+  Ok(arg as *mut u32)
+}
+
 pub struct ComDriver {
   com_number: usize,
 }
@@ -156,7 +241,29 @@ impl DeviceDriver for ComDriver {
 
   fn read(&self, index: IOHandle, buffer: &mut [u8]) -> Result<usize, ()> {
     let device = self.get_device()?;
-    Ok(device.read(index, buffer))
+    device.read(index, buffer)
+  }
+
+  fn read_nonblocking(&self, _index: IOHandle, buffer: &mut [u8]) -> Result<Option<usize>, ()> {
+    let device = self.get_device()?;
+    if device.com.has_line_errors() {
+      return Err(());
+    }
+    let bytes_read = device.read_available_data(buffer);
+    if bytes_read == 0 && buffer.len() > 0 {
+      return Ok(None);
+    }
+    Ok(Some(bytes_read))
+  }
+
+  fn poll_readable(&self, _index: IOHandle) -> Result<bool, ()> {
+    let device = self.get_device()?;
+    Ok(device.poll_readable())
+  }
+
+  fn poll_writable(&self, _index: IOHandle) -> Result<bool, ()> {
+    let device = self.get_device()?;
+    Ok(device.poll_writable())
   }
 
   fn write(&self, index: IOHandle, buffer: &[u8]) -> Result<usize, ()> {
@@ -168,4 +275,9 @@ impl DeviceDriver for ComDriver {
     let device = self.get_device()?;
     Ok(device.close(index))
   }
+
+  fn ioctl(&self, _index: IOHandle, command: u32, arg: u32) -> Result<u32, ()> {
+    let device = self.get_device()?;
+    device.ioctl(command, arg)
+  }
 }
@@ -1,42 +1,65 @@
+#[cfg(not(test))]
 pub mod device;
+pub mod receive;
 pub mod serial;
 
+#[cfg(not(test))]
 use crate::memory::address::VirtualAddress;
+#[cfg(not(test))]
 use crate::task::id::ProcessID;
 
+/// The standard I/O base and IRQ line of a PC serial port
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ComPort {
+  pub base: u16,
+  pub irq: usize,
+}
+
+/// COM1 and COM2, in the order of their device indices. COM3 and COM4 share
+/// these IRQs, and aren't supported.
+pub const COM_PORTS: [ComPort; 2] = [
+  ComPort { base: 0x3f8, irq: 4 },
+  ComPort { base: 0x2f8, irq: 3 },
+];
+
+#[cfg(not(test))]
 pub fn init() {
-  let com1 = device::ComDevice::new(0x3f8);
-  com1.init();
-  let com2 = device::ComDevice::new(0x2f8);
-  com2.init();
-  unsafe {
-    device::COM_DEVICES[0] = Some(com1);
-    device::COM_DEVICES[1] = Some(com2);
-  }
+  let handlers: [extern "C" fn(); 2] = [int_com1, int_com2];
 
   crate::kprintln!("Install COM handlers");
 
-  let install_result = crate::interrupts::handlers::install_handler(
-    4,
-    ProcessID::new(0),
-    VirtualAddress::new(int_com1 as *const fn () -> () as usize),
-    VirtualAddress::new(0),
-  );
-  if let Err(_) = install_result {
-    crate::kprintln!("Failed to install IRQ4");
+  for (index, port) in COM_PORTS.iter().enumerate() {
+    let com = device::ComDevice::new(port.base);
+    com.init();
+    unsafe {
+      device::COM_DEVICES[index] = Some(com);
+    }
+
+    let install_result = crate::interrupts::handlers::install_handler(
+      port.irq,
+      ProcessID::new(0),
+      VirtualAddress::new(handlers[index] as *const fn () -> () as usize),
+      VirtualAddress::new(0),
+    );
+    if let Err(_) = install_result {
+      crate::kprintln!("Failed to install IRQ{}", port.irq);
+    }
   }
 }
 
+#[cfg(not(test))]
 pub extern "C" fn int_com1() {
   handle_interrupt(0);
   crate::interrupts::handlers::return_from_handler(4);
 }
 
+#[cfg(not(test))]
 pub extern "C" fn int_com2() {
   handle_interrupt(1);
   crate::interrupts::handlers::return_from_handler(3);
 }
 
+#[cfg(not(test))]
 pub fn handle_interrupt(index: usize) {
   use crate::devices::queue::QueuedIO;
 
@@ -45,14 +68,46 @@ pub fn handle_interrupt(index: usize) {
   };
   if let Some(com) = driver {
     let interrupt_info = com.get_interrupt_info();
-    if interrupt_info & 4 != 0 { // Received data available
+    // Received data available, or a FIFO timeout with data below the trigger
+    // level. Both are indicated by bit 2 of the interrupt ID.
+    if interrupt_info & 4 != 0 {
+      com.receive_pending();
       com.wake_front();
     }
   }
 }
 
+#[cfg(not(test))]
 pub fn get_device(index: usize) -> &'static device::ComDevice {
   unsafe {
     &device::COM_DEVICES[index].as_ref().unwrap()
   }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+  use crate::interrupts::installed::{install_handler, try_get_installed_handler};
+  use crate::memory::address::VirtualAddress;
+  use crate::task::id::ProcessID;
+  use super::serial::SerialPort;
+  use super::COM_PORTS;
+
+  #[test]
+  fn com2_is_independent() {
+    let com1 = SerialPort::new(COM_PORTS[0].base);
+    let com2 = SerialPort::new(COM_PORTS[1].base);
+    assert_eq!(com1.get_base_port(), 0x3f8);
+    assert_eq!(com2.get_base_port(), 0x2f8);
+    // Each UART spans eight registers, which must not overlap
+    assert!(com1.get_base_port() >= com2.get_base_port() + 8);
+
+    assert_eq!(COM_PORTS[0].irq, 4);
+    assert_eq!(COM_PORTS[1].irq, 3);
+    let kernel = ProcessID::new(0);
+    let no_stack = VirtualAddress::new(0);
+    install_handler(COM_PORTS[0].irq, kernel, VirtualAddress::new(0x1000), no_stack).unwrap();
+    install_handler(COM_PORTS[1].irq, kernel, VirtualAddress::new(0x2000), no_stack).unwrap();
+    // Installing the COM2 handler doesn't replace the one for COM1
+    assert_eq!(try_get_installed_handler(4).map(|h| h.function.as_usize()), Some(0x1000));
+    assert_eq!(try_get_installed_handler(3).map(|h| h.function.as_usize()), Some(0x2000));
+  }
+}
@@ -0,0 +1,132 @@
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of bytes a serial port can hold before a process reads them. At
+/// 38400 baud this is a little over a quarter second of continuous input.
+pub const RECEIVE_BUFFER_LENGTH: usize = 1024;
+
+/// Bytes received on a serial port are moved out of the UART's small hardware
+/// FIFO and into a ReceiveRing as soon as the port raises an interrupt. Reading
+/// processes pull from the ring, so a slow reader no longer causes the UART to
+/// overrun. If the ring itself fills up, incoming bytes are dropped and
+/// counted, so that the loss can be reported.
+/// The interrupt handler is the only writer, and the port's current reader is
+/// the only consumer.
+pub struct ReceiveRing {
+  data: UnsafeCell<[u8; RECEIVE_BUFFER_LENGTH]>,
+  head: AtomicUsize,
+  tail: AtomicUsize,
+  /// Bytes dropped because the ring was full
+  overruns: AtomicUsize,
+}
+
+unsafe impl Sync for ReceiveRing {}
+
+impl ReceiveRing {
+  pub const fn new() -> Self {
+    Self {
+      data: UnsafeCell::new([0; RECEIVE_BUFFER_LENGTH]),
+      head: AtomicUsize::new(0),
+      tail: AtomicUsize::new(0),
+      overruns: AtomicUsize::new(0),
+    }
+  }
+
+  /// Add a received byte to the end of the ring. If there is no room, the
+  /// byte is dropped and the overrun count increases.
+  pub fn push(&self, byte: u8) -> bool {
+    let head = self.head.load(Ordering::SeqCst);
+    let tail = self.tail.load(Ordering::SeqCst);
+    if tail.wrapping_sub(head) >= RECEIVE_BUFFER_LENGTH {
+      self.overruns.fetch_add(1, Ordering::SeqCst);
+      return false;
+    }
+    unsafe {
+      (*self.data.get())[tail % RECEIVE_BUFFER_LENGTH] = byte;
+    }
+    self.tail.store(tail.wrapping_add(1), Ordering::SeqCst);
+    true
+  }
+
+  /// Copy as many buffered bytes as will fit into `dest`, returning the number
+  /// of bytes copied
+  pub fn read(&self, dest: &mut [u8]) -> usize {
+    let head = self.head.load(Ordering::SeqCst);
+    let tail = self.tail.load(Ordering::SeqCst);
+    let to_read = dest.len().min(tail.wrapping_sub(head));
+    let data = unsafe { &*self.data.get() };
+    for i in 0..to_read {
+      dest[i] = data[head.wrapping_add(i) % RECEIVE_BUFFER_LENGTH];
+    }
+    self.head.store(head.wrapping_add(to_read), Ordering::SeqCst);
+    to_read
+  }
+
+  pub fn available_bytes(&self) -> usize {
+    let head = self.head.load(Ordering::SeqCst);
+    let tail = self.tail.load(Ordering::SeqCst);
+    tail.wrapping_sub(head)
+  }
+
+  /// Return the number of bytes dropped since the last call, resetting the
+  /// count
+  pub fn take_overruns(&self) -> usize {
+    self.overruns.swap(0, Ordering::SeqCst)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec;
+  use super::{ReceiveRing, RECEIVE_BUFFER_LENGTH};
+
+  #[test]
+  fn bursty_input_up_to_capacity() {
+    let ring = ReceiveRing::new();
+    let mut received = vec![0; RECEIVE_BUFFER_LENGTH];
+    let mut total_read = 0;
+    // Bursts the size of a full UART FIFO arrive faster than the reader
+    // consumes them, but the ring never overflows
+    let mut next: usize = 0;
+    while next < RECEIVE_BUFFER_LENGTH {
+      for _ in 0..16 {
+        if next < RECEIVE_BUFFER_LENGTH {
+          assert!(ring.push(next as u8));
+          next += 1;
+        }
+      }
+      if next % 64 == 0 {
+        total_read += ring.read(&mut received[total_read..total_read + 8]);
+      }
+    }
+    total_read += ring.read(&mut received[total_read..]);
+    assert_eq!(total_read, RECEIVE_BUFFER_LENGTH);
+    for (i, byte) in received.iter().enumerate() {
+      assert_eq!(*byte, i as u8);
+    }
+    assert_eq!(ring.take_overruns(), 0);
+  }
+
+  #[test]
+  fn overflow_is_counted() {
+    let ring = ReceiveRing::new();
+    for i in 0..(RECEIVE_BUFFER_LENGTH + 10) {
+      ring.push(i as u8);
+    }
+    assert_eq!(ring.available_bytes(), RECEIVE_BUFFER_LENGTH);
+    assert_eq!(ring.take_overruns(), 10);
+    assert_eq!(ring.take_overruns(), 0);
+
+    // The oldest bytes are kept; the newest are the ones dropped
+    let mut first = [0; 4];
+    assert_eq!(ring.read(&mut first), 4);
+    assert_eq!(first, [0, 1, 2, 3]);
+    // Reading made room for exactly four more bytes
+    for byte in 0..4 {
+      assert!(ring.push(0xa0 + byte));
+    }
+    assert_eq!(ring.available_bytes(), RECEIVE_BUFFER_LENGTH);
+    assert!(!ring.push(0xbb));
+    assert_eq!(ring.take_overruns(), 1);
+  }
+}
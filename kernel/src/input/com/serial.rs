@@ -1,5 +1,7 @@
 use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
 use crate::x86::io::Port;
+use syscall::flags::{TIOCM_CD, TIOCM_CTS, TIOCM_DSR, TIOCM_DTR, TIOCM_RI, TIOCM_RTS};
 
 const STATUS_ERROR_IMPENDING: u8 = 1 << 7;
 const STATUS_TRANSMIT_IDLE: u8 = 1 << 6;
@@ -9,6 +11,61 @@ const STATUS_FRAME_ERROR: u8 = 1 << 3;
 const STATUS_PARITY_ERROR: u8 = 1 << 2;
 const STATUS_OVERRUN_ERROR: u8 = 1 << 1;
 const STATUS_DATA_READY: u8 = 1;
+/// These bits are cleared by the UART whenever the line status is read, so
+/// they need to be remembered until a program asks for them
+const STATUS_STICKY_ERRORS: u8 = STATUS_BREAK | STATUS_FRAME_ERROR | STATUS_PARITY_ERROR | STATUS_OVERRUN_ERROR;
+
+const FIFO_ENABLE: u8 = 1;
+const FIFO_CLEAR_RECEIVE: u8 = 1 << 1;
+const FIFO_CLEAR_TRANSMIT: u8 = 1 << 2;
+
+const MODEM_CONTROL_DTR: u8 = 1;
+const MODEM_CONTROL_RTS: u8 = 1 << 1;
+
+const MODEM_STATUS_CTS: u8 = 1 << 4;
+const MODEM_STATUS_DSR: u8 = 1 << 5;
+const MODEM_STATUS_RI: u8 = 1 << 6;
+const MODEM_STATUS_DCD: u8 = 1 << 7;
+
+/// Number of bytes the UART's receive FIFO collects before raising an
+/// interrupt. Bytes that sit below the trigger level for a few character times
+/// raise a timeout interrupt instead, so nothing is left behind.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FifoTrigger {
+  One,
+  Four,
+  Eight,
+  Fourteen,
+}
+
+impl FifoTrigger {
+  pub fn from_bytes(bytes: u32) -> Option<FifoTrigger> {
+    match bytes {
+      1 => Some(FifoTrigger::One),
+      4 => Some(FifoTrigger::Four),
+      8 => Some(FifoTrigger::Eight),
+      14 => Some(FifoTrigger::Fourteen),
+      _ => None,
+    }
+  }
+
+  /// Value for the FIFO control register that enables and clears both FIFOs,
+  /// and sets this trigger level
+  pub fn fifo_control_value(&self) -> u8 {
+    let level = match self {
+      FifoTrigger::One => 0,
+      FifoTrigger::Four => 1,
+      FifoTrigger::Eight => 2,
+      FifoTrigger::Fourteen => 3,
+    };
+    FIFO_ENABLE | FIFO_CLEAR_RECEIVE | FIFO_CLEAR_TRANSMIT | (level << 6)
+  }
+}
+
+/// Received bytes are moved into the receive ring by the interrupt handler, so
+/// the FIFO only needs to cover interrupt latency. Triggering at 8 leaves room
+/// for 8 more bytes to arrive before the handler runs.
+pub const DEFAULT_FIFO_TRIGGER: FifoTrigger = FifoTrigger::Eight;
 
 pub struct SerialPort {
   data: Port,
@@ -18,6 +75,8 @@ pub struct SerialPort {
   modem_control: Port,
   line_status: Port,
   modem_status: Port,
+  /// Error bits seen in the line status register since they were last taken
+  pending_errors: AtomicU8,
 }
 
 impl SerialPort {
@@ -30,9 +89,15 @@ impl SerialPort {
       modem_control: Port::new(initial_port + 4),
       line_status: Port::new(initial_port + 5),
       modem_status: Port::new(initial_port + 6),
+      pending_errors: AtomicU8::new(0),
     }
   }
 
+  /// The first I/O port of the UART, where data is read and written
+  pub fn get_base_port(&self) -> u16 {
+    self.data.get_number()
+  }
+
   pub fn init(&self) {
     unsafe {
       self.interrupt_enable.write_u8(0x01); // Enable data ready interrupt
@@ -40,17 +105,57 @@ impl SerialPort {
       self.data.write_u8(0x03); // Set divisor low to 3, aka 38400 baud
       self.interrupt_enable.write_u8(0x00); // Set divisor high
       self.line_control.write_u8(0x03); // 8 bits, no parity, 1 stop bit
-      self.fifo_control.write_u8(0xc7); // Enable fifo
+      self.fifo_control.write_u8(DEFAULT_FIFO_TRIGGER.fifo_control_value());
       self.modem_control.write_u8(0x0b); // Set RTS/DTR
     }
   }
 
-  pub fn is_transmitting(&self) -> bool {
+  /// Read the line status register, holding onto any error bits that the
+  /// read clears
+  pub fn read_line_status(&self) -> u8 {
+    let status = unsafe { self.line_status.read_u8() };
+    let errors = status & STATUS_STICKY_ERRORS;
+    if errors != 0 {
+      self.pending_errors.fetch_or(errors, Ordering::SeqCst);
+    }
+    status
+  }
+
+  /// Return the current line status along with any errors that have occurred
+  /// since the last call, clearing those errors
+  pub fn take_line_status(&self) -> u8 {
+    let status = self.read_line_status();
+    status | self.pending_errors.swap(0, Ordering::SeqCst)
+  }
+
+  /// Check whether a framing, parity, overrun, or break condition has
+  /// occurred that hasn't been taken yet
+  pub fn has_line_errors(&self) -> bool {
+    self.read_line_status();
+    self.pending_errors.load(Ordering::SeqCst) != 0
+  }
+
+  /// Get the state of the modem control and status lines, as TIOCM_* bits
+  pub fn get_modem_lines(&self) -> u32 {
+    let (control, status) = unsafe {
+      (self.modem_control.read_u8(), self.modem_status.read_u8())
+    };
+    modem_lines_from_registers(control, status)
+  }
+
+  /// Set DTR and RTS from TIOCM_* bits. Other bits are ignored, since the
+  /// remaining lines are inputs.
+  pub fn set_modem_lines(&self, lines: u32) {
     unsafe {
-      (self.line_status.read_u8() & STATUS_TRANSMIT_BUFFER_EMPTY) == 0
+      let control = self.modem_control.read_u8();
+      self.modem_control.write_u8(modem_control_with_lines(control, lines));
     }
   }
 
+  pub fn is_transmitting(&self) -> bool {
+    (self.read_line_status() & STATUS_TRANSMIT_BUFFER_EMPTY) == 0
+  }
+
   pub fn send_byte(&self, byte: u8) {
     unsafe {
       while self.is_transmitting() {}
@@ -59,9 +164,7 @@ impl SerialPort {
   }
 
   pub fn has_data(&self) -> bool {
-    unsafe {
-      (self.line_status.read_u8() & STATUS_DATA_READY) != 0
-    }
+    (self.read_line_status() & STATUS_DATA_READY) != 0
   }
 
   pub fn receive_byte(&self) -> Option<u8> {
@@ -74,6 +177,14 @@ impl SerialPort {
     }
   }
 
+  /// Reprogram the receive FIFO's trigger level. This also clears both
+  /// FIFOs, so any bytes still in the hardware are lost.
+  pub fn set_fifo_trigger(&self, trigger: FifoTrigger) {
+    unsafe {
+      self.fifo_control.write_u8(trigger.fifo_control_value());
+    }
+  }
+
   pub fn get_interrupt_id(&self) -> u8 {
     unsafe {
       self.fifo_control.read_u8()
@@ -88,4 +199,82 @@ impl fmt::Write for SerialPort {
     }
     Ok(())
   }
-}
\ No newline at end of file
+}
+
+/// Combine the modem control and modem status registers into TIOCM_* bits
+pub fn modem_lines_from_registers(control: u8, status: u8) -> u32 {
+  let mut lines = 0;
+  if control & MODEM_CONTROL_DTR != 0 {
+    lines |= TIOCM_DTR;
+  }
+  if control & MODEM_CONTROL_RTS != 0 {
+    lines |= TIOCM_RTS;
+  }
+  if status & MODEM_STATUS_CTS != 0 {
+    lines |= TIOCM_CTS;
+  }
+  if status & MODEM_STATUS_DSR != 0 {
+    lines |= TIOCM_DSR;
+  }
+  if status & MODEM_STATUS_RI != 0 {
+    lines |= TIOCM_RI;
+  }
+  if status & MODEM_STATUS_DCD != 0 {
+    lines |= TIOCM_CD;
+  }
+  lines
+}
+
+/// Update the DTR and RTS bits of the modem control register, leaving the
+/// others (like the OUT2 bit that gates interrupts) untouched
+pub fn modem_control_with_lines(control: u8, lines: u32) -> u8 {
+  let mut updated = control & !(MODEM_CONTROL_DTR | MODEM_CONTROL_RTS);
+  if lines & TIOCM_DTR != 0 {
+    updated |= MODEM_CONTROL_DTR;
+  }
+  if lines & TIOCM_RTS != 0 {
+    updated |= MODEM_CONTROL_RTS;
+  }
+  updated
+}
+
+#[cfg(test)]
+mod tests {
+  use syscall::flags::{TIOCM_CD, TIOCM_CTS, TIOCM_DTR, TIOCM_RTS};
+  use super::{modem_control_with_lines, modem_lines_from_registers, FifoTrigger};
+
+  #[test]
+  fn toggle_rts_dtr() {
+    // The port is initialized with DTR, RTS, and OUT2 set
+    let control = 0x0b;
+    assert_eq!(modem_lines_from_registers(control, 0), TIOCM_DTR | TIOCM_RTS);
+
+    let control = modem_control_with_lines(control, TIOCM_DTR);
+    assert_eq!(control, 0x09);
+    assert_eq!(modem_lines_from_registers(control, 0), TIOCM_DTR);
+
+    let control = modem_control_with_lines(control, TIOCM_RTS);
+    assert_eq!(control, 0x0a);
+    assert_eq!(modem_lines_from_registers(control, 0), TIOCM_RTS);
+
+    // Input lines can't be set, and don't disturb the outputs
+    let control = modem_control_with_lines(control, TIOCM_CTS | TIOCM_DTR | TIOCM_RTS);
+    assert_eq!(control, 0x0b);
+  }
+
+  #[test]
+  fn modem_status_lines() {
+    assert_eq!(modem_lines_from_registers(0, 0x90), TIOCM_CTS | TIOCM_CD);
+  }
+
+  #[test]
+  fn fifo_trigger_levels() {
+    assert_eq!(FifoTrigger::from_bytes(14), Some(FifoTrigger::Fourteen));
+    assert_eq!(FifoTrigger::from_bytes(2), None);
+    assert_eq!(FifoTrigger::One.fifo_control_value(), 0x07);
+    assert_eq!(FifoTrigger::Four.fifo_control_value(), 0x47);
+    assert_eq!(FifoTrigger::Eight.fifo_control_value(), 0x87);
+    // The value this driver used to hard-code
+    assert_eq!(FifoTrigger::Fourteen.fifo_control_value(), 0xc7);
+  }
+}
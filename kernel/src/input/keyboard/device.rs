@@ -5,8 +5,9 @@
 use alloc::sync::Arc;
 use crate::collections::SlotList;
 use crate::devices::driver::{DeviceDriver, IOHandle};
-use crate::task::switching::{get_current_id, get_current_process, yield_coop};
-use spin::RwLock;
+use crate::task::switching::{get_current_id, get_current_process};
+use crate::task::yield_interruptible;
+use crate::locks::RwLock;
 use super::super::buffers::InputBuffer;
 
 /// Buffers for each of the processes reading the 
@@ -31,9 +32,13 @@ impl DeviceDriver for KeyboardDriver {
     while written < dest.len() {
       get_current_process().write().io_block(None);
       buffer.start_read();
-      yield_coop();
+      let interrupted = yield_interruptible().is_err();
       let partial_read = buffer.read_to_buffer(&mut dest[written..]);
       written += partial_read;
+      if interrupted {
+        // Keys that arrived before the signal are still returned
+        return if written > 0 { Ok(written) } else { Err(()) };
+      }
     }
     Ok(written)
   }
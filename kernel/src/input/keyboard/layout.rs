@@ -0,0 +1,182 @@
+//! Keyboard layouts translate a KeyCode into the characters it produces.
+//! KeyCodes describe the physical position of a key (named after the key in
+//! that position on a US keyboard), so each layout is a data table indexed by
+//! KeyCode. Characters are encoded in code page 437, to match the VGA font.
+
+use super::codes::KeyCode;
+use syscall::flags::{KBD_LAYOUT_DE, KBD_LAYOUT_UK, KBD_LAYOUT_US};
+
+/// A key that doesn't produce a character on its own, but applies an accent
+/// to the next key that is pressed.
+pub struct DeadKey {
+  pub key: KeyCode,
+  pub shifted: bool,
+  /// Character to emit if the next key can't be combined with the accent
+  pub accent: u8,
+  /// Pairs of (base character, accented character)
+  pub compositions: &'static [(u8, u8)],
+}
+
+pub struct KeyboardLayout {
+  pub name: &'static str,
+  /// Unshifted and shifted characters for each KeyCode
+  pub keys: &'static [(u8, u8); 0x60],
+  /// Characters produced while AltGr is held. Layouts without AltGr leave this
+  /// empty, and the right Alt key behaves like the left one.
+  pub alt_graph: &'static [(KeyCode, u8)],
+  pub dead_keys: &'static [DeadKey],
+}
+
+impl KeyboardLayout {
+  pub fn has_alt_graph(&self) -> bool {
+    self.alt_graph.len() > 0
+  }
+
+  /// Look up the character for a key, returning 0 if it produces nothing
+  pub fn get_char(&self, key: KeyCode, shift: bool) -> u8 {
+    let index = key as usize;
+    if index >= self.keys.len() {
+      return 0;
+    }
+    let (normal, shifted) = self.keys[index];
+    if shift {
+      shifted
+    } else {
+      normal
+    }
+  }
+
+  pub fn get_alt_graph_char(&self, key: KeyCode) -> u8 {
+    self.alt_graph
+      .iter()
+      .find_map(|(k, c)| if *k == key { Some(*c) } else { None })
+      .unwrap_or(0)
+  }
+
+  pub fn get_dead_key(&self, key: KeyCode, shift: bool) -> Option<&'static DeadKey> {
+    self.dead_keys.iter().find(|d| d.key == key && d.shifted == shift)
+  }
+}
+
+impl DeadKey {
+  /// Combine the accent with the next character, if possible
+  pub fn compose(&self, base: u8) -> Option<u8> {
+    self.compositions
+      .iter()
+      .find_map(|(b, c)| if *b == base { Some(*c) } else { None })
+  }
+}
+
+pub static US: KeyboardLayout = KeyboardLayout {
+  name: "US",
+  keys: &US_KEYS,
+  alt_graph: &[],
+  dead_keys: &[],
+};
+
+pub static UK: KeyboardLayout = KeyboardLayout {
+  name: "UK",
+  keys: &UK_KEYS,
+  alt_graph: &[],
+  dead_keys: &[],
+};
+
+pub static DE: KeyboardLayout = KeyboardLayout {
+  name: "DE",
+  keys: &DE_KEYS,
+  alt_graph: &[
+    (KeyCode::Q, b'@'),
+    (KeyCode::Num2, 0xfd), // ²
+    (KeyCode::Num7, b'{'),
+    (KeyCode::Num8, b'['),
+    (KeyCode::Num9, b']'),
+    (KeyCode::Num0, b'}'),
+    (KeyCode::Minus, b'\\'),
+    (KeyCode::BracketRight, b'~'),
+    (KeyCode::LessThan, b'|'),
+    (KeyCode::M, 0xe6), // µ
+  ],
+  dead_keys: &[
+    DeadKey {
+      key: KeyCode::Backtick,
+      shifted: false,
+      accent: b'^',
+      compositions: &[(b'a', 0x83), (b'e', 0x88), (b'i', 0x8c), (b'o', 0x93), (b'u', 0x96)],
+    },
+    DeadKey {
+      key: KeyCode::Equals,
+      shifted: false,
+      // CP437 has no acute accent, so an apostrophe stands in for it
+      accent: b'\'',
+      compositions: &[
+        (b'a', 0xa0), (b'e', 0x82), (b'i', 0xa1), (b'o', 0xa2), (b'u', 0xa3), (b'E', 0x90),
+      ],
+    },
+    DeadKey {
+      key: KeyCode::Equals,
+      shifted: true,
+      accent: b'`',
+      compositions: &[(b'a', 0x85), (b'e', 0x8a), (b'i', 0x8d), (b'o', 0x95), (b'u', 0x97)],
+    },
+  ],
+};
+
+/// Find a layout by the ID passed to the set_keyboard_layout syscall
+pub fn get_layout(id: u32) -> Option<&'static KeyboardLayout> {
+  match id {
+    KBD_LAYOUT_US => Some(&US),
+    KBD_LAYOUT_UK => Some(&UK),
+    KBD_LAYOUT_DE => Some(&DE),
+    _ => None,
+  }
+}
+
+/// Standard US QWERTY layout
+pub const US_KEYS: [(u8, u8); 0x60] = [
+  (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0x7f, 0x7f),
+  (0x08, 0x08), (0x09, 0x09), (0, 0), (0, 0), (0, 0), (0x0a, 0x0a), (0, 0), (0, 0),
+  (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0),
+  (0, 0), (0, 0), (0, 0), (0x1b, 0x1b), (0, 0), (0, 0), (0, 0), (0, 0),
+  (0x20, 0x20), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0),
+  (0, 0), (0, 0), (0, 0), (0, 0), (0x2c, 0x3c), (0x2d, 0x5f), (0x2e, 0x3e), (0x2f, 0x3f),
+  (0x30, 0x29), (0x31, 0x21), (0x32, 0x40), (0x33, 0x23), (0x34, 0x24), (0x35, 0x25), (0x36, 0x5e), (0x37, 0x26),
+  (0x38, 0x2a), (0x39, 0x28), (0x3b, 0x3a), (0x27, 0x22), (0, 0), (0x3d, 0x2b), (0, 0), (0, 0),
+  (0, 0), (0x61, 0x41), (0x62, 0x42), (0x63, 0x43), (0x64, 0x44), (0x65, 0x45), (0x66, 0x46), (0x67, 0x47),
+  (0x68, 0x48), (0x69, 0x49), (0x6a, 0x4a), (0x6b, 0x4b), (0x6c, 0x4c), (0x6d, 0x4d), (0x6e, 0x4e), (0x6f, 0x4f),
+  (0x70, 0x50), (0x71, 0x51), (0x72, 0x52), (0x73, 0x53), (0x74, 0x54), (0x75, 0x55), (0x76, 0x56), (0x77, 0x57),
+  (0x78, 0x58), (0x79, 0x59), (0x7a, 0x5a), (0x5b, 0x7b), (0x5c, 0x7c), (0x5d, 0x7d), (0, 0), (0x60, 0x7e),
+];
+
+/// UK QWERTY layout. Differs from US on a handful of punctuation keys, and
+/// adds the ISO key beside the left Shift.
+pub const UK_KEYS: [(u8, u8); 0x60] = [
+  (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0x7f, 0x7f),
+  (0x08, 0x08), (0x09, 0x09), (0, 0), (0, 0), (0, 0), (0x0a, 0x0a), (0, 0), (0, 0),
+  (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0),
+  (0, 0), (0, 0), (0, 0), (0x1b, 0x1b), (0, 0), (0, 0), (0, 0), (0, 0),
+  (0x20, 0x20), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0),
+  (0, 0), (0, 0), (0, 0), (0, 0), (0x2c, 0x3c), (0x2d, 0x5f), (0x2e, 0x3e), (0x2f, 0x3f),
+  (0x30, 0x29), (0x31, 0x21), (0x32, 0x22), (0x33, 0x9c), (0x34, 0x24), (0x35, 0x25), (0x36, 0x5e), (0x37, 0x26),
+  (0x38, 0x2a), (0x39, 0x28), (0x3b, 0x3a), (0x27, 0x40), (0x5c, 0x7c), (0x3d, 0x2b), (0, 0), (0, 0),
+  (0, 0), (0x61, 0x41), (0x62, 0x42), (0x63, 0x43), (0x64, 0x44), (0x65, 0x45), (0x66, 0x46), (0x67, 0x47),
+  (0x68, 0x48), (0x69, 0x49), (0x6a, 0x4a), (0x6b, 0x4b), (0x6c, 0x4c), (0x6d, 0x4d), (0x6e, 0x4e), (0x6f, 0x4f),
+  (0x70, 0x50), (0x71, 0x51), (0x72, 0x52), (0x73, 0x53), (0x74, 0x54), (0x75, 0x55), (0x76, 0x56), (0x77, 0x57),
+  (0x78, 0x58), (0x79, 0x59), (0x7a, 0x5a), (0x5b, 0x7b), (0x23, 0x7e), (0x5d, 0x7d), (0, 0), (0x60, 0xaa),
+];
+
+/// German QWERTZ layout. The accent keys beside Backspace and 1 are dead keys,
+/// so their table entries are left empty.
+pub const DE_KEYS: [(u8, u8); 0x60] = [
+  (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0x7f, 0x7f),
+  (0x08, 0x08), (0x09, 0x09), (0, 0), (0, 0), (0, 0), (0x0a, 0x0a), (0, 0), (0, 0),
+  (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0),
+  (0, 0), (0, 0), (0, 0), (0x1b, 0x1b), (0, 0), (0, 0), (0, 0), (0, 0),
+  (0x20, 0x20), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0),
+  (0, 0), (0, 0), (0, 0), (0, 0), (0x2c, 0x3b), (0xe1, 0x3f), (0x2e, 0x3a), (0x2d, 0x5f),
+  (0x30, 0x3d), (0x31, 0x21), (0x32, 0x22), (0x33, 0x15), (0x34, 0x24), (0x35, 0x25), (0x36, 0x26), (0x37, 0x2f),
+  (0x38, 0x28), (0x39, 0x29), (0x94, 0x99), (0x84, 0x8e), (0x3c, 0x3e), (0, 0), (0, 0), (0, 0),
+  (0, 0), (0x61, 0x41), (0x62, 0x42), (0x63, 0x43), (0x64, 0x44), (0x65, 0x45), (0x66, 0x46), (0x67, 0x47),
+  (0x68, 0x48), (0x69, 0x49), (0x6a, 0x4a), (0x6b, 0x4b), (0x6c, 0x4c), (0x6d, 0x4d), (0x6e, 0x4e), (0x6f, 0x4f),
+  (0x70, 0x50), (0x71, 0x51), (0x72, 0x52), (0x73, 0x53), (0x74, 0x54), (0x75, 0x55), (0x76, 0x56), (0x77, 0x57),
+  (0x78, 0x58), (0x7a, 0x5a), (0x79, 0x59), (0x81, 0x9a), (0x23, 0x27), (0x2b, 0x2a), (0, 0), (0, 0xf8),
+];
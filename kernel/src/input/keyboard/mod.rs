@@ -3,6 +3,7 @@ use crate::x86::io::Port;
 pub mod codes;
 #[cfg(not(test))]
 pub mod device;
+pub mod layout;
 
 pub use codes::KeyCode;
 
@@ -10,7 +10,6 @@ use crate::tty;
 use crate::vterm;
 
 pub mod buffers;
-#[cfg(not(test))]
 pub mod com;
 pub mod keyboard;
 
@@ -21,7 +20,7 @@ static mut INPUT_EVENTS_DATA: [u8; 32] = [0; 32];
 pub static INPUT_EVENTS: RingBuffer = RingBuffer::new(unsafe { &INPUT_EVENTS_DATA });
 /// Global instance of the keyboard state machine
 #[cfg(not(test))]
-static KEYBOARD: spin::RwLock<keyboard::Keyboard> = spin::RwLock::new(keyboard::Keyboard::new());
+static KEYBOARD: crate::locks::RwLock<keyboard::Keyboard> = crate::locks::RwLock::new(keyboard::Keyboard::new());
 
 /// The main process thread for handling inputs.
 #[cfg(not(test))]
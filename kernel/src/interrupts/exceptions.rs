@@ -1,7 +1,7 @@
 use crate::{klog, kprintln};
 use crate::memory::{
   address::{VirtualAddress},
-  virt::page_directory::{CurrentPageDirectory, invalidate_page},
+  virt::page_directory::CurrentPageDirectory,
 };
 use super::stack::StackFrame;
 
@@ -88,13 +88,14 @@ pub extern "x86-interrupt" fn page_fault(stack_frame: StackFrame, error: u32) {
       let eip = stack_frame.eip as usize;
       kprintln!("Attempt to access kernel memory ({:#010x}) from userspace (IP {:#010x})", address, eip);
 
-      if eip == address && eip > 0xc0000000 && eip < 0xc0000010 {
-        // Userspace attempted to return to an "IRQ marker"
-        // This is our way of creating a simple developer experience for
-        // userspace interrupt handlers -- all a program needs to do is return
-        // to the fake calling address placed on its stack.
-        let irq = eip - 0xc0000000;
-        super::handlers::return_from_handler(irq);
+      if eip == address {
+        if let Some(irq) = super::installed::irq_from_marker(eip) {
+          // Userspace attempted to return to an "IRQ marker"
+          // This is our way of creating a simple developer experience for
+          // userspace interrupt handlers -- all a program needs to do is return
+          // to the fake calling address placed on its stack.
+          super::handlers::return_from_handler(irq);
+        }
       }
 
       loop {}
@@ -131,7 +132,7 @@ pub extern "x86-interrupt" fn page_fault(stack_frame: StackFrame, error: u32) {
       // Either this is a CoW modification, or a permissions violation
       // Load the page entry to determine which case should be handled
       let id = crate::task::switching::get_current_id();
-      kprintln!("Write to page {:?}", id);
+      kprintln!(Debug, Memory; "Write to page {:?}", id);
 
       let vaddr = VirtualAddress::new(address);
       let mut current_pagedir = CurrentPageDirectory::get();
@@ -139,26 +140,12 @@ pub extern "x86-interrupt" fn page_fault(stack_frame: StackFrame, error: u32) {
       if let Some(entry) = page_table_entry {
         //kprintln!("ENTRY: {:b}", entry.0);
         if entry.is_cow() {
-          let new_count = crate::memory::physical::release_frame_at_address(entry.get_address());
-          if new_count == 0 {
-            // this was the only reference to the frame, simply mark it as readable
-            //kprintln!("Entry is no longer marked COW");
-            entry.clear_cow();
-            entry.set_write_access();
-            invalidate_page(vaddr);
-            return;
+          if crate::task::paging::break_copy_on_write(entry, vaddr).is_err() {
+            // Memory is exhausted, and the write can't go anywhere. The
+            // process can't continue past the faulting instruction.
+            klog!(Error, Memory; "No memory to copy page for {:?}, terminating\n", id);
+            crate::task::exec::terminate(crate::task::signal::Signal::Kill.get_exit_status());
           }
-          kprintln!("Decrement COW, {} refs remaining", new_count);
-          let page_start = vaddr.prev_page_barrier();
-          let new_frame = crate::task::paging::duplicate_frame(page_start);
-
-          kprintln!("COW: Replacing {:?} with {:?}", entry.get_address(), new_frame.get_address());
-
-          entry.clear_cow();
-          entry.set_address(new_frame.to_frame().get_address());
-          entry.set_write_access();
-          invalidate_page(page_start);
-
           return;
         }
       }
@@ -1,77 +1,11 @@
-use crate::memory::address::VirtualAddress;
 use crate::task::{id::ProcessID, regs::SavedState};
-use spin::RwLock;
+use crate::locks::RwLock;
 use super::stack::{FullStackFrame, RestorationStack};
 
-#[derive(Copy, Clone)]
-pub struct InterruptHandler {
-  process: ProcessID,
-  function: VirtualAddress,
-  stack_top: VirtualAddress,
-}
-
-/// Store an optional installed vectors for each hardware IRQ on the PIC.
-/// Some of these will be unused, but we create them all anyways for simplicity.
-pub static INSTALLED: [RwLock<Option<InterruptHandler>>; 16] = [
-  RwLock::new(None),
-  RwLock::new(None),
-  RwLock::new(None),
-  RwLock::new(None),
-  RwLock::new(None),
-  RwLock::new(None),
-  RwLock::new(None),
-  RwLock::new(None),
-  RwLock::new(None),
-  RwLock::new(None),
-  RwLock::new(None),
-  RwLock::new(None),
-  RwLock::new(None),
-  RwLock::new(None),
-  RwLock::new(None),
-  RwLock::new(None),
-];
-
-/// Install a user-mode handler for a hardware interrupt.
-/// At this point only one interrupt handler can be installed for each IRQ
-/// number. That's all that should be necessary to implement drivers --
-/// conflicting handlers would need to interop with each other anyways.
-pub fn install_handler(irq: usize, process: ProcessID, function: VirtualAddress, stack_top: VirtualAddress) -> Result<(), ()> {
-  if irq >= 16 {
-    return Err(());
-  }
-  if process.as_u32() != 0 && stack_top.as_usize() < core::mem::size_of::<usize>() {
-    // For usermode interrupts, we need enough space on the stack to push the
-    // return address
-    return Err(());
-  }
-  match INSTALLED[irq].try_write() {
-    Some(mut handler) => {
-      *handler = Some(
-        InterruptHandler {
-          process,
-          function,
-          stack_top,
-        }
-      );
-    },
-    None => {
-      // The entry is locked. Are you trying to install a handler during an
-      // interrupt?
-      return Err(());
-    },
-  }
-  Ok(())
-}
-
-/// Attempt to fetch an installed handler function for an IRQ number.
-/// If the fetch fails (the data structure is locked?) or no handler is
-/// installed, it will return None.
-pub fn try_get_installed_handler(irq: usize) -> Option<InterruptHandler> {
-  match INSTALLED[irq].try_read() {
-    Some(inner) => *inner,
-    None => None,
-  }
-}
+pub use super::installed::{
+  install_handler, irq_marker, remove_handlers_for_process, try_get_chained_handler,
+  try_get_installed_handler, uninstall_handler, InterruptHandler,
+};
 
 /// InterruptReturnPoint tells the kernel how to resume execution at the point
 /// where the interrupt occurred. It tells us which process was executing, and
@@ -82,6 +16,8 @@ pub fn try_get_installed_handler(irq: usize) -> Option<InterruptHandler> {
 pub struct InterruptReturnPoint {
   pub process: ProcessID,
   pub frame: FullStackFrame,
+  /// Position in the IRQ's chain of the handler that is currently running
+  pub position: usize,
 }
 
 /// Stores the return info for the interrupt currently being executed. It should
@@ -119,12 +55,20 @@ pub fn enter_handler(handler: InterruptHandler, irq: usize, registers: &SavedSta
         InterruptReturnPoint {
           process: current_id,
           frame: interrupt_frame,
+          position: 0,
         }
       );
     },
     None => return,
   }
 
+  jump_to_handler(handler, irq);
+}
+
+/// Switch to the process that installed a handler, and begin executing it.
+/// This only returns if that process no longer exists.
+fn jump_to_handler(handler: InterruptHandler, irq: usize) {
+  let current_id = crate::task::switching::get_current_id();
   if handler.process.as_u32() == 0 {
     // There is no process zero -- this is an indication of a kernel-mode
     // interrupt handler.
@@ -172,7 +116,7 @@ pub fn enter_handler(handler: InterruptHandler, irq: usize, registers: &SavedSta
     sp -= 4;
     // The magic number for execution on return is 0xC000000X, where X is the
     // IRQ number.
-    (sp as *mut usize).write(0xc0000000 + irq);
+    (sp as *mut usize).write(irq_marker(irq));
   }
 
   // Enter the process with IRET
@@ -191,7 +135,7 @@ pub fn enter_handler(handler: InterruptHandler, irq: usize, registers: &SavedSta
     );
   }
 
-  unreachable!("End of enter_handler");
+  unreachable!("End of jump_to_handler");
 }
 
 pub fn return_from_handler(irq: usize) {
@@ -209,6 +153,19 @@ pub fn return_from_handler(irq: usize) {
     None => panic!("Attempted to return from an IRQ, but return info was locked"),
   };
 
+  // Other handlers chained on the same IRQ run before the interrupted code
+  // resumes. The registers saved on entry stay untouched until the last one
+  // returns.
+  let next_position = return_point.position + 1;
+  if let Some(next) = try_get_chained_handler(irq, next_position) {
+    if let Some(mut inner) = CURRENT_INTERRUPT.try_write() {
+      if let Some(point) = inner.as_mut() {
+        point.position = next_position;
+      }
+    }
+    jump_to_handler(next, irq);
+  }
+
   // Return to the memory space of the originating process
   let current_id = crate::task::switching::get_current_id();
   if return_point.process != current_id {
@@ -19,6 +19,7 @@ extern "x86-interrupt" {
   fn irq_9(frame: stack::StackFrame) -> ();
   fn irq_10(frame: stack::StackFrame) -> ();
   fn irq_11(frame: stack::StackFrame) -> ();
+  fn irq_14(frame: stack::StackFrame) -> ();
 }
 
 // Flags used in IDT entries
@@ -244,7 +245,7 @@ pub unsafe fn init() {
   IDT[0x3b].set_handler(irq_11, GateType::Interrupt);
   //IDT[0x3c].set_handler(pic::mouse, GateType::Interrupt);
   //IDT[0x3d].set_handler(pic::fpu, GateType::Interrupt);
-  //IDT[0x3e].set_handler(pic::ata_primary, GateType::Interrupt);
+  IDT[0x3e].set_handler(irq_14, GateType::Interrupt);
   //IDT[0x3f].set_handler(pic::ata_secondary, GateType::Interrupt);
 
   // With the table initialized, tell the CPU where it is
@@ -259,7 +260,14 @@ pub extern "C" fn _irq_inner(registers: SavedState, irq: usize, frame: stack::Fu
 
   let handler = match handlers::try_get_installed_handler(irq) {
     Some(handler) => handler,
-    None => return,
+    None => {
+      // Nobody owns this IRQ, possibly because its process just exited.
+      // Acknowledge it anyways, or the line stays masked forever.
+      unsafe {
+        crate::devices::PIC.acknowledge_interrupt(irq as u8);
+      }
+      return;
+    },
   };
   handlers::enter_handler(handler, irq, &registers, &frame);
   
@@ -0,0 +1,212 @@
+//! Table of the handlers installed for each hardware IRQ. Entering and leaving
+//! a handler happens in the `handlers` module; this only tracks which
+//! processes have hooked each IRQ, so that the table can be cleaned up when
+//! one of them exits.
+//! ISA cards can share an interrupt line, so each IRQ has a chain of handlers
+//! rather than a single owner. When an IRQ fires, the first handler is
+//! entered. Returning to the IRQ marker enters the next one, and only once the
+//! last handler has returned is the interrupt acknowledged and the interrupted
+//! code resumed. Each handler is expected to check whether its own device
+//! raised the interrupt, and return right away if it didn't.
+
+use alloc::vec::Vec;
+use crate::memory::address::VirtualAddress;
+use crate::task::id::ProcessID;
+use crate::locks::RwLock;
+
+/// Userspace handlers return to 0xC000000X, where X is the IRQ number. The
+/// page fault from jumping into kernel memory tells the kernel that the
+/// handler is done.
+pub const IRQ_MARKER_BASE: usize = 0xc0000000;
+
+#[derive(Copy, Clone)]
+pub struct InterruptHandler {
+  pub process: ProcessID,
+  pub function: VirtualAddress,
+  pub stack_top: VirtualAddress,
+}
+
+/// Store the chain of installed handlers for each hardware IRQ on the PIC, in
+/// the order they run. Some of these will be unused, but we create them all
+/// anyways for simplicity.
+pub static INSTALLED: [RwLock<Vec<InterruptHandler>>; 16] = [
+  RwLock::new(Vec::new()),
+  RwLock::new(Vec::new()),
+  RwLock::new(Vec::new()),
+  RwLock::new(Vec::new()),
+  RwLock::new(Vec::new()),
+  RwLock::new(Vec::new()),
+  RwLock::new(Vec::new()),
+  RwLock::new(Vec::new()),
+  RwLock::new(Vec::new()),
+  RwLock::new(Vec::new()),
+  RwLock::new(Vec::new()),
+  RwLock::new(Vec::new()),
+  RwLock::new(Vec::new()),
+  RwLock::new(Vec::new()),
+  RwLock::new(Vec::new()),
+  RwLock::new(Vec::new()),
+];
+
+/// Only these IRQs are routed through the installed handlers. The rest are
+/// either handled by the kernel itself, like the PIT and keyboard, or have no
+/// IDT entry at all, so a handler installed on them would never run.
+pub fn is_hookable(irq: usize) -> bool {
+  match irq {
+    3 | 4 | 5 | 6 | 9 | 10 | 11 | 14 => true,
+    _ => false,
+  }
+}
+
+/// The address a handler for `irq` returns to
+pub fn irq_marker(irq: usize) -> usize {
+  IRQ_MARKER_BASE + irq
+}
+
+/// Determine which IRQ's handler returned, if an address is an IRQ marker
+pub fn irq_from_marker(address: usize) -> Option<usize> {
+  if address < IRQ_MARKER_BASE {
+    return None;
+  }
+  let irq = address - IRQ_MARKER_BASE;
+  if is_hookable(irq) {
+    Some(irq)
+  } else {
+    None
+  }
+}
+
+/// Install a handler for a hardware interrupt, at the end of the IRQ's chain.
+/// Each process can have one handler per IRQ; installing another one replaces
+/// the earlier handler without moving it in the chain.
+pub fn install_handler(irq: usize, process: ProcessID, function: VirtualAddress, stack_top: VirtualAddress) -> Result<(), ()> {
+  if !is_hookable(irq) {
+    return Err(());
+  }
+  if process.as_u32() != 0 && stack_top.as_usize() < core::mem::size_of::<usize>() {
+    // For usermode interrupts, we need enough space on the stack to push the
+    // return address
+    return Err(());
+  }
+  let handler = InterruptHandler {
+    process,
+    function,
+    stack_top,
+  };
+  // If the chain is locked, someone is trying to install a handler during an
+  // interrupt
+  let mut chain = INSTALLED[irq].try_write().ok_or(())?;
+  match chain.iter_mut().find(|installed| installed.process == process) {
+    Some(existing) => *existing = handler,
+    None => chain.push(handler),
+  }
+  Ok(())
+}
+
+/// Remove the handler a process installed for an IRQ, leaving the rest of the
+/// chain in place. Fails if the process had no handler there.
+pub fn uninstall_handler(irq: usize, process: ProcessID) -> Result<(), ()> {
+  let mut chain = INSTALLED.get(irq).ok_or(())?.try_write().ok_or(())?;
+  let position = chain.iter().position(|installed| installed.process == process).ok_or(())?;
+  chain.remove(position);
+  Ok(())
+}
+
+/// Attempt to fetch the handler at some position in an IRQ's chain.
+/// If the fetch fails (the data structure is locked?) or the chain is shorter
+/// than that, it will return None.
+pub fn try_get_chained_handler(irq: usize, position: usize) -> Option<InterruptHandler> {
+  let chain = INSTALLED.get(irq)?.try_read()?;
+  chain.get(position).copied()
+}
+
+/// Attempt to fetch the first handler installed for an IRQ number, which is
+/// entered when the interrupt fires.
+pub fn try_get_installed_handler(irq: usize) -> Option<InterruptHandler> {
+  try_get_chained_handler(irq, 0)
+}
+
+/// Remove every handler installed by a process, so that an interrupt arriving
+/// after it exits doesn't jump into code that is no longer mapped. Handlers
+/// that other processes chained on the same IRQs stay installed. Returns the
+/// number of handlers that were removed.
+/// An interrupt that fires while this runs finds the handler either still in
+/// its chain, and enters it before the process is gone, or already removed,
+/// in which case it moves on to the next handler, or acknowledges the
+/// interrupt if there is none.
+pub fn remove_handlers_for_process(process: ProcessID) -> usize {
+  let mut removed = 0;
+  for slot in INSTALLED.iter() {
+    let mut chain = slot.write();
+    let before = chain.len();
+    chain.retain(|installed| installed.process != process);
+    removed += before - chain.len();
+  }
+  removed
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::memory::address::VirtualAddress;
+  use crate::task::id::ProcessID;
+  use super::{
+    install_handler, irq_from_marker, irq_marker, remove_handlers_for_process,
+    try_get_chained_handler, try_get_installed_handler, uninstall_handler,
+  };
+
+  #[test]
+  fn exiting_process_loses_handlers() {
+    let driver = ProcessID::new(12);
+    let other = ProcessID::new(13);
+    let stack = VirtualAddress::new(0xbfff0000);
+    install_handler(9, driver, VirtualAddress::new(0x1000), stack).unwrap();
+    install_handler(10, driver, VirtualAddress::new(0x1100), stack).unwrap();
+    install_handler(11, other, VirtualAddress::new(0x2000), stack).unwrap();
+
+    assert_eq!(remove_handlers_for_process(driver), 2);
+    assert!(try_get_installed_handler(9).is_none());
+    assert!(try_get_installed_handler(10).is_none());
+    assert_eq!(try_get_installed_handler(11).map(|h| h.process), Some(other));
+    // Removing again is harmless
+    assert_eq!(remove_handlers_for_process(driver), 0);
+  }
+
+  #[test]
+  fn chained_handlers_share_irq() {
+    let sound = ProcessID::new(20);
+    let network = ProcessID::new(21);
+    let stack = VirtualAddress::new(0xbfff0000);
+    install_handler(5, sound, VirtualAddress::new(0x1000), stack).unwrap();
+    install_handler(5, network, VirtualAddress::new(0x2000), stack).unwrap();
+
+    // Simulate IRQ 5 firing: the first handler is entered, and each one that
+    // returns to the marker hands off to the next
+    let run_chain = || {
+      let mut invoked = alloc::vec::Vec::new();
+      let mut position = 0;
+      while let Some(handler) = try_get_chained_handler(5, position) {
+        invoked.push(handler.function.as_usize());
+        position += 1;
+      }
+      invoked
+    };
+    assert_eq!(run_chain(), [0x1000, 0x2000]);
+    assert_eq!(irq_from_marker(irq_marker(5)), Some(5));
+
+    // Installing again replaces a process's handler in place
+    install_handler(5, sound, VirtualAddress::new(0x1800), stack).unwrap();
+    assert_eq!(run_chain(), [0x1800, 0x2000]);
+
+    uninstall_handler(5, sound).unwrap();
+    assert_eq!(run_chain(), [0x2000]);
+    assert!(uninstall_handler(5, sound).is_err());
+    assert_eq!(remove_handlers_for_process(network), 1);
+    assert!(run_chain().is_empty());
+
+    // The timer and keyboard belong to the kernel, and can't be hooked
+    assert!(install_handler(0, sound, VirtualAddress::new(0x1000), stack).is_err());
+    assert!(install_handler(1, sound, VirtualAddress::new(0x1000), stack).is_err());
+    assert_eq!(irq_from_marker(irq_marker(1)), None);
+    assert_eq!(irq_from_marker(0xbffffff0), None);
+  }
+}
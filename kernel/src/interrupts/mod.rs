@@ -6,6 +6,7 @@ pub mod exceptions;
 pub mod handlers;
 #[cfg(not(test))]
 pub mod idt;
+pub mod installed;
 #[cfg(not(test))]
 pub mod pic;
 #[cfg(not(test))]
@@ -1,13 +1,22 @@
-use crate::{devices, input, task, time, x86};
+use crate::{devices, input, locks, task, time, x86};
 use super::stack;
 
 pub extern "x86-interrupt" fn pit(_frame: stack::StackFrame) {
-  time::system::increment_offset(time::system::HUNDRED_NS_PER_TICK);
-  task::switching::update_timeouts(time::system::MS_PER_TICK);
+  time::system::tick();
+  locks::interrupt_context(|| {
+    task::switching::update_timeouts(time::system::MS_PER_TICK);
+  });
 
   unsafe {
     devices::PIC.acknowledge_interrupt(0);
   }
+  // The interrupt has been acknowledged, so the next process will keep
+  // receiving ticks. Once the running process has used up its time slice, it
+  // is switched away from. If the interrupted code is in a critical section,
+  // the switch happens when it leaves.
+  if task::scheduler::TIME_SLICE.tick() && task::preempt::PREEMPTION.request_reschedule() {
+    task::switching::yield_coop();
+  }
 }
 
 pub extern "x86-interrupt" fn keyboard(_frame: stack::StackFrame) {
@@ -23,7 +32,7 @@ pub extern "x86-interrupt" fn keyboard(_frame: stack::StackFrame) {
 
 pub extern "x86-interrupt" fn com1(_frame: stack::StackFrame) {
   unsafe {
-    input::com::handle_interrupt(0);
+    locks::interrupt_context(|| input::com::handle_interrupt(0));
     //devices::COM1.handle_interrupt();
     devices::PIC.acknowledge_interrupt(4);
   }
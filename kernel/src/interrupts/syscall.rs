@@ -1,5 +1,7 @@
 use crate::kprintln;
-use crate::syscalls::{exec, file, fs, hardware};
+use crate::syscalls::{exec, file, fs, hardware, ipc};
+use crate::syscalls::user::{copy_string_from_user, validate_user_range};
+use crate::task::signal::SignalFrame;
 use super::stack;
 use syscall::result::SystemError;
 
@@ -35,9 +37,138 @@ impl core::fmt::Debug for SavedRegisters {
   }
 }
 
+impl SavedRegisters {
+  /// Capture everything needed to resume the caller after a signal handler
+  unsafe fn to_signal_frame(&self, frame: &stack::FullStackFrame) -> SignalFrame {
+    // The syscall entry doesn't switch data segments, so these still hold the
+    // caller's values
+    let (ds, es, fs, gs): (u32, u32, u32, u32);
+    asm!(
+      "mov {0}, ds
+      mov {1}, es
+      mov {2}, fs
+      mov {3}, gs",
+      out(reg) ds,
+      out(reg) es,
+      out(reg) fs,
+      out(reg) gs,
+    );
+    SignalFrame {
+      signal: 0,
+      eax: self.eax,
+      ebx: self.ebx,
+      ecx: self.ecx,
+      edx: self.edx,
+      ebp: self.ebp,
+      esi: self.esi,
+      edi: self.edi,
+      eip: frame.eip as u32,
+      cs: frame.cs as u32,
+      eflags: frame.eflags as u32,
+      esp: frame.esp as u32,
+      ss: frame.ss as u32,
+      ds: ds & 0xffff,
+      es: es & 0xffff,
+      fs: fs & 0xffff,
+      gs: gs & 0xffff,
+    }
+  }
+
+  /// Overwrite the registers and return frame with a state saved by
+  /// `to_signal_frame`. The frame must have already been validated.
+  unsafe fn restore_signal_frame(&mut self, frame: &mut stack::FullStackFrame, saved: &SignalFrame) {
+    self.eax = saved.eax;
+    self.ebx = saved.ebx;
+    self.ecx = saved.ecx;
+    self.edx = saved.edx;
+    self.ebp = saved.ebp;
+    self.esi = saved.esi;
+    self.edi = saved.edi;
+    frame.eip = saved.eip as usize;
+    frame.cs = saved.cs as usize;
+    frame.eflags = saved.restored_flags() as usize;
+    frame.esp = saved.esp as usize;
+    frame.ss = saved.ss as usize;
+    asm!(
+      "mov ds, {0:x}
+      mov es, {1:x}
+      mov fs, {2:x}
+      mov gs, {3:x}",
+      in(reg) saved.ds,
+      in(reg) saved.es,
+      in(reg) saved.fs,
+      in(reg) saved.gs,
+    );
+  }
+}
+
+/// Calls from 32-bit userspace also push the caller's stack pointer and stack
+/// segment, which are needed to enter and leave signal handlers. VM86 code has
+/// no signal handlers.
+unsafe fn user_stack_frame(frame: &stack::StackFrame) -> Option<&mut stack::FullStackFrame> {
+  if frame.cs & 3 != 3 || frame.eflags & 0x20000 != 0 {
+    return None;
+  }
+  Some(&mut *(frame as *const stack::StackFrame as *mut stack::FullStackFrame))
+}
+
+/// Point to a four-word IPC message in the caller's memory, once the whole
+/// message has been checked to sit in userspace
+unsafe fn user_message<'a>(addr: u32) -> Result<&'a mut [u32; 4], SystemError> {
+  validate_user_range(addr as usize, core::mem::size_of::<[u32; 4]>())?;
+  Ok(&mut *(addr as *mut [u32; 4]))
+}
+
+/// Length of the `int 0x2b` instruction that makes a syscall
+const SYSCALL_INSTRUCTION_LENGTH: usize = 2;
+
+/// Sleeps report an interruption even to a handler installed with
+/// SA_RESTART, since restarting one would start the whole duration over
+fn is_restartable(syscall: u32) -> bool {
+  match syscall {
+    0x5 | 0x80 => false,
+    _ => true,
+  }
+}
+
+/// If a signal interrupted a blocked syscall, and its handler was installed
+/// with SA_RESTART, rewind the caller to the syscall instruction with the
+/// syscall number back in eax. The handler is entered first, and once it
+/// returns, the call is made again.
+unsafe fn restart_interrupted_syscall(frame: &stack::StackFrame, registers: &mut SavedRegisters, syscall: u32) {
+  let result = registers.eax;
+  if result != SystemError::Interrupted.to_code() || !is_restartable(syscall) {
+    return;
+  }
+  let user_frame = match user_stack_frame(frame) {
+    Some(user_frame) => user_frame,
+    None => return,
+  };
+  if exec::should_restart_syscall() {
+    user_frame.eip -= SYSCALL_INSTRUCTION_LENGTH;
+    registers.eax = syscall;
+  }
+}
+
+/// Before returning to userspace, enter the handler for any signal that is
+/// waiting on the current process. The handler sees the signal number as its
+/// argument, and returns to the trampoline that calls sigreturn.
+unsafe fn deliver_pending_signal(frame: &stack::StackFrame, registers: &mut SavedRegisters) {
+  let user_frame = match user_stack_frame(frame) {
+    Some(user_frame) => user_frame,
+    None => return,
+  };
+  let interrupted = registers.to_signal_frame(user_frame);
+  if let Some((esp, eip)) = exec::enter_signal_handler(&interrupted) {
+    user_frame.esp = esp as usize;
+    user_frame.eip = eip as usize;
+    user_frame.eflags = interrupted.handler_flags() as usize;
+  }
+}
+
 #[no_mangle]
 #[inline(never)]
-pub unsafe extern "C" fn _syscall_inner(_frame: &stack::StackFrame, registers: &mut SavedRegisters) {
+pub unsafe extern "C" fn _syscall_inner(frame: &stack::StackFrame, registers: &mut SavedRegisters) {
   let eax = registers.eax;
   match eax {
     // execution
@@ -50,19 +181,21 @@ pub unsafe extern "C" fn _syscall_inner(_frame: &stack::StackFrame, registers: &
       registers.eax = pid;
     },
     0x2 => { // exec
-      let path_str_ptr = &*(registers.ebx as *const syscall::StringPtr);
-      let path_str = path_str_ptr.as_str();
-      let arg_str = if registers.ecx == 0 {
-        ""
-      } else {
-        let arg_str_ptr = &*(registers.ecx as *const syscall::StringPtr);
-        arg_str_ptr.as_str()
-      };
-      let result = match exec::exec_path(path_str, arg_str, registers.edx) {
+      let path_addr = registers.ebx as usize;
+      let arg_addr = registers.ecx as usize;
+      let interp_mode = registers.edx;
+      let result = copy_string_from_user(path_addr).and_then(|path_str| {
+        let arg_str = if arg_addr == 0 {
+          alloc::string::String::new()
+        } else {
+          copy_string_from_user(arg_addr)?
+        };
+        exec::exec_path(path_str, arg_str, interp_mode)
+      });
+      registers.eax = match result {
         Ok(_) => 0,
         Err(e) => e.to_code(),
       };
-      registers.eax = result;
     },
     0x3 => { // get_pid
       let pid = exec::get_pid();
@@ -72,37 +205,109 @@ pub unsafe extern "C" fn _syscall_inner(_frame: &stack::StackFrame, registers: &
       let method = registers.ebx;
       let offset = registers.ecx;
       let result = match exec::brk(method, offset) {
-        Ok(new_cursor) => new_cursor,
-        Err(_) => SystemError::Unknown.to_code(),
+        Ok(cursor) => cursor,
+        Err(err) => err.to_code(),
       };
       registers.eax = result;
     },
     0x5 => { // sleep
       let time = registers.ebx;
-      exec::sleep(time);
+      registers.eax = match exec::sleep(time) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
     },
     0x6 => { // yield
       exec::yield_coop();
     },
-    0x7 => {
-      
+    0x7 => { // vfork
+      let pid = exec::vfork();
+      registers.eax = pid;
     },
-    0x8 => {
-      
+    0x8 => { // kill
+      let id = registers.ebx;
+      let signal = registers.ecx;
+      registers.eax = match exec::kill(id, signal) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
     },
     0x09 => { // wait_pid
       let wait_id = registers.ebx;
-      let (pid, code) = exec::wait_pid(wait_id);
+      let options = registers.edx;
       let status_ptr = registers.ecx as *mut u32;
-      *status_ptr = code;
-      registers.eax = pid;
+      registers.eax = match exec::wait_pid(wait_id, options) {
+        Ok((pid, code)) => {
+          *status_ptr = code;
+          pid
+        },
+        Err(e) => e.to_code(),
+      };
+    },
+    0x0a => { // self_exe
+      let buffer = registers.ebx as *mut u8;
+      let length = registers.ecx as usize;
+      let result = match exec::self_exe(buffer, length) {
+        Ok(path_length) => path_length,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x0b => { // setenv
+      let name_addr = registers.ebx as usize;
+      let value_addr = registers.ecx as usize;
+      let result = copy_string_from_user(name_addr).and_then(|name| {
+        let value = copy_string_from_user(value_addr)?;
+        exec::setenv(&name, &value)
+      });
+      registers.eax = match result {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x0c => { // getenv
+      let name_addr = registers.ebx as usize;
+      let buffer = registers.ecx as *mut u8;
+      let length = registers.edx as usize;
+      let result = copy_string_from_user(name_addr)
+        .and_then(|name| exec::getenv(&name, buffer, length));
+      registers.eax = match result {
+        Ok(value_length) => value_length,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x0d => { // unsetenv
+      let name_addr = registers.ebx as usize;
+      let result = copy_string_from_user(name_addr)
+        .and_then(|name| exec::unsetenv(&name));
+      registers.eax = match result {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x0e => { // munmap
+      let addr = registers.ebx;
+      let length = registers.ecx;
+      registers.eax = match exec::munmap(addr, length) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x0f => { // mlock / munlock
+      let addr = registers.ebx;
+      let length = registers.ecx;
+      let method = registers.edx;
+      registers.eax = match exec::mlock(addr, length, method) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
     },
 
     // files
     0x10 => { // open
-      let path_str_ptr = &*(registers.ebx as *const syscall::StringPtr);
-      let path_str = path_str_ptr.as_str();
-      let result = match file::open_path(path_str) {
+      let path_addr = registers.ebx as usize;
+      let flags = registers.ecx;
+      let result = match copy_string_from_user(path_addr).and_then(|path_str| file::open_path(&path_str, flags)) {
         Ok(handle) => handle,
         Err(e) => e.to_code(),
       };
@@ -137,7 +342,13 @@ pub unsafe extern "C" fn _syscall_inner(_frame: &stack::StackFrame, registers: &
       registers.eax = result;
     },
     0x14 => { // unlink
-
+      let path_addr = registers.ebx as usize;
+      let result = copy_string_from_user(path_addr)
+        .and_then(|path_str| file::unlink(&path_str));
+      registers.eax = match result {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
     },
     0x15 => { // seek
 
@@ -155,9 +366,8 @@ pub unsafe extern "C" fn _syscall_inner(_frame: &stack::StackFrame, registers: &
 
     },
     0x1a => { // opendir
-      let path_str_ptr = &*(registers.ebx as *const syscall::StringPtr);
-      let path_str = path_str_ptr.as_str();
-      let result = match file::open_dir(path_str) {
+      let path_addr = registers.ebx as usize;
+      let result = match copy_string_from_user(path_addr).and_then(|path_str| file::open_dir(&path_str)) {
         Ok(handle) => handle,
         Err(e) => e.to_code(),
       };
@@ -190,7 +400,7 @@ pub unsafe extern "C" fn _syscall_inner(_frame: &stack::StackFrame, registers: &
       let arg = registers.edx;
       let result = match file::ioctl(handle, command, arg) {
         Ok(value) => value,
-        Err(_) => SystemError::UnsupportedCommand.to_code(),
+        Err(e) => e.to_code(),
       };
       registers.eax = result;
     },
@@ -218,9 +428,8 @@ pub unsafe extern "C" fn _syscall_inner(_frame: &stack::StackFrame, registers: &
       registers.eax = result;
     },
     0x21 => { // set current drive
-      let name_str_ptr = &*(registers.ebx as *const syscall::StringPtr);
-      let name_str = name_str_ptr.as_str();
-      let result = match fs::set_current_drive(name_str) {
+      let name_addr = registers.ebx as usize;
+      let result = match copy_string_from_user(name_addr).and_then(|name_str| fs::set_current_drive(&name_str)) {
         Ok(number) => number,
         Err(e) => e.to_code(),
       };
@@ -242,9 +451,115 @@ pub unsafe extern "C" fn _syscall_inner(_frame: &stack::StackFrame, registers: &
       };
       registers.eax = result;
     },
-    0x24 => { // chdir for drive number
+    0x24 => { // chdir
+      let path_addr = registers.ebx as usize;
+      let result = copy_string_from_user(path_addr)
+        .and_then(|path_str| fs::change_directory(&path_str));
+      registers.eax = match result {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
     },
     0x25 => { // get cwd for drive number
+      let drive = registers.ebx;
+      let buffer = registers.ecx as *mut u8;
+      let length = registers.edx as usize;
+      registers.eax = match fs::get_working_directory(drive, buffer, length) {
+        Ok(length) => length,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x26 => { // fcntl
+      let handle = registers.ebx;
+      let command = registers.ecx;
+      let arg = registers.edx;
+      let result = match file::fcntl(handle, command, arg) {
+        Ok(value) => value,
+        Err(e) => e.to_code(),
+      };
+      registers.eax = result;
+    },
+    0x27 => { // access
+      let path_addr = registers.ebx as usize;
+      let mode = registers.ecx;
+      let result = copy_string_from_user(path_addr)
+        .and_then(|path_str| file::access(&path_str, mode));
+      registers.eax = match result {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x28 => { // rename
+      let old_addr = registers.ebx as usize;
+      let new_addr = registers.ecx as usize;
+      let flags = registers.edx;
+      let result = copy_string_from_user(old_addr)
+        .and_then(|old_path| {
+          let new_path = copy_string_from_user(new_addr)?;
+          file::rename(&old_path, &new_path, flags)
+        });
+      registers.eax = match result {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x29 => { // tmpfile
+      let descriptor_flags = registers.ebx;
+      registers.eax = match file::tmpfile(descriptor_flags) {
+        Ok(handle) => handle,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x2a => { // fchdir
+      let handle = registers.ebx;
+      registers.eax = match fs::change_directory_to_handle(handle) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x2b => { // find matching directory entry
+      let handle = registers.ebx;
+      let pattern_addr = registers.ecx as usize;
+      let info_ptr = registers.edx as *mut syscall::files::DirEntryInfo;
+      let result = copy_string_from_user(pattern_addr)
+        .and_then(|pattern| file::find_dir_entry(handle, &pattern, info_ptr));
+      registers.eax = match result {
+        Ok(has_more) => has_more,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x2c => { // dup3
+      let to_duplicate = registers.ebx;
+      let to_replace = registers.ecx;
+      let flags = registers.edx;
+      registers.eax = match file::dup3(to_duplicate, to_replace, flags) {
+        Ok(handle) => handle,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x2d => { // isatty
+      let handle = registers.ebx;
+      registers.eax = match file::is_terminal(handle) {
+        Ok(true) => 1,
+        Ok(false) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x2e => { // flock
+      let handle = registers.ebx;
+      let operation = registers.ecx;
+      registers.eax = match file::flock(handle, operation) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x2f => { // timerfd
+      let interval_ms = registers.ebx;
+      let flags = registers.ecx;
+      registers.eax = match file::timerfd(interval_ms, flags) {
+        Ok(handle) => handle,
+        Err(e) => e.to_code(),
+      };
     },
 
     // filesystem
@@ -257,6 +572,68 @@ pub unsafe extern "C" fn _syscall_inner(_frame: &stack::StackFrame, registers: &
     0x32 => { // mount
     },
     0x33 => { // unmount
+      let name_addr = registers.ebx as usize;
+      let flags = registers.ecx;
+      let result = copy_string_from_user(name_addr)
+        .and_then(|name| fs::unmount(&name, flags));
+      registers.eax = match result {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x34 => { // list drives
+      let buffer = registers.ebx as *mut syscall::files::DriveInfo;
+      let count = registers.ecx as usize;
+      registers.eax = match fs::list_drives(buffer, count) {
+        Ok(total) => total,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x35 => { // list devices
+      let buffer = registers.ebx as *mut syscall::files::DeviceInfo;
+      let count = registers.ecx as usize;
+      registers.eax = match fs::list_devices(buffer, count) {
+        Ok(total) => total,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x36 => { // block cache stats
+      let stats = registers.ebx as *mut syscall::files::CacheStats;
+      let reset = registers.ecx != 0;
+      registers.eax = match fs::cache_stats(stats, reset) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x37 => { // drop caches
+      registers.eax = match fs::drop_caches() {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x38 => { // sendfile
+      let out_handle = registers.ebx;
+      let in_handle = registers.ecx;
+      let count = registers.edx;
+      registers.eax = match file::sendfile(out_handle, in_handle, count) {
+        Ok(moved) => moved,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x39 => { // ftruncate
+      let handle = registers.ebx;
+      let size = registers.ecx;
+      registers.eax = match file::ftruncate(handle, size) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x3a => { // map sealed file
+      let handle = registers.ebx;
+      registers.eax = match file::map_sealed(handle) {
+        Ok(address) => address,
+        Err(e) => e.to_code(),
+      };
     },
 
     0x40 => { // install interrupt handler
@@ -265,14 +642,225 @@ pub unsafe extern "C" fn _syscall_inner(_frame: &stack::StackFrame, registers: &
       let stack_top = registers.edx;
       let result = match exec::install_interrupt_handler(irq, address, stack_top) {
         Ok(_) => 0,
-        Err(_) => 0xff,
+        Err(e) => e.to_code(),
       };
       registers.eax = result;
     },
+    0x41 => { // grant io ports
+      let start = registers.ebx;
+      let count = registers.ecx;
+      registers.eax = match hardware::grant_io_ports(start, count) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x42 => { // read io port
+      let port = registers.ebx;
+      let width = registers.ecx;
+      let dest = registers.edx as *mut u32;
+      registers.eax = match hardware::port_read(port, width, dest) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x43 => { // write io port
+      let port = registers.ebx;
+      let width = registers.ecx;
+      let value = registers.edx;
+      registers.eax = match hardware::port_write(port, width, value) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x44 => { // shutdown
+      registers.eax = match hardware::shutdown() {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+
+    0x60 => { // ipc send
+      let to = registers.ebx;
+      let sent = user_message(registers.ecx).and_then(|message| ipc::ipc_send(to, message));
+      registers.eax = match sent {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x61 => { // ipc send with handle
+      let to = registers.ebx;
+      let handle = registers.edx;
+      let sent = user_message(registers.ecx).and_then(|message| ipc::ipc_send_handle(to, message, handle));
+      registers.eax = match sent {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x62 => { // ipc read
+      registers.eax = match user_message(registers.ebx).and_then(|dest| ipc::ipc_read(dest)) {
+        Ok(from) => from,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x63 => { // ipc send with pages
+      let to = registers.ebx;
+      let flags = registers.edx;
+      let sent = user_message(registers.ecx).and_then(|message| ipc::ipc_send_pages(to, message, flags));
+      registers.eax = match sent {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x64 => { // register port
+      let name_addr = registers.ebx as usize;
+      registers.eax = match copy_string_from_user(name_addr).and_then(|name| ipc::register_port(&name)) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x65 => { // send to port
+      let name_addr = registers.ebx as usize;
+      let sent = user_message(registers.ecx).and_then(|message| {
+        copy_string_from_user(name_addr).and_then(|name| ipc::send_to_port(&name, message))
+      });
+      registers.eax = match sent {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x66 => { // receive from port
+      let name_addr = registers.ebx as usize;
+      let received = user_message(registers.ecx).and_then(|dest| {
+        copy_string_from_user(name_addr).and_then(|name| ipc::recv_from_port(&name, dest))
+      });
+      registers.eax = match received {
+        Ok(from) => from,
+        Err(e) => e.to_code(),
+      };
+    },
 
     0x50 => { // change video mode
       let mode = registers.ebx;
-      hardware::change_video_mode(mode as u8);
+      registers.eax = match hardware::change_video_mode(mode as u8) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x51 => { // set keyboard layout
+      let layout = registers.ebx;
+      registers.eax = match hardware::set_keyboard_layout(layout) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x52 => { // get video mode
+      registers.eax = match hardware::get_video_mode() {
+        Ok(mode) => mode,
+        Err(e) => e.to_code(),
+      };
+    },
+
+    0x70 => { // install signal handler
+      let signal = registers.ebx;
+      let function = registers.ecx;
+      let restorer = registers.edx;
+      registers.eax = match exec::install_signal_handler(signal, function, restorer) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x71 => { // sigreturn
+      let result = match user_stack_frame(frame) {
+        Some(user_frame) => exec::signal_return().map(|saved| {
+          registers.restore_signal_frame(user_frame, &saved);
+        }),
+        None => Err(SystemError::InvalidArgument),
+      };
+      if let Err(e) = result {
+        registers.eax = e.to_code();
+      }
+    },
+    0x72 => { // setpgid
+      let id = registers.ebx;
+      let group = registers.ecx;
+      registers.eax = match exec::setpgid(id, group) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x73 => { // getpgid
+      let id = registers.ebx;
+      registers.eax = match exec::getpgid(id) {
+        Ok(group) => group,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x74 => { // get_args
+      let buffer = registers.ebx as *mut u8;
+      let length = registers.ecx as usize;
+      let required = registers.edx as *mut u32;
+      registers.eax = match exec::get_args(buffer, length, required) {
+        Ok(count) => count,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x75 => { // waitid
+      let id = registers.ebx;
+      let info = registers.ecx as *mut syscall::signals::WaitInfo;
+      let options = registers.edx;
+      registers.eax = match exec::waitid(id, info, options) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x76 => { // dump memory map
+      let id = registers.ebx;
+      let buffer = registers.ecx as *mut u8;
+      let length = registers.edx as usize;
+      registers.eax = match exec::dump_memory_map(id, buffer, length) {
+        Ok(size) => size,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x77 => { // yield_to
+      let id = registers.ebx;
+      exec::yield_to(id);
+    },
+    0x78 => { // nice
+      let increment = registers.ebx as i32;
+      registers.eax = match exec::nice(increment) {
+        Ok(nice) => nice,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x79 => { // getrlimit
+      let resource = registers.ebx;
+      let limit = registers.ecx as *mut syscall::data::ResourceLimit;
+      registers.eax = match exec::get_resource_limit(resource, limit) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x7a => { // setrlimit
+      let resource = registers.ebx;
+      let limit = registers.ecx as *const syscall::data::ResourceLimit;
+      registers.eax = match exec::set_resource_limit(resource, limit) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
+    },
+    0x7b => { // drop privileges
+      exec::drop_privileges();
+      registers.eax = 0;
+    },
+
+    // time
+    0x80 => { // sleep until
+      let timestamp = registers.ebx;
+      registers.eax = match exec::sleep_until(timestamp) {
+        Ok(_) => 0,
+        Err(e) => e.to_code(),
+      };
     },
 
     // misc
@@ -285,4 +873,7 @@ pub unsafe extern "C" fn _syscall_inner(_frame: &stack::StackFrame, registers: &
       registers.eax = SystemError::Unknown.to_code();
     },
   }
+
+  restart_interrupted_syscall(frame, registers, eax);
+  deliver_pending_signal(frame, registers);
 }
\ No newline at end of file
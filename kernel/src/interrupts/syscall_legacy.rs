@@ -1,8 +1,10 @@
 use crate::dos::{
   devices,
+  drives,
   errors,
   execution,
   files,
+  memory,
   registers::{DosApiRegisters, VM86Frame}
 };
 use super::stack::StackFrame;
@@ -77,8 +79,8 @@ pub fn dos_api(regs: &mut DosApiRegisters, segments: &mut VM86Frame, stack_frame
     0x0e => { // Select disk
       // Set the drive letter for the "active" disk
       // %dl is zero-based, 0 == A:, 25 == Z:
-      let _drive = regs.dx & 0xff;
       // On return, set %al to the letter of available drives
+      drives::select_disk(regs);
     },
     0x0f => { // Open file using FCB
       // DS:DX points to a FCB
@@ -132,9 +134,11 @@ pub fn dos_api(regs: &mut DosApiRegisters, segments: &mut VM86Frame, stack_frame
     },
     0x19 => { // Get current drive
       // Set %al to the zero-based number representing the current drive
+      drives::get_current_disk(regs);
     },
     0x1a => { // Set DTA
       // DS:DX contains the address to the new DTA location
+      errors::with_error_code(regs, segments, stack_frame, |r, s| files::set_dta(r, s));
     },
     0x1b => { // Get FAT info for the current drive
       // Set %al to sectors per cluster
@@ -190,6 +194,7 @@ pub fn dos_api(regs: &mut DosApiRegisters, segments: &mut VM86Frame, stack_frame
     0x2e => { // Set disk verification mode
     },
     0x2f => { // Get DTA
+      errors::with_error_code(regs, segments, stack_frame, |r, s| files::get_dta_address(r, s));
     },
     0x30 => { // Get DOS Version
     },
@@ -210,10 +215,13 @@ pub fn dos_api(regs: &mut DosApiRegisters, segments: &mut VM86Frame, stack_frame
     0x38 => { // Locale-dependent info
     },
     0x39 => { // mkdir
+      errors::with_error_code(regs, segments, stack_frame, |r, s| files::make_directory(r, s));
     },
     0x3a => { // rmdir
+      errors::with_error_code(regs, segments, stack_frame, |r, s| files::remove_directory(r, s));
     },
     0x3b => { // chdir
+      errors::with_error_code(regs, segments, stack_frame, |r, s| files::change_directory(r, s));
     },
     0x3c => { // Create file using handle
     },
@@ -240,12 +248,16 @@ pub fn dos_api(regs: &mut DosApiRegisters, segments: &mut VM86Frame, stack_frame
     0x46 => { // Force dup file handle
     },
     0x47 => { // Get cwd
+      errors::with_error_code(regs, segments, stack_frame, |r, s| files::get_current_directory(r, s));
     },
     0x48 => { // Allocate memory
+      errors::with_error_code(regs, segments, stack_frame, |r, s| memory::allocate_memory(r, s));
     },
     0x49 => { // Free memory
+      errors::with_error_code(regs, segments, stack_frame, |r, s| memory::free_memory(r, s));
     },
     0x4a => { // Modify allocated memory
+      errors::with_error_code(regs, segments, stack_frame, |r, s| memory::resize_memory(r, s));
     },
     0x4b => { // Load and execute program
     },
@@ -254,8 +266,10 @@ pub fn dos_api(regs: &mut DosApiRegisters, segments: &mut VM86Frame, stack_frame
     0x4d => { // Get return code of child
     },
     0x4e => { // Find first matching file
+      errors::with_error_code(regs, segments, stack_frame, |r, s| files::find_first(r, s));
     },
     0x4f => { // Find next matching file
+      errors::with_error_code(regs, segments, stack_frame, |r, s| files::find_next(r, s));
     },
     0x50 => { // Set current PSP
     },
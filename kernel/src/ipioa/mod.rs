@@ -18,6 +18,9 @@
 //! request to determine what to do result (and if it's even still needed -- the
 //! requestor could have aborted it or exited). As a kernel service, it performs
 //! any necessary process-to-process copying, and awakens the original caller.
+//! A caller aborts a request by sending MSG_ABORT with the request ID. Exits
+//! are found when the completion arrives, by checking that the caller is still
+//! in the task map. Either way, the result is dropped instead of being copied.
 //! 
 //! Because filesystems and device drivers perform similar operations, and can
 //! both be built on the same message-passing infrastructure, a single arbiter
@@ -39,7 +42,45 @@
 //! checking the highest bit in the first message argument. This is set on all
 //! IPC requests that are sent from kernel-space code.
 
-use crate::task::switching;
+pub mod requests;
+
+use crate::task::id::ProcessID;
+use crate::task::ipc::IPCMessage;
+use requests::{Completion, RequestID, RequestTable};
+
+/// Sent by a driver when it has finished a request. The second message value
+/// is the request ID.
+pub const MSG_COMPLETE: u32 = 2;
+/// Sent by a caller that no longer wants the result of a request. The second
+/// message value is the request ID.
+pub const MSG_ABORT: u32 = 3;
+
+/// Handle a message that refers to a request already sent to a driver.
+/// Completions return what needs to happen to the caller; other messages
+/// return None.
+pub fn handle_request_message<F>(
+  table: &mut RequestTable,
+  from: ProcessID,
+  message: IPCMessage,
+  is_alive: F,
+) -> Option<Completion>
+  where F: Fn(ProcessID) -> bool {
+  let id = RequestID::new(message.1);
+  match message.0 {
+    MSG_COMPLETE => Some(table.complete(id, is_alive)),
+    MSG_ABORT => {
+      let _ = table.abort(id, from);
+      None
+    },
+    _ => None,
+  }
+}
+
+/// A caller is still alive as long as it is in the task map
+#[cfg(not(test))]
+pub fn requester_is_alive(id: ProcessID) -> bool {
+  crate::task::switching::get_process(&id).is_some()
+}
 
 pub fn ipioa_run() {
   // Perform setup
@@ -49,4 +90,26 @@ pub fn ipioa_run() {
     // Block on incoming messages
     
   }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::task::id::ProcessID;
+  use crate::task::ipc::IPCMessage;
+  use super::requests::{Completion, RequestTable};
+  use super::{handle_request_message, MSG_ABORT, MSG_COMPLETE};
+
+  #[test]
+  fn abort_message() {
+    let mut table = RequestTable::new();
+    let caller = ProcessID::new(3);
+    let driver = ProcessID::new(8);
+    let request = table.submit(caller, None);
+    let abort = IPCMessage(MSG_ABORT, request.as_u32(), 0, 0);
+    // Another process can't abort the caller's request
+    assert_eq!(handle_request_message(&mut table, driver, abort, |_| true), None);
+    assert_eq!(handle_request_message(&mut table, caller, abort, |_| true), None);
+    let complete = IPCMessage(MSG_COMPLETE, request.as_u32(), 0, 0);
+    assert_eq!(handle_request_message(&mut table, driver, complete, |_| true), Some(Completion::Discarded));
+  }
+}
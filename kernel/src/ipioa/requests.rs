@@ -0,0 +1,149 @@
+//! The arbiter tracks every request it has handed to a driver, until the
+//! driver reports that it is done. A request can stop being wanted before
+//! then: the caller can abort it, or exit. The driver is never interrupted
+//! when that happens, since it may be partway through talking to hardware.
+//! Instead, the request is marked as abandoned and its buffer is freed right
+//! away. The entry itself stays in the table until the driver completes, so
+//! that the completion can be recognized and thrown away, rather than being
+//! mistaken for a newer request that reused the same ID.
+
+use alloc::vec::Vec;
+use crate::collections::SlotList;
+use crate::task::id::ProcessID;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RequestID(u32);
+
+impl RequestID {
+  pub fn new(id: u32) -> RequestID {
+    RequestID(id)
+  }
+
+  pub fn as_u32(&self) -> u32 {
+    self.0
+  }
+}
+
+struct Request {
+  requester: ProcessID,
+  /// Kernel-side copy of the data being read or written. The arbiter copies
+  /// it to or from the requester's memory.
+  buffer: Option<Vec<u8>>,
+  aborted: bool,
+}
+
+/// What the arbiter should do with a completion from a driver
+#[derive(Debug, Eq, PartialEq)]
+pub enum Completion {
+  /// The caller is still waiting. The results need to be copied into its
+  /// memory, and the caller woken up.
+  Deliver(ProcessID, Option<Vec<u8>>),
+  /// Nobody wants the result anymore, and it has been dropped
+  Discarded,
+  /// No request has this ID, because it was already completed or never
+  /// existed
+  Unknown,
+}
+
+pub struct RequestTable {
+  requests: SlotList<Request>,
+}
+
+impl RequestTable {
+  pub const fn new() -> RequestTable {
+    RequestTable {
+      requests: SlotList::new(),
+    }
+  }
+
+  /// Start tracking a request made by a process
+  pub fn submit(&mut self, requester: ProcessID, buffer: Option<Vec<u8>>) -> RequestID {
+    let index = self.requests.insert(
+      Request {
+        requester,
+        buffer,
+        aborted: false,
+      }
+    );
+    RequestID::new(index as u32)
+  }
+
+  /// Abandon a request at the caller's request. Processes can only abort
+  /// their own requests.
+  pub fn abort(&mut self, id: RequestID, requester: ProcessID) -> Result<(), ()> {
+    let request = self.requests.get_mut(id.as_u32() as usize).ok_or(())?;
+    if request.requester != requester || request.aborted {
+      return Err(());
+    }
+    request.aborted = true;
+    request.buffer = None;
+    Ok(())
+  }
+
+  /// Handle a driver's completion of a request, and stop tracking it. A
+  /// process can exit without the arbiter hearing about it, so `is_alive` is
+  /// used to check that the caller still exists before anything is copied
+  /// into it.
+  pub fn complete<F>(&mut self, id: RequestID, is_alive: F) -> Completion
+    where F: Fn(ProcessID) -> bool {
+    let request = match self.requests.remove(id.as_u32() as usize) {
+      Some(request) => request,
+      None => return Completion::Unknown,
+    };
+    if request.aborted || !is_alive(request.requester) {
+      return Completion::Discarded;
+    }
+    Completion::Deliver(request.requester, request.buffer)
+  }
+
+  /// Count the requests that are still waiting on a driver, including any
+  /// that have been abandoned
+  pub fn in_flight(&self) -> usize {
+    self.requests.iter().count()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec;
+  use crate::task::id::ProcessID;
+  use super::{Completion, RequestID, RequestTable};
+
+  #[test]
+  fn requester_exits_before_driver_responds() {
+    let mut table = RequestTable::new();
+    let caller = ProcessID::new(4);
+    let other = ProcessID::new(5);
+    let request = table.submit(caller, Some(vec![0; 512]));
+    let kept = table.submit(other, None);
+
+    // The caller exits without telling the arbiter. Its result is dropped
+    // once the driver finishes.
+    let alive = |id: ProcessID| id != caller;
+    assert_eq!(table.complete(request, alive), Completion::Discarded);
+    assert_eq!(table.complete(kept, alive), Completion::Deliver(other, None));
+    assert_eq!(table.in_flight(), 0);
+    // A duplicate completion doesn't match anything
+    assert_eq!(table.complete(request, alive), Completion::Unknown);
+  }
+
+  #[test]
+  fn completion_after_abort() {
+    let mut table = RequestTable::new();
+    let caller = ProcessID::new(4);
+    let request = table.submit(caller, Some(vec![0; 512]));
+    // Only the caller can abort its own request, and only once
+    assert!(table.abort(request, ProcessID::new(5)).is_err());
+    assert!(table.abort(request, caller).is_ok());
+    assert!(table.abort(request, caller).is_err());
+    assert!(table.abort(RequestID::new(9), caller).is_err());
+
+    // The abandoned request holds onto its ID until the driver is done, so a
+    // new request can't be confused with it
+    let next = table.submit(caller, None);
+    assert_ne!(next, request);
+    assert_eq!(table.complete(request, |_| true), Completion::Discarded);
+    assert_eq!(table.complete(next, |_| true), Completion::Deliver(caller, None));
+    assert_eq!(table.in_flight(), 0);
+  }
+}
@@ -21,9 +21,11 @@ pub mod fs;
 pub mod hardware;
 pub mod input;
 pub mod interrupts;
+pub mod ipioa;
 pub mod loaders;
+pub mod locks;
 pub mod memory;
-//pub mod pipes;
+pub mod pipes;
 pub mod promise;
 pub mod task;
 pub mod time;
@@ -39,7 +41,6 @@ pub mod gdt;
 pub mod init;
 #[cfg(not(test))]
 pub mod panic;
-#[cfg(not(test))]
 pub mod syscalls;
 
 extern crate alloc;
@@ -98,7 +99,10 @@ unsafe fn init_tables() {
 /// Once virtual memory has been enabled, all references to kernel addresses
 /// need to be or-ed with 0xc0000000 so that they can correctly point to the
 /// kernel in all processes.
+/// This is inlined into `_start`, so that the jump to highmem moves the
+/// entry point itself. A separate function would return into the lowmem copy.
 #[cfg(not(test))]
+#[inline(always)]
 unsafe fn init_memory() {
   use memory::address::PhysicalAddress;
 
@@ -131,15 +135,16 @@ unsafe fn init_memory() {
   );
 
   memory::high_jump();
-  // CLOWNTOWN: ebx points to the PLT, and is initialized based on a relative
-  // position to the starting instruction pointer. Since we start in lowmem and
-  // then jump to highmem, it gets set to a location in user memory space, which
-  // breaks everything the first time a user process unmaps the low copy of the
-  // kernel.
-  // If we had a bootloader that initialized a page table and mapped us into
-  // highmem before entering the kernel, this wouldn't be necessary...
+  // The kernel is position-independent, and ebx holds the address of its GOT.
+  // Each function computes it from its own instruction pointer on entry, so
+  // every function called from here on gets a highmem address. The one frame
+  // that was already running before the jump still holds the lowmem address,
+  // and needs to be moved by hand, just like eip and esp.
   asm!("or ebx, 0xc0000000");
 
+  // Nothing refers to the lowmem copy of the kernel anymore
+  memory::virt::unmap_low_memory();
+
   kprintln!("\nKernel range: {:?}-{:?}", kernel_data_bounds.ro_start, kernel_data_bounds.rw_end);
 }
 
@@ -232,6 +237,7 @@ pub extern fn run_init() {
   //tty::init_ttys();
   vterm::init_vterm();
   devices::init();
+  fs::mount_boot_drive();
   time::system::initialize_from_rtc();
 
   let current_time = time::system::get_system_time().to_timestamp().to_datetime();
@@ -251,12 +257,10 @@ pub extern fn run_init() {
   }
   */
 
-  let session = vterm::begin_session(1, "INIT:\\command.elf");
-  if let Err(_) = session {
-    kprintln!("Failed to initialize shell");
-    loop {
-      task::yield_coop();
-    }
+  vterm::supervise_session(1, "INIT:\\command.elf", vterm::session::ExitAction::Respawn);
+  kprintln!("Shell session ended");
+  loop {
+    task::yield_coop();
   }
 }
 
@@ -39,6 +39,8 @@ pub fn build_environment(
         ss: Some(0x23),
       },
       require_vm: false,
+      initial_heap_size: 0,
+      arguments: Vec::new(),
     }
   )
 }
@@ -60,6 +60,8 @@ pub fn build_environment(
         ss: Some(psp_segment),
       },
       require_vm: true,
+      initial_heap_size: 0,
+      arguments: Vec::new(),
     }
   )
 }
@@ -2,25 +2,47 @@ use alloc::vec::Vec;
 use crate::files::handle::LocalHandle;
 use crate::fs::drive::DriveID;
 use crate::memory::address::VirtualAddress;
-use crate::task::memory::{ExecutionSection, ExecutionSegment};
+use crate::task::memory::{ExecutionSection, ExecutionSegment, Relocation};
 use super::LoaderError;
 use super::environment::{ExecutionEnvironment, InitialRegisters};
+use tables::{Header, ProgramHeader, SectionHeader};
 
 pub mod read;
 pub mod tables;
 
+/// Position-independent executables are linked to start at address zero.
+/// They get moved up to this base, so that the first page stays unmapped and
+/// null pointers still fault.
+pub const PIE_LOAD_BASE: u32 = 0x400000;
+
+/// Choose where an executable gets loaded. Executables with fixed addresses
+/// are loaded exactly where they ask to be.
+pub fn choose_load_base(header: &Header) -> u32 {
+  match header.object_file_type {
+    tables::OBJECT_TYPE_SHARED => PIE_LOAD_BASE,
+    _ => 0,
+  }
+}
+
 pub fn build_environment(
   drive_id: DriveID,
   local_handle: LocalHandle,
 ) -> Result<ExecutionEnvironment, LoaderError> {
 
   let (header, program_headers, section_headers) = read::load_tables(drive_id, local_handle)?;
+  let base = choose_load_base(&header);
+  let initial_heap_size = read_initial_heap_size(drive_id, local_handle, &program_headers)?;
+  let relocations = if base != 0 {
+    read_relocations(drive_id, local_handle, &header, &section_headers, base)?
+  } else {
+    Vec::new()
+  };
 
   let mut segments: Vec<ExecutionSegment> = program_headers.iter().map(|program_header| {
     if program_header.segment_type != tables::SEGMENT_TYPE_LOAD {
       return None;
     }
-    let segment_start = VirtualAddress::new(program_header.segment_virtual_address as usize);
+    let segment_start = VirtualAddress::new(program_header.segment_virtual_address.wrapping_add(base) as usize);
     let segment_end = segment_start + program_header.segment_size_in_memory as usize;
     let address = segment_start.prev_page_barrier();
     let page_count = (segment_end.next_page_barrier() - address) / 4096;
@@ -33,7 +55,7 @@ pub fn build_environment(
   .collect();
 
   for section_header in section_headers.iter() {
-    let start = VirtualAddress::new(section_header.section_virtual_address as usize);
+    let start = VirtualAddress::new(section_header.section_virtual_address.wrapping_add(base) as usize);
     for segment in segments.iter_mut() {
       // only allocate memory for sections marked ALLOC
       if section_header.section_flags & tables::SECTION_FLAG_ALLOC == 0 {
@@ -66,10 +88,10 @@ pub fn build_environment(
 
   let env = ExecutionEnvironment {
     segments,
-    relocations: Vec::new(),
+    relocations,
     registers: InitialRegisters {
       eax: None,
-      eip: Some(header.entry_point),
+      eip: Some(header.entry_point.wrapping_add(base)),
       esp: Some(0xbffffffc),
       cs: None,
       ds: None,
@@ -77,7 +99,244 @@ pub fn build_environment(
       ss: None,
     },
     require_vm: false,
+    initial_heap_size,
+    arguments: Vec::new(),
   };
 
   return Ok(env);
-}
\ No newline at end of file
+}
+
+/// Look through the executable's notes for an initial heap size. Without one,
+/// the heap starts out empty.
+fn read_initial_heap_size(
+  drive_id: DriveID,
+  local_handle: LocalHandle,
+  program_headers: &Vec<ProgramHeader>,
+) -> Result<usize, LoaderError> {
+  for program_header in program_headers.iter() {
+    if program_header.segment_type != tables::SEGMENT_TYPE_NOTE {
+      continue;
+    }
+    let notes = read::read_bytes(
+      drive_id,
+      local_handle,
+      program_header.segment_file_offset as usize,
+      program_header.segment_size_in_file as usize,
+    )?;
+    if let Some(size) = find_initial_heap_size(&notes) {
+      return Ok(size);
+    }
+  }
+  Ok(0)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+  u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+/// Walk a block of notes, each made of a name size, description size, and
+/// type, followed by the name and description padded to four bytes. Notes
+/// from other owners, like the GNU build ID, are skipped.
+fn find_initial_heap_size(notes: &[u8]) -> Option<usize> {
+  let padded = |size: usize| size.checked_add(3).map(|size| size & !3);
+  let mut offset = 0;
+  while offset + 12 <= notes.len() {
+    let name_size = read_u32(notes, offset) as usize;
+    let description_size = read_u32(notes, offset + 4) as usize;
+    let note_type = read_u32(notes, offset + 8);
+    let name_start = offset + 12;
+    let description_start = name_start.checked_add(padded(name_size)?)?;
+    let next = description_start.checked_add(padded(description_size)?)?;
+    if next > notes.len() {
+      return None;
+    }
+    let name = &notes[name_start..name_start + name_size];
+    if name == tables::NOTE_OWNER && note_type == tables::NOTE_TYPE_INITIAL_HEAP && description_size == 4 {
+      return Some(read_u32(notes, description_start) as usize);
+    }
+    offset = next;
+  }
+  None
+}
+
+/// Find the name of a section in the section header string table
+fn section_name<'a>(names: &'a [u8], section: &SectionHeader) -> &'a [u8] {
+  let start = (section.section_name_offset as usize).min(names.len());
+  let length = names[start..].iter().position(|ch| *ch == 0).unwrap_or(names.len() - start);
+  &names[start..start + length]
+}
+
+/// Collect the relocations needed to move a position-independent executable
+/// to its load base. They aren't applied here: each one is applied when the
+/// page containing it is first loaded from the file.
+fn read_relocations(
+  drive_id: DriveID,
+  local_handle: LocalHandle,
+  header: &Header,
+  section_headers: &Vec<SectionHeader>,
+  base: u32,
+) -> Result<Vec<Relocation>, LoaderError> {
+  let names = match section_headers.get(header.section_header_strings_index as usize) {
+    Some(strings) => read::read_bytes(
+      drive_id,
+      local_handle,
+      strings.section_file_offset as usize,
+      strings.section_size_in_file as usize,
+    )?,
+    None => return Ok(Vec::new()),
+  };
+  let relocation_section = section_headers.iter().find(|section| {
+    section.section_type == tables::SECTION_TYPE_REL
+      && section_name(&names, section) == tables::RELOCATION_SECTION_NAME.as_bytes()
+  });
+  let section = match relocation_section {
+    Some(section) => section,
+    // Code that only uses relative addressing doesn't need any relocations
+    None => return Ok(Vec::new()),
+  };
+  let entries = read::read_bytes(
+    drive_id,
+    local_handle,
+    section.section_file_offset as usize,
+    section.section_size_in_file as usize,
+  )?;
+  let mut relocations = Vec::with_capacity(entries.len() / tables::RELOCATION_ENTRY_SIZE);
+  for entry in entries.chunks_exact(tables::RELOCATION_ENTRY_SIZE) {
+    let offset = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]);
+    let info = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]);
+    match info & 0xff {
+      tables::RELOCATION_TYPE_NONE => (),
+      tables::RELOCATION_TYPE_RELATIVE => {
+        let address = VirtualAddress::new(offset.wrapping_add(base) as usize);
+        relocations.push(Relocation::ElfRelative(address, base));
+      },
+      _ => return Err(LoaderError::UnsupportedRelocation),
+    }
+  }
+  Ok(relocations)
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec;
+  use alloc::vec::Vec;
+  use crate::fs::DRIVES;
+  use crate::fs::drivers::testfs::TestFileSystem;
+  use crate::fs::filesystem::{FileSystemCategory, KernelFileSystem};
+  use crate::memory::address::VirtualAddress;
+  use crate::task::memory::Relocation;
+  use super::super::LoaderError;
+  use super::{build_environment, find_initial_heap_size, PIE_LOAD_BASE};
+
+  fn put_u16(image: &mut [u8], offset: usize, value: u16) {
+    image[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+  }
+
+  fn put_u32(image: &mut [u8], offset: usize, value: u32) {
+    image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+  }
+
+  fn put_section(image: &mut [u8], index: usize, fields: [u32; 6]) {
+    let start = 0x180 + index * 40;
+    for (i, field) in fields.iter().enumerate() {
+      put_u32(image, start + i * 4, *field);
+    }
+  }
+
+  /// A position-independent executable linked at address zero. Its data
+  /// section holds a pointer to 0x120, with a relative relocation for it.
+  fn pie_image() -> Vec<u8> {
+    let mut image = vec![0; 0x220];
+    image[0..7].copy_from_slice(&[0x7f, b'E', b'L', b'F', 1, 1, 1]);
+    put_u16(&mut image, 0x10, super::tables::OBJECT_TYPE_SHARED);
+    put_u16(&mut image, 0x12, 3);
+    put_u32(&mut image, 0x14, 1);
+    put_u32(&mut image, 0x18, 0x10);
+    put_u32(&mut image, 0x1c, 0x34);
+    put_u32(&mut image, 0x20, 0x180);
+    put_u16(&mut image, 0x28, 52);
+    put_u16(&mut image, 0x2a, 32);
+    put_u16(&mut image, 0x2c, 1);
+    put_u16(&mut image, 0x2e, 40);
+    put_u16(&mut image, 0x30, 4);
+    put_u16(&mut image, 0x32, 3);
+    // One loadable segment covering the whole image
+    put_u32(&mut image, 0x34, super::tables::SEGMENT_TYPE_LOAD);
+    put_u32(&mut image, 0x44, 0x180);
+    put_u32(&mut image, 0x48, 0x180);
+    put_u32(&mut image, 0x4c, 7);
+    // The pointer, and its relocation
+    put_u32(&mut image, 0x100, 0x120);
+    put_u32(&mut image, 0x140, 0x100);
+    put_u32(&mut image, 0x144, super::tables::RELOCATION_TYPE_RELATIVE);
+    let names = b"\0.data\0.rel.dyn\0.shstrtab\0";
+    image[0x150..0x150 + names.len()].copy_from_slice(names);
+    // Name, type, flags, address, offset, size
+    put_section(&mut image, 1, [1, super::tables::SECTION_TYPE_PROGBITS, 3, 0x100, 0x100, 0x40]);
+    put_section(&mut image, 2, [7, super::tables::SECTION_TYPE_REL, 2, 0x140, 0x140, 0x10]);
+    put_section(&mut image, 3, [16, super::tables::SECTION_TYPE_STRTAB, 0, 0, 0x150, names.len() as u32]);
+    image
+  }
+
+  #[test]
+  fn pie_is_relocated() {
+    let fs = TestFileSystem::new();
+    let image = fs.add_file("PIE.ELF", &pie_image());
+    let drive_id = DRIVES.mount_drive("PIE", FileSystemCategory::KernelSync, fs.mountable());
+    let handle = fs.open("PIE.ELF").unwrap();
+
+    let env = build_environment(drive_id, handle).ok().unwrap();
+    assert_eq!(env.registers.eip, Some(PIE_LOAD_BASE + 0x10));
+    assert_eq!(env.segments[0].get_starting_address(), VirtualAddress::new(PIE_LOAD_BASE as usize));
+    assert_eq!(env.relocations.len(), 1);
+    let base = match env.relocations[0] {
+      Relocation::ElfRelative(address, base) => {
+        assert_eq!(address, VirtualAddress::new(PIE_LOAD_BASE as usize + 0x100));
+        base
+      },
+      _ => panic!("Expected a relative relocation"),
+    };
+    // Applying it to the value from the file points at the relocated target
+    let mut pointer: u32 = 0x120;
+    unsafe {
+      Relocation::ElfRelative(VirtualAddress::new(&mut pointer as *mut u32 as usize), base).apply();
+    }
+    assert_eq!(pointer, PIE_LOAD_BASE + 0x120);
+
+    // Relocations that need a symbol lookup aren't supported
+    put_u32(&mut image.write(), 0x144, 1);
+    assert!(matches!(build_environment(drive_id, handle), Err(LoaderError::UnsupportedRelocation)));
+
+    // A fixed-address executable is loaded as-is, even with a relocation table
+    put_u16(&mut image.write(), 0x10, super::tables::OBJECT_TYPE_EXECUTABLE);
+    let env = build_environment(drive_id, handle).ok().unwrap();
+    assert_eq!(env.registers.eip, Some(0x10));
+    assert_eq!(env.segments[0].get_starting_address(), VirtualAddress::new(0));
+    assert!(env.relocations.is_empty());
+  }
+
+  #[test]
+  fn initial_heap_note() {
+    let mut notes = vec![0; 0x3c];
+    // A GNU build ID, which the loader doesn't care about
+    put_u32(&mut notes, 0, 4);
+    put_u32(&mut notes, 4, 8);
+    put_u32(&mut notes, 8, 3);
+    notes[12..16].copy_from_slice(b"GNU\0");
+    // An IMM-DOS note asking for 64KiB of heap
+    put_u32(&mut notes, 0x18, 8);
+    put_u32(&mut notes, 0x1c, 4);
+    put_u32(&mut notes, 0x20, super::tables::NOTE_TYPE_INITIAL_HEAP);
+    notes[0x24..0x2c].copy_from_slice(super::tables::NOTE_OWNER);
+    put_u32(&mut notes, 0x2c, 0x10000);
+    assert_eq!(find_initial_heap_size(&notes[..0x30]), Some(0x10000));
+    // Without the note, or with one cut short, the heap starts out empty
+    assert_eq!(find_initial_heap_size(&notes[..0x18]), None);
+    assert_eq!(find_initial_heap_size(&notes[..0x2e]), None);
+    put_u32(&mut notes, 0x20, 2);
+    assert_eq!(find_initial_heap_size(&notes[..0x30]), None);
+    // Sizes that would run past the end of memory don't overflow
+    put_u32(&mut notes, 0x18, 0xffffffff);
+    assert_eq!(find_initial_heap_size(&notes), None);
+  }
+}
@@ -1,3 +1,4 @@
+use alloc::vec;
 use alloc::vec::Vec;
 use crate::files::{cursor::SeekMethod, handle::LocalHandle};
 use crate::fs::{DRIVES, drive::DriveID};
@@ -50,4 +51,26 @@ pub fn load_tables(
 
     Ok((header, program_table, section_table))
   }
-}
\ No newline at end of file
+}
+
+/// Read a range of bytes from the file, such as the contents of a section
+pub fn read_bytes(
+  drive_id: DriveID,
+  local_handle: LocalHandle,
+  offset: usize,
+  length: usize,
+) -> Result<Vec<u8>, LoaderError> {
+  let (_, instance) = DRIVES.get_drive_instance(&drive_id).ok_or(LoaderError::FileNotFound)?;
+  instance.seek(local_handle, SeekMethod::Absolute(offset)).map_err(|_| LoaderError::FileNotFound)?;
+  let mut buffer = vec![0; length];
+  let mut total = 0;
+  while total < length {
+    let read = instance.read(local_handle, &mut buffer[total..]).map_err(|_| LoaderError::FileNotFound)?;
+    if read == 0 {
+      // The file ends before the range does
+      return Err(LoaderError::InvalidHeader);
+    }
+    total += read;
+  }
+  Ok(buffer)
+}
@@ -11,7 +11,7 @@ pub struct Header {
   abi_version: u8,
   padding: [u8; 7],
   /// Determines how this object file should be interpreted
-  object_file_type: u16,
+  pub object_file_type: u16,
   /// Indicates the target machine architecture
   machine: u16,
   elf_version: u32,
@@ -36,6 +36,12 @@ pub struct Header {
   pub section_header_strings_index: u16,
 }
 
+/// Executable with fixed addresses
+pub const OBJECT_TYPE_EXECUTABLE: u16 = 2;
+/// Shared object, which is also how position-independent executables are
+/// marked
+pub const OBJECT_TYPE_SHARED: u16 = 3;
+
 #[repr(C, packed)]
 pub struct ProgramHeader {
   pub segment_type: u32,
@@ -55,6 +61,14 @@ pub const SEGMENT_FLAG_READ: u32 = 4;
 pub const SEGMENT_TYPE_NULL: u32 = 0;
 pub const SEGMENT_TYPE_LOAD: u32 = 1;
 pub const SEGMENT_TYPE_DYNAMIC: u32 = 2;
+pub const SEGMENT_TYPE_NOTE: u32 = 4;
+
+/// Notes meant for the IMM-DOS loader are marked with this owner name,
+/// including its null terminator
+pub const NOTE_OWNER: &[u8] = b"IMM-DOS\0";
+/// Asks for part of the heap to be reserved at startup. The description is
+/// the size in bytes, as a little-endian u32.
+pub const NOTE_TYPE_INITIAL_HEAP: u32 = 1;
 
 #[repr(C, packed)]
 pub struct SectionHeader {
@@ -97,4 +111,16 @@ pub const SECTION_FLAG_WRITE: u32 = 1;
 pub const SECTION_FLAG_ALLOC: u32 = 2;
 pub const SECTION_FLAG_EXEC: u32 = 4;
 pub const SECTION_FLAG_MERGE: u32 = 0x10;
-pub const SECTION_FLAG_STRINGS: u32 = 0x20;
\ No newline at end of file
+pub const SECTION_FLAG_STRINGS: u32 = 0x20;
+
+/// Name of the section holding relocations for a position-independent
+/// executable
+pub const RELOCATION_SECTION_NAME: &str = ".rel.dyn";
+
+/// Size of each entry in a REL section
+pub const RELOCATION_ENTRY_SIZE: usize = 8;
+
+/// Relocation types for i386. Each REL entry is an offset, followed by a word
+/// with the relocation type in its low byte.
+pub const RELOCATION_TYPE_NONE: u32 = 0;
+pub const RELOCATION_TYPE_RELATIVE: u32 = 8;
@@ -1,3 +1,4 @@
+use alloc::string::String;
 use alloc::vec::Vec;
 use crate::task::memory::{ExecutionSegment, Relocation};
 
@@ -18,4 +19,12 @@ pub struct ExecutionEnvironment {
   pub relocations: Vec<Relocation>,
   pub registers: InitialRegisters,
   pub require_vm: bool,
+  /// Bytes of heap to reserve before the program starts, so it doesn't need
+  /// to call brk for memory it always uses. Most executables leave this at
+  /// zero.
+  pub initial_heap_size: usize,
+  /// The program's arguments, starting with the path it was run as. When an
+  /// interpreter script is run, the interpreter comes first, followed by its
+  /// argument from the script and the script's own path.
+  pub arguments: Vec<String>,
 }
\ No newline at end of file
@@ -0,0 +1,112 @@
+//! When an executable has no magic number that identifies its format, the
+//! loader falls back to its file extension. This table maps extensions to
+//! formats. Extensions are compared without regard to case, since files on a
+//! DOS disk are usually named in uppercase.
+//! The built-in entries cover the formats the kernel ships with. More can be
+//! registered at runtime, and take priority over the built-in ones.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::locks::RwLock;
+use super::ExecutableFormat;
+
+/// Used when a file has no extension, or one that isn't in the table
+pub const DEFAULT_FORMAT: ExecutableFormat = ExecutableFormat::BIN;
+
+const BUILT_IN: [(&str, ExecutableFormat); 5] = [
+  ("bin", ExecutableFormat::BIN),
+  ("com", ExecutableFormat::COM),
+  // DOS decides how to load a program by its signature, not its name. An EXE
+  // without the MZ header is run as a flat COM image.
+  ("exe", ExecutableFormat::COM),
+  ("elf", ExecutableFormat::ELF),
+  // Native drivers, like the ones started at boot
+  ("sys", ExecutableFormat::BIN),
+];
+
+pub struct ExtensionTable {
+  registered: RwLock<Vec<(String, ExecutableFormat)>>,
+}
+
+impl ExtensionTable {
+  pub const fn new() -> ExtensionTable {
+    ExtensionTable {
+      registered: RwLock::new(Vec::new()),
+    }
+  }
+
+  /// Load files with an extension as a specific format, replacing any
+  /// earlier mapping for the same extension. The extension is given without
+  /// its leading dot.
+  pub fn register(&self, extension: &str, format: ExecutableFormat) -> Result<(), ()> {
+    if extension.is_empty() || extension.contains(|c: char| c == '.' || c == '\\' || c == '/') {
+      return Err(());
+    }
+    let mut registered = self.registered.write();
+    match registered.iter_mut().find(|(known, _)| known.eq_ignore_ascii_case(extension)) {
+      Some(entry) => entry.1 = format,
+      None => registered.push((extension.to_ascii_lowercase(), format)),
+    }
+    Ok(())
+  }
+
+  /// Determine the format of a file with no recognizable magic number
+  pub fn format_for(&self, extension: Option<&str>) -> ExecutableFormat {
+    let extension = match extension {
+      Some(extension) => extension,
+      None => return DEFAULT_FORMAT,
+    };
+    let registered = self.registered.read()
+      .iter()
+      .find(|(known, _)| known.eq_ignore_ascii_case(extension))
+      .map(|(_, format)| *format);
+    registered
+      .or_else(|| {
+        BUILT_IN.iter()
+          .find(|(known, _)| known.eq_ignore_ascii_case(extension))
+          .map(|(_, format)| *format)
+      })
+      .unwrap_or(DEFAULT_FORMAT)
+  }
+}
+
+pub static EXTENSIONS: ExtensionTable = ExtensionTable::new();
+
+/// Load files with an extension as a specific format, when they have no magic
+/// number of their own
+pub fn register_extension(extension: &str, format: ExecutableFormat) -> Result<(), ()> {
+  EXTENSIONS.register(extension, format)
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::files::filename::get_extension;
+  use super::super::ExecutableFormat;
+  use super::ExtensionTable;
+
+  #[test]
+  fn extension_to_format() {
+    let table = ExtensionTable::new();
+    let format_of = |path: &str| table.format_for(get_extension(path));
+    assert_eq!(format_of("A:\\GAME.COM"), ExecutableFormat::COM);
+    assert_eq!(format_of("A:\\game.com"), ExecutableFormat::COM);
+    assert_eq!(format_of("A:\\OLD.EXE"), ExecutableFormat::COM);
+    assert_eq!(format_of("A:\\TOOL.Elf"), ExecutableFormat::ELF);
+    assert_eq!(format_of("INIT:\\DRIVER.SYS"), ExecutableFormat::BIN);
+    assert_eq!(format_of("INIT:\\SHELL.BIN"), ExecutableFormat::BIN);
+    // Anything else runs as a native flat binary
+    assert_eq!(format_of("A:\\README.TXT"), ExecutableFormat::BIN);
+    assert_eq!(format_of("A:\\PROGRAM"), ExecutableFormat::BIN);
+    assert_eq!(format_of("A:\\DIR.D\\PROGRAM"), ExecutableFormat::BIN);
+
+    // Registered mappings can add extensions, or override built-in ones
+    table.register("ovl", ExecutableFormat::MZ).unwrap();
+    table.register("SYS", ExecutableFormat::COM).unwrap();
+    assert_eq!(format_of("A:\\GAME.OVL"), ExecutableFormat::MZ);
+    assert_eq!(format_of("A:\\ANSI.SYS"), ExecutableFormat::COM);
+    table.register("Sys", ExecutableFormat::ELF).unwrap();
+    assert_eq!(format_of("A:\\ANSI.sys"), ExecutableFormat::ELF);
+    assert!(table.register("", ExecutableFormat::COM).is_err());
+    assert!(table.register(".com", ExecutableFormat::BIN).is_err());
+  }
+}
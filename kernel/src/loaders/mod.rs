@@ -4,7 +4,13 @@
 //! Once processing is done, it returns an "Environment" containing information
 //! on how to copy program data into memory, what to set the initial registers
 //! to, and how to perform any relocations.
+//! Native programs that begin with `#!` are interpreter scripts. Rather than
+//! being loaded themselves, they cause the interpreter named on their first
+//! line to be loaded, with the script's path added to its arguments.
 
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use crate::files::cursor::SeekMethod;
 use crate::files::filename;
 use crate::files::handle::LocalHandle;
 use crate::fs::{drive::DriveID, DRIVES};
@@ -14,8 +20,10 @@ pub mod bin;
 pub mod com;
 pub mod elf;
 pub mod environment;
+pub mod extensions;
 pub mod mz;
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum ExecutableFormat {
   /// Native 32-bit binary using IMM-DOS syscalls and linear memory
   BIN,
@@ -26,12 +34,22 @@ pub enum ExecutableFormat {
   COM,
   /// 16-bit DOS MZ Executable,
   MZ,
+  /// Text file whose first line names the interpreter that runs it
+  Script,
 }
 
+/// An interpreter script may name another script as its interpreter, but only
+/// this many times before the load fails
+pub const MAX_INTERPRETER_DEPTH: usize = 4;
+
+/// The `#!` line of a script has to fit in this many bytes
+const MAX_SHEBANG_LENGTH: usize = 128;
+
 /// Tells the kernel what type of executable it should expect
 pub enum InterpretationMode {
   /// Attempt to determine the executable type from magic numbers.
-  /// If none is detected, it will be interpreted as a native static binary.
+  /// If none is detected, the file extension decides, and unknown extensions
+  /// are interpreted as a native static binary.
   Detect,
   /// Interpret it as a native IMM-DOS program, either ELF or BIN
   Native,
@@ -53,6 +71,11 @@ pub enum LoaderError {
   FileNotFound,
   InternalError,
   InvalidHeader,
+  /// Interpreter scripts were nested deeper than MAX_INTERPRETER_DEPTH
+  TooManyInterpreters,
+  /// A position-independent executable needs a kind of relocation that the
+  /// loader can't perform
+  UnsupportedRelocation,
 }
 
 impl LoaderError {
@@ -61,10 +84,44 @@ impl LoaderError {
       LoaderError::FileNotFound => SystemError::NoSuchEntity,
       LoaderError::InternalError => SystemError::Unknown,
       LoaderError::InvalidHeader => SystemError::Unknown,
+      LoaderError::TooManyInterpreters => SystemError::TooManyLevels,
+      LoaderError::UnsupportedRelocation => SystemError::Unknown,
     }
   }
 }
 
+/// The interpreter named on the first line of a script, and the optional
+/// argument that follows it
+pub struct Shebang {
+  pub interpreter: String,
+  pub argument: Option<String>,
+}
+
+/// Parse the `#!PATH ARGS` line at the start of a script. Like other systems,
+/// everything after the interpreter path is passed as a single argument.
+pub fn parse_shebang(header: &[u8]) -> Result<Shebang, LoaderError> {
+  if !header.starts_with(b"#!") {
+    return Err(LoaderError::InvalidHeader);
+  }
+  let line = match header.iter().position(|&b| b == b'\n') {
+    Some(end) => &header[2..end],
+    // The whole header was read without finding the end of the line
+    None if header.len() >= MAX_SHEBANG_LENGTH => return Err(LoaderError::InvalidHeader),
+    None => &header[2..],
+  };
+  let line = core::str::from_utf8(line).map_err(|_| LoaderError::InvalidHeader)?.trim();
+  let mut parts = line.splitn(2, |c: char| c == ' ' || c == '\t');
+  let interpreter = parts.next().unwrap_or("");
+  if interpreter.is_empty() {
+    return Err(LoaderError::InvalidHeader);
+  }
+  let argument = parts.next().map(|arg| arg.trim()).filter(|arg| !arg.is_empty());
+  Ok(Shebang {
+    interpreter: interpreter.to_string(),
+    argument: argument.map(|arg| arg.to_string()),
+  })
+}
+
 /// Read the header of an open file, and based on the current interpretation
 /// mode, attempt to determine what kind of executable file it is.
 pub fn determine_format(
@@ -78,23 +135,24 @@ pub fn determine_format(
   let _ = instance.read(local_handle, &mut magic_number).map_err(|_| LoaderError::FileNotFound)?;
   let is_elf = magic_number == [0x7f, 0x45, 0x4c, 0x46];
   let is_mz = magic_number[0..2] == [b'M', b'Z'] || magic_number[0..2] == [b'Z', b'M'];
+  let is_script = magic_number[0..2] == [b'#', b'!'];
   let format = match interp_mode {
     InterpretationMode::Detect => {
       if is_elf {
         ExecutableFormat::ELF
       } else if is_mz {
         ExecutableFormat::MZ
+      } else if is_script {
+        ExecutableFormat::Script
       } else {
-        match extension {
-          Some("com") => ExecutableFormat::COM,
-          Some("bin") => ExecutableFormat::BIN,
-          _ => ExecutableFormat::BIN,
-        }
+        extensions::EXTENSIONS.format_for(extension)
       }
     },
     InterpretationMode::Native => {
       if is_elf {
         ExecutableFormat::ELF
+      } else if is_script {
+        ExecutableFormat::Script
       } else {
         ExecutableFormat::BIN
       }
@@ -112,10 +170,22 @@ pub fn determine_format(
 
 /// Open an executable file, read its headers to determine how it should be set
 /// up in memory, and export the information necessary for a process to run this
-/// binary file
+/// binary file. `args` are the arguments that follow the program's path.
+/// If the file is an interpreter script, the interpreter is loaded instead, and
+/// the returned handle belongs to the interpreter.
 pub fn load_executable(
   path_str: &str,
   interp_mode: InterpretationMode,
+  args: Vec<String>,
+) -> Result<(DriveID, LocalHandle, environment::ExecutionEnvironment), LoaderError> {
+  load_executable_at_depth(path_str, interp_mode, args, 0)
+}
+
+fn load_executable_at_depth(
+  path_str: &str,
+  interp_mode: InterpretationMode,
+  args: Vec<String>,
+  depth: usize,
 ) -> Result<(DriveID, LocalHandle, environment::ExecutionEnvironment), LoaderError> {
   let (drive_id, full_path) = crate::task::io::get_drive_id_and_path(path_str).map_err(|_| LoaderError::FileNotFound)?;
   let (_, instance) = DRIVES.get_drive_instance(&drive_id).ok_or(LoaderError::FileNotFound)?;
@@ -123,8 +193,67 @@ pub fn load_executable(
 
   let ext = filename::get_extension(path_str);
 
-  let format = determine_format(drive_id, local_handle, interp_mode, ext)?;
-  let env = match format {
+  let format = match determine_format(drive_id, local_handle, interp_mode, ext) {
+    Ok(format) => format,
+    Err(e) => {
+      let _ = instance.close(local_handle);
+      return Err(e);
+    },
+  };
+  if let ExecutableFormat::Script = format {
+    // The interpreter opens the script again by its path, so this handle is
+    // only needed to read the first line
+    let header = read_shebang(drive_id, local_handle);
+    let _ = instance.close(local_handle);
+    let shebang = header.and_then(|header| parse_shebang(&header))?;
+    if depth >= MAX_INTERPRETER_DEPTH {
+      return Err(LoaderError::TooManyInterpreters);
+    }
+    let mut interpreter_args = Vec::with_capacity(args.len() + 2);
+    interpreter_args.extend(shebang.argument);
+    interpreter_args.push(path_str.to_string());
+    interpreter_args.extend(args);
+    return load_executable_at_depth(&shebang.interpreter, InterpretationMode::Detect, interpreter_args, depth + 1);
+  }
+
+  let env = build_environment(drive_id, local_handle, format).map(|mut env| {
+    env.arguments.push(path_str.to_string());
+    env.arguments.extend(args);
+    env
+  });
+  match env {
+    Ok(env) => Ok((drive_id, local_handle, env)),
+    Err(e) => {
+      // Nothing will run from the file, so it shouldn't stay open
+      let _ = instance.close(local_handle);
+      Err(e)
+    },
+  }
+}
+
+/// Read the start of a script, up to the longest allowed `#!` line
+fn read_shebang(drive_id: DriveID, local_handle: LocalHandle) -> Result<Vec<u8>, LoaderError> {
+  let (_, instance) = DRIVES.get_drive_instance(&drive_id).ok_or(LoaderError::FileNotFound)?;
+  instance.seek(local_handle, SeekMethod::Absolute(0)).map_err(|_| LoaderError::InternalError)?;
+  let mut header = alloc::vec![0; MAX_SHEBANG_LENGTH];
+  let mut length = 0;
+  while length < MAX_SHEBANG_LENGTH {
+    let read = instance.read(local_handle, &mut header[length..]).map_err(|_| LoaderError::FileNotFound)?;
+    if read == 0 {
+      break;
+    }
+    length += read;
+  }
+  header.truncate(length);
+  Ok(header)
+}
+
+fn build_environment(
+  drive_id: DriveID,
+  local_handle: LocalHandle,
+  format: ExecutableFormat,
+) -> Result<environment::ExecutionEnvironment, LoaderError> {
+  match format {
     ExecutableFormat::BIN => {
       bin::build_environment(drive_id, local_handle)
     },
@@ -137,10 +266,79 @@ pub fn load_executable(
     ExecutableFormat::MZ => {
       mz::build_environment(drive_id, local_handle)
     },
-    _ => {
-      // Not supported yet
-      return Err(LoaderError::InternalError);
-    },
-  }?;
-  Ok((drive_id, local_handle, env))
+    ExecutableFormat::Script => Err(LoaderError::InvalidHeader),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::string::String;
+  use alloc::vec;
+  use alloc::vec::Vec;
+  use crate::fs::DRIVES;
+  use crate::fs::drivers::testfs::TestFileSystem;
+  use crate::fs::filesystem::FileSystemCategory;
+  use super::{load_executable, parse_shebang, InterpretationMode, LoaderError};
+
+  /// An MZ header claiming 10 pages of code, in a file only 28 bytes long
+  const TRUNCATED_EXE: [u8; 28] = [
+    b'M', b'Z', 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0xff, 0xff,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1c, 0x00, 0x00, 0x00,
+  ];
+
+  #[test]
+  fn failed_load_closes_file() {
+    let fs = TestFileSystem::new();
+    fs.add_file("GAME.EXE", &TRUNCATED_EXE);
+    DRIVES.mount_drive("BADEXE", FileSystemCategory::KernelSync, fs.mountable());
+    let result = load_executable("BADEXE:\\GAME.EXE", InterpretationMode::DOS, Vec::new());
+    assert!(matches!(result, Err(LoaderError::InvalidHeader)));
+    // Nothing was handed to the process, so the file was closed again
+    assert_eq!(fs.open_count(), 0);
+    assert!(matches!(load_executable("NODRIVE:\\GAME.EXE", InterpretationMode::DOS, Vec::new()), Err(LoaderError::FileNotFound)));
+  }
+
+  #[test]
+  fn shebang_parsing() {
+    let shebang = parse_shebang(b"#!A:\\BIN\\SHELL.BIN -e -x\nrest").ok().unwrap();
+    assert_eq!(shebang.interpreter, "A:\\BIN\\SHELL.BIN");
+    assert_eq!(shebang.argument.as_deref(), Some("-e -x"));
+    let shebang = parse_shebang(b"#!  A:\\SHELL.BIN  \r\n").ok().unwrap();
+    assert_eq!(shebang.interpreter, "A:\\SHELL.BIN");
+    assert_eq!(shebang.argument, None);
+    assert!(matches!(parse_shebang(b"#!\n"), Err(LoaderError::InvalidHeader)));
+    // The line has to end within the header
+    let mut long_line = [b'A'; 128];
+    long_line[..2].copy_from_slice(b"#!");
+    assert!(matches!(parse_shebang(&long_line), Err(LoaderError::InvalidHeader)));
+  }
+
+  #[test]
+  fn script_runs_interpreter() {
+    // A native shell and some scripts that run it
+    let fs = TestFileSystem::new();
+    fs.add_file("SHELL.BIN", &[0x90, 0x90, 0xc3]);
+    fs.add_file("HELLO.SH", b"#!SCRIPTS:\\SHELL.BIN -e -x\r\necho hello\n");
+    fs.add_file("NESTED.SH", b"#! SCRIPTS:\\HELLO.SH\n");
+    fs.add_file("LOOP.SH", b"#!SCRIPTS:\\LOOP.SH\n");
+    fs.add_file("ORPHAN.SH", b"#!SCRIPTS:\\MISSING.BIN\n");
+    DRIVES.mount_drive("SCRIPTS", FileSystemCategory::KernelSync, fs.mountable());
+    let args = vec![String::from("world")];
+    let (_, _, env) = load_executable("SCRIPTS:\\HELLO.SH", InterpretationMode::Detect, args).ok().unwrap();
+    assert_eq!(env.arguments, vec!["SCRIPTS:\\SHELL.BIN", "-e -x", "SCRIPTS:\\HELLO.SH", "world"]);
+    assert!(!env.require_vm);
+
+    // A script can name another script, which adds its own arguments
+    let (_, _, env) = load_executable("SCRIPTS:\\NESTED.SH", InterpretationMode::Native, Vec::new()).ok().unwrap();
+    assert_eq!(env.arguments, vec!["SCRIPTS:\\SHELL.BIN", "-e -x", "SCRIPTS:\\HELLO.SH", "SCRIPTS:\\NESTED.SH"]);
+
+    assert!(matches!(
+      load_executable("SCRIPTS:\\ORPHAN.SH", InterpretationMode::Detect, Vec::new()),
+      Err(LoaderError::FileNotFound),
+    ));
+    assert!(matches!(
+      load_executable("SCRIPTS:\\LOOP.SH", InterpretationMode::Detect, Vec::new()),
+      Err(LoaderError::TooManyInterpreters),
+    ));
+  }
 }
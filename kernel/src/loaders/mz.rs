@@ -42,12 +42,85 @@ pub struct MZHeader {
   overlay_number: u16,
 }
 
+/// Size of the fixed portion of the header, before any relocation table
+pub const MZ_HEADER_SIZE: usize = 28;
+
+/// DOS accepts two signatures for an EXE file. Some early linkers wrote the
+/// magic number reversed, as "ZM". DOS treats both the same way: the fields
+/// that follow have the same little-endian layout regardless of which order
+/// the signature bytes appear in.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MagicOrder {
+  MZ,
+  ZM,
+}
+
+impl MagicOrder {
+  pub fn detect(bytes: &[u8]) -> Option<MagicOrder> {
+    match bytes.get(0..2)? {
+      b"MZ" => Some(MagicOrder::MZ),
+      b"ZM" => Some(MagicOrder::ZM),
+      _ => None,
+    }
+  }
+}
+
 impl MZHeader {
+  /// Parse the fixed portion of an EXE header. Each field is read as a
+  /// little-endian value, whichever magic number the file uses. Headers that
+  /// describe more data than the file actually contains are rejected.
+  pub fn parse(bytes: &[u8], file_size: usize) -> Result<MZHeader, LoaderError> {
+    if bytes.len() < MZ_HEADER_SIZE {
+      return Err(LoaderError::InvalidHeader);
+    }
+    MagicOrder::detect(bytes).ok_or(LoaderError::InvalidHeader)?;
+    let field = |index: usize| u16::from_le_bytes([bytes[2 + index * 2], bytes[3 + index * 2]]);
+    let header = MZHeader {
+      magic_number: [bytes[0], bytes[1]],
+      last_page_size: field(0),
+      page_count: field(1),
+      relocation_entries: field(2),
+      header_size_paragraphs: field(3),
+      min_alloc_paragraphs: field(4),
+      max_alloc_paragraphs: field(5),
+      initial_ss: field(6),
+      initial_sp: field(7),
+      checksum: field(8),
+      initial_ip: field(9),
+      initial_cs: field(10),
+      relocation_table_offset: field(11),
+      overlay_number: field(12),
+    };
+    if header.page_count < 1 || header.last_page_size > 512 {
+      return Err(LoaderError::InvalidHeader);
+    }
+    let byte_length = header.byte_length();
+    if byte_length > file_size || header.header_size_bytes() > byte_length {
+      return Err(LoaderError::InvalidHeader);
+    }
+    let relocation_table_end = header.relocation_table_offset as usize
+      + header.relocation_entries as usize * core::mem::size_of::<SegmentedAddress>();
+    if relocation_table_end > file_size {
+      return Err(LoaderError::InvalidHeader);
+    }
+    Ok(header)
+  }
+
+  pub fn magic_order(&self) -> Option<MagicOrder> {
+    MagicOrder::detect(&self.magic_number)
+  }
+
+  /// The number of bytes in the executable image, including the header. A
+  /// final page size of zero means the last page is full.
   pub fn byte_length(&self) -> usize {
     if self.page_count == 0 {
       return 0;
     }
-    (self.page_count as usize - 1) * 512 + (self.last_page_size as usize)
+    let last_page_size = match self.last_page_size {
+      0 => 512,
+      size => size as usize,
+    };
+    (self.page_count as usize - 1) * 512 + last_page_size
   }
 
   pub fn header_size_bytes(&self) -> usize {
@@ -66,30 +139,18 @@ pub fn build_environment(
   drive_id: DriveID,
   local_handle: LocalHandle,
 ) -> Result<ExecutionEnvironment, LoaderError> {
-  let header = unsafe {
-    let mut header: MZHeader = core::mem::zeroed::<MZHeader>();
-    let header_slice = core::slice::from_raw_parts_mut(
-      &mut header as *mut MZHeader as *mut u8,
-      core::mem::size_of::<MZHeader>(),
-    );
-
-    read_exec_file(drive_id, local_handle, 0, header_slice)?;
-
-    header
+  let file_size = {
+    let (_, instance) = DRIVES.get_drive_instance(&drive_id).ok_or(LoaderError::FileNotFound)?;
+    let mut stat = syscall::files::FileStatus::empty();
+    let _ = instance.stat(local_handle, &mut stat).map_err(|_| LoaderError::FileNotFound)?;
+    stat.byte_size
   };
+  let mut header_bytes = [0u8; MZ_HEADER_SIZE];
+  read_exec_file(drive_id, local_handle, 0, &mut header_bytes)?;
+  let header = MZHeader::parse(&header_bytes, file_size)?;
 
-  if header.page_count < 1 {
-    return Err(LoaderError::InternalError);
-  }
-  let mut file_size = (header.page_count as usize - 1) * 512;
-  file_size += if header.last_page_size == 0 {
-    512
-  } else {
-    header.last_page_size as usize
-  };
-
-  let code_start = header.header_size_paragraphs as usize * 16;
-  let exec_size = file_size - code_start;
+  let code_start = header.header_size_bytes();
+  let exec_size = header.byte_length() - code_start;
 
   // segment location of the PSP
   let psp_segment: usize = 0x100;
@@ -173,6 +234,74 @@ pub fn build_environment(
         ss: Some(header.initial_ss as u32 + load_module_segment),
       },
       require_vm: true,
+      initial_heap_size: 0,
+      arguments: Vec::new(),
     }
   )
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+  use super::{MagicOrder, MZHeader, MZ_HEADER_SIZE};
+
+  fn header_bytes(magic: &[u8; 2], fields: &[u16; 13]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(MZ_HEADER_SIZE);
+    bytes.extend_from_slice(magic);
+    for field in fields.iter() {
+      bytes.extend_from_slice(&field.to_le_bytes());
+    }
+    bytes
+  }
+
+  // 3 pages with 0x40 bytes in the last one, 2 relocations at 0x1c, a
+  // 0x20-byte header, SS:SP 0010:0200, CS:IP 0002:0100
+  const FIELDS: [u16; 13] = [
+    0x40, 3, 2, 2, 0x10, 0xffff, 0x10, 0x200, 0, 0x100, 2, 0x1c, 0,
+  ];
+
+  #[test]
+  fn both_magic_orders() {
+    for (magic, order) in [(b"MZ", MagicOrder::MZ), (b"ZM", MagicOrder::ZM)].iter() {
+      let bytes = header_bytes(magic, &FIELDS);
+      let header = MZHeader::parse(&bytes, 0x440).ok().unwrap();
+      assert_eq!(header.magic_order(), Some(*order));
+      assert_eq!(header.byte_length(), 0x440);
+      assert_eq!(header.header_size_bytes(), 0x20);
+      assert_eq!({ header.relocation_entries }, 2);
+      assert_eq!({ header.relocation_table_offset }, 0x1c);
+      assert_eq!({ header.initial_ss }, 0x10);
+      assert_eq!({ header.initial_sp }, 0x200);
+      assert_eq!({ header.initial_ip }, 0x100);
+      assert_eq!({ header.initial_cs }, 2);
+    }
+    assert!(MZHeader::parse(&header_bytes(b"MM", &FIELDS), 0x440).is_err());
+    assert!(MZHeader::parse(&header_bytes(b"MZ", &FIELDS)[..20], 0x440).is_err());
+  }
+
+  #[test]
+  fn declared_size_must_fit_in_file() {
+    for magic in [b"MZ", b"ZM"].iter() {
+      let bytes = header_bytes(magic, &FIELDS);
+      assert!(MZHeader::parse(&bytes, 0x43f).is_err());
+      // Extra data past the image, like an overlay, is allowed
+      assert!(MZHeader::parse(&bytes, 0x800).is_ok());
+
+      // A last page size of zero means the final page is full
+      let mut full_page = FIELDS;
+      full_page[0] = 0;
+      let header = MZHeader::parse(&header_bytes(magic, &full_page), 0x600).ok().unwrap();
+      assert_eq!(header.byte_length(), 0x600);
+      assert!(MZHeader::parse(&header_bytes(magic, &full_page), 0x5ff).is_err());
+
+      // The header can't be larger than the image it describes
+      let mut oversized_header = FIELDS;
+      oversized_header[3] = 0x45;
+      assert!(MZHeader::parse(&header_bytes(magic, &oversized_header), 0x800).is_err());
+
+      // Neither can the relocation table extend past the end of the file
+      let mut relocations = FIELDS;
+      relocations[2] = 0x200;
+      assert!(MZHeader::parse(&header_bytes(magic, &relocations), 0x440).is_err());
+    }
+  }
+}
@@ -0,0 +1,347 @@
+//! The kernel uses a number of global locks, and some code paths need to hold
+//! more than one of them at a time. To avoid deadlocks, locks must always be
+//! acquired in a consistent order. Each global lock is assigned a level, and a
+//! lock may only be acquired while every lock already held has a lower (or the
+//! same) level:
+//!
+//!   TASK_MAP -> Process -> KERNEL_STACKS -> DRIVES -> DEVICES
+//!     -> KERNEL_MEMORY -> REF_COUNT -> ALLOCATOR -> RUN_QUEUE
+//!
+//! Multiple locks of the same level (like two Process locks) should be taken
+//! in ascending order of ID. The kernel runs on a single CPU, so code can only
+//! interleave through a context switch or an interrupt, which begins with a
+//! clean slate of held locks.
+//!
+//! In debug builds, including tests, acquiring a lock out of order triggers an
+//! assertion.
+//! Release builds skip all tracking.
+//!
+//! Kernel code uses the `Mutex` and `RwLock` defined here instead of the ones
+//! from the spin crate. They disable preemption for as long as they are held,
+//! so the timer never switches away from code holding any lock, and other
+//! processes never spin on a lock whose holder isn't running. Declaring a lock
+//! level does the same, so that a deferred switch waits for the level to be
+//! released too.
+
+#[cfg(test)]
+extern crate std;
+
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::task::preempt::{disable_preemption, PreemptGuard};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+#[repr(usize)]
+pub enum LockLevel {
+  TaskMap = 0,
+  Process = 1,
+  KernelStacks = 2,
+  Drives = 3,
+  Devices = 4,
+  KernelMemory = 5,
+  RefCount = 6,
+  Allocator = 7,
+  RunQueue = 8,
+}
+
+const LEVEL_COUNT: usize = 9;
+
+const LEVELS: [LockLevel; LEVEL_COUNT] = [
+  LockLevel::TaskMap,
+  LockLevel::Process,
+  LockLevel::KernelStacks,
+  LockLevel::Drives,
+  LockLevel::Devices,
+  LockLevel::KernelMemory,
+  LockLevel::RefCount,
+  LockLevel::Allocator,
+  LockLevel::RunQueue,
+];
+
+/// Counts how many locks of each level are currently held by one execution
+/// context
+pub struct LockTracker {
+  held: [AtomicUsize; LEVEL_COUNT],
+}
+
+impl LockTracker {
+  pub const fn new() -> LockTracker {
+    LockTracker {
+      held: [
+        AtomicUsize::new(0),
+        AtomicUsize::new(0),
+        AtomicUsize::new(0),
+        AtomicUsize::new(0),
+        AtomicUsize::new(0),
+        AtomicUsize::new(0),
+        AtomicUsize::new(0),
+        AtomicUsize::new(0),
+        AtomicUsize::new(0),
+      ],
+    }
+  }
+
+  /// Record that a lock is being acquired. If a lock of a higher level is
+  /// already held, the acquisition is out of order, and that level is
+  /// returned as an error.
+  pub fn acquire(&self, level: LockLevel) -> Result<(), LockLevel> {
+    for higher in (level as usize + 1)..LEVEL_COUNT {
+      if self.held[higher].load(Ordering::SeqCst) > 0 {
+        return Err(LEVELS[higher]);
+      }
+    }
+    self.held[level as usize].fetch_add(1, Ordering::SeqCst);
+    Ok(())
+  }
+
+  pub fn release(&self, level: LockLevel) {
+    self.held[level as usize].fetch_sub(1, Ordering::SeqCst);
+  }
+
+  pub fn held_count(&self, level: LockLevel) -> usize {
+    self.held[level as usize].load(Ordering::SeqCst)
+  }
+
+  /// Clear all held counts, returning the previous state so it can be put
+  /// back with `restore`
+  pub fn suspend(&self) -> [usize; LEVEL_COUNT] {
+    let mut saved = [0; LEVEL_COUNT];
+    for (index, count) in self.held.iter().enumerate() {
+      saved[index] = count.swap(0, Ordering::SeqCst);
+    }
+    saved
+  }
+
+  pub fn restore(&self, saved: [usize; LEVEL_COUNT]) {
+    for (index, count) in self.held.iter().enumerate() {
+      count.store(saved[index], Ordering::SeqCst);
+    }
+  }
+}
+
+/// Locks held by the kernel's single execution context
+pub static KERNEL_LOCKS: LockTracker = LockTracker::new();
+
+// Tests run on many threads at once, and each thread stands in for its own
+// execution context
+#[cfg(test)]
+std::thread_local! {
+  static THREAD_LOCKS: LockTracker = LockTracker::new();
+}
+
+/// Run a function with the lock tracker for the current execution context
+fn with_tracker<F, R>(f: F) -> R
+  where F: FnOnce(&LockTracker) -> R {
+  #[cfg(not(test))]
+  {
+    f(&KERNEL_LOCKS)
+  }
+  #[cfg(test)]
+  {
+    THREAD_LOCKS.with(f)
+  }
+}
+
+/// Marks a lock level as held until it is dropped. It should be created just
+/// before the lock it describes, so that it is dropped just after that lock's
+/// guard.
+pub struct OrderGuard {
+  #[cfg(debug_assertions)]
+  level: LockLevel,
+  /// Dropped after the level is released, so that a deferred context switch
+  /// happens with the lock order already cleared
+  _preempt: PreemptGuard<'static>,
+}
+
+/// Declare that a lock of the given level is about to be acquired, asserting
+/// in debug builds that no higher-level lock is already held. Preemption stays
+/// disabled while the returned guard is alive.
+pub fn ordered(level: LockLevel) -> OrderGuard {
+  #[cfg(debug_assertions)]
+  {
+    let preempt = disable_preemption();
+    if let Err(held) = with_tracker(|tracker| tracker.acquire(level)) {
+      panic!("Lock order violation: acquiring {:?} while holding {:?}", level, held);
+    }
+    OrderGuard { level, _preempt: preempt }
+  }
+  #[cfg(not(debug_assertions))]
+  {
+    let _ = level;
+    OrderGuard { _preempt: disable_preemption() }
+  }
+}
+
+impl Drop for OrderGuard {
+  fn drop(&mut self) {
+    #[cfg(debug_assertions)]
+    with_tracker(|tracker| tracker.release(self.level));
+  }
+}
+
+/// Run an interrupt handler with its own lock ordering. The interrupted code
+/// may be holding any locks, and the handler starts from scratch.
+pub fn interrupt_context<F, R>(f: F) -> R
+  where F: FnOnce() -> R {
+  let saved = with_tracker(|tracker| tracker.suspend());
+  let result = f();
+  with_tracker(|tracker| tracker.restore(saved));
+  result
+}
+
+/// A spin lock that keeps preemption disabled while it is held
+pub struct Mutex<T: ?Sized> {
+  inner: spin::Mutex<T>,
+}
+
+/// The lock is declared before the preemption guard, so that it is released
+/// before a deferred context switch can happen
+pub struct MutexGuard<'a, T: ?Sized + 'a> {
+  inner: spin::MutexGuard<'a, T>,
+  _preempt: PreemptGuard<'static>,
+}
+
+impl<T> Mutex<T> {
+  pub const fn new(value: T) -> Mutex<T> {
+    Mutex {
+      inner: spin::Mutex::new(value),
+    }
+  }
+
+  pub fn into_inner(self) -> T {
+    self.inner.into_inner()
+  }
+}
+
+impl<T: ?Sized> Mutex<T> {
+  pub fn lock(&self) -> MutexGuard<'_, T> {
+    let preempt = disable_preemption();
+    MutexGuard { inner: self.inner.lock(), _preempt: preempt }
+  }
+
+  pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+    let preempt = disable_preemption();
+    self.inner.try_lock().map(|inner| MutexGuard { inner, _preempt: preempt })
+  }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    &self.inner
+  }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    &mut self.inner
+  }
+}
+
+/// A reader-writer spin lock that keeps preemption disabled while it is held
+pub struct RwLock<T: ?Sized> {
+  inner: spin::RwLock<T>,
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized + 'a> {
+  inner: spin::RwLockReadGuard<'a, T>,
+  _preempt: PreemptGuard<'static>,
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized + 'a> {
+  inner: spin::RwLockWriteGuard<'a, T>,
+  _preempt: PreemptGuard<'static>,
+}
+
+impl<T> RwLock<T> {
+  pub const fn new(value: T) -> RwLock<T> {
+    RwLock {
+      inner: spin::RwLock::new(value),
+    }
+  }
+
+  pub fn into_inner(self) -> T {
+    self.inner.into_inner()
+  }
+}
+
+impl<T: ?Sized> RwLock<T> {
+  pub fn read(&self) -> RwLockReadGuard<'_, T> {
+    let preempt = disable_preemption();
+    RwLockReadGuard { inner: self.inner.read(), _preempt: preempt }
+  }
+
+  pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+    let preempt = disable_preemption();
+    self.inner.try_read().map(|inner| RwLockReadGuard { inner, _preempt: preempt })
+  }
+
+  pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+    let preempt = disable_preemption();
+    RwLockWriteGuard { inner: self.inner.write(), _preempt: preempt }
+  }
+
+  pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+    let preempt = disable_preemption();
+    self.inner.try_write().map(|inner| RwLockWriteGuard { inner, _preempt: preempt })
+  }
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    &self.inner
+  }
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    &self.inner
+  }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    &mut self.inner
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{ordered, LockLevel, LockTracker};
+
+  #[test]
+  fn detects_out_of_order_acquisition() {
+    let tracker = LockTracker::new();
+    tracker.acquire(LockLevel::TaskMap).unwrap();
+    tracker.acquire(LockLevel::Process).unwrap();
+    // Two locks of the same level are allowed
+    tracker.acquire(LockLevel::Process).unwrap();
+    tracker.acquire(LockLevel::RefCount).unwrap();
+    tracker.acquire(LockLevel::Allocator).unwrap();
+    tracker.release(LockLevel::Allocator);
+    assert_eq!(tracker.acquire(LockLevel::Process), Err(LockLevel::RefCount));
+    tracker.release(LockLevel::RefCount);
+    tracker.release(LockLevel::Process);
+    tracker.release(LockLevel::Process);
+    assert_eq!(tracker.held_count(LockLevel::Process), 0);
+
+    // Suspending for an interrupt hides the held locks
+    let saved = tracker.suspend();
+    tracker.acquire(LockLevel::Allocator).unwrap();
+    tracker.release(LockLevel::Allocator);
+    tracker.restore(saved);
+    assert_eq!(tracker.held_count(LockLevel::TaskMap), 1);
+  }
+
+  #[test]
+  #[should_panic(expected = "Lock order violation")]
+  fn ordered_catches_violation() {
+    let _refcount = ordered(LockLevel::RefCount);
+    let _process = ordered(LockLevel::Process);
+  }
+}
@@ -3,7 +3,7 @@ pub mod list_allocator;
 extern crate alloc;
 
 use alloc::alloc::{GlobalAlloc, Layout};
-use spin::Mutex;
+use crate::locks::Mutex;
 
 use super::address::VirtualAddress;
 use super::physical;
@@ -2,6 +2,7 @@ pub mod address;
 pub mod map;
 pub mod physical;
 pub mod virt;
+pub mod zero;
 
 // not test-safe
 #[cfg(not(test))]
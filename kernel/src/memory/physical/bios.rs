@@ -36,3 +36,40 @@ impl core::fmt::Debug for MapEntry {
     write!(f, "{:#010x}-{:#010x}: {}", start, end, type_string)
   }
 }
+
+/// The BIOS Data Area stores the segment of the Extended BIOS Data Area at
+/// this address
+pub const EBDA_SEGMENT_POINTER: usize = 0x40e;
+
+/// The EBDA always sits just below the VGA framebuffer, and is rarely larger
+/// than 128KiB. If the BIOS Data Area points elsewhere, it is not trustworthy.
+const EBDA_LOWEST_BASE: usize = 0x80000;
+/// Conventional location of a 1KiB EBDA, used when the pointer is invalid
+const EBDA_DEFAULT_BASE: usize = 0x9fc00;
+
+/// Convert the segment stored in the BIOS Data Area to the physical base of
+/// the EBDA. Values outside the range the EBDA can occupy fall back to the
+/// conventional location.
+pub fn ebda_base_from_segment(segment: u16) -> usize {
+  let base = (segment as usize) << 4;
+  if base < EBDA_LOWEST_BASE || base >= LEGACY_VGA_START {
+    EBDA_DEFAULT_BASE
+  } else {
+    base
+  }
+}
+
+/// Read the base of the EBDA from the BIOS Data Area. This needs to run before
+/// paging is enabled, since low memory is unmapped once the kernel is running
+/// from highmem.
+pub unsafe fn read_ebda_base() -> usize {
+  let segment = core::ptr::read_volatile(EBDA_SEGMENT_POINTER as *const u16);
+  ebda_base_from_segment(segment)
+}
+
+/// Start of the VGA framebuffer window
+pub const LEGACY_VGA_START: usize = 0xa0000;
+/// Start of the area used by option ROMs, like the VGA BIOS
+pub const LEGACY_ROM_START: usize = 0xc0000;
+/// The motherboard BIOS ROM ends where extended memory begins
+pub const LEGACY_ROM_END: usize = 0x100000;
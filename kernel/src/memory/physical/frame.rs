@@ -29,11 +29,8 @@ impl Frame {
 
   pub unsafe fn zero_memory(&self) {
     let start = self.get_address().as_usize();
-    let mut ptr = start as *mut u32;
-    for _ in 0..1024 {
-      *ptr = 0;
-      ptr = ptr.offset(1);
-    }
+    let words = core::slice::from_raw_parts_mut(start as *mut u32, 1024);
+    crate::memory::zero::zero_words(words);
   }
 }
 
@@ -81,6 +81,27 @@ impl FrameBitmap {
     Ok(())
   }
 
+  /// Mark everything between the EBDA and the start of extended memory as
+  /// unavailable: the EBDA, the VGA framebuffer, and the option and BIOS ROMs.
+  /// Some BIOS memory maps report parts of these as free, but handing them out
+  /// would let allocations and DMA collide with memory-mapped hardware.
+  /// Regions beyond the end of the bitmap are skipped.
+  pub fn reserve_legacy_regions(&mut self, ebda_base: usize) {
+    let regions = [
+      (ebda_base & !0xfff, bios::LEGACY_VGA_START),
+      (bios::LEGACY_VGA_START, bios::LEGACY_ROM_START),
+      (bios::LEGACY_ROM_START, bios::LEGACY_ROM_END),
+    ];
+    let bitmap_end = self.frame_count << 12;
+    for &(start, end) in regions.iter() {
+      let clipped_end = end.min(bitmap_end);
+      if start < clipped_end {
+        // The range is in bounds, so this can't fail
+        let _ = self.allocate_range(FrameRange::new(start, clipped_end - start));
+      }
+    }
+  }
+
   /// How big is this table, in 4096-byte frames? Useful for allocating itself.
   pub fn size_in_frames(&self) -> usize {
     let byte_size = self.frame_count >> 3;
@@ -243,7 +264,7 @@ impl core::fmt::Debug for BitmapError {
 
 #[cfg(test)]
 mod tests {
-  use super::{BitmapError, FrameBitmap, FrameRange, VirtualAddress};
+  use super::{bios, BitmapError, FrameBitmap, FrameRange, VirtualAddress};
 
   #[test]
   fn bitmap_creation() {
@@ -327,4 +348,47 @@ mod tests {
     bitmap.free_range(range).unwrap();
     assert_eq!(bitmap.get_free_frame_count(), 53);
   }
+
+  #[test]
+  fn legacy_regions_reserved() {
+    let memory: [u8; 34] = [0; 34];
+    let mut bitmap = FrameBitmap::at_location(
+      VirtualAddress::new(&memory[0] as *const u8 as usize),
+      0x110,
+    );
+    // A BIOS map that wrongly claims all of low memory is free
+    let map = [
+      bios::MapEntry {
+        base: 0,
+        length: 0x110000,
+        region_type: bios::REGION_TYPE_FREE,
+        acpi: 0,
+      },
+    ];
+    bitmap.initialize_from_memory_map(&map).unwrap();
+    let ebda = bios::ebda_base_from_segment(0x9f80);
+    assert_eq!(ebda, 0x9f800);
+    bitmap.reserve_legacy_regions(ebda);
+
+    // EBDA
+    assert!(!bitmap.is_range_free(FrameRange::new(0x9f000, 0x1000)));
+    // VGA framebuffer
+    assert!(!bitmap.is_range_free(FrameRange::new(0xa0000, 0x1000)));
+    assert!(!bitmap.is_range_free(FrameRange::new(0xbf000, 0x1000)));
+    // Option ROMs and BIOS ROM
+    assert!(!bitmap.is_range_free(FrameRange::new(0xc0000, 0x1000)));
+    assert!(!bitmap.is_range_free(FrameRange::new(0xf0000, 0x1000)));
+    assert!(!bitmap.is_range_free(FrameRange::new(0xff000, 0x1000)));
+    // Conventional and extended memory are untouched
+    assert!(bitmap.is_range_free(FrameRange::new(0x1000, 0x9e000)));
+    assert!(bitmap.is_range_free(FrameRange::new(0x100000, 0x10000)));
+    assert_eq!(bitmap.get_free_frame_count(), 0x9f + 0x10);
+  }
+
+  #[test]
+  fn invalid_ebda_pointer() {
+    assert_eq!(bios::ebda_base_from_segment(0), 0x9fc00);
+    assert_eq!(bios::ebda_base_from_segment(0xb800), 0x9fc00);
+    assert_eq!(bios::ebda_base_from_segment(0x8000), 0x80000);
+  }
 }
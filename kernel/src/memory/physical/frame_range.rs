@@ -55,13 +55,9 @@ impl FrameRange {
   }
 
   pub unsafe fn zero_memory(&self) {
-    let start = self.start;
-    let mut ptr = start as *mut u32;
     let size = self.size_in_bytes() >> 2;
-    for _ in 0..size {
-      *ptr = 0;
-      ptr = ptr.offset(1);
-    }
+    let words = core::slice::from_raw_parts_mut(self.start as *mut u32, size);
+    crate::memory::zero::zero_words(words);
   }
 }
 
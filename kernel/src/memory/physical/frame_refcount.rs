@@ -36,7 +36,7 @@ impl FrameRefcount {
       self.references.insert(addr, 2);
       2
     } else {
-      prev
+      prev + 1
     }
   }
 
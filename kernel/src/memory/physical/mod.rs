@@ -42,7 +42,8 @@ use allocated_frame::AllocatedFrame;
 use frame_bitmap::{BitmapError, FrameBitmap};
 use frame_range::FrameRange;
 use frame_refcount::FrameRefcount;
-use spin::Mutex;
+use crate::locks::{ordered, LockLevel};
+use crate::locks::Mutex;
 use super::address::{PhysicalAddress, VirtualAddress};
 
 static mut ALLOCATOR: Option<Mutex<FrameBitmap>> = None;
@@ -73,6 +74,11 @@ pub fn init_allocator(location: usize, memory_map_addr: usize) {
   // Mark the first frame as allocated, we may need the BIOS memory area
   bitmap.allocate_range(FrameRange::new(0, 0x1000)).unwrap();
 
+  // The EBDA, VGA memory, and ROMs are never usable RAM, no matter what the
+  // BIOS map claims
+  let ebda_base = unsafe { bios::read_ebda_base() };
+  bitmap.reserve_legacy_regions(ebda_base);
+
   unsafe {
     ALLOCATOR = Some(Mutex::new(bitmap));
   }
@@ -91,10 +97,11 @@ pub fn init_refcount() {
 }
 
 pub fn with_allocator<F, T>(f: F) -> T where
-  F: Fn(&mut FrameBitmap) -> T {
+  F: FnOnce(&mut FrameBitmap) -> T {
   // Safe because the ALLOCATOR will only be set once, synchronously
   match unsafe { &ALLOCATOR } {
     Some(m) => {
+      let _order = ordered(LockLevel::Allocator);
       let mut alloc = m.lock();
       f(&mut alloc)
     },
@@ -103,10 +110,11 @@ pub fn with_allocator<F, T>(f: F) -> T where
 }
 
 pub fn with_refcount<F, T>(f: F) -> T where
-  F: Fn(&mut FrameRefcount) -> T {
+  F: FnOnce(&mut FrameRefcount) -> T {
   // Safe because the REF_COUNT will only be set once, synchronously
   match unsafe { &REF_COUNT } {
     Some(r) => {
+      let _order = ordered(LockLevel::RefCount);
       let mut refcount = r.lock();
       f(&mut refcount)
     },
@@ -137,20 +145,27 @@ pub fn allocate_range(range: FrameRange) -> Result<(), BitmapError> {
 /// false if other references to the frame prevented it from being freed.
 pub fn free_frame(alloc_frame: AllocatedFrame) -> Result<bool, BitmapError> {
   let frame = alloc_frame.to_frame();
-  let paddr = frame.get_address();
-  let remaining_refs = with_refcount(|refcount| {
-    refcount.release_frame_at_address(paddr)
-  });
-  if remaining_refs < 1 {
-    let range = frame.to_range();
+  with_refcount(|refcount| {
     with_allocator(|alloc| {
-      #[cfg(not(test))]
-      crate::kprintln!("FREE FRAME {:?}", paddr);
-      alloc.free_range(range).map(|_| true)
+      release_frame(alloc, refcount, frame)
     })
+  })
+}
+
+/// Drop a single reference to a frame. If that was the last reference, the
+/// frame is marked as free in the bitmap. This is the logic behind
+/// `free_frame`, separated from the global allocator so that it can be run
+/// against any bitmap and refcount table.
+pub fn release_frame(alloc: &mut FrameBitmap, refcount: &mut FrameRefcount, frame: frame::Frame) -> Result<bool, BitmapError> {
+  let paddr = frame.get_address();
+  let remaining_refs = refcount.release_frame_at_address(paddr);
+  if remaining_refs < 1 {
+    #[cfg(not(test))]
+    crate::kprintln!(Debug, Memory; "FREE FRAME {:?}", paddr);
+    alloc.free_range(frame.to_range()).map(|_| true)
   } else {
     #[cfg(not(test))]
-    crate::kprintln!("Decrement refs: {:?}", paddr);
+    crate::kprintln!(Debug, Memory; "Decrement refs: {:?}", paddr);
     Ok(false)
   }
 }
@@ -165,7 +180,7 @@ pub fn reference_frame_at_address(addr: PhysicalAddress) -> AllocatedFrame {
   with_refcount(|refcount| {
     let count = refcount.reference_frame_at_address(addr);
     #[cfg(not(test))]
-    crate::kprintln!("New RefCount: {}", count);
+    crate::kprintln!(Debug, Memory; "New RefCount: {}", count);
   });
   AllocatedFrame::new(addr)
 }
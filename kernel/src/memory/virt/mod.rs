@@ -33,6 +33,12 @@ pub fn create_initial_pagedir() -> PageTableReference {
   PageTableReference::new(dir_address)
 }
 
+/// Directory entry that identity-maps the first 4MiB during boot
+pub const LOW_IDENTITY_ENTRY: usize = 0;
+/// Directory entry that maps the first 4MiB at 0xc0000000, where the kernel
+/// is linked
+pub const KERNEL_LOW_MEMORY_ENTRY: usize = 0x300;
+
 pub struct KernelDataBounds {
   pub ro_start: PhysicalAddress,
 
@@ -49,26 +55,22 @@ pub fn map_kernel(directory_ref: PageTableReference, bounds: &KernelDataBounds)
     bounds.rw_end.as_usize() - bounds.ro_start.as_usize() - 1,
   );
   physical::allocate_range(kernel_range).unwrap();
-  // For now, just identity-map the first 4MiB
+  // Map the first 4MiB to highmem at 0xc0000000
   let table_zero_frame = physical::allocate_frame().unwrap().to_frame();
   unsafe { table_zero_frame.zero_memory() };
   let dir = PageTable::at_address(VirtualAddress::new(directory_ref.get_address().as_usize()));
-  dir.get_mut(0).set_address(table_zero_frame.get_address());
-  dir.get_mut(0).set_present();
-  // Used for access to testing methods in userspace. Can be removed when those
-  // no longer exist.
-  dir.get_mut(0).set_user_access();
-
-  
   let table_zero = PageTable::at_address(VirtualAddress::new(table_zero_frame.get_address().as_usize()));
   for index in 0..1024 {
     table_zero.get_mut(index).set_address(PhysicalAddress::new(0x1000 * index));
     table_zero.get_mut(index).set_present();
-    table_zero.get_mut(index).set_user_access();
   }
-  // Also, map it to highmem at 0xc0000000
-  dir.get_mut(0x300).set_address(table_zero_frame.get_address());
-  dir.get_mut(0x300).set_present();
+  dir.get_mut(KERNEL_LOW_MEMORY_ENTRY).set_address(table_zero_frame.get_address());
+  dir.get_mut(KERNEL_LOW_MEMORY_ENTRY).set_present();
+  // The kernel is still running from its physical address when paging is
+  // enabled, so the same table also identity-maps low memory until the jump
+  // to highmem is complete. See `unmap_low_memory`.
+  dir.get_mut(LOW_IDENTITY_ENTRY).set_address(table_zero_frame.get_address());
+  dir.get_mut(LOW_IDENTITY_ENTRY).set_present();
   // Finally, move the stack to the top of memory, just below the temp page
   let last_page_addr = dir.get(1022).get_address();
   let last_page = PageTable::at_address(VirtualAddress::new(last_page_addr.as_usize()));
@@ -105,16 +107,77 @@ pub fn map_kernel_stack(stack_range: core::ops::Range<VirtualAddress>) {
   while stack_pages > 0 {
     let stack_frame = physical::allocate_frame().unwrap();
     #[cfg(not(test))]
-    crate::kprintln!("  New kernel stack @ {:?}", stack_frame.get_address());
+    crate::kprintln!(Debug, Memory; "  New kernel stack @ {:?}", stack_frame.get_address());
     let address = stack_range.end - (0x1000 * stack_pages);
     CurrentPageDirectory::get().map(stack_frame, address, page_directory::PermissionFlags::empty());
     stack_pages -= 1;
   }
 }
 
+/// Remove the boot-time identity mapping from a page directory. Only the
+/// directory entry is cleared: the table behind it is the same one that maps
+/// the kernel at 0xc0000000, and none of its entries can be touched.
+pub fn remove_low_identity_mapping(dir: &mut PageTable) {
+  dir.get_mut(LOW_IDENTITY_ENTRY).zero();
+}
+
+/// Once the kernel is running from highmem, nothing should use the identity
+/// mapping again. Leaving it in place would put the kernel image and all of
+/// its tables within reach of userspace addresses, and let any process that
+/// maps or unmaps memory below 4MiB edit the kernel's own page table.
+/// Removing it makes any stray reference to a low kernel address fault
+/// immediately instead.
+#[cfg(not(test))]
+pub fn unmap_low_memory() {
+  let dir = PageTable::at_address(page_directory::get_current_page_address());
+  remove_low_identity_mapping(dir);
+  // Reloading cr3 flushes every low translation still cached in the TLB
+  page_directory::set_current_pagedir(page_directory::get_current_pagedir());
+  assert!(
+    CurrentPageDirectory::get().get_physical_address(VirtualAddress::new(0)).is_none(),
+    "Low memory is still identity-mapped",
+  );
+}
+
 pub fn enable_paging() {
   #[cfg(not(test))]
   {
     x86::registers::enable_paging();
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use alloc::boxed::Box;
+  use crate::memory::address::{PhysicalAddress, VirtualAddress};
+  use super::page_entry::PageTableEntry;
+  use super::page_table::{PageTable, TABLE_ENTRY_COUNT};
+  use super::{remove_low_identity_mapping, KERNEL_LOW_MEMORY_ENTRY, LOW_IDENTITY_ENTRY};
+
+  #[test]
+  fn low_identity_mapping_teardown() {
+    let mut dir_entries = Box::new([PageTableEntry::new(); TABLE_ENTRY_COUNT]);
+    let mut table_entries = Box::new([PageTableEntry::new(); TABLE_ENTRY_COUNT]);
+    let dir = PageTable::at_address(VirtualAddress::new(dir_entries.as_mut_ptr() as usize));
+    let table = PageTable::at_address(VirtualAddress::new(table_entries.as_mut_ptr() as usize));
+    let table_address = PhysicalAddress::new(0x3000);
+    for index in 0..TABLE_ENTRY_COUNT {
+      table.get_mut(index).set_address(PhysicalAddress::new(0x1000 * index));
+      table.get_mut(index).set_present();
+    }
+    for entry in [LOW_IDENTITY_ENTRY, KERNEL_LOW_MEMORY_ENTRY].iter() {
+      dir.get_mut(*entry).set_address(table_address);
+      dir.get_mut(*entry).set_present();
+    }
+
+    remove_low_identity_mapping(dir);
+    assert!(!dir.get(LOW_IDENTITY_ENTRY).is_present());
+    // The kernel's own mapping of the shared table is left alone
+    assert!(dir.get(KERNEL_LOW_MEMORY_ENTRY).is_present());
+    assert_eq!(dir.get(KERNEL_LOW_MEMORY_ENTRY).get_address(), table_address);
+    for index in 0..TABLE_ENTRY_COUNT {
+      assert!(table.get(index).is_present());
+      assert_eq!(table.get(index).get_address(), PhysicalAddress::new(0x1000 * index));
+    }
+  }
+}
@@ -39,8 +39,9 @@ impl CurrentPageDirectory {
   }
 
   /// Unmap the page containing a specific address. If a page table entry
-  /// existed for that address, it is returned.
-  pub fn unmap(&self, vaddr: VirtualAddress) -> Option<(AllocatedFrame, PageTableEntry)> {
+  /// existed for that address, it is returned. The caller decides whether the
+  /// frame it pointed to should be freed.
+  pub fn unmap(&self, vaddr: VirtualAddress) -> Option<PageTableEntry> {
     let dir_index = vaddr.get_page_directory_index();
     let table_index = vaddr.get_page_table_index();
     let directory = PageTable::at_address(VirtualAddress::new(0xfffff000));
@@ -57,17 +58,16 @@ impl CurrentPageDirectory {
     table.get_mut(table_index).zero();
     invalidate_page(vaddr);
 
-    let frame = AllocatedFrame::new(entry.get_address());
-    Some((frame, entry))
+    Some(entry)
   }
 
   pub fn unmap_region(&self, region: VirtualMemoryRegion) {
     let mut page_start = VirtualAddress::new(region.get_starting_address_as_usize());
     while region.contains_address(page_start) {
       match self.unmap(page_start) {
-        Some((frame, entry)) => {
+        Some(entry) => {
           if entry.should_reclaim() {
-            free_frame(frame).unwrap();
+            free_frame(AllocatedFrame::new(entry.get_address())).unwrap();
           }
         },
         None => (),
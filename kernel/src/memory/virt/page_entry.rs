@@ -17,6 +17,11 @@ pub const ENTRY_COW: u32 = 1 << 9;
 /// Indicates that when the entry is unmapped, it should NOT be freed. This is
 /// useful for memory-mapped hardware that should not be re-allocated as RAM
 pub const ENTRY_NO_RECLAIM: u32 = 1 << 10;
+/// Indicates that the page has been locked into memory with mlock. It must
+/// stay resident and private to its process, so any future eviction or swap
+/// logic needs to skip it, and fork gives the child its own copy instead of
+/// sharing it copy-on-write.
+pub const ENTRY_LOCKED: u32 = 1 << 11;
 
 /**
  * We can use the same struct for the Page Directory and each Page Table.
@@ -118,4 +123,38 @@ impl PageTableEntry {
   pub fn clear_no_reclaim(&mut self) {
     self.0 &= !ENTRY_NO_RECLAIM;
   }
+
+  pub fn is_locked(&self) -> bool {
+    self.0 & ENTRY_LOCKED == ENTRY_LOCKED
+  }
+
+  pub fn set_locked(&mut self) {
+    self.0 |= ENTRY_LOCKED;
+  }
+
+  pub fn clear_locked(&mut self) {
+    self.0 &= !ENTRY_LOCKED;
+  }
+
+  /// Make a copy-on-write entry writable, after a write to its page.
+  /// `references` is the number of page tables pointing at its frame. If this
+  /// entry is the only one left, it keeps the frame. Otherwise `copy` has to
+  /// return a new frame holding the same data, and the entry moves there.
+  /// Returns the shared frame the entry let go of. Its reference should only
+  /// be released after the copy is made; until then, the other owner can't
+  /// take the frame for itself and change it mid-copy.
+  /// If `copy` fails, the entry is left copy-on-write and still shared.
+  pub fn resolve_copy_on_write<F, E>(&mut self, references: usize, copy: F) -> Result<Option<PhysicalAddress>, E>
+    where F: FnOnce(PhysicalAddress) -> Result<PhysicalAddress, E> {
+    let released = if references > 1 {
+      let shared = self.get_address();
+      self.set_address(copy(shared)?);
+      Some(shared)
+    } else {
+      None
+    };
+    self.clear_cow();
+    self.set_write_access();
+    Ok(released)
+  }
 }
@@ -1,3 +1,6 @@
+use alloc::vec::Vec;
+use core::ops::Range;
+use crate::memory::physical::allocated_frame::AllocatedFrame;
 use super::page_directory;
 use super::page_entry::PageTableEntry;
 use super::super::address::{PhysicalAddress, VirtualAddress};
@@ -12,6 +15,11 @@ pub const TEMP_REFERENCE_INDEX: usize = 1022;
 pub struct PageTable([PageTableEntry; TABLE_ENTRY_COUNT]);
 
 impl PageTable {
+  /// Create a table with no entries present
+  pub const fn new() -> PageTable {
+    PageTable([PageTableEntry::new(); TABLE_ENTRY_COUNT])
+  }
+
   pub fn at_address(addr: VirtualAddress) -> &'static mut PageTable {
     let ptr = addr.as_usize() as *mut PageTable;
     unsafe { &mut *ptr }
@@ -30,6 +38,98 @@ impl PageTable {
   pub fn get_mut(&mut self, index: usize) -> &mut PageTableEntry {
     &mut self.0[index & 0x3ff]
   }
+
+  /// Clear every present entry in the table. Frames that should be reclaimed
+  /// are handed to the `release` callback, which is responsible for dropping
+  /// the reference (and freeing the frame if nothing else points to it).
+  /// Entries marked as NO_RECLAIM point at memory the table does not own, like
+  /// video RAM, so they are cleared without being released.
+  pub fn release_entries<F>(&mut self, release: F)
+    where F: FnMut(AllocatedFrame) {
+    self.release_range(0..TABLE_ENTRY_COUNT, release)
+  }
+
+  /// Clear the present entries within a range of table indices, following the
+  /// same rules as `release_entries`. This is used to unmap part of a table,
+  /// like when a process munmaps a few pages of a larger region.
+  pub fn release_range<F>(&mut self, indices: Range<usize>, mut release: F)
+    where F: FnMut(AllocatedFrame) {
+    let end = indices.end.min(TABLE_ENTRY_COUNT);
+    for index in indices.start..end {
+      let entry = self.0[index];
+      if !entry.is_present() {
+        continue;
+      }
+      self.0[index].zero();
+      if entry.should_reclaim() {
+        release(AllocatedFrame::new(entry.get_address()));
+      }
+    }
+  }
+
+  /// Lock a range of entries into memory, so that they can be accessed
+  /// without faulting. Every entry must already be present and point at RAM
+  /// owned by the table; otherwise nothing is locked. Entries still shared
+  /// copy-on-write would fault on their next write, so they are handed to
+  /// `make_private` first, which is expected to give the entry its own frame.
+  /// If that fails, locking stops there, and the entries before it stay
+  /// locked.
+  pub fn lock_range<F>(&mut self, indices: Range<usize>, mut make_private: F) -> Result<(), ()>
+    where F: FnMut(usize, &mut PageTableEntry) -> Result<(), ()> {
+    let end = indices.end.min(TABLE_ENTRY_COUNT);
+    for index in indices.start..end {
+      let entry = self.0[index];
+      if !entry.is_present() || !entry.should_reclaim() {
+        return Err(());
+      }
+    }
+    for index in indices.start..end {
+      if self.0[index].is_cow() {
+        make_private(index, &mut self.0[index])?;
+      }
+      self.0[index].set_locked();
+    }
+    Ok(())
+  }
+
+  /// Prepare a user page table to be copied into a forked child. Each entry
+  /// pointing at RAM the table owns is passed to `share` exactly once, which
+  /// is expected to add a reference for the child. Writable entries become
+  /// copy-on-write first, so whichever process writes to the page next gets
+  /// its own copy. Device memory isn't owned by the process, and both
+  /// processes keep writing to the same device.
+  /// Locked pages can't become copy-on-write, or the next write from the
+  /// parent would fault. They are left alone, and their indices returned, so
+  /// that the child can be given its own copies instead.
+  pub fn share_for_fork<F>(&mut self, mut share: F) -> Vec<usize>
+    where F: FnMut(usize, &PageTableEntry) {
+    let mut locked = Vec::new();
+    for index in 0..TABLE_ENTRY_COUNT {
+      let entry = &mut self.0[index];
+      if !entry.is_present() || !entry.should_reclaim() {
+        continue;
+      }
+      if entry.is_locked() {
+        locked.push(index);
+        continue;
+      }
+      if entry.is_write_access_granted() {
+        entry.clear_write_access();
+        entry.set_cow();
+      }
+      share(index, entry);
+    }
+    locked
+  }
+
+  /// Allow a range of entries to be evicted again. Entries that aren't locked
+  /// are left alone.
+  pub fn unlock_range(&mut self, indices: Range<usize>) {
+    let end = indices.end.min(TABLE_ENTRY_COUNT);
+    for index in indices.start..end {
+      self.0[index].clear_locked();
+    }
+  }
 }
 
 #[derive(Copy, Clone)]
@@ -64,3 +164,223 @@ impl PageTableReference {
     self.address == current
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use alloc::boxed::Box;
+  use alloc::collections::BTreeMap;
+  use alloc::vec::Vec;
+  use crate::memory::physical::{
+    frame_bitmap::FrameBitmap,
+    frame_range::FrameRange,
+    frame_refcount::FrameRefcount,
+    release_frame,
+  };
+  use super::{PageTable, PageTableEntry, PhysicalAddress, VirtualAddress, TABLE_ENTRY_COUNT};
+
+  #[test]
+  fn releasing_entries_restores_free_frames() {
+    let memory: [u8; 2] = [0; 2];
+    let mut bitmap = FrameBitmap::at_location(
+      VirtualAddress::new(&memory[0] as *const u8 as usize),
+      16,
+    );
+    let mut refcount = FrameRefcount::new();
+    let baseline = bitmap.get_free_frame_count();
+
+    let mut table = Box::new(PageTable([PageTableEntry::new(); TABLE_ENTRY_COUNT]));
+    // Three private frames, as if paged in by a running program
+    for index in 0..3 {
+      let range = bitmap.allocate_frames(1).unwrap();
+      table.get_mut(index).set_address(range.get_starting_address());
+      table.get_mut(index).set_present();
+    }
+    // One frame shared copy-on-write with another process
+    let shared = bitmap.allocate_frames(1).unwrap().get_starting_address();
+    refcount.reference_frame_at_address(shared);
+    table.get_mut(3).set_address(shared);
+    table.get_mut(3).set_present();
+    table.get_mut(3).set_cow();
+    // Memory-mapped hardware that must never be returned to the allocator
+    table.get_mut(4).set_address(PhysicalAddress::new(0xf000));
+    table.get_mut(4).set_present();
+    table.get_mut(4).set_no_reclaim();
+    assert_eq!(bitmap.get_free_frame_count(), baseline - 4);
+
+    table.release_entries(|frame| {
+      release_frame(&mut bitmap, &mut refcount, frame.to_frame()).unwrap();
+    });
+    for index in 0..5 {
+      assert!(!table.get(index).is_present());
+    }
+    // The shared frame is still held by the other process
+    assert_eq!(bitmap.get_free_frame_count(), baseline - 1);
+    assert_eq!(refcount.get_count_for_address(shared), 1);
+    // Once the other process lets go, everything is back to where it started
+    let freed = release_frame(
+      &mut bitmap,
+      &mut refcount,
+      FrameRange::new(shared.as_usize(), 0x1000).get_first_frame(),
+    ).unwrap();
+    assert!(freed);
+    assert_eq!(bitmap.get_free_frame_count(), baseline);
+  }
+
+  #[test]
+  fn partial_release_keeps_frames_shared_with_fork() {
+    let memory: [u8; 2] = [0; 2];
+    let mut bitmap = FrameBitmap::at_location(
+      VirtualAddress::new(&memory[0] as *const u8 as usize),
+      16,
+    );
+    let mut refcount = FrameRefcount::new();
+    let baseline = bitmap.get_free_frame_count();
+
+    let mut parent = Box::new(PageTable([PageTableEntry::new(); TABLE_ENTRY_COUNT]));
+    let mut frames = [PhysicalAddress::new(0); 4];
+    for index in 0..4 {
+      frames[index] = bitmap.allocate_frames(1).unwrap().get_starting_address();
+      parent.get_mut(index).set_address(frames[index]);
+      parent.get_mut(index).set_present();
+    }
+    // Forking copies the table and shares every frame copy-on-write
+    for index in 0..4 {
+      refcount.reference_frame_at_address(frames[index]);
+      parent.get_mut(index).set_cow();
+    }
+    let mut child = Box::new(*parent);
+
+    // The child unmaps the middle of the region
+    child.release_range(1..3, |frame| {
+      assert!(!release_frame(&mut bitmap, &mut refcount, frame.to_frame()).unwrap());
+    });
+    assert!(child.get(0).is_present());
+    assert!(!child.get(1).is_present());
+    assert!(!child.get(2).is_present());
+    assert!(child.get(3).is_present());
+    // The parent still maps those frames, so none of them were freed
+    assert_eq!(bitmap.get_free_frame_count(), baseline - 4);
+    assert_eq!(refcount.get_count_for_address(frames[1]), 1);
+    assert_eq!(refcount.get_count_for_address(frames[0]), 2);
+
+    // Once the parent unmaps the same pages, they are returned
+    parent.release_range(1..3, |frame| {
+      assert!(release_frame(&mut bitmap, &mut refcount, frame.to_frame()).unwrap());
+    });
+    assert_eq!(bitmap.get_free_frame_count(), baseline - 2);
+    assert!(parent.get(0).is_present());
+    assert!(parent.get(3).is_present());
+  }
+
+  #[test]
+  fn locked_entries_are_present_and_private() {
+    let mut table = Box::new(PageTable([PageTableEntry::new(); TABLE_ENTRY_COUNT]));
+    for index in 0..3 {
+      table.get_mut(index).set_address(PhysicalAddress::new(0x10000 + index * 0x1000));
+      table.get_mut(index).set_present();
+      table.get_mut(index).set_write_access();
+    }
+    // The middle page is still shared with a fork
+    table.get_mut(1).clear_write_access();
+    table.get_mut(1).set_cow();
+
+    // A range with a missing page is refused, without locking anything
+    assert!(table.lock_range(0..4, |_, _| panic!("Nothing should be copied")).is_err());
+    for index in 0..4 {
+      assert!(!table.get(index).is_locked());
+    }
+    // So is memory-mapped hardware
+    table.get_mut(3).set_address(PhysicalAddress::new(0xb8000));
+    table.get_mut(3).set_present();
+    table.get_mut(3).set_no_reclaim();
+    assert!(table.lock_range(2..4, |_, _| panic!("Nothing should be copied")).is_err());
+    assert!(!table.get(2).is_locked());
+
+    let mut copied = Vec::new();
+    table.lock_range(0..3, |index, entry| {
+      copied.push(index);
+      entry.set_address(PhysicalAddress::new(0x20000));
+      entry.clear_cow();
+      entry.set_write_access();
+      Ok(())
+    }).unwrap();
+    assert_eq!(copied, [1]);
+    for index in 0..3 {
+      let entry = table.get(index);
+      assert!(entry.is_present());
+      assert!(entry.is_locked());
+      assert!(entry.is_write_access_granted());
+      assert!(!entry.is_cow());
+    }
+    assert_eq!(table.get(1).get_address(), PhysicalAddress::new(0x20000));
+
+    table.unlock_range(1..3);
+    assert!(table.get(0).is_locked());
+    assert!(!table.get(1).is_locked());
+    assert!(!table.get(2).is_locked());
+    assert!(table.get(2).is_present());
+  }
+
+  #[test]
+  fn copy_on_write_after_fork() {
+    let mut refcount = FrameRefcount::new();
+    // Stands in for physical memory, holding one value per frame
+    let mut memory = BTreeMap::new();
+    let mut parent = Box::new(PageTable([PageTableEntry::new(); TABLE_ENTRY_COUNT]));
+    for index in 0..4 {
+      let frame = PhysicalAddress::new(0x10000 + index * 0x1000);
+      parent.get_mut(index).set_address(frame);
+      parent.get_mut(index).set_present();
+      parent.get_mut(index).set_write_access();
+      memory.insert(frame, 5);
+    }
+    // A read-only page, like program code
+    parent.get_mut(1).clear_write_access();
+    // A framebuffer, which the parent doesn't own
+    parent.get_mut(2).set_no_reclaim();
+    parent.get_mut(3).set_locked();
+
+    let mut shared = Vec::new();
+    let locked = parent.share_for_fork(|index, entry| {
+      shared.push(index);
+      refcount.reference_frame_at_address(entry.get_address());
+    });
+    assert_eq!(shared, [0, 1]);
+    assert_eq!(locked, [3]);
+    // Every shared page gained exactly one reference, for the child
+    assert_eq!(refcount.get_count_for_address(PhysicalAddress::new(0x10000)), 2);
+    assert_eq!(refcount.get_count_for_address(PhysicalAddress::new(0x11000)), 2);
+    assert_eq!(refcount.get_count_for_address(PhysicalAddress::new(0x12000)), 1);
+    assert!(parent.get(0).is_cow());
+    assert!(!parent.get(1).is_cow());
+    assert!(parent.get(2).is_write_access_granted());
+    assert!(parent.get(3).is_write_access_granted());
+    let mut child = Box::new(*parent);
+
+    // The child writes first, and gets a copy of the frame
+    let frame = child.get(0).get_address();
+    let released = child.get_mut(0).resolve_copy_on_write(refcount.get_count_for_address(frame), |shared| {
+      let copy = PhysicalAddress::new(0x20000);
+      let value = memory[&shared];
+      memory.insert(copy, value);
+      Ok::<_, ()>(copy)
+    });
+    assert_eq!(released, Ok(Some(frame)));
+    refcount.release_frame_at_address(frame);
+    memory.insert(child.get(0).get_address(), 7);
+    assert_eq!(memory[&parent.get(0).get_address()], 5);
+
+    // The parent is now the only owner, so it writes in place
+    let frame = parent.get(0).get_address();
+    let released = parent.get_mut(0).resolve_copy_on_write(refcount.get_count_for_address(frame), |_| -> Result<PhysicalAddress, ()> {
+      panic!("An unshared frame shouldn't be copied");
+    });
+    assert_eq!(released, Ok(None));
+    assert_eq!(parent.get(0).get_address(), PhysicalAddress::new(0x10000));
+    assert!(parent.get(0).is_write_access_granted());
+    memory.insert(parent.get(0).get_address(), 9);
+    assert_eq!(memory[&child.get(0).get_address()], 7);
+    assert_eq!(memory[&parent.get(0).get_address()], 9);
+    assert_eq!(refcount.get_count_for_address(frame), 1);
+  }
+}
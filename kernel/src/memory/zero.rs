@@ -0,0 +1,53 @@
+//! Frames come out of the allocator holding whatever their previous owner left
+//! in them. Any frame handed to a user process must be cleared first, so that
+//! one process can't read another's data, and because programs expect new
+//! heap, stack, and BSS memory to read as zero. Pages get cleared on every
+//! fault that maps fresh memory, so this uses `rep stosd` rather than a loop.
+
+use super::address::VirtualAddress;
+
+pub const PAGE_WORDS: usize = 0x400;
+
+/// Fill a buffer with zeroes, four bytes at a time
+pub fn zero_words(buffer: &mut [u32]) {
+  #[cfg(not(test))]
+  unsafe {
+    asm!(
+      "rep stosd",
+      inout("ecx") buffer.len() => _,
+      inout("edi") buffer.as_mut_ptr() => _,
+      in("eax") 0,
+      options(nostack, preserves_flags),
+    );
+  }
+  #[cfg(test)]
+  for word in buffer.iter_mut() {
+    *word = 0;
+  }
+}
+
+/// Clear the page starting at a mapped, writable, page-aligned address
+pub unsafe fn zero_page(page: VirtualAddress) {
+  let buffer = core::slice::from_raw_parts_mut(page.as_usize() as *mut u32, PAGE_WORDS);
+  zero_words(buffer);
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::boxed::Box;
+  use crate::memory::address::VirtualAddress;
+  use super::{zero_page, PAGE_WORDS};
+
+  #[test]
+  fn faulted_page_reads_as_zero() {
+    // A frame freed by another process still holds its data
+    let mut frame = Box::new([0xdeadbeefu32; PAGE_WORDS + 1]);
+    let page = VirtualAddress::new(frame.as_mut_ptr() as usize);
+    unsafe {
+      zero_page(page);
+    }
+    assert!(frame[..PAGE_WORDS].iter().all(|word| *word == 0));
+    // Nothing past the end of the page is touched
+    assert_eq!(frame[PAGE_WORDS], 0xdeadbeef);
+  }
+}
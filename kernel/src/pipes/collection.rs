@@ -1,11 +1,23 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
 use crate::collections::SlotList;
 use crate::files::handle::{Handle, LocalHandle};
-use spin::RwLock;
+use crate::locks::RwLock;
 use super::{Pipe, PipeError, PipeHandle};
 
+/// A read that is waiting for data. If its handle is closed in the meantime,
+/// the read is cancelled, so that it doesn't go on to read from whatever pipe
+/// reuses the handle's slot.
+struct BlockedRead {
+  handle: LocalHandle,
+  cancelled: Arc<AtomicBool>,
+}
+
 pub struct PipeCollection {
   pipes: RwLock<SlotList<Pipe>>,
   handles: RwLock<SlotList<PipeHandle>>,
+  blocked_reads: RwLock<Vec<BlockedRead>>,
 }
 
 impl PipeCollection {
@@ -13,9 +25,15 @@ impl PipeCollection {
     PipeCollection {
       pipes: RwLock::new(SlotList::new()),
       handles: RwLock::new(SlotList::new()),
+      blocked_reads: RwLock::new(Vec::new()),
     }
   }
 
+  fn get_pipe_handle(&self, handle: LocalHandle) -> Result<PipeHandle, PipeError> {
+    let handles = self.handles.read();
+    handles.get(handle.as_usize()).copied().ok_or(PipeError::InvalidHandle)
+  }
+
   /// Create a pipe and a pair of read/write handles
   pub fn create(&self) -> Result<(LocalHandle, LocalHandle), PipeError> {
     let pipe_index = {
@@ -32,47 +50,183 @@ impl PipeCollection {
   }
 
   /// Read available bytes into a mutable slice, using a Pipe Read Handle.
+  /// If the pipe is empty but still has writers, the caller yields until data
+  /// arrives. Once all writers have closed, an empty pipe reads as EOF.
+  /// If the handle is closed while the caller is waiting, the read fails with
+  /// `InvalidHandle`, and if a signal arrives it fails with `Interrupted`.
   /// Returns the number of bytes copied to the buffer.
   pub fn read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<usize, PipeError> {
-    let pipe_handle = {
-      let handles = self.handles.read();
-      *handles.get(handle.as_usize()).ok_or(PipeError::InvalidHandle)?
+    self.read_blocking(handle, buffer, crate::task::yield_interruptible)
+  }
+
+  /// The body of `read`, calling `wait` each time the pipe is found empty.
+  /// Once `wait` fails, the read gives up unless data arrived in the
+  /// meantime.
+  fn read_blocking<F>(&self, handle: LocalHandle, buffer: &mut [u8], mut wait: F) -> Result<usize, PipeError>
+    where F: FnMut() -> Result<(), ()> {
+    if let Some(read) = self.try_read(handle, buffer)? {
+      return Ok(read);
+    }
+    let cancelled = Arc::new(AtomicBool::new(false));
+    self.blocked_reads.write().push(BlockedRead {
+      handle,
+      cancelled: cancelled.clone(),
+    });
+    let result = loop {
+      let interrupted = wait().is_err();
+      match self.read_unless_cancelled(handle, buffer, &cancelled) {
+        Ok(Some(read)) => break Ok(read),
+        Ok(None) if interrupted => break Err(PipeError::Interrupted),
+        Ok(None) => (),
+        Err(e) => break Err(e),
+      }
     };
-    match pipe_handle {
+    self.blocked_reads.write().retain(|blocked| !Arc::ptr_eq(&blocked.cancelled, &cancelled));
+    result
+  }
+
+  /// Read from a pipe without blocking. If the pipe is empty and a writer is
+  /// still open, it returns `None` rather than reporting EOF.
+  pub fn try_read(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<Option<usize>, PipeError> {
+    self.read_unless_cancelled(handle, buffer, &AtomicBool::new(false))
+  }
+
+  /// The cancellation flag is checked while the handle table is locked, and
+  /// the lock is held for the rest of the read. Closing the handle needs to
+  /// write to the table, so it can't slip in between the check and the read.
+  fn read_unless_cancelled(&self, handle: LocalHandle, buffer: &mut [u8], cancelled: &AtomicBool) -> Result<Option<usize>, PipeError> {
+    let handles = self.handles.read();
+    if cancelled.load(Ordering::SeqCst) {
+      return Err(PipeError::InvalidHandle);
+    }
+    match handles.get(handle.as_usize()).copied().ok_or(PipeError::InvalidHandle)? {
       PipeHandle::ReadHandle(index) => {
         let pipes = self.pipes.read();
         let pipe = pipes.get(index).ok_or(PipeError::UnknownPipe)?;
-        let read = pipe.data_buffer.read(buffer);
-        Ok(read)
+        if buffer.len() == 0 || pipe.can_read() {
+          return Ok(Some(pipe.data_buffer.read(buffer)));
+        }
+        if pipe.has_writers() {
+          Ok(None)
+        } else {
+          Ok(Some(0))
+        }
       },
       PipeHandle::WriteHandle(_) => Err(PipeError::WrongHandleType),
     }
   }
 
   /// Write bytes from a slice into the pipe, using a Pipe Write Handle.
+  /// If the pipe fills up, the caller yields until readers have made room for
+  /// the rest of the data.
   /// Returns the number of bytes copied to the pipe.
   pub fn write(&self, handle: LocalHandle, buffer: &[u8]) -> Result<usize, PipeError> {
-    let pipe_handle = {
-      let handles = self.handles.read();
-      *handles.get(handle.as_usize()).ok_or(PipeError::InvalidHandle)?
-    };
-    match pipe_handle {
+    let mut written = 0;
+    while written < buffer.len() {
+      match self.try_write(handle, &buffer[written..])? {
+        Some(partial) => written += partial,
+        None => crate::task::yield_coop(),
+      }
+    }
+    Ok(written)
+  }
+
+  /// Write to a pipe without blocking. If the pipe is full, it returns `None`.
+  /// Writing to a pipe with no remaining readers is an error.
+  pub fn try_write(&self, handle: LocalHandle, buffer: &[u8]) -> Result<Option<usize>, PipeError> {
+    match self.get_pipe_handle(handle)? {
       PipeHandle::WriteHandle(index) => {
         let pipes = self.pipes.read();
         let pipe = pipes.get(index).ok_or(PipeError::UnknownPipe)?;
+        if !pipe.has_readers() {
+          return Err(PipeError::WriteToClosedPipe);
+        }
         let written = pipe.data_buffer.write(buffer);
-        Ok(written)
+        if written == 0 && buffer.len() > 0 {
+          Ok(None)
+        } else {
+          Ok(Some(written))
+        }
       },
       PipeHandle::ReadHandle(_) => Err(PipeError::WrongHandleType),
     }
   }
 
-  pub fn get_available_bytes(&self, handle: LocalHandle) -> Result<usize, PipeError> {
+  /// Create another handle to the same end of a pipe
+  pub fn reopen(&self, handle: LocalHandle) -> Result<LocalHandle, PipeError> {
+    let pipe_handle = self.get_pipe_handle(handle)?;
+    {
+      let pipes = self.pipes.read();
+      let pipe = pipes.get(pipe_handle.to_index()).ok_or(PipeError::UnknownPipe)?;
+      if pipe_handle.can_read() {
+        pipe.add_reader();
+      } else {
+        pipe.add_writer();
+      }
+    }
+    let new_index = self.handles.write().insert(pipe_handle);
+    Ok(LocalHandle::new(new_index as u32))
+  }
+
+  /// Close a handle to a pipe. Once both ends have been fully closed, the pipe
+  /// itself is released. Any reads blocked on the handle are cancelled.
+  pub fn close(&self, handle: LocalHandle) -> Result<(), PipeError> {
     let pipe_handle = {
-      let handles = self.handles.read();
-      *handles.get(handle.as_usize()).ok_or(PipeError::InvalidHandle)?
+      let mut handles = self.handles.write();
+      let pipe_handle = handles.remove(handle.as_usize()).ok_or(PipeError::InvalidHandle)?;
+      self.blocked_reads.write().retain(|blocked| {
+        if blocked.handle == handle {
+          blocked.cancelled.store(true, Ordering::SeqCst);
+          false
+        } else {
+          true
+        }
+      });
+      pipe_handle
     };
-    match pipe_handle {
+    let index = pipe_handle.to_index();
+    let mut pipes = self.pipes.write();
+    let pipe = pipes.get(index).ok_or(PipeError::UnknownPipe)?;
+    if pipe_handle.can_read() {
+      pipe.remove_reader();
+    } else {
+      pipe.remove_writer();
+    }
+    if !pipe.has_readers() && !pipe.has_writers() {
+      pipes.remove(index);
+    }
+    Ok(())
+  }
+
+  /// A read handle is ready when there is data, or when every writer has
+  /// closed and a read would return EOF. A write handle is never readable.
+  pub fn poll_readable(&self, handle: LocalHandle) -> Result<bool, PipeError> {
+    match self.get_pipe_handle(handle)? {
+      PipeHandle::ReadHandle(index) => {
+        let pipes = self.pipes.read();
+        let pipe = pipes.get(index).ok_or(PipeError::UnknownPipe)?;
+        Ok(pipe.can_read() || !pipe.has_writers())
+      },
+      PipeHandle::WriteHandle(_) => Ok(false),
+    }
+  }
+
+  /// A write handle is ready when the pipe has room, or when every reader has
+  /// closed and a write would fail immediately. A read handle is never
+  /// writable.
+  pub fn poll_writable(&self, handle: LocalHandle) -> Result<bool, PipeError> {
+    match self.get_pipe_handle(handle)? {
+      PipeHandle::WriteHandle(index) => {
+        let pipes = self.pipes.read();
+        let pipe = pipes.get(index).ok_or(PipeError::UnknownPipe)?;
+        Ok(pipe.has_room() || !pipe.has_readers())
+      },
+      PipeHandle::ReadHandle(_) => Ok(false),
+    }
+  }
+
+  pub fn get_available_bytes(&self, handle: LocalHandle) -> Result<usize, PipeError> {
+    match self.get_pipe_handle(handle)? {
       PipeHandle::ReadHandle(index) => {
         let pipes = self.pipes.read();
         let pipe = pipes.get(index).ok_or(PipeError::UnknownPipe)?;
@@ -81,4 +235,144 @@ impl PipeCollection {
       PipeHandle::WriteHandle(_) => Err(PipeError::WrongHandleType),
     }
   }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{PipeCollection, PipeError};
+  use super::super::pipe::BUFFER_SIZE;
+
+  #[test]
+  fn nonblocking_read_on_empty_pipe() {
+    let pipes = PipeCollection::new();
+    let (read, write) = pipes.create().unwrap();
+    let mut buffer: [u8; 4] = [0; 4];
+    // The writer is still open, so an empty pipe is not EOF
+    assert_eq!(pipes.try_read(read, &mut buffer), Ok(None));
+    pipes.try_write(write, &[1, 2]).unwrap();
+    assert_eq!(pipes.try_read(read, &mut buffer), Ok(Some(2)));
+    assert_eq!(&buffer[0..2], &[1, 2]);
+    assert_eq!(pipes.try_read(read, &mut buffer), Ok(None));
+    // Once the writer closes, the empty pipe reads as EOF
+    pipes.close(write).unwrap();
+    assert_eq!(pipes.try_read(read, &mut buffer), Ok(Some(0)));
+  }
+
+  #[test]
+  fn nonblocking_write_on_full_pipe() {
+    let pipes = PipeCollection::new();
+    let (read, write) = pipes.create().unwrap();
+    let data: [u8; BUFFER_SIZE] = [0xaa; BUFFER_SIZE];
+    assert_eq!(pipes.try_write(write, &data), Ok(Some(BUFFER_SIZE)));
+    assert_eq!(pipes.try_write(write, &[1]), Ok(None));
+    let mut buffer: [u8; 1] = [0];
+    assert_eq!(pipes.try_read(read, &mut buffer), Ok(Some(1)));
+    assert_eq!(pipes.try_write(write, &[1, 2]), Ok(Some(1)));
+    // Writing with no readers left is an error, rather than blocking
+    pipes.close(read).unwrap();
+    assert_eq!(pipes.try_write(write, &[1]), Err(PipeError::WriteToClosedPipe));
+  }
+
+  #[test]
+  fn pipe_readiness() {
+    let pipes = PipeCollection::new();
+    let (read, write) = pipes.create().unwrap();
+    assert_eq!(pipes.poll_readable(read), Ok(false));
+    assert_eq!(pipes.poll_writable(write), Ok(true));
+    // Each end is only ready in its own direction
+    assert_eq!(pipes.poll_readable(write), Ok(false));
+    assert_eq!(pipes.poll_writable(read), Ok(false));
+
+    pipes.try_write(write, &[1, 2, 3]).unwrap();
+    assert_eq!(pipes.poll_readable(read), Ok(true));
+    let mut buffer: [u8; 4] = [0; 4];
+    pipes.try_read(read, &mut buffer).unwrap();
+    assert_eq!(pipes.poll_readable(read), Ok(false));
+
+    let full: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+    pipes.try_write(write, &full).unwrap();
+    assert_eq!(pipes.poll_writable(write), Ok(false));
+
+    // Reading EOF doesn't block, so a pipe without writers is readable
+    let (empty_read, empty_write) = pipes.create().unwrap();
+    pipes.close(empty_write).unwrap();
+    assert_eq!(pipes.poll_readable(empty_read), Ok(true));
+  }
+
+  #[test]
+  fn closing_handle_cancels_blocked_read() {
+    extern crate std;
+    use alloc::sync::Arc;
+    use crate::files::handle::Handle;
+    use std::thread;
+
+    let pipes = Arc::new(PipeCollection::new());
+    let (read, write) = pipes.create().unwrap();
+    let reader = {
+      let pipes = pipes.clone();
+      thread::spawn(move || {
+        let mut buffer: [u8; 4] = [0; 4];
+        pipes.read(read, &mut buffer)
+      })
+    };
+    // Wait for the reader to block on the empty pipe
+    while pipes.blocked_reads.read().is_empty() {
+      thread::yield_now();
+    }
+    pipes.close(read).unwrap();
+    // A new pipe reuses the closed handle's slot. The cancelled read must not
+    // pick up its data.
+    let (reused, reused_write) = pipes.create().unwrap();
+    assert_eq!(reused.as_usize(), read.as_usize());
+    pipes.try_write(reused_write, &[7]).unwrap();
+
+    assert_eq!(reader.join().unwrap(), Err(PipeError::InvalidHandle));
+    assert!(pipes.blocked_reads.read().is_empty());
+    let mut buffer: [u8; 1] = [0];
+    assert_eq!(pipes.try_read(reused, &mut buffer), Ok(Some(1)));
+    // The original writer sees that there are no readers left
+    assert_eq!(pipes.try_write(write, &[1]), Err(PipeError::WriteToClosedPipe));
+  }
+
+  #[test]
+  fn signal_interrupts_blocked_read() {
+    extern crate std;
+    use alloc::sync::Arc;
+    use crate::memory::address::VirtualAddress;
+    use crate::task::signal::{SignalHandler, SignalState};
+    use crate::locks::RwLock;
+    use std::thread;
+    use syscall::signals::INT;
+
+    let pipes = Arc::new(PipeCollection::new());
+    let (read, write) = pipes.create().unwrap();
+    let signals = Arc::new(RwLock::new(SignalState::new()));
+    let handler = SignalHandler {
+      function: VirtualAddress::new(0x00402000),
+      restorer: VirtualAddress::new(0x00403000),
+    };
+    signals.write().set_handler(INT, Some(handler)).unwrap();
+    let reader = {
+      let pipes = pipes.clone();
+      let signals = signals.clone();
+      thread::spawn(move || {
+        let mut buffer: [u8; 4] = [0; 4];
+        // Checks the reader's signals, like yield_interruptible
+        let wait = || if signals.read().has_pending() { Err(()) } else { Ok(()) };
+        pipes.read_blocking(read, &mut buffer, wait)
+      })
+    };
+    while pipes.blocked_reads.read().is_empty() {
+      thread::yield_now();
+    }
+    assert!(signals.write().raise(INT));
+    assert_eq!(reader.join().unwrap(), Err(PipeError::Interrupted));
+    assert!(pipes.blocked_reads.read().is_empty());
+
+    // Once the handler has run, the read can be made again
+    signals.write().take_pending();
+    pipes.try_write(write, &[1, 2]).unwrap();
+    let mut buffer: [u8; 4] = [0; 4];
+    assert_eq!(pipes.read_blocking(read, &mut buffer, || Ok(())), Ok(2));
+  }
+}
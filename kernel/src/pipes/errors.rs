@@ -1,4 +1,4 @@
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum PipeError {
   /// The specified handle does not point to a valid pipe
   InvalidHandle,
@@ -8,4 +8,6 @@ pub enum PipeError {
   WrongHandleType,
   /// Writing to a pipe with no readers
   WriteToClosedPipe,
-}
\ No newline at end of file
+  /// A signal arrived while a read was waiting for data
+  Interrupted,
+}
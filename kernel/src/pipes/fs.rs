@@ -2,9 +2,10 @@ use alloc::sync::Arc;
 use crate::files::cursor::SeekMethod;
 use crate::files::handle::LocalHandle;
 use crate::files::ioctl::FIONREAD;
-use crate::filesystems::filesystem::FileSystem;
+use crate::fs::filesystem::KernelFileSystem;
+use crate::task::id::ProcessID;
 use super::collection::PipeCollection;
-use syscall::files::DirEntryInfo;
+use syscall::files::{DirEntryInfo, FileStatus};
 
 pub struct PipeFileSystem {
   collection: Arc<PipeCollection>,
@@ -18,7 +19,7 @@ impl PipeFileSystem {
   }
 }
 
-impl FileSystem for PipeFileSystem {
+impl KernelFileSystem for PipeFileSystem {
   /// Open only works for named pipes, which are not yet implemented
   fn open(&self, _path: &str) -> Result<LocalHandle, ()> {
     Err(())
@@ -32,12 +33,28 @@ impl FileSystem for PipeFileSystem {
     self.collection.write(handle, buffer).map_err(|_| ())
   }
 
-  fn close(&self, _handle: LocalHandle) -> Result<(), ()> {
-    Err(())
+  fn read_nonblocking(&self, handle: LocalHandle, buffer: &mut [u8]) -> Result<Option<usize>, ()> {
+    self.collection.try_read(handle, buffer).map_err(|_| ())
   }
 
-  fn dup(&self, _handle: LocalHandle) -> Result<LocalHandle, ()> {
-    Err(())
+  fn write_nonblocking(&self, handle: LocalHandle, buffer: &[u8]) -> Result<Option<usize>, ()> {
+    self.collection.try_write(handle, buffer).map_err(|_| ())
+  }
+
+  fn close(&self, handle: LocalHandle) -> Result<(), ()> {
+    self.collection.close(handle).map_err(|_| ())
+  }
+
+  fn poll_readable(&self, handle: LocalHandle) -> Result<bool, ()> {
+    self.collection.poll_readable(handle).map_err(|_| ())
+  }
+
+  fn poll_writable(&self, handle: LocalHandle) -> Result<bool, ()> {
+    self.collection.poll_writable(handle).map_err(|_| ())
+  }
+
+  fn reopen(&self, handle: LocalHandle, _id: ProcessID) -> Result<LocalHandle, ()> {
+    self.collection.reopen(handle).map_err(|_| ())
   }
 
   fn ioctl(&self, handle: LocalHandle, command: u32, arg: u32) -> Result<u32, ()> {
@@ -59,11 +76,16 @@ impl FileSystem for PipeFileSystem {
     Err(())
   }
 
-  fn open_dir(&self, path: &str) -> Result<LocalHandle, ()> {
+  fn open_dir(&self, _path: &str) -> Result<LocalHandle, ()> {
     Err(())
   }
 
-  fn read_dir(&self, handle: LocalHandle, index: usize, info: &mut DirEntryInfo) -> Result<(), ()> {
+  fn read_dir(&self, _handle: LocalHandle, _info: &mut DirEntryInfo) -> Result<bool, ()> {
     Err(())
   }
-}
\ No newline at end of file
+
+  fn stat(&self, handle: LocalHandle, status: &mut FileStatus) -> Result<(), ()> {
+    status.byte_size = self.collection.get_available_bytes(handle).unwrap_or(0);
+    Ok(())
+  }
+}
@@ -1,7 +1,7 @@
 use alloc::boxed::Box;
 use alloc::sync::Arc;
 use crate::files::handle::LocalHandle;
-use crate::filesystems::FileSystemType;
+use crate::fs::filesystem::FileSystemType;
 
 pub mod collection;
 pub mod errors;
@@ -1,7 +1,8 @@
 use alloc::boxed::Box;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use crate::buffers::RingBuffer;
 
-const BUFFER_SIZE: usize = 256;
+pub const BUFFER_SIZE: usize = 256;
 
 /// A Pipe is a simple fifo queue of byte data, allowing data to be passed
 /// between different processes.
@@ -10,6 +11,10 @@ pub struct Pipe {
   data_raw_ptr: usize,
   /// Ring buffer containing pipe data
   pub data_buffer: RingBuffer<'static>,
+  /// Number of open handles that can read from the pipe
+  readers: AtomicUsize,
+  /// Number of open handles that can write to the pipe
+  writers: AtomicUsize,
 }
 
 impl Pipe {
@@ -23,6 +28,8 @@ impl Pipe {
     Pipe {
       data_raw_ptr: data_raw_ptr as usize,
       data_buffer: RingBuffer::new(data_slice),
+      readers: AtomicUsize::new(1),
+      writers: AtomicUsize::new(1),
     }
   }
 
@@ -35,6 +42,37 @@ impl Pipe {
   pub fn can_read(&self) -> bool {
     self.available_bytes() > 0
   }
+
+  /// Return true if there is space to write at least one byte
+  pub fn has_room(&self) -> bool {
+    self.data_buffer.available_room() > 0
+  }
+
+  pub fn has_readers(&self) -> bool {
+    self.readers.load(Ordering::SeqCst) > 0
+  }
+
+  pub fn has_writers(&self) -> bool {
+    self.writers.load(Ordering::SeqCst) > 0
+  }
+
+  pub fn add_reader(&self) {
+    self.readers.fetch_add(1, Ordering::SeqCst);
+  }
+
+  pub fn add_writer(&self) {
+    self.writers.fetch_add(1, Ordering::SeqCst);
+  }
+
+  /// Drop a reader reference, returning the number that remain
+  pub fn remove_reader(&self) -> usize {
+    self.readers.fetch_sub(1, Ordering::SeqCst) - 1
+  }
+
+  /// Drop a writer reference, returning the number that remain
+  pub fn remove_writer(&self) -> usize {
+    self.writers.fetch_sub(1, Ordering::SeqCst) - 1
+  }
 }
 
 impl Drop for Pipe {
@@ -44,4 +82,4 @@ impl Drop for Pipe {
       Box::from_raw(ptr);
     }
   }
-}
\ No newline at end of file
+}
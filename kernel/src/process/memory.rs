@@ -15,7 +15,7 @@ use crate::memory::{
     },
   },
 };
-use spin::RwLock;
+use crate::locks::RwLock;
 use super::process_state::ProcessState;
 
 /// The kernel stack extends from 0xffbf0000 to 0xffbfefff
@@ -2,7 +2,7 @@ use alloc::sync::Arc;
 use crate::files::handle::LocalHandle;
 use crate::gdt;
 use crate::kprintln;
-use spin::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use crate::locks::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 pub mod exec;
 pub mod files;
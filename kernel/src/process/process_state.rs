@@ -7,7 +7,7 @@ use crate::memory::virt::page_table::{PageTable, PageTableReference};
 use crate::memory::virt::region::VirtualMemoryRegion;
 use crate::promise::Promise;
 use crate::time;
-use spin::RwLock;
+use crate::locks::RwLock;
 use super::id::ProcessID;
 use super::memory::{MemoryRegions, STACK_SIZE, STACK_START};
 use super::subsystem::Subsystem;
@@ -1,5 +1,5 @@
 use alloc::sync::Arc;
-use spin::RwLock;
+use crate::locks::RwLock;
 
 pub struct Promise<T: Copy> {
   value: Arc<RwLock<Option<T>>>,
@@ -1,14 +1,99 @@
+use alloc::string::String;
 use crate::memory::address::VirtualAddress;
 use crate::task;
+use crate::task::environment::EnvironmentError;
+use crate::task::limits::{Limit, Resource, UNLIMITED};
+use crate::task::memory::ProcessMemoryError;
+use crate::task::process::WaitTarget;
+use crate::task::signal::{frame_location, read_frame, write_frame, PendingSignal, Signal, SignalFrame, SignalHandler};
+use crate::task::vm::Subsystem;
+use syscall::data::ResourceLimit;
+use syscall::flags::{P_ALL, P_PGID, P_PID, RLIM_INFINITY};
 use syscall::result::SystemError;
+use syscall::signals::{WaitInfo, EXIT_CODE_MASK, SA_RESTART, SIG_IGN};
+use super::user::{copy_to_user, validate_user_range};
 
 pub fn yield_coop() {
   let id = task::switching::get_current_id().as_u32();
   task::yield_coop();
 }
 
-pub fn sleep(ms: u32) {
-  task::sleep(ms as usize)
+/// Give up the CPU to a specific process. A process that is blocked or
+/// doesn't exist is ignored, and the next process in line runs instead.
+pub fn yield_to(id: u32) {
+  task::yield_to(task::id::ProcessID::new(id));
+}
+
+/// Change the current process's nice value by a relative amount. The new
+/// value is returned offset by 20, so that it is never negative. Only
+/// privileged processes can raise their priority.
+pub fn nice(increment: i32) -> Result<u32, SystemError> {
+  let process_lock = task::switching::get_current_process();
+  let mut process = process_lock.write();
+  if increment < 0 && !process.is_privileged() {
+    return Err(SystemError::PermissionDenied);
+  }
+  let nice = process.adjust_nice(increment);
+  Ok((nice - task::scheduler::NICE_MIN) as u32)
+}
+
+fn limit_to_syscall(value: usize) -> u32 {
+  if value >= RLIM_INFINITY as usize {
+    RLIM_INFINITY
+  } else {
+    value as u32
+  }
+}
+
+fn limit_from_syscall(value: u32) -> usize {
+  if value == RLIM_INFINITY {
+    UNLIMITED
+  } else {
+    value as usize
+  }
+}
+
+pub fn get_resource_limit(resource: u32, limit: *mut ResourceLimit) -> Result<(), SystemError> {
+  validate_user_range(limit as usize, core::mem::size_of::<ResourceLimit>())?;
+  let resource = Resource::from_code(resource).ok_or(SystemError::InvalidArgument)?;
+  let current = task::switching::get_current_process().read().get_resource_limit(resource);
+  unsafe {
+    *limit = ResourceLimit {
+      current: limit_to_syscall(current.soft),
+      maximum: limit_to_syscall(current.hard),
+    };
+  }
+  Ok(())
+}
+
+/// Change a resource limit of the current process. Like `nice`, only
+/// privileged processes can raise their hard limits.
+pub fn set_resource_limit(resource: u32, limit: *const ResourceLimit) -> Result<(), SystemError> {
+  validate_user_range(limit as usize, core::mem::size_of::<ResourceLimit>())?;
+  let resource = Resource::from_code(resource).ok_or(SystemError::InvalidArgument)?;
+  let requested = unsafe {
+    let limit = &*limit;
+    Limit::new(limit_from_syscall(limit.current), limit_from_syscall(limit.maximum))
+  };
+  let process_lock = task::switching::get_current_process();
+  let mut process = process_lock.write();
+  let privileged = process.is_privileged();
+  process.set_resource_limit(resource, requested, privileged)?;
+  Ok(())
+}
+
+/// Permanently give up the current process's privileges. Children forked
+/// afterwards inherit the unprivileged state.
+pub fn drop_privileges() {
+  task::switching::get_current_process().write().drop_privileges();
+}
+
+pub fn sleep(ms: u32) -> Result<(), SystemError> {
+  task::sleep_interruptibly(ms as usize).map_err(|_| SystemError::Interrupted)
+}
+
+pub fn sleep_until(timestamp: u32) -> Result<(), SystemError> {
+  task::sleep_until(crate::time::timestamp::Timestamp(timestamp)).map_err(|_| SystemError::Interrupted)
 }
 
 pub fn fork() -> u32 {
@@ -16,52 +101,375 @@ pub fn fork() -> u32 {
   id.as_u32()
 }
 
-pub fn exec_path(path_str: &'static str, _arg_str: &'static str, raw_interp_mode: u32) -> Result<(), SystemError> {
+pub fn vfork() -> u32 {
+  let id = task::vfork();
+  id.as_u32()
+}
+
+pub fn exec_path(path_str: String, arg_str: String, raw_interp_mode: u32) -> Result<(), SystemError> {
   let interp_mode = crate::loaders::InterpretationMode::from_u32(raw_interp_mode);
-  task::exec::exec(path_str, interp_mode)
+  let args = arg_str.split_whitespace().map(String::from).collect();
+  // Release the copied string now, because a successful exec does not return
+  drop(arg_str);
+  task::exec::exec(path_str, args, interp_mode)
+}
+
+/// Copy the full path of the current executable into a buffer, returning the
+/// length of the path. If the buffer is too small, nothing is copied and the
+/// caller can retry with a buffer of the returned length.
+pub fn self_exe(buffer: *mut u8, length: usize) -> Result<u32, SystemError> {
+  let path = {
+    let process_lock = task::switching::get_current_process();
+    let process = process_lock.read();
+    String::from(process.get_exec_path().ok_or(SystemError::NoSuchEntity)?)
+  };
+  unsafe { copy_to_user(buffer, length, path.as_bytes())? };
+  Ok(path.len() as u32)
+}
+
+/// Copy the current program's arguments into a buffer, packed as consecutive
+/// NUL-terminated strings. Returns the argument count, and stores the number
+/// of bytes needed to hold them at `required`. Like `self_exe`, nothing is
+/// copied if the buffer is too small.
+pub fn get_args(buffer: *mut u8, length: usize, required: *mut u32) -> Result<u32, SystemError> {
+  validate_user_range(required as usize, core::mem::size_of::<u32>())?;
+  let (count, packed) = {
+    let process_lock = task::switching::get_current_process();
+    let process = process_lock.read();
+    let (count, size) = process.copy_arguments(&mut []);
+    let mut packed = alloc::vec![0; size];
+    process.copy_arguments(&mut packed);
+    (count, packed)
+  };
+  unsafe {
+    copy_to_user(buffer, length, &packed)?;
+    *required = packed.len() as u32;
+  }
+  Ok(count as u32)
+}
+
+/// Copy a listing of a process's memory regions into a buffer, for debugging
+/// page faults and leaks. A pid of 0 means the current process. Returns the
+/// size of the whole listing; if the buffer is too small, nothing is copied
+/// and the size can be used to retry. DOS programs can't inspect memory, and
+/// only privileged processes can inspect processes other than their children.
+pub fn dump_memory_map(id: u32, buffer: *mut u8, length: usize) -> Result<u32, SystemError> {
+  let privileged = {
+    let process_lock = task::switching::get_current_process();
+    let process = process_lock.read();
+    if let Subsystem::DOS(_) = process.subsystem {
+      return Err(SystemError::PermissionDenied);
+    }
+    process.is_privileged()
+  };
+  let id = if id == 0 {
+    task::switching::get_current_id()
+  } else {
+    task::id::ProcessID::new(id)
+  };
+  let listing = task::exec::describe_memory(id, privileged)?;
+  unsafe { copy_to_user(buffer, length, listing.as_bytes())? };
+  Ok(listing.len() as u32)
+}
+
+fn map_environment_error(err: EnvironmentError) -> SystemError {
+  match err {
+    EnvironmentError::InvalidName => SystemError::InvalidArgument,
+    EnvironmentError::TooLarge => SystemError::NoSpace,
+  }
+}
+
+/// Set an environment variable on the current process, replacing any existing
+/// value
+pub fn setenv(name: &str, value: &str) -> Result<(), SystemError> {
+  let process_lock = task::switching::get_current_process();
+  let mut process = process_lock.write();
+  process.get_environment_mut().set(name, value).map_err(map_environment_error)
+}
+
+/// Copy the value of an environment variable into a buffer, returning the
+/// length of the value. Like `self_exe`, nothing is copied if the buffer is too
+/// small.
+pub fn getenv(name: &str, buffer: *mut u8, length: usize) -> Result<u32, SystemError> {
+  let value = {
+    let process_lock = task::switching::get_current_process();
+    let process = process_lock.read();
+    String::from(process.get_environment().get(name).ok_or(SystemError::NoSuchEntity)?)
+  };
+  unsafe { copy_to_user(buffer, length, value.as_bytes())? };
+  Ok(value.len() as u32)
 }
 
+pub fn unsetenv(name: &str) -> Result<(), SystemError> {
+  let process_lock = task::switching::get_current_process();
+  let mut process = process_lock.write();
+  process.get_environment_mut().unset(name).map(|_| ()).ok_or(SystemError::NoSuchEntity)
+}
+
+/// Only the low bits of the code are kept, so that a program can't report an
+/// exit status that looks like it was killed by a signal
 pub fn exit(code: u32) {
-  task::exec::terminate(code);
+  task::exec::terminate(code & EXIT_CODE_MASK);
 }
 
 pub fn get_pid() -> u32 {
   task::switching::get_current_id().as_u32()
 }
 
-pub fn wait_pid(id: u32) -> (u32, u32) {
-  if id == 0 {
-    let code = task::wait(None);
-    (0, code)
+pub fn wait_pid(id: u32, options: u32) -> Result<(u32, u32), SystemError> {
+  let child_id = if id == 0 {
+    None
   } else {
-    let code = task::wait(Some(task::id::ProcessID::new(id)));
-    (id, code)
+    Some(task::id::ProcessID::new(id))
+  };
+  let (waited, code) = task::wait_with_options(child_id, options)?;
+  let pid = waited.map(|waited_id| waited_id.as_u32()).unwrap_or(id);
+  Ok((pid, code))
+}
+
+/// Wait on children selected by an id type and id. There is no room for a
+/// fourth argument, so the P_* id type is stored in the top four bits of
+/// `options`.
+pub fn waitid(id: u32, info: *mut WaitInfo, options: u32) -> Result<(), SystemError> {
+  validate_user_range(info as usize, core::mem::size_of::<WaitInfo>())?;
+  let id_type = options >> 28;
+  let options = options & 0x0fffffff;
+  let target = match id_type {
+    P_ALL => WaitTarget::AnyChild,
+    P_PID => WaitTarget::Child(task::id::ProcessID::new(id)),
+    P_PGID => {
+      let group = if id == 0 {
+        task::switching::get_current_process().read().get_process_group()
+      } else {
+        task::id::ProcessID::new(id)
+      };
+      WaitTarget::Group(group)
+    },
+    _ => return Err(SystemError::InvalidArgument),
+  };
+  let (waited, status) = task::wait_for(target, options)?;
+  let pid = waited.map(|waited_id| waited_id.as_u32()).unwrap_or(0);
+  unsafe {
+    *info = WaitInfo::from_status(pid, status);
   }
+  Ok(())
 }
 
-pub fn brk(method: u32, offset: u32) -> Result<u32, ()> {
-  match method {
+/// Move the end of the heap. Method 0 sets an absolute address and returns the
+/// new end, like `brk`. Method 1 adds a signed offset and returns the previous
+/// end, like `sbrk`.
+pub fn brk(method: u32, offset: u32) -> Result<u32, SystemError> {
+  let result = match method {
     0 => { // Absolute
       let addr = VirtualAddress::new(offset as usize);
-      task::exec::set_heap_top(addr).map(|addr| addr.as_u32())
+      task::exec::set_heap_top(addr)
     },
     1 => { // Relative
       let delta = offset as i32 as isize;
-      task::exec::move_heap_top(delta).map(|addr| addr.as_u32())
+      task::exec::move_heap_top(delta)
     },
     _ => {
-      Err(())
+      return Err(SystemError::InvalidArgument);
     },
+  };
+  result
+    .map(|addr| addr.as_u32())
+    .map_err(|err| match err {
+      ProcessMemoryError::NotEnoughMemory => SystemError::NoSpace,
+      ProcessMemoryError::FreeFailed => SystemError::IOError,
+      _ => SystemError::InvalidArgument,
+    })
+}
+
+pub fn munmap(addr: u32, length: u32) -> Result<(), SystemError> {
+  if addr & 0xfff != 0 {
+    return Err(SystemError::InvalidArgument);
+  }
+  task::exec::munmap(VirtualAddress::new(addr as usize), length as usize)
+    .map_err(|err| match err {
+      ProcessMemoryError::FreeFailed => SystemError::IOError,
+      _ => SystemError::InvalidArgument,
+    })
+}
+
+/// Lock or unlock a range of memory, depending on the method: 1 locks the
+/// pages into RAM, and 0 unlocks them
+pub fn mlock(addr: u32, length: u32, method: u32) -> Result<(), SystemError> {
+  let start = VirtualAddress::new(addr as usize);
+  match method {
+    0 => task::exec::munlock(start, length as usize),
+    1 => task::exec::mlock(start, length as usize),
+    _ => Err(SystemError::InvalidArgument),
   }
 }
 
-pub fn install_interrupt_handler(irq: u32, address: u32, stack_top: u32) -> Result<(), ()> {
+/// Install a handler for a hardware IRQ, chained after any handlers that
+/// other processes have installed on the same line. The handler runs on the
+/// stack at `stack_top`, and finishes by returning normally. A handler address
+/// of zero removes the caller's handler instead.
+/// There are no user accounts yet, so the only privilege check is that DOS
+/// programs can't hook hardware interrupts.
+pub fn install_interrupt_handler(irq: u32, address: u32, stack_top: u32) -> Result<(), SystemError> {
+  if let Subsystem::DOS(_) = task::switching::get_current_process().read().subsystem {
+    return Err(SystemError::PermissionDenied);
+  }
   let cur_id = task::switching::get_current_id();
-  crate::kprintln!("INSTALL HANDLER AT {}:{:#010x} to IRQ {}", cur_id.as_u32(), address, irq);
+  if address == 0 {
+    crate::kprintln!(Debug, Driver; "REMOVE HANDLER FOR {} FROM IRQ {}", cur_id.as_u32(), irq);
+    return crate::interrupts::handlers::uninstall_handler(irq as usize, cur_id)
+      .map_err(|_| SystemError::NoSuchEntity);
+  }
+  validate_user_range(address as usize, 1)?;
+  validate_user_range((stack_top as usize).saturating_sub(4), 4)?;
+  crate::kprintln!(Debug, Driver; "INSTALL HANDLER AT {}:{:#010x} to IRQ {}", cur_id.as_u32(), address, irq);
   crate::interrupts::handlers::install_handler(
     irq as usize,
     cur_id,
     VirtualAddress::new(address as usize),
     VirtualAddress::new(stack_top as usize),
-  )
+  ).map_err(|_| SystemError::InvalidArgument)
+}
+
+/// Install a handler for a signal, or restore its default action if the
+/// handler address is zero. When the handler returns, it jumps to `restorer`,
+/// which is expected to call sigreturn. SA_RESTART can be combined with the
+/// signal number to restart syscalls that the signal interrupts. A handler of
+/// SIG_IGN ignores the signal instead.
+pub fn install_signal_handler(signal: u32, function: u32, restorer: u32) -> Result<(), SystemError> {
+  let restart = signal & SA_RESTART != 0;
+  let signal = signal & !SA_RESTART;
+  if function == SIG_IGN {
+    let process_lock = task::switching::get_current_process();
+    let mut process = process_lock.write();
+    return process.signals.ignore(signal).map_err(|_| SystemError::InvalidArgument);
+  }
+  let handler = if function == 0 {
+    None
+  } else {
+    validate_user_range(function as usize, 1)?;
+    validate_user_range(restorer as usize, 1)?;
+    Some(SignalHandler {
+      function: VirtualAddress::new(function as usize),
+      restorer: VirtualAddress::new(restorer as usize),
+    })
+  };
+  let installing = handler.is_some();
+  let process_lock = task::switching::get_current_process();
+  let mut process = process_lock.write();
+  process.signals.set_handler(signal, handler).map_err(|_| SystemError::InvalidArgument)?;
+  process.signals.set_restart(signal, restart && installing);
+  Ok(())
+}
+
+/// Whether the handler that is about to run asked for the syscall it
+/// interrupted to be restarted
+pub fn should_restart_syscall() -> bool {
+  let process_lock = task::switching::get_current_process();
+  let process = process_lock.read();
+  process.signals.restarts_next()
+}
+
+/// Send a signal to a process, or to the current process if the ID is zero.
+/// A negative ID sends the signal to every process in that process group.
+pub fn kill(id: u32, signal: u32) -> Result<(), SystemError> {
+  let signal = Signal::from_number(signal).ok_or(SystemError::InvalidArgument)?;
+  if (id as i32) < 0 {
+    let group = task::id::ProcessID::new((id as i32).wrapping_neg() as u32);
+    return task::exec::send_signal_to_group(group, signal);
+  }
+  let target = if id == 0 {
+    None
+  } else {
+    Some(task::id::ProcessID::new(id))
+  };
+  task::exec::send_signal(target, signal)
+}
+
+/// Move a process into a process group. An ID of zero refers to the current
+/// process, and a group of zero uses the process's own ID as the group.
+pub fn setpgid(id: u32, group: u32) -> Result<(), SystemError> {
+  let id = if id == 0 {
+    task::switching::get_current_id()
+  } else {
+    task::id::ProcessID::new(id)
+  };
+  let group = if group == 0 {
+    id
+  } else {
+    task::id::ProcessID::new(group)
+  };
+  task::exec::set_process_group(id, group)
+}
+
+/// Get the process group of a process, or of the current process if the ID
+/// is zero
+pub fn getpgid(id: u32) -> Result<u32, SystemError> {
+  let id = if id == 0 {
+    task::switching::get_current_id()
+  } else {
+    task::id::ProcessID::new(id)
+  };
+  task::exec::get_process_group(id).map(|group| group.as_u32())
+}
+
+/// If the current process has a pending signal, save the interrupted state
+/// on its stack and return the stack pointer and entry point of the handler.
+/// A process without room on its stack for the frame is terminated.
+pub fn enter_signal_handler(interrupted: &SignalFrame) -> Option<(u32, u32)> {
+  let process_lock = task::switching::get_current_process();
+  let pending = process_lock.write().signals.take_pending()?;
+  let (signal, handler) = match pending {
+    PendingSignal::Handle(signal, handler) => (signal, handler),
+    PendingSignal::Default(signal) => {
+      // With no handler left to run, sending it again applies the default
+      if let Some(signal) = Signal::from_number(signal) {
+        let _ = task::exec::send_signal(None, signal);
+      }
+      return None;
+    },
+  };
+
+  let esp = interrupted.esp as usize;
+  let location = frame_location(esp).filter(|&(new_esp, _)| {
+    validate_user_range(new_esp, esp - new_esp).is_ok()
+      && process_lock.read().owns_user_range(VirtualAddress::new(new_esp), esp - new_esp)
+  });
+  let (new_esp, frame_addr) = match location {
+    Some(location) => location,
+    None => {
+      task::exec::terminate(Signal::Segfault.get_exit_status());
+      return None;
+    },
+  };
+  let mut frame = *interrupted;
+  frame.signal = signal;
+  // Writing to the stack may page it in, so the process can't be locked here
+  unsafe {
+    write_frame(new_esp, frame_addr, &frame, handler.restorer.as_u32());
+  }
+  process_lock.write().signals.enter_handler(frame_addr);
+  Some((new_esp as u32, handler.function.as_u32()))
+}
+
+/// Leave the most recently entered signal handler, returning the state that
+/// was interrupted when the signal arrived
+pub fn signal_return() -> Result<SignalFrame, SystemError> {
+  let frame_size = core::mem::size_of::<SignalFrame>();
+  let frame_addr = {
+    let process_lock = task::switching::get_current_process();
+    let mut process = process_lock.write();
+    let frame_addr = process.signals.leave_handler().ok_or(SystemError::InvalidArgument)?;
+    validate_user_range(frame_addr, frame_size)?;
+    // The handler may have unmapped the memory below its stack, so every page
+    // of the frame needs to still belong to the process
+    if !process.owns_user_range(VirtualAddress::new(frame_addr), frame_size) {
+      return Err(SystemError::InvalidArgument);
+    }
+    frame_addr
+  };
+  let frame = unsafe { read_frame(frame_addr) };
+  if !frame.is_valid() {
+    return Err(SystemError::InvalidArgument);
+  }
+  Ok(frame)
 }
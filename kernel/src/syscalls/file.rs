@@ -1,29 +1,61 @@
 use crate::files::cursor::SeekMethod;
 use crate::files::handle::{FileHandle, Handle};
+use crate::files::wildcard::WildcardPattern;
 use syscall::files::{DirEntryInfo};
+use syscall::flags::TFD_ONESHOT;
 use syscall::result::SystemError;
+use super::user::{validate_user_range, validate_user_transfer};
 
-pub fn open_path(path_str: &'static str) -> Result<u32, SystemError> {
-  crate::task::io::open_path(path_str).map(|handle| handle.as_u32())
+/// Reads and writes report how many bytes they moved, which has to fit in the
+/// result without looking like an error code
+const MAX_TRANSFER_LENGTH: usize = 0x7fffffff;
+
+pub fn open_path(path_str: &str, flags: u32) -> Result<u32, SystemError> {
+  crate::task::io::open_path_with_flags(path_str, flags).map(|handle| handle.as_u32())
+}
+
+pub fn access(path_str: &str, mode: u32) -> Result<(), SystemError> {
+  crate::task::io::access_path(path_str, mode)
+}
+
+pub fn unlink(path_str: &str) -> Result<(), SystemError> {
+  crate::task::io::unlink_path(path_str)
+}
+
+pub fn rename(old_path: &str, new_path: &str, flags: u32) -> Result<(), SystemError> {
+  crate::task::io::rename_path(old_path, new_path, flags)
 }
 
 pub fn close(handle: u32) -> Result<(), SystemError> {
   crate::task::io::close_file(FileHandle::new(handle))
 }
 
+/// Read into a userspace buffer. The whole buffer must be below the kernel;
+/// a zero-length read returns 0 without looking at the handle or buffer.
+/// Lengths too large for the result are clamped.
 pub unsafe fn read(handle: u32, dest: *mut u8, length: usize) -> Result<usize, SystemError> {
-  let buffer = core::slice::from_raw_parts_mut(dest, length);
+  if !validate_user_transfer(dest as usize, length)? {
+    return Ok(0);
+  }
+  let buffer = core::slice::from_raw_parts_mut(dest, length.min(MAX_TRANSFER_LENGTH));
   crate::task::io::read_file(FileHandle::new(handle), buffer)
 }
 
+/// Write from a userspace buffer, with the same checks as `read`
 pub unsafe fn write(handle: u32, src: *const u8, length: usize) -> Result<usize, SystemError> {
-  let buffer = core::slice::from_raw_parts(src, length);
+  if !validate_user_transfer(src as usize, length)? {
+    return Ok(0);
+  }
+  let buffer = core::slice::from_raw_parts(src, length.min(MAX_TRANSFER_LENGTH));
   crate::task::io::write_file(FileHandle::new(handle), buffer)
 }
 
 pub fn ioctl(handle: u32, command: u32, arg: u32) -> Result<u32, SystemError> {
-  //crate::task::io::ioctl(handle, command, arg)
-  Err(SystemError::IOError)
+  crate::task::io::ioctl(FileHandle::new(handle), command, arg)
+}
+
+pub fn fcntl(handle: u32, command: u32, arg: u32) -> Result<u32, SystemError> {
+  crate::task::io::fcntl(FileHandle::new(handle), command, arg)
 }
 
 pub fn dup(to_duplicate: u32, to_replace: u32) -> Result<u32, SystemError> {
@@ -36,19 +68,83 @@ pub fn dup(to_duplicate: u32, to_replace: u32) -> Result<u32, SystemError> {
   crate::task::io::dup(from_handle, to_handle).map(|h| h.as_u32())
 }
 
+pub fn dup3(to_duplicate: u32, to_replace: u32, flags: u32) -> Result<u32, SystemError> {
+  crate::task::io::dup3(FileHandle::new(to_duplicate), FileHandle::new(to_replace), flags).map(|h| h.as_u32())
+}
+
+pub fn is_terminal(handle: u32) -> Result<bool, SystemError> {
+  crate::task::io::is_terminal(FileHandle::new(handle))
+}
+
+pub fn flock(handle: u32, operation: u32) -> Result<(), SystemError> {
+  crate::task::io::flock(FileHandle::new(handle), operation)
+}
+
 pub fn pipe() -> Result<(u32, u32), SystemError> {
-  /*
-  let (read_local, write_local) = pipes::create_pipe().map_err(|_| SystemError::Unknown)?;
-  let (read, write) = {
-    let current = current_process();
-    let fs_number = unsafe { filesystems::PIPE_FS };
-    let read = current.open_file(fs_number, read_local).as_u32();
-    let write = current.open_file(fs_number, write_local).as_u32();
-    (read, write)
+  let (read_local, write_local) = crate::pipes::create_pipe().map_err(|_| SystemError::Unknown)?;
+  let drive = crate::fs::DRIVES.get_drive_number("PIPE").ok_or(SystemError::NoSuchDrive)?;
+  let read = match crate::task::io::install_local_handle(drive, read_local) {
+    Ok(handle) => handle,
+    Err(e) => {
+      if let Some((_, instance)) = crate::fs::DRIVES.get_drive_instance(&drive) {
+        let _ = instance.close(write_local);
+      }
+      return Err(e);
+    },
   };
-  Ok((read, write))
-  */
-  Err(SystemError::Unknown)
+  match crate::task::io::install_local_handle(drive, write_local) {
+    Ok(write) => Ok((read.as_u32(), write.as_u32())),
+    Err(e) => {
+      let _ = crate::task::io::close_file(read);
+      Err(e)
+    },
+  }
+}
+
+/// Create an anonymous scratch file on the TMP drive. It is deleted as soon as
+/// the last handle to it is closed. `descriptor_flags` is a set of FD_* flags.
+pub fn tmpfile(descriptor_flags: u32) -> Result<u32, SystemError> {
+  let drive = crate::fs::DRIVES.get_drive_number("TMP").ok_or(SystemError::NoSuchDrive)?;
+  let local_handle = crate::fs::drivers::tmpfs::create_anonymous_file().map_err(|_| SystemError::NoSuchDrive)?;
+  let handle = crate::task::io::install_local_handle(drive, local_handle)?;
+  if descriptor_flags != 0 {
+    crate::task::get_current_process().write().set_descriptor_flags(handle, descriptor_flags);
+  }
+  Ok(handle.as_u32())
+}
+
+/// Create a timer handle on the TIMER drive, expiring every `interval_ms`
+/// milliseconds, or just once with TFD_ONESHOT
+pub fn timerfd(interval_ms: u32, flags: u32) -> Result<u32, SystemError> {
+  let interval = crate::time::ticks::ms_to_ticks(interval_ms as usize);
+  if interval == 0 {
+    return Err(SystemError::InvalidArgument);
+  }
+  let drive = crate::fs::DRIVES.get_drive_number("TIMER").ok_or(SystemError::NoSuchDrive)?;
+  let periodic = flags & TFD_ONESHOT == 0;
+  let local_handle = crate::fs::drivers::timerfs::create_timer(interval, periodic)
+    .map_err(|_| SystemError::InvalidArgument)?;
+  let handle = crate::task::io::install_local_handle(drive, local_handle)?;
+  Ok(handle.as_u32())
+}
+
+/// Move up to `count` bytes from one open file to another within the kernel.
+/// Counts that can't be represented in the result are clamped.
+pub fn sendfile(out_handle: u32, in_handle: u32, count: u32) -> Result<u32, SystemError> {
+  let count = (count as usize).min(MAX_TRANSFER_LENGTH);
+  crate::task::io::send_file(FileHandle::new(out_handle), FileHandle::new(in_handle), count)
+    .map(|moved| moved as u32)
+}
+
+/// Truncate or extend an open file to `size` bytes
+pub fn ftruncate(handle: u32, size: u32) -> Result<(), SystemError> {
+  crate::task::io::set_file_size(FileHandle::new(handle), size as usize)
+}
+
+/// Map a file that has been sealed against writing into the calling process,
+/// read-only, returning the address of the mapping
+pub fn map_sealed(handle: u32) -> Result<u32, SystemError> {
+  crate::task::exec::map_sealed_file(FileHandle::new(handle)).map(|address| address.as_u32())
 }
 
 pub fn seek(handle: u32, method: u32, cursor: u32) -> Result<u32, SystemError> {
@@ -59,7 +155,7 @@ pub fn seek(handle: u32, method: u32, cursor: u32) -> Result<u32, SystemError> {
   crate::task::io::seek(FileHandle::new(handle), seek_method).map(|cur| cur as u32)
 }
 
-pub fn open_dir(path_str: &'static str) -> Result<u32, SystemError> {
+pub fn open_dir(path_str: &str) -> Result<u32, SystemError> {
   crate::task::io::open_directory(path_str).map(|handle| handle.as_u32())
   /*
   let (drive, path) = filename::string_to_drive_and_path(path_str);
@@ -84,3 +180,13 @@ pub fn read_dir(handle: u32, info: *mut DirEntryInfo) -> Result<u32, SystemError
   fs.read_dir(drive_and_handle.1, index, entry).map_err(|_| SystemError::NoSuchEntity)
   */
 }
+
+pub fn find_dir_entry(handle: u32, pattern: &str, info: *mut DirEntryInfo) -> Result<u32, SystemError> {
+  validate_user_range(info as usize, core::mem::size_of::<DirEntryInfo>())?;
+  crate::task::io::find_matching_entry(
+    FileHandle::new(handle),
+    &WildcardPattern::parse(pattern),
+    |_| true,
+    unsafe { &mut *info },
+  ).map(|has_more| if has_more { 1 } else { 0 })
+}
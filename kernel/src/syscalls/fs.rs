@@ -1,4 +1,11 @@
-use crate::fs::DRIVES;
+use alloc::vec::Vec;
+use crate::files::handle::{FileHandle, Handle, LocalHandle};
+use crate::fs::{DRIVES, drive::{DriveID, UnmountError}};
+use crate::task::switching::{get_current_process, with_task_map};
+use crate::locks::{ordered, LockLevel};
+use super::user::validate_user_range;
+use syscall::files::{CacheStats, DeviceInfo, DriveInfo};
+use syscall::flags::UNMOUNT_FORCE;
 use syscall::result::SystemError;
 
 /// Register the current process as a new filesystem driver
@@ -40,3 +47,119 @@ pub fn get_current_drive_number() -> Result<u32, SystemError> {
   let current = current_lock.read();
   Ok(current.current_drive.as_u32())
 }
+
+pub fn change_directory(path: &str) -> Result<(), SystemError> {
+  crate::task::io::change_directory(path)
+}
+
+pub fn change_directory_to_handle(handle: u32) -> Result<(), SystemError> {
+  crate::task::io::change_directory_to_handle(FileHandle::new(handle))
+}
+
+/// Copy as much of a drive's working directory as fits in a userspace buffer,
+/// returning the full length
+pub fn get_working_directory(drive: u32, buffer: *mut u8, length: usize) -> Result<u32, SystemError> {
+  let directory = crate::task::io::get_working_directory(DriveID::new(drive as usize))?;
+  let dest = user_listing_buffer(buffer, length)?;
+  let to_copy = dest.len().min(directory.len());
+  dest[..to_copy].copy_from_slice(&directory.as_bytes()[..to_copy]);
+  Ok(directory.len() as u32)
+}
+
+/// Flush and remove a mounted drive. If any process still has files open on
+/// the drive, the unmount fails as Busy, unless the force flag is set, in which
+/// case those handles are closed first. A drive backing a running executable
+/// can never be force-unmounted. Only privileged processes may unmount drives.
+/// The task map stays locked from the busy check until the drive is taken out
+/// of the drive map, so no process can be created or pick up the drive in
+/// between.
+pub fn unmount(name: &str, flags: u32) -> Result<(), SystemError> {
+  if !get_current_process().read().is_privileged() {
+    return Err(SystemError::PermissionDenied);
+  }
+  let force = flags & UNMOUNT_FORCE != 0;
+
+  let (drive_id, entry, to_close) = with_task_map(|processes| {
+    let drive_id = DRIVES.get_drive_number(name).ok_or(SystemError::NoSuchDrive)?;
+    for process_lock in processes.values() {
+      let process = process_lock.read();
+      if let Some((exec_drive, _)) = process.get_exec_file() {
+        if exec_drive == drive_id {
+          return Err(SystemError::Busy);
+        }
+      }
+      if !force && process.has_files_on_drive(drive_id) {
+        return Err(SystemError::Busy);
+      }
+    }
+
+    let mut to_close: Vec<LocalHandle> = Vec::new();
+    if force {
+      for process_lock in processes.values() {
+        to_close.append(&mut process_lock.write().close_files_on_drive(drive_id));
+      }
+    }
+    let entry = DRIVES.take_drive(&drive_id).ok_or(SystemError::NoSuchDrive)?;
+    Ok((drive_id, entry, to_close))
+  })?;
+
+  let instance = entry.get_fs();
+  for handle in to_close {
+    let _ = instance.close(handle);
+  }
+
+  DRIVES.sync_taken_drive(drive_id, entry).map(|_| ()).map_err(|err| match err {
+    UnmountError::NoSuchDrive => SystemError::NoSuchDrive,
+    UnmountError::SyncFailed => SystemError::IOError,
+  })
+}
+
+/// Fill a userspace buffer with descriptions of the mounted drives, returning
+/// the total number of drives
+pub fn list_drives(buffer: *mut DriveInfo, count: usize) -> Result<u32, SystemError> {
+  let dest = user_listing_buffer(buffer, count)?;
+  Ok(DRIVES.list_drives(dest) as u32)
+}
+
+/// Fill a userspace buffer with descriptions of the installed devices,
+/// returning the total number of devices
+pub fn list_devices(buffer: *mut DeviceInfo, count: usize) -> Result<u32, SystemError> {
+  let dest = user_listing_buffer(buffer, count)?;
+  let _order = ordered(LockLevel::Devices);
+  let devices = crate::devices::DEVICES.read();
+  Ok(devices.list_devices(dest) as u32)
+}
+
+/// Copy the combined block cache counters to userspace. Resetting them
+/// afterwards makes it easy to measure a single workload.
+pub fn cache_stats(stats: *mut CacheStats, reset: bool) -> Result<(), SystemError> {
+  validate_user_range(stats as usize, core::mem::size_of::<CacheStats>())?;
+  unsafe {
+    *stats = crate::devices::block::cache::get_stats();
+  }
+  if reset {
+    crate::devices::block::cache::reset_stats();
+  }
+  Ok(())
+}
+
+/// Empty every block cache, so that following reads come from the disks. Like
+/// unmount, this is only allowed for privileged processes.
+pub fn drop_caches() -> Result<(), SystemError> {
+  if !get_current_process().read().is_privileged() {
+    return Err(SystemError::PermissionDenied);
+  }
+  crate::devices::block::cache::drop_caches();
+  Ok(())
+}
+
+/// Validate a userspace array of `count` entries. A count of zero is allowed,
+/// so that callers can ask how large a buffer they need.
+fn user_listing_buffer<T>(buffer: *mut T, count: usize) -> Result<&'static mut [T], SystemError> {
+  if count == 0 {
+    return Ok(&mut []);
+  }
+  let size = count.checked_mul(core::mem::size_of::<T>()).ok_or(SystemError::InvalidArgument)?;
+  validate_user_range(buffer as usize, size)?;
+  Ok(unsafe { core::slice::from_raw_parts_mut(buffer, count) })
+}
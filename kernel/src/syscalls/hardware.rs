@@ -1,7 +1,80 @@
-pub fn change_video_mode(mode: u8) {
-  let vterm_index = match crate::task::vterm::get_current_vterm() {
-    Some(current) => current,
-    None => return,
+use crate::x86::io;
+use syscall::result::SystemError;
+use super::user::validate_user_range;
+
+/// Change the video mode of the caller's controlling vterm
+pub fn change_video_mode(mode: u8) -> Result<(), SystemError> {
+  let vterm_index = crate::task::vterm::get_current_vterm().ok_or(SystemError::NoSuchEntity)?;
+  crate::vterm::change_video_mode(vterm_index, mode).map_err(|_| SystemError::InvalidArgument)
+}
+
+/// Fetch the video mode of the caller's controlling vterm
+pub fn get_video_mode() -> Result<u32, SystemError> {
+  let vterm_index = crate::task::vterm::get_current_vterm().ok_or(SystemError::NoSuchEntity)?;
+  crate::vterm::get_video_mode(vterm_index)
+    .map(|mode| mode as u32)
+    .ok_or(SystemError::NoSuchEntity)
+}
+
+pub fn set_keyboard_layout(layout: u32) -> Result<(), SystemError> {
+  crate::vterm::set_keyboard_layout(layout).map_err(|_| SystemError::NoSuchEntity)
+}
+
+/// Stop every process and power off the system. DOS programs can't shut down the
+/// system. On success, this never returns to the caller.
+pub fn shutdown() -> Result<(), SystemError> {
+  if let crate::task::vm::Subsystem::DOS(_) = crate::task::get_current_process().read().subsystem {
+    return Err(SystemError::PermissionDenied);
+  }
+  crate::task::shutdown::shutdown_system()
+}
+
+/// Claim a range of I/O ports for a userspace driver
+pub fn grant_io_ports(start: u32, count: u32) -> Result<(), SystemError> {
+  crate::task::get_current_process()
+    .write()
+    .grant_io_ports(start as usize, count as usize)
+}
+
+fn check_port_access(port: u32, width: u32) -> Result<u16, SystemError> {
+  match width {
+    1 | 2 | 4 => (),
+    _ => return Err(SystemError::InvalidArgument),
+  }
+  crate::task::get_current_process()
+    .read()
+    .check_io_port_access(port as usize, width as usize)?;
+  Ok(port as u16)
+}
+
+/// Read 1, 2, or 4 bytes from a granted I/O port. The value is written to
+/// `dest` rather than returned, since a 32-bit value could look like an
+/// error code.
+pub fn port_read(port: u32, width: u32, dest: *mut u32) -> Result<(), SystemError> {
+  let port = check_port_access(port, width)?;
+  validate_user_range(dest as usize, 4)?;
+  let value = unsafe {
+    match width {
+      1 => io::inb(port) as u32,
+      2 => io::inw(port) as u32,
+      _ => io::inl(port),
+    }
   };
-  crate::vterm::change_video_mode(vterm_index, mode);
+  unsafe {
+    *dest = value;
+  }
+  Ok(())
+}
+
+/// Write 1, 2, or 4 bytes to a granted I/O port
+pub fn port_write(port: u32, width: u32, value: u32) -> Result<(), SystemError> {
+  let port = check_port_access(port, width)?;
+  unsafe {
+    match width {
+      1 => io::outb(port, value as u8),
+      2 => io::outw(port, value as u16),
+      _ => io::outl(port, value),
+    }
+  }
+  Ok(())
 }
@@ -0,0 +1,125 @@
+use crate::files::handle::{FileHandle, Handle};
+use crate::task;
+use crate::task::id::ProcessID;
+use crate::task::ipc::{IPCMessage, PageTransferMode};
+use crate::task::ports::PORTS;
+use syscall::result::SystemError;
+
+/// Messages sent from userspace don't expire
+const NO_EXPIRATION: u32 = 0xffffffff;
+
+fn message_from_words(words: &[u32; 4]) -> IPCMessage {
+  IPCMessage(words[0], words[1], words[2], words[3])
+}
+
+/// A message sent to a process that is waiting on a reply from the sender is
+/// that reply, and the sender doesn't expect anything back. This has to be
+/// checked before sending, since the recipient stops waiting once it reads
+/// the message.
+fn expects_reply(to: u32) -> bool {
+  let current_id = task::switching::get_current_id();
+  match task::switching::get_process(&ProcessID::new(to)) {
+    Some(recipient) => !recipient.read().is_awaiting_reply_from(current_id),
+    None => false,
+  }
+}
+
+/// Remember who a userspace process last sent a request to, so that a read
+/// waiting on the reply can fail if that process exits. Replies, including
+/// those sent by kernel drivers, don't expect an answer, so they aren't
+/// tracked.
+fn record_send(result: Result<(), SystemError>, to: u32, expects_reply: bool) -> Result<(), SystemError> {
+  if result.is_ok() && expects_reply {
+    task::switching::get_current_process().write().ipc_sent(ProcessID::new(to));
+  }
+  result
+}
+
+pub fn ipc_send(to: u32, message: &[u32; 4]) -> Result<(), SystemError> {
+  let recipient = ProcessID::new(to);
+  task::switching::get_process(&recipient).ok_or(SystemError::NoSuchEntity)?;
+  let expects_reply = expects_reply(to);
+  task::ipc_send(recipient, message_from_words(message), NO_EXPIRATION);
+  record_send(Ok(()), to, expects_reply)
+}
+
+pub fn ipc_send_handle(to: u32, message: &[u32; 4], handle: u32) -> Result<(), SystemError> {
+  let expects_reply = expects_reply(to);
+  let result = task::ipc_send_handle(
+    ProcessID::new(to),
+    message_from_words(message),
+    FileHandle::new(handle),
+    NO_EXPIRATION,
+  );
+  record_send(result, to, expects_reply)
+}
+
+pub fn ipc_send_pages(to: u32, message: &[u32; 4], flags: u32) -> Result<(), SystemError> {
+  let expects_reply = expects_reply(to);
+  let result = task::ipc_send_pages(
+    ProcessID::new(to),
+    message_from_words(message),
+    PageTransferMode::from_flags(flags),
+    NO_EXPIRATION,
+  );
+  record_send(result, to, expects_reply)
+}
+
+/// Block until a message arrives, copying it into the destination. Returns
+/// the ID of the sending process. If the process this one last sent a
+/// request to exits without replying, the read fails with RecipientGone.
+pub fn ipc_read(dest: &mut [u32; 4]) -> Result<u32, SystemError> {
+  loop {
+    let (packet, _) = task::ipc_read(None);
+    if let Some(packet) = packet {
+      let IPCMessage(a, b, c, d) = packet.message;
+      *dest = [a, b, c, d];
+      return Ok(packet.from.as_u32());
+    }
+    let gone = task::switching::get_current_process().write().take_ipc_recipient_gone();
+    if gone.is_some() {
+      return Err(SystemError::RecipientGone);
+    }
+    if task::signal_pending() {
+      return Err(SystemError::Interrupted);
+    }
+  }
+}
+
+/// A port whose owner has terminated, but hasn't been cleaned up yet, is
+/// treated the same as one whose owner is already gone
+fn process_is_alive(id: ProcessID) -> bool {
+  match task::switching::get_process(&id) {
+    Some(lock) => !lock.read().is_terminated(),
+    None => false,
+  }
+}
+
+pub fn register_port(name: &str) -> Result<(), SystemError> {
+  let current_id = task::switching::get_current_id();
+  PORTS.register(name, current_id, process_is_alive).map_err(|e| e.to_system_error())
+}
+
+/// Queue a message on a named port. Unlike `ipc_send`, a full queue fails
+/// with WouldBlock rather than growing without limit.
+pub fn send_to_port(name: &str, message: &[u32; 4]) -> Result<(), SystemError> {
+  let current_id = task::switching::get_current_id();
+  PORTS
+    .send(name, current_id, message_from_words(message), process_is_alive)
+    .map_err(|e| e.to_system_error())
+}
+
+/// Block until a message arrives on a port owned by the current process,
+/// copying it into the destination. Returns the ID of the sending process.
+pub fn recv_from_port(name: &str, dest: &mut [u32; 4]) -> Result<u32, SystemError> {
+  let current_id = task::switching::get_current_id();
+  loop {
+    match PORTS.receive(name, current_id).map_err(|e| e.to_system_error())? {
+      Some((from, IPCMessage(a, b, c, d))) => {
+        *dest = [a, b, c, d];
+        return Ok(from.as_u32());
+      },
+      None => task::yield_interruptible().map_err(|_| SystemError::Interrupted)?,
+    }
+  }
+}
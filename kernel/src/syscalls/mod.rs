@@ -1,4 +1,11 @@
+#[cfg(not(test))]
 pub mod exec;
+#[cfg(not(test))]
 pub mod file;
+#[cfg(not(test))]
 pub mod fs;
+#[cfg(not(test))]
 pub mod hardware;
+#[cfg(not(test))]
+pub mod ipc;
+pub mod user;
@@ -0,0 +1,218 @@
+//! Syscall arguments often point into the calling process's memory. Before the
+//! kernel reads through one of these pointers, it needs to verify that the
+//! whole range sits in userspace; otherwise a program could trick the kernel
+//! into reading its own memory on the program's behalf.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::task::memory::USER_KERNEL_BARRIER;
+use syscall::StringPtr;
+use syscall::result::SystemError;
+
+/// Longest string that will be copied out of userspace. Paths and names are
+/// far shorter than this; the limit exists so that a bogus length can't make
+/// the kernel walk through a huge range of memory.
+pub const MAX_USER_STRING_LENGTH: usize = 4096;
+
+/// Check that a range of `length` bytes starting at `addr` is within the
+/// userspace area below `limit`
+fn validate_range(addr: usize, length: usize, limit: usize) -> Result<(), SystemError> {
+  if addr == 0 {
+    return Err(SystemError::InvalidArgument);
+  }
+  let end = addr.checked_add(length).ok_or(SystemError::InvalidArgument)?;
+  if end > limit {
+    return Err(SystemError::InvalidArgument);
+  }
+  Ok(())
+}
+
+/// Check that a range of memory passed to a syscall is in userspace
+pub fn validate_user_range(addr: usize, length: usize) -> Result<(), SystemError> {
+  validate_range(addr, length, USER_KERNEL_BARRIER)
+}
+
+/// Check the buffer of a read or write below `limit`. A zero-length transfer
+/// never touches its buffer, so its pointer isn't checked at all, and false is
+/// returned to tell the caller there is nothing to do.
+fn validate_transfer(addr: usize, length: usize, limit: usize) -> Result<bool, SystemError> {
+  if length == 0 {
+    return Ok(false);
+  }
+  validate_range(addr, length, limit)?;
+  Ok(true)
+}
+
+/// Check the buffer passed to a read or write syscall. Returns false if the
+/// length is zero, and the transfer can be skipped.
+pub fn validate_user_transfer(addr: usize, length: usize) -> Result<bool, SystemError> {
+  validate_transfer(addr, length, USER_KERNEL_BARRIER)
+}
+
+/// Copy a string described by a StringPtr, both of which must be found below
+/// `limit`. If the string contains a null byte, it ends there.
+unsafe fn copy_string_below(string_ptr_addr: usize, limit: usize) -> Result<String, SystemError> {
+  validate_range(string_ptr_addr, core::mem::size_of::<StringPtr>(), limit)?;
+  let string_ptr = core::ptr::read_unaligned(string_ptr_addr as *const StringPtr);
+  let (addr, length) = (string_ptr.addr, string_ptr.length);
+  if length == 0 {
+    return Ok(String::new());
+  }
+  // Never read more than the cap, even if the caller claims a longer string
+  let read_length = length.min(MAX_USER_STRING_LENGTH + 1);
+  validate_range(addr, read_length, limit)?;
+  let source = core::slice::from_raw_parts(addr as *const u8, read_length);
+  let terminated = match source.iter().position(|&byte| byte == 0) {
+    Some(end) => &source[..end],
+    None => source,
+  };
+  if terminated.len() > MAX_USER_STRING_LENGTH {
+    return Err(SystemError::InvalidArgument);
+  }
+  let mut bytes = Vec::with_capacity(terminated.len());
+  bytes.extend_from_slice(terminated);
+  String::from_utf8(bytes).map_err(|_| SystemError::InvalidArgument)
+}
+
+/// Copy a string from the calling process into a kernel-owned buffer.
+/// `string_ptr_addr` is the userspace address of a StringPtr, as passed in a
+/// syscall register. Both the StringPtr and the bytes it points to must be in
+/// userspace. Strings longer than MAX_USER_STRING_LENGTH are rejected rather
+/// than truncated, since a truncated path could name a different file.
+pub unsafe fn copy_string_from_user(string_ptr_addr: usize) -> Result<String, SystemError> {
+  copy_string_below(string_ptr_addr, USER_KERNEL_BARRIER)
+}
+
+/// Copy `data` into a buffer of `length` bytes below `limit`. If the data
+/// doesn't fit, nothing is copied.
+unsafe fn copy_below(buffer: usize, length: usize, data: &[u8], limit: usize) -> Result<(), SystemError> {
+  validate_range(buffer, length, limit)?;
+  if data.len() <= length {
+    let dest = core::slice::from_raw_parts_mut(buffer as *mut u8, data.len());
+    dest.copy_from_slice(data);
+  }
+  Ok(())
+}
+
+/// Copy kernel data out to a buffer in the calling process. The whole buffer
+/// must be in userspace. If the data doesn't fit, nothing is copied, so that
+/// the caller can retry with a buffer of the right size. Writing to the buffer
+/// may page it in, so this must not be called with the process locked.
+pub unsafe fn copy_to_user(buffer: *mut u8, length: usize, data: &[u8]) -> Result<(), SystemError> {
+  copy_below(buffer as usize, length, data, USER_KERNEL_BARRIER)
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+  use super::{copy_below, copy_string_below, validate_range, validate_transfer, MAX_USER_STRING_LENGTH, StringPtr};
+  use syscall::result::SystemError;
+
+  fn address_of(ptr: &StringPtr) -> usize {
+    ptr as *const StringPtr as usize
+  }
+
+  #[test]
+  fn copies_strings() {
+    let ptr = StringPtr::from_str("A:\\COMMAND.ELF");
+    let copied = unsafe { copy_string_below(address_of(&ptr), usize::MAX) }.unwrap();
+    assert_eq!(copied, "A:\\COMMAND.ELF");
+
+    // A C-style string ends at its null byte
+    let ptr = StringPtr::from_str("DEV:\\TTY0\0garbage");
+    let copied = unsafe { copy_string_below(address_of(&ptr), usize::MAX) }.unwrap();
+    assert_eq!(copied, "DEV:\\TTY0");
+
+    let ptr = StringPtr::from_str("");
+    let copied = unsafe { copy_string_below(address_of(&ptr), usize::MAX) }.unwrap();
+    assert_eq!(copied, "");
+  }
+
+  #[test]
+  fn overlong_strings_are_capped() {
+    let mut buffer = Vec::new();
+    buffer.resize(MAX_USER_STRING_LENGTH + 1, b'x');
+    let ptr = StringPtr {
+      addr: buffer.as_ptr() as usize,
+      length: buffer.len(),
+    };
+    assert!(unsafe { copy_string_below(address_of(&ptr), usize::MAX) }.is_err());
+
+    // A string that is terminated within the cap is fine, even if the caller
+    // passed a bogus length. Only the capped range is checked and read.
+    buffer[10] = 0;
+    let ptr = StringPtr {
+      addr: buffer.as_ptr() as usize,
+      length: usize::MAX - buffer.as_ptr() as usize,
+    };
+    let copied = unsafe { copy_string_below(address_of(&ptr), usize::MAX) }.unwrap();
+    assert_eq!(copied.len(), 10);
+
+    // Exactly at the limit
+    buffer[10] = b'x';
+    let ptr = StringPtr {
+      addr: buffer.as_ptr() as usize,
+      length: MAX_USER_STRING_LENGTH,
+    };
+    let copied = unsafe { copy_string_below(address_of(&ptr), usize::MAX) }.unwrap();
+    assert_eq!(copied.len(), MAX_USER_STRING_LENGTH);
+  }
+
+  #[test]
+  fn kernel_pointers_are_rejected() {
+    // Lay out a StringPtr followed by its string, and treat everything from
+    // the string onward as kernel space
+    let mut memory: Vec<u8> = Vec::new();
+    memory.resize(64, 0);
+    let base = memory.as_mut_ptr() as usize;
+    memory[32..38].copy_from_slice(b"secret");
+    let ptr = StringPtr {
+      addr: base + 32,
+      length: 6,
+    };
+    unsafe {
+      core::ptr::write_unaligned(base as *mut StringPtr, ptr);
+    }
+    assert_eq!(unsafe { copy_string_below(base, base + 64) }.unwrap(), "secret");
+    assert!(unsafe { copy_string_below(base, base + 32) }.is_err());
+    // A StringPtr that itself lives in kernel space
+    assert!(unsafe { copy_string_below(base, base + 4) }.is_err());
+    // Null pointers and ranges that wrap around
+    assert!(unsafe { copy_string_below(0, usize::MAX) }.is_err());
+    assert!(validate_range(usize::MAX - 4, 8, usize::MAX).is_err());
+    assert!(validate_range(0x1000, 0x1000, 0x2000).is_ok());
+    assert!(validate_range(0x1000, 0x1001, 0x2000).is_err());
+  }
+
+  #[test]
+  fn transfer_buffers() {
+    let barrier = 0xc0000000;
+    // Zero-length transfers are skipped, whatever the pointer
+    assert!(!validate_transfer(0, 0, barrier).unwrap());
+    assert!(!validate_transfer(0xd0000000, 0, barrier).unwrap());
+    assert!(validate_transfer(0x1000, 0x200, barrier).unwrap());
+    assert!(validate_transfer(barrier - 0x200, 0x200, barrier).unwrap());
+
+    // Buffers that reach past the barrier, or wrap the address space
+    assert!(matches!(validate_transfer(barrier - 0x200, 0x201, barrier), Err(SystemError::InvalidArgument)));
+    assert!(matches!(validate_transfer(0x1000, barrier, barrier), Err(SystemError::InvalidArgument)));
+    assert!(matches!(validate_transfer(0x1000, usize::MAX, barrier), Err(SystemError::InvalidArgument)));
+    assert!(matches!(validate_transfer(0, 0x10, barrier), Err(SystemError::InvalidArgument)));
+  }
+
+  #[test]
+  fn copies_to_buffers() {
+    let mut buffer = [0u8; 8];
+    let base = buffer.as_mut_ptr() as usize;
+    unsafe { copy_below(base, 8, b"PATH", usize::MAX) }.unwrap();
+    assert_eq!(&buffer[..5], b"PATH\0");
+
+    // Too small a buffer is left untouched
+    unsafe { copy_below(base, 8, b"LONGPATH.TXT", usize::MAX) }.unwrap();
+    assert_eq!(&buffer[..5], b"PATH\0");
+
+    // The whole buffer must be below the limit, not just the data written
+    assert!(matches!(unsafe { copy_below(base, 8, b"B:", base + 4) }, Err(SystemError::InvalidArgument)));
+    assert_eq!(buffer[0], b'P');
+  }
+}
@@ -0,0 +1,158 @@
+//! Every time a process exits, an accounting record is added to a log that
+//! can be read through DEV:\ACCT. Each record is a line of text listing the
+//! process and its parent, the program it was running, how it ended, how many
+//! timer ticks it spent on the CPU, and its peak resident memory. Having a
+//! record of processes that have already been cleaned up helps track down
+//! crashes and memory hogs after the fact.
+//! Records are written to a fixed-size ring like the kernel log, so old
+//! entries are overwritten rather than growing the log without bound.
+//! Processes can be terminated while the log is being read, like when the OOM
+//! killer runs in the middle of a read. Writers never wait on the lock: if the
+//! log is busy, the record is dropped and counted instead.
+
+use alloc::string::String;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::devices::kmsg::LogRing;
+use crate::locks::RwLock;
+use syscall::signals::{EXIT_CODE_MASK, STATUS_SIGNALED};
+use super::id::ProcessID;
+use super::process::Process;
+
+/// Shown in place of a program name for kernel processes, which never
+/// exec'd a file
+const KERNEL_PROGRAM: &str = "[kernel]";
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ExitReason {
+  /// The process exited on its own, with this code
+  Code(u32),
+  /// The process was terminated by this signal
+  Signal(u32),
+}
+
+impl ExitReason {
+  /// Decode the status that is reported to the parent
+  pub fn from_status(status: u32) -> ExitReason {
+    let value = status & EXIT_CODE_MASK;
+    if status & STATUS_SIGNALED != 0 {
+      ExitReason::Signal(value)
+    } else {
+      ExitReason::Code(value)
+    }
+  }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AccountingRecord {
+  pub id: ProcessID,
+  pub parent: ProcessID,
+  pub program: String,
+  pub exit: ExitReason,
+  pub cpu_ticks: u32,
+  /// Peak resident memory, in bytes
+  pub peak_memory: usize,
+}
+
+impl AccountingRecord {
+  /// Build the record for a process that is exiting with a status
+  pub fn for_process(process: &Process, status: u32) -> AccountingRecord {
+    AccountingRecord {
+      id: *process.get_id(),
+      parent: *process.get_parent_id(),
+      program: String::from(program_name(process.get_exec_path())),
+      exit: ExitReason::from_status(status),
+      cpu_ticks: process.get_cpu_ticks(),
+      peak_memory: process.get_peak_resident_pages() * 0x1000,
+    }
+  }
+}
+
+/// The file name of a program, without its drive or directories
+fn program_name(path: Option<&str>) -> &str {
+  match path {
+    Some(path) => path.rsplit(|ch| ch == '\\' || ch == '/' || ch == ':').next().unwrap_or(path),
+    None => KERNEL_PROGRAM,
+  }
+}
+
+impl fmt::Display for AccountingRecord {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "pid={} ppid={} prog={} ", self.id.as_u32(), self.parent.as_u32(), self.program)?;
+    match self.exit {
+      ExitReason::Code(code) => write!(f, "exit={}", code)?,
+      ExitReason::Signal(signal) => write!(f, "signal={}", signal)?,
+    }
+    write!(f, " ticks={} peak={}K", self.cpu_ticks, self.peak_memory / 1024)
+  }
+}
+
+pub static ACCOUNTING_LOG: RwLock<LogRing> = RwLock::new(LogRing::new());
+
+/// Number of records dropped because the log was busy
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Append a record to a log, as a single line
+pub fn write_record(log: &mut LogRing, record: &AccountingRecord) {
+  let _ = write!(log, "{}\n", record);
+}
+
+/// Add a record to the accounting log. If the log is locked, the record is
+/// dropped.
+pub fn record_exit(record: &AccountingRecord) {
+  match ACCOUNTING_LOG.try_write() {
+    Some(mut log) => write_record(&mut log, record),
+    None => {
+      DROPPED.fetch_add(1, Ordering::SeqCst);
+    },
+  }
+}
+
+pub fn get_dropped_count() -> usize {
+  DROPPED.load(Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::string::String;
+  use crate::devices::kmsg::LogRing;
+  use crate::task::id::ProcessID;
+  use crate::task::process::Process;
+  use crate::task::signal::Signal;
+  use super::{write_record, AccountingRecord, ExitReason};
+
+  #[test]
+  fn terminated_process_record() {
+    let parent = Process::initial(0);
+    let mut child = parent.create_fork(ProcessID::new(12), 40);
+    child.set_exec_path(String::from("C:\\BIN\\LS.BIN"));
+    for _ in 0..7 {
+      child.charge_cpu_tick();
+    }
+    child.note_resident_pages(5);
+    child.note_resident_pages(16);
+    // Freeing memory doesn't lower the peak
+    child.note_resident_pages(3);
+
+    let record = AccountingRecord::for_process(&child, Signal::Kill.get_exit_status());
+    assert_eq!(record, AccountingRecord {
+      id: ProcessID::new(12),
+      parent: ProcessID::new(0),
+      program: String::from("LS.BIN"),
+      exit: ExitReason::Signal(9),
+      cpu_ticks: 7,
+      peak_memory: 16 * 0x1000,
+    });
+
+    let mut log = LogRing::new();
+    write_record(&mut log, &record);
+    write_record(&mut log, &AccountingRecord::for_process(&parent, 3));
+    let mut buffer = [0; 128];
+    let (len, _) = log.read_from(0, &mut buffer);
+    assert_eq!(
+      core::str::from_utf8(&buffer[..len]).unwrap(),
+      "pid=12 ppid=0 prog=LS.BIN signal=9 ticks=7 peak=64K\n\
+      pid=0 ppid=0 prog=[kernel] exit=3 ticks=0 peak=0K\n",
+    );
+  }
+}
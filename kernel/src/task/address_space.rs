@@ -0,0 +1,166 @@
+//! More than one process can run in the same page directory. A vfork child
+//! borrows its parent's directory until it calls exec, and threads share one
+//! for their whole lives. Each process using a directory holds a reference to
+//! its frame in the frame refcount table, so a process that exits only tears
+//! down the userspace mappings and frees the directory if nobody else is still
+//! running in it.
+//! Kernel page tables are linked into every directory when it is created, and
+//! are never freed, so they don't need references of their own. The kernel
+//! stack of each process belongs to that process alone, and is released on
+//! exit no matter who else shares the directory.
+
+use crate::memory::address::PhysicalAddress;
+use crate::memory::physical::allocated_frame::AllocatedFrame;
+use crate::memory::physical::frame_refcount::FrameRefcount;
+use crate::memory::virt::page_table::PageTable;
+
+/// Directory entries below this index map userspace; the rest are kernel
+/// tables shared by every directory
+pub const USER_DIRECTORY_ENTRIES: usize = 0x300;
+
+/// Add another user to a page directory, returning the new number of users
+pub fn share_directory(refcount: &mut FrameRefcount, directory: PhysicalAddress) -> usize {
+  refcount.reference_frame_at_address(directory)
+}
+
+/// Drop one user of a page directory. Returns true if it was the last one, in
+/// which case the caller is responsible for tearing the directory down.
+pub fn release_directory(refcount: &mut FrameRefcount, directory: PhysicalAddress) -> bool {
+  refcount.release_frame_at_address(directory) == 0
+}
+
+/// Remove every userspace mapping from a page directory, on exec or once its
+/// last user exits. Each reclaimable frame is handed to `release`, which drops
+/// its reference, so frames still shared copy-on-write stay allocated. Each
+/// page table is handed to `release` as well, once it has been emptied.
+/// Tables are reached through `with_table`, which is given the directory
+/// index and address of a table, and has to map it for the callback.
+pub fn release_user_space<W, R>(directory: &mut PageTable, mut with_table: W, mut release: R)
+  where W: FnMut(usize, PhysicalAddress, &mut dyn FnMut(&mut PageTable)), R: FnMut(AllocatedFrame) {
+  for dir_entry in 0..USER_DIRECTORY_ENTRIES {
+    if !directory.get(dir_entry).is_present() {
+      continue;
+    }
+    let table_address = directory.get(dir_entry).get_address();
+    with_table(dir_entry, table_address, &mut |table| table.release_entries(&mut release));
+    directory.get_mut(dir_entry).zero();
+    release(AllocatedFrame::new(table_address));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::collections::BTreeMap;
+  use crate::memory::address::PhysicalAddress;
+  use crate::memory::physical::frame_refcount::FrameRefcount;
+  use super::{release_directory, release_user_space, share_directory, USER_DIRECTORY_ENTRIES};
+
+  #[test]
+  fn threads_exit_before_survivor() {
+    let mut refcount = FrameRefcount::new();
+    let directory = PhysicalAddress::new(0x40000);
+    // Pages mapped in the shared directory, by virtual address
+    let mut mappings = BTreeMap::new();
+    mappings.insert(0x1000, PhysicalAddress::new(0x80000));
+    mappings.insert(0x2000, PhysicalAddress::new(0x81000));
+
+    // The process starts two threads, then both exit
+    assert_eq!(share_directory(&mut refcount, directory), 2);
+    assert_eq!(share_directory(&mut refcount, directory), 3);
+    for _ in 0..2 {
+      if release_directory(&mut refcount, directory) {
+        mappings.clear();
+      }
+    }
+    // The survivor still sees every page it had mapped
+    assert_eq!(mappings.get(&0x1000), Some(&PhysicalAddress::new(0x80000)));
+    assert_eq!(mappings.len(), 2);
+
+    // Threads can come and go again, until the last user exits
+    share_directory(&mut refcount, directory);
+    assert!(!release_directory(&mut refcount, directory));
+    assert!(release_directory(&mut refcount, directory));
+    assert_eq!(refcount.get_count_for_address(directory), 1);
+  }
+
+  #[test]
+  fn exec_and_exit_return_frames() {
+    use alloc::boxed::Box;
+    use crate::memory::address::VirtualAddress;
+    use crate::memory::physical::frame_bitmap::FrameBitmap;
+    use crate::memory::physical::frame_range::FrameRange;
+    use crate::memory::physical::release_frame;
+    use crate::memory::virt::page_table::PageTable;
+
+    let memory: [u8; 4] = [0; 4];
+    let mut bitmap = FrameBitmap::at_location(
+      VirtualAddress::new(&memory[0] as *const u8 as usize),
+      32,
+    );
+    let mut refcount = FrameRefcount::new();
+    let baseline = bitmap.get_free_frame_count();
+    let directory_address = bitmap.allocate_frames(1).unwrap().get_starting_address();
+    let mut directory = Box::new(PageTable::new());
+    // Kernel tables are linked into every directory, and are never released
+    directory.get_mut(USER_DIRECTORY_ENTRIES).set_address(PhysicalAddress::new(0x1000));
+    directory.get_mut(USER_DIRECTORY_ENTRIES).set_present();
+    let mut tables: BTreeMap<usize, Box<PageTable>> = BTreeMap::new();
+    // Load a program: a table for its code, and another for its stack
+    let load = |directory: &mut PageTable, bitmap: &mut FrameBitmap, tables: &mut BTreeMap<usize, Box<PageTable>>| {
+      for dir_entry in [0, USER_DIRECTORY_ENTRIES - 1] {
+        let table_address = bitmap.allocate_frames(1).unwrap().get_starting_address();
+        let mut table = Box::new(PageTable::new());
+        for index in 0..3 {
+          table.get_mut(index).set_address(bitmap.allocate_frames(1).unwrap().get_starting_address());
+          table.get_mut(index).set_present();
+        }
+        tables.insert(table_address.as_usize(), table);
+        directory.get_mut(dir_entry).set_address(table_address);
+        directory.get_mut(dir_entry).set_present();
+      }
+    };
+    load(&mut directory, &mut bitmap, &mut tables);
+    // One page is shared copy-on-write with a forked child
+    let shared = tables.values().next().unwrap().get(0).get_address();
+    refcount.reference_frame_at_address(shared);
+    for table in tables.values_mut() {
+      if table.get(0).get_address() == shared {
+        table.get_mut(0).set_cow();
+      }
+    }
+    assert_eq!(bitmap.get_free_frame_count(), baseline - 9);
+
+    let release_all = |directory: &mut PageTable, bitmap: &mut FrameBitmap, refcount: &mut FrameRefcount, tables: &mut BTreeMap<usize, Box<PageTable>>| {
+      release_user_space(
+        directory,
+        |_, address, release| release(tables.get_mut(&address.as_usize()).unwrap()),
+        |frame| {
+          release_frame(bitmap, refcount, frame.to_frame()).unwrap();
+        },
+      );
+    };
+
+    // Exec tears down the old program's mappings and tables, keeping only
+    // the directory and the page the child still uses
+    release_all(&mut directory, &mut bitmap, &mut refcount, &mut tables);
+    assert_eq!(bitmap.get_free_frame_count(), baseline - 2);
+    assert_eq!(refcount.get_count_for_address(shared), 1);
+    for dir_entry in 0..USER_DIRECTORY_ENTRIES {
+      assert!(!directory.get(dir_entry).is_present());
+    }
+    assert!(directory.get(USER_DIRECTORY_ENTRIES).is_present());
+
+    // The new program runs, then exits as the directory's last user
+    tables.clear();
+    load(&mut directory, &mut bitmap, &mut tables);
+    assert_eq!(bitmap.get_free_frame_count(), baseline - 10);
+    assert!(release_directory(&mut refcount, directory_address));
+    release_all(&mut directory, &mut bitmap, &mut refcount, &mut tables);
+    bitmap.free_range(FrameRange::new(directory_address.as_usize(), 0x1000)).unwrap();
+    assert_eq!(bitmap.get_free_frame_count(), baseline - 1);
+
+    // Once the child lets go of the shared page, nothing is left allocated
+    assert!(release_frame(&mut bitmap, &mut refcount, FrameRange::new(shared.as_usize(), 0x1000).get_first_frame()).unwrap());
+    assert_eq!(bitmap.get_free_frame_count(), baseline);
+  }
+}
@@ -0,0 +1,111 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+
+/// Upper limit on the combined size of all variables in an environment. Each
+/// variable counts as NAME=VALUE plus a terminating byte, which is how it would
+/// be laid out in an envp block.
+pub const MAX_ENVIRONMENT_SIZE: usize = 4096;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EnvironmentError {
+  /// The name is empty, or contains '=' or a null byte
+  InvalidName,
+  /// Storing the variable would exceed the environment's size limit
+  TooLarge,
+}
+
+/// The environment is a set of named string variables belonging to a process,
+/// used for things like PATH and HOME. Forking gives the child a copy of its
+/// parent's environment, and the environment is kept across exec.
+#[derive(Clone)]
+pub struct Environment {
+  variables: BTreeMap<String, String>,
+  size: usize,
+}
+
+fn entry_size(name: &str, value: &str) -> usize {
+  name.len() + value.len() + 2
+}
+
+impl Environment {
+  pub const fn new() -> Environment {
+    Environment {
+      variables: BTreeMap::new(),
+      size: 0,
+    }
+  }
+
+  pub fn get(&self, name: &str) -> Option<&str> {
+    self.variables.get(name).map(|value| value.as_str())
+  }
+
+  /// Set a variable, replacing any existing value
+  pub fn set(&mut self, name: &str, value: &str) -> Result<(), EnvironmentError> {
+    if name.is_empty() || name.contains('=') || name.contains('\0') || value.contains('\0') {
+      return Err(EnvironmentError::InvalidName);
+    }
+    let previous_size = self.get(name).map_or(0, |previous| entry_size(name, previous));
+    let new_size = self.size - previous_size + entry_size(name, value);
+    if new_size > MAX_ENVIRONMENT_SIZE {
+      return Err(EnvironmentError::TooLarge);
+    }
+    self.variables.insert(String::from(name), String::from(value));
+    self.size = new_size;
+    Ok(())
+  }
+
+  /// Remove a variable, returning its previous value if it existed
+  pub fn unset(&mut self, name: &str) -> Option<String> {
+    let value = self.variables.remove(name)?;
+    self.size -= entry_size(name, &value);
+    Some(value)
+  }
+
+  /// Combined size of all variables, as counted against the size limit
+  pub fn size(&self) -> usize {
+    self.size
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Environment, EnvironmentError, MAX_ENVIRONMENT_SIZE};
+  use alloc::string::String;
+
+  #[test]
+  fn set_and_overwrite() {
+    let mut env = Environment::new();
+    assert_eq!(env.get("PATH"), None);
+    env.set("PATH", "A:\\BIN").unwrap();
+    assert_eq!(env.get("PATH"), Some("A:\\BIN"));
+    assert_eq!(env.size(), 12);
+    env.set("PATH", "INIT:\\").unwrap();
+    assert_eq!(env.get("PATH"), Some("INIT:\\"));
+    assert_eq!(env.size(), 12);
+    assert_eq!(env.unset("PATH"), Some(String::from("INIT:\\")));
+    assert_eq!(env.unset("PATH"), None);
+    assert_eq!(env.size(), 0);
+
+    assert_eq!(env.set("", "x"), Err(EnvironmentError::InvalidName));
+    assert_eq!(env.set("A=B", "x"), Err(EnvironmentError::InvalidName));
+  }
+
+  #[test]
+  fn size_limit() {
+    let mut env = Environment::new();
+    let mut value = String::new();
+    for _ in 0..(MAX_ENVIRONMENT_SIZE - 3) {
+      value.push('x');
+    }
+    // Exactly fills the environment
+    env.set("A", &value).unwrap();
+    assert_eq!(env.size(), MAX_ENVIRONMENT_SIZE);
+    assert_eq!(env.set("B", ""), Err(EnvironmentError::TooLarge));
+    // Replacing a value only counts the difference
+    value.push('x');
+    assert_eq!(env.set("A", &value), Err(EnvironmentError::TooLarge));
+    env.set("A", "short").unwrap();
+    env.set("B", "").unwrap();
+    assert_eq!(env.size(), 11);
+  }
+}
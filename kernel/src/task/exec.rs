@@ -1,54 +1,138 @@
-use crate::dos::state::VMState;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::files::handle::FileHandle;
 use crate::fs::DRIVES;
 use crate::loaders;
+use crate::loaders::environment::ExecutionEnvironment;
 use crate::memory::address::VirtualAddress;
 use crate::task::switching::{get_current_process, yield_coop};
 use super::id::ProcessID;
+use super::memory::{MMapBacking, ProcessMemoryError};
+use super::accounting::{record_exit, AccountingRecord};
+use super::process::{ExecImage, Process};
 use super::regs::EnvironmentRegisters;
 use super::signal::Signal;
-use super::vm::Subsystem;
+use crate::locks::RwLock;
 use syscall::result::SystemError;
 
-/// Load an executable file from disk, map it into memory, and begin execution
-pub fn exec(path_str: &str, interp_mode: loaders::InterpretationMode) -> Result<(), SystemError> {
-  let (drive_id, local_handle, env) = loaders::load_executable(path_str, interp_mode).map_err(|e| e.to_system_error())?;
-  // TODO: If anything fails within or after this block, we need a way to
-  // "rewind" the changes here.
-  let to_close = {
-    let process_lock = get_current_process();
-    let mut process = process_lock.write();
-    let heap_range = process.memory.get_heap_page_range();
-    let old_exec = process.prepare_exec_mapping(env.segments);
-    // Remove the old exec and mmap mappings:
-    super::paging::unmap_task(old_exec, heap_range);
+/// Expand a path into the fully-qualified form stored as a process's
+/// executable path, like INIT:\SHELL.BIN
+fn get_full_path(path_str: &str) -> Result<String, SystemError> {
+  let (drive_id, path) = super::io::get_drive_id_and_path(path_str)?;
+  let drive_name = DRIVES.get_drive_name(&drive_id).ok_or(SystemError::NoSuchDrive)?;
+  Ok(alloc::format!("{}:\\{}", drive_name, path.as_str()))
+}
 
-    // Map a new stack frame, and push arguments onto it
+/// A DOS program's memory arena starts with the MCB just below its PSP, so a
+/// PSP at segment 0 can't be set up
+fn dos_memory_arena(env: &ExecutionEnvironment) -> Result<(u16, crate::dos::memory::MemoryArena), SystemError> {
+  let segment = env.registers.ds.unwrap_or(0) as u16;
+  let arena = crate::dos::memory::MemoryArena::for_psp(segment).map_err(|_| SystemError::InvalidArgument)?;
+  Ok((segment, arena))
+}
 
-    // If running a DOS program, the VM needs to be initialized
+/// Load an executable and gather everything needed to run it, without
+/// touching the current process. If anything fails, the executable file is
+/// closed again and the caller keeps running its current program.
+fn load_exec_image(path: &str, args: Vec<String>, interp_mode: loaders::InterpretationMode) -> Result<(ExecImage, ExecutionEnvironment), SystemError> {
+  let (drive_id, local_handle, mut env) = loaders::load_executable(path, interp_mode, args).map_err(|e| e.to_system_error())?;
+  let checked = get_full_path(path).and_then(|exec_path| {
     if env.require_vm {
-      process.subsystem = Subsystem::DOS(VMState::new());
+      dos_memory_arena(&env)?;
     }
+    Ok(exec_path)
+  });
+  let exec_path = match checked {
+    Ok(exec_path) => exec_path,
+    Err(e) => {
+      if let Some((_, instance)) = DRIVES.get_drive_instance(&drive_id) {
+        let _ = instance.close(local_handle);
+      }
+      return Err(e);
+    },
+  };
+  let image = ExecImage {
+    segments: core::mem::replace(&mut env.segments, Vec::new()),
+    relocations: core::mem::replace(&mut env.relocations, Vec::new()),
+    exec_file: (drive_id, local_handle),
+    exec_path,
+    require_vm: env.require_vm,
+    initial_heap_size: env.initial_heap_size,
+    arguments: core::mem::replace(&mut env.arguments, Vec::new()),
+  };
+  Ok((image, env))
+}
 
-    process.set_relocations(env.relocations);
-
-    process.set_exec_file(drive_id, local_handle)
+/// Load an executable file from disk, map it into memory, and begin execution.
+/// Loading happens in full before the current program is replaced, so if it
+/// fails, the error is returned and the process carries on unchanged.
+/// `args` are the arguments that follow the program's path.
+pub fn exec<P: AsRef<str>>(path: P, args: Vec<String>, interp_mode: loaders::InterpretationMode) -> Result<(), SystemError> {
+  let (image, env) = load_exec_image(path.as_ref(), args, interp_mode)?;
+  // A successful exec never returns to the caller, so a path that was copied
+  // out of userspace needs to be released here
+  drop(path);
+  // Past this point, nothing can fail: the old program is replaced
+  let replaced = {
+    let process_lock = get_current_process();
+    let mut process = process_lock.write();
+    // Only stdio, and handles explicitly marked to survive, are passed on to
+    // the new program
+    let replaced = process.commit_exec(image);
+    match process.get_vfork_parent() {
+      Some(_) => {
+        // A vfork child has been running in its parent's address space. Rather
+        // than tearing it down, give the child a fresh page directory of its
+        // own, and drop its reference to the borrowed one.
+        let borrowed = process.page_directory.get_address();
+        process.page_directory = super::switching::fork_page_directory(false);
+        process.page_directory.make_active();
+        super::paging::release_page_directory(borrowed);
+      },
+      None => {
+        note_resident_pages(&mut process);
+        // Remove the old exec, heap, stack, and mmap mappings, returning their
+        // frames and page tables to the allocator
+        super::paging::unmap_user_space();
+      },
+    }
+    replaced
   };
-  // Close the old executable
-  match to_close {
-    Some((close_drive, close_handle)) => {
-      let (_, instance) = DRIVES.get_drive_instance(&close_drive).ok_or(SystemError::NoSuchFileSystem)?;
-      instance.close(close_handle).map_err(|_| SystemError::IOError)?;
-    },
-    None => (),
+  // The address space now belongs to the child alone, so a vfork parent can
+  // pick up where it left off
+  let (vfork_parent, current_id) = {
+    let process_lock = get_current_process();
+    let mut process = process_lock.write();
+    (process.take_vfork_parent(), *process.get_id())
+  };
+  if let Some(parent_id) = vfork_parent {
+    super::switching::release_vfork_parent(parent_id, current_id);
+  }
+  for file in replaced.uninherited {
+    if let Some((_, instance)) = DRIVES.get_drive_instance(&file.drive) {
+      let _ = instance.close(file.local_handle);
+    }
+  }
+  // Close the old executable. The new program is already in place, so a
+  // failure here can't be reported to the old one.
+  if let Some((close_drive, close_handle)) = replaced.exec_file {
+    if let Some((_, instance)) = DRIVES.get_drive_instance(&close_drive) {
+      let _ = instance.close(close_handle);
+    }
   }
   // Set up the environment to run the new program
-  if env.require_vm {
-    // Initialize DOS memory
-    let segment = env.registers.ds.unwrap_or(0) as u16;
+  if let (true, Ok((segment, arena))) = (env.require_vm, dos_memory_arena(&env)) {
+    // Initialize DOS memory. The arena was already checked when the image
+    // was loaded.
     let psp = unsafe { crate::dos::execution::PSP::at_segment(segment) };
     // Writing to this PSP will trigger a page fault and fill the first page of
     // the program.
     psp.reset();
+    // The program starts out owning all conventional memory above its PSP.
+    // It is expected to shrink its block before allocating any more.
+    psp.memory_top_paragraphs = crate::dos::memory::CONVENTIONAL_MEMORY_END;
+    arena.init(segment);
 
     let vterm_index = {
       get_current_process().read().get_vterm()
@@ -129,91 +213,313 @@ pub fn terminate(exit_code: u32) {
 }
 
 pub fn terminate_process(id: ProcessID, exit_code: u32) {
-  let parent_id = {
+  let (parent_id, group, vfork_parent, record) = {
     let mut process = super::switching::get_process(&id);
     match process {
       Some(proc_lock) => {
         let mut proc = proc_lock.write();
+        // A process that was already terminated has been accounted for
+        let record = if proc.is_terminated() {
+          None
+        } else {
+          // A vfork child's memory belongs to its parent
+          if proc.get_vfork_parent().is_none() {
+            note_resident_pages(&mut proc);
+          }
+          Some(AccountingRecord::for_process(&proc, exit_code))
+        };
         proc.terminate();
-        *proc.get_parent_id()
+        (*proc.get_parent_id(), proc.get_process_group(), proc.get_vfork_parent(), record)
       },
       None => return,
     }
   };
-  {
-    let parent_lock = super::switching::get_process(&parent_id);
-    if let Some(parent) = parent_lock {
-      parent.write().child_returned(id, exit_code);
-    }
+  if let Some(record) = record {
+    record_exit(&record);
+  }
+  // An interrupt arriving after this point is acknowledged and dropped,
+  // rather than entering code that is about to be unmapped
+  crate::interrupts::handlers::remove_handlers_for_process(id);
+  super::ports::PORTS.remove_ports_for_process(id);
+  crate::files::locks::LOCKS.release_process(id);
+  if let Some(vfork_parent_id) = vfork_parent {
+    // Exiting without an exec also hands the address space back
+    super::switching::release_vfork_parent(vfork_parent_id, id);
+  }
+  // A process that is exiting on its own is still running on its kernel
+  // stack, so it is left for the cleanup task
+  let removable = id != super::switching::get_current_id();
+  let reaped = super::fork::report_exit(&super::switching::TASK_MAP, id, parent_id, group, exit_code, removable);
+  if let Some(task_lock) = reaped {
+    super::switching::release_process(id, task_lock);
   }
 }
 
-pub fn send_signal(proc: Option<ProcessID>, signal: Signal) {
+/// Send a signal to a process. If the process has installed a handler, the
+/// signal is queued and the handler runs the next time the process returns
+/// from a syscall. A process blocked in a syscall is woken up, and the syscall
+/// is interrupted. Otherwise the default action applies. Fails if the process
+/// doesn't exist.
+pub fn send_signal(proc: Option<ProcessID>, signal: Signal) -> Result<(), SystemError> {
   let receiver = match proc {
     Some(id) => id,
     None => super::switching::get_current_id(),
   };
 
-  // todo: custom signal handlers
+  let (continued, handled) = {
+    let proc_lock = super::switching::get_process(&receiver).ok_or(SystemError::NoSuchEntity)?;
+    let mut process = proc_lock.write();
+    // A stopped process always continues, even if it handles or ignores the
+    // signal
+    let continued = match signal {
+      Signal::Continue if process.is_paused() => {
+        process.resume();
+        true
+      },
+      _ => false,
+    };
+    let handled = if process.signals.is_ignored(signal.get_number()) {
+      true
+    } else if process.signals.raise(signal.get_number()) {
+      // A blocked syscall returns early, so that the handler can run
+      process.wake_for_signal();
+      true
+    } else {
+      false
+    };
+    (continued, handled)
+  };
+  if continued {
+    notify_parent_of_status(receiver, syscall::signals::STATUS_CONTINUED);
+  }
+  if handled {
+    return Ok(());
+  }
 
   match signal {
     Signal::Segfault => {
       //terminate(0);
     },
-    Signal::UserInterrupt => {
-      terminate_process(receiver, 0);
+    Signal::Stop => {
+      stop_process(receiver, signal.get_number());
+    },
+    Signal::Continue => (),
+    Signal::Hangup | Signal::UserInterrupt | Signal::UserQuit | Signal::Kill | Signal::Terminate => {
+      terminate_process(receiver, signal.get_exit_status());
     },
-    Signal::UserQuit => {
-      terminate_process(receiver, 0);
+  }
+  Ok(())
+}
+
+/// Send a signal to every live process in a group. The current process is
+/// signaled last, so that a signal which stops or ends it still reaches the
+/// rest of the group. Fails if the group has no members.
+pub fn send_signal_to_group(group: ProcessID, signal: Signal) -> Result<(), SystemError> {
+  let current_id = super::switching::get_current_id();
+  let processes: Vec<Arc<RwLock<Process>>> = super::switching::with_task_map(|map| map.values().cloned().collect());
+  super::process::signal_group(&processes, group, current_id, |id| {
+    // A member may exit before its signal arrives
+    let _ = send_signal(Some(id), signal);
+  })
+}
+
+/// Move a process into a process group. The process must be the caller or
+/// one of its children. Joining a group named after the process's own ID
+/// makes it the leader of a new group; any other group must already exist.
+pub fn set_process_group(id: ProcessID, group: ProcessID) -> Result<(), SystemError> {
+  let current_id = super::switching::get_current_id();
+  if group != id {
+    let mut group_exists = false;
+    super::switching::for_each_process_mut(|proc_lock| {
+      if proc_lock.read().is_in_group(group) {
+        group_exists = true;
+      }
+    });
+    if !group_exists {
+      return Err(SystemError::PermissionDenied);
     }
   }
+  let proc_lock = super::switching::get_process(&id).ok_or(SystemError::NoSuchEntity)?;
+  let mut process = proc_lock.write();
+  if *process.get_id() != current_id && *process.get_parent_id() != current_id {
+    return Err(SystemError::NoSuchEntity);
+  }
+  process.set_process_group(group);
+  Ok(())
+}
+
+/// Get the group of a process, which must exist
+pub fn get_process_group(id: ProcessID) -> Result<ProcessID, SystemError> {
+  let proc_lock = super::switching::get_process(&id).ok_or(SystemError::NoSuchEntity)?;
+  let process = proc_lock.read();
+  Ok(process.get_process_group())
+}
+
+/// List the memory regions of a process, along with how much of each is
+/// currently backed by physical frames, for debugging. Unless the caller is
+/// privileged, the process must be the caller or one of its children.
+pub fn describe_memory(id: ProcessID, privileged: bool) -> Result<String, SystemError> {
+  let current_id = super::switching::get_current_id();
+  let proc_lock = super::switching::get_process(&id).ok_or(SystemError::NoSuchEntity)?;
+  let process = proc_lock.read();
+  if !privileged && *process.get_id() != current_id && *process.get_parent_id() != current_id {
+    return Err(SystemError::PermissionDenied);
+  }
+  let pagedir_address = process.page_directory.get_address();
+  let mut listing = String::new();
+  process.memory
+    .write_map(&mut listing, |page| super::paging::is_page_resident(pagedir_address, page))
+    .map_err(|_| SystemError::Unknown)?;
+  Ok(listing)
+}
+
+/// Pause a process until it receives CONTINUE. Stopping a process that is
+/// already stopped does nothing, so its parent only hears about it once.
+fn stop_process(id: ProcessID, signal: u32) {
+  {
+    let proc_lock = match super::switching::get_process(&id) {
+      Some(lock) => lock,
+      None => return,
+    };
+    let mut process = proc_lock.write();
+    if process.is_paused() || process.is_terminated() {
+      return;
+    }
+    process.pause();
+  }
+  notify_parent_of_status(id, syscall::signals::STATUS_STOPPED | signal);
+  if id == super::switching::get_current_id() {
+    yield_coop();
+  }
+}
+
+fn notify_parent_of_status(id: ProcessID, status: u32) {
+  let (parent_id, group) = match super::switching::get_process(&id) {
+    Some(lock) => {
+      let process = lock.read();
+      (*process.get_parent_id(), process.get_process_group())
+    },
+    None => return,
+  };
+  if let Some(parent) = super::switching::get_process(&parent_id) {
+    parent.write().child_status_changed(id, group, status);
+  }
 }
 
-pub fn set_heap_top(addr: VirtualAddress) -> Result<VirtualAddress, ()> {
+/// Sample a process's resident memory before some of it is released, so
+/// that its peak usage can be reported when it exits
+fn note_resident_pages(process: &mut Process) {
+  let resident = super::paging::count_resident_pages(process.page_directory.get_address());
+  process.note_resident_pages(resident);
+}
+
+/// Implements `brk`: move the end of the heap to an absolute address, and
+/// return the new end. The heap can't grow beyond the process's data limit.
+pub fn set_heap_top(addr: VirtualAddress) -> Result<VirtualAddress, ProcessMemoryError> {
   let current_process_lock = get_current_process();
   let mut cur = current_process_lock.write();
   let heap_start = cur.memory.get_heap_start();
   if addr < heap_start {
-    return Err(());
+    return Err(ProcessMemoryError::MapOutOfBounds);
+  }
+  let new_size = addr - heap_start;
+  if new_size > cur.memory.get_heap_size() && !cur.heap_size_allowed(new_size) {
+    return Err(ProcessMemoryError::NotEnoughMemory);
+  }
+  let freed = cur.memory.resize_heap(new_size)?;
+  if freed.start < freed.end {
+    note_resident_pages(&mut cur);
   }
-  let prev_size = cur.memory.get_heap_size();
-  let size = addr - heap_start;
-  cur.memory.set_heap_size(size);
-  unmap_unused_heap(heap_start, prev_size, size);
+  super::paging::unmap_range(freed).map_err(|_| ProcessMemoryError::FreeFailed)?;
   Ok(cur.memory.get_heap_start() + cur.memory.get_heap_size())
 }
 
-pub fn move_heap_top(delta: isize) -> Result<VirtualAddress, ()> {
+/// Implements `sbrk`: grow or shrink the heap by a number of bytes, and return
+/// the previous end, which is the start of any newly added memory. Shrinking
+/// frees the frames behind any pages that fall entirely outside the heap.
+pub fn move_heap_top(delta: isize) -> Result<VirtualAddress, ProcessMemoryError> {
   let current_process_lock = get_current_process();
   let mut cur = current_process_lock.write();
+  let prev_end = cur.memory.get_heap_start() + cur.memory.get_heap_size();
   if delta != 0 {
     let current_size = cur.memory.get_heap_size();
     let new_size = current_size as isize + delta;
     if new_size < 0 {
-      return Err(());
-    } else {
-      cur.memory.set_heap_size(new_size as usize);
+      return Err(ProcessMemoryError::MapOutOfBounds);
+    }
+    if delta > 0 && !cur.heap_size_allowed(new_size as usize) {
+      return Err(ProcessMemoryError::NotEnoughMemory);
     }
+    let freed = cur.memory.resize_heap(new_size as usize)?;
+    if freed.start < freed.end {
+      note_resident_pages(&mut cur);
+    }
+    super::paging::unmap_range(freed).map_err(|_| ProcessMemoryError::FreeFailed)?;
+  }
+  Ok(prev_end)
+}
 
-    let heap_start = cur.memory.get_heap_start();
-    unmap_unused_heap(heap_start, current_size, new_size as usize);
+/// Remove part or all of the current process's mmap regions. Only pages that
+/// were actually mapped are released, so a request that overlaps the edge of a
+/// region (or several regions) frees just the overlapping pages.
+pub fn munmap(addr: VirtualAddress, length: usize) -> Result<(), ProcessMemoryError> {
+  let current_process_lock = get_current_process();
+  let mut cur = current_process_lock.write();
+  let unmapped = cur.memory.munmap(addr, length)?;
+  if !unmapped.is_empty() {
+    note_resident_pages(&mut cur);
   }
+  let mut result = Ok(());
+  for range in unmapped {
+    let freed = super::paging::unmap_range(range).map_err(|_| ProcessMemoryError::FreeFailed);
+    result = result.and(freed);
+  }
+  result
+}
 
-  Ok(cur.memory.get_heap_start() + cur.memory.get_heap_size())
+/// Map a copy of a file that has been sealed against writing into the current
+/// process, read-only. Returns the address of the mapping, which is released
+/// by munmap or exit like any other.
+pub fn map_sealed_file(handle: FileHandle) -> Result<VirtualAddress, SystemError> {
+  let contents = super::io::get_sealed_contents(handle)?;
+  if contents.is_empty() {
+    return Err(SystemError::InvalidArgument);
+  }
+  let size = VirtualAddress::new(contents.len()).next_page_barrier().as_usize();
+  let address = get_current_process()
+    .write()
+    .memory
+    .mmap(None, size, MMapBacking::Transferred)
+    .map_err(|_| SystemError::NoSpace)?;
+  if super::paging::map_read_only_copy(address, &contents).is_err() {
+    let _ = munmap(address, size);
+    return Err(SystemError::NoSpace);
+  }
+  Ok(address)
 }
 
-fn unmap_unused_heap(start: VirtualAddress, prev_size: usize, new_size: usize) {
-  if new_size >= prev_size {
-    return;
+/// Find the pages covered by a userspace range of `length` bytes, rounding
+/// outwards to page boundaries
+fn user_page_range(addr: VirtualAddress, length: usize) -> Result<core::ops::Range<VirtualAddress>, SystemError> {
+  let end = addr.as_usize().checked_add(length).ok_or(SystemError::InvalidArgument)?;
+  if end > super::memory::USER_KERNEL_BARRIER {
+    return Err(SystemError::InvalidArgument);
   }
+  Ok(addr.prev_page_barrier()..VirtualAddress::new(end).next_page_barrier())
+}
 
-  let prev_end = start + prev_size;
-  let new_end = start + new_size as usize;
+/// Lock part of the current process's memory into RAM, faulting in every page
+/// immediately. See `paging::lock_pages`.
+pub fn mlock(addr: VirtualAddress, length: usize) -> Result<(), SystemError> {
+  let range = user_page_range(addr, length)?;
+  super::paging::lock_pages(get_current_process(), range)
+    .map_err(|_| SystemError::InvalidArgument)
+}
 
-  let mut page = new_end.prev_page_barrier() + 4096;
-  let end = prev_end.prev_page_barrier();
-  while page <= end {
-    super::paging::unmap_page(page);
-    page = page + 4096;
-  }
+/// Release a lock made by `mlock`
+pub fn munlock(addr: VirtualAddress, length: usize) -> Result<(), SystemError> {
+  let range = user_page_range(addr, length)?;
+  super::paging::unlock_pages(range)
+    .map_err(|_| SystemError::InvalidArgument)
 }
+
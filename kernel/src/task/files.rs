@@ -2,12 +2,35 @@ use crate::collections::SlotList;
 use crate::files::handle::LocalHandle;
 use crate::fs::drive::DriveID;
 
+/// Handles below this number are stdin, stdout, and stderr, which are kept
+/// across exec unless marked FD_CLOEXEC
+pub const STDIO_HANDLE_COUNT: usize = 3;
+
 /// An open file contains a reference to a drive, and the handle local to that
-/// drive that can be used to access the file.
-#[derive(Copy, Clone)]
+/// drive that can be used to access the file. It also stores the status flags
+/// (like O_NONBLOCK) that change how IO on the handle behaves, and descriptor
+/// flags (like FD_CLOEXEC) that belong to this handle alone.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct OpenFile {
   pub drive: DriveID,
   pub local_handle: LocalHandle,
+  pub flags: u32,
+  pub descriptor_flags: u32,
+}
+
+impl OpenFile {
+  pub fn is_nonblocking(&self) -> bool {
+    self.flags & syscall::flags::O_NONBLOCK != 0
+  }
+
+  /// Determine whether the file stays open when the process at `handle`
+  /// execs a new program
+  pub fn survives_exec(&self, handle: usize) -> bool {
+    if self.descriptor_flags & syscall::flags::FD_CLOEXEC != 0 {
+      return false;
+    }
+    handle < STDIO_HANDLE_COUNT || self.descriptor_flags & syscall::flags::FD_KEEPEXEC != 0
+  }
 }
 
 /// A file map contains slots to open files. A FileHandle represents an index
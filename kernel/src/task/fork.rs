@@ -0,0 +1,284 @@
+//! Forking touches most of the kernel's global state: the task map, the parent
+//! and child processes, the kernel stacks, and the frame allocator and
+//! reference counts for every page the two processes share. To keep to the
+//! order in locks.rs, each step releases its locks before the next one begins:
+//!
+//!   1. The parent is read-locked just long enough to copy it into the child,
+//!      which also reserves the child's kernel stack.
+//!   2. The address space is copied with no task or process lock held, since
+//!      it takes the refcount and allocator locks for each shared page.
+//!   3. The child is added to the task map, and only locked afterwards, to
+//!      hand it to the scheduler.
+//!
+//! Tearing a process down mirrors this: it is removed from the task map
+//! before it is locked. The task map is never acquired while a process lock is
+//! held.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use crate::locks::{ordered, LockLevel};
+use crate::memory::virt::page_table::PageTableReference;
+use crate::locks::RwLock;
+use super::id::ProcessID;
+use super::process::{Process, WaitTarget};
+
+pub type TaskMap = BTreeMap<ProcessID, Arc<RwLock<Process>>>;
+
+/// Create a child of a process and add it to the task map.
+/// `copy_address_space` builds the child's page directory. `prepare` finishes
+/// setting up the child, like reopening its files, before any other code can
+/// see it. Neither runs with a task or process lock held.
+pub fn fork_process<C, P>(
+  task_map: &RwLock<TaskMap>,
+  parent_lock: &RwLock<Process>,
+  child_id: ProcessID,
+  current_ticks: u32,
+  copy_address_space: C,
+  prepare: P,
+) -> Arc<RwLock<Process>>
+  where C: FnOnce() -> PageTableReference, P: FnOnce(&mut Process) {
+  let mut child = {
+    let _order = ordered(LockLevel::Process);
+    let parent = parent_lock.read();
+    parent.create_fork(child_id, current_ticks)
+  };
+  child.page_directory = copy_address_space();
+  prepare(&mut child);
+  add_task(task_map, child)
+}
+
+/// Make a new process visible to the rest of the kernel, and to the scheduler
+pub fn add_task(task_map: &RwLock<TaskMap>, process: Process) -> Arc<RwLock<Process>> {
+  let id = *process.get_id();
+  let entry = Arc::new(RwLock::new(process));
+  {
+    let _order = ordered(LockLevel::TaskMap);
+    task_map.write().insert(id, entry.clone());
+  }
+  let _order = ordered(LockLevel::Process);
+  entry.write().enter_run_queue();
+  entry
+}
+
+/// Remove a process from the task map and the scheduler, returning it so that
+/// its resources can be released
+pub fn remove_task(task_map: &RwLock<TaskMap>, id: ProcessID) -> Option<Arc<RwLock<Process>>> {
+  let entry = {
+    let _order = ordered(LockLevel::TaskMap);
+    task_map.write().remove(&id)?
+  };
+  {
+    let _order = ordered(LockLevel::Process);
+    entry.write().leave_run_queue();
+  }
+  Some(entry)
+}
+
+/// Check whether a running child of `parent` could still end a wait for
+/// `target`
+pub fn has_live_child(task_map: &RwLock<TaskMap>, parent: ProcessID, target: WaitTarget) -> bool {
+  let _order = ordered(LockLevel::TaskMap);
+  let map = task_map.read();
+  map.values().any(|entry| {
+    let _order = ordered(LockLevel::Process);
+    entry.read().is_live_child_of(parent, target)
+  })
+}
+
+/// Tell the parent of a terminated process about its exit. A parent that
+/// ignores SIGCHLD never collects the exit, so the child is removed from the
+/// task map right away and returned, for the caller to release what it holds.
+/// If `removable` is false, because the child is still running on its own
+/// kernel stack, it is left for the cleanup task instead.
+pub fn report_exit(
+  task_map: &RwLock<TaskMap>,
+  id: ProcessID,
+  parent_id: ProcessID,
+  group: ProcessID,
+  exit_code: u32,
+  removable: bool,
+) -> Option<Arc<RwLock<Process>>> {
+  let parent = {
+    let _order = ordered(LockLevel::TaskMap);
+    task_map.read().get(&parent_id).cloned()
+  };
+  let reap_now = match parent {
+    Some(parent) => {
+      let _order = ordered(LockLevel::Process);
+      let mut parent = parent.write();
+      parent.child_exited(id, group, exit_code)
+    },
+    None => false,
+  };
+  if reap_now && removable {
+    remove_task(task_map, id)
+  } else {
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate std;
+
+  use alloc::collections::BTreeMap;
+  use alloc::sync::Arc;
+  use alloc::vec::Vec;
+  use core::sync::atomic::{AtomicU32, Ordering};
+  use crate::locks::{ordered, LockLevel};
+  use crate::memory::address::PhysicalAddress;
+  use crate::memory::virt::page_table::PageTableReference;
+  use crate::task::id::ProcessID;
+  use crate::task::process::Process;
+  use crate::locks::{Mutex, RwLock};
+  use std::thread;
+  use super::{add_task, fork_process, has_live_child, remove_task, report_exit, TaskMap};
+
+  /// Stand-ins for the frame allocator and reference counts, locked at the
+  /// same levels as the real ones
+  struct Frames {
+    refcount: Mutex<BTreeMap<usize, usize>>,
+    allocator: Mutex<usize>,
+  }
+
+  impl Frames {
+    /// Share the parent's pages with a child, and allocate its page directory
+    fn copy_address_space(&self, pages: usize) -> PageTableReference {
+      for page in 0..pages {
+        let _order = ordered(LockLevel::RefCount);
+        *self.refcount.lock().entry(page).or_insert(0) += 1;
+      }
+      let _order = ordered(LockLevel::Allocator);
+      let mut allocator = self.allocator.lock();
+      *allocator += 1;
+      PageTableReference::new(PhysicalAddress::new(*allocator * 0x1000))
+    }
+
+    fn release(&self, pages: usize) {
+      for page in 0..pages {
+        let _order = ordered(LockLevel::RefCount);
+        let mut refcount = self.refcount.lock();
+        *refcount.get_mut(&page).unwrap() -= 1;
+        // Freeing a frame nests the allocator inside the refcount
+        let _order = ordered(LockLevel::Allocator);
+        let _ = self.allocator.lock();
+      }
+    }
+  }
+
+  const SHARED_PAGES: usize = 4;
+
+  #[test]
+  fn concurrent_forks() {
+    let task_map: Arc<RwLock<TaskMap>> = Arc::new(RwLock::new(BTreeMap::new()));
+    let frames = Arc::new(Frames {
+      refcount: Mutex::new(BTreeMap::new()),
+      allocator: Mutex::new(0),
+    });
+    // IDs no other test uses, since the run queue is shared
+    let next_id = Arc::new(AtomicU32::new(20001));
+    let root_id = ProcessID::new(20000);
+    add_task(&task_map, Process::initial(0).create_fork(root_id, 0));
+
+    let workers: Vec<_> = (0..8).map(|_| {
+      let task_map = task_map.clone();
+      let frames = frames.clone();
+      let next_id = next_id.clone();
+      thread::spawn(move || {
+        let mut children = Vec::new();
+        for round in 0..64 {
+          // Fork from the root and from this worker's own children
+          let parent_id = if round % 2 == 0 || children.is_empty() { root_id } else { children[children.len() - 1] };
+          let parent = {
+            let _order = ordered(LockLevel::TaskMap);
+            task_map.read().get(&parent_id).unwrap().clone()
+          };
+          let child_id = ProcessID::new(next_id.fetch_add(1, Ordering::SeqCst));
+          let child = fork_process(
+            &task_map,
+            &parent,
+            child_id,
+            round,
+            || frames.copy_address_space(SHARED_PAGES),
+            |child| {
+              child.adjust_nice(1);
+            },
+          );
+          assert_eq!(*child.read().get_parent_id(), parent_id);
+          children.push(child_id);
+          if round % 4 == 3 {
+            let id = children.remove(0);
+            remove_task(&task_map, id).unwrap();
+            frames.release(SHARED_PAGES);
+          }
+        }
+        for id in children {
+          remove_task(&task_map, id).unwrap();
+          frames.release(SHARED_PAGES);
+        }
+      })
+    }).collect();
+    for worker in workers {
+      worker.join().unwrap();
+    }
+
+    assert_eq!(task_map.read().len(), 1);
+    remove_task(&task_map, root_id).unwrap();
+    for (_, count) in frames.refcount.lock().iter() {
+      assert_eq!(*count, 0);
+    }
+    assert_eq!(*frames.allocator.lock(), 8 * 64);
+  }
+
+  #[test]
+  fn ignored_sigchld_leaves_no_zombies() {
+    use crate::task::process::WaitTarget;
+    use syscall::result::SystemError;
+    use syscall::signals::CHILD;
+
+    let task_map: RwLock<TaskMap> = RwLock::new(BTreeMap::new());
+    let parent_id = ProcessID::new(20600);
+    let group = ProcessID::new(0);
+    let parent = add_task(&task_map, Process::initial(0).create_fork(parent_id, 0));
+    parent.write().signals.ignore(CHILD).unwrap();
+    let children: Vec<ProcessID> = (20601..20604).map(|raw| {
+      let child = parent.read().create_fork(ProcessID::new(raw), 0);
+      *add_task(&task_map, child).read().get_id()
+    }).collect();
+    let exit = |id: ProcessID| {
+      let child = task_map.read().get(&id).unwrap().clone();
+      child.write().terminate();
+      report_exit(&task_map, id, parent_id, group, 0, true)
+    };
+
+    // The first child is torn down as soon as it exits, and waiting for it
+    // fails instead of blocking
+    assert!(exit(children[0]).is_some());
+    assert!(!task_map.read().contains_key(&children[0]));
+    assert!(!has_live_child(&task_map, parent_id, WaitTarget::Child(children[0])));
+    let wait = parent.write().wait_for_live(WaitTarget::Child(children[0]), 0, false);
+    assert!(matches!(wait, Err(SystemError::NoSuchEntity)));
+
+    // An explicit wait on a running child blocks until it exits, then finds
+    // nothing to report
+    assert!(has_live_child(&task_map, parent_id, WaitTarget::Child(children[1])));
+    parent.write().wait_for_live(WaitTarget::Child(children[1]), 0, true).unwrap();
+    assert!(!parent.read().can_resume());
+    assert!(exit(children[1]).is_some());
+    assert!(parent.read().can_resume());
+    assert_eq!(parent.write().finish_wait(), None);
+    assert!(!has_live_child(&task_map, parent_id, WaitTarget::Child(children[1])));
+
+    // Waiting for any child works until the last one is gone
+    assert!(has_live_child(&task_map, parent_id, WaitTarget::AnyChild));
+    assert!(exit(children[2]).is_some());
+    assert!(!has_live_child(&task_map, parent_id, WaitTarget::AnyChild));
+    let wait = parent.write().wait_for_live(WaitTarget::AnyChild, 0, false);
+    assert!(matches!(wait, Err(SystemError::NoSuchEntity)));
+
+    // Only the parent is left, with no zombies
+    assert_eq!(task_map.read().keys().copied().collect::<Vec<_>>(), [parent_id]);
+    assert_eq!(parent.read().reaped_children(), 3);
+    remove_task(&task_map, parent_id).unwrap();
+  }
+}
@@ -1,40 +1,250 @@
+use alloc::string::String;
+use alloc::vec::Vec;
 use crate::files::cursor::SeekMethod;
 use crate::files::filename;
 use crate::files::handle::{FileHandle, LocalHandle};
+use crate::files::locks::{FileKey, LockError, LockMode, LockOwner, LOCKS};
 use crate::files::path::Path;
+use crate::files::wildcard::WildcardPattern;
 use crate::fs::{DRIVES, drive::DriveID};
+use crate::fs::filesystem::transfer_through_buffer;
 use crate::task::get_current_process;
 use syscall::files::DirEntryInfo;
+use syscall::flags::{
+  FD_CLOEXEC, F_ADD_SEALS, F_GETFD, F_GETFL, F_GET_SEALS, F_SETFD, F_SETFL, LOCK_EX, LOCK_NB, LOCK_SH, LOCK_UN, O_CLOEXEC, O_CREAT, O_EXCL,
+  RENAME_REPLACE,
+};
 use syscall::result::SystemError;
 use super::id::ProcessID;
 use super::files::{FileMap, OpenFile};
 
+/// Split a path into the drive it refers to and its location on that drive.
+/// Paths without a drive use the current drive, and relative paths start from
+/// the process's working directory on their drive.
 pub fn get_drive_id_and_path(path_str: &str) -> Result<(DriveID, Path), SystemError> {
   let (drive, path) = filename::string_to_drive_and_path(path_str);
-  let (drive_id, full_path) = if drive.is_empty() {
-    let proc_lock = get_current_process();
-    let proc = proc_lock.read();
-    let cwd = "";
-    let full_path = Path::resolve(cwd, path);
-    (proc.current_drive, full_path)
+  let drive_id = if drive.is_empty() {
+    get_current_process().read().current_drive
   } else {
-    let drive_id = DRIVES.get_drive_number(drive).ok_or(SystemError::NoSuchDrive)?;
-    let full_path = Path::new(path);
-    (drive_id, full_path)
+    DRIVES.get_drive_number(drive).ok_or(SystemError::NoSuchDrive)?
   };
-
+  if path.starts_with('\\') {
+    return Ok((drive_id, Path::new(path)));
+  }
+  let proc_lock = get_current_process();
+  let proc = proc_lock.read();
+  let full_path = Path::resolve(proc.get_working_directory(drive_id), path);
   Ok((drive_id, full_path))
 }
 
+/// Change the working directory on the drive named by a path. Like DOS, this
+/// doesn't change which drive is current.
+pub fn change_directory(path_str: &str) -> Result<(), SystemError> {
+  let (drive_id, full_path) = get_drive_id_and_path(path_str)?;
+  let (_, instance) = DRIVES.get_drive_instance(&drive_id).ok_or(SystemError::NoSuchFileSystem)?;
+  instance.access(full_path.as_str()).map_err(|_| SystemError::NoSuchEntity)?;
+  let handle = instance.open_dir(full_path.as_str()).map_err(|_| SystemError::NotDirectory)?;
+  let _ = instance.close(handle);
+  get_current_process().write().set_working_directory(drive_id, full_path.as_str());
+  Ok(())
+}
+
+/// Change the working directory to an already-open directory handle. The
+/// directory's location comes from the handle itself, so unlike resolving a
+/// path, nothing can be moved into its place between opening and switching.
+pub fn change_directory_to_handle(handle: FileHandle) -> Result<(), SystemError> {
+  let open_file = {
+    let process_lock = get_current_process();
+    let process = process_lock.read();
+    *process.get_open_file_info(handle).ok_or(SystemError::BadFileDescriptor)?
+  };
+  let (_, instance) = DRIVES.get_drive_instance(&open_file.drive).ok_or(SystemError::NoSuchFileSystem)?;
+  let path = instance.directory_path(open_file.local_handle)
+    .map_err(|_| SystemError::NotDirectory)?
+    .ok_or(SystemError::NoSuchEntity)?;
+  get_current_process().write().set_working_directory(open_file.drive, path.as_str());
+  Ok(())
+}
+
+/// Get the calling process's working directory on a drive, without a
+/// leading separator
+pub fn get_working_directory(drive_id: DriveID) -> Result<String, SystemError> {
+  DRIVES.get_drive_instance(&drive_id).ok_or(SystemError::NoSuchDrive)?;
+  let proc_lock = get_current_process();
+  let proc = proc_lock.read();
+  Ok(String::from(proc.get_working_directory(drive_id)))
+}
+
+/// Anything that changes the contents of a drive is refused if the drive was
+/// mounted read-only
+fn check_writable(drive_id: DriveID) -> Result<(), SystemError> {
+  if DRIVES.is_read_only(&drive_id) {
+    Err(SystemError::ReadOnlyFileSystem)
+  } else {
+    Ok(())
+  }
+}
+
+/// The directory containing a path, or the root for top-level entries
+fn parent_path(path: &Path) -> &str {
+  match path.as_str().rfind('\\') {
+    Some(index) => &path.as_str()[..index],
+    None => "",
+  }
+}
+
+pub fn make_directory(path_str: &str) -> Result<(), SystemError> {
+  let (drive_id, full_path) = get_drive_id_and_path(path_str)?;
+  let (_, instance) = DRIVES.get_drive_instance(&drive_id).ok_or(SystemError::NoSuchFileSystem)?;
+  check_writable(drive_id)?;
+  if full_path.as_str().is_empty() || instance.access(full_path.as_str()).is_ok() {
+    return Err(SystemError::AlreadyExists);
+  }
+  instance.access(parent_path(&full_path)).map_err(|_| SystemError::NoSuchEntity)?;
+  instance.mkdir(full_path.as_str()).map_err(|_| SystemError::IOError)
+}
+
+/// Remove an empty directory. A directory that the calling process is
+/// working in can't be removed.
+pub fn remove_directory(path_str: &str) -> Result<(), SystemError> {
+  let (drive_id, full_path) = get_drive_id_and_path(path_str)?;
+  let (_, instance) = DRIVES.get_drive_instance(&drive_id).ok_or(SystemError::NoSuchFileSystem)?;
+  check_writable(drive_id)?;
+  instance.access(full_path.as_str()).map_err(|_| SystemError::NoSuchEntity)?;
+  if is_working_directory(drive_id, &full_path) {
+    return Err(SystemError::Busy);
+  }
+  let handle = instance.open_dir(full_path.as_str()).map_err(|_| SystemError::NotDirectory)?;
+  let mut info = DirEntryInfo::empty();
+  let mut is_empty = true;
+  while let Ok(has_more) = instance.read_dir(handle, &mut info) {
+    if !is_dot_entry(&info) {
+      is_empty = false;
+      break;
+    }
+    if !has_more {
+      break;
+    }
+  }
+  let _ = instance.close(handle);
+  if !is_empty {
+    return Err(SystemError::NotEmpty);
+  }
+  instance.rmdir(full_path.as_str()).map_err(|_| SystemError::IOError)
+}
+
+/// Check whether the calling process is working in a directory, or somewhere
+/// inside of it
+fn is_working_directory(drive_id: DriveID, path: &Path) -> bool {
+  let proc_lock = get_current_process();
+  let proc = proc_lock.read();
+  let cwd = proc.get_working_directory(drive_id);
+  let path = path.as_str();
+  if cwd.len() < path.len() || !cwd[..path.len()].eq_ignore_ascii_case(path) {
+    return false;
+  }
+  path.is_empty() || cwd.len() == path.len() || cwd.as_bytes()[path.len()] == b'\\'
+}
+
+/// Directories on FAT volumes list "." and ".." entries, which don't count
+/// as contents
+fn is_dot_entry(info: &DirEntryInfo) -> bool {
+  &info.file_name == b".       " || &info.file_name == b"..      "
+}
+
 pub fn open_path<'path>(path_str: &'path str) -> Result<FileHandle, SystemError> {
+  open_path_with_flags(path_str, 0)
+}
+
+pub fn open_path_with_flags<'path>(path_str: &'path str, flags: u32) -> Result<FileHandle, SystemError> {
   let (drive_id, full_path) = get_drive_id_and_path(path_str)?;
 
   let (_, instance) = DRIVES.get_drive_instance(&drive_id).ok_or(SystemError::NoSuchFileSystem)?;
-  let local_handle = instance.open(full_path.as_str()).map_err(|_| SystemError::NoSuchEntity)?;
-  let process_handle = get_current_process().write().open_file(drive_id, local_handle);
+  let local_handle = if flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL {
+    check_writable(drive_id)?;
+    // Leave the existence check to the filesystem, which can make it atomic
+    match instance.create_new(full_path.as_str()) {
+      Ok(Some(handle)) => handle,
+      Ok(None) => return Err(SystemError::AlreadyExists),
+      Err(_) => return Err(SystemError::IOError),
+    }
+  } else {
+    match instance.open(full_path.as_str()) {
+      Ok(handle) => handle,
+      Err(_) if flags & O_CREAT != 0 => {
+        check_writable(drive_id)?;
+        instance.create(full_path.as_str()).map_err(|_| SystemError::IOError)?
+      },
+      Err(_) => return Err(SystemError::NoSuchEntity),
+    }
+  };
+  let process_handle = install_local_handle(drive_id, local_handle)?;
+  // Creation flags only apply to the open call, and aren't stored
+  let status_flags = flags & !(O_CREAT | O_EXCL);
+  if status_flags != 0 {
+    get_current_process().write().set_file_flags(process_handle, status_flags);
+  }
   Ok(process_handle)
 }
 
+/// Give the current process a handle to a file that was just opened in a
+/// drive. If the process has reached its limit on open files, the file is
+/// closed in the drive again.
+pub fn install_local_handle(drive_id: DriveID, local_handle: LocalHandle) -> Result<FileHandle, SystemError> {
+  let installed = get_current_process().write().open_file(drive_id, local_handle);
+  if installed.is_err() {
+    if let Some((_, instance)) = DRIVES.get_drive_instance(&drive_id) {
+      let _ = instance.close(local_handle);
+    }
+  }
+  installed
+}
+
+/// Check whether a path exists and can be accessed as described by `mode`, a
+/// combination of R_OK and W_OK (or F_OK to check existence alone). The file
+/// is not opened.
+pub fn access_path(path_str: &str, mode: u32) -> Result<(), SystemError> {
+  let (drive_id, full_path) = get_drive_id_and_path(path_str)?;
+  let (_, instance) = DRIVES.get_drive_instance(&drive_id).ok_or(SystemError::NoSuchFileSystem)?;
+  let access = instance.access(full_path.as_str()).map_err(|_| SystemError::NoSuchEntity)?;
+  match access.permits(mode) {
+    Ok(true) => Ok(()),
+    Ok(false) => Err(SystemError::PermissionDenied),
+    Err(_) => Err(SystemError::InvalidArgument),
+  }
+}
+
+/// Delete a file. If it is still open, its contents remain available to
+/// existing handles until they are closed.
+pub fn unlink_path(path_str: &str) -> Result<(), SystemError> {
+  let (drive_id, full_path) = get_drive_id_and_path(path_str)?;
+  let (_, instance) = DRIVES.get_drive_instance(&drive_id).ok_or(SystemError::NoSuchFileSystem)?;
+  check_writable(drive_id)?;
+  instance.access(full_path.as_str()).map_err(|_| SystemError::NoSuchEntity)?;
+  instance.unlink(full_path.as_str()).map_err(|_| SystemError::IOError)
+}
+
+/// Move a file or directory to a new path on the same drive. Unless `flags`
+/// contains RENAME_REPLACE, an existing destination is left alone and the
+/// rename fails.
+pub fn rename_path(old_path_str: &str, new_path_str: &str, flags: u32) -> Result<(), SystemError> {
+  let (old_drive, old_path) = get_drive_id_and_path(old_path_str)?;
+  let (new_drive, new_path) = get_drive_id_and_path(new_path_str)?;
+  if old_drive != new_drive {
+    return Err(SystemError::CrossDevice);
+  }
+  let (_, instance) = DRIVES.get_drive_instance(&old_drive).ok_or(SystemError::NoSuchFileSystem)?;
+  check_writable(old_drive)?;
+  instance.access(old_path.as_str()).map_err(|_| SystemError::NoSuchEntity)?;
+  let replace = flags & RENAME_REPLACE != 0;
+  // Changing the case of a name finds the source itself at the destination
+  let same_path = old_path.as_str().eq_ignore_ascii_case(new_path.as_str());
+  if !replace && !same_path && instance.access(new_path.as_str()).is_ok() {
+    return Err(SystemError::AlreadyExists);
+  }
+  instance.rename(old_path.as_str(), new_path.as_str(), replace).map_err(|_| SystemError::IOError)
+}
+
 pub fn read_file(handle: FileHandle, buffer: &mut [u8]) -> Result<usize, SystemError> {
   let open_file_info = {
     let process_lock = get_current_process();
@@ -46,7 +256,28 @@ pub fn read_file(handle: FileHandle, buffer: &mut [u8]) -> Result<usize, SystemE
   };
 
   let (_, instance) = DRIVES.get_drive_instance(&open_file_info.drive).ok_or(SystemError::NoSuchFileSystem)?;
-  instance.read(open_file_info.local_handle, buffer).map_err(|_| SystemError::IOError)
+  if open_file_info.is_nonblocking() {
+    return match instance.read_nonblocking(open_file_info.local_handle, buffer) {
+      Ok(Some(bytes_read)) => Ok(bytes_read),
+      Ok(None) => Err(SystemError::WouldBlock),
+      Err(_) => Err(SystemError::IOError),
+    };
+  }
+  instance.read(open_file_info.local_handle, buffer).map_err(|_| {
+    // A blocking read may have been woken because the handle was closed while
+    // it waited. If the descriptor no longer refers to the same file, report
+    // it as a bad descriptor rather than an IO failure. A read that gave up
+    // because a signal arrived is reported as interrupted.
+    let process_lock = get_current_process();
+    let process = process_lock.read();
+    if process.signals.has_pending() {
+      return SystemError::Interrupted;
+    }
+    match process.get_open_file_info(handle) {
+      Some(info) if info.drive == open_file_info.drive && info.local_handle == open_file_info.local_handle => SystemError::IOError,
+      _ => SystemError::BadFileDescriptor,
+    }
+  })
 }
 
 pub fn write_file(handle: FileHandle, buffer: &[u8]) -> Result<usize, SystemError> {
@@ -60,10 +291,115 @@ pub fn write_file(handle: FileHandle, buffer: &[u8]) -> Result<usize, SystemErro
   };
 
   let (_, instance) = DRIVES.get_drive_instance(&open_file_info.drive).ok_or(SystemError::NoSuchFileSystem)?;
-  instance.write(open_file_info.local_handle, buffer).map_err(|_| SystemError::IOError)
+  check_writable(open_file_info.drive)?;
+  if open_file_info.is_nonblocking() {
+    return match instance.write_nonblocking(open_file_info.local_handle, buffer) {
+      Ok(Some(written)) => bytes_written(written, buffer.len()),
+      Ok(None) => Err(SystemError::WouldBlock),
+      Err(_) => Err(SystemError::IOError),
+    };
+  }
+  let written = instance.write(open_file_info.local_handle, buffer).map_err(|_| SystemError::IOError)?;
+  bytes_written(written, buffer.len())
+}
+
+/// Move up to `count` bytes from one open file to another, without copying
+/// them through the calling process. Files on the same drive are copied by
+/// the filesystem itself when it supports that; anything else passes through
+/// a kernel buffer. Returns the number of bytes moved, which is less than
+/// `count` if the input ran out first.
+pub fn send_file(out_handle: FileHandle, in_handle: FileHandle, count: usize) -> Result<usize, SystemError> {
+  let (source, dest) = {
+    let process_lock = get_current_process();
+    let process = process_lock.read();
+    let source = *process.get_open_file_info(in_handle).ok_or(SystemError::BadFileDescriptor)?;
+    let dest = *process.get_open_file_info(out_handle).ok_or(SystemError::BadFileDescriptor)?;
+    (source, dest)
+  };
+  let (_, source_fs) = DRIVES.get_drive_instance(&source.drive).ok_or(SystemError::NoSuchFileSystem)?;
+  let (_, dest_fs) = DRIVES.get_drive_instance(&dest.drive).ok_or(SystemError::NoSuchFileSystem)?;
+  check_writable(dest.drive)?;
+  if source.drive == dest.drive {
+    let copied = source_fs
+      .copy_file_range(source.local_handle, dest.local_handle, count)
+      .map_err(|_| SystemError::IOError)?;
+    if let Some(copied) = copied {
+      return Ok(copied);
+    }
+  }
+  transfer_through_buffer(&**source_fs, source.local_handle, &**dest_fs, dest.local_handle, count)
+    .map_err(|_| SystemError::IOError)
+}
+
+/// Filesystems and devices report that they are out of space by accepting
+/// none of a non-empty write
+pub fn bytes_written(written: usize, requested: usize) -> Result<usize, SystemError> {
+  if written == 0 && requested > 0 {
+    Err(SystemError::NoSpace)
+  } else {
+    Ok(written)
+  }
 }
 
 pub fn close_file(handle: FileHandle) -> Result<(), SystemError> {
+  let (open_file_info, still_referenced) = {
+    let process_lock = get_current_process();
+    let mut process = process_lock.write();
+    let info = process
+      .close_file(handle)
+      .ok_or(SystemError::BadFileDescriptor)?;
+    let still_referenced = process.references_local_handle(info.drive, info.local_handle);
+    (info, still_referenced)
+  };
+  if still_referenced {
+    // Another descriptor duplicated from this one keeps the file open
+    return Ok(());
+  }
+
+  LOCKS.release_handle(open_file_info.drive, open_file_info.local_handle);
+  let (_, instance) = DRIVES.get_drive_instance(&open_file_info.drive).ok_or(SystemError::NoSuchFileSystem)?;
+  instance.close(open_file_info.local_handle).map_err(|_| SystemError::IOError)
+}
+
+/// Get or set the status or descriptor flags of an open file handle
+pub fn fcntl(handle: FileHandle, command: u32, arg: u32) -> Result<u32, SystemError> {
+  if command == F_ADD_SEALS || command == F_GET_SEALS {
+    return file_seals(handle, command, arg);
+  }
+  let process_lock = get_current_process();
+  let mut process = process_lock.write();
+  match command {
+    F_GETFD => {
+      let info = process
+        .get_open_file_info(handle)
+        .ok_or(SystemError::BadFileDescriptor)?;
+      Ok(info.descriptor_flags)
+    },
+    F_SETFD => {
+      process
+        .set_descriptor_flags(handle, arg)
+        .ok_or(SystemError::BadFileDescriptor)?;
+      Ok(0)
+    },
+    F_GETFL => {
+      let info = process
+        .get_open_file_info(handle)
+        .ok_or(SystemError::BadFileDescriptor)?;
+      Ok(info.flags)
+    },
+    F_SETFL => {
+      process
+        .set_file_flags(handle, arg)
+        .ok_or(SystemError::BadFileDescriptor)?;
+      Ok(0)
+    },
+    _ => Err(SystemError::UnsupportedCommand),
+  }
+}
+
+/// Add to or read the seals on an open file. Seals belong to the file, not
+/// the process, so they aren't kept in the process's open file info.
+fn file_seals(handle: FileHandle, command: u32, arg: u32) -> Result<u32, SystemError> {
   let open_file_info = {
     let process_lock = get_current_process();
     let process = process_lock.read();
@@ -72,16 +408,98 @@ pub fn close_file(handle: FileHandle) -> Result<(), SystemError> {
       .ok_or(SystemError::BadFileDescriptor)?;
     *info
   };
-  
+
   let (_, instance) = DRIVES.get_drive_instance(&open_file_info.drive).ok_or(SystemError::NoSuchFileSystem)?;
-  instance.close(open_file_info.local_handle).map_err(|_| SystemError::IOError)
+  let seals = if command == F_ADD_SEALS {
+    check_writable(open_file_info.drive)?;
+    instance.add_seals(open_file_info.local_handle, arg)
+  } else {
+    instance.get_seals(open_file_info.local_handle)
+  };
+  seals.map_err(|_| SystemError::InvalidArgument)
+}
+
+/// Truncate or extend an open file. Files that are sealed against the change
+/// can't be resized.
+pub fn set_file_size(handle: FileHandle, size: usize) -> Result<(), SystemError> {
+  let open_file_info = {
+    let process_lock = get_current_process();
+    let process = process_lock.read();
+    let info = process
+      .get_open_file_info(handle)
+      .ok_or(SystemError::BadFileDescriptor)?;
+    *info
+  };
+
+  let (_, instance) = DRIVES.get_drive_instance(&open_file_info.drive).ok_or(SystemError::NoSuchFileSystem)?;
+  check_writable(open_file_info.drive)?;
+  instance.set_size(open_file_info.local_handle, size).map_err(|_| SystemError::IOError)
+}
+
+/// Copy the contents of an open file that has been sealed against writing
+pub fn get_sealed_contents(handle: FileHandle) -> Result<Vec<u8>, SystemError> {
+  let open_file_info = {
+    let process_lock = get_current_process();
+    let process = process_lock.read();
+    let info = process
+      .get_open_file_info(handle)
+      .ok_or(SystemError::BadFileDescriptor)?;
+    *info
+  };
+
+  let (_, instance) = DRIVES.get_drive_instance(&open_file_info.drive).ok_or(SystemError::NoSuchFileSystem)?;
+  instance.sealed_contents(open_file_info.local_handle).map_err(|_| SystemError::PermissionDenied)
 }
 
 pub fn dup(from_handle: FileHandle, to_handle: Option<FileHandle>) -> Result<FileHandle, SystemError> {
-  let process_lock = get_current_process();
-  let mut process = process_lock.write();
-  let (_, new_handle) = process.duplicate_file_descriptor(from_handle, to_handle);
-  new_handle.ok_or(SystemError::BadFileDescriptor)
+  match to_handle {
+    // Like dup2, duplicating a handle onto itself leaves it as it is
+    Some(to) if to == from_handle => {
+      let process_lock = get_current_process();
+      let process = process_lock.read();
+      process.get_open_file_info(from_handle).ok_or(SystemError::BadFileDescriptor)?;
+      Ok(from_handle)
+    },
+    Some(to) => duplicate_onto(from_handle, to, 0),
+    None => {
+      let process_lock = get_current_process();
+      let mut process = process_lock.write();
+      process.check_open_file_limit()?;
+      let (_, new_handle) = process.duplicate_file_descriptor(from_handle, None);
+      new_handle.ok_or(SystemError::BadFileDescriptor)
+    },
+  }
+}
+
+/// Determine the descriptor flags for the new handle created by dup3. The two
+/// handles must differ, and O_CLOEXEC is the only supported flag.
+fn dup3_descriptor_flags(from_handle: FileHandle, to_handle: FileHandle, flags: u32) -> Result<u32, SystemError> {
+  if from_handle == to_handle || flags & !O_CLOEXEC != 0 {
+    return Err(SystemError::InvalidArgument);
+  }
+  Ok(if flags & O_CLOEXEC != 0 { FD_CLOEXEC } else { 0 })
+}
+
+/// Duplicate a handle onto a specific handle number. Unlike dup2, it is an
+/// error for both to be the same handle. If O_CLOEXEC is set in `flags`, the
+/// new handle is closed when the process execs.
+pub fn dup3(from_handle: FileHandle, to_handle: FileHandle, flags: u32) -> Result<FileHandle, SystemError> {
+  let descriptor_flags = dup3_descriptor_flags(from_handle, to_handle, flags)?;
+  duplicate_onto(from_handle, to_handle, descriptor_flags)
+}
+
+/// Replace `to_handle` with a copy of `from_handle`. If `to_handle` was open,
+/// and was the last handle to its file, the file is closed.
+fn duplicate_onto(from_handle: FileHandle, to_handle: FileHandle, descriptor_flags: u32) -> Result<FileHandle, SystemError> {
+  let replaced = {
+    let process_lock = get_current_process();
+    let mut process = process_lock.write();
+    process.duplicate_onto(from_handle, to_handle, descriptor_flags)?
+  };
+  if let Some(file) = replaced {
+    close_open_files(alloc::vec![file]);
+  }
+  Ok(to_handle)
 }
 
 pub fn seek(handle: FileHandle, cursor: SeekMethod) -> Result<usize, SystemError> {
@@ -98,15 +516,131 @@ pub fn seek(handle: FileHandle, cursor: SeekMethod) -> Result<usize, SystemError
   instance.seek(open_file_info.local_handle, cursor).map_err(|_| SystemError::IOError)
 }
 
+pub fn ioctl(handle: FileHandle, command: u32, arg: u32) -> Result<u32, SystemError> {
+  let open_file_info = {
+    let process_lock = get_current_process();
+    let process = process_lock.read();
+    let info = process
+      .get_open_file_info(handle)
+      .ok_or(SystemError::BadFileDescriptor)?;
+    *info
+  };
+
+  let (_, instance) = DRIVES.get_drive_instance(&open_file_info.drive).ok_or(SystemError::NoSuchFileSystem)?;
+  instance.ioctl(open_file_info.local_handle, command, arg).map_err(|_| SystemError::UnsupportedCommand)
+}
+
+/// Determine whether an open file handle is connected to a terminal
+pub fn is_terminal(handle: FileHandle) -> Result<bool, SystemError> {
+  let open_file_info = {
+    let process_lock = get_current_process();
+    let process = process_lock.read();
+    let info = process
+      .get_open_file_info(handle)
+      .ok_or(SystemError::BadFileDescriptor)?;
+    *info
+  };
+
+  let (_, instance) = DRIVES.get_drive_instance(&open_file_info.drive).ok_or(SystemError::NoSuchFileSystem)?;
+  instance.is_terminal(open_file_info.local_handle).map_err(|_| SystemError::BadFileDescriptor)
+}
+
+/// Place, convert, or remove an advisory lock on an open file. Blocking
+/// requests wait until the lock is free, unless waiting would deadlock.
+pub fn flock(handle: FileHandle, operation: u32) -> Result<(), SystemError> {
+  let (process_id, open_file_info) = {
+    let process_lock = get_current_process();
+    let process = process_lock.read();
+    let info = process
+      .get_open_file_info(handle)
+      .ok_or(SystemError::BadFileDescriptor)?;
+    (*process.get_id(), *info)
+  };
+
+  let (_, instance) = DRIVES.get_drive_instance(&open_file_info.drive).ok_or(SystemError::NoSuchFileSystem)?;
+  let file = FileKey {
+    drive: open_file_info.drive,
+    file: instance.file_identity(open_file_info.local_handle).map_err(|_| SystemError::UnsupportedCommand)?,
+  };
+  let mode = match operation & (LOCK_SH | LOCK_EX | LOCK_UN) {
+    LOCK_SH => LockMode::Shared,
+    LOCK_EX => LockMode::Exclusive,
+    LOCK_UN => {
+      LOCKS.unlock(file, open_file_info.drive, open_file_info.local_handle);
+      return Ok(());
+    },
+    _ => return Err(SystemError::InvalidArgument),
+  };
+  let owner = LockOwner {
+    drive: open_file_info.drive,
+    handle: open_file_info.local_handle,
+    process: process_id,
+  };
+  let result = if operation & LOCK_NB != 0 {
+    LOCKS.try_lock(file, owner, mode)
+  } else {
+    LOCKS.lock(file, owner, mode, || crate::task::yield_coop())
+  };
+  result.map_err(|err| match err {
+    LockError::WouldBlock => SystemError::WouldBlock,
+    LockError::Deadlock => SystemError::Deadlock,
+  })
+}
+
+/// Readiness of an open file handle, as reported by its filesystem
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Readiness {
+  pub readable: bool,
+  pub writable: bool,
+}
+
+/// Check whether reads and writes on a handle would complete without
+/// blocking. This never blocks itself, so it can be called on each handle in
+/// a set to find out which of them are ready.
+pub fn poll_handle(handle: FileHandle) -> Result<Readiness, SystemError> {
+  let open_file_info = {
+    let process_lock = get_current_process();
+    let process = process_lock.read();
+    let info = process
+      .get_open_file_info(handle)
+      .ok_or(SystemError::BadFileDescriptor)?;
+    *info
+  };
+
+  let (_, instance) = DRIVES.get_drive_instance(&open_file_info.drive).ok_or(SystemError::NoSuchFileSystem)?;
+  let local_handle = open_file_info.local_handle;
+  Ok(Readiness {
+    readable: instance.poll_readable(local_handle).map_err(|_| SystemError::BadFileDescriptor)?,
+    writable: instance.poll_writable(local_handle).map_err(|_| SystemError::BadFileDescriptor)?,
+  })
+}
+
+/// Close open files that no longer belong to any process
+pub fn close_open_files(files: Vec<OpenFile>) {
+  for file in files {
+    LOCKS.release_handle(file.drive, file.local_handle);
+    if let Some((_, instance)) = DRIVES.get_drive_instance(&file.drive) {
+      let _ = instance.close(file.local_handle);
+    }
+  }
+}
+
+/// Duplicate a parent's open files into a forked child. Each copy shares the
+/// parent's cursor, as it would on Unix.
 pub fn reopen_files(id: ProcessID, files: &mut FileMap) {
   files.map_in_place(|open_file| {
     match DRIVES.get_drive_instance(&open_file.drive) {
-      Some((_, instance)) => match instance.reopen(open_file.local_handle, id) {
+      Some((_, instance)) => match instance.reopen_shared(open_file.local_handle, id) {
         Ok(local_handle) => {
+          // Locks belong to the open file, so the child shares them
+          let copy = LockOwner { drive: open_file.drive, handle: local_handle, process: id };
+          LOCKS.share_with(open_file.drive, open_file.local_handle, copy);
           Some(
             OpenFile {
               drive: open_file.drive,
               local_handle,
+              flags: open_file.flags,
+              descriptor_flags: open_file.descriptor_flags,
             }
           )
         },
@@ -128,8 +662,7 @@ pub fn open_directory<'path>(path_str: &'path str) -> Result<FileHandle, SystemE
 
   let (_, instance) = DRIVES.get_drive_instance(&drive_id).ok_or(SystemError::NoSuchFileSystem)?;
   let local_handle = instance.open_dir(full_path.as_str()).map_err(|_| SystemError::NoSuchEntity)?;
-  let process_handle = get_current_process().write().open_file(drive_id, local_handle);
-  Ok(process_handle)
+  install_local_handle(drive_id, local_handle)
 }
 
 pub fn read_directory(handle: FileHandle, entry_info: &mut DirEntryInfo) -> Result<bool, SystemError> {
@@ -145,3 +678,117 @@ pub fn read_directory(handle: FileHandle, entry_info: &mut DirEntryInfo) -> Resu
   let (_, instance) = DRIVES.get_drive_instance(&open_file_info.drive).ok_or(SystemError::NoSuchFileSystem)?;
   instance.read_dir(open_file_info.local_handle, entry_info).map_err(|_| SystemError::IOError)
 }
+
+/// Read entries from an open directory until one matches a DOS wildcard
+/// pattern and is accepted by the filter. Like read_directory, resolves with
+/// whether the directory has more entries after the one that was returned.
+/// If the rest of the directory has no match, `entry_info` is left empty.
+pub fn find_matching_entry<F>(
+  handle: FileHandle,
+  pattern: &WildcardPattern,
+  accept: F,
+  entry_info: &mut DirEntryInfo,
+) -> Result<bool, SystemError>
+  where F: Fn(&DirEntryInfo) -> bool {
+  *entry_info = DirEntryInfo::empty();
+  loop {
+    let mut candidate = DirEntryInfo::empty();
+    let has_more = read_directory(handle, &mut candidate)?;
+    if pattern.matches(&candidate.file_name, &candidate.file_ext) && accept(&candidate) {
+      *entry_info = candidate;
+      return Ok(has_more);
+    }
+    if !has_more {
+      return Ok(false);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::boxed::Box;
+  use alloc::sync::Arc;
+  use crate::fs::DRIVES;
+  use crate::fs::drive::MountOptions;
+  use crate::fs::drivers::testfs::TestFileSystem;
+  use crate::fs::filesystem::{FileSystemCategory, FileSystemType};
+  use syscall::flags::{F_OK, R_OK, RENAME_REPLACE, W_OK};
+  use syscall::result::SystemError;
+  use super::{
+    access_path, dup3_descriptor_flags, make_directory, open_path_with_flags, remove_directory, rename_path, unlink_path, FileHandle,
+  };
+
+  /// Contains a writable README.TXT and a read-only SYSTEM.DAT
+  fn access_fs() -> Arc<Box<FileSystemType>> {
+    let fs = TestFileSystem::new();
+    fs.add_file("README.TXT", b"");
+    fs.add_read_only_file("SYSTEM.DAT", b"");
+    fs.mountable()
+  }
+
+  #[test]
+  fn access_modes() {
+    DRIVES.mount_drive("ACCESS", FileSystemCategory::KernelSync, access_fs());
+
+    assert!(access_path("ACCESS:\\README.TXT", F_OK).is_ok());
+    assert!(access_path("ACCESS:\\README.TXT", R_OK | W_OK).is_ok());
+    assert!(access_path("ACCESS:\\SYSTEM.DAT", R_OK).is_ok());
+    assert!(matches!(access_path("ACCESS:\\SYSTEM.DAT", W_OK), Err(SystemError::PermissionDenied)));
+    assert!(matches!(access_path("ACCESS:\\MISSING.TXT", F_OK), Err(SystemError::NoSuchEntity)));
+    assert!(matches!(access_path("ACCESS:\\README.TXT", 0x100), Err(SystemError::InvalidArgument)));
+    assert!(matches!(access_path("NODRIVE:\\README.TXT", F_OK), Err(SystemError::NoSuchDrive)));
+  }
+
+  #[test]
+  fn rename_checks() {
+    DRIVES.mount_drive("MOVEA", FileSystemCategory::KernelSync, access_fs());
+    DRIVES.mount_drive("MOVEB", FileSystemCategory::KernelSync, access_fs());
+
+    assert!(rename_path("MOVEA:\\README.TXT", "MOVEA:\\NOTES.TXT", 0).is_ok());
+    assert!(matches!(access_path("MOVEA:\\README.TXT", F_OK), Err(SystemError::NoSuchEntity)));
+    assert!(rename_path("MOVEA:\\NOTES.TXT", "MOVEA:\\notes.txt", 0).is_ok());
+    assert!(matches!(rename_path("MOVEA:\\NOTES.TXT", "MOVEB:\\NOTES.TXT", 0), Err(SystemError::CrossDevice)));
+    assert!(matches!(rename_path("MOVEA:\\NOTES.TXT", "MOVEA:\\SYSTEM.DAT", 0), Err(SystemError::AlreadyExists)));
+    assert!(rename_path("MOVEA:\\NOTES.TXT", "MOVEA:\\SYSTEM.DAT", RENAME_REPLACE).is_ok());
+    assert!(matches!(rename_path("MOVEA:\\MISSING.TXT", "MOVEA:\\NOTES.TXT", 0), Err(SystemError::NoSuchEntity)));
+  }
+
+  #[test]
+  fn dup3_flags() {
+    use crate::files::handle::Handle;
+    use syscall::flags::{FD_CLOEXEC, O_CLOEXEC};
+
+    let first = FileHandle::new(3);
+    let second = FileHandle::new(4);
+    assert!(matches!(dup3_descriptor_flags(first, first, 0), Err(SystemError::InvalidArgument)));
+    assert!(matches!(dup3_descriptor_flags(first, first, O_CLOEXEC), Err(SystemError::InvalidArgument)));
+    assert!(matches!(dup3_descriptor_flags(first, second, 0), Ok(0)));
+    assert!(matches!(dup3_descriptor_flags(first, second, O_CLOEXEC), Ok(FD_CLOEXEC)));
+    assert!(matches!(dup3_descriptor_flags(first, second, 0x40), Err(SystemError::InvalidArgument)));
+  }
+
+  #[test]
+  fn unlink_checks() {
+    DRIVES.mount_drive("DELETE", FileSystemCategory::KernelSync, access_fs());
+
+    assert!(unlink_path("DELETE:\\README.TXT").is_ok());
+    assert!(matches!(unlink_path("DELETE:\\MISSING.TXT"), Err(SystemError::NoSuchEntity)));
+    assert!(matches!(unlink_path("DELETE:\\SYSTEM.DAT"), Err(SystemError::IOError)));
+    assert!(matches!(unlink_path("NODRIVE:\\README.TXT"), Err(SystemError::NoSuchDrive)));
+  }
+
+  #[test]
+  fn read_only_drive() {
+    use syscall::flags::{O_CREAT, O_EXCL};
+
+    DRIVES.mount_drive_with_options("LOCKED", FileSystemCategory::KernelSync, access_fs(), MountOptions::read_only());
+
+    // Reading is still allowed, but nothing on the drive can change
+    assert!(access_path("LOCKED:\\README.TXT", R_OK).is_ok());
+    assert!(matches!(unlink_path("LOCKED:\\README.TXT"), Err(SystemError::ReadOnlyFileSystem)));
+    assert!(matches!(rename_path("LOCKED:\\README.TXT", "LOCKED:\\NOTES.TXT", 0), Err(SystemError::ReadOnlyFileSystem)));
+    assert!(matches!(make_directory("LOCKED:\\NEWDIR"), Err(SystemError::ReadOnlyFileSystem)));
+    assert!(matches!(remove_directory("LOCKED:\\OLDDIR"), Err(SystemError::ReadOnlyFileSystem)));
+    assert!(matches!(open_path_with_flags("LOCKED:\\NEW.TXT", O_CREAT | O_EXCL), Err(SystemError::ReadOnlyFileSystem)));
+  }
+}
@@ -0,0 +1,84 @@
+//! Userspace drivers talk to their hardware through I/O ports, but they can't
+//! run IN and OUT themselves, since user code runs at IOPL 0. Instead, they
+//! ask the kernel to perform each access. A driver is granted the ports it
+//! needs when it registers, and every access is checked against its grants.
+//! Grants are kept as a bitmap with one bit per port. Most processes never
+//! touch hardware, so the bitmap is only allocated once something is granted.
+
+use alloc::boxed::Box;
+
+/// The x86 I/O address space is 64KiB
+pub const PORT_COUNT: usize = 0x10000;
+
+const BITMAP_WORDS: usize = PORT_COUNT / 32;
+
+pub struct IOPortPermissions {
+  bitmap: Option<Box<[u32; BITMAP_WORDS]>>,
+}
+
+impl IOPortPermissions {
+  pub const fn new() -> IOPortPermissions {
+    IOPortPermissions {
+      bitmap: None,
+    }
+  }
+
+  /// Allow access to `count` ports, starting at `start`
+  pub fn grant(&mut self, start: usize, count: usize) -> Result<(), ()> {
+    let end = start.checked_add(count).ok_or(())?;
+    if count == 0 || end > PORT_COUNT {
+      return Err(());
+    }
+    let bitmap = self.bitmap.get_or_insert_with(|| Box::new([0; BITMAP_WORDS]));
+    for port in start..end {
+      bitmap[port / 32] |= 1 << (port % 32);
+    }
+    Ok(())
+  }
+
+  /// Drop every grant
+  pub fn revoke_all(&mut self) {
+    self.bitmap = None;
+  }
+
+  /// Determine whether an access of `width` bytes at `port` is allowed. Wide
+  /// accesses touch several consecutive ports, and each one must be granted.
+  pub fn allows(&self, port: usize, width: usize) -> bool {
+    let bitmap = match &self.bitmap {
+      Some(bitmap) => bitmap,
+      None => return false,
+    };
+    if width == 0 || port + width > PORT_COUNT {
+      return false;
+    }
+    (port..(port + width)).all(|p| bitmap[p / 32] & (1 << (p % 32)) != 0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{IOPortPermissions, PORT_COUNT};
+
+  #[test]
+  fn granted_ranges() {
+    let mut ports = IOPortPermissions::new();
+    assert!(!ports.allows(0x60, 1));
+    ports.grant(0x3f8, 8).unwrap();
+    assert!(ports.allows(0x3f8, 1));
+    assert!(ports.allows(0x3ff, 1));
+    assert!(!ports.allows(0x3f7, 1));
+    assert!(!ports.allows(0x400, 1));
+    // Every byte of a wide access needs to be granted
+    assert!(ports.allows(0x3fc, 4));
+    assert!(!ports.allows(0x3fe, 4));
+
+    assert!(ports.grant(0xfffe, 4).is_err());
+    assert!(ports.grant(0x100, 0).is_err());
+    ports.grant(PORT_COUNT - 1, 1).unwrap();
+    assert!(ports.allows(0xffff, 1));
+    assert!(!ports.allows(0xffff, 2));
+
+    ports.revoke_all();
+    assert!(!ports.allows(0x3f8, 1));
+  }
+}
@@ -1,18 +1,97 @@
 use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use crate::fs::filesystem::KernelFileSystem;
+use crate::memory::address::PhysicalAddress;
+use crate::memory::virt::page_entry::PageTableEntry;
+use super::files::OpenFile;
 use super::id::ProcessID;
 
 /// IPC is implemented by passing a simple tuple of u32 values from one process
 /// to another.
+/// A message can also carry an open file handle. The kernel duplicates the
+/// sender's handle when the message is sent, so the message holds its own
+/// reference to the underlying file, and the sender is free to close its copy
+/// right away. When the message is read, the handle is installed in the
+/// receiver's open files, and the last value of the message is replaced with
+/// the receiver's handle number.
+/// For bulk data, a message can instead carry a range of pages. Rather than
+/// copying bytes through the queue, the kernel hands the physical frames to
+/// the receiver. When the message is read, the frames are mapped into a new
+/// region of the receiver's memory, and the third value of the message is
+/// replaced with the address of that region.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct IPCMessage(pub u32, pub u32, pub u32, pub u32);
 
-/// A packet associates an IPC message with its sender.
+/// A packet associates an IPC message with its sender, and any file handle
+/// or pages sent along with it.
 #[derive(Debug, Eq, PartialEq)]
 pub struct IPCPacket {
   pub from: ProcessID,
   pub message: IPCMessage,
+  pub handle: Option<OpenFile>,
+  /// Physical frames handed off with the message. The packet owns one
+  /// reference to each frame, which passes to the receiver's page table when
+  /// the message is read.
+  pub pages: Vec<PhysicalAddress>,
 }
 
+impl IPCPacket {
+  /// Build a packet carrying a copy of one of the sender's open files. The
+  /// file is reopened on behalf of the recipient, so that the packet holds its
+  /// own reference to it.
+  pub fn with_file(from: ProcessID, to: ProcessID, message: IPCMessage, file: OpenFile, fs: &dyn KernelFileSystem) -> Result<IPCPacket, ()> {
+    let local_handle = fs.reopen(file.local_handle, to)?;
+    Ok(IPCPacket {
+      from,
+      message,
+      handle: Some(OpenFile {
+        local_handle,
+        ..file
+      }),
+      pages: Vec::new(),
+    })
+  }
+}
+
+/// Determines what happens to the sender's view of pages sent over IPC
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PageTransferMode {
+  /// The pages are removed from the sender's address space, and the receiver
+  /// becomes their only owner. No frames are copied or newly referenced.
+  Move,
+  /// The sender and receiver both map the same frames, and see each other's
+  /// writes. Each frame gains a reference for the receiver, and is only freed
+  /// once both processes have unmapped it.
+  Share,
+}
+
+impl PageTransferMode {
+  pub fn from_flags(flags: u32) -> PageTransferMode {
+    if flags & syscall::flags::IPC_PAGES_MOVE != 0 {
+      PageTransferMode::Move
+    } else {
+      PageTransferMode::Share
+    }
+  }
+
+  /// Take a reference to the frame behind one of the sender's present page
+  /// table entries, for a message to own. Moving clears the entry, so the
+  /// sender's own reference passes to the message. Sharing leaves the entry
+  /// in place, and calls `reference_frame` to add a new reference.
+  pub fn take_frame<F>(&self, entry: &mut PageTableEntry, reference_frame: F) -> PhysicalAddress
+    where F: FnOnce(PhysicalAddress) {
+    let address = entry.get_address();
+    match self {
+      PageTransferMode::Move => entry.zero(),
+      PageTransferMode::Share => reference_frame(address),
+    }
+    address
+  }
+}
+
+/// The most pages a single message can carry
+pub const MAX_TRANSFER_PAGES: usize = 1024;
+
 /// For storing IPC messages in a process's receiving queue, each message is
 /// associated with an expiration time. The time is recorded in system ticks,
 /// and indicates the time after which this entry is no longer valid.
@@ -28,13 +107,29 @@ pub struct EnqueuedIPC {
 /// the process.
 pub struct IPCQueue {
   queue: VecDeque<EnqueuedIPC>,
+  /// Handles attached to messages that expired before they were read. They
+  /// still hold a reference to a file, and need to be closed by the kernel.
+  orphaned_handles: Vec<OpenFile>,
+  /// Frames carried by messages that expired before they were read. Each one
+  /// still holds a reference that needs to be released.
+  orphaned_pages: Vec<PhysicalAddress>,
 }
 
 impl IPCQueue {
   pub fn new() -> Self {
     Self {
       queue: VecDeque::new(),
+      orphaned_handles: Vec::new(),
+      orphaned_pages: Vec::new(),
+    }
+  }
+
+  /// Hold onto the resources of a packet that will never be read
+  fn orphan(&mut self, packet: IPCPacket) {
+    if let Some(handle) = packet.handle {
+      self.orphaned_handles.push(handle);
     }
+    self.orphaned_pages.extend(packet.pages);
   }
 
   fn remove_expired_entries(&mut self, current_ticks: u32) {
@@ -42,23 +137,60 @@ impl IPCQueue {
       if entry.expiration_ticks > current_ticks {
         return;
       }
-      self.queue.pop_front();
+      if let Some(expired) = self.queue.pop_front() {
+        self.orphan(expired.packet);
+      }
     }
   }
 
   /// Add a message from another process.
   pub fn add(&mut self, from: ProcessID, message: IPCMessage, current_ticks: u32, expiration_ticks: u32) {
+    self.add_packet(IPCPacket { from, message, handle: None, pages: Vec::new() }, current_ticks, expiration_ticks);
+  }
+
+  /// Add a packet from another process, which may carry a file handle or
+  /// pages
+  pub fn add_packet(&mut self, packet: IPCPacket, current_ticks: u32, expiration_ticks: u32) {
     self.remove_expired_entries(current_ticks);
     let for_queue = EnqueuedIPC {
-      packet: IPCPacket {
-        from,
-        message,
-      },
+      packet,
       expiration_ticks,
     };
     self.queue.push_back(for_queue);
   }
 
+  /// Collect handles from expired messages, so that they can be closed
+  pub fn take_orphaned_handles(&mut self) -> Vec<OpenFile> {
+    core::mem::replace(&mut self.orphaned_handles, Vec::new())
+  }
+
+  /// Collect frames from expired messages, so that their references can be
+  /// released
+  pub fn take_orphaned_pages(&mut self) -> Vec<PhysicalAddress> {
+    core::mem::replace(&mut self.orphaned_pages, Vec::new())
+  }
+
+  /// Discard every message in the queue, returning any handles they carried.
+  /// Their pages are left with the other orphaned pages.
+  /// This is used when the receiving process goes away.
+  pub fn drain_handles(&mut self) -> Vec<OpenFile> {
+    self.discard_all();
+    self.take_orphaned_handles()
+  }
+
+  /// Orphan every message still in the queue
+  pub fn discard_all(&mut self) {
+    while let Some(entry) = self.queue.pop_front() {
+      self.orphan(entry.packet);
+    }
+  }
+
+  /// Hold onto frames from a message that was read, but couldn't be mapped
+  /// into the receiver's memory
+  pub fn orphan_pages(&mut self, pages: Vec<PhysicalAddress>) {
+    self.orphaned_pages.extend(pages);
+  }
+
   /// Attempt to read a packet from the message queue. The first parameter of
   /// the return value is an option that may contain a packet if one exists. The
   /// second parameter is a boolean reflecting whether there are more packets
@@ -73,7 +205,12 @@ impl IPCQueue {
 
 #[cfg(test)]
 mod tests {
-  use super::{IPCMessage, IPCPacket, IPCQueue, ProcessID};
+  use crate::files::handle::{Handle, LocalHandle};
+  use crate::fs::drive::DriveID;
+  use crate::memory::address::PhysicalAddress;
+  use alloc::vec;
+  use alloc::vec::Vec;
+  use super::{IPCMessage, IPCPacket, IPCQueue, OpenFile, ProcessID};
 
   #[test]
   fn add_and_read() {
@@ -100,6 +237,8 @@ mod tests {
       assert_eq!(front.unwrap(), IPCPacket {
         from: ProcessID::new(10),
         message: IPCMessage(1, 2, 3, 4),
+        handle: None,
+        pages: Vec::new(),
       });
       assert!(remaining);
     }
@@ -108,6 +247,8 @@ mod tests {
       assert_eq!(front.unwrap(), IPCPacket {
         from: ProcessID::new(14),
         message: IPCMessage(5, 6, 7, 8),
+        handle: None,
+        pages: Vec::new(),
       });
       assert!(!remaining);
     }
@@ -133,8 +274,63 @@ mod tests {
       assert_eq!(front.unwrap(), IPCPacket {
         from: ProcessID::new(12),
         message: IPCMessage(5, 6, 7, 8),
+        handle: None,
+        pages: Vec::new(),
       });
       assert!(!remaining);
     }
   }
+
+  #[test]
+  fn expired_handles_are_orphaned() {
+    let mut queue = IPCQueue::new();
+    let file = OpenFile {
+      drive: DriveID::new(3),
+      local_handle: LocalHandle::new(7),
+      flags: 0,
+      descriptor_flags: 0,
+    };
+    let packet = IPCPacket {
+      from: ProcessID::new(10),
+      message: IPCMessage(1, 2, 3, 0),
+      handle: Some(file),
+      pages: Vec::new(),
+    };
+    queue.add_packet(packet, 0, 2000);
+    queue.add(ProcessID::new(11), IPCMessage(5, 6, 7, 8), 0, 5000);
+    assert!(queue.take_orphaned_handles().is_empty());
+    let (front, _) = queue.read(3000);
+    assert_eq!(front.unwrap().from, ProcessID::new(11));
+    assert_eq!(queue.take_orphaned_handles(), [file]);
+    assert!(queue.drain_handles().is_empty());
+  }
+
+  #[test]
+  fn expired_pages_are_orphaned() {
+    let mut queue = IPCQueue::new();
+    let pages = vec![PhysicalAddress::new(0x4000), PhysicalAddress::new(0x9000)];
+    let packet = IPCPacket {
+      from: ProcessID::new(10),
+      message: IPCMessage(1, 2, 0, 2),
+      handle: None,
+      pages: pages.clone(),
+    };
+    queue.add_packet(packet, 0, 2000);
+    let (front, _) = queue.read(3000);
+    assert!(front.is_none());
+    assert_eq!(queue.take_orphaned_pages(), pages);
+    assert!(queue.take_orphaned_pages().is_empty());
+
+    // Pages in messages that are still queued are orphaned when the queue is
+    // drained
+    let packet = IPCPacket {
+      from: ProcessID::new(10),
+      message: IPCMessage(1, 2, 0, 1),
+      handle: None,
+      pages: vec![PhysicalAddress::new(0x5000)],
+    };
+    queue.add_packet(packet, 3000, 5000);
+    assert!(queue.drain_handles().is_empty());
+    assert_eq!(queue.take_orphaned_pages(), [PhysicalAddress::new(0x5000)]);
+  }
 }
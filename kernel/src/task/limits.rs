@@ -0,0 +1,165 @@
+//! Resource limits cap how much of the system a single process can use. Each
+//! limit has a soft value, which is what gets enforced, and a hard value that
+//! the soft one can be raised to. Any process can lower either value, or move
+//! its soft limit anywhere up to the hard one, but raising a hard limit takes
+//! privilege. Children inherit their parent's limits on fork, and keep them
+//! across exec.
+
+use syscall::flags::{RLIMIT_DATA, RLIMIT_NOFILE, RLIMIT_STACK};
+use syscall::result::SystemError;
+use super::memory::USER_KERNEL_BARRIER;
+
+/// A limit that is never reached
+pub const UNLIMITED: usize = usize::MAX;
+
+/// No process can hold more handles than this, even with privilege
+pub const MAX_OPEN_FILES: usize = 4096;
+/// Handles a process can open before raising its limit
+pub const DEFAULT_OPEN_FILES: usize = 256;
+
+/// The user stack grows down from the top of user memory. It can't grow past
+/// this size, which keeps it clear of the mmap area below it.
+pub const MAX_STACK_SIZE: usize = 0x100000;
+pub const DEFAULT_STACK_SIZE: usize = 0x2000;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Resource {
+  /// Open file handles
+  OpenFiles,
+  /// Bytes of user stack
+  Stack,
+  /// Bytes of heap, as set by brk and sbrk
+  Data,
+}
+
+impl Resource {
+  /// Decode one of the RLIMIT_* values used by the syscalls
+  pub fn from_code(code: u32) -> Option<Resource> {
+    match code {
+      RLIMIT_NOFILE => Some(Resource::OpenFiles),
+      RLIMIT_STACK => Some(Resource::Stack),
+      RLIMIT_DATA => Some(Resource::Data),
+      _ => None,
+    }
+  }
+
+  /// The highest value a hard limit can take
+  fn ceiling(&self) -> usize {
+    match self {
+      Resource::OpenFiles => MAX_OPEN_FILES,
+      Resource::Stack => MAX_STACK_SIZE,
+      Resource::Data => UNLIMITED,
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Limit {
+  pub soft: usize,
+  pub hard: usize,
+}
+
+impl Limit {
+  pub const fn new(soft: usize, hard: usize) -> Limit {
+    Limit {
+      soft,
+      hard,
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ResourceLimits {
+  open_files: Limit,
+  stack: Limit,
+  data: Limit,
+}
+
+impl ResourceLimits {
+  pub const fn new() -> ResourceLimits {
+    ResourceLimits {
+      open_files: Limit::new(DEFAULT_OPEN_FILES, MAX_OPEN_FILES),
+      stack: Limit::new(DEFAULT_STACK_SIZE, MAX_STACK_SIZE),
+      data: Limit::new(UNLIMITED, UNLIMITED),
+    }
+  }
+
+  pub fn get(&self, resource: Resource) -> Limit {
+    match resource {
+      Resource::OpenFiles => self.open_files,
+      Resource::Stack => self.stack,
+      Resource::Data => self.data,
+    }
+  }
+
+  /// Replace a limit, returning the previous one. Raising the hard limit
+  /// fails unless the caller is privileged.
+  pub fn set(&mut self, resource: Resource, limit: Limit, privileged: bool) -> Result<Limit, SystemError> {
+    if limit.soft > limit.hard || limit.hard > resource.ceiling() {
+      return Err(SystemError::InvalidArgument);
+    }
+    let entry = match resource {
+      Resource::OpenFiles => &mut self.open_files,
+      Resource::Stack => &mut self.stack,
+      Resource::Data => &mut self.data,
+    };
+    if limit.hard > entry.hard && !privileged {
+      return Err(SystemError::PermissionDenied);
+    }
+    Ok(core::mem::replace(entry, limit))
+  }
+
+  /// Determine whether using `amount` of a resource stays within the soft
+  /// limit
+  pub fn allows(&self, resource: Resource, amount: usize) -> bool {
+    amount <= self.get(resource).soft
+  }
+
+  /// The lowest address the user stack can grow down to
+  pub fn stack_bottom(&self) -> usize {
+    (USER_KERNEL_BARRIER - self.stack.soft) & !0xfff
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use syscall::result::SystemError;
+  use super::{Limit, Resource, ResourceLimits, DEFAULT_STACK_SIZE, MAX_OPEN_FILES, UNLIMITED};
+
+  #[test]
+  fn raising_and_lowering_limits() {
+    let mut limits = ResourceLimits::new();
+    let open_files = limits.get(Resource::OpenFiles);
+    assert_eq!(open_files.hard, MAX_OPEN_FILES);
+
+    // Anyone can lower a limit, and raise the soft value back up to the hard
+    assert_eq!(limits.set(Resource::OpenFiles, Limit::new(16, 32), false).unwrap(), open_files);
+    assert_eq!(limits.set(Resource::OpenFiles, Limit::new(32, 32), false).unwrap(), Limit::new(16, 32));
+    assert!(limits.allows(Resource::OpenFiles, 32));
+    assert!(!limits.allows(Resource::OpenFiles, 33));
+    // Raising the hard limit again needs privilege
+    assert!(matches!(limits.set(Resource::OpenFiles, Limit::new(32, 64), false), Err(SystemError::PermissionDenied)));
+    assert_eq!(limits.set(Resource::OpenFiles, Limit::new(32, 64), true).unwrap(), Limit::new(32, 32));
+    // Nobody can go beyond the system ceiling, or above their own hard limit
+    assert!(matches!(limits.set(Resource::OpenFiles, Limit::new(32, MAX_OPEN_FILES + 1), true), Err(SystemError::InvalidArgument)));
+    assert!(matches!(limits.set(Resource::OpenFiles, Limit::new(65, 64), true), Err(SystemError::InvalidArgument)));
+    assert_eq!(limits.get(Resource::OpenFiles), Limit::new(32, 64));
+
+    // The heap is unlimited until a process says otherwise
+    assert!(limits.allows(Resource::Data, UNLIMITED));
+    limits.set(Resource::Data, Limit::new(0x4000, 0x10000), false).unwrap();
+    assert!(limits.allows(Resource::Data, 0x4000));
+    assert!(!limits.allows(Resource::Data, 0x4001));
+  }
+
+  #[test]
+  fn stack_growth_stops_at_limit() {
+    let mut limits = ResourceLimits::new();
+    assert_eq!(limits.stack_bottom(), 0xc0000000 - DEFAULT_STACK_SIZE);
+    limits.set(Resource::Stack, Limit::new(0x10000, 0x10000), false).unwrap();
+    assert_eq!(limits.stack_bottom(), 0xbfff0000);
+    // Partial pages count as a whole page of stack
+    limits.set(Resource::Stack, Limit::new(0x1800, 0x10000), false).unwrap();
+    assert_eq!(limits.stack_bottom(), 0xbfffe000);
+  }
+}
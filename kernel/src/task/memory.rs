@@ -33,9 +33,11 @@
 
 use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
+use core::fmt;
 use core::ops::Range;
+use crate::locks::{ordered, LockLevel};
 use crate::memory::address::{PAGE_SIZE_IN_BYTES, PhysicalAddress, VirtualAddress};
-use spin::RwLock;
+use crate::locks::RwLock;
 
 pub const USER_KERNEL_BARRIER: usize = 0xc0000000;
 
@@ -216,6 +218,22 @@ pub enum MMapBacking {
   /// This region is backed by the contents of a file. When a page fault occurs,
   /// the file will be read and the appropriate range will be copied to memory.
   DeviceFile,
+  /// This region holds pages that were handed over by another process through
+  /// IPC. Its frames are mapped as soon as the region is created, so it never
+  /// needs to be filled on demand.
+  Transferred,
+}
+
+impl fmt::Display for MMapBacking {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      MMapBacking::Direct(address) => write!(f, "direct@{:08x}", address.as_usize()),
+      MMapBacking::Anonymous => f.write_str("anon"),
+      MMapBacking::DMA => f.write_str("dma"),
+      MMapBacking::DeviceFile => f.write_str("file"),
+      MMapBacking::Transferred => f.write_str("transferred"),
+    }
+  }
 }
 
 pub struct MemoryRegions {
@@ -305,6 +323,13 @@ impl MemoryRegions {
     old
   }
 
+  /// Remove all mmap regions, returning them so that their pages can be
+  /// unmapped from the page table.
+  pub fn reset_mmap_regions(&mut self) -> Vec<MMapRegion> {
+    let old = core::mem::replace(&mut self.mmap_regions, BTreeMap::new());
+    old.into_iter().map(|(_, region)| region).collect()
+  }
+
   pub fn get_heap_start(&self) -> VirtualAddress {
     self.heap_start
   }
@@ -333,6 +358,38 @@ impl MemoryRegions {
     start..VirtualAddress::new(end)
   }
 
+  /// Move the end of the heap, as requested by `brk` or `sbrk`. Growing only
+  /// reserves the address range: each page is mapped to a zeroed frame the
+  /// first time it is touched. The heap can't grow into an mmap region.
+  /// Returns the whole pages that are no longer part of the heap, which the
+  /// caller needs to unmap in order to release their frames. The page holding
+  /// the start of the heap is never returned, because it may also hold the end
+  /// of the program's data.
+  pub fn resize_heap(&mut self, size: usize) -> Result<Range<VirtualAddress>, ProcessMemoryError> {
+    let prev_pages = self.get_heap_page_range();
+    let end = self.heap_start.as_usize().checked_add(size).ok_or(ProcessMemoryError::NotEnoughMemory)?;
+    if end > self.memory_top {
+      return Err(ProcessMemoryError::NotEnoughMemory);
+    }
+    let new_end = VirtualAddress::new(end).next_page_barrier();
+    if new_end > prev_pages.end {
+      let growth = prev_pages.end..new_end;
+      for (_, mmap) in self.mmap_regions.iter() {
+        if ranges_overlap(&mmap.get_address_range(), &growth) {
+          return Err(ProcessMemoryError::NotEnoughMemory);
+        }
+      }
+    }
+    self.heap_size = size;
+    let first_page_end = VirtualAddress::new((self.heap_start.as_usize() & 0xfffff000) + 0x1000);
+    let release_start = if new_end < first_page_end { first_page_end } else { new_end };
+    if release_start < prev_pages.end {
+      Ok(release_start..prev_pages.end)
+    } else {
+      Ok(prev_pages.end..prev_pages.end)
+    }
+  }
+
   pub fn can_fit_range(&self, range: Range<VirtualAddress>) -> bool {
     // Check if it fits before executable ranges
     if range.end <= self.get_execution_segments_start() {
@@ -418,13 +475,16 @@ impl MemoryRegions {
   }
 
   /// Remove a memory mapping for this process.
-  /// On success, it returns a range of addresses that are now freed up. This
-  /// can be used elsewhere in the kernel to invalidate page table entries.
-  pub fn munmap(&mut self, addr: VirtualAddress, length: usize) -> Result<Range<VirtualAddress>, ProcessMemoryError> {
+  /// On success, it returns the ranges of addresses that were actually mapped
+  /// and are now freed up. A request that only partially overlaps existing
+  /// mappings produces only the overlapping pieces. These can be used
+  /// elsewhere in the kernel to release frames and invalidate page table
+  /// entries.
+  pub fn munmap(&mut self, addr: VirtualAddress, length: usize) -> Result<Vec<Range<VirtualAddress>>, ProcessMemoryError> {
     if length & 0xfff != 0 {
       return Err(ProcessMemoryError::MUnmapNotPageMultiple);
     }
-    if addr.as_usize() >= USER_KERNEL_BARRIER || addr.as_usize() + length >= USER_KERNEL_BARRIER {
+    if addr.as_usize() >= USER_KERNEL_BARRIER || addr.as_usize() + length > USER_KERNEL_BARRIER {
       return Err(ProcessMemoryError::MapOutOfBounds);
     }
     // We should really replace this BTree with an Interval Tree...
@@ -458,9 +518,13 @@ impl MemoryRegions {
         }
       }
     }
+    let mut unmapped = Vec::with_capacity(modified_regions.len());
     for modification in modified_regions {
       match self.mmap_regions.remove(&modification.0) {
         Some(region) => {
+          unmapped.push(
+            (region.address + modification.1.start)..(region.address + modification.1.end)
+          );
           if modification.1.start > 0 {
             let before = MMapRegion {
               address: region.address,
@@ -483,7 +547,7 @@ impl MemoryRegions {
         None => (), // Unreachable
       }
     }
-    Ok(addr..(addr + length))
+    Ok(unmapped)
   }
 
   /// Return a reference to a mmap region if it contains the requested
@@ -496,6 +560,54 @@ impl MemoryRegions {
     }
     None
   }
+
+  /// Write a compact listing of every region, like /proc/pid/maps, for
+  /// debugging page faults and leaks. Each line has the address range,
+  /// permissions, what the region is, and how many of its pages are currently
+  /// backed by physical frames according to `is_resident`. The sections of an
+  /// execution segment are listed underneath it.
+  pub fn write_map<W, F>(&self, out: &mut W, is_resident: F) -> fmt::Result
+    where W: fmt::Write, F: Fn(VirtualAddress) -> bool {
+    for segment in self.execution_segments.iter() {
+      let range = segment.address..(segment.address + segment.size);
+      let permissions = if segment.can_write { "rw" } else { "r-" };
+      write_map_line(out, range, permissions, &"exec", &is_resident)?;
+      for section in segment.sections.iter() {
+        let range = section.as_virtual_range(segment.address);
+        write!(out, "  {:08x}-{:08x} ", range.start.as_usize(), range.end.as_usize())?;
+        match section.executable_offset {
+          Some(offset) => writeln!(out, "file+{:x}", offset)?,
+          None => writeln!(out, "zero")?,
+        }
+      }
+    }
+    let heap = self.get_heap_page_range();
+    if heap.start < heap.end {
+      write_map_line(out, heap, "rw", &"heap", &is_resident)?;
+    }
+    for region in self.mmap_regions.values() {
+      write_map_line(out, region.get_address_range(), "rw", &region.backed_by, &is_resident)?;
+    }
+    Ok(())
+  }
+}
+
+fn write_map_line<W, F>(out: &mut W, range: Range<VirtualAddress>, permissions: &str, kind: &dyn fmt::Display, is_resident: &F) -> fmt::Result
+  where W: fmt::Write, F: Fn(VirtualAddress) -> bool {
+  let pages = (range.end - range.start + PAGE_SIZE_IN_BYTES - 1) / PAGE_SIZE_IN_BYTES;
+  let resident = (0..pages)
+    .filter(|index| is_resident(range.start + index * PAGE_SIZE_IN_BYTES))
+    .count();
+  writeln!(
+    out,
+    "{:08x}-{:08x} {} {} {}/{}",
+    range.start.as_usize(),
+    range.end.as_usize(),
+    permissions,
+    kind,
+    resident,
+    pages,
+  )
 }
 
 impl Clone for MemoryRegions {
@@ -524,6 +636,8 @@ pub enum ProcessMemoryError {
   MapOutOfBounds,
   /// Attempted to unmap a region of memory that wasn't a multiple of page size
   MUnmapNotPageMultiple,
+  /// A page was unmapped, but its frame couldn't be returned to the allocator
+  FreeFailed,
 }
 
 pub fn ranges_overlap(a: &Range<VirtualAddress>, b: &Range<VirtualAddress>) -> bool {
@@ -540,13 +654,17 @@ pub fn ranges_overlap(a: &Range<VirtualAddress>, b: &Range<VirtualAddress>) -> b
 pub enum Relocation {
   /// Only type of relocation supported in a DOS EXE: add a 16-bit offset to a
   /// word at a specific address
-  DosExe(VirtualAddress, u16)
+  DosExe(VirtualAddress, u16),
+  /// R_386_RELATIVE, used by position-independent ELF executables: add the
+  /// load base to a 32-bit pointer at a specific address
+  ElfRelative(VirtualAddress, u32),
 }
 
 impl Relocation {
   pub fn get_address(&self) -> VirtualAddress {
     match self {
       Self::DosExe(addr, _) => *addr,
+      Self::ElfRelative(addr, _) => *addr,
     }
   }
 
@@ -556,6 +674,10 @@ impl Relocation {
         let ptr = addr.as_usize() as *mut u16;
         *ptr += *offset;
       },
+      Self::ElfRelative(addr, base) => {
+        let ptr = addr.as_usize() as *mut u32;
+        *ptr = (*ptr).wrapping_add(*base);
+      },
     }
   }
 }
@@ -566,6 +688,7 @@ impl Relocation {
 pub static KERNEL_MEMORY: RwLock<MemoryRegions> = RwLock::new(MemoryRegions::with_memory_top(KERNEL_MMAP_TOP));
 
 pub fn kernel_mmap(addr: Option<VirtualAddress>, size: usize, backing: MMapBacking) -> Result<VirtualAddress, ProcessMemoryError> {
+  let _order = ordered(LockLevel::KernelMemory);
   let mut mem = KERNEL_MEMORY.write();
   let location = mem.mmap(addr, size, backing)?;
 
@@ -573,12 +696,16 @@ pub fn kernel_mmap(addr: Option<VirtualAddress>, size: usize, backing: MMapBacki
 }
 
 pub fn get_kernel_mapping(addr: VirtualAddress) -> Option<MMapRegion> {
+  let _order = ordered(LockLevel::KernelMemory);
   let kernel_mem = KERNEL_MEMORY.read();
   kernel_mem.get_mapping_containing_address(&addr).map(|m| m.clone())
 }
 
 #[cfg(test)]
 mod tests {
+  use alloc::string::String;
+  use alloc::vec;
+  use crate::memory::address::PhysicalAddress;
   use super::{
     ranges_overlap,
     ExecutionSection,
@@ -648,20 +775,45 @@ mod tests {
     );
   }
 
+  #[test]
+  fn heap_growth() {
+    let mut regions = MemoryRegions::new();
+    regions.mmap(Some(VirtualAddress::new(0x5000)), 0x1000, MMapBacking::Anonymous).unwrap();
+    // Growing never releases anything
+    let freed = regions.resize_heap(0x2800).unwrap();
+    assert!(freed.start >= freed.end);
+    assert_eq!(regions.get_heap_page_range().end, VirtualAddress::new(0x3000));
+    // The heap can't run into a mapping
+    assert!(regions.resize_heap(0x5800).is_err());
+    assert_eq!(regions.get_heap_size(), 0x2800);
+    // Shrinking returns the pages that are entirely outside the new heap
+    assert_eq!(
+      regions.resize_heap(0x1200).unwrap(),
+      VirtualAddress::new(0x2000)..VirtualAddress::new(0x3000),
+    );
+    assert_eq!(
+      regions.resize_heap(0).unwrap(),
+      VirtualAddress::new(0x1000)..VirtualAddress::new(0x2000),
+    );
+    // The memory above the old heap can be reclaimed again
+    regions.resize_heap(0x5000).unwrap();
+    assert_eq!(regions.get_heap_address_range().end, VirtualAddress::new(0x5000));
+  }
+
   #[test]
   fn unmapping() {
     let mut regions = MemoryRegions::new();
     regions.mmap(Some(VirtualAddress::new(0x1000)), 0x1000, MMapBacking::Anonymous).unwrap();
     assert_eq!(
       regions.munmap(VirtualAddress::new(0x1000), 0x1000).unwrap(),
-      VirtualAddress::new(0x1000)..VirtualAddress::new(0x2000),
+      vec![VirtualAddress::new(0x1000)..VirtualAddress::new(0x2000)],
     );
     assert!(regions.mmap_regions.is_empty());
     regions.mmap(Some(VirtualAddress::new(0x1000)), 0x2000, MMapBacking::Anonymous).unwrap();
     regions.mmap(Some(VirtualAddress::new(0x4000)), 0x3000, MMapBacking::Anonymous).unwrap();
     assert_eq!(
       regions.munmap(VirtualAddress::new(0x2000), 0x2000).unwrap(),
-      VirtualAddress::new(0x2000)..VirtualAddress::new(0x4000),
+      vec![VirtualAddress::new(0x2000)..VirtualAddress::new(0x3000)],
     );
     {
       let shrunk = regions.mmap_regions.get(&VirtualAddress::new(0x1000)).unwrap();
@@ -671,7 +823,10 @@ mod tests {
     assert_eq!(regions.mmap_regions.len(), 2);
     assert_eq!(
       regions.munmap(VirtualAddress::new(0x1000), 0x4000).unwrap(),
-      VirtualAddress::new(0x1000)..VirtualAddress::new(0x5000),
+      vec![
+        VirtualAddress::new(0x1000)..VirtualAddress::new(0x2000),
+        VirtualAddress::new(0x4000)..VirtualAddress::new(0x5000),
+      ],
     );
     {
       let shrunk = regions.mmap_regions.get(&VirtualAddress::new(0x5000)).unwrap();
@@ -758,4 +913,37 @@ mod tests {
       (ExecutionSection { segment_offset: 0x200, executable_offset: Some(0x550), size: 0x300 })
     );
   }
+
+  #[test]
+  fn map_listing() {
+    let mut regions = MemoryRegions::new();
+    let mut code = ExecutionSegment::at_address(VirtualAddress::new(0x1000), 2).unwrap();
+    code.add_section(ExecutionSection { segment_offset: 0, executable_offset: Some(0x80), size: 0x1200 }).unwrap();
+    let mut data = ExecutionSegment::at_address(VirtualAddress::new(0x3000), 1).unwrap();
+    data.set_user_can_write(true);
+    data.add_section(ExecutionSection { segment_offset: 0x100, executable_offset: None, size: 0x40 }).unwrap();
+    regions.reset_execution_segments(vec![code, data]);
+    regions.resize_heap(0x1800).unwrap();
+    regions.mmap(None, 0x3000, MMapBacking::Anonymous).unwrap();
+    regions.mmap(Some(VirtualAddress::new(0x80000)), 0x2000, MMapBacking::Direct(PhysicalAddress::new(0xa0000))).unwrap();
+
+    // Pretend only the first code page, the heap, and the framebuffer have
+    // been touched
+    let resident = |page: VirtualAddress| match page.as_usize() {
+      0x1000 | 0x4000 | 0x5000 | 0x80000 | 0x81000 => true,
+      _ => false,
+    };
+    let mut listing = String::new();
+    regions.write_map(&mut listing, resident).unwrap();
+    assert_eq!(
+      listing,
+      "00001000-00003000 r- exec 1/2\n\
+       \x20 00001000-00002200 file+80\n\
+       00003000-00004000 rw exec 0/1\n\
+       \x20 00003100-00003140 zero\n\
+       00004000-00006000 rw heap 2/2\n\
+       00080000-00082000 rw direct@000a0000 2/2\n\
+       bfffd000-c0000000 rw anon 0/3\n",
+    );
+  }
 }
@@ -1,14 +1,25 @@
+pub mod accounting;
+pub mod address_space;
+pub mod environment;
 #[cfg(not(test))]
 pub mod exec;
+pub mod fork;
 pub mod files;
 pub mod id;
 pub mod io;
+pub mod io_ports;
 pub mod ipc;
+pub mod limits;
 pub mod memory;
+pub mod oom;
 #[cfg(not(test))]
 pub mod paging;
+pub mod ports;
+pub mod preempt;
 pub mod process;
 pub mod regs;
+pub mod scheduler;
+pub mod shutdown;
 pub mod signal;
 pub mod stack;
 pub mod state;
@@ -18,45 +29,145 @@ pub mod vm;
 pub mod vterm;
 
 #[cfg(not(test))]
-pub use switching::yield_coop;
+pub use switching::{yield_coop, yield_to};
 #[cfg(test)]
 pub fn yield_coop() {}
 
+/// Whether a signal with a handler is waiting on the current process.
+/// Blocked syscalls give up once this is true, so that the handler can run.
+#[cfg(not(test))]
+pub fn signal_pending() -> bool {
+  switching::get_current_process().read().signals.has_pending()
+}
+#[cfg(test)]
+pub fn signal_pending() -> bool {
+  false
+}
+
+/// Yield while blocked inside a syscall. Fails if a signal with a handler is
+/// waiting once the process resumes, so that the syscall can return early.
+#[cfg(not(test))]
+pub fn yield_interruptible() -> Result<(), ()> {
+  yield_coop();
+  if signal_pending() {
+    Err(())
+  } else {
+    Ok(())
+  }
+}
+#[cfg(test)]
+pub fn yield_interruptible() -> Result<(), ()> {
+  Ok(())
+}
+
 #[cfg(not(test))]
 pub fn sleep(duration: usize) {
-  let current_lock = switching::get_current_process();
-  current_lock.write().sleep(duration);
+  if duration > 0 {
+    let current_ticks = crate::time::system::get_system_ticks();
+    let current_lock = switching::get_current_process();
+    current_lock.write().sleep(current_ticks, duration);
+  }
   yield_coop();
 }
 #[cfg(test)]
 pub fn sleep(_duration: usize) {}
 
+/// Sleep on behalf of a syscall. Fails if a signal cut the sleep short.
+#[cfg(not(test))]
+pub fn sleep_interruptibly(duration: usize) -> Result<(), ()> {
+  if duration > 0 {
+    let current_ticks = crate::time::system::get_system_ticks();
+    let current_lock = switching::get_current_process();
+    current_lock.write().sleep_interruptibly(current_ticks, duration);
+  }
+  yield_interruptible()
+}
+
+/// Sleep until the system clock reaches a specific time. Returns right away if
+/// that time has already passed, and fails if a signal cut the sleep short.
+#[cfg(not(test))]
+pub fn sleep_until(timestamp: crate::time::timestamp::Timestamp) -> Result<(), ()> {
+  use crate::time::{system, timestamp::TimestampHires};
+
+  let wake_time = TimestampHires::from_timestamp(timestamp).0;
+  {
+    let current_lock = switching::get_current_process();
+    let mut current = current_lock.write();
+    current.sleep_until(system::get_system_ticks(), system::get_system_time().0, wake_time);
+  }
+  yield_interruptible()
+}
+
 #[cfg(not(test))]
 pub fn fork() -> id::ProcessID {
   let current_ticks = crate::time::system::get_system_ticks();
   switching::fork(current_ticks, true)
 }
 
+#[cfg(not(test))]
+pub fn vfork() -> id::ProcessID {
+  let current_ticks = crate::time::system::get_system_ticks();
+  switching::vfork(current_ticks)
+}
+
+/// Kernel processes install no signal handlers, so their waits always end
+/// with a child's status
 #[cfg(not(test))]
 pub fn wait(child_id: Option<id::ProcessID>) -> u32 {
+  wait_with_options(child_id, 0).map(|(_, code)| code).unwrap_or(0)
+}
+
+/// Wait on a child, with WUNTRACED / WCONTINUED options. Returns the child
+/// that ended the wait, if known, and its status.
+#[cfg(not(test))]
+pub fn wait_with_options(child_id: Option<id::ProcessID>, options: u32) -> Result<(Option<id::ProcessID>, u32), syscall::result::SystemError> {
+  wait_for(process::WaitTarget::from_child(child_id), options)
+}
+
+/// Wait on any child, a specific child, or any child in a process group.
+/// Returns the child that ended the wait and its status. Fails with
+/// NoSuchEntity once no child could end the wait, or with Interrupted if a
+/// signal interrupted the wait first.
+#[cfg(not(test))]
+pub fn wait_for(target: process::WaitTarget, options: u32) -> Result<(Option<id::ProcessID>, u32), syscall::result::SystemError> {
+  use syscall::result::SystemError;
+
+  let current_id = switching::get_current_id();
   let current = switching::get_current_process();
-  current.write().wait(child_id);
-  yield_coop();
-  let code = current.write().resume_from_wait();
-  code
+  loop {
+    let reaped = current.read().reaped_children();
+    let has_live_child = switching::has_live_child(current_id, target);
+    {
+      let mut process = current.write();
+      // A child that was reaped during the check may have been the last one
+      if process.reaped_children() != reaped {
+        continue;
+      }
+      process.wait_for_live(target, options, has_live_child)?;
+    }
+    yield_coop();
+    let mut process = current.write();
+    if let Some(code) = process.finish_wait() {
+      return Ok((process.take_waited_child(), code));
+    }
+    // Reaping a child wakes the wait without a status, to check again
+    if process.reaped_children() == reaped {
+      return Err(SystemError::Interrupted);
+    }
+  }
 }
 
 #[cfg(not(test))]
 pub use switching::get_current_process;
 #[cfg(test)]
-pub fn get_current_process() -> alloc::sync::Arc<spin::RwLock<process::Process>> {
+pub fn get_current_process() -> alloc::sync::Arc<crate::locks::RwLock<process::Process>> {
   panic!("No current process in test");
 }
 
 #[cfg(not(test))]
 pub use switching::get_process;
 #[cfg(test)]
-pub fn get_process(_id: &id::ProcessID) -> Option<alloc::sync::Arc<spin::RwLock<process::Process>>> {
+pub fn get_process(_id: &id::ProcessID) -> Option<alloc::sync::Arc<crate::locks::RwLock<process::Process>>> {
   panic!("Not available in test");
 }
 
@@ -80,11 +191,99 @@ pub fn ipc_read(timeout: Option<usize>) -> (Option<ipc::IPCPacket>, bool) {
     let mut current_process = current_process_lock.write();
     current_process.ipc_read(current_ticks, timeout)
   };
-  if first.is_some() {
-    return (first, has_more);
+  let (packet, has_more) = if first.is_some() {
+    (first, has_more)
+  } else {
+    yield_coop();
+    switching::get_current_process().write().ipc_read_unblocking(current_ticks)
+  };
+  (packet.map(accept_ipc_packet), has_more)
+}
+
+/// Install any file handle or pages carried by a received packet, and clean
+/// up the handles and pages of messages that expired without being read
+#[cfg(not(test))]
+fn accept_ipc_packet(mut packet: ipc::IPCPacket) -> ipc::IPCPacket {
+  let (orphaned, orphaned_pages, transferred) = {
+    let current_process_lock = switching::get_current_process();
+    let mut current_process = current_process_lock.write();
+    current_process.install_ipc_handle(&mut packet);
+    let transferred = current_process.reserve_ipc_pages(&mut packet);
+    (
+      current_process.take_orphaned_ipc_handles(),
+      current_process.take_orphaned_ipc_pages(),
+      transferred,
+    )
+  };
+  if let Some((address, frames)) = transferred {
+    paging::map_transferred_pages(address, frames);
   }
-  yield_coop();
-  switching::get_current_process().write().ipc_read_unblocking(current_ticks)
+  paging::release_frames(orphaned_pages);
+  io::close_open_files(orphaned);
+  packet
+}
+
+/// Send an IPC message along with a copy of one of the current process's open
+/// files. The copy belongs to the message until it is read, so the sender can
+/// close its own handle immediately.
+#[cfg(not(test))]
+pub fn ipc_send_handle(to: id::ProcessID, message: ipc::IPCMessage, handle: crate::files::handle::FileHandle, expiration: u32) -> Result<(), syscall::result::SystemError> {
+  use syscall::result::SystemError;
+
+  let current_id = switching::get_current_id();
+  let current_ticks = crate::time::system::get_system_ticks();
+  let recipient = switching::get_process(&to).ok_or(SystemError::NoSuchEntity)?;
+  let open_file = {
+    let current_process_lock = switching::get_current_process();
+    let current_process = current_process_lock.read();
+    *current_process.get_open_file_info(handle).ok_or(SystemError::BadFileDescriptor)?
+  };
+  let (_, instance) = crate::fs::DRIVES.get_drive_instance(&open_file.drive).ok_or(SystemError::NoSuchFileSystem)?;
+  let packet = ipc::IPCPacket::with_file(current_id, to, message, open_file, &**instance)
+    .map_err(|_| SystemError::IOError)?;
+  recipient.write().ipc_receive_packet(current_ticks, packet, expiration);
+  Ok(())
+}
+
+/// Send an IPC message along with a range of the current process's pages.
+/// The range starts at the page-aligned address in the third value of the
+/// message, and is as many pages long as the last value. Depending on the
+/// mode, the sender either keeps sharing the pages with the receiver or loses
+/// them entirely; moved pages are also removed from the sender's mmap regions,
+/// so touching them afterwards is a fault rather than a fresh page.
+#[cfg(not(test))]
+pub fn ipc_send_pages(to: id::ProcessID, message: ipc::IPCMessage, mode: ipc::PageTransferMode, expiration: u32) -> Result<(), syscall::result::SystemError> {
+  use memory::USER_KERNEL_BARRIER;
+  use syscall::result::SystemError;
+
+  let start = crate::memory::address::VirtualAddress::new(message.2 as usize);
+  let page_count = message.3 as usize;
+  if !start.is_page_aligned() || page_count == 0 || page_count > ipc::MAX_TRANSFER_PAGES {
+    return Err(SystemError::InvalidArgument);
+  }
+  let size = page_count * 0x1000;
+  if start.as_usize() >= USER_KERNEL_BARRIER || USER_KERNEL_BARRIER - start.as_usize() < size {
+    return Err(SystemError::InvalidArgument);
+  }
+  let current_id = switching::get_current_id();
+  let current_ticks = crate::time::system::get_system_ticks();
+  let recipient = switching::get_process(&to).ok_or(SystemError::NoSuchEntity)?;
+  let current_process_lock = switching::get_current_process();
+  // Collecting the pages may fault them in, which needs the process lock, so
+  // it can't be held here
+  let pages = paging::collect_transfer_pages(alloc::sync::Arc::clone(&current_process_lock), start, page_count, mode)
+    .map_err(|_| SystemError::InvalidArgument)?;
+  if mode == ipc::PageTransferMode::Move {
+    let _ = current_process_lock.write().memory.munmap(start, size);
+  }
+  let packet = ipc::IPCPacket {
+    from: current_id,
+    message,
+    handle: None,
+    pages,
+  };
+  recipient.write().ipc_receive_packet(current_ticks, packet, expiration);
+  Ok(())
 }
 
 #[cfg(not(test))]
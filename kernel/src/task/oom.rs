@@ -0,0 +1,374 @@
+//! When physical memory runs out while backing a user process's memory, the
+//! kernel doesn't give up on the allocation right away. Instead, the OOM
+//! killer picks a victim process, terminates it, and returns its frames to the
+//! allocator, after which the allocation is attempted one more time.
+//! The choice of victim is made by a VictimPolicy, which can be replaced at
+//! runtime. Kernel processes, like init and the device drivers, are protected
+//! and never offered to the policy.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use crate::locks::{ordered, LockLevel, RwLock};
+use crate::memory::address::PhysicalAddress;
+use super::fork::TaskMap;
+use super::id::ProcessID;
+
+/// A process that the OOM killer is allowed to terminate
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct OOMCandidate {
+  pub id: ProcessID,
+  /// Number of user pages currently backed by physical memory
+  pub resident_pages: usize,
+}
+
+pub trait VictimPolicy: Send + Sync {
+  /// Choose which candidate should be terminated to free up memory. Returning
+  /// None leaves every process running, and the allocation fails.
+  fn select_victim(&self, candidates: &[OOMCandidate]) -> Option<ProcessID>;
+}
+
+/// The default policy: terminate whichever process holds the most memory
+pub struct LargestResidentSet;
+
+impl VictimPolicy for LargestResidentSet {
+  fn select_victim(&self, candidates: &[OOMCandidate]) -> Option<ProcessID> {
+    candidates
+      .iter()
+      .filter(|candidate| candidate.resident_pages > 0)
+      .max_by_key(|candidate| candidate.resident_pages)
+      .map(|candidate| candidate.id)
+  }
+}
+
+static POLICY: RwLock<Option<Box<dyn VictimPolicy>>> = RwLock::new(None);
+
+/// Replace the policy used to pick a victim when memory is exhausted
+pub fn set_victim_policy(policy: Box<dyn VictimPolicy>) {
+  *POLICY.write() = Some(policy);
+}
+
+/// Return to the default, largest-process-first policy
+pub fn reset_victim_policy() {
+  *POLICY.write() = None;
+}
+
+pub fn select_victim(candidates: &[OOMCandidate]) -> Option<ProcessID> {
+  match &*POLICY.read() {
+    Some(policy) => policy.select_victim(candidates),
+    None => LargestResidentSet.select_victim(candidates),
+  }
+}
+
+/// Run an allocation. If it fails, call `reclaim` to free up memory, and if
+/// that succeeds, try the allocation exactly once more.
+pub fn retry_after_reclaim<T, E, A, R>(mut allocate: A, reclaim: R) -> Result<T, E>
+  where A: FnMut() -> Result<T, E>, R: FnOnce() -> bool {
+  match allocate() {
+    Ok(value) => Ok(value),
+    Err(e) => {
+      if reclaim() {
+        allocate()
+      } else {
+        Err(e)
+      }
+    },
+  }
+}
+
+/// List the processes in the task map that the OOM killer may terminate.
+/// `count_resident` reports how many user pages of a page directory are
+/// backed by frames. It walks page tables, so it isn't called until the task
+/// map has been released.
+pub fn find_candidates<F>(task_map: &RwLock<TaskMap>, count_resident: F) -> Vec<OOMCandidate>
+  where F: Fn(PhysicalAddress) -> usize {
+  let mut processes = Vec::new();
+  {
+    let _order = ordered(LockLevel::TaskMap);
+    for entry in task_map.read().values() {
+      let _order = ordered(LockLevel::Process);
+      let process = entry.read();
+      // A vfork child is borrowing its parent's memory; the parent is counted
+      if process.is_terminated() || process.is_oom_protected() || process.get_vfork_parent().is_some() {
+        continue;
+      }
+      processes.push((*process.get_id(), process.page_directory.get_address()));
+    }
+  }
+  processes
+    .into_iter()
+    .map(|(id, pagedir)| OOMCandidate {
+      id,
+      resident_pages: count_resident(pagedir),
+    })
+    .collect()
+}
+
+/// Free up memory by terminating a victim process. Returns true if another
+/// process was torn down and its frames released. If the victim is the
+/// current process, it is terminated and never resumes.
+#[cfg(not(test))]
+pub fn reclaim_memory() -> bool {
+  let current_id = super::switching::get_current_id();
+  let candidates = find_candidates(&super::switching::TASK_MAP, super::paging::count_resident_pages);
+
+  let victim = match select_victim(&candidates) {
+    Some(id) => id,
+    None => return false,
+  };
+  crate::klog!(Error, Memory; "Out of memory: terminating {:?}\n", victim);
+  let exit_code = super::signal::Signal::Kill.get_exit_status();
+  if victim == current_id {
+    super::exec::terminate(exit_code);
+    return false;
+  }
+  super::exec::terminate_process(victim, exit_code);
+  super::switching::clean_up_process(victim);
+  true
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::boxed::Box;
+  use alloc::vec::Vec;
+  use crate::memory::address::VirtualAddress;
+  use crate::memory::physical::frame_bitmap::FrameBitmap;
+  use crate::memory::physical::frame_range::FrameRange;
+  use crate::memory::physical::frame_refcount::FrameRefcount;
+  use crate::memory::physical::release_frame;
+  use crate::memory::virt::page_entry::PageTableEntry;
+  use crate::memory::virt::page_table::PageTable;
+  use super::{
+    find_candidates,
+    retry_after_reclaim,
+    reset_victim_policy,
+    select_victim,
+    set_victim_policy,
+    OOMCandidate,
+    ProcessID,
+    VictimPolicy,
+  };
+
+  struct Newest;
+
+  impl VictimPolicy for Newest {
+    fn select_victim(&self, candidates: &[OOMCandidate]) -> Option<ProcessID> {
+      candidates.iter().map(|candidate| candidate.id).max()
+    }
+  }
+
+  #[test]
+  fn killing_memory_hog_reclaims_frames() {
+    let memory: [u8; 2] = [0; 2];
+    let mut bitmap = FrameBitmap::at_location(
+      VirtualAddress::new(&memory[0] as *const u8 as usize),
+      16,
+    );
+    let hog = ProcessID::new(3);
+    let small = ProcessID::new(4);
+    // The hog takes almost everything, a small process takes the rest
+    let hog_frames: Vec<FrameRange> = (0..12).map(|_| bitmap.allocate_frames(1).unwrap()).collect();
+    let small_frames: Vec<FrameRange> = (0..4).map(|_| bitmap.allocate_frames(1).unwrap()).collect();
+    assert_eq!(bitmap.get_free_frame_count(), 0);
+    // Init and drivers are protected, so they never show up as candidates
+    let candidates = [
+      OOMCandidate { id: small, resident_pages: small_frames.len() },
+      OOMCandidate { id: hog, resident_pages: hog_frames.len() },
+    ];
+
+    let bitmap = crate::locks::Mutex::new(bitmap);
+    let mut victims = Vec::new();
+    let mut attempts = 0;
+    let frame = retry_after_reclaim(
+      || {
+        attempts += 1;
+        bitmap.lock().allocate_frames(1)
+      },
+      || {
+        let victim = match select_victim(&candidates) {
+          Some(id) => id,
+          None => return false,
+        };
+        victims.push(victim);
+        let frames = if victim == hog { &hog_frames } else { &small_frames };
+        for range in frames.iter() {
+          bitmap.lock().free_range(*range).unwrap();
+        }
+        true
+      },
+    );
+    assert!(frame.is_ok());
+    assert_eq!(attempts, 2);
+    assert_eq!(victims, [hog]);
+    assert_eq!(bitmap.lock().get_free_frame_count(), 11);
+
+    // Without a victim, the allocation is only tried once
+    let mut attempts = 0;
+    let result: Result<(), ()> = retry_after_reclaim(|| { attempts += 1; Err(()) }, || false);
+    assert!(result.is_err());
+    assert_eq!(attempts, 1);
+
+    // Processes without any resident memory aren't worth killing
+    assert_eq!(select_victim(&[OOMCandidate { id: small, resident_pages: 0 }]), None);
+    assert_eq!(select_victim(&[]), None);
+
+    // The policy can be swapped out
+    set_victim_policy(Box::new(Newest));
+    assert_eq!(select_victim(&candidates), Some(small));
+    reset_victim_policy();
+    assert_eq!(select_victim(&candidates), Some(hog));
+  }
+
+  #[test]
+  fn copy_on_write_under_memory_pressure() {
+    let memory: [u8; 2] = [0; 2];
+    let bitmap = crate::locks::Mutex::new(FrameBitmap::at_location(
+      VirtualAddress::new(&memory[0] as *const u8 as usize),
+      16,
+    ));
+    let mut refcount = FrameRefcount::new();
+    // A page shared copy-on-write between a process and its fork
+    let shared = bitmap.lock().allocate_frames(1).unwrap().get_starting_address();
+    refcount.reference_frame_at_address(shared);
+    let mut entry = PageTableEntry::new();
+    entry.set_address(shared);
+    entry.set_present();
+    entry.set_cow();
+    // Another process holds every remaining frame
+    let mut victim = Box::new(PageTable::new());
+    for index in 0..15 {
+      let frame = bitmap.lock().allocate_frames(1).unwrap().get_starting_address();
+      victim.get_mut(index).set_address(frame);
+      victim.get_mut(index).set_present();
+    }
+    assert_eq!(bitmap.lock().get_free_frame_count(), 0);
+
+    // With no victim to terminate, the write fails instead of panicking, and
+    // the page is left shared and read-only
+    let result = entry.resolve_copy_on_write(refcount.get_count_for_address(shared), |_| {
+      retry_after_reclaim(|| bitmap.lock().allocate_frames(1), || false)
+        .map(|range| range.get_starting_address())
+    });
+    assert!(result.is_err());
+    assert!(entry.is_cow());
+    assert!(!entry.is_write_access_granted());
+    assert_eq!(entry.get_address(), shared);
+    assert_eq!(refcount.get_count_for_address(shared), 2);
+
+    // Terminating the victim releases its frames, and the retry makes the copy
+    let released = entry.resolve_copy_on_write(refcount.get_count_for_address(shared), |_| {
+      retry_after_reclaim(
+        || bitmap.lock().allocate_frames(1),
+        || {
+          victim.release_entries(|frame| {
+            assert!(release_frame(&mut bitmap.lock(), &mut refcount, frame.to_frame()).unwrap());
+          });
+          true
+        },
+      ).map(|range| range.get_starting_address())
+    }).unwrap();
+    assert_eq!(released, Some(shared));
+    refcount.release_frame_at_address(shared);
+    assert!(!entry.is_cow());
+    assert!(entry.is_write_access_granted());
+    assert!(entry.get_address() != shared);
+    assert_eq!(refcount.get_count_for_address(shared), 1);
+    assert_eq!(bitmap.lock().get_free_frame_count(), 14);
+  }
+
+  #[test]
+  fn reclaims_frames_from_task_map_before_failing() {
+    use alloc::collections::BTreeMap;
+    use crate::locks::{Mutex, RwLock};
+    use crate::memory::address::PhysicalAddress;
+    use crate::memory::virt::page_table::PageTableReference;
+    use crate::task::fork::{add_task, remove_task, TaskMap};
+    use crate::task::process::Process;
+
+    let memory: [u8; 2] = [0; 2];
+    let bitmap = Mutex::new(FrameBitmap::at_location(
+      VirtualAddress::new(&memory[0] as *const u8 as usize),
+      16,
+    ));
+    let mut refcount = FrameRefcount::new();
+    // Stand-ins for each process's page directory, keyed by its address
+    let page_tables: Mutex<BTreeMap<usize, Box<PageTable>>> = Mutex::new(BTreeMap::new());
+    let task_map: RwLock<TaskMap> = RwLock::new(BTreeMap::new());
+    let init = Process::initial(0);
+    let spawn = |id: u32, pages: usize, vfork: bool| {
+      let mut process = if vfork {
+        init.create_vfork(ProcessID::new(id), 0)
+      } else {
+        init.create_fork(ProcessID::new(id), 0)
+      };
+      let mut table = Box::new(PageTable::new());
+      for index in 0..pages {
+        let frame = bitmap.lock().allocate_frames(1).unwrap().get_starting_address();
+        table.get_mut(index).set_address(frame);
+        table.get_mut(index).set_present();
+      }
+      let address = 0x100000 + id as usize * 0x1000;
+      page_tables.lock().insert(address, table);
+      process.page_directory = PageTableReference::new(PhysicalAddress::new(address));
+      add_task(&task_map, process);
+    };
+    // IDs no other test uses, since the run queue is shared
+    let (hog, small, vforked, protected) = (20700, 20701, 20702, 20703);
+    spawn(hog, 10, false);
+    spawn(small, 6, false);
+    // A vfork child and a kernel process are never victims, however much
+    // they appear to hold
+    spawn(vforked, 0, true);
+    add_task(&task_map, Process::initial(0).create_fork(ProcessID::new(protected), 0));
+    task_map.read().get(&ProcessID::new(protected)).unwrap().write().protect_from_oom();
+    assert_eq!(bitmap.lock().get_free_frame_count(), 0);
+
+    let count_resident = |pagedir: PhysicalAddress| {
+      page_tables.lock().get(&pagedir.as_usize()).map_or(0, |table| {
+        (0..1024).filter(|index| table.get(*index).is_present()).count()
+      })
+    };
+    let mut candidates = find_candidates(&task_map, count_resident);
+    candidates.sort_by_key(|candidate| candidate.id);
+    assert_eq!(candidates, [
+      OOMCandidate { id: ProcessID::new(hog), resident_pages: 10 },
+      OOMCandidate { id: ProcessID::new(small), resident_pages: 6 },
+    ]);
+
+    // Reclaim the way the kernel does: terminate the largest candidate, take
+    // it out of the task map, and release its page tables
+    let mut reclaim = || {
+      let candidates = find_candidates(&task_map, count_resident);
+      let victim = match select_victim(&candidates) {
+        Some(id) => id,
+        None => return false,
+      };
+      task_map.read().get(&victim).unwrap().write().terminate();
+      let process = remove_task(&task_map, victim).unwrap();
+      let pagedir = process.read().page_directory.get_address().as_usize();
+      page_tables.lock().get_mut(&pagedir).unwrap().release_entries(|frame| {
+        assert!(release_frame(&mut bitmap.lock(), &mut refcount, frame.to_frame()).unwrap());
+      });
+      true
+    };
+
+    // The first allocation under pressure frees the hog's frames and succeeds
+    let frame = retry_after_reclaim(|| bitmap.lock().allocate_frames(1), &mut reclaim);
+    assert!(frame.is_ok());
+    assert!(!task_map.read().contains_key(&ProcessID::new(hog)));
+    assert_eq!(bitmap.lock().get_free_frame_count(), 9);
+
+    // Use up the rest; then the small process goes next
+    while bitmap.lock().allocate_frames(1).is_ok() {}
+    assert!(retry_after_reclaim(|| bitmap.lock().allocate_frames(1), &mut reclaim).is_ok());
+    assert_eq!(bitmap.lock().get_free_frame_count(), 5);
+
+    // Once only protected processes are left, nothing is reclaimed and the
+    // allocation fails
+    while bitmap.lock().allocate_frames(1).is_ok() {}
+    assert!(retry_after_reclaim(|| bitmap.lock().allocate_frames(1), &mut reclaim).is_err());
+    assert_eq!(task_map.read().len(), 2);
+    assert_eq!(bitmap.lock().get_free_frame_count(), 0);
+    remove_task(&task_map, ProcessID::new(vforked)).unwrap();
+    remove_task(&task_map, ProcessID::new(protected)).unwrap();
+  }
+}
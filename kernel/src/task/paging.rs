@@ -3,41 +3,38 @@ use alloc::vec::Vec;
 use core::ops::Range;
 use crate::files::cursor::SeekMethod;
 use crate::fs::DRIVES;
+use crate::locks::{ordered, LockLevel};
 use crate::memory::address::{PhysicalAddress, VirtualAddress};
-use crate::memory::physical::{free_frame, allocated_frame::AllocatedFrame};
+use crate::memory::physical::{free_frame, allocated_frame::AllocatedFrame, frame_bitmap::BitmapError};
 use crate::memory::virt::page_directory::{self, PermissionFlags};
-use crate::memory::virt::page_table::PageTable;
-use spin::RwLock;
-use super::memory::{USER_KERNEL_BARRIER, ExecutionSegment, MMapBacking, MMapRegion};
+use crate::memory::virt::page_entry::PageTableEntry;
+use crate::memory::virt::page_table::{PageTable, TABLE_ENTRY_COUNT};
+use crate::memory::zero::zero_page;
+use crate::locks::RwLock;
+use super::address_space::release_user_space;
+use super::ipc::PageTransferMode;
+use super::memory::{USER_KERNEL_BARRIER, MMapBacking, MMapRegion};
 use super::process::Process;
 use super::stack::{STACK_SIZE_IN_PAGES, UnmappedPage};
-
-pub static STACK_SIZE: usize = 0x2000;
+use super::vm::Subsystem;
 
 pub fn page_on_demand(lock: Arc<RwLock<Process>>, address: VirtualAddress) -> bool {
-  let stack_range = VirtualAddress::new(USER_KERNEL_BARRIER - STACK_SIZE)..VirtualAddress::new(USER_KERNEL_BARRIER);
+  let (heap_range, in_stack) = {
+    let process = lock.read();
+    // The stack grows down on demand, until it reaches the process's stack
+    // limit. Mappings below the top of memory take priority over it.
+    let stack_range = process.get_stack_bottom()..VirtualAddress::new(USER_KERNEL_BARRIER);
+    let in_stack = stack_range.contains(&address)
+      && process.memory.get_mapping_containing_address(&address).is_none();
+    // The whole page containing the end of the heap belongs to it, so that
+    // touching the bytes past the end doesn't depend on whether the page
+    // happens to be mapped already
+    (process.memory.get_heap_page_range(), in_stack)
+  };
 
-  let heap_range = lock.read().memory.get_heap_address_range();
-  
-  if heap_range.contains(&address) || stack_range.contains(&address) {
+  if heap_range.contains(&address) || in_stack {
     // allocate a new frame for the heap
-    let new_frame = match crate::memory::physical::allocate_frame() {
-      Ok(frame) => frame,
-      Err(_) => return false,
-    };
-    crate::kprintln!("  Page heap/stack @ {:?}", new_frame.get_address());
-    let current_pagedir = page_directory::CurrentPageDirectory::get();
-    current_pagedir.map(
-      new_frame,
-      address.prev_page_barrier(),
-      PermissionFlags::new(PermissionFlags::USER_ACCESS | PermissionFlags::WRITE_ACCESS),
-    );
-    // zero the page
-    let buffer = unsafe { core::slice::from_raw_parts_mut(address.prev_page_barrier().as_usize() as *mut u32, 0x400) };
-    for i in 0..0x400 {
-      buffer[i] = 0;
-    }
-    return true;
+    return map_zeroed_page(address);
   }
 
   let mut subsections = Vec::new();
@@ -80,17 +77,23 @@ pub fn page_on_demand(lock: Arc<RwLock<Process>>, address: VirtualAddress) -> bo
   };
 
   if subsections.len() > 0 {
-    let new_frame = match crate::memory::physical::allocate_frame() {
+    let new_frame = match allocate_user_frame() {
       Ok(frame) => frame,
       Err(_) => return false,
     };
-    crate::kprintln!("  Page exec @ {:?}", new_frame.get_address());
+    crate::kprintln!(Debug, Memory; "  Page exec @ {:?}", new_frame.get_address());
     let current_pagedir = page_directory::CurrentPageDirectory::get();
     current_pagedir.map(
       new_frame,
       address.prev_page_barrier(),
       flags,
     );
+    // Sections may not cover the whole page, and a short read can leave part
+    // of a section unfilled. Clear everything first so the frame's previous
+    // contents never show through.
+    unsafe {
+      zero_page(address.prev_page_barrier());
+    }
 
     // copy all sections from file to the page
     let drive_instance = match DRIVES.get_drive_instance(&exec_file_info.0) {
@@ -110,30 +113,54 @@ pub fn page_on_demand(lock: Arc<RwLock<Process>>, address: VirtualAddress) -> bo
           // should really do something with these potential errors
           let _ = drive_instance.seek(exec_file_info.1, SeekMethod::Absolute(offset));
           let _ = drive_instance.read(exec_file_info.1, buffer);
-
-          // Apply relocations
-          for rel in relocations.iter() {
-            crate::kprintln!("Apply Relocation: {:?}", rel.get_address());
-            unsafe {
-              rel.apply();
-            }
-          }
-        },
-        None => {
-          // Fill with zeroes
-          //crate::kprintln!("FILL WITH ZEROES: {:?} {:X}", section.0, section.1);
-          for i in 0..buffer.len() {
-            buffer[i] = 0;
-          }
         },
+        // Zero-filled sections like BSS are already clear
+        None => (),
+      }
+    }
+    // Apply relocations once the whole page has been filled, so that a page
+    // made of several sections doesn't relocate the same value twice
+    for rel in relocations.iter() {
+      crate::kprintln!(Debug, Memory; "Apply Relocation: {:?}", rel.get_address());
+      unsafe {
+        rel.apply();
       }
     }
     return true;
   }
 
+  // DOS programs can allocate any conventional memory below video memory,
+  // which gets backed the first time it is touched
+  let is_dos = match lock.read().subsystem {
+    Subsystem::DOS(_) => true,
+    Subsystem::Native => false,
+  };
+  if is_dos && address < VirtualAddress::new(0xa0000) {
+    return map_zeroed_page(address);
+  }
+
   false
 }
 
+/// Back the page containing an address with a newly allocated, zeroed frame
+fn map_zeroed_page(address: VirtualAddress) -> bool {
+  let new_frame = match allocate_user_frame() {
+    Ok(frame) => frame,
+    Err(_) => return false,
+  };
+  crate::kprintln!(Debug, Memory; "  Page zeroed @ {:?}", new_frame.get_address());
+  let current_pagedir = page_directory::CurrentPageDirectory::get();
+  current_pagedir.map(
+    new_frame,
+    address.prev_page_barrier(),
+    PermissionFlags::new(PermissionFlags::USER_ACCESS | PermissionFlags::WRITE_ACCESS),
+  );
+  unsafe {
+    zero_page(address.prev_page_barrier());
+  }
+  true
+}
+
 pub fn get_or_allocate_physical_address(addr: VirtualAddress) -> Result<PhysicalAddress, ()> {
   if !addr.is_page_aligned() {
     return Err(());
@@ -149,6 +176,7 @@ pub fn get_or_allocate_physical_address(addr: VirtualAddress) -> Result<Physical
   }
   // Not currently mapped
   {
+    let _order = ordered(LockLevel::KernelMemory);
     let kernel_mem = super::memory::KERNEL_MEMORY.read();
     let mapping = kernel_mem.get_mapping_containing_address(&addr);
     let new_frame = match mapping {
@@ -194,9 +222,55 @@ pub fn share_kernel_page_directory(vaddr: VirtualAddress) {
   });
 }
 
+/// Allocate a frame to back a page of user memory. If physical memory is
+/// exhausted, the OOM killer terminates a victim process to make room, and
+/// the allocation is retried once.
+pub fn allocate_user_frame() -> Result<AllocatedFrame, BitmapError> {
+  super::oom::retry_after_reclaim(
+    crate::memory::physical::allocate_frame,
+    super::oom::reclaim_memory,
+  )
+}
+
+/// Count the user pages backed by physical memory in a page directory
+pub fn count_resident_pages(pagedir_address: PhysicalAddress) -> usize {
+  with_inactive_page_table(pagedir_address, |directory| {
+    let mut count = 0;
+    for dir_entry in 0..0x300 {
+      if !directory.get(dir_entry).is_present() {
+        continue;
+      }
+      let table_address = directory.get(dir_entry).get_address();
+      count += with_inactive_page_table(table_address, |table| {
+        (0..TABLE_ENTRY_COUNT).filter(|index| table.get(*index).is_present()).count()
+      });
+    }
+    count
+  })
+}
+
+/// Check whether a single page is backed by physical memory in a page
+/// directory, which doesn't need to be the current one
+pub fn is_page_resident(pagedir_address: PhysicalAddress, page: VirtualAddress) -> bool {
+  with_inactive_page_table(pagedir_address, |directory| {
+    let dir_entry = directory.get(page.get_page_directory_index());
+    if !dir_entry.is_present() {
+      return false;
+    }
+    with_inactive_page_table(dir_entry.get_address(), |table| {
+      table.get(page.get_page_table_index()).is_present()
+    })
+  })
+}
+
 pub fn duplicate_frame(page_start: VirtualAddress) -> AllocatedFrame {
   let new_frame = crate::memory::physical::allocate_frame().unwrap();
-  crate::kprintln!("  New dup frame @ {:?}", new_frame.get_address());
+  copy_page_to_frame(page_start, new_frame)
+}
+
+/// Fill a newly allocated frame with the contents of a mapped page
+fn copy_page_to_frame(page_start: VirtualAddress, new_frame: AllocatedFrame) -> AllocatedFrame {
+  crate::kprintln!(Debug, Memory; "  New dup frame @ {:?}", new_frame.get_address());
   let temp_mapping = UnmappedPage::map(new_frame.get_address());
   let temp_addr = temp_mapping.virtual_address();
   unsafe {
@@ -207,86 +281,291 @@ pub fn duplicate_frame(page_start: VirtualAddress) -> AllocatedFrame {
   new_frame
 }
 
-pub fn invalidate_page(addr: VirtualAddress) {
-  unsafe {
-    llvm_asm!("invlpg ($0)" : : "r"(addr.as_u32()));
+/// Give the current process its own writable copy of a copy-on-write page.
+/// If no other process still refers to the frame, it is simply made writable.
+/// The shared frame's reference is only dropped once the copy is complete.
+/// If there is no memory for the copy, even after the OOM killer has run, the
+/// page stays shared and read-only, and the error is returned.
+pub fn break_copy_on_write(entry: &mut PageTableEntry, vaddr: VirtualAddress) -> Result<(), BitmapError> {
+  let page_start = vaddr.prev_page_barrier();
+  let references = crate::memory::physical::get_current_refcount_for_address(entry.get_address());
+  let released = entry.resolve_copy_on_write(references, |_| {
+    let new_frame = copy_page_to_frame(page_start, allocate_user_frame()?);
+    Ok(new_frame.to_frame().get_address())
+  })?;
+  if let Some(shared) = released {
+    crate::memory::physical::release_frame_at_address(shared);
   }
+  invalidate_page(page_start);
+  Ok(())
 }
 
-/// Unmap a single page, reducing COW counts as needed
-pub fn unmap_page(address: VirtualAddress) {
+/// Collect the frames behind a range of the current process's memory, so that
+/// they can be sent to another process over IPC. Pages that haven't been
+/// touched yet are paged in, and copy-on-write pages are split first, so the
+/// receiver never sees memory that is still shared with a fork. Only writable
+/// RAM can be transferred; read-only segments and device memory are refused.
+/// Each returned frame carries one reference for the caller to own. When
+/// moving, the sender's mapping is removed and its reference is handed over.
+/// When sharing, the sender keeps its mapping and a new reference is added.
+pub fn collect_transfer_pages(lock: Arc<RwLock<Process>>, start: VirtualAddress, page_count: usize, mode: PageTransferMode) -> Result<Vec<PhysicalAddress>, ()> {
+  let mut current_pagedir = page_directory::CurrentPageDirectory::get();
+  // Check every page before taking any references, so that a failure leaves
+  // nothing to undo
+  for index in 0..page_count {
+    let page = start + index * 0x1000;
+    let present = match current_pagedir.get_table_entry_for(page) {
+      Some(entry) => entry.is_present(),
+      None => false,
+    };
+    if !present && !page_on_demand(Arc::clone(&lock), page) {
+      return Err(());
+    }
+    let entry = current_pagedir.get_table_entry_for(page).ok_or(())?;
+    if !entry.should_reclaim() || !entry.is_user_access_granted() {
+      return Err(());
+    }
+    if entry.is_cow() {
+      break_copy_on_write(entry, page).map_err(|_| ())?;
+    }
+    if !entry.is_write_access_granted() {
+      return Err(());
+    }
+  }
+
+  let mut frames = Vec::with_capacity(page_count);
+  for index in 0..page_count {
+    let page = start + index * 0x1000;
+    if let Some(entry) = current_pagedir.get_table_entry_for(page) {
+      frames.push(mode.take_frame(entry, |address| {
+        let _ = crate::memory::physical::reference_frame_at_address(address).to_frame();
+      }));
+    }
+    invalidate_page(page);
+  }
+  Ok(frames)
+}
+
+/// Lock a page-aligned range of the current process's memory into RAM, for
+/// code that can't tolerate page faults, like a buffer about to be used for
+/// DMA. Every page is faulted in right away: heap and stack pages are
+/// allocated, pages of the executable are read from its file, and pages
+/// shared copy-on-write with a fork get their own copy. Any page that can't be
+/// backed, because nothing is mapped there, makes the whole call fail.
+pub fn lock_pages(lock: Arc<RwLock<Process>>, range: Range<VirtualAddress>) -> Result<(), ()> {
+  let mut current_pagedir = page_directory::CurrentPageDirectory::get();
+  let mut page = range.start;
+  while page < range.end {
+    let present = match current_pagedir.get_table_entry_for(page) {
+      Some(entry) => entry.is_present(),
+      None => false,
+    };
+    if !present && !page_on_demand(Arc::clone(&lock), page) {
+      return Err(());
+    }
+    page = page + 0x1000;
+  }
+  for_each_table_in_range(range, |dir_index, table, indices| {
+    table.lock_range(indices, |index, entry| {
+      break_copy_on_write(entry, VirtualAddress::new((dir_index << 22) | (index << 12))).map_err(|_| ())
+    })
+  })
+}
+
+/// Allow a page-aligned range of the current process's memory to be evicted
+/// again. Pages in the range that were never locked are unaffected.
+pub fn unlock_pages(range: Range<VirtualAddress>) -> Result<(), ()> {
+  for_each_table_in_range(range, |_, table, indices| {
+    table.unlock_range(indices);
+    Ok(())
+  })
+}
+
+/// Call `f` with each present page table covering a page-aligned range of the
+/// current address space, along with the table indices that fall in the range
+fn for_each_table_in_range<F>(range: Range<VirtualAddress>, mut f: F) -> Result<(), ()>
+  where F: FnMut(usize, &mut PageTable, Range<usize>) -> Result<(), ()> {
+  let directory = PageTable::at_address(page_directory::get_current_page_address());
+  let mut page = range.start;
+  while page < range.end {
+    let dir_index = page.get_page_directory_index();
+    let table_start = page.get_page_table_index();
+    let table_end = if range.end.get_page_directory_index() == dir_index {
+      range.end.get_page_table_index()
+    } else {
+      TABLE_ENTRY_COUNT
+    };
+    if directory.get(dir_index).is_present() {
+      let table = PageTable::at_address(VirtualAddress::new(0xffc00000 + dir_index * 0x1000));
+      f(dir_index, table, table_start..table_end)?;
+    }
+    page = VirtualAddress::new((dir_index + 1) << 22);
+  }
+  Ok(())
+}
+
+/// Map frames received over IPC into consecutive pages of the current
+/// process, starting at a page-aligned address. The references owned by the
+/// message pass to the new page table entries.
+pub fn map_transferred_pages(start: VirtualAddress, frames: Vec<PhysicalAddress>) {
   let current_pagedir = page_directory::CurrentPageDirectory::get();
-  if let Some((frame, mapping)) = current_pagedir.unmap(address) {
-    free_frame(frame).unwrap();
+  for (index, address) in frames.into_iter().enumerate() {
+    let page = start + index * 0x1000;
+    current_pagedir.map(
+      AllocatedFrame::new(address),
+      page,
+      PermissionFlags::new(PermissionFlags::USER_ACCESS | PermissionFlags::WRITE_ACCESS),
+    );
+    invalidate_page(page);
   }
 }
 
-/// Unmap a task, removing its executable segments, stack, and heap
-pub fn unmap_task(exec_segments: Vec<ExecutionSegment>, heap_pages: Range<VirtualAddress>) {
+/// Fill new frames with a copy of `contents`, and map them read-only into a
+/// region already reserved in the current process. The kernel can still write
+/// to pages that are read-only for userspace, so they are filled after being
+/// mapped. If memory runs out partway through, the pages mapped so far are
+/// left for the caller to unmap.
+pub fn map_read_only_copy(start: VirtualAddress, contents: &[u8]) -> Result<(), ()> {
   let current_pagedir = page_directory::CurrentPageDirectory::get();
-  crate::kprintln!("Unmap Segments");
-  for segment in exec_segments.iter() {
-    let mut cur: VirtualAddress = segment.address;
-    let end: VirtualAddress = segment.address + segment.size;
+  for (index, chunk) in contents.chunks(0x1000).enumerate() {
+    let page = start + index * 0x1000;
+    let frame = allocate_user_frame().map_err(|_| ())?;
+    current_pagedir.map(frame, page, PermissionFlags::new(PermissionFlags::USER_ACCESS));
+    invalidate_page(page);
+    let buffer = unsafe {
+      zero_page(page);
+      core::slice::from_raw_parts_mut(page.as_usize() as *mut u8, 0x1000)
+    };
+    buffer[..chunk.len()].copy_from_slice(chunk);
+  }
+  Ok(())
+}
 
-    while cur < end {
-      if let Some((frame, mapping)) = current_pagedir.unmap(cur) {
-        free_frame(frame).unwrap();
-      }
-      cur = cur + 4096;
-    }
+/// Drop the references held by frames that were never mapped, like those of
+/// an IPC message that expired before it was read
+pub fn release_frames(frames: Vec<PhysicalAddress>) {
+  for address in frames {
+    free_frame(AllocatedFrame::new(address)).unwrap();
   }
-  // unmap stack
-  {
-    crate::kprintln!("Unmap Stack");
-    let mut cur = VirtualAddress::new(USER_KERNEL_BARRIER - STACK_SIZE);
-    let stack_end = VirtualAddress::new(USER_KERNEL_BARRIER);
-    while cur < stack_end {
-      if let Some((frame, mapping)) = current_pagedir.unmap(cur) {
-        free_frame(frame).unwrap();
-      }
-      cur = cur + 4096;
-    }
+}
+
+pub fn invalidate_page(addr: VirtualAddress) {
+  unsafe {
+    llvm_asm!("invlpg ($0)" : : "r"(addr.as_u32()));
   }
+}
 
-  // unmap heap
-  {
-    crate::kprintln!("Unmap Heap");
-    let mut cur = heap_pages.start;
-    let heap_end = heap_pages.end;
-    while cur < heap_end {
-      if let Some((frame, mapping)) = current_pagedir.unmap(cur) {
-        free_frame(frame).unwrap();
-      }
-      cur = cur + 4096;
+/// Unmap a single page, reducing COW counts as needed
+pub fn unmap_page(address: VirtualAddress) {
+  let current_pagedir = page_directory::CurrentPageDirectory::get();
+  if let Some(mapping) = current_pagedir.unmap(address) {
+    if mapping.should_reclaim() {
+      free_frame(AllocatedFrame::new(mapping.get_address())).unwrap();
     }
   }
 }
 
-pub fn unmap_terminated_task(pagedir_address: PhysicalAddress, kernel_stack: VirtualAddress) {
-  with_inactive_page_table(pagedir_address, |directory| {
-    // Iterate over all userspace entries and free the frames, if they should be
-    // reclaimed. This will cover all executable code, heap, stack, and mmap.
-    for dir_entry in 0..0x300 {
-      if !directory.get(dir_entry).is_present() {
-        continue;
-      }
-      let table_address = directory.get(dir_entry).get_address();
-      with_inactive_page_table(table_address, |table| {
-        for table_entry in 0..0x400 {
-          if !table.get(table_entry).is_present() || !table.get(table_entry).should_reclaim() {
-            continue;
+/// Unmap every page in a range of the current address space. Each present
+/// entry drops its reference to the underlying frame, which is only freed once
+/// no other process (like a fork sharing it copy-on-write) still maps it. The
+/// range is expected to be page-aligned.
+/// If a frame can't be released, the rest of the range is still unmapped, and
+/// the first error is returned once the whole range is gone.
+pub fn unmap_range(range: Range<VirtualAddress>) -> Result<(), BitmapError> {
+  let mut result = Ok(());
+  let directory = PageTable::at_address(page_directory::get_current_page_address());
+  let mut page = range.start;
+  while page < range.end {
+    let dir_index = page.get_page_directory_index();
+    let table_start = page.get_page_table_index();
+    let table_end = if range.end.get_page_directory_index() == dir_index {
+      range.end.get_page_table_index()
+    } else {
+      TABLE_ENTRY_COUNT
+    };
+    if directory.get(dir_index).is_present() {
+      let table = PageTable::at_address(VirtualAddress::new(0xffc00000 + dir_index * 0x1000));
+      table.release_range(table_start..table_end, |frame| {
+        if let Err(err) = free_frame(frame) {
+          if result.is_ok() {
+            result = Err(err);
           }
-          let frame_addr = table.get(table_entry).get_address();
-          let frame = AllocatedFrame::new(frame_addr);
-          free_frame(frame).unwrap();
         }
       });
-      // Clean up the page table itself
-      free_frame(AllocatedFrame::new(table_address)).unwrap();
     }
+    for index in table_start..table_end {
+      invalidate_page(VirtualAddress::new((dir_index << 22) | (index << 12)));
+    }
+    page = VirtualAddress::new((dir_index + 1) << 22);
+  }
+  result
+}
+
+/// Tear down all userspace mappings in the current page directory, in
+/// preparation for exec-ing a new program. This covers executable segments,
+/// heap, stack, and mmap regions alike. Each reclaimable frame has its
+/// reference dropped -- frames still shared with a fork through copy-on-write
+/// will stay allocated until the last process releases them -- and the page
+/// tables themselves are returned to the allocator.
+pub fn unmap_user_space() {
+  let directory = PageTable::at_address(page_directory::get_current_page_address());
+  release_user_space(
+    directory,
+    |dir_entry, _, release| {
+      release(PageTable::at_address(VirtualAddress::new(0xffc00000 + dir_entry * 0x1000)));
+    },
+    |frame| {
+      free_frame(frame).unwrap();
+    },
+  );
+  // Every userspace page has changed, so reload CR3 to flush the whole TLB
+  // rather than invalidating each page individually
+  page_directory::set_current_pagedir(page_directory::get_current_pagedir());
+}
 
-    crate::kprintln!("Free Kernel Stack at {:?}", kernel_stack);
+/// Another process starts running in a page directory, like a vfork child
+/// borrowing its parent's address space
+pub fn share_page_directory(pagedir_address: PhysicalAddress) {
+  crate::memory::physical::with_refcount(|refcount| {
+    super::address_space::share_directory(refcount, pagedir_address);
+  });
+}
+
+/// Stop using a page directory. If no other process is running in it, all of
+/// its userspace mappings are removed and the directory itself is freed.
+/// Returns true if the directory was freed.
+pub fn release_page_directory(pagedir_address: PhysicalAddress) -> bool {
+  let last_user = crate::memory::physical::with_refcount(|refcount| {
+    super::address_space::release_directory(refcount, pagedir_address)
+  });
+  if !last_user {
+    return false;
+  }
+  unmap_directory_user_space(pagedir_address);
+  free_frame(AllocatedFrame::new(pagedir_address)).unwrap();
+  true
+}
+
+/// Remove the userspace mappings of an inactive page directory
+fn unmap_directory_user_space(pagedir_address: PhysicalAddress) {
+  with_inactive_page_table(pagedir_address, |directory| {
+    // This covers all executable code, heap, stack, and mmap
+    release_user_space(
+      directory,
+      |_, table_address, release| {
+        with_inactive_page_table(table_address, |table| release(table));
+      },
+      |frame| {
+        free_frame(frame).unwrap();
+      },
+    );
+  });
+}
+
+/// Free the frames backing a terminated process's kernel stack
+pub fn unmap_kernel_stack(pagedir_address: PhysicalAddress, kernel_stack: VirtualAddress) {
+  with_inactive_page_table(pagedir_address, |directory| {
+    crate::kprintln!(Debug, Memory; "Free Kernel Stack at {:?}", kernel_stack);
     let kstack_dir_index = kernel_stack.get_page_directory_index();
     if !directory.get(kstack_dir_index).is_present() {
       return;
@@ -308,7 +587,7 @@ pub fn unmap_terminated_task(pagedir_address: PhysicalAddress, kernel_stack: Vir
 }
 
 pub fn with_inactive_page_table<F, R>(table_address: PhysicalAddress, f: F) -> R
-  where F: Fn(&mut PageTable) -> R {
+  where F: FnOnce(&mut PageTable) -> R {
   let table_scratch_space = UnmappedPage::map(table_address);
   let mut table = PageTable::at_address(
     table_scratch_space.virtual_address(),
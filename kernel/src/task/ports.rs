@@ -0,0 +1,202 @@
+//! Named ports let processes find each other without knowing PIDs. A service
+//! registers a port under a well-known name, and clients send messages to
+//! that name. Each port has its own bounded queue, separate from the owner's
+//! regular IPC queue, which only the owner can read from.
+//! A port lives as long as the process that registered it. Ports are removed
+//! when their owner exits, and a send that finds the owner already gone
+//! removes the port as well, so the name can be registered again.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use crate::locks::RwLock;
+use syscall::result::SystemError;
+use super::id::ProcessID;
+use super::ipc::IPCMessage;
+
+/// Messages that can wait on a port before sends start failing
+pub const PORT_QUEUE_CAPACITY: usize = 16;
+
+pub const MAX_PORT_NAME_LENGTH: usize = 32;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PortError {
+  /// Another live process already registered the name
+  NameTaken,
+  /// The name is empty or too long
+  InvalidName,
+  /// No port has been registered with the name
+  NoSuchPort,
+  /// The process that registered the port has exited
+  OwnerGone,
+  /// The port's queue is full
+  QueueFull,
+  /// Only the owner of a port can receive from it
+  NotOwner,
+}
+
+impl PortError {
+  pub fn to_system_error(&self) -> SystemError {
+    match self {
+      PortError::NameTaken => SystemError::AlreadyExists,
+      PortError::InvalidName => SystemError::InvalidArgument,
+      PortError::NoSuchPort => SystemError::NoSuchEntity,
+      PortError::OwnerGone => SystemError::RecipientGone,
+      PortError::QueueFull => SystemError::WouldBlock,
+      PortError::NotOwner => SystemError::PermissionDenied,
+    }
+  }
+}
+
+struct Port {
+  owner: ProcessID,
+  queue: VecDeque<(ProcessID, IPCMessage)>,
+}
+
+pub struct PortRegistry {
+  ports: RwLock<BTreeMap<String, Port>>,
+}
+
+impl PortRegistry {
+  pub const fn new() -> Self {
+    Self {
+      ports: RwLock::new(BTreeMap::new()),
+    }
+  }
+
+  /// Claim a name for a process. A name left behind by a process that has
+  /// since exited can be claimed again.
+  pub fn register<F>(&self, name: &str, owner: ProcessID, is_alive: F) -> Result<(), PortError>
+    where F: Fn(ProcessID) -> bool {
+    if name.is_empty() || name.len() > MAX_PORT_NAME_LENGTH {
+      return Err(PortError::InvalidName);
+    }
+    let mut ports = self.ports.write();
+    if let Some(existing) = ports.get(name) {
+      if is_alive(existing.owner) {
+        return Err(PortError::NameTaken);
+      }
+    }
+    ports.insert(String::from(name), Port { owner, queue: VecDeque::new() });
+    Ok(())
+  }
+
+  /// Queue a message on a port. If the owner has exited, the port is removed
+  /// and the send fails.
+  pub fn send<F>(&self, name: &str, from: ProcessID, message: IPCMessage, is_alive: F) -> Result<(), PortError>
+    where F: Fn(ProcessID) -> bool {
+    let mut ports = self.ports.write();
+    let port = ports.get_mut(name).ok_or(PortError::NoSuchPort)?;
+    if !is_alive(port.owner) {
+      ports.remove(name);
+      return Err(PortError::OwnerGone);
+    }
+    if port.queue.len() >= PORT_QUEUE_CAPACITY {
+      return Err(PortError::QueueFull);
+    }
+    port.queue.push_back((from, message));
+    Ok(())
+  }
+
+  /// Take the oldest message from a port, along with the ID of its sender.
+  /// Resolves with `None` if the queue is empty.
+  pub fn receive(&self, name: &str, receiver: ProcessID) -> Result<Option<(ProcessID, IPCMessage)>, PortError> {
+    let mut ports = self.ports.write();
+    let port = ports.get_mut(name).ok_or(PortError::NoSuchPort)?;
+    if port.owner != receiver {
+      return Err(PortError::NotOwner);
+    }
+    Ok(port.queue.pop_front())
+  }
+
+  /// Remove every port owned by a process, discarding any queued messages.
+  /// Returns the number of ports removed.
+  pub fn remove_ports_for_process(&self, owner: ProcessID) -> usize {
+    let mut ports = self.ports.write();
+    let before = ports.len();
+    ports.retain(|_, port| port.owner != owner);
+    before - ports.len()
+  }
+}
+
+pub static PORTS: PortRegistry = PortRegistry::new();
+
+#[cfg(test)]
+mod tests {
+  use super::{IPCMessage, PortError, PortRegistry, ProcessID, PORT_QUEUE_CAPACITY};
+
+  fn alive(_id: ProcessID) -> bool {
+    true
+  }
+
+  #[test]
+  fn registration() {
+    let registry = PortRegistry::new();
+    let server = ProcessID::new(4);
+    registry.register("LOGGER", server, alive).unwrap();
+    assert_eq!(registry.register("LOGGER", ProcessID::new(5), alive), Err(PortError::NameTaken));
+    assert_eq!(registry.register("", server, alive), Err(PortError::InvalidName));
+    // Once the owner has exited, the name is free again
+    registry.register("LOGGER", ProcessID::new(5), |id| id != server).unwrap();
+
+    assert_eq!(registry.remove_ports_for_process(ProcessID::new(5)), 1);
+    assert_eq!(registry.receive("LOGGER", ProcessID::new(5)), Err(PortError::NoSuchPort));
+  }
+
+  #[test]
+  fn send_to_exited_owner() {
+    let registry = PortRegistry::new();
+    let server = ProcessID::new(4);
+    let client = ProcessID::new(6);
+    registry.register("DISK", server, alive).unwrap();
+    for i in 0..PORT_QUEUE_CAPACITY {
+      registry.send("DISK", client, IPCMessage(i as u32, 0, 0, 0), alive).unwrap();
+    }
+    assert_eq!(registry.send("DISK", client, IPCMessage(0, 0, 0, 0), alive), Err(PortError::QueueFull));
+    assert_eq!(registry.receive("DISK", client), Err(PortError::NotOwner));
+
+    assert_eq!(
+      registry.send("DISK", client, IPCMessage(0, 0, 0, 0), |id| id != server),
+      Err(PortError::OwnerGone),
+    );
+    // The dead port was removed
+    assert_eq!(registry.send("DISK", client, IPCMessage(0, 0, 0, 0), alive), Err(PortError::NoSuchPort));
+  }
+
+  #[test]
+  fn cross_process_port() {
+    extern crate std;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use std::thread;
+
+    let registry = Arc::new(PortRegistry::new());
+    let server = ProcessID::new(10);
+    registry.register("ECHO", server, alive).unwrap();
+
+    let clients: Vec<_> = (0..3).map(|i| {
+      let registry = registry.clone();
+      thread::spawn(move || {
+        let client = ProcessID::new(20 + i);
+        registry.send("ECHO", client, IPCMessage(i, 1, 2, 3), alive).unwrap();
+      })
+    }).collect();
+
+    let mut received = Vec::new();
+    while received.len() < 3 {
+      match registry.receive("ECHO", server).unwrap() {
+        Some((from, message)) => {
+          // The sender is identified, even though it never needed the
+          // server's ID
+          assert_eq!(from, ProcessID::new(20 + message.0));
+          received.push(message.0);
+        },
+        None => thread::yield_now(),
+      }
+    }
+    for client in clients {
+      client.join().unwrap();
+    }
+    received.sort();
+    assert_eq!(received, [0, 1, 2]);
+  }
+}
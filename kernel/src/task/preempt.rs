@@ -0,0 +1,128 @@
+//! The timer interrupt can switch away from whatever is running, including
+//! kernel code in the middle of a syscall. Switching away while a kernel lock
+//! is held would leave every other process spinning on that lock, so every
+//! lock type in `crate::locks` disables preemption for as long as it is held.
+//! Preemption is tracked with a single kernel-wide depth counter, since only
+//! one execution context runs at a time. When the timer fires while the count
+//! is above zero, the switch is remembered rather than performed, and happens
+//! as soon as the count drops back to zero. The count must be zero whenever a
+//! context switch happens, because the next process resumes with the same
+//! counter.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+pub struct PreemptionCounter {
+  depth: AtomicUsize,
+  /// Set when the timer wanted to switch while preemption was disabled
+  pending: AtomicBool,
+  /// Performs a deferred switch
+  reschedule: fn(),
+}
+
+impl PreemptionCounter {
+  pub const fn new(reschedule: fn()) -> Self {
+    Self {
+      depth: AtomicUsize::new(0),
+      pending: AtomicBool::new(false),
+      reschedule,
+    }
+  }
+
+  /// Disable preemption until the returned guard is dropped. Guards can be
+  /// nested.
+  pub fn disable(&self) -> PreemptGuard {
+    self.depth.fetch_add(1, Ordering::SeqCst);
+    PreemptGuard { counter: self }
+  }
+
+  fn enable(&self) {
+    let previous = self.depth.fetch_sub(1, Ordering::SeqCst);
+    if previous == 1 && self.pending.swap(false, Ordering::SeqCst) {
+      (self.reschedule)();
+    }
+  }
+
+  pub fn is_enabled(&self) -> bool {
+    self.depth.load(Ordering::SeqCst) == 0
+  }
+
+  /// Called by the timer when the running process should give up the CPU.
+  /// Returns true if it is safe to switch right away. Otherwise, the switch
+  /// is deferred until preemption is enabled again.
+  pub fn request_reschedule(&self) -> bool {
+    if self.is_enabled() {
+      return true;
+    }
+    self.pending.store(true, Ordering::SeqCst);
+    false
+  }
+
+  /// Forget a deferred switch. Called when switching for any other reason,
+  /// since the running process has given up the CPU either way.
+  pub fn clear_pending(&self) {
+    self.pending.store(false, Ordering::SeqCst);
+  }
+}
+
+/// Keeps preemption disabled until it is dropped
+pub struct PreemptGuard<'counter> {
+  counter: &'counter PreemptionCounter,
+}
+
+impl Drop for PreemptGuard<'_> {
+  fn drop(&mut self) {
+    self.counter.enable();
+  }
+}
+
+pub static PREEMPTION: PreemptionCounter = PreemptionCounter::new(super::yield_coop);
+
+/// Disable preemption for a critical section, until the guard is dropped
+pub fn disable_preemption() -> PreemptGuard<'static> {
+  PREEMPTION.disable()
+}
+
+/// Run a critical section that can't be switched away from
+pub fn without_preemption<F, R>(f: F) -> R
+  where F: FnOnce() -> R {
+  let _guard = disable_preemption();
+  f()
+}
+
+#[cfg(test)]
+mod tests {
+  use core::sync::atomic::{AtomicUsize, Ordering};
+  use super::PreemptionCounter;
+
+  static SWITCHES: AtomicUsize = AtomicUsize::new(0);
+
+  fn count_switch() {
+    SWITCHES.fetch_add(1, Ordering::SeqCst);
+  }
+
+  #[test]
+  fn deferred_reschedule() {
+    let counter = PreemptionCounter::new(count_switch);
+    assert!(counter.request_reschedule());
+
+    let outer = counter.disable();
+    let inner = counter.disable();
+    assert!(!counter.request_reschedule());
+    drop(inner);
+    // Still inside the outer critical section
+    assert_eq!(SWITCHES.load(Ordering::SeqCst), 0);
+    drop(outer);
+    assert_eq!(SWITCHES.load(Ordering::SeqCst), 1);
+
+    // Without a timer request, leaving the section doesn't switch
+    drop(counter.disable());
+    assert_eq!(SWITCHES.load(Ordering::SeqCst), 1);
+
+    // A voluntary switch satisfies the request
+    let guard = counter.disable();
+    counter.request_reschedule();
+    counter.clear_pending();
+    drop(guard);
+    assert_eq!(SWITCHES.load(Ordering::SeqCst), 1);
+  }
+}
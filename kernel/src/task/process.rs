@@ -1,32 +1,116 @@
 use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use crate::files::handle::{FileHandle, Handle, LocalHandle};
 use crate::fs::drive::DriveID;
-use crate::memory::address::VirtualAddress;
+use crate::memory::address::{PhysicalAddress, VirtualAddress};
 use crate::memory::virt::page_table::PageTableReference;
+use crate::time::ticks;
+use super::environment::Environment;
 use super::files::{FileMap, OpenFile};
 use super::id::ProcessID;
+use super::io_ports::IOPortPermissions;
 use super::ipc::{IPCMessage, IPCPacket, IPCQueue};
-use super::memory::{ExecutionSegment, MemoryRegions, Relocation};
+use super::limits::{Limit, Resource, ResourceLimits};
+use super::memory::{ExecutionSegment, MMapBacking, MemoryRegions, Relocation, USER_KERNEL_BARRIER};
 use super::regs::SavedState;
+use super::scheduler::RUN_QUEUE;
+use super::signal::SignalState;
 use super::state::RunState;
 use super::vm::Subsystem;
+use syscall::flags::{WCONTINUED, WNOWAIT, WUNTRACED};
+use syscall::result::SystemError;
+use crate::locks::RwLock;
+use syscall::signals::{STATUS_CONTINUED, STATUS_STOPPED};
 
 pub const MAX_PROCESS_COUNT: usize = 256 * 64 - 1;
 
+/// Everything a new program needs installed in a process. It is gathered
+/// before any part of the current program is torn down, so that an exec which
+/// fails while loading leaves the process exactly as it was.
+pub struct ExecImage {
+  pub segments: Vec<ExecutionSegment>,
+  pub relocations: Vec<Relocation>,
+  pub exec_file: (DriveID, LocalHandle),
+  pub exec_path: String,
+  pub require_vm: bool,
+  pub initial_heap_size: usize,
+  pub arguments: Vec<String>,
+}
+
+/// Resources of the previous program, released by a committed exec. The
+/// caller closes them once it no longer holds the process lock.
+pub struct ReplacedImage {
+  pub exec_file: Option<(DriveID, LocalHandle)>,
+  pub uninherited: Vec<OpenFile>,
+}
+
+/// Which children a wait is interested in
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum WaitTarget {
+  AnyChild,
+  Child(ProcessID),
+  /// Any child that was in the process group when it changed state
+  Group(ProcessID),
+}
+
+impl WaitTarget {
+  /// Wait on a specific child, or on any child
+  pub fn from_child(child_id: Option<ProcessID>) -> WaitTarget {
+    match child_id {
+      Some(id) => WaitTarget::Child(id),
+      None => WaitTarget::AnyChild,
+    }
+  }
+
+  fn matches(&self, id: ProcessID, group: ProcessID) -> bool {
+    match self {
+      WaitTarget::AnyChild => true,
+      WaitTarget::Child(expected) => *expected == id,
+      WaitTarget::Group(expected) => *expected == group,
+    }
+  }
+}
+
+/// A change in a child's state that hasn't been collected by a wait yet
+#[derive(Copy, Clone)]
+struct ChildChange {
+  id: ProcessID,
+  /// The child's process group at the time of the change
+  group: ProcessID,
+  status: u32,
+}
+
 pub struct Process {
   /// The unique ID of this process
   id: ProcessID,
   /// The ID of the parent process
   parent_id: ProcessID,
+  /// The process group used for job control. A group leader's group ID is
+  /// the same as its own ID.
+  process_group: ProcessID,
   /// Stores the details of all addresses mapped into the process's memory.
   /// When a page fault occurs, this information is used to determine how
   /// content is paged into memory, or if it's a crash-causing fault.
   pub memory: MemoryRegions,
   /// Represents the current execution state of the process
   state: RunState,
+  /// Set while the process is stopped by a signal. Stopping leaves `state`
+  /// alone, so that whatever the process was doing when it stopped (sleeping,
+  /// waiting on a child, blocked on IO) carries on once it continues, and
+  /// wakeups that arrive in between aren't lost.
+  stopped: bool,
+  /// Set while the process is tracked by the scheduler's run queue. Every
+  /// state change needs to be reflected there.
+  scheduled: bool,
   /// The number of system ticks when this process was started
   start_ticks: u32,
+  /// Timer ticks that fired while this process was running
+  cpu_ticks: u32,
+  /// The most user pages that were backed by physical memory at once
+  peak_resident_pages: usize,
   /// Stores IPC messages that have been sent to this process
   ipc_queue: IPCQueue,
   /// Stores references to all currently open files
@@ -49,6 +133,12 @@ pub struct Process {
   pub page_directory: PageTableReference,
   /// Reference to the open file being executed by this process
   exec_file: Option<(DriveID, LocalHandle)>,
+  /// Full path of the executable, as resolved when it was loaded
+  exec_path: Option<String>,
+  /// Environment variables, inherited from the parent and kept across exec
+  environment: Environment,
+  /// Arguments the current program was started with, beginning with its path
+  arguments: Vec<String>,
   /// Stores the relocation data necessary for setting up the executable file in
   /// memory.
   relocations: Vec<Relocation>,
@@ -60,6 +150,59 @@ pub struct Process {
   vterm: Option<usize>,
   /// Points to the drive of the current working dir
   pub current_drive: DriveID,
+  /// Like DOS, each drive has its own working directory. Drives without an
+  /// entry are at their root.
+  working_directories: BTreeMap<DriveID, String>,
+  /// If this process was created by vfork and has not yet called exec, it is
+  /// borrowing the address space of this parent process.
+  vfork_parent: Option<ProcessID>,
+  /// Installed signal handlers, and the state of any that are running
+  pub signals: SignalState,
+  /// Children that have stopped or continued since the last time a wait
+  /// reported them, along with their status codes
+  child_status_changes: Vec<ChildChange>,
+  /// Children whose exit was reported to a WNOWAIT wait. They stay here until
+  /// a wait without WNOWAIT collects them.
+  peeked_exits: Vec<ChildChange>,
+  /// The WUNTRACED / WCONTINUED / WNOWAIT flags of the current wait
+  wait_options: u32,
+  /// The children the current wait is interested in
+  wait_target: WaitTarget,
+  /// The child whose status ended the most recent wait
+  waited_child: Option<ProcessID>,
+  /// How many children were reaped without being reported, because SIGCHLD
+  /// was ignored. A wait compares this before and after checking for live
+  /// children, to notice one that exited in between.
+  reaped_children: u32,
+  /// Kernel processes, like init and the drivers, are never chosen by the
+  /// OOM killer. The protection is dropped once the process execs a program.
+  oom_protected: bool,
+  /// Privileged processes can unmount drives and raise their hard limits.
+  /// Init starts out privileged and children inherit the flag, but once it is
+  /// dropped it can't be regained.
+  privileged: bool,
+  /// Scheduling priority, from NICE_MIN to NICE_MAX. Lower values get longer
+  /// time slices. Children inherit their parent's value.
+  nice: i32,
+  /// Caps on open files, stack size, and heap size. Children inherit their
+  /// parent's limits.
+  limits: ResourceLimits,
+  /// I/O ports the process has been granted as a driver. Grants aren't
+  /// inherited by children, and are dropped on exec.
+  io_ports: IOPortPermissions,
+  /// When sleeping until a wall-clock time, that time in 100ns increments.
+  /// The wake tick is recomputed from it if the system clock is re-synced.
+  wake_time: Option<u64>,
+  /// Set when the current sleep was requested by a syscall, and can be cut
+  /// short by a signal. Sleeps inside the kernel, like waiting for a floppy
+  /// motor to spin up, run to completion.
+  interruptible_sleep: bool,
+  /// The process most recently sent an IPC message, which it may be waiting
+  /// on a reply from
+  ipc_awaiting_reply: Option<ProcessID>,
+  /// Set when that process exits without replying. A blocking IPC read
+  /// returns an error instead of waiting forever.
+  ipc_recipient_gone: Option<ProcessID>,
 }
 
 impl Process {
@@ -71,9 +214,14 @@ impl Process {
     Self {
       id: ProcessID::new(0),
       parent_id: ProcessID::new(0),
+      process_group: ProcessID::new(0),
       memory: MemoryRegions::new(),
       state: RunState::Running,
+      stopped: false,
+      scheduled: false,
       start_ticks: current_ticks,
+      cpu_ticks: 0,
+      peak_resident_pages: 0,
       ipc_queue: IPCQueue::new(),
       open_files: FileMap::with_capacity(3),
       kernel_stack: Some(kernel_stack),
@@ -81,11 +229,32 @@ impl Process {
       saved_state: SavedState::empty(),
       page_directory: PageTableReference::current(),
       exec_file: None,
+      exec_path: None,
+      environment: Environment::new(),
+      arguments: Vec::new(),
       relocations: Vec::new(),
       subsystem: Subsystem::Native,
       on_exit_vm: None,
       vterm: None,
       current_drive: DriveID::initial(),
+      working_directories: BTreeMap::new(),
+      vfork_parent: None,
+      signals: SignalState::new(),
+      child_status_changes: Vec::new(),
+      peeked_exits: Vec::new(),
+      wait_options: 0,
+      wait_target: WaitTarget::AnyChild,
+      waited_child: None,
+      reaped_children: 0,
+      oom_protected: true,
+      privileged: true,
+      nice: 0,
+      limits: ResourceLimits::new(),
+      io_ports: IOPortPermissions::new(),
+      wake_time: None,
+      interruptible_sleep: false,
+      ipc_awaiting_reply: None,
+      ipc_recipient_gone: None,
     }
   }
 
@@ -97,24 +266,104 @@ impl Process {
     &self.parent_id
   }
 
+  pub fn get_process_group(&self) -> ProcessID {
+    self.process_group
+  }
+
+  /// Move the process into another group. Forked children stay in their
+  /// parent's group until this is called.
+  pub fn set_process_group(&mut self, group: ProcessID) {
+    self.process_group = group;
+  }
+
+  /// Check if the process is a live member of a group, and should receive
+  /// signals sent to that group
+  pub fn is_in_group(&self, group: ProcessID) -> bool {
+    self.process_group == group && !self.is_terminated()
+  }
+
   pub fn get_exec_file(&self) -> Option<(DriveID, LocalHandle)> {
     self.exec_file
   }
 
+  /// Get the working directory on a drive, relative to its root. The root
+  /// itself is an empty string.
+  pub fn get_working_directory(&self, drive: DriveID) -> &str {
+    self.working_directories.get(&drive).map_or("", |path| path.as_str())
+  }
+
+  pub fn set_working_directory(&mut self, drive: DriveID, path: &str) {
+    if path.is_empty() {
+      self.working_directories.remove(&drive);
+    } else {
+      self.working_directories.insert(drive, String::from(path));
+    }
+  }
+
   /// Based on the current system time in ticks, how long has this process been
   /// running?
   pub fn uptime_ticks(&self, current_ticks: u32) -> u32 {
     current_ticks - self.start_ticks
   }
 
+  /// Charge a timer tick to this process, which was running when it fired
+  pub fn charge_cpu_tick(&mut self) {
+    self.cpu_ticks = self.cpu_ticks.wrapping_add(1);
+  }
+
+  pub fn get_cpu_ticks(&self) -> u32 {
+    self.cpu_ticks
+  }
+
+  /// Record how many pages are resident. Memory only shrinks when pages are
+  /// released, so sampling right before each release catches the peak.
+  pub fn note_resident_pages(&mut self, pages: usize) {
+    self.peak_resident_pages = self.peak_resident_pages.max(pages);
+  }
+
+  pub fn get_peak_resident_pages(&self) -> usize {
+    self.peak_resident_pages
+  }
+
   /// Determine if the scheduler can re-enter this process
   pub fn can_resume(&self) -> bool {
+    if self.stopped {
+      return false;
+    }
     match self.state {
       RunState::Running | RunState::Resumed(_) => true,
       _ => false,
     }
   }
 
+  /// Every change to the run state goes through here, so that the run queue
+  /// always knows whether the process can be scheduled
+  fn set_state(&mut self, state: RunState) {
+    self.state = state;
+    if self.scheduled {
+      RUN_QUEUE.update(self.id, self.can_resume());
+    }
+  }
+
+  /// Hand the process to the scheduler. This should happen once the process
+  /// has been added to the task map, since the scheduler may switch to it at
+  /// any time afterwards.
+  pub fn enter_run_queue(&mut self) {
+    if !self.scheduled {
+      self.scheduled = true;
+      RUN_QUEUE.register(self.id, self.can_resume());
+    }
+  }
+
+  /// Stop the scheduler from considering this process, before it is removed
+  /// from the task map
+  pub fn leave_run_queue(&mut self) {
+    if self.scheduled {
+      self.scheduled = false;
+      RUN_QUEUE.unregister(self.id);
+    }
+  }
+
   pub fn is_terminated(&self) -> bool {
     match self.state {
       RunState::Terminated => true,
@@ -186,21 +435,6 @@ impl Process {
     stack[offset + 3] = ((value & 0xff000000) >> 24) as u8;
   }
 
-  /// Used to force a kernel process into usermode. This should only be used
-  /// for testing, and not in the real kernel.
-  pub fn set_usermode_entrypoint(&mut self, func: extern fn(), esp: u32) {
-    // Stack segment
-    self.stack_push_u32(0x23);
-    // Stack pointer
-    self.stack_push_u32(esp);
-    // eflags
-    self.stack_push_u32(0x200); // Interrupts enabled
-    // Code segment
-    self.stack_push_u32(0x1b);
-    // Instruction pointer
-    self.stack_push_u32(func as u32 & 0x3fffffff);
-  }
-
   /// Force a process to think it was started by the specified vterm. This is
   /// used for the initial process in each vterm.
   pub fn force_vterm(&mut self, index: usize) {
@@ -211,60 +445,276 @@ impl Process {
     self.vterm
   }
 
+  /// Keep the OOM killer from choosing this process
+  pub fn protect_from_oom(&mut self) {
+    self.oom_protected = true;
+  }
+
+  pub fn is_oom_protected(&self) -> bool {
+    self.oom_protected
+  }
+
+  pub fn get_nice(&self) -> i32 {
+    self.nice
+  }
+
+  /// Change the nice value by a relative amount, returning the new value
+  pub fn adjust_nice(&mut self, increment: i32) -> i32 {
+    self.nice = super::scheduler::adjust_nice(self.nice, increment);
+    self.nice
+  }
+
   /// End all execution of the process, and mark its resources for cleanup.
   pub fn terminate(&mut self) {
-    self.state = RunState::Terminated;
+    self.set_state(RunState::Terminated);
   }
 
   /// Pause this process for a specified number of milliseconds. When the
-  /// duration has passed, the process's state will return to Running.
-  pub fn sleep(&mut self, duration: usize) {
-    self.state = RunState::Sleeping(duration);
+  /// duration has passed, the process's state will return to Running. A
+  /// duration of zero leaves the process runnable.
+  pub fn sleep(&mut self, current_ticks: u32, duration: usize) {
+    if duration == 0 {
+      return;
+    }
+    self.wake_time = None;
+    self.interruptible_sleep = false;
+    self.set_state(RunState::Sleeping(ticks::target_tick(current_ticks, duration)));
+  }
+
+  /// Like `sleep`, but a signal with a handler wakes the process early
+  pub fn sleep_interruptibly(&mut self, current_ticks: u32, duration: usize) {
+    if duration == 0 {
+      return;
+    }
+    self.wake_time = None;
+    self.interruptible_sleep = true;
+    self.block_interruptibly(RunState::Sleeping(ticks::target_tick(current_ticks, duration)));
+  }
+
+  /// Pause this process until the system clock reaches `wake_time`. Both times
+  /// are measured in 100ns increments since the epoch. The wait is converted
+  /// to a wake tick, like a relative sleep; if the time has already passed,
+  /// the process remains runnable.
+  pub fn sleep_until(&mut self, current_ticks: u32, current_time: u64, wake_time: u64) {
+    let remaining = ticks::ticks_until(current_time, wake_time);
+    if remaining == 0 {
+      return;
+    }
+    self.wake_time = Some(wake_time);
+    self.interruptible_sleep = true;
+    self.block_interruptibly(RunState::Sleeping(current_ticks.wrapping_add(remaining)));
+  }
+
+  /// After the system clock has been set to a new time, move the wake tick of
+  /// a process sleeping until a wall-clock time, so that it still wakes when
+  /// the clock reaches that time. Relative sleeps are unaffected.
+  pub fn resync_wake_time(&mut self, current_ticks: u32, current_time: u64) {
+    let wake_time = match (self.state, self.wake_time) {
+      (RunState::Sleeping(_), Some(wake_time)) => wake_time,
+      _ => return,
+    };
+    let remaining = ticks::ticks_until(current_time, wake_time);
+    self.set_state(if remaining == 0 {
+      RunState::Running
+    } else {
+      RunState::Sleeping(current_ticks.wrapping_add(remaining))
+    });
   }
 
   /// Pause the process due to a signal. It will not resume until woken by
   /// a different signal.
   pub fn pause(&mut self) {
-    self.state = RunState::Paused;
+    self.stopped = true;
+    self.set_state(self.state);
   }
 
-  /// Resume the process due to a signal. If the process is not explicitly
-  /// paused, this is a no-op.
+  /// Resume the process due to a signal, returning it to the state it was in
+  /// when it was paused. If the process is not explicitly paused, this is a
+  /// no-op.
   pub fn resume(&mut self) {
+    if self.stopped {
+      self.stopped = false;
+      self.set_state(self.state);
+    }
+  }
+
+  /// Enter a blocking state on behalf of a syscall. If a signal with a
+  /// handler is already waiting, the process stays runnable, so that the
+  /// syscall notices it right away instead of once the wait ends.
+  fn block_interruptibly(&mut self, state: RunState) {
+    if !self.signals.has_pending() {
+      self.set_state(state);
+    }
+  }
+
+  /// A signal with a handler has arrived. If the process is blocked in a
+  /// syscall, wake it so that the syscall can give up and let the handler
+  /// run. Stopped processes stay stopped, and waits on hardware or on a
+  /// vfork child always finish, since giving up on them early would leave a
+  /// device or borrowed address space in the middle of an operation.
+  pub fn wake_for_signal(&mut self) {
     match self.state {
-      RunState::Paused => self.state = RunState::Running,
+      RunState::Sleeping(_) if !self.interruptible_sleep => (),
+      RunState::Sleeping(_) | RunState::AwaitingIPC(_) | RunState::WaitingForChild(_) | RunState::FileIO(_) => {
+        self.set_state(RunState::Running);
+      },
       _ => (),
     }
   }
 
+  /// Determine if the process has been stopped by a signal
+  pub fn is_paused(&self) -> bool {
+    self.stopped
+  }
+
   pub fn wait(&mut self, child_id: Option<ProcessID>) {
-    self.state = RunState::WaitingForChild(child_id);
+    self.wait_with_options(child_id, 0);
+  }
+
+  /// Block until a child exits. With WUNTRACED or WCONTINUED in `options`,
+  /// children that stop or continue also end the wait. If one of those
+  /// changes is already waiting to be reported, the process resumes
+  /// immediately.
+  pub fn wait_with_options(&mut self, child_id: Option<ProcessID>, options: u32) {
+    self.wait_for(WaitTarget::from_child(child_id), options);
+  }
+
+  /// Block until a child matching the target exits, or stops or continues if
+  /// the options ask for it. With WNOWAIT, the reported change is left in
+  /// place, so that a later wait reports it again.
+  pub fn wait_for(&mut self, target: WaitTarget, options: u32) {
+    self.wait_options = options;
+    self.wait_target = target;
+    self.waited_child = None;
+    if let Some(change) = self.take_child_change(target, options) {
+      self.waited_child = Some(change.id);
+      self.set_state(RunState::Resumed(change.status));
+      return;
+    }
+    let child_id = match target {
+      WaitTarget::Child(id) => Some(id),
+      _ => None,
+    };
+    self.block_interruptibly(RunState::WaitingForChild(child_id));
+  }
+
+  /// Like `wait_for`, but fails instead of blocking when nothing could ever
+  /// end the wait: no uncollected change matches the target, and
+  /// `has_live_child` says that no running child does either.
+  pub fn wait_for_live(&mut self, target: WaitTarget, options: u32, has_live_child: bool) -> Result<(), SystemError> {
+    if !has_live_child && self.take_child_change(target, options | WNOWAIT).is_none() {
+      return Err(SystemError::NoSuchEntity);
+    }
+    self.wait_for(target, options);
+    Ok(())
+  }
+
+  /// Count of children reaped without their exit being reported
+  pub fn reaped_children(&self) -> u32 {
+    self.reaped_children
+  }
+
+  /// Whether this is a running child of `parent` that could end a wait for
+  /// `target`
+  pub fn is_live_child_of(&self, parent: ProcessID, target: WaitTarget) -> bool {
+    self.parent_id == parent && !self.is_terminated() && target.matches(self.id, self.process_group)
+  }
+
+  /// Collect the status that ended a wait. Returns None if there is nothing
+  /// to report, because a signal interrupted the wait.
+  pub fn finish_wait(&mut self) -> Option<u32> {
+    match self.state {
+      RunState::Resumed(_) => Some(self.resume_from_wait()),
+      _ => None,
+    }
   }
 
   pub fn resume_from_wait(&mut self) -> u32 {
     match self.state {
       RunState::Resumed(code) => {
-        self.state = RunState::Running;
+        self.set_state(RunState::Running);
         return code;
       },
       _ => 0,
     }
   }
 
-  /// Tell a process that a child has exited. If the process is currently
-  /// waiting on that child, it will resume execution.
-  pub fn child_returned(&mut self, child_id: ProcessID, code: u32) {
-    let waiting_on = match self.state {
-      RunState::WaitingForChild(id) => id,
-      _ => return,
-    };
-    match waiting_on {
-      None => self.state = RunState::Resumed(code),
-      Some(id) if id == child_id => self.state = RunState::Resumed(code),
-      _ => (),
+  /// The child that ended the most recent wait, if known
+  pub fn take_waited_child(&mut self) -> Option<ProcessID> {
+    self.waited_child.take()
+  }
+
+  fn is_waiting_on(&self, child_id: ProcessID, group: ProcessID) -> bool {
+    match self.state {
+      RunState::WaitingForChild(_) => self.wait_target.matches(child_id, group),
+      _ => false,
     }
   }
 
+  /// Handle the exit of a child in `group`. A process that ignores SIGCHLD
+  /// doesn't keep the exit status of its children: no wait ever reports it,
+  /// and the child can be torn down right away. Returns true in that case.
+  /// A wait that matched the child is woken without a status, so that it can
+  /// check whether any other child could still end it.
+  pub fn child_exited(&mut self, child_id: ProcessID, group: ProcessID, code: u32) -> bool {
+    if self.signals.is_ignored(syscall::signals::CHILD) {
+      self.child_status_changes.retain(|change| change.id != child_id);
+      self.reaped_children = self.reaped_children.wrapping_add(1);
+      if self.is_waiting_on(child_id, group) {
+        self.set_state(RunState::Running);
+      }
+      return true;
+    }
+    self.child_returned(child_id, group, code);
+    false
+  }
+
+  /// Tell a process that a child in `group` has exited. If the process is
+  /// currently waiting on that child, it will resume execution. Exits that
+  /// nobody is waiting for are not kept, unless a WNOWAIT wait has already
+  /// seen them.
+  pub fn child_returned(&mut self, child_id: ProcessID, group: ProcessID, code: u32) {
+    // A stop or continue that was never reported is meaningless now
+    self.child_status_changes.retain(|change| change.id != child_id);
+    if self.is_waiting_on(child_id, group) {
+      self.waited_child = Some(child_id);
+      self.set_state(RunState::Resumed(code));
+      if self.wait_options & WNOWAIT != 0 {
+        self.peeked_exits.push(ChildChange { id: child_id, group, status: code });
+      }
+    }
+  }
+
+  /// Tell a process that a child has stopped or continued. The change is
+  /// reported to a wait that asked for it; otherwise it is kept until a later
+  /// wait does. Each child only keeps its most recent change, so a stop that
+  /// is followed by a continue is never reported.
+  pub fn child_status_changed(&mut self, child_id: ProcessID, group: ProcessID, status: u32) {
+    self.child_status_changes.retain(|change| change.id != child_id);
+    if self.is_waiting_on(child_id, group) && wait_reports(self.wait_options, status) {
+      self.waited_child = Some(child_id);
+      self.set_state(RunState::Resumed(status));
+      if self.wait_options & WNOWAIT == 0 {
+        return;
+      }
+    }
+    self.child_status_changes.push(ChildChange { id: child_id, group, status });
+  }
+
+  /// Find the oldest uncollected change that a wait with these options would
+  /// report. It is removed, unless the wait only peeks with WNOWAIT.
+  fn take_child_change(&mut self, target: WaitTarget, options: u32) -> Option<ChildChange> {
+    let peek = options & WNOWAIT != 0;
+    let exit = self.peeked_exits.iter().position(|change| target.matches(change.id, change.group));
+    if let Some(index) = exit {
+      return Some(if peek { self.peeked_exits[index] } else { self.peeked_exits.remove(index) });
+    }
+    let index = self.child_status_changes.iter().position(|change| {
+      target.matches(change.id, change.group) && wait_reports(options, change.status)
+    })?;
+    Some(if peek { self.child_status_changes[index] } else { self.child_status_changes.remove(index) })
+  }
+
   /// Attempt to read an IPC message. If none is available, the process will
   /// block until a message is received or the optional timeout argument
   /// expires. When the process unblocks, it should re-issue a call to this
@@ -274,14 +724,109 @@ impl Process {
   /// any items that are due for removal.
   pub fn ipc_read(&mut self, current_ticks: u32, timeout: Option<usize>) -> (Option<IPCPacket>, bool) {
     let (first_read, has_more) = self.ipc_queue.read(current_ticks);
-    if first_read.is_some() {
+    if let Some(packet) = &first_read {
+      if self.ipc_awaiting_reply == Some(packet.from) {
+        self.ipc_awaiting_reply = None;
+      }
       return (first_read, has_more);
     }
+    if self.ipc_recipient_gone.is_some() {
+      // The reply this process was waiting on will never arrive
+      return (None, false);
+    }
     // Nothing in the queue, block the process until something arrives
-    self.state = RunState::AwaitingIPC(timeout);
+    self.block_interruptibly(RunState::AwaitingIPC(timeout));
     (None, false)
   }
 
+  /// Record that this process sent an IPC message, and may wait on a reply
+  pub fn ipc_sent(&mut self, to: ProcessID) {
+    self.ipc_awaiting_reply = Some(to);
+    self.ipc_recipient_gone = None;
+  }
+
+  /// True if this process is waiting on a reply from `id`. A message sent to
+  /// it by that process is the reply, so the sender doesn't expect one back.
+  pub fn is_awaiting_reply_from(&self, id: ProcessID) -> bool {
+    self.ipc_awaiting_reply == Some(id)
+  }
+
+  /// Called when another process has exited. If this process was waiting on
+  /// a reply from it, it is woken up so that its read can fail instead of
+  /// blocking forever. Returns true if this process was affected.
+  pub fn ipc_recipient_exited(&mut self, id: ProcessID) -> bool {
+    if self.ipc_awaiting_reply != Some(id) {
+      return false;
+    }
+    self.ipc_awaiting_reply = None;
+    self.ipc_recipient_gone = Some(id);
+    if let RunState::AwaitingIPC(_) = self.state {
+      self.set_state(RunState::Running);
+    }
+    true
+  }
+
+  /// Collect the exited process that a failed IPC read was waiting on
+  pub fn take_ipc_recipient_gone(&mut self) -> Option<ProcessID> {
+    self.ipc_recipient_gone.take()
+  }
+
+  /// Move a file handle carried by an IPC packet into this process's open
+  /// files. The last value of the message is replaced with the new handle.
+  pub fn install_ipc_handle(&mut self, packet: &mut IPCPacket) -> Option<FileHandle> {
+    let mut file = packet.handle.take()?;
+    file.descriptor_flags = 0;
+    let index = self.open_files.insert(file);
+    let handle = FileHandle::new(index as u32);
+    packet.message.3 = handle.as_u32();
+    Some(handle)
+  }
+
+  /// Reserve a region of memory for the pages carried by an IPC packet. The
+  /// third value of the message is replaced with the address of the region,
+  /// and the frames that need to be mapped into it are returned alongside it.
+  /// If there is no room for the region, the address is set to zero and the
+  /// frames are orphaned so that their references get released.
+  pub fn reserve_ipc_pages(&mut self, packet: &mut IPCPacket) -> Option<(VirtualAddress, Vec<PhysicalAddress>)> {
+    if packet.pages.is_empty() {
+      return None;
+    }
+    let pages = core::mem::replace(&mut packet.pages, Vec::new());
+    let size = pages.len() * 0x1000;
+    match self.memory.mmap(None, size, MMapBacking::Transferred) {
+      Ok(address) => {
+        packet.message.2 = address.as_u32();
+        Some((address, pages))
+      },
+      Err(_) => {
+        packet.message.2 = 0;
+        self.ipc_queue.orphan_pages(pages);
+        None
+      },
+    }
+  }
+
+  /// Collect frames attached to IPC messages that will never be read, so that
+  /// the kernel can release them. Like `take_orphaned_ipc_handles`, this
+  /// includes every queued message once the process has terminated.
+  pub fn take_orphaned_ipc_pages(&mut self) -> Vec<PhysicalAddress> {
+    if self.is_terminated() {
+      self.ipc_queue.discard_all();
+    }
+    self.ipc_queue.take_orphaned_pages()
+  }
+
+  /// Collect file handles attached to IPC messages that will never be read,
+  /// so that the kernel can close them. If the process is terminated, this
+  /// includes every message still in its queue.
+  pub fn take_orphaned_ipc_handles(&mut self) -> Vec<OpenFile> {
+    if self.is_terminated() {
+      self.ipc_queue.drain_handles()
+    } else {
+      self.ipc_queue.take_orphaned_handles()
+    }
+  }
+
   /// Unblocking version of ipc_read
   pub fn ipc_read_unblocking(&mut self, current_ticks: u32) -> (Option<IPCPacket>, bool) {
     self.ipc_queue.read(current_ticks)
@@ -292,31 +837,41 @@ impl Process {
   /// Each message is accompanied by an expiration time (in system ticks), after
   /// which point the message will be considered invalid if it hasn't been read.
   pub fn ipc_receive(&mut self, current_ticks: u32, from: ProcessID, message: IPCMessage, expiration_ticks: u32) {
-    self.ipc_queue.add(from, message, current_ticks, expiration_ticks);
+    self.ipc_receive_packet(current_ticks, IPCPacket { from, message, handle: None, pages: Vec::new() }, expiration_ticks);
+  }
+
+  /// Send an IPC packet to this process, which may carry a file handle or
+  /// pages. The handle and frames must already be references owned by the
+  /// packet, distinct from the sender's copies.
+  pub fn ipc_receive_packet(&mut self, current_ticks: u32, packet: IPCPacket, expiration_ticks: u32) {
+    self.ipc_queue.add_packet(packet, current_ticks, expiration_ticks);
     match self.state {
       RunState::AwaitingIPC(_) => {
-        self.state = RunState::Running;
+        self.set_state(RunState::Running);
       },
       _ => (),
     }
   }
 
   /// Update any internal timers based on regular system clock updates.
-  pub fn update_timeouts(&mut self, delta_ms: usize) {
+  pub fn update_timeouts(&mut self, current_ticks: u32, delta_ms: usize) {
     match self.state {
       RunState::AwaitingIPC(Some(timeout)) => {
-        self.state = if timeout < delta_ms {
+        self.set_state(if timeout < delta_ms {
           RunState::Running
         } else {
           RunState::AwaitingIPC(Some(timeout - delta_ms))
-        };
+        });
       },
-      RunState::Sleeping(timeout) => {
-        self.state = if timeout < delta_ms {
-          RunState::Running
-        } else {
-          RunState::Sleeping(timeout - delta_ms)
-        };
+      RunState::Sleeping(wake_tick) => {
+        if ticks::tick_reached(current_ticks, wake_tick) {
+          self.set_state(RunState::Running);
+        }
+      },
+      RunState::HardwareIO(Some(deadline)) => {
+        if ticks::tick_reached(current_ticks, deadline) {
+          self.set_state(RunState::Running);
+        }
       },
       _ => (),
     }
@@ -333,10 +888,22 @@ impl Process {
 
   /// Prepare for an exec syscall by removing the current execution segments and
   /// mmap mappings, and replacing them with a new set of segments.
-  pub fn prepare_exec_mapping(&mut self, exec: Vec<ExecutionSegment>) -> Vec<ExecutionSegment> {
-    let previous_exec = self.memory.reset_execution_segments(exec);
-    // TODO: remove all mmap entries
-    previous_exec
+  /// This only updates the bookkeeping; the caller is responsible for tearing
+  /// down the old page table entries.
+  pub fn prepare_exec_mapping(&mut self, exec: Vec<ExecutionSegment>) {
+    self.memory.reset_execution_segments(exec);
+    self.memory.reset_mmap_regions();
+  }
+
+  /// Reserve the heap an executable asked for, so it can use that memory
+  /// without calling brk first. Like any heap growth, this only reserves the
+  /// address range, and pages are backed when they are first touched. A size
+  /// beyond the data limit is ignored, leaving the heap empty.
+  fn reserve_initial_heap(&mut self, size: usize) {
+    if size == 0 || !self.heap_size_allowed(size) {
+      return;
+    }
+    let _ = self.memory.resize_heap(size);
   }
 
   /// Change the reference to the executable file being run in this process.
@@ -350,6 +917,44 @@ impl Process {
     self.exec_file.take()
   }
 
+  /// Record the full path of the executable that was loaded by exec
+  pub fn set_exec_path(&mut self, path: String) {
+    self.exec_path = Some(path);
+  }
+
+  pub fn get_exec_path(&self) -> Option<&str> {
+    self.exec_path.as_ref().map(|path| path.as_str())
+  }
+
+  /// Arguments of the current program, starting with the path it was run as
+  pub fn get_arguments(&self) -> &[String] {
+    &self.arguments
+  }
+
+  /// Pack the arguments into a buffer, each one followed by a NUL byte.
+  /// Returns the number of arguments, and the number of bytes needed to hold
+  /// them all. If the buffer is too small, nothing is copied.
+  pub fn copy_arguments(&self, buffer: &mut [u8]) -> (usize, usize) {
+    let required = self.arguments.iter().map(|arg| arg.len() + 1).sum();
+    if required <= buffer.len() {
+      let mut offset = 0;
+      for arg in self.arguments.iter() {
+        buffer[offset..offset + arg.len()].copy_from_slice(arg.as_bytes());
+        buffer[offset + arg.len()] = 0;
+        offset += arg.len() + 1;
+      }
+    }
+    (self.arguments.len(), required)
+  }
+
+  pub fn get_environment(&self) -> &Environment {
+    &self.environment
+  }
+
+  pub fn get_environment_mut(&mut self) -> &mut Environment {
+    &mut self.environment
+  }
+
   pub fn set_relocations(&mut self, relocations: Vec<Relocation>) {
     self.relocations = relocations;
   }
@@ -366,9 +971,14 @@ impl Process {
     Process {
       id: new_id,
       parent_id: self.id,
+      process_group: self.process_group,
       memory: self.memory.clone(),
       state: RunState::Running,
+      stopped: false,
+      scheduled: false,
       start_ticks: current_ticks,
+      cpu_ticks: 0,
+      peak_resident_pages: 0,
       ipc_queue: IPCQueue::new(),
       open_files: self.open_files.clone(),
       kernel_stack: Some(new_stack),
@@ -376,29 +986,270 @@ impl Process {
       saved_state: SavedState::empty(),
       page_directory: self.page_directory.clone(),
       exec_file: self.exec_file,
+      exec_path: self.exec_path.clone(),
+      environment: self.environment.clone(),
+      arguments: self.arguments.clone(),
       relocations: self.relocations.clone(),
       subsystem: Subsystem::Native,
       on_exit_vm: None,
       vterm: self.vterm,
       current_drive: self.current_drive,
+      working_directories: self.working_directories.clone(),
+      vfork_parent: None,
+      signals: self.signals.clone(),
+      child_status_changes: Vec::new(),
+      peeked_exits: Vec::new(),
+      wait_options: 0,
+      wait_target: WaitTarget::AnyChild,
+      waited_child: None,
+      reaped_children: 0,
+      oom_protected: false,
+      privileged: self.privileged,
+      nice: self.nice,
+      limits: self.limits,
+      io_ports: IOPortPermissions::new(),
+      wake_time: None,
+      interruptible_sleep: false,
+      ipc_awaiting_reply: None,
+      ipc_recipient_gone: None,
+    }
+  }
+
+  /// Create a child process that shares this process's address space. The
+  /// caller is responsible for suspending this process until the child has
+  /// called exec or exited.
+  pub fn create_vfork(&self, new_id: ProcessID, current_ticks: u32) -> Process {
+    let mut child = self.create_fork(new_id, current_ticks);
+    child.vfork_parent = Some(self.id);
+    child
+  }
+
+  /// If this process is still borrowing the address space of a vfork parent,
+  /// return that parent's ID
+  pub fn get_vfork_parent(&self) -> Option<ProcessID> {
+    self.vfork_parent
+  }
+
+  /// Stop borrowing the address space of a vfork parent, returning the ID of
+  /// the parent that needs to be resumed. This should only be called once the
+  /// process has its own page directory.
+  pub fn take_vfork_parent(&mut self) -> Option<ProcessID> {
+    self.vfork_parent.take()
+  }
+
+  /// Suspend this process until a vfork child execs or exits
+  pub fn vfork_wait(&mut self, child_id: ProcessID) {
+    self.set_state(RunState::VForkWaiting(child_id));
+  }
+
+  /// Tell a process that its vfork child no longer needs its address space. If
+  /// the process is suspended on that child, it will resume execution.
+  pub fn vfork_release(&mut self, child_id: ProcessID) {
+    match self.state {
+      RunState::VForkWaiting(id) if id == child_id => {
+        self.set_state(RunState::Running);
+      },
+      _ => (),
     }
   }
 
   /// When a file has been opened within a specific drive, it can be added to
   /// this process. The index in the file map is returned as a FileHandle.
-  pub fn open_file(&mut self, drive: DriveID, local_handle: LocalHandle) -> FileHandle {
+  /// Fails once the process holds as many handles as its limit allows.
+  pub fn open_file(&mut self, drive: DriveID, local_handle: LocalHandle) -> Result<FileHandle, SystemError> {
+    self.check_open_file_limit()?;
     let file = OpenFile {
       drive,
       local_handle,
+      flags: 0,
+      descriptor_flags: 0,
     };
     let index = self.open_files.insert(file);
-    FileHandle::new(index as u32)
+    Ok(FileHandle::new(index as u32))
+  }
+
+  /// Make sure the process can hold one more open handle
+  pub fn check_open_file_limit(&self) -> Result<(), SystemError> {
+    let open_count = self.open_files.iter().count();
+    if !self.limits.allows(Resource::OpenFiles, open_count + 1) {
+      return Err(SystemError::MaxFilesExceeded);
+    }
+    Ok(())
+  }
+
+  pub fn get_resource_limit(&self, resource: Resource) -> Limit {
+    self.limits.get(resource)
+  }
+
+  /// Replace the limit on a resource, returning the previous one. Only
+  /// privileged callers can raise the hard limit.
+  pub fn set_resource_limit(&mut self, resource: Resource, limit: Limit, privileged: bool) -> Result<Limit, SystemError> {
+    self.limits.set(resource, limit, privileged)
+  }
+
+  /// Whether the process may perform system-wide operations, like unmounting
+  /// drives. DOS programs are never privileged.
+  pub fn is_privileged(&self) -> bool {
+    match self.subsystem {
+      Subsystem::DOS(_) => false,
+      _ => self.privileged,
+    }
+  }
+
+  /// Give up privileges for good. Children forked afterwards are unprivileged
+  /// as well.
+  pub fn drop_privileges(&mut self) {
+    self.privileged = false;
+  }
+
+  /// Determine whether the heap can grow to a new size
+  pub fn heap_size_allowed(&self, size: usize) -> bool {
+    self.limits.allows(Resource::Data, size)
+  }
+
+  /// The lowest address the user stack is allowed to grow down to
+  pub fn get_stack_bottom(&self) -> VirtualAddress {
+    VirtualAddress::new(self.limits.stack_bottom())
+  }
+
+  /// Whether every page of a range of user memory belongs to the process: its
+  /// stack, heap, program, or one of its mappings. The kernel checks this
+  /// before touching memory that the process could have unmapped.
+  pub fn owns_user_range(&self, start: VirtualAddress, length: usize) -> bool {
+    let end = match start.as_usize().checked_add(length) {
+      Some(end) if end <= USER_KERNEL_BARRIER => end,
+      _ => return false,
+    };
+    let stack_bottom = self.get_stack_bottom();
+    let heap = self.memory.get_heap_page_range();
+    let mut page = start.prev_page_barrier();
+    while page.as_usize() < end {
+      let owned = page >= stack_bottom
+        || heap.contains(&page)
+        || self.memory.get_mapping_containing_address(&page).is_some()
+        || self.memory.get_execution_segment_containing_address(&page).is_some();
+      if !owned {
+        return false;
+      }
+      page = page + 0x1000;
+    }
+    true
+  }
+
+  /// Grant a driver access to a range of I/O ports. There are no user
+  /// accounts yet, so the only privilege check is that DOS programs can't
+  /// claim ports; they reach hardware through the VM's emulated ports.
+  pub fn grant_io_ports(&mut self, start: usize, count: usize) -> Result<(), SystemError> {
+    if let Subsystem::DOS(_) = self.subsystem {
+      return Err(SystemError::PermissionDenied);
+    }
+    self.io_ports.grant(start, count).map_err(|_| SystemError::InvalidArgument)
+  }
+
+  /// Make sure the process has been granted every port touched by an access
+  /// of `width` bytes
+  pub fn check_io_port_access(&self, port: usize, width: usize) -> Result<(), SystemError> {
+    if !self.io_ports.allows(port, width) {
+      return Err(SystemError::PermissionDenied);
+    }
+    Ok(())
   }
 
   pub fn get_open_file_info(&self, handle: FileHandle) -> Option<&OpenFile> {
     self.open_files.get(handle.as_usize())
   }
 
+  /// Replace the status flags stored with an open file handle, returning the
+  /// previous flags. If the handle is not open, nothing happens.
+  pub fn set_file_flags(&mut self, handle: FileHandle, flags: u32) -> Option<u32> {
+    let open_file = self.open_files.get_mut(handle.as_usize())?;
+    let prev = open_file.flags;
+    open_file.flags = flags;
+    Some(prev)
+  }
+
+  /// Replace the descriptor flags of an open file handle, returning the
+  /// previous flags. If the handle is not open, nothing happens.
+  pub fn set_descriptor_flags(&mut self, handle: FileHandle, flags: u32) -> Option<u32> {
+    let open_file = self.open_files.get_mut(handle.as_usize())?;
+    let prev = open_file.descriptor_flags;
+    open_file.descriptor_flags = flags;
+    Some(prev)
+  }
+
+  /// Replace the current program with a fully loaded new one. Nothing here can
+  /// fail, so once an exec has gotten this far the old image is gone for
+  /// good; every fallible step needs to happen while building the ExecImage.
+  /// The caller is still responsible for replacing the user address space.
+  pub fn commit_exec(&mut self, image: ExecImage) -> ReplacedImage {
+    self.prepare_exec_mapping(image.segments);
+    self.reserve_initial_heap(image.initial_heap_size);
+    let uninherited = self.prepare_for_exec();
+    if image.require_vm {
+      self.subsystem = Subsystem::DOS(crate::dos::state::VMState::new());
+    }
+    self.set_relocations(image.relocations);
+    self.set_exec_path(image.exec_path);
+    self.arguments = image.arguments;
+    let (drive_id, local_handle) = image.exec_file;
+    ReplacedImage {
+      exec_file: self.set_exec_file(drive_id, local_handle),
+      uninherited,
+    }
+  }
+
+  /// Remove every handle that should not be inherited by a newly exec'd
+  /// program: by default only stdin, stdout, and stderr survive. The removed
+  /// files are returned so that the caller can close them in their drives.
+  /// Signal handlers point into the old program, so they are reset as well.
+  pub fn prepare_for_exec(&mut self) -> Vec<OpenFile> {
+    self.signals.reset_for_exec();
+    self.oom_protected = false;
+    self.io_ports.revoke_all();
+    let mut closed = Vec::new();
+    for index in 0..self.open_files.len() {
+      let survives = match self.open_files.get(index) {
+        Some(file) => file.survives_exec(index),
+        None => true,
+      };
+      if !survives {
+        if let Some(file) = self.open_files.remove(index) {
+          closed.push(file);
+        }
+      }
+    }
+    closed
+  }
+
+  /// Determine if the process holds any files open on a drive, including the
+  /// executable it is running
+  pub fn has_files_on_drive(&self, drive: DriveID) -> bool {
+    if let Some((exec_drive, _)) = self.exec_file {
+      if exec_drive == drive {
+        return true;
+      }
+    }
+    self.open_files.iter().any(|file| file.drive == drive)
+  }
+
+  /// Remove every handle that points to a file on a drive, returning the local
+  /// handles so that the caller can close them in the filesystem.
+  pub fn close_files_on_drive(&mut self, drive: DriveID) -> Vec<LocalHandle> {
+    let mut closed = Vec::new();
+    for index in 0..self.open_files.len() {
+      let matches = match self.open_files.get(index) {
+        Some(file) => file.drive == drive,
+        None => false,
+      };
+      if matches {
+        if let Some(file) = self.open_files.remove(index) {
+          closed.push(file.local_handle);
+        }
+      }
+    }
+    closed
+  }
+
   /// Close an open file handle. If it represented a file within a drive, a
   /// struct containing that drive's ID and its local handle will be returned.
   pub fn close_file(&mut self, handle: FileHandle) -> Option<OpenFile> {
@@ -409,8 +1260,12 @@ impl Process {
   /// returns the previous open file descriptor if one was overwritten, and the
   /// file handle that was created.
   pub fn duplicate_file_descriptor(&mut self, old: FileHandle, new: Option<FileHandle>) -> (Option<OpenFile>, Option<FileHandle>) {
+    // Descriptor flags belong to the original handle, and are not copied
     let copied_entry = match self.open_files.get(old.as_usize()) {
-      Some(entry) => *entry,
+      Some(entry) => OpenFile {
+        descriptor_flags: 0,
+        ..*entry
+      },
       None => return (None, None),
     };
     match new {
@@ -425,31 +1280,50 @@ impl Process {
     }
   }
 
+  /// Duplicate `old` onto the specific handle `new`, giving the copy its own
+  /// descriptor flags. Whatever `new` pointed to before is replaced. If no
+  /// other handle in the process still refers to that file, it is returned so
+  /// that the caller can close it in its drive.
+  pub fn duplicate_onto(&mut self, old: FileHandle, new: FileHandle, descriptor_flags: u32) -> Result<Option<OpenFile>, SystemError> {
+    self.get_open_file_info(old).ok_or(SystemError::BadFileDescriptor)?;
+    let (replaced, _) = self.duplicate_file_descriptor(old, Some(new));
+    self.set_descriptor_flags(new, descriptor_flags);
+    Ok(replaced.filter(|file| !self.references_local_handle(file.drive, file.local_handle)))
+  }
+
+  /// Determine whether any handle still points to a file that is open in a
+  /// drive. Duplicated handles share the same local handle.
+  pub fn references_local_handle(&self, drive: DriveID, local_handle: LocalHandle) -> bool {
+    self.open_files.iter().any(|file| file.drive == drive && file.local_handle == local_handle)
+  }
+
   /// Mark a process as blocked on file IO
   pub fn io_block(&mut self, timeout: Option<usize>) {
-    self.state = RunState::FileIO(timeout);
+    self.block_interruptibly(RunState::FileIO(timeout));
   }
 
   /// If a process is blocked on file IO, wake it up
   pub fn io_resume(&mut self) {
     match self.state {
       RunState::FileIO(_) => {
-        self.state = RunState::Running;
+        self.set_state(RunState::Running);
       },
       _ => (),
     }
   }
 
-  /// Mark a process as blocked on hardware IO
-  pub fn hardware_block(&mut self, timeout: Option<usize>) {
-    self.state = RunState::HardwareIO(timeout);
+  /// Mark a process as blocked on hardware IO. If a deadline tick is given,
+  /// the process wakes once it is reached, even if the driver never resumes
+  /// it.
+  pub fn hardware_block(&mut self, deadline: Option<u32>) {
+    self.set_state(RunState::HardwareIO(deadline));
   }
 
   /// If a process is blocked on hardware IO, wake it up
   pub fn hardware_resume(&mut self) {
     match self.state {
       RunState::HardwareIO(_) => {
-        self.state = RunState::Running;
+        self.set_state(RunState::Running);
       },
       _ => (),
     }
@@ -466,6 +1340,38 @@ impl Process {
   }
 }
 
+/// Determine whether a stop or continue status should end a wait
+fn wait_reports(options: u32, status: u32) -> bool {
+  if status & STATUS_STOPPED != 0 {
+    options & WUNTRACED != 0
+  } else if status & STATUS_CONTINUED != 0 {
+    options & WCONTINUED != 0
+  } else {
+    false
+  }
+}
+
+/// Pass every live member of a process group to `send`. The current process
+/// is sent last, so that a signal which stops or ends it still reaches the
+/// rest of the group. Fails if the group has no members.
+pub fn signal_group<F>(processes: &[Arc<RwLock<Process>>], group: ProcessID, current_id: ProcessID, mut send: F) -> Result<(), SystemError>
+  where F: FnMut(ProcessID) {
+  let mut members: Vec<ProcessID> = processes
+    .iter()
+    .map(|proc_lock| proc_lock.read())
+    .filter(|process| process.is_in_group(group))
+    .map(|process| *process.get_id())
+    .collect();
+  if members.is_empty() {
+    return Err(SystemError::NoSuchEntity);
+  }
+  members.sort_by_key(|id| *id == current_id);
+  for id in members {
+    send(id);
+  }
+  Ok(())
+}
+
 impl Drop for Process {
   fn drop(&mut self) {
     // Make sure it doesn't attempt to deallocate the stack Box
@@ -478,18 +1384,166 @@ impl Drop for Process {
 
 #[cfg(test)]
 mod tests {
-  use super::{DriveID, FileHandle, Handle, LocalHandle, Process, VirtualAddress};
+  use super::{DriveID, ExecImage, FileHandle, Handle, IPCMessage, LocalHandle, Process, ProcessID, RunState, String, VirtualAddress, WaitTarget};
+  use alloc::vec;
+  use alloc::vec::Vec;
+  use crate::time::ticks::MS_PER_TICK;
+  use syscall::result::SystemError;
 
   #[test]
   fn sleeping() {
     let mut p = Process::initial(0);
-    p.sleep(2000);
+    p.sleep(0, 2000);
+    assert!(!p.can_resume());
+    p.update_timeouts(50, 500);
+    p.update_timeouts(150, 1000);
+    assert!(!p.can_resume());
+    p.update_timeouts(220, 700);
+    assert!(p.can_resume());
+  }
+
+  #[test]
+  fn hardware_block_deadline() {
+    // A driver that never resumes the process, like a floppy controller that
+    // never raises its interrupt
+    let mut p = Process::initial(0);
+    p.hardware_block(Some(30));
+    p.update_timeouts(29, MS_PER_TICK);
+    assert!(!p.can_resume());
+    p.update_timeouts(30, MS_PER_TICK);
+    assert!(p.can_resume());
+
+    // Without a deadline, only the driver can resume it
+    let mut p = Process::initial(0);
+    p.hardware_block(None);
+    p.update_timeouts(5000, MS_PER_TICK);
+    assert!(!p.can_resume());
+    p.hardware_resume();
+    assert!(p.can_resume());
+  }
+
+  #[test]
+  fn sleep_wakes_on_target_tick() {
+    // Each requested duration is rounded up to whole ticks from the tick where
+    // the sleep began
+    for &(start, duration, expected_wake) in &[(0, 10, 1), (5, 15, 7), (100, 1, 101), (7, 1000, 107)] {
+      let mut p = Process::initial(0);
+      p.sleep(start, duration);
+      let mut tick = start;
+      while !p.can_resume() {
+        tick += 1;
+        p.update_timeouts(tick, MS_PER_TICK);
+      }
+      assert_eq!(tick, expected_wake);
+    }
+
+    // A late timer interrupt still wakes the process, without underflow
+    let mut p = Process::initial(0);
+    p.sleep(10, 30);
+    p.update_timeouts(12, MS_PER_TICK);
+    assert!(!p.can_resume());
+    p.update_timeouts(5000, usize::MAX);
+    assert!(p.can_resume());
+
+    // Sleeping across the tick counter wrapping around
+    let mut p = Process::initial(0);
+    p.sleep(u32::MAX, 20);
+    p.update_timeouts(0, MS_PER_TICK);
+    assert!(!p.can_resume());
+    p.update_timeouts(1, MS_PER_TICK);
+    assert!(p.can_resume());
+
+    // Sleeping for zero behaves like a yield, and leaves the process runnable
+    let mut p = Process::initial(0);
+    p.sleep(40, 0);
+    assert!(p.can_resume());
+  }
+
+  #[test]
+  fn sleep_until_wall_clock_time() {
+    use crate::time::ticks::HUNDRED_NS_PER_TICK;
+
+    // Each tick advances the clock, and the process wakes on the first tick
+    // at or after the target time
+    let start_time = 1_000_000_000;
+    let target = start_time + HUNDRED_NS_PER_TICK * 25 + 5;
+    let mut p = Process::initial(0);
+    p.sleep_until(100, start_time, target);
+    let mut tick = 100;
+    while !p.can_resume() {
+      tick += 1;
+      p.update_timeouts(tick, MS_PER_TICK);
+    }
+    let woken_at = start_time + (tick - 100) as u64 * HUNDRED_NS_PER_TICK;
+    assert!(woken_at >= target);
+    assert!(woken_at - target < HUNDRED_NS_PER_TICK);
+
+    // A time that has already passed returns immediately
+    let mut p = Process::initial(0);
+    p.sleep_until(100, start_time, start_time - 1);
+    assert!(p.can_resume());
+
+    // When the clock jumps forward past the target, the process wakes
+    let mut p = Process::initial(0);
+    p.sleep_until(0, start_time, target);
+    p.resync_wake_time(1, target + 1);
+    assert!(p.can_resume());
+
+    // When the clock is set back, the sleep is extended to match
+    let mut p = Process::initial(0);
+    p.sleep_until(0, start_time, target);
+    p.resync_wake_time(10, start_time - HUNDRED_NS_PER_TICK * 10);
+    p.update_timeouts(26, MS_PER_TICK);
     assert!(!p.can_resume());
-    p.update_timeouts(500);
-    p.update_timeouts(1000);
+    p.update_timeouts(45, MS_PER_TICK);
     assert!(!p.can_resume());
-    p.update_timeouts(700);
+    p.update_timeouts(46, MS_PER_TICK);
     assert!(p.can_resume());
+
+    // Relative sleeps ignore clock changes
+    let mut p = Process::initial(0);
+    p.sleep_until(0, start_time, target);
+    p.sleep(0, 1000);
+    p.resync_wake_time(1, target + 1);
+    assert!(!p.can_resume());
+  }
+
+  #[test]
+  fn state_changes_update_run_queue() {
+    use crate::task::scheduler::RUN_QUEUE;
+
+    // An ID no other test uses, since the run queue is shared
+    let id = ProcessID::new(9001);
+    let mut p = Process::initial(0).create_fork(id, 0);
+    p.sleep(0, 100);
+    // Not tracked until it has been handed to the scheduler
+    assert!(!RUN_QUEUE.is_runnable(id));
+    p.enter_run_queue();
+    assert!(!RUN_QUEUE.is_runnable(id));
+    p.update_timeouts(10, 100);
+    assert!(RUN_QUEUE.is_runnable(id));
+    p.io_block(None);
+    assert!(!RUN_QUEUE.is_runnable(id));
+    p.io_resume();
+    assert!(RUN_QUEUE.is_runnable(id));
+    p.wait(None);
+    assert!(!RUN_QUEUE.is_runnable(id));
+    p.child_returned(ProcessID::new(9002), ProcessID::new(0), 0);
+    assert!(RUN_QUEUE.is_runnable(id));
+    p.resume_from_wait();
+    p.pause();
+    assert!(!RUN_QUEUE.is_runnable(id));
+    p.resume();
+    assert!(RUN_QUEUE.is_runnable(id));
+    p.ipc_read(0, None);
+    assert!(!RUN_QUEUE.is_runnable(id));
+    p.ipc_receive(0, ProcessID::new(1), IPCMessage(0, 0, 0, 0), 100);
+    assert!(RUN_QUEUE.is_runnable(id));
+    p.terminate();
+    assert!(!RUN_QUEUE.is_runnable(id));
+    p.leave_run_queue();
+    p.set_state(RunState::Running);
+    assert!(!RUN_QUEUE.is_runnable(id));
   }
 
   #[test]
@@ -514,9 +1568,9 @@ mod tests {
   #[test]
   fn file_handle_dup() {
     let mut p = Process::initial(0);
-    let first = p.open_file(DriveID::new(0), LocalHandle::new(2));
-    let second = p.open_file(DriveID::new(1), LocalHandle::new(0));
-    let third = p.open_file(DriveID::new(0), LocalHandle::new(4));
+    let first = p.open_file(DriveID::new(0), LocalHandle::new(2)).unwrap();
+    let second = p.open_file(DriveID::new(1), LocalHandle::new(0)).unwrap();
+    let third = p.open_file(DriveID::new(0), LocalHandle::new(4)).unwrap();
     {
       // `dup` syscall
       let (prev_entry, new_handle) = p.duplicate_file_descriptor(second, None);
@@ -540,4 +1594,793 @@ mod tests {
       assert_eq!(new_handle, Some(FileHandle::new(0)));
     }
   }
+
+  #[test]
+  fn open_file_limit() {
+    use super::super::limits::{Limit, Resource};
+
+    let mut parent = Process::initial(0);
+    parent.set_resource_limit(Resource::OpenFiles, Limit::new(4, 8), false).unwrap();
+    for local in 0..4 {
+      parent.open_file(DriveID::new(1), LocalHandle::new(local)).unwrap();
+    }
+    assert!(matches!(
+      parent.open_file(DriveID::new(1), LocalHandle::new(4)),
+      Err(SystemError::MaxFilesExceeded),
+    ));
+    // Closing a handle makes room for another
+    parent.close_file(FileHandle::new(1));
+    assert_eq!(parent.open_file(DriveID::new(1), LocalHandle::new(4)).unwrap(), FileHandle::new(1));
+
+    // Children start with the same limits, and can't raise the hard one
+    let mut child = parent.create_fork(ProcessID::new(1), 0);
+    assert_eq!(child.get_resource_limit(Resource::OpenFiles), Limit::new(4, 8));
+    assert!(child.check_open_file_limit().is_err());
+    child.set_resource_limit(Resource::OpenFiles, Limit::new(8, 8), false).unwrap();
+    assert!(child.check_open_file_limit().is_ok());
+    assert!(child.set_resource_limit(Resource::OpenFiles, Limit::new(8, 16), false).is_err());
+
+    // A lowered heap limit stops the heap from growing
+    child.set_resource_limit(Resource::Data, Limit::new(0x3000, 0x3000), false).unwrap();
+    assert!(child.heap_size_allowed(0x3000));
+    assert!(!child.heap_size_allowed(0x3001));
+  }
+  #[test]
+  fn dropped_privileges_stay_dropped() {
+    let mut init = Process::initial(0);
+    assert!(init.is_privileged());
+    let child = init.create_fork(ProcessID::new(1), 0);
+    assert!(child.is_privileged());
+
+    init.drop_privileges();
+    assert!(!init.is_privileged());
+    let mut grandchild = init.create_fork(ProcessID::new(2), 0);
+    assert!(!grandchild.is_privileged());
+    grandchild.drop_privileges();
+    assert!(!grandchild.is_privileged());
+    assert!(child.is_privileged());
+  }
+
+
+  #[test]
+  fn io_port_grants() {
+    let mut driver = Process::initial(0);
+    // Nothing is accessible until it has been granted
+    assert!(matches!(driver.check_io_port_access(0x278, 1), Err(SystemError::PermissionDenied)));
+    driver.grant_io_ports(0x278, 3).unwrap();
+    assert!(driver.check_io_port_access(0x278, 2).is_ok());
+    assert!(driver.check_io_port_access(0x27a, 2).is_err());
+
+    // Other processes, including the driver's own children, are denied
+    let mut child = driver.create_fork(ProcessID::new(1), 0);
+    assert!(matches!(child.check_io_port_access(0x278, 1), Err(SystemError::PermissionDenied)));
+    child.subsystem = super::Subsystem::DOS(crate::dos::state::VMState::new());
+    assert!(matches!(child.grant_io_ports(0x278, 3), Err(SystemError::PermissionDenied)));
+
+    // Running a new program drops the grants
+    driver.prepare_for_exec();
+    assert!(driver.check_io_port_access(0x278, 1).is_err());
+  }
+
+  #[test]
+  fn exec_only_inherits_stdio() {
+    let mut parent = Process::initial(0);
+    for local in 0..6 {
+      parent.open_file(DriveID::new(1), LocalHandle::new(local)).unwrap();
+    }
+    // The shell wants one extra handle passed to its child, and one of the
+    // standard handles closed
+    parent.set_descriptor_flags(FileHandle::new(4), syscall::flags::FD_KEEPEXEC);
+    parent.set_descriptor_flags(FileHandle::new(2), syscall::flags::FD_CLOEXEC);
+    let mut child = parent.create_fork(ProcessID::new(1), 0);
+
+    let closed = child.prepare_for_exec();
+    let closed_handles: Vec<LocalHandle> = closed.iter().map(|file| file.local_handle).collect();
+    assert_eq!(closed_handles, vec![LocalHandle::new(2), LocalHandle::new(3), LocalHandle::new(5)]);
+    let remaining: Vec<LocalHandle> = child.open_files.iter().map(|file| file.local_handle).collect();
+    assert_eq!(remaining, vec![LocalHandle::new(0), LocalHandle::new(1), LocalHandle::new(4)]);
+    assert_eq!(child.get_open_file_info(FileHandle::new(1)).unwrap().local_handle, LocalHandle::new(1));
+    assert!(child.get_open_file_info(FileHandle::new(3)).is_none());
+    // The parent's handles are untouched
+    assert_eq!(parent.open_files.iter().count(), 6);
+
+    // Duplicates don't carry over descriptor flags
+    let (_, copy) = parent.duplicate_file_descriptor(FileHandle::new(4), None);
+    assert_eq!(parent.get_open_file_info(copy.unwrap()).unwrap().descriptor_flags, 0);
+  }
+
+  #[test]
+  fn duplicate_onto_open_handle() {
+    use syscall::flags::FD_CLOEXEC;
+
+    let mut p = Process::initial(0);
+    for local in 0..5 {
+      p.open_file(DriveID::new(1), LocalHandle::new(local)).unwrap();
+    }
+    let extra = FileHandle::new(3);
+    let target = FileHandle::new(4);
+    // Replacing the last handle to a file hands it back to be closed
+    let replaced = p.duplicate_onto(extra, target, FD_CLOEXEC).unwrap();
+    assert_eq!(replaced.unwrap().local_handle, LocalHandle::new(4));
+    let copy = p.get_open_file_info(target).unwrap();
+    assert_eq!(copy.local_handle, LocalHandle::new(3));
+    assert_eq!(copy.descriptor_flags, FD_CLOEXEC);
+    assert_eq!(p.get_open_file_info(extra).unwrap().descriptor_flags, 0);
+
+    // The file stays open while another handle still refers to it
+    let replaced = p.duplicate_onto(FileHandle::new(0), extra, 0).unwrap();
+    assert!(replaced.is_none());
+    assert!(p.duplicate_onto(FileHandle::new(9), extra, 0).is_err());
+    assert_eq!(p.get_open_file_info(extra).unwrap().local_handle, LocalHandle::new(0));
+
+    // The close-on-exec copy doesn't survive exec, even onto a stdio handle
+    p.duplicate_onto(target, FileHandle::new(1), FD_CLOEXEC).unwrap();
+    let closed: Vec<LocalHandle> = p.prepare_for_exec().iter().map(|file| file.local_handle).collect();
+    assert_eq!(closed, vec![LocalHandle::new(3), LocalHandle::new(0), LocalHandle::new(3)]);
+  }
+
+  #[test]
+  fn exec_commits_whole_image() {
+    use super::ExecImage;
+
+    let mut parent = Process::initial(0);
+    for local in 0..4 {
+      parent.open_file(DriveID::new(1), LocalHandle::new(local)).unwrap();
+    }
+    parent.set_exec_file(DriveID::new(2), LocalHandle::new(3));
+    parent.set_exec_path(String::from("C:\\SHELL.BIN"));
+    let mut child = parent.create_fork(ProcessID::new(1), 0);
+
+    // A failed load never produces an image, so the child keeps everything
+    // it had, and can carry on or exit normally
+    assert_eq!(child.get_exec_path(), Some("C:\\SHELL.BIN"));
+    assert_eq!(child.open_files.iter().count(), 4);
+    assert!(child.can_resume());
+
+    let replaced = child.commit_exec(ExecImage {
+      segments: Vec::new(),
+      relocations: Vec::new(),
+      exec_file: (DriveID::new(2), LocalHandle::new(4)),
+      exec_path: String::from("C:\\GAME.EXE"),
+      require_vm: true,
+      initial_heap_size: 0,
+      arguments: vec![String::from("C:\\GAME.EXE")],
+    });
+    assert_eq!(replaced.exec_file, Some((DriveID::new(2), LocalHandle::new(3))));
+    let closed: Vec<LocalHandle> = replaced.uninherited.iter().map(|file| file.local_handle).collect();
+    assert_eq!(closed, vec![LocalHandle::new(3)]);
+    assert_eq!(child.get_exec_path(), Some("C:\\GAME.EXE"));
+    assert_eq!(child.get_arguments(), &[String::from("C:\\GAME.EXE")]);
+    assert!(matches!(child.subsystem, super::Subsystem::DOS(_)));
+
+    child.terminate();
+    assert!(child.is_terminated());
+    // The parent is untouched
+    assert_eq!(parent.get_exec_path(), Some("C:\\SHELL.BIN"));
+  }
+
+  #[test]
+  fn initial_heap_hint() {
+    use super::super::limits::{Limit, Resource};
+
+    let image = |initial_heap_size: usize| ExecImage {
+      segments: Vec::new(),
+      relocations: Vec::new(),
+      exec_file: (DriveID::new(2), LocalHandle::new(4)),
+      exec_path: String::from("C:\\ALLOC.ELF"),
+      require_vm: false,
+      initial_heap_size,
+      arguments: Vec::new(),
+    };
+    let mut process = Process::initial(0);
+    process.commit_exec(image(0x2800));
+    // The heap is reserved without a brk, and the fault handler treats every
+    // page of it as demand-paged heap
+    let start = process.memory.get_heap_start();
+    assert_eq!(process.memory.get_heap_size(), 0x2800);
+    assert!(process.memory.get_heap_page_range().contains(&(start + 0x2fff)));
+    assert!(!process.memory.get_heap_page_range().contains(&(start + 0x3000)));
+    // Growing it further still works as usual
+    let prev = process.increase_heap(0x800);
+    assert_eq!(prev, start + 0x2800);
+
+    // Without a hint, the next program starts with an empty heap
+    process.commit_exec(image(0));
+    assert_eq!(process.memory.get_heap_size(), 0);
+
+    // A hint beyond the data limit is ignored
+    process.set_resource_limit(Resource::Data, Limit::new(0x1000, 0x1000), false).unwrap();
+    process.commit_exec(image(0x2000));
+    assert_eq!(process.memory.get_heap_size(), 0);
+  }
+
+  #[test]
+  fn owned_user_ranges() {
+    use super::{MMapBacking, USER_KERNEL_BARRIER};
+
+    let mut process = Process::initial(0);
+    let top = VirtualAddress::new(USER_KERNEL_BARRIER - 0x40);
+    assert!(process.owns_user_range(top, 0x40));
+    assert!(!process.owns_user_range(top, 0x41));
+
+    let buffer = VirtualAddress::new(0x4000_0000);
+    process.memory.mmap(Some(buffer), 0x3000, MMapBacking::Anonymous).unwrap();
+    assert!(process.owns_user_range(buffer + 0xff0, 0x20));
+    // Unmapping the middle page leaves a hole in the range
+    process.memory.munmap(buffer + 0x1000, 0x1000).unwrap();
+    assert!(process.owns_user_range(buffer, 0x1000));
+    assert!(!process.owns_user_range(buffer + 0xff0, 0x20));
+    assert!(!process.owns_user_range(buffer + 0x1800, 0x10));
+  }
+
+  #[test]
+  fn arguments_from_exec() {
+    let mut process = Process::initial(0);
+    let mut buffer = [0xff; 32];
+    // A process that was never exec'd has no arguments
+    assert_eq!(process.copy_arguments(&mut buffer), (0, 0));
+
+    process.commit_exec(ExecImage {
+      segments: Vec::new(),
+      relocations: Vec::new(),
+      exec_file: (DriveID::new(2), LocalHandle::new(4)),
+      exec_path: String::from("C:\\ECHO.BIN"),
+      require_vm: false,
+      initial_heap_size: 0,
+      arguments: vec![String::from("C:\\ECHO.BIN"), String::from("hi"), String::new()],
+    });
+    assert_eq!(process.copy_arguments(&mut buffer), (3, 16));
+    assert_eq!(&buffer[..16], b"C:\\ECHO.BIN\0hi\0\0");
+
+    // A buffer that's too small is left alone, and the caller learns how
+    // much room is needed
+    let mut small = [0xff; 8];
+    assert_eq!(process.copy_arguments(&mut small), (3, 16));
+    assert_eq!(small, [0xff; 8]);
+  }
+  #[test]
+  fn vfork_then_exec() {
+    let mut parent = Process::initial(0);
+    let child_id = ProcessID::new(1);
+    let mut child = parent.create_vfork(child_id, 0);
+    // The child borrows the parent's page directory
+    assert_eq!(child.page_directory.get_address(), parent.page_directory.get_address());
+    assert_eq!(child.get_vfork_parent(), Some(*parent.get_id()));
+
+    parent.vfork_wait(child_id);
+    assert!(!parent.can_resume());
+    // Another child exiting does not wake the parent
+    parent.vfork_release(ProcessID::new(2));
+    assert!(!parent.can_resume());
+
+    // On exec, the child stops borrowing the address space and releases the
+    // parent, which picks up where it left off
+    let released = child.take_vfork_parent().unwrap();
+    assert_eq!(released, *parent.get_id());
+    assert_eq!(child.get_vfork_parent(), None);
+    parent.vfork_release(*child.get_id());
+    assert!(parent.can_resume());
+  }
+
+  #[test]
+  fn exec_path_follows_fork_and_exec() {
+    let mut parent = Process::initial(0);
+    assert_eq!(parent.get_exec_path(), None);
+    parent.set_exec_path(String::from("INIT:\\SHELL.BIN"));
+    let mut child = parent.create_fork(ProcessID::new(1), 0);
+    assert_eq!(child.get_exec_path(), Some("INIT:\\SHELL.BIN"));
+    // Exec in the child replaces its path, without affecting the parent
+    child.set_exec_path(String::from("C:\\BIN\\LS.BIN"));
+    assert_eq!(child.get_exec_path(), Some("C:\\BIN\\LS.BIN"));
+    assert_eq!(parent.get_exec_path(), Some("INIT:\\SHELL.BIN"));
+  }
+
+  #[test]
+  fn environment_is_inherited() {
+    let mut parent = Process::initial(0);
+    parent.get_environment_mut().set("PATH", "INIT:\\").unwrap();
+    parent.get_environment_mut().set("HOME", "A:\\").unwrap();
+    let mut child = parent.create_fork(ProcessID::new(1), 0);
+    assert_eq!(child.get_environment().get("PATH"), Some("INIT:\\"));
+    assert_eq!(child.get_environment().get("HOME"), Some("A:\\"));
+
+    // Changes in the child stay in the child
+    child.get_environment_mut().set("PATH", "A:\\BIN").unwrap();
+    child.get_environment_mut().unset("HOME");
+    child.get_environment_mut().set("TERM", "vt100").unwrap();
+    assert_eq!(parent.get_environment().get("PATH"), Some("INIT:\\"));
+    assert_eq!(parent.get_environment().get("HOME"), Some("A:\\"));
+    assert_eq!(parent.get_environment().get("TERM"), None);
+  }
+
+  #[test]
+  fn process_group_membership() {
+    let shell = Process::initial(0);
+    let mut job = shell.create_fork(ProcessID::new(1), 0);
+    // A child stays in its parent's group until it moves itself
+    let pipeline = job.create_fork(ProcessID::new(2), 0);
+    assert_eq!(job.get_process_group(), ProcessID::new(0));
+    assert_eq!(pipeline.get_process_group(), ProcessID::new(0));
+
+    job.set_process_group(ProcessID::new(1));
+    assert!(job.is_in_group(ProcessID::new(1)));
+    assert!(!job.is_in_group(ProcessID::new(0)));
+    assert!(shell.is_in_group(ProcessID::new(0)));
+    assert_eq!(pipeline.get_process_group(), ProcessID::new(0));
+    // Children forked after the move join the new group
+    let mut worker = job.create_fork(ProcessID::new(3), 0);
+    assert!(worker.is_in_group(ProcessID::new(1)));
+    worker.terminate();
+    assert!(!worker.is_in_group(ProcessID::new(1)));
+  }
+
+  #[test]
+  fn group_signal_reaches_members() {
+    use alloc::sync::Arc;
+    use crate::task::signal::SignalHandler;
+    use crate::locks::RwLock;
+    use syscall::signals::INT;
+    use super::signal_group;
+
+    let shell = Process::initial(0);
+    let processes: Vec<Arc<RwLock<Process>>> = (1..5).map(|id| {
+      let mut process = shell.create_fork(ProcessID::new(id), 0);
+      let handler = SignalHandler {
+        function: VirtualAddress::new(0x00402000),
+        restorer: VirtualAddress::new(0x00403000),
+      };
+      process.signals.set_handler(INT, Some(handler)).unwrap();
+      Arc::new(RwLock::new(process))
+    }).collect();
+    processes[0].write().set_process_group(ProcessID::new(1));
+    processes[2].write().set_process_group(ProcessID::new(1));
+    processes[3].write().set_process_group(ProcessID::new(1));
+    // A member that has already exited is skipped
+    processes[3].write().terminate();
+
+    // The sender is a member of the group, so it is signaled last
+    let mut order = Vec::new();
+    let result = signal_group(&processes, ProcessID::new(1), ProcessID::new(1), |id| {
+      order.push(id);
+      let target = processes.iter().find(|p| *p.read().get_id() == id).unwrap();
+      assert!(target.write().signals.raise(INT));
+    });
+    assert!(result.is_ok());
+    assert_eq!(order, [ProcessID::new(3), ProcessID::new(1)]);
+    assert!(processes[0].write().signals.take_pending().is_some());
+    assert!(processes[1].write().signals.take_pending().is_none());
+    assert!(processes[2].write().signals.take_pending().is_some());
+
+    let result = signal_group(&processes, ProcessID::new(7), ProcessID::new(1), |_| panic!("the group is empty"));
+    assert!(matches!(result, Err(SystemError::NoSuchEntity)));
+  }
+
+  #[test]
+  fn signal_wakes_blocked_syscalls() {
+    use crate::task::signal::SignalHandler;
+    use syscall::signals::INT;
+
+    let handler = SignalHandler {
+      function: VirtualAddress::new(0x00402000),
+      restorer: VirtualAddress::new(0x00403000),
+    };
+    let mut p = Process::initial(0);
+    p.signals.set_handler(INT, Some(handler)).unwrap();
+    p.sleep_interruptibly(0, 2000);
+    assert!(!p.can_resume());
+    p.wake_for_signal();
+    assert!(p.can_resume());
+    p.io_block(None);
+    p.wake_for_signal();
+    assert!(p.can_resume());
+    p.wait(None);
+    assert!(!p.can_resume());
+    p.wake_for_signal();
+    assert_eq!(p.finish_wait(), None);
+
+    // Sleeps inside the kernel and hardware waits run to completion
+    p.sleep(0, 2000);
+    p.wake_for_signal();
+    assert!(!p.can_resume());
+    let mut p = Process::initial(0);
+    p.hardware_block(None);
+    p.wake_for_signal();
+    assert!(!p.can_resume());
+
+    // A signal that is already waiting keeps the process from blocking
+    let mut p = Process::initial(0);
+    p.signals.set_handler(INT, Some(handler)).unwrap();
+    p.signals.raise(INT);
+    assert!(p.ipc_read(0, None).0.is_none());
+    assert!(p.can_resume());
+    p.io_block(None);
+    assert!(p.can_resume());
+    // Children are still reported once the handler has run
+    p.signals.take_pending();
+    let child = ProcessID::new(4);
+    p.wait(Some(child));
+    p.child_returned(child, ProcessID::new(0), 3);
+    assert_eq!(p.finish_wait(), Some(3));
+  }
+
+  #[test]
+  fn pass_pipe_over_ipc() {
+    use alloc::sync::Arc;
+    use crate::fs::filesystem::KernelFileSystem;
+    use crate::pipes::collection::PipeCollection;
+    use crate::pipes::fs::PipeFileSystem;
+    use super::{IPCMessage, IPCPacket};
+
+    let pipes = Arc::new(PipeCollection::new());
+    let pipe_fs = PipeFileSystem::new(&pipes);
+    let pipe_drive = DriveID::new(5);
+    let mut sender = Process::initial(0);
+    let mut receiver = Process::initial(0);
+    let receiver_id = ProcessID::new(1);
+
+    let (read_end, write_end) = pipes.create().unwrap();
+    let sender_read = sender.open_file(pipe_drive, read_end).unwrap();
+    sender.open_file(pipe_drive, write_end).unwrap();
+
+    // Sending the handle gives the message its own reference to the pipe
+    let sent = *sender.get_open_file_info(sender_read).unwrap();
+    let packet = IPCPacket::with_file(*sender.get_id(), receiver_id, IPCMessage(0x10, 0, 0, 0), sent, &pipe_fs).unwrap();
+    assert_ne!(packet.handle.unwrap().local_handle, sent.local_handle);
+    receiver.ipc_receive_packet(0, packet, 2000);
+
+    // The sender closes its copy before the receiver has picked it up. The
+    // pipe still has a reader, so writing doesn't fail.
+    let closed = sender.close_file(sender_read).unwrap();
+    pipe_fs.close(closed.local_handle).unwrap();
+    assert_eq!(pipe_fs.write(write_end, b"hello").unwrap(), 5);
+
+    let (packet, _) = receiver.ipc_read_unblocking(0);
+    let mut packet = packet.unwrap();
+    let handle = receiver.install_ipc_handle(&mut packet).unwrap();
+    assert_eq!(packet.message, IPCMessage(0x10, 0, 0, handle.as_u32()));
+    assert!(packet.handle.is_none());
+
+    let received = *receiver.get_open_file_info(handle).unwrap();
+    assert_eq!(received.drive, pipe_drive);
+    let mut buffer = [0u8; 5];
+    assert_eq!(pipe_fs.read(received.local_handle, &mut buffer).unwrap(), 5);
+    assert_eq!(&buffer, b"hello");
+  }
+
+  #[test]
+  fn recipient_exits_mid_send() {
+    use crate::memory::address::PhysicalAddress;
+    use super::IPCPacket;
+
+    let server_id = ProcessID::new(20);
+    let client_id = ProcessID::new(21);
+    let bystander_id = ProcessID::new(22);
+    let mut server = Process::initial(0).create_fork(server_id, 0);
+    let mut client = Process::initial(0).create_fork(client_id, 0);
+    let mut bystander = Process::initial(0).create_fork(bystander_id, 0);
+
+    // An earlier request carrying a page expires before it is read. The
+    // client sends another request, and blocks waiting on the reply.
+    let expired_page = PhysicalAddress::new(0x5000);
+    server.ipc_receive_packet(0, IPCPacket {
+      from: client_id,
+      message: IPCMessage(1, 0, 0, 0),
+      handle: None,
+      pages: vec![expired_page],
+    }, 10);
+    server.ipc_receive(20, client_id, IPCMessage(2, 0, 0, 0), 2000);
+    client.ipc_sent(server_id);
+    let (packet, _) = client.ipc_read(20, None);
+    assert!(packet.is_none());
+    assert!(!client.can_resume());
+    // A message from the server would be the reply, while the client isn't
+    // expecting anything from the bystander
+    assert!(client.is_awaiting_reply_from(server_id));
+    assert!(!client.is_awaiting_reply_from(bystander_id));
+    // Another process is waiting on a reply from someone else
+    bystander.ipc_sent(ProcessID::new(23));
+    bystander.ipc_read(20, None);
+
+    // The server exits without reading anything. Its queue is drained, and
+    // the expired message's page is released along with the rest.
+    server.terminate();
+    assert_eq!(server.take_orphaned_ipc_pages(), [expired_page]);
+    assert!(server.take_orphaned_ipc_handles().is_empty());
+    assert!(server.ipc_read_unblocking(20).0.is_none());
+
+    assert!(client.ipc_recipient_exited(server_id));
+    assert!(!bystander.ipc_recipient_exited(server_id));
+    assert!(client.can_resume());
+    assert!(!bystander.can_resume());
+    // Reading again fails instead of blocking
+    let (packet, _) = client.ipc_read(30, None);
+    assert!(packet.is_none());
+    assert!(client.can_resume());
+    assert_eq!(client.take_ipc_recipient_gone(), Some(server_id));
+
+    // Once a reply arrives, the sender is no longer waiting on that process
+    client.ipc_sent(bystander_id);
+    client.ipc_receive(30, bystander_id, IPCMessage(3, 0, 0, 0), 2000);
+    let (packet, _) = client.ipc_read(30, None);
+    assert_eq!(packet.unwrap().message, IPCMessage(3, 0, 0, 0));
+    assert!(!client.is_awaiting_reply_from(bystander_id));
+    assert!(!client.ipc_recipient_exited(bystander_id));
+    assert_eq!(client.take_ipc_recipient_gone(), None);
+  }
+
+  #[test]
+  fn transfer_pages_over_ipc() {
+    use alloc::boxed::Box;
+    use crate::memory::physical::{
+      frame_bitmap::FrameBitmap,
+      frame_range::FrameRange,
+      frame_refcount::FrameRefcount,
+      release_frame,
+    };
+    use crate::memory::virt::page_table::PageTable;
+    use super::{IPCPacket, MMapBacking, PhysicalAddress};
+    use super::super::ipc::PageTransferMode;
+
+    let memory: [u8; 2] = [0; 2];
+    let mut bitmap = FrameBitmap::at_location(
+      VirtualAddress::new(&memory[0] as *const u8 as usize),
+      16,
+    );
+    let mut refcount = FrameRefcount::new();
+    let baseline = bitmap.get_free_frame_count();
+    let first_frame = |address: PhysicalAddress| FrameRange::new(address.as_usize(), 0x1000).get_first_frame();
+
+    let mut sender = Process::initial(0);
+    let mut receiver = Process::initial(0);
+    let mut sender_table = Box::new(PageTable::new());
+    let mut receiver_table = Box::new(PageTable::new());
+
+    // The sender has a four-page buffer, all of it paged in
+    let buffer = sender.memory.mmap(None, 0x4000, MMapBacking::Anonymous).unwrap();
+    for index in 0..4 {
+      let frame = bitmap.allocate_frames(1).unwrap().get_starting_address();
+      sender_table.get_mut(index).set_address(frame);
+      sender_table.get_mut(index).set_present();
+    }
+    assert_eq!(bitmap.get_free_frame_count(), baseline - 4);
+
+    // Share the first three pages
+    let mut shared = Vec::new();
+    for index in 0..3 {
+      shared.push(PageTransferMode::Share.take_frame(sender_table.get_mut(index), |address| {
+        refcount.reference_frame_at_address(address);
+      }));
+    }
+    receiver.ipc_receive_packet(0, IPCPacket {
+      from: *sender.get_id(),
+      message: IPCMessage(0x20, 0, buffer.as_u32(), 3),
+      handle: None,
+      pages: shared.clone(),
+    }, 2000);
+
+    let (packet, _) = receiver.ipc_read_unblocking(0);
+    let mut packet = packet.unwrap();
+    let (address, frames) = receiver.reserve_ipc_pages(&mut packet).unwrap();
+    assert_eq!(packet.message, IPCMessage(0x20, 0, address.as_u32(), 3));
+    assert!(packet.pages.is_empty());
+    assert_eq!(frames, shared);
+    let region = receiver.memory.get_mapping_containing_address(&address).unwrap();
+    assert_eq!(region.size, 0x3000);
+    assert_eq!(region.backed_by, MMapBacking::Transferred);
+    for (index, frame) in frames.into_iter().enumerate() {
+      receiver_table.get_mut(index).set_address(frame);
+      receiver_table.get_mut(index).set_present();
+    }
+
+    // Both sides map the same frames, so no memory was copied
+    for index in 0..3 {
+      assert!(sender_table.get(index).is_present());
+      assert_eq!(sender_table.get(index).get_address(), receiver_table.get(index).get_address());
+      assert_eq!(refcount.get_count_for_address(shared[index]), 2);
+    }
+    assert_eq!(bitmap.get_free_frame_count(), baseline - 4);
+
+    // The receiver is done with the pages, but the sender still holds them
+    receiver_table.release_entries(|frame| {
+      assert!(!release_frame(&mut bitmap, &mut refcount, frame.to_frame()).unwrap());
+    });
+    assert_eq!(bitmap.get_free_frame_count(), baseline - 4);
+
+    // Moving the last page removes it from the sender without a new reference
+    let last_frame = sender_table.get(3).get_address();
+    let moved = PageTransferMode::Move.take_frame(sender_table.get_mut(3), |_| {
+      panic!("Moving a page should not add a reference");
+    });
+    assert_eq!(moved, last_frame);
+    assert!(!sender_table.get(3).is_present());
+    // Its one reference now belongs to the message
+    assert_eq!(refcount.get_count_for_address(moved), 1);
+    sender.memory.munmap(buffer + 0x3000, 0x1000).unwrap();
+    assert!(sender.memory.get_mapping_containing_address(&(buffer + 0x3000)).is_none());
+    receiver.ipc_receive_packet(0, IPCPacket {
+      from: *sender.get_id(),
+      message: IPCMessage(0x21, 0, (buffer + 0x3000).as_u32(), 1),
+      handle: None,
+      pages: vec![moved],
+    }, 2000);
+
+    // The message expires before it is read, so its page is orphaned and
+    // its only reference is released
+    let (packet, _) = receiver.ipc_read_unblocking(3000);
+    assert!(packet.is_none());
+    let orphaned = receiver.take_orphaned_ipc_pages();
+    assert_eq!(orphaned, [moved]);
+    for frame in orphaned {
+      assert!(release_frame(&mut bitmap, &mut refcount, first_frame(frame)).unwrap());
+    }
+    assert_eq!(bitmap.get_free_frame_count(), baseline - 3);
+
+    // Once the sender unmaps its shared pages, everything has been returned
+    sender_table.release_entries(|frame| {
+      assert!(release_frame(&mut bitmap, &mut refcount, frame.to_frame()).unwrap());
+    });
+    assert_eq!(bitmap.get_free_frame_count(), baseline);
+  }
+
+  #[test]
+  fn stopped_and_continued_children() {
+    use syscall::flags::{WCONTINUED, WUNTRACED};
+    use syscall::signals::{STATUS_CONTINUED, STATUS_STOPPED, STOP};
+
+    let mut parent = Process::initial(0);
+    let child = ProcessID::new(9003);
+    let group = ProcessID::new(0);
+
+    // A stop doesn't end a wait that only asked about exits, but it is kept
+    // for a later wait that does ask
+    parent.wait(Some(child));
+    parent.child_status_changed(child, group, STATUS_STOPPED | STOP);
+    assert!(!parent.can_resume());
+    parent.wait_with_options(Some(child), WUNTRACED);
+    assert!(parent.can_resume());
+    assert_eq!(parent.resume_from_wait(), STATUS_STOPPED | STOP);
+    assert_eq!(parent.take_waited_child(), Some(child));
+
+    // The stop has been reported, so the next wait blocks
+    parent.wait_with_options(None, WUNTRACED | WCONTINUED);
+    assert!(!parent.can_resume());
+    parent.child_status_changed(child, group, STATUS_CONTINUED);
+    assert!(parent.can_resume());
+    assert_eq!(parent.resume_from_wait(), STATUS_CONTINUED);
+    assert_eq!(parent.take_waited_child(), Some(child));
+
+    // Exits are still reported to a wait with options
+    parent.wait_with_options(Some(child), WUNTRACED | WCONTINUED);
+    assert!(!parent.can_resume());
+    parent.child_returned(child, group, 3);
+    assert!(parent.can_resume());
+    assert_eq!(parent.resume_from_wait(), 3);
+    assert_eq!(parent.take_waited_child(), Some(child));
+
+    // A child that exits with an unreported stop doesn't report it later
+    parent.child_status_changed(child, group, STATUS_STOPPED | STOP);
+    parent.child_returned(child, group, 0);
+    parent.wait_with_options(None, WUNTRACED);
+    assert!(!parent.can_resume());
+  }
+
+  #[test]
+  fn stop_keeps_blocked_state() {
+    let mut p = Process::initial(0);
+    let child = ProcessID::new(9005);
+
+    // A wait that was in progress when the process stopped is still in
+    // progress once it continues
+    p.wait(Some(child));
+    p.pause();
+    assert!(p.is_paused());
+    p.resume();
+    assert!(!p.is_paused());
+    assert!(!p.can_resume());
+
+    // A child that exits while its parent is stopped still ends the wait,
+    // but the parent only runs again once it continues
+    p.pause();
+    p.child_returned(child, ProcessID::new(0), 4);
+    assert!(!p.can_resume());
+    p.resume();
+    assert!(p.can_resume());
+    assert_eq!(p.finish_wait(), Some(4));
+
+    // Same for a sleep
+    p.sleep(0, 2000);
+    p.pause();
+    p.resume();
+    assert!(!p.can_resume());
+  }
+
+  #[test]
+  fn peek_then_reap() {
+    use syscall::flags::WNOWAIT;
+
+    let mut parent = Process::initial(0);
+    let child = ProcessID::new(9004);
+    let group = ProcessID::new(0);
+
+    parent.wait_for(WaitTarget::Child(child), WNOWAIT);
+    assert!(!parent.can_resume());
+    parent.child_returned(child, group, 7);
+    assert!(parent.can_resume());
+    assert_eq!(parent.resume_from_wait(), 7);
+    assert_eq!(parent.take_waited_child(), Some(child));
+
+    // A peeked exit is reported again, as often as it is peeked at
+    parent.wait_for(WaitTarget::AnyChild, WNOWAIT);
+    assert!(parent.can_resume());
+    assert_eq!(parent.resume_from_wait(), 7);
+    assert_eq!(parent.take_waited_child(), Some(child));
+
+    // A regular wait collects it
+    parent.wait_for(WaitTarget::Child(child), 0);
+    assert!(parent.can_resume());
+    assert_eq!(parent.resume_from_wait(), 7);
+    assert_eq!(parent.take_waited_child(), Some(child));
+
+    parent.wait_for(WaitTarget::AnyChild, 0);
+    assert!(!parent.can_resume());
+  }
+
+  #[test]
+  fn wait_on_group() {
+    use syscall::flags::WUNTRACED;
+    use syscall::signals::{STATUS_STOPPED, STOP};
+
+    let mut parent = Process::initial(0);
+    let group = ProcessID::new(40);
+
+    parent.wait_for(WaitTarget::Group(group), 0);
+    parent.child_returned(ProcessID::new(9005), ProcessID::new(41), 1);
+    assert!(!parent.can_resume());
+    parent.child_returned(ProcessID::new(9006), group, 2);
+    assert!(parent.can_resume());
+    assert_eq!(parent.resume_from_wait(), 2);
+    assert_eq!(parent.take_waited_child(), Some(ProcessID::new(9006)));
+
+    // Kept stops are matched by the group the child was in when it stopped
+    parent.child_status_changed(ProcessID::new(9007), ProcessID::new(41), STATUS_STOPPED | STOP);
+    parent.wait_for(WaitTarget::Group(group), WUNTRACED);
+    assert!(!parent.can_resume());
+    parent.wait_for(WaitTarget::Group(ProcessID::new(41)), WUNTRACED);
+    assert!(parent.can_resume());
+    assert_eq!(parent.take_waited_child(), Some(ProcessID::new(9007)));
+  }
+
+  #[test]
+  fn ignored_sigchld_reaps_children() {
+    use syscall::signals::CHILD;
+
+    let mut parent = Process::initial(0);
+    let group = ProcessID::new(0);
+    let (early, kept) = (ProcessID::new(9008), ProcessID::new(9009));
+    parent.signals.ignore(CHILD).unwrap();
+
+    // An exit is discarded and the child can be reaped, even when a wait
+    // would have matched it. The wait wakes up with nothing to report, so
+    // that it can look for other children.
+    parent.wait_for_live(WaitTarget::AnyChild, 0, true).unwrap();
+    assert!(parent.child_exited(early, group, 4));
+    assert_eq!(parent.reaped_children(), 1);
+    assert!(parent.can_resume());
+    assert_eq!(parent.finish_wait(), None);
+    assert_eq!(parent.take_waited_child(), None);
+
+    // With no child left that could end it, a wait fails rather than blocking
+    assert!(matches!(parent.wait_for_live(WaitTarget::Child(early), 0, false), Err(SystemError::NoSuchEntity)));
+    assert!(matches!(parent.wait_for_live(WaitTarget::AnyChild, 0, false), Err(SystemError::NoSuchEntity)));
+    assert!(parent.can_resume());
+
+    // Once SIGCHLD has its default action again, exits are reported
+    parent.signals.set_handler(CHILD, None).unwrap();
+    parent.wait_for_live(WaitTarget::AnyChild, 0, true).unwrap();
+    assert!(!parent.can_resume());
+    assert!(!parent.child_exited(kept, group, 5));
+    assert!(parent.can_resume());
+    assert_eq!(parent.resume_from_wait(), 5);
+    assert_eq!(parent.take_waited_child(), Some(kept));
+  }
 }
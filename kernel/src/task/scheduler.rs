@@ -0,0 +1,327 @@
+//! The scheduler used to find the next process by walking the entire task map
+//! on every switch, checking the state of each process along the way. With
+//! many processes blocked on IO or sleeping, that walk dominated the cost of a
+//! context switch.
+//! Instead, the run queue keeps the IDs of runnable processes only. Processes
+//! are added and removed as their state changes, so picking the next process
+//! no longer depends on how many processes are blocked.
+//! A process can also hand the CPU to a specific process, like a producer
+//! waking its consumer. That only changes the immediate switch: the next
+//! regular turn picks up where the round robin would have been, so processes
+//! handing the CPU back and forth can't keep everyone else waiting.
+//! How long a process runs before the timer switches away depends on its nice
+//! value. Lower values get longer time slices.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use crate::locks::{ordered, LockLevel};
+use crate::locks::RwLock;
+use super::id::ProcessID;
+
+pub const NICE_MIN: i32 = -20;
+pub const NICE_MAX: i32 = 19;
+
+/// The set of processes the scheduler is allowed to switch to
+pub static RUN_QUEUE: RunQueue = RunQueue::new();
+
+pub struct RunQueue {
+  /// IDs of runnable processes, kept in ascending order
+  runnable: RwLock<Vec<ProcessID>>,
+  /// Number of processes tracked by the queue, runnable or not. The runnable
+  /// list always has room for all of them, so that waking a process from an
+  /// interrupt never needs to allocate.
+  registered: RwLock<usize>,
+  /// Set when the CPU was handed to a specific process. The next regular
+  /// turn continues the round robin from here, instead of from the process
+  /// that was handed the CPU.
+  resume_after: RwLock<Option<ProcessID>>,
+}
+
+impl RunQueue {
+  pub const fn new() -> Self {
+    Self {
+      runnable: RwLock::new(Vec::new()),
+      registered: RwLock::new(0),
+      resume_after: RwLock::new(None),
+    }
+  }
+
+  /// Start tracking a process that has just been added to the task map
+  pub fn register(&self, id: ProcessID, runnable: bool) {
+    without_interrupts(|| {
+      let _order = ordered(LockLevel::RunQueue);
+      let mut registered = self.registered.write();
+      *registered += 1;
+      let mut list = self.runnable.write();
+      let additional = registered.saturating_sub(list.len());
+      list.reserve(additional);
+    });
+    self.update(id, runnable);
+  }
+
+  /// Stop tracking a process that is being removed from the task map
+  pub fn unregister(&self, id: ProcessID) {
+    self.update(id, false);
+    without_interrupts(|| {
+      let _order = ordered(LockLevel::RunQueue);
+      let mut registered = self.registered.write();
+      *registered = registered.saturating_sub(1);
+    });
+  }
+
+  /// Record whether a tracked process can currently be run
+  pub fn update(&self, id: ProcessID, runnable: bool) {
+    without_interrupts(|| {
+      let _order = ordered(LockLevel::RunQueue);
+      let mut list = self.runnable.write();
+      match (list.binary_search(&id), runnable) {
+        (Err(index), true) => list.insert(index, id),
+        (Ok(index), false) => {
+          list.remove(index);
+        },
+        _ => (),
+      }
+    });
+  }
+
+  pub fn is_runnable(&self, id: ProcessID) -> bool {
+    without_interrupts(|| {
+      let _order = ordered(LockLevel::RunQueue);
+      self.runnable.read().binary_search(&id).is_ok()
+    })
+  }
+
+  pub fn runnable_count(&self) -> usize {
+    without_interrupts(|| {
+      let _order = ordered(LockLevel::RunQueue);
+      self.runnable.read().len()
+    })
+  }
+
+  /// Choose the process to run after `current`. Processes take turns in order
+  /// of ID: the first runnable process with an ID after the current one is
+  /// picked, wrapping around to the lowest ID at the end. If no other process
+  /// is runnable, None is returned and the current process keeps running.
+  /// If the CPU was recently handed to a specific process, the turn goes to
+  /// whoever would have been next before the hand-off.
+  pub fn next_after(&self, current: ProcessID) -> Option<ProcessID> {
+    without_interrupts(|| {
+      let _order = ordered(LockLevel::RunQueue);
+      let position = self.resume_after.write().take().unwrap_or(current);
+      let list = self.runnable.read();
+      let start = match list.binary_search(&position) {
+        Ok(index) => index + 1,
+        Err(index) => index,
+      };
+      let next = match list.get(start) {
+        Some(id) => Some(*id),
+        None => list.first().copied(),
+      };
+      next.filter(|id| *id != current)
+    })
+  }
+
+  /// Choose the process to run when `current` hands the CPU to `target`.
+  /// If the target isn't runnable, this is the same as a regular turn.
+  /// Otherwise the target runs next, and the round robin remembers where it
+  /// left off. A chain of hand-offs keeps the position from before the first
+  /// one.
+  pub fn hand_off(&self, current: ProcessID, target: ProcessID) -> Option<ProcessID> {
+    if target == current || !self.is_runnable(target) {
+      return self.next_after(current);
+    }
+    without_interrupts(|| {
+      let _order = ordered(LockLevel::RunQueue);
+      let mut resume_after = self.resume_after.write();
+      if resume_after.is_none() {
+        *resume_after = Some(current);
+      }
+    });
+    Some(target)
+  }
+}
+
+/// Apply a relative change to a nice value, keeping it within the allowed
+/// range
+pub fn adjust_nice(nice: i32, increment: i32) -> i32 {
+  nice.saturating_add(increment).max(NICE_MIN).min(NICE_MAX)
+}
+
+/// Number of ticks a process with a given nice value runs before the timer
+/// switches away. The default of 0 runs for four ticks; the lowest value runs
+/// for eight, and values of 15 and up only run for a single tick.
+pub fn time_slice_ticks(nice: i32) -> u32 {
+  ((20 - nice) / 5).max(1) as u32
+}
+
+/// Counts down the ticks left in the running process's time slice
+pub struct TimeSlice {
+  remaining: AtomicU32,
+}
+
+impl TimeSlice {
+  pub const fn new() -> Self {
+    Self {
+      remaining: AtomicU32::new(0),
+    }
+  }
+
+  /// Begin a new slice, when switching to a process
+  pub fn start(&self, ticks: u32) {
+    self.remaining.store(ticks, Ordering::SeqCst);
+  }
+
+  /// Called on each timer tick. Returns true once the slice has run out.
+  pub fn tick(&self) -> bool {
+    let previous = self.remaining.load(Ordering::SeqCst);
+    let remaining = previous.saturating_sub(1);
+    self.remaining.store(remaining, Ordering::SeqCst);
+    remaining == 0
+  }
+}
+
+/// The time slice of whichever process is running
+pub static TIME_SLICE: TimeSlice = TimeSlice::new();
+
+/// The timer interrupt wakes sleeping processes, so the queue must not be
+/// interrupted while it's being modified
+#[cfg(not(test))]
+fn without_interrupts<F, R>(f: F) -> R
+  where F: FnOnce() -> R {
+  use crate::interrupts::control::{cli, is_interrupt_enabled, sti};
+
+  let reenable = is_interrupt_enabled();
+  cli();
+  let result = f();
+  if reenable {
+    sti();
+  }
+  result
+}
+
+#[cfg(test)]
+fn without_interrupts<F, R>(f: F) -> R
+  where F: FnOnce() -> R {
+  f()
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+  use super::{adjust_nice, time_slice_ticks, ProcessID, RunQueue, TimeSlice};
+
+  fn take_turns(queue: &RunQueue, start: u32, count: usize) -> Vec<u32> {
+    let mut current = ProcessID::new(start);
+    let mut order = Vec::new();
+    for _ in 0..count {
+      current = queue.next_after(current).unwrap_or(current);
+      order.push(current.as_u32());
+    }
+    order
+  }
+
+  #[test]
+  fn round_robin_by_id() {
+    let queue = RunQueue::new();
+    for id in 0..5 {
+      queue.register(ProcessID::new(id), id != 2);
+    }
+    assert_eq!(take_turns(&queue, 0, 6), [1, 3, 4, 0, 1, 3]);
+
+    queue.update(ProcessID::new(3), false);
+    queue.update(ProcessID::new(2), true);
+    assert_eq!(take_turns(&queue, 0, 4), [1, 2, 4, 0]);
+
+    // A blocked current process still gives up the CPU in ID order
+    queue.update(ProcessID::new(1), false);
+    assert_eq!(queue.next_after(ProcessID::new(1)), Some(ProcessID::new(2)));
+
+    queue.unregister(ProcessID::new(4));
+    assert_eq!(take_turns(&queue, 0, 3), [2, 0, 2]);
+  }
+
+  #[test]
+  fn only_runnable_process_keeps_running() {
+    let queue = RunQueue::new();
+    queue.register(ProcessID::new(0), true);
+    queue.register(ProcessID::new(1), false);
+    assert_eq!(queue.next_after(ProcessID::new(0)), None);
+    queue.update(ProcessID::new(0), false);
+    assert_eq!(queue.next_after(ProcessID::new(0)), None);
+  }
+
+  #[test]
+  fn switch_cost_ignores_blocked_processes() {
+    let queue = RunQueue::new();
+    queue.register(ProcessID::new(0), true);
+    queue.register(ProcessID::new(1), true);
+    let few_blocked = {
+      for id in 2..10 {
+        queue.register(ProcessID::new(id), false);
+      }
+      queue.runnable_count()
+    };
+    for id in 10..20000 {
+      queue.register(ProcessID::new(id), false);
+    }
+    queue.register(ProcessID::new(20000), true);
+    // Blocked processes never enter the list the scheduler searches, so
+    // tens of thousands of them add nothing to each switch
+    assert_eq!(few_blocked, 2);
+    assert_eq!(queue.runnable_count(), 3);
+    assert_eq!(take_turns(&queue, 0, 6), [1, 20000, 0, 1, 20000, 0]);
+
+    // Waking a process never needs to grow the list
+    let capacity = queue.runnable.read().capacity();
+    for id in 2..20000 {
+      queue.update(ProcessID::new(id), true);
+    }
+    assert_eq!(queue.runnable.read().capacity(), capacity);
+    assert_eq!(queue.next_after(ProcessID::new(1)), Some(ProcessID::new(2)));
+  }
+
+  #[test]
+  fn yield_to_runs_named_process() {
+    let queue = RunQueue::new();
+    for id in 0..6 {
+      queue.register(ProcessID::new(id), id != 3);
+    }
+    // Process 1 hands the CPU to 4, skipping 2
+    assert_eq!(queue.hand_off(ProcessID::new(1), ProcessID::new(4)), Some(ProcessID::new(4)));
+    // The next regular turn continues from 1, so 2 isn't passed over
+    assert_eq!(take_turns(&queue, 4, 4), [2, 4, 5, 0]);
+
+    // Handing the CPU back and forth still leaves turns for everyone else
+    assert_eq!(queue.hand_off(ProcessID::new(1), ProcessID::new(5)), Some(ProcessID::new(5)));
+    assert_eq!(queue.hand_off(ProcessID::new(5), ProcessID::new(1)), Some(ProcessID::new(1)));
+    assert_eq!(queue.next_after(ProcessID::new(1)), Some(ProcessID::new(2)));
+    // If the process that would be next is already running, it keeps going
+    queue.hand_off(ProcessID::new(1), ProcessID::new(2));
+    assert_eq!(queue.next_after(ProcessID::new(2)), None);
+    assert_eq!(queue.next_after(ProcessID::new(2)), Some(ProcessID::new(4)));
+
+    // Blocked and nonexistent processes get a regular turn instead
+    assert_eq!(queue.hand_off(ProcessID::new(1), ProcessID::new(3)), Some(ProcessID::new(2)));
+    assert_eq!(queue.hand_off(ProcessID::new(1), ProcessID::new(99)), Some(ProcessID::new(2)));
+    assert_eq!(queue.hand_off(ProcessID::new(1), ProcessID::new(1)), Some(ProcessID::new(2)));
+  }
+
+  #[test]
+  fn nice_sets_time_slice() {
+    assert_eq!(adjust_nice(0, 5), 5);
+    assert_eq!(adjust_nice(5, -30), -20);
+    assert_eq!(adjust_nice(10, 100), 19);
+    assert_eq!(adjust_nice(0, i32::MIN), -20);
+    assert_eq!(time_slice_ticks(-20), 8);
+    assert_eq!(time_slice_ticks(0), 4);
+    assert_eq!(time_slice_ticks(10), 2);
+    assert_eq!(time_slice_ticks(19), 1);
+
+    let slice = TimeSlice::new();
+    slice.start(time_slice_ticks(10));
+    assert!(!slice.tick());
+    assert!(slice.tick());
+    // A switch that was deferred keeps reporting the slice as used up
+    assert!(slice.tick());
+  }
+}
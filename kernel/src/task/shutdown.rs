@@ -0,0 +1,168 @@
+//! Shutting down stops every user process before the drives are flushed, so
+//! that nothing is still writing when the last data goes to disk.
+//! Processes are first asked to exit with TERM, which gives daemons a chance
+//! to save their state. Anything still running after the grace period is
+//! sent KILL, which can't be handled or ignored. Signals go to the newest
+//! processes first, so that services started early by init outlive the
+//! programs that may still be talking to them.
+//! Kernel processes, like the drivers and the cleanup task, are never
+//! signaled; they keep running until the system powers off. The VGA driver
+//! in particular is still needed, since it makes the APM call.
+
+use alloc::vec::Vec;
+use crate::time::ticks::{target_tick, tick_reached};
+use super::id::ProcessID;
+use super::signal::Signal;
+
+/// How long processes have to exit after TERM, before they are killed
+pub const GRACE_PERIOD_MS: usize = 5000;
+
+/// How often the remaining processes are checked while waiting
+pub const POLL_INTERVAL_MS: usize = 50;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum ShutdownStep {
+  /// Send a signal to each of these processes, in order
+  Signal(Signal, Vec<ProcessID>),
+  /// Give the remaining processes more time to exit
+  Wait,
+  /// Every process has stopped, and the drives can be flushed
+  Flush,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Phase {
+  Starting,
+  /// TERM has been sent. Holds the tick at which the grace period ends.
+  Terminating(u32),
+  Killing,
+  Stopped,
+}
+
+pub struct Shutdown {
+  phase: Phase,
+  grace_period_ms: usize,
+}
+
+impl Shutdown {
+  pub fn new(grace_period_ms: usize) -> Shutdown {
+    Shutdown {
+      phase: Phase::Starting,
+      grace_period_ms,
+    }
+  }
+
+  /// Decide what to do next, given the current tick and the user processes
+  /// that are still alive. Once Flush is returned, every later call returns
+  /// it too.
+  pub fn next_step(&mut self, now: u32, remaining: &[ProcessID]) -> ShutdownStep {
+    if remaining.is_empty() {
+      self.phase = Phase::Stopped;
+    }
+    match self.phase {
+      Phase::Starting => {
+        self.phase = Phase::Terminating(target_tick(now, self.grace_period_ms));
+        ShutdownStep::Signal(Signal::Terminate, newest_first(remaining))
+      },
+      Phase::Terminating(deadline) => {
+        if !tick_reached(now, deadline) {
+          return ShutdownStep::Wait;
+        }
+        self.phase = Phase::Killing;
+        ShutdownStep::Signal(Signal::Kill, newest_first(remaining))
+      },
+      // Killed processes still need to be torn down by the cleanup task
+      Phase::Killing => ShutdownStep::Wait,
+      Phase::Stopped => ShutdownStep::Flush,
+    }
+  }
+}
+
+fn newest_first(processes: &[ProcessID]) -> Vec<ProcessID> {
+  let mut ordered = processes.to_vec();
+  ordered.sort_by(|a, b| b.cmp(a));
+  ordered
+}
+
+/// Find the user processes that shutdown needs to stop. The caller isn't
+/// included, since it is the one waiting for the others.
+#[cfg(not(test))]
+fn remaining_processes(caller: ProcessID) -> Vec<ProcessID> {
+  let mut remaining = Vec::new();
+  super::switching::for_each_process_mut(|proc_lock| {
+    let process = proc_lock.read();
+    let id = *process.get_id();
+    if id != caller && !process.is_oom_protected() && !process.is_terminated() {
+      remaining.push(id);
+    }
+  });
+  remaining
+}
+
+/// Stop every user process, flush and unmount all drives, and power off. The
+/// caller is the last user process left running, and never resumes.
+#[cfg(not(test))]
+pub fn shutdown_system() -> ! {
+  let caller = super::switching::get_current_id();
+  crate::klog!(Warn, Scheduler; "System is shutting down\n");
+  let mut shutdown = Shutdown::new(GRACE_PERIOD_MS);
+  loop {
+    let remaining = remaining_processes(caller);
+    match shutdown.next_step(crate::time::system::get_system_ticks(), &remaining) {
+      ShutdownStep::Signal(signal, processes) => {
+        for id in processes {
+          // A process may exit on its own before its signal arrives
+          let _ = super::exec::send_signal(Some(id), signal);
+        }
+      },
+      ShutdownStep::Wait => super::sleep(POLL_INTERVAL_MS),
+      ShutdownStep::Flush => break,
+    }
+  }
+
+  for name in crate::fs::DRIVES.unmount_all() {
+    crate::klog!(Error, FileSystem; "Failed to flush drive {}: before shutdown\n", name);
+  }
+  crate::hardware::apm::power_off()
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec;
+  use crate::task::id::ProcessID;
+  use crate::task::signal::Signal;
+  use crate::time::ticks::ms_to_ticks;
+  use super::{Shutdown, ShutdownStep};
+
+  #[test]
+  fn terminate_then_kill() {
+    let daemon = ProcessID::new(3);
+    let stubborn = ProcessID::new(7);
+    let editor = ProcessID::new(9);
+    let mut shutdown = Shutdown::new(1000);
+    let grace = ms_to_ticks(1000);
+
+    // Everyone is asked to exit, newest first
+    assert_eq!(
+      shutdown.next_step(100, &[daemon, stubborn, editor]),
+      ShutdownStep::Signal(Signal::Terminate, vec![editor, stubborn, daemon]),
+    );
+    assert_eq!(shutdown.next_step(101, &[daemon, stubborn, editor]), ShutdownStep::Wait);
+    // The editor exits right away, and the daemon takes a while to clean up
+    assert_eq!(shutdown.next_step(110, &[daemon, stubborn]), ShutdownStep::Wait);
+    assert_eq!(shutdown.next_step(100 + grace - 1, &[stubborn]), ShutdownStep::Wait);
+
+    // One process ignores TERM, and is killed once the grace period is over
+    assert_eq!(shutdown.next_step(100 + grace, &[stubborn]), ShutdownStep::Signal(Signal::Kill, vec![stubborn]));
+    assert_eq!(shutdown.next_step(100 + grace + 1, &[stubborn]), ShutdownStep::Wait);
+    // Drives are only flushed after the last process is gone
+    assert_eq!(shutdown.next_step(100 + grace + 2, &[]), ShutdownStep::Flush);
+    assert_eq!(shutdown.next_step(100 + grace + 3, &[]), ShutdownStep::Flush);
+  }
+
+  #[test]
+  fn nothing_to_stop() {
+    let mut shutdown = Shutdown::new(1000);
+    assert_eq!(shutdown.next_step(0, &[]), ShutdownStep::Flush);
+  }
+}
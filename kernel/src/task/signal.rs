@@ -1,6 +1,541 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use crate::memory::address::VirtualAddress;
+use super::memory::USER_KERNEL_BARRIER;
+
 /// Subset of POSIX signals, useful for modifying process state
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Signal {
+  Hangup,
   Segfault,
   UserInterrupt,
   UserQuit,
+  Kill,
+  Terminate,
+  Stop,
+  Continue,
+}
+
+impl Signal {
+  pub fn from_number(number: u32) -> Option<Signal> {
+    match number {
+      syscall::signals::HUP => Some(Signal::Hangup),
+      syscall::signals::SEGFAULT => Some(Signal::Segfault),
+      syscall::signals::INT => Some(Signal::UserInterrupt),
+      syscall::signals::QUIT => Some(Signal::UserQuit),
+      syscall::signals::KILL => Some(Signal::Kill),
+      syscall::signals::TERM => Some(Signal::Terminate),
+      syscall::signals::STOP => Some(Signal::Stop),
+      syscall::signals::CONTINUE => Some(Signal::Continue),
+      _ => None,
+    }
+  }
+
+  pub fn get_number(&self) -> u32 {
+    match self {
+      Signal::Hangup => syscall::signals::HUP,
+      Signal::Segfault => syscall::signals::SEGFAULT,
+      Signal::UserInterrupt => syscall::signals::INT,
+      Signal::UserQuit => syscall::signals::QUIT,
+      Signal::Kill => syscall::signals::KILL,
+      Signal::Terminate => syscall::signals::TERM,
+      Signal::Stop => syscall::signals::STOP,
+      Signal::Continue => syscall::signals::CONTINUE,
+    }
+  }
+
+  /// The status reported to a parent waiting on a process that was
+  /// terminated by this signal
+  pub fn get_exit_status(&self) -> u32 {
+    syscall::signals::STATUS_SIGNALED | self.get_number()
+  }
+}
+
+/// Only signals with a number below this can be handled or left pending
+pub const SIGNAL_COUNT: u32 = 32;
+
+pub const USER_CODE_SELECTOR: u32 = 0x1b;
+pub const USER_DATA_SELECTOR: u32 = 0x23;
+
+/// Flags that a handler may change on behalf of the interrupted code: the
+/// arithmetic flags, trap, direction, alignment check, and CPUID support.
+/// Everything else (IOPL, VM86, nested task) stays under kernel control.
+const RESTORABLE_FLAGS: u32 = 0x0024_0dd5;
+/// Interrupts remain enabled in userspace, and bit 1 is always set
+const REQUIRED_FLAGS: u32 = 0x0000_0202;
+/// Trap and direction are cleared when entering a handler, so that it starts
+/// from the state a compiled function expects
+const HANDLER_CLEARED_FLAGS: u32 = 0x0000_0500;
+
+/// The complete register state of a process at the moment a signal arrived.
+/// It is written to the process's own stack before the handler is entered,
+/// and read back by sigreturn so that the interrupted code can continue as if
+/// nothing had happened.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub struct SignalFrame {
+  pub signal: u32,
+
+  pub eax: u32,
+  pub ebx: u32,
+  pub ecx: u32,
+  pub edx: u32,
+  pub ebp: u32,
+  pub esi: u32,
+  pub edi: u32,
+
+  pub eip: u32,
+  pub cs: u32,
+  pub eflags: u32,
+  pub esp: u32,
+  pub ss: u32,
+
+  pub ds: u32,
+  pub es: u32,
+  pub fs: u32,
+  pub gs: u32,
+}
+
+impl SignalFrame {
+  /// The frame lives in user memory while the handler runs, so the handler is
+  /// free to modify it. Before it is restored, make sure it can only return
+  /// to userspace code with userspace selectors.
+  pub fn is_valid(&self) -> bool {
+    let optional_segment = |s: u32| s == 0 || s == USER_DATA_SELECTOR;
+    self.cs == USER_CODE_SELECTOR
+      && self.ss == USER_DATA_SELECTOR
+      && self.ds == USER_DATA_SELECTOR
+      && self.es == USER_DATA_SELECTOR
+      && optional_segment(self.fs)
+      && optional_segment(self.gs)
+      && (self.eip as usize) < USER_KERNEL_BARRIER
+      && (self.esp as usize) < USER_KERNEL_BARRIER
+  }
+
+  /// The eflags value to place back in the interrupted code
+  pub fn restored_flags(&self) -> u32 {
+    (self.eflags & RESTORABLE_FLAGS) | REQUIRED_FLAGS
+  }
+
+  /// The eflags value to use while running the handler for this frame
+  pub fn handler_flags(&self) -> u32 {
+    self.restored_flags() & !HANDLER_CLEARED_FLAGS
+  }
+}
+
+/// Determine where a signal frame goes on a stack whose top is at `esp`.
+/// Below the frame sit the arguments for entering the handler: the address it
+/// returns to, and the signal number. Returns the new stack pointer and the
+/// address of the frame, or None if the stack has no room.
+pub fn frame_location(esp: usize) -> Option<(usize, usize)> {
+  let frame_addr = esp.checked_sub(core::mem::size_of::<SignalFrame>())? & !3;
+  let new_esp = frame_addr.checked_sub(8)?;
+  Some((new_esp, frame_addr))
+}
+
+/// Write the frame and handler arguments to the locations returned by
+/// `frame_location`. The caller needs to ensure the range is writable.
+pub unsafe fn write_frame(new_esp: usize, frame_addr: usize, frame: &SignalFrame, restorer: u32) {
+  core::ptr::write_unaligned(frame_addr as *mut SignalFrame, *frame);
+  core::ptr::write_unaligned(new_esp as *mut u32, restorer);
+  core::ptr::write_unaligned((new_esp + 4) as *mut u32, frame.signal);
+}
+
+pub unsafe fn read_frame(frame_addr: usize) -> SignalFrame {
+  core::ptr::read_unaligned(frame_addr as *const SignalFrame)
+}
+
+/// A userspace function that runs when a signal arrives, along with the
+/// trampoline it returns to. The trampoline calls sigreturn to resume the
+/// interrupted code.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SignalHandler {
+  pub function: VirtualAddress,
+  pub restorer: VirtualAddress,
+}
+
+/// A signal taken off the pending set, ready to be delivered
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PendingSignal {
+  /// Run the installed handler
+  Handle(u32, SignalHandler),
+  /// The handler was removed before the signal could be delivered
+  Default(u32),
+}
+
+/// Per-process signal bookkeeping: installed handlers, signals that still
+/// need to be delivered, and the frames of handlers that are currently
+/// running. A signal that arrives during a handler pushes another frame, so
+/// handlers unwind in the reverse order they were entered.
+#[derive(Clone)]
+pub struct SignalState {
+  handlers: BTreeMap<u32, SignalHandler>,
+  pending: u32,
+  /// Signals installed with SA_RESTART. Blocked syscalls they interrupt are
+  /// restarted after the handler, instead of failing.
+  restart: u32,
+  /// Signals that are discarded on arrival, rather than handled or given
+  /// their default action
+  ignored: u32,
+  active_frames: Vec<usize>,
+}
+
+impl SignalState {
+  pub fn new() -> Self {
+    Self {
+      handlers: BTreeMap::new(),
+      pending: 0,
+      restart: 0,
+      ignored: 0,
+      active_frames: Vec::new(),
+    }
+  }
+
+  /// Install or remove the handler for a signal. KILL and STOP always have
+  /// their default behavior.
+  pub fn set_handler(&mut self, signal: u32, handler: Option<SignalHandler>) -> Result<(), ()> {
+    if signal == 0 || signal >= SIGNAL_COUNT {
+      return Err(());
+    }
+    if signal == syscall::signals::KILL || signal == syscall::signals::STOP {
+      return Err(());
+    }
+    self.ignored &= !(1 << signal);
+    match handler {
+      Some(h) => self.handlers.insert(signal, h),
+      None => {
+        self.restart &= !(1 << signal);
+        self.handlers.remove(&signal)
+      },
+    };
+    Ok(())
+  }
+
+  /// Choose whether a syscall interrupted by this signal is restarted once
+  /// its handler returns, or fails with Interrupted
+  pub fn set_restart(&mut self, signal: u32, restart: bool) {
+    if signal >= SIGNAL_COUNT {
+      return;
+    }
+    if restart {
+      self.restart |= 1 << signal;
+    } else {
+      self.restart &= !(1 << signal);
+    }
+  }
+
+  /// Discard a signal whenever it arrives. This replaces any handler, and a
+  /// pending delivery is dropped. Like handlers, KILL and STOP can't be
+  /// ignored.
+  pub fn ignore(&mut self, signal: u32) -> Result<(), ()> {
+    self.set_handler(signal, None)?;
+    self.ignored |= 1 << signal;
+    Ok(())
+  }
+
+  pub fn is_ignored(&self, signal: u32) -> bool {
+    signal < SIGNAL_COUNT && self.ignored & (1 << signal) != 0
+  }
+
+  pub fn get_handler(&self, signal: u32) -> Option<SignalHandler> {
+    self.handlers.get(&signal).copied()
+  }
+
+  /// Queue a signal for delivery. Returns false if no handler is installed,
+  /// in which case the signal's default action applies.
+  pub fn raise(&mut self, signal: u32) -> bool {
+    if self.get_handler(signal).is_none() {
+      return false;
+    }
+    self.pending |= 1 << signal;
+    true
+  }
+
+  /// The lowest-numbered pending signal that still has a handler, which is
+  /// the next one to be delivered
+  fn next_pending(&self) -> Option<u32> {
+    let mut pending = self.pending;
+    while pending != 0 {
+      let signal = pending.trailing_zeros();
+      if self.handlers.contains_key(&signal) {
+        return Some(signal);
+      }
+      pending &= !(1 << signal);
+    }
+    None
+  }
+
+  /// Blocked syscalls check this to decide whether to give up early, so that
+  /// the handler can run
+  pub fn has_pending(&self) -> bool {
+    self.next_pending().is_some()
+  }
+
+  /// Whether a syscall that was just interrupted should be restarted, based
+  /// on the handler that is about to run
+  pub fn restarts_next(&self) -> bool {
+    match self.next_pending() {
+      Some(signal) => self.restart & (1 << signal) != 0,
+      None => false,
+    }
+  }
+
+  /// Remove the lowest-numbered pending signal. If its handler was removed
+  /// after it arrived, its default action applies instead, unless the signal
+  /// has since been ignored.
+  pub fn take_pending(&mut self) -> Option<PendingSignal> {
+    while self.pending != 0 {
+      let signal = self.pending.trailing_zeros();
+      self.pending &= !(1 << signal);
+      if let Some(handler) = self.get_handler(signal) {
+        return Some(PendingSignal::Handle(signal, handler));
+      }
+      if !self.is_ignored(signal) {
+        return Some(PendingSignal::Default(signal));
+      }
+    }
+    None
+  }
+
+  pub fn enter_handler(&mut self, frame_addr: usize) {
+    self.active_frames.push(frame_addr);
+  }
+
+  /// Remove the most recently entered handler, returning the address of the
+  /// frame that needs to be restored
+  pub fn leave_handler(&mut self) -> Option<usize> {
+    self.active_frames.pop()
+  }
+
+  pub fn handler_depth(&self) -> usize {
+    self.active_frames.len()
+  }
+
+  /// Handlers point into the old program's code, so none of them survive
+  /// exec. Ignored signals stay ignored, so that a program started by a
+  /// daemon or nohup keeps its parent's choices.
+  pub fn reset_for_exec(&mut self) {
+    let ignored = self.ignored;
+    *self = SignalState::new();
+    self.ignored = ignored;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{frame_location, read_frame, write_frame, PendingSignal, SignalFrame, SignalHandler, SignalState, VirtualAddress};
+  use alloc::vec;
+
+  fn interrupted_state() -> SignalFrame {
+    SignalFrame {
+      signal: 0,
+      eax: 0x11111111,
+      ebx: 0x22222222,
+      ecx: 0x33333333,
+      edx: 0x44444444,
+      ebp: 0x55555555,
+      esi: 0x66666666,
+      edi: 0x77777777,
+      eip: 0x00401234,
+      cs: 0x1b,
+      // carry, zero, direction, and interrupts
+      eflags: 0x0000_0643,
+      esp: 0xbfff_f000,
+      ss: 0x23,
+      ds: 0x23,
+      es: 0x23,
+      fs: 0,
+      gs: 0x23,
+    }
+  }
+
+  fn handled(pending: Option<PendingSignal>) -> (u32, SignalHandler) {
+    match pending {
+      Some(PendingSignal::Handle(signal, handler)) => (signal, handler),
+      other => panic!("Expected a handled signal, got {:?}", other),
+    }
+  }
+
+  fn handler() -> SignalHandler {
+    SignalHandler {
+      function: VirtualAddress::new(0x00402000),
+      restorer: VirtualAddress::new(0x00403000),
+    }
+  }
+
+  #[test]
+  fn pending_signals() {
+    let mut state = SignalState::new();
+    assert!(!state.raise(syscall::signals::INT));
+    assert!(state.set_handler(syscall::signals::KILL, Some(handler())).is_err());
+    assert!(state.set_handler(syscall::signals::STOP, Some(handler())).is_err());
+    assert!(state.set_handler(40, Some(handler())).is_err());
+    state.set_handler(syscall::signals::INT, Some(handler())).unwrap();
+    state.set_handler(syscall::signals::HUP, Some(handler())).unwrap();
+    assert!(state.raise(syscall::signals::INT));
+    assert!(state.raise(syscall::signals::HUP));
+    assert_eq!(state.take_pending(), Some(PendingSignal::Handle(syscall::signals::HUP, handler())));
+    // A handler removed while its signal is pending no longer runs, and the
+    // signal gets its default action instead
+    state.set_handler(syscall::signals::INT, None).unwrap();
+    assert_eq!(state.take_pending(), Some(PendingSignal::Default(syscall::signals::INT)));
+    assert_eq!(state.take_pending(), None);
+
+    // Ignoring the signal drops it entirely
+    state.set_handler(syscall::signals::HUP, Some(handler())).unwrap();
+    assert!(state.raise(syscall::signals::HUP));
+    state.ignore(syscall::signals::HUP).unwrap();
+    assert_eq!(state.take_pending(), None);
+  }
+
+  #[test]
+  fn restart_follows_next_handler() {
+    use syscall::signals::{INT, TERM};
+    let mut state = SignalState::new();
+    state.set_handler(INT, Some(handler())).unwrap();
+    state.set_handler(TERM, Some(handler())).unwrap();
+    state.set_restart(TERM, true);
+    assert!(!state.has_pending());
+    state.raise(TERM);
+    assert!(state.has_pending());
+    assert!(state.restarts_next());
+    // INT is delivered first, and its handler didn't ask for a restart
+    state.raise(INT);
+    assert!(!state.restarts_next());
+    state.take_pending();
+    assert!(state.restarts_next());
+
+    // Removing a handler forgets its flag
+    state.set_handler(TERM, None).unwrap();
+    assert!(!state.has_pending());
+    state.set_handler(TERM, Some(handler())).unwrap();
+    state.raise(TERM);
+    assert!(!state.restarts_next());
+  }
+
+  #[test]
+  fn ignored_signals() {
+    use syscall::signals::{CHILD, HUP, INT, KILL};
+    let mut state = SignalState::new();
+    assert!(state.ignore(KILL).is_err());
+    assert!(!state.is_ignored(KILL));
+    state.set_handler(HUP, Some(handler())).unwrap();
+    state.raise(HUP);
+    // Ignoring replaces the handler, and drops the pending delivery
+    state.ignore(HUP).unwrap();
+    assert!(state.is_ignored(HUP));
+    assert!(!state.has_pending());
+    state.ignore(CHILD).unwrap();
+
+    // Ignored signals outlive exec, unlike handlers
+    state.set_handler(INT, Some(handler())).unwrap();
+    state.reset_for_exec();
+    assert!(state.is_ignored(CHILD));
+    assert!(state.is_ignored(HUP));
+    assert_eq!(state.get_handler(INT), None);
+
+    // Installing a handler, or restoring the default, stops ignoring
+    state.set_handler(HUP, Some(handler())).unwrap();
+    assert!(!state.is_ignored(HUP));
+    state.set_handler(CHILD, None).unwrap();
+    assert!(!state.is_ignored(CHILD));
+  }
+
+  #[test]
+  fn sanitized_flags() {
+    let mut frame = interrupted_state();
+    // IOPL 3, VM86, and nested task can't be smuggled in through the frame
+    frame.eflags = 0x0002_7000 | 0x0000_0001;
+    assert_eq!(frame.restored_flags(), 0x0000_0203);
+    frame.eflags = 0x0000_0643;
+    assert_eq!(frame.restored_flags(), 0x0000_0643);
+    assert_eq!(frame.handler_flags(), 0x0000_0243);
+  }
+
+  #[test]
+  fn invalid_frames() {
+    assert!(interrupted_state().is_valid());
+    let mut frame = interrupted_state();
+    frame.cs = 0x08;
+    assert!(!frame.is_valid());
+    let mut frame = interrupted_state();
+    frame.ds = 0x10;
+    assert!(!frame.is_valid());
+    let mut frame = interrupted_state();
+    frame.eip = 0xc0100000;
+    assert!(!frame.is_valid());
+    assert!(frame_location(16).is_none());
+  }
+
+  #[test]
+  fn nested_handlers_restore_state() {
+    let mut stack = vec![0u32; 256];
+    let stack_top = stack.as_mut_ptr() as usize + stack.len() * 4;
+    let mut state = SignalState::new();
+    state.set_handler(syscall::signals::INT, Some(handler())).unwrap();
+    state.set_handler(syscall::signals::TERM, Some(handler())).unwrap();
+
+    // The saved values are only data, but the frames are placed in the buffer
+    let mut interrupted = interrupted_state();
+    let original = interrupted;
+
+    state.raise(syscall::signals::INT);
+    let (signal, first) = handled(state.take_pending());
+    interrupted.signal = signal;
+    let (first_esp, first_addr) = frame_location(stack_top).unwrap();
+    unsafe { write_frame(first_esp, first_addr, &interrupted, first.restorer.as_u32()) };
+    state.enter_handler(first_addr);
+    assert_eq!(stack[(first_esp - stack.as_ptr() as usize) / 4], 0x00403000);
+    assert_eq!(stack[(first_esp - stack.as_ptr() as usize) / 4 + 1], syscall::signals::INT);
+
+    // The handler clobbers every register, then is interrupted itself
+    let mut in_handler = SignalFrame {
+      signal: syscall::signals::TERM,
+      eax: 0xdead0001,
+      ebx: 0xdead0002,
+      ecx: 0xdead0003,
+      edx: 0xdead0004,
+      ebp: 0xdead0005,
+      esi: 0xdead0006,
+      edi: 0xdead0007,
+      eip: 0x00402010,
+      cs: 0x1b,
+      eflags: interrupted.handler_flags(),
+      esp: 0xbfff_0000,
+      ss: 0x23,
+      ds: 0x23,
+      es: 0x23,
+      fs: 0x23,
+      gs: 0,
+    };
+    state.raise(syscall::signals::TERM);
+    let (signal, second) = handled(state.take_pending());
+    in_handler.signal = signal;
+    let (second_esp, second_addr) = frame_location(first_esp - 0x20).unwrap();
+    unsafe { write_frame(second_esp, second_addr, &in_handler, second.restorer.as_u32()) };
+    state.enter_handler(second_addr);
+    assert_eq!(state.handler_depth(), 2);
+
+    // Returning from the inner handler resumes the outer one
+    let restored = unsafe { read_frame(state.leave_handler().unwrap()) };
+    assert_eq!(restored, in_handler);
+    assert!(restored.is_valid());
+
+    // Returning from the outer handler resumes the original code, with every
+    // register and flag intact
+    let restored = unsafe { read_frame(state.leave_handler().unwrap()) };
+    assert!(restored.is_valid());
+    assert_eq!(restored.eax, original.eax);
+    assert_eq!(restored.ebx, original.ebx);
+    assert_eq!(restored.ecx, original.ecx);
+    assert_eq!(restored.edx, original.edx);
+    assert_eq!(restored.ebp, original.ebp);
+    assert_eq!(restored.esi, original.esi);
+    assert_eq!(restored.edi, original.edi);
+    assert_eq!(restored.eip, original.eip);
+    assert_eq!(restored.esp, original.esp);
+    assert_eq!((restored.ds, restored.es, restored.fs, restored.gs), (0x23, 0x23, 0, 0x23));
+    assert_eq!(restored.restored_flags(), original.eflags);
+    assert!(state.leave_handler().is_none());
+  }
 }
@@ -23,8 +23,9 @@ use alloc::boxed::Box;
 use alloc::vec::Vec;
 use core::sync::atomic::{AtomicU32, Ordering};
 use core::ops::Range;
+use crate::locks::{ordered, LockLevel};
 use crate::memory::address::{PhysicalAddress, VirtualAddress};
-use spin::RwLock;
+use crate::locks::RwLock;
 
 pub static ALLOCATED_KERNEL_STACKS: RwLock<Vec<u8>> = RwLock::new(Vec::new());
 
@@ -65,6 +66,7 @@ pub fn allocate_stack() -> Box<[u8]> {
 }
 
 fn find_free_space(stacks: &RwLock<Vec<u8>>) -> usize {
+  let _order = ordered(LockLevel::KernelStacks);
   let mut alloc_map = stacks.write();
   for (index, map) in alloc_map.iter_mut().enumerate() {
     let mut stack_index = index * 8;
@@ -95,6 +97,7 @@ pub fn free_stack(stack: Box<[u8]>) {
 }
 
 fn free_index(stacks: &RwLock<Vec<u8>>, index: usize) {
+  let _order = ordered(LockLevel::KernelStacks);
   let mut alloc_map = stacks.write();
   let byte_index = index / 8;
   let local_index = index & 7;
@@ -16,20 +16,24 @@ use super::id::ProcessID;
 /// 
 /// Sleeping is used when a process wants to pause execution and yield the CPU
 /// to other processes for a fixed period of time. When a process enters sleep,
-/// it specifies how long it should sleep for. The Sleeping state stores a
-/// counter of time remaining before the process should resume. At regular
-/// intervals, the kernel updates the counter for all sleeping proceses. When
-/// this counter reaches zero, the process state is replaced with Running.
+/// it specifies how long it should sleep for. The duration is converted to the
+/// absolute system tick at which the process should wake, so it does not drift
+/// no matter how late the timer interrupt that checks it arrives. On every
+/// tick, the kernel compares the current tick against all sleeping processes.
+/// Once the wake tick has been reached, the process state is replaced with
+/// Running.
 /// 
 ///             Call Sleep(n)
-///   [Running] ------------> [Sleeping(n)] --
-///       ^                      |   ^        | Kernel updates the remaining
-///       |                      |   |        | value on an interrupt
-///       | n has reached zero   |    --------
+///   [Running] ------------> [Sleeping(now + n)] --
+///       ^                      |   ^              | Kernel checks the current
+///       |                      |   |              | tick on an interrupt
+///       | wake tick reached    |    --------------
 ///        ----------------------
 /// 
-/// A process can be Paused by external signals. It needs to be woken up by a
-/// different signal to return to Running state.
+/// A process can also be stopped by external signals, until a different
+/// signal continues it. Stopping is tracked apart from the RunState, which
+/// keeps describing what the process was doing, so that it picks up where it
+/// left off.
 /// 
 /// When a process chooses to listen for IPC messages, it switches to
 /// AwaitingIPC state. The scheduler will not enter this process, but IPC
@@ -42,16 +46,21 @@ use super::id::ProcessID;
 /// the return code. The next time the scheduler enters that process, it sets up
 /// the registers to return that code, updates to a Running state, and resumes
 /// execution.
+/// 
+/// A process that calls vfork lends its address space to the new child, and is
+/// suspended in a VForkWaiting state until that child either calls exec or
+/// exits. Until then, the child runs on the parent's memory and user stack, so
+/// it must not return from the function that called vfork -- it may only exec
+/// a new program or exit. Once the child stops borrowing the address space,
+/// the parent returns to Running and receives the child's ID.
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub enum RunState {
   /// Running normally
   Running,
   /// Process has exited, or been terminated. The kernel should clean it up.
   Terminated,
-  /// Sleeping for a fixed amount of time
-  Sleeping(usize),
-  /// Paused because of a signal
-  Paused,
+  /// Sleeping until the system reaches a specific tick
+  Sleeping(u32),
   /// Waiting for IPC messages, with an optional timeout
   AwaitingIPC(Option<usize>),
   /// Waiting for a child process to finish executing
@@ -67,6 +76,8 @@ pub enum RunState {
   HandlingInterrupt(u32),
   /// Blocked on a file IO operation, with an optional timeout
   FileIO(Option<usize>),
-  /// Blocked on a hardware device, with an optional timeout
-  HardwareIO(Option<usize>),
+  /// Blocked on a hardware device, with an optional deadline tick
+  HardwareIO(Option<u32>),
+  /// Suspended after vfork, until the child execs or exits
+  VForkWaiting(ProcessID),
 }
@@ -1,20 +1,21 @@
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
-use alloc::vec::Vec;
 use core::ops::DerefMut;
-use crate::memory::physical::{allocated_frame::AllocatedFrame, free_frame, reference_frame_at_address};
+use crate::locks::{ordered, LockLevel};
+use crate::memory::physical::reference_frame_at_address;
 use crate::memory::address::VirtualAddress;
 use crate::memory::virt::map_kernel_stack;
 use crate::memory::virt::page_table::PageTableReference;
-use spin::RwLock;
+use crate::locks::RwLock;
 use super::id::{IDGenerator, ProcessID};
 use super::paging;
-use super::process::Process;
+use super::process::{Process, WaitTarget};
+use super::scheduler::{time_slice_ticks, RUN_QUEUE, TIME_SLICE};
 use super::stack::UnmappedPage;
 
-/// The task map allows fetching process information by ID. It's also used for
-/// scheduling, to determine which process should run next.
+/// The task map allows fetching process information by ID. Scheduling uses the
+/// separate run queue, which only contains runnable processes.
 /// Previous versions of the kernel used locks for every mutable field in the
 /// process, rather than placing the whole process in a single lock. This
 /// created a lot of extra code and room for potential deadlocks, though, so
@@ -28,50 +29,63 @@ pub static NEXT_ID: IDGenerator = IDGenerator::new();
 pub static CURRENT_ID: RwLock<ProcessID> = RwLock::new(ProcessID::new(0));
 
 /// Cooperatively yield, forcing the scheduler to switch to another process
+/// Give up the CPU to the next runnable process. Interrupts are held off
+/// until the switch completes, so that the timer can't start a second switch
+/// from inside this one.
 pub fn yield_coop() {
-  let next = find_next_running_process();
+  yield_with(find_next_running_process)
+}
+
+/// Give up the CPU to a specific process, like a consumer that a producer
+/// just handed some data. If that process can't run, this is a regular yield.
+/// Only this one switch is affected; later turns follow the usual order.
+pub fn yield_to(target: ProcessID) {
+  yield_with(|| RUN_QUEUE.hand_off(get_current_id(), target))
+}
+
+fn yield_with<F>(choose: F)
+  where F: FnOnce() -> Option<ProcessID> {
+  use crate::interrupts::control::{cli, is_interrupt_enabled, sti};
+
+  let reenable = is_interrupt_enabled();
+  cli();
+  super::preempt::PREEMPTION.clear_pending();
+  let next = choose();
   match next {
     Some(id) => switch_to(&id),
     None => (),
   }
+  if reenable {
+    sti();
+  }
 }
 
 pub fn initialize() {
   let idle_task = super::process::Process::initial(0);
   let id = *idle_task.get_id();
   let entry = Arc::new(RwLock::new(idle_task));
-  let mut map = TASK_MAP.write();
-  map.insert(id, entry);
+  {
+    let _order = ordered(LockLevel::TaskMap);
+    let mut map = TASK_MAP.write();
+    map.insert(id, entry.clone());
+  }
+  let _order = ordered(LockLevel::Process);
+  entry.write().enter_run_queue();
 }
 
-/// Find another process to switch to. If non is available (eg, we are currently
-/// in the idle task and all other tasks are blocked), it will return None.
-/// For now, our switching algo is simple: find the first process whose ID comes
-/// after the current ID. If none is found, return the first runnable process we
-/// encountered in our first pass.
+/// Find another process to switch to. If none is available (eg, we are
+/// currently in the idle task and all other tasks are blocked), it will return
+/// None.
+/// Processes take turns in order of ID: the next runnable process after the
+/// current ID is chosen, wrapping around to the start. Only runnable processes
+/// are in the run queue, so blocked processes add nothing to the search.
 pub fn find_next_running_process() -> Option<ProcessID> {
   let current_id = *CURRENT_ID.read();
-  let mut first_runnable = None;
-  let task_map = TASK_MAP.read();
-  for (id, process) in task_map.iter() {
-    if *id == current_id {
-      continue;
-    }
-    if process.read().can_resume() {
-      if first_runnable.is_none() {
-        first_runnable.replace(*id);
-      }
-      if *id > current_id {
-        return Some(*id);
-      }
-    }
-  }
-  // If we hit the end of the list, loop back to the first running process
-  // we found. If there is none, we stay on the current process.
-  first_runnable
+  RUN_QUEUE.next_after(current_id)
 }
 
 pub fn get_process(id: &ProcessID) -> Option<Arc<RwLock<Process>>> {
+  let _order = ordered(LockLevel::TaskMap);
   let map = TASK_MAP.read();
   let entry = map.get(id)?;
   Some(entry.clone())
@@ -83,6 +97,7 @@ pub fn get_current_id() -> ProcessID {
 
 pub fn get_current_process() -> Arc<RwLock<Process>> {
   let current_id: ProcessID = *CURRENT_ID.read();
+  let _order = ordered(LockLevel::TaskMap);
   let map = TASK_MAP.read();
   let entry = map.get(&current_id).expect("Current process does not exist!");
   entry.clone()
@@ -90,6 +105,7 @@ pub fn get_current_process() -> Arc<RwLock<Process>> {
 
 pub fn for_each_process<F>(f: F)
   where F: Fn(Arc<RwLock<Process>>) -> () {
+  let _order = ordered(LockLevel::TaskMap);
   for (_, proc) in TASK_MAP.read().iter() {
     f(proc.clone());
   }
@@ -97,11 +113,22 @@ pub fn for_each_process<F>(f: F)
 
 pub fn for_each_process_mut<F>(mut f: F)
   where F: FnMut(Arc<RwLock<Process>>) -> () {
+  let _order = ordered(LockLevel::TaskMap);
   for (_, proc) in TASK_MAP.read().iter() {
     f(proc.clone());
   }
 }
 
+/// Run a function with the task map locked, so that no process can be
+/// created or exit until it returns. Useful when a decision about every
+/// process has to stay true while the caller acts on it.
+pub fn with_task_map<F, T>(f: F) -> T
+  where F: FnOnce(&BTreeMap<ProcessID, Arc<RwLock<Process>>>) -> T {
+  let _order = ordered(LockLevel::TaskMap);
+  let map = TASK_MAP.read();
+  f(&map)
+}
+
 /// When a process gets forked, we create a duplicate process with an empty
 /// stack. Previously the kernel used a bunch of hacks to duplicate the stack
 /// and ensure that the child process returned through all the callers in the
@@ -109,16 +136,50 @@ pub fn for_each_process_mut<F>(mut f: F)
 /// return to the userspace entrypoint with the same registers.
 /// When a process enters a syscall, we store a pointer to the
 pub fn fork(current_ticks: u32, include_userspace: bool) -> ProcessID {
+  let current_process = get_current_process();
+  let next_id = NEXT_ID.next();
+  // See fork.rs for the order in which each step takes its locks
+  super::fork::fork_process(
+    &TASK_MAP,
+    &current_process,
+    next_id,
+    current_ticks,
+    || fork_page_directory(include_userspace),
+    |child| prepare_child(&current_process, child),
+  );
+  next_id
+}
+
+/// A vfork creates a child that runs in the parent's address space, skipping
+/// the page directory copy entirely. The parent is suspended until the child
+/// calls exec or exits; until then the child may not return from the function
+/// that called vfork, since it shares the parent's user stack.
+pub fn vfork(current_ticks: u32) -> ProcessID {
   let current_process = get_current_process();
   let next_id = NEXT_ID.next();
   let mut child = {
-    let parent = current_process.read();
-    parent.create_fork(next_id, current_ticks)
+    let _order = ordered(LockLevel::Process);
+    let mut parent = current_process.write();
+    // Suspend the parent before the child becomes visible to the scheduler,
+    // so the child can't release it before it starts waiting
+    parent.vfork_wait(next_id);
+    parent.create_vfork(next_id, current_ticks)
   };
-  super::io::reopen_files(*child.get_id(), &mut child.open_files);
+  paging::share_page_directory(child.page_directory.get_address());
+  prepare_child(&current_process, &mut child);
+  super::fork::add_task(&TASK_MAP, child);
+  yield_coop();
+  next_id
+}
+
+/// Finish setting up a newly forked child, before it is added to the task map
+/// where the scheduler can run it.
+fn prepare_child(current_process: &Arc<RwLock<Process>>, child: &mut Process) {
+  let child_id = *child.get_id();
+  super::io::reopen_files(child_id, &mut child.open_files);
   {
     // re-open the executable file
-    match super::io::reopen_executable(*child.get_id(), child.get_exec_file()) {
+    match super::io::reopen_executable(child_id, child.get_exec_file()) {
       Some((drive, handle)) => {
         child.set_exec_file(drive, handle);
       },
@@ -128,11 +189,13 @@ pub fn fork(current_ticks: u32, include_userspace: bool) -> ProcessID {
     }
   }
   map_kernel_stack(child.get_stack_range());
-  child.page_directory = fork_page_directory(include_userspace);
-  super::stack::duplicate_stack(
-    current_process.read().get_kernel_stack(),
-    child.get_kernel_stack_mut(),
-  );
+  {
+    let _order = ordered(LockLevel::Process);
+    super::stack::duplicate_stack(
+      current_process.read().get_kernel_stack(),
+      child.get_kernel_stack_mut(),
+    );
+  }
   // Move the stack pointer down past the 5 values from the interrupt,
   // and the 10 values pushed by the syscall wrapper.
   // It should return within the syscall wrapper, popping off the registers and
@@ -140,12 +203,15 @@ pub fn fork(current_ticks: u32, include_userspace: bool) -> ProcessID {
   child.stack_pointer -= 5 * core::mem::size_of::<u32>();
   child.stack_push_u32(0); // replace eax with 0 in the child
   child.stack_pointer -= 9 * core::mem::size_of::<u32>();
-  //crate::kprintln!("Child {:?} ({:?}) stack: {:?}", next_id, current_process.read().get_id(), child.get_stack_range());
-  {
-    let mut map = TASK_MAP.write();
-    map.insert(next_id, Arc::new(RwLock::new(child)));
+  //crate::kprintln!("Child {:?} ({:?}) stack: {:?}", child_id, current_process.read().get_id(), child.get_stack_range());
+}
+
+/// When a vfork child stops borrowing its parent's address space, the parent
+/// can resume execution.
+pub fn release_vfork_parent(parent_id: ProcessID, child_id: ProcessID) {
+  if let Some(parent) = get_process(&parent_id) {
+    parent.write().vfork_release(child_id);
   }
-  next_id
 }
 
 pub fn kfork(dest: extern "C" fn() -> ()) -> ProcessID {
@@ -153,6 +219,7 @@ pub fn kfork(dest: extern "C" fn() -> ()) -> ProcessID {
   {
     let child_lock = get_process(&child_id).unwrap();
     let mut child = child_lock.write();
+    child.protect_from_oom();
     child.stack_push_u32(dest as u32);
     //crate::kprintln!("Child %esp: {:#0x}", child.stack_pointer);
   }
@@ -160,24 +227,58 @@ pub fn kfork(dest: extern "C" fn() -> ()) -> ProcessID {
   child_id
 }
 
+/// Like kfork, but the child begins by calling `dest` with an argument, so
+/// that each child can be handed its own work. `dest` must never return.
+pub fn kfork_with_arg(dest: extern "C" fn(usize) -> (), arg: usize) -> ProcessID {
+  let child_id = fork(0, false);
+  {
+    let child_lock = get_process(&child_id).unwrap();
+    let mut child = child_lock.write();
+    child.protect_from_oom();
+    // Lay out the stack as if dest had been called: its argument, then a
+    // return address, which is never used
+    child.stack_push_u32(arg as u32);
+    child.stack_push_u32(0);
+    child.stack_push_u32(dest as u32);
+  }
+  child_id
+}
+
+/// Check whether a running child of `parent` could still end a wait
+pub fn has_live_child(parent: ProcessID, target: WaitTarget) -> bool {
+  super::fork::has_live_child(&TASK_MAP, parent, target)
+}
+
 pub fn clean_up_process(id: ProcessID) {
-  let task_lock = {
-    let mut task_map = TASK_MAP.write();
-    match task_map.remove(&id) {
-      Some(t) => t,
-      None => return,
-    }
-  };
+  if let Some(task_lock) = super::fork::remove_task(&TASK_MAP, id) {
+    release_process(id, task_lock);
+  }
+}
+
+/// Release everything held by a process that has been removed from the task
+/// map
+pub fn release_process(id: ProcessID, task_lock: Arc<RwLock<Process>>) {
+  // Anyone blocked waiting on a reply from the process would wait forever
+  for_each_process_mut(|p| {
+    let _order = ordered(LockLevel::Process);
+    p.write().ipc_recipient_exited(id);
+  });
   let mut task = task_lock.write();
-  crate::kprintln!("Clean up {:?}", task.get_id());
-  // Remove all references to memory held by the executable
+  crate::kprintln!(Debug, Scheduler; "Clean up {:?}", task.get_id());
+  // Handles and pages sent to the process that it never read need to be
+  // released
+  super::io::close_open_files(task.take_orphaned_ipc_handles());
+  super::paging::release_frames(task.take_orphaned_ipc_pages());
   let pagedir_address = task.page_directory.get_address();
-  let kstack_address = task.get_kernel_stack().as_ptr() as usize;
-  super::paging::unmap_terminated_task(pagedir_address, VirtualAddress::new(kstack_address));
-  // Free the frames that were allocated to support the task itself, like the
-  // page directory
-  crate::kprintln!("Clean up pagedir: {:?}", pagedir_address);
-  free_frame(AllocatedFrame::new(pagedir_address)).unwrap();
+  let kstack_address = VirtualAddress::new(task.get_kernel_stack().as_ptr() as usize);
+  // The kernel stack belongs to this process alone, even if the page
+  // directory is shared
+  super::paging::unmap_kernel_stack(pagedir_address, kstack_address);
+  // Remove all references to memory held by the executable, unless another
+  // process, like a vfork child or its parent, is still running in it
+  if super::paging::release_page_directory(pagedir_address) {
+    crate::kprintln!(Debug, Memory; "Clean up pagedir: {:?}", pagedir_address);
+  }
 }
 
 /// Execute a context switch to another process. If that process does not exist,
@@ -186,11 +287,16 @@ pub fn switch_to(id: &ProcessID) {
   let current_ptr;
   let next_ptr;
   {
-    // Nasty deref_mut hacks to get around the locks
+    // Look up both processes before locking either of them, so that the task
+    // map is never acquired while a process lock is held
     let current_lock = get_current_process();
+    let next_lock = get_process(id).unwrap();
+    // The guards can't be held across the switch, since the next process will
+    // resume with its own stack. Raw pointers let the switch access both
+    // processes after the locks are released.
+    let _order = ordered(LockLevel::Process);
     let mut current = current_lock.write();
     current_ptr = Some(current.deref_mut() as *mut Process);
-    let next_lock = get_process(id).unwrap();
     let mut next = next_lock.write();
     next_ptr = Some(next.deref_mut() as *mut Process);
   }
@@ -200,6 +306,7 @@ pub fn switch_to(id: &ProcessID) {
     let current = &mut *current_ptr.unwrap();
     let next = &mut *next_ptr.unwrap();
     crate::gdt::set_tss_stack_pointer(next.get_stack_range().end.as_u32() - 4);
+    TIME_SLICE.start(time_slice_ticks(next.get_nice()));
     llvm_asm!("push eax; push ecx; push edx; push ebx; push ebp; push esi; push edi" : : : "esp" : "intel", "volatile");
     {
       let pagedir_addr = next.page_directory.get_address().as_usize();
@@ -228,9 +335,29 @@ unsafe extern "cdecl" fn switch_inner(_pagedir_addr: usize, _current_sp_addr: us
 }
 
 pub fn update_timeouts(delta_ms: usize) {
+  let current_ticks = crate::time::system::get_system_ticks();
+  // If the clock has been set since the last tick, processes sleeping until a
+  // wall-clock time get new wake ticks, so they still wake when it arrives
+  let resync_time = if crate::time::system::take_clock_change() {
+    Some(crate::time::system::get_system_time().0)
+  } else {
+    None
+  };
+  // The tick is charged to whichever process was running. If it fired in the
+  // middle of a switch, nobody is charged.
+  let running = CURRENT_ID.try_read().map(|id| *id);
+  let _order = ordered(LockLevel::TaskMap);
   let task_map = TASK_MAP.read();
-  for (_, process) in task_map.iter() {
-    process.write().update_timeouts(delta_ms);
+  for (id, process) in task_map.iter() {
+    let _order = ordered(LockLevel::Process);
+    let mut process = process.write();
+    if Some(*id) == running {
+      process.charge_cpu_tick();
+    }
+    if let Some(current_time) = resync_time {
+      process.resync_wake_time(current_ticks, current_time);
+    }
+    process.update_timeouts(current_ticks, delta_ms);
   }
 }
 
@@ -240,7 +367,7 @@ pub fn fork_page_directory(include_userspace: bool) -> PageTableReference {
 
   // Create a new page directory
   let directory_frame = physical::allocate_frame().unwrap().to_frame();
-  crate::kprintln!("  New Dirframe @ {:?}", directory_frame.get_address());
+  crate::kprintln!(Debug, Memory; "  New Dirframe @ {:?}", directory_frame.get_address());
   let directory_scratch_space = UnmappedPage::map(directory_frame.get_address());
   let directory_table = page_table::PageTable::at_address(directory_scratch_space.virtual_address());
   directory_table.zero();
@@ -266,32 +393,31 @@ pub fn fork_page_directory(include_userspace: bool) -> PageTableReference {
       }
       let table_address = VirtualAddress::new(0xffc00000 + (dir_entry * 0x1000));
       let table = page_table::PageTable::at_address(table_address);
-      for table_index in 0..1024 {
-        let table_entry = table.get_mut(table_index);
-        if table_entry.is_present() {
-          // All entries, writable or not, now have an additional reference
-          let _ = reference_frame_at_address(table_entry.get_address())
-            // since the entire page table is being copied, there is no call to
-            // .map and we can safely dispose of this AllocatedFrame
-            .to_frame();
-
-          if table_entry.is_write_access_granted() {
-            table_entry.clear_write_access();
-            table_entry.set_cow();
-            {
-              let page_start =
-                (dir_entry * 4 * 1024 * 1024)
-                + table_index * 4 * 1024;
-              paging::invalidate_page(VirtualAddress::new(page_start));
-            }
-            crate::kprintln!("SET COW {} {}", dir_entry, table_index);
-          }
-
-          let ref_count = crate::memory::physical::get_current_refcount_for_address(table_entry.get_address());
-          crate::kprintln!("{:?} count is now {}", table_entry.get_address(), ref_count);
+      // Each shared page gains one reference here. The table itself is copied
+      // below, which doesn't touch the references of the pages it points to.
+      let locked = table.share_for_fork(|table_index, entry| {
+        // Since the entire page table is being copied, there is no call to
+        // .map and we can safely dispose of this AllocatedFrame
+        let _ = reference_frame_at_address(entry.get_address()).to_frame();
+        if entry.is_cow() {
+          let page_start = (dir_entry << 22) | (table_index << 12);
+          paging::invalidate_page(VirtualAddress::new(page_start));
         }
-      }
+        let ref_count = crate::memory::physical::get_current_refcount_for_address(entry.get_address());
+        crate::kprintln!(Debug, Memory; "{:?} count is now {}", entry.get_address(), ref_count);
+      });
       let table_frame = paging::duplicate_frame(table_address).to_frame();
+      if !locked.is_empty() {
+        let table_scratch_space = UnmappedPage::map(table_frame.get_address());
+        let child_table = page_table::PageTable::at_address(table_scratch_space.virtual_address());
+        for table_index in locked {
+          let page_start = VirtualAddress::new((dir_entry << 22) | (table_index << 12));
+          let copy = paging::duplicate_frame(page_start).to_frame();
+          // The lock belongs to the parent, and isn't inherited
+          child_table.get_mut(table_index).set_address(copy.get_address());
+          child_table.get_mut(table_index).clear_locked();
+        }
+      }
       directory_table.get_mut(dir_entry).set_address(table_frame.get_address());
       directory_table.get_mut(dir_entry).set_user_access();
       directory_table.get_mut(dir_entry).set_present();
@@ -1,4 +1,5 @@
 pub mod date;
 #[cfg(not(test))]
 pub mod system;
+pub mod ticks;
 pub mod timestamp;
@@ -1,13 +1,12 @@
 /// Utilities for managing system time
 
-use spin::Mutex;
-use core::sync::atomic::{AtomicU32, Ordering};
+use crate::locks::Mutex;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use crate::devices;
 use crate::interrupts;
 use super::timestamp::{Timestamp, TimestampHires};
 
-pub const HUNDRED_NS_PER_TICK: u64 = 100002;
-pub const MS_PER_TICK: usize = (HUNDRED_NS_PER_TICK / 10000) as usize;
+pub use super::ticks::{HUNDRED_NS_PER_TICK, MS_PER_TICK};
 
 /// Store a known fixed point in time, sourced from CMOS RTC or (in the future)
 /// a NTP service. We use the programmable timer to update an offset relative to
@@ -21,6 +20,10 @@ static TIME_OFFSET: Mutex<TimestampHires> = Mutex::new(TimestampHires(0));
 /// used for relative time offsets within the various kernel internals.
 static SYSTEM_TICKS: AtomicU32 = AtomicU32::new(0);
 
+/// Set when the known time changes, until the timer has moved the wake ticks
+/// of processes sleeping until a wall-clock time
+static CLOCK_CHANGED: AtomicBool = AtomicBool::new(false);
+
 /// Reset the known true reference point
 pub fn reset_known_time(time: u64) {
   let int_reenable = interrupts::control::is_interrupt_enabled();
@@ -31,11 +34,21 @@ pub fn reset_known_time(time: u64) {
     TIME_OFFSET.lock().set(0);
   }
 
+  // Sleeping processes are resynced on the next timer tick, which already
+  // visits every process. Doing it here would lock each process, and the
+  // clock may be set from a context that can't safely wait on those locks.
+  CLOCK_CHANGED.store(true, Ordering::SeqCst);
+
   if int_reenable {
     interrupts::control::sti();
   }
 }
 
+/// Returns true once after each change of the known time
+pub fn take_clock_change() -> bool {
+  CLOCK_CHANGED.swap(false, Ordering::SeqCst)
+}
+
 pub fn get_system_time() -> TimestampHires {
   let int_reenable = interrupts::control::is_interrupt_enabled();
   interrupts::control::cli();
@@ -0,0 +1,92 @@
+//! The PIT fires at a fixed rate, and each interrupt advances the system tick
+//! counter. Ticks are the kernel's unit for scheduling timeouts: rather than
+//! counting down a remaining duration on every interrupt, a timeout records the
+//! absolute tick at which it expires.
+
+pub const HUNDRED_NS_PER_TICK: u64 = 100002;
+pub const MS_PER_TICK: usize = (HUNDRED_NS_PER_TICK / 10000) as usize;
+
+/// The tick counter is 32 bits and wraps around, so a target tick can only be
+/// compared against the current tick if it lies less than half the counter's
+/// range away. Durations are clamped to this many ticks.
+pub const MAX_TICK_DELTA: u32 = i32::MAX as u32;
+
+/// Convert a duration in milliseconds to a number of ticks, rounding up so
+/// that waiting that many ticks never ends early
+pub fn ms_to_ticks(ms: usize) -> u32 {
+  let ticks = ms / MS_PER_TICK + if ms % MS_PER_TICK == 0 { 0 } else { 1 };
+  if ticks > MAX_TICK_DELTA as usize {
+    MAX_TICK_DELTA
+  } else {
+    ticks as u32
+  }
+}
+
+/// Compute the tick at which a duration starting now will have elapsed
+pub fn target_tick(current_ticks: u32, ms: usize) -> u32 {
+  current_ticks.wrapping_add(ms_to_ticks(ms))
+}
+
+/// Compute how many ticks remain until a wall-clock time, both measured in
+/// 100ns increments. Partial ticks are rounded up, so that a process sleeping
+/// until that time never wakes before it. A time in the past is zero ticks
+/// away.
+pub fn ticks_until(current_time: u64, wake_time: u64) -> u32 {
+  if wake_time <= current_time {
+    return 0;
+  }
+  let remaining = wake_time - current_time;
+  let ticks = remaining / HUNDRED_NS_PER_TICK + if remaining % HUNDRED_NS_PER_TICK == 0 { 0 } else { 1 };
+  if ticks > MAX_TICK_DELTA as u64 {
+    MAX_TICK_DELTA
+  } else {
+    ticks as u32
+  }
+}
+
+/// Determine whether a target tick has been reached, accounting for the
+/// counter wrapping around
+pub fn tick_reached(current_ticks: u32, target: u32) -> bool {
+  (current_ticks.wrapping_sub(target) as i32) >= 0
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{ms_to_ticks, target_tick, tick_reached, ticks_until, HUNDRED_NS_PER_TICK, MAX_TICK_DELTA, MS_PER_TICK};
+
+  #[test]
+  fn duration_conversion() {
+    assert_eq!(ms_to_ticks(0), 0);
+    assert_eq!(ms_to_ticks(1), 1);
+    assert_eq!(ms_to_ticks(MS_PER_TICK), 1);
+    assert_eq!(ms_to_ticks(MS_PER_TICK + 1), 2);
+    assert_eq!(ms_to_ticks(usize::MAX), MAX_TICK_DELTA);
+  }
+
+  #[test]
+  fn wall_clock_conversion() {
+    let now = 50_000_000;
+    assert_eq!(ticks_until(now, now), 0);
+    assert_eq!(ticks_until(now, now - 1), 0);
+    assert_eq!(ticks_until(now, now + 1), 1);
+    assert_eq!(ticks_until(now, now + HUNDRED_NS_PER_TICK), 1);
+    assert_eq!(ticks_until(now, now + HUNDRED_NS_PER_TICK * 3 + 1), 4);
+    assert_eq!(ticks_until(0, u64::MAX), MAX_TICK_DELTA);
+  }
+
+  #[test]
+  fn wrapping_targets() {
+    assert!(tick_reached(10, 10));
+    assert!(tick_reached(11, 10));
+    assert!(!tick_reached(9, 10));
+    let target = target_tick(u32::MAX - 1, MS_PER_TICK * 4);
+    assert_eq!(target, 2);
+    assert!(!tick_reached(u32::MAX, target));
+    assert!(tick_reached(2, target));
+    // The longest possible sleep is still in the future
+    let far = target_tick(100, usize::MAX);
+    assert!(!tick_reached(100, far));
+    assert!(!tick_reached(far.wrapping_sub(1), far));
+    assert!(tick_reached(far, far));
+  }
+}
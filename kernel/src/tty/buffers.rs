@@ -6,7 +6,7 @@ use crate::collections::SlotList;
 use crate::devices::driver::IOHandle;
 use crate::devices::queue::QueuedIO;
 use crate::task::id::ProcessID;
-use spin::RwLock;
+use crate::locks::RwLock;
 
 const BUFFER_SIZE: usize = 512;
 
@@ -75,6 +75,9 @@ pub struct TTYReaderBuffer {
 
   open_handles: Arc<RwLock<SlotList<Descriptor>>>,
   io_queue: RwLock<VecDeque<IOHandle>>,
+  /// Stream positions where input was ended with Ctrl+D, oldest first. A read
+  /// stops when it reaches one, without reading past it.
+  end_marks: RwLock<VecDeque<usize>>,
 }
 
 impl TTYReaderBuffer {
@@ -88,17 +91,33 @@ impl TTYReaderBuffer {
       buffer: RingBuffer::new(buffer_slice),
       io_queue: RwLock::new(VecDeque::new()),
       open_handles,
+      end_marks: RwLock::new(VecDeque::new()),
     }
   }
 
-  pub fn read(&self, handle: IOHandle, dest: &mut [u8]) -> usize {
+  /// Read a line of input. If the handle is closed while the reader is
+  /// waiting, the read fails. If input was ended partway through a line, only
+  /// the text before that point is returned, and a read that starts where
+  /// input ended returns nothing, signalling end of file. Either way, the end
+  /// is only seen once, and later reads wait for new input.
+  pub fn read(&self, handle: IOHandle, dest: &mut [u8]) -> Result<usize, ()> {
     self.perform_io(handle, || {
       let mut bytes_read = 0;
       let mut byte_buffer: [u8; 1] = [0];
       while bytes_read < dest.len() && byte_buffer[0] != b'\n' {
+        if self.get_process_id_for_handle(handle).is_none() {
+          return Err(());
+        }
+        if self.take_end_mark() {
+          break;
+        }
         if self.buffer.available_bytes() < 1 {
           crate::task::get_current_process().write().io_block(None);
-          crate::task::yield_coop();
+          if crate::task::yield_interruptible().is_err() {
+            // Whatever was typed before the signal is still returned
+            return if bytes_read > 0 { Ok(bytes_read) } else { Err(()) };
+          }
+          continue;
         }
         let partial_read = self.buffer.read(&mut byte_buffer);
         if partial_read > 0 {
@@ -113,14 +132,45 @@ impl TTYReaderBuffer {
           }
         }
       }
-      bytes_read
+      Ok(bytes_read)
     })
   }
 
+  /// Reads return a line at a time, so a reader only stops waiting once a
+  /// full line has been entered, input has been ended, or the buffer fills up
+  /// before either has happened
+  pub fn has_line(&self) -> bool {
+    self.buffer.contains(b'\n')
+      || self.buffer.available_room() == 0
+      || !self.end_marks.read().is_empty()
+  }
+
+  /// Determine whether a read would return right away
+  pub fn has_data(&self) -> bool {
+    self.buffer.available_bytes() > 0 || !self.end_marks.read().is_empty()
+  }
+
   pub fn add_data(&self, data: &[u8]) {
     self.buffer.write(&data);
     self.wake_front();
   }
+
+  /// End the input that readers are waiting on, after everything added so far
+  pub fn end_input(&self) {
+    self.end_marks.write().push_back(self.buffer.total_written());
+    self.wake_front();
+  }
+
+  /// If the reader has caught up to the point where input was ended, consume
+  /// that mark
+  fn take_end_mark(&self) -> bool {
+    let mut end_marks = self.end_marks.write();
+    if end_marks.front() == Some(&self.buffer.total_read()) {
+      end_marks.pop_front();
+      return true;
+    }
+    false
+  }
 }
 
 impl Drop for TTYReaderBuffer {
@@ -129,7 +179,7 @@ impl Drop for TTYReaderBuffer {
   }
 }
 
-impl QueuedIO<(), usize> for TTYReaderBuffer {
+impl QueuedIO<(), Result<usize, ()>> for TTYReaderBuffer {
   fn get_process_id_for_handle(&self, handle: IOHandle) -> Option<ProcessID> {
     self.open_handles
       .read()
@@ -172,6 +222,10 @@ impl TTYWriterBuffer {
   pub fn available_bytes(&self) -> usize {
     self.buffer.available_bytes()
   }
+
+  pub fn has_room(&self) -> bool {
+    self.buffer.available_room() > 0
+  }
 }
 
 impl Drop for TTYWriterBuffer {
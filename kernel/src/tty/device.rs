@@ -1,11 +1,18 @@
+use alloc::boxed::Box;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use crate::collections::SlotList;
 use crate::devices::driver::{DeviceDriver, IOHandle};
+use crate::files::handle::{FileHandle, Handle, LocalHandle};
+use crate::fs::{DRIVES, drive::DriveID};
+use crate::hardware::vga::text_mode::{apply_cursor_shape, apply_text_palette, CursorShape, CURSOR_SHAPE, TEXT_PALETTE};
 use crate::task::{get_current_id, id::ProcessID};
-use spin::RwLock;
+use crate::locks::RwLock;
+use syscall::flags::{TIOCCLOG, TIOCGCURSOR, TIOCSBRIGHTBG, TIOCSCURSOR, TIOCSHISTORY, TIOCSLOG, TIOCSPALETTE};
 use super::buffers::{TTYReaderBuffer, TTYWriterBuffer, Descriptor};
+use super::line::{DEFAULT_HISTORY_DEPTH, MAX_HISTORY_DEPTH};
+use super::tee::{LogSink, LogSinkType, Tee};
 
 /// Device driver representing a TTY, so a shell program can open up DEV:/TTY1
 /// and listen to console input / publish to the terminal.
@@ -53,6 +60,18 @@ impl DeviceDriver for TTYDevice {
     */
   }
 
+  fn is_terminal(&self) -> bool {
+    true
+  }
+
+  fn poll_readable(&self, _handle: IOHandle) -> Result<bool, ()> {
+    self.with_device_data(|d| Ok(d.read_buffer.has_line()))
+  }
+
+  fn poll_writable(&self, _handle: IOHandle) -> Result<bool, ()> {
+    self.with_device_data(|d| Ok(d.write_buffer.has_room()))
+  }
+
   fn read(&self, handle: IOHandle, dest: &mut [u8]) -> Result<usize, ()> {
     self.with_device_data(|d| d.read(handle, dest))
     /*
@@ -70,8 +89,21 @@ impl DeviceDriver for TTYDevice {
     */
   }
 
+  fn read_nonblocking(&self, handle: IOHandle, dest: &mut [u8]) -> Result<Option<usize>, ()> {
+    self.with_device_data(|d| {
+      if !d.read_buffer.has_data() {
+        return Ok(None);
+      }
+      d.read(handle, dest).map(|bytes| Some(bytes))
+    })
+  }
+
   fn write(&self, handle: IOHandle, buffer: &[u8]) -> Result<usize, ()> {
-    self.with_device_data(|d| d.write(handle, buffer))
+    let (bytes_written, detached) = self.with_device_data(|d| d.write(handle, buffer))?;
+    // A log that failed is closed once DEVICE_DATA is unlocked, since closing
+    // a file reaches into its drive
+    drop(detached);
+    Ok(bytes_written)
     /*
     // this needs enqueuing
     let mut total_written = 0;
@@ -109,6 +141,100 @@ impl DeviceDriver for TTYDevice {
     }
     */
   }
+
+  fn ioctl(&self, _handle: IOHandle, command: u32, arg: u32) -> Result<u32, ()> {
+    match command {
+      TIOCSLOG => {
+        let sink = FileSink::from_current_process(FileHandle::new(arg))?;
+        // Replacing a previous log closes it, after DEVICE_DATA is unlocked
+        let previous = self.with_device_data(|d| Ok(d.log.attach(Box::new(sink))))?;
+        drop(previous);
+        Ok(0)
+      },
+      TIOCCLOG => {
+        let previous = self.with_device_data(|d| Ok(d.log.detach()))?;
+        drop(previous);
+        Ok(0)
+      },
+      TIOCSHISTORY => {
+        if arg as usize > MAX_HISTORY_DEPTH {
+          return Err(());
+        }
+        self.with_device_data(|d| {
+          Ok(d.history_depth.swap(arg as usize, Ordering::SeqCst) as u32)
+        })
+      },
+      // The palette belongs to the VGA card, so it is shared by every TTY
+      TIOCSBRIGHTBG => {
+        let was_blinking = TEXT_PALETTE.write().set_blink(arg == 0);
+        apply_text_palette();
+        Ok(if was_blinking { 0 } else { 1 })
+      },
+      TIOCSPALETTE => {
+        let color = (arg >> 24) as usize;
+        let rgb = [(arg >> 18) as u8 & 0x3f, (arg >> 10) as u8 & 0x3f, (arg >> 2) as u8 & 0x3f];
+        let previous = TEXT_PALETTE.write().set_color(color, rgb)?;
+        apply_text_palette();
+        Ok(
+          (arg & 0xff000000)
+            | ((previous[0] as u32) << 18)
+            | ((previous[1] as u32) << 10)
+            | ((previous[2] as u32) << 2)
+        )
+      },
+      TIOCSCURSOR => {
+        let shape = CursorShape::from_ioctl(arg)?;
+        let previous = core::mem::replace(&mut *CURSOR_SHAPE.write(), shape);
+        apply_cursor_shape();
+        Ok(previous.as_ioctl())
+      },
+      TIOCGCURSOR => Ok(CURSOR_SHAPE.read().as_ioctl()),
+      _ => Err(()),
+    }
+  }
+}
+
+/// Log sink that writes to a file handle on any drive. It holds its own
+/// reference to the file, which is closed when the sink is dropped.
+struct FileSink {
+  drive: DriveID,
+  handle: LocalHandle,
+}
+
+impl FileSink {
+  /// Create a sink from one of the current process's open files. Files on
+  /// read-only drives can't be used, since every log write would fail.
+  fn from_current_process(handle: FileHandle) -> Result<FileSink, ()> {
+    let open_file = {
+      let process_lock = crate::task::get_current_process();
+      let process = process_lock.read();
+      *process.get_open_file_info(handle).ok_or(())?
+    };
+    if DRIVES.is_read_only(&open_file.drive) {
+      return Err(());
+    }
+    let (_, instance) = DRIVES.get_drive_instance(&open_file.drive).ok_or(())?;
+    let local_handle = instance.reopen(open_file.local_handle, get_current_id())?;
+    Ok(FileSink {
+      drive: open_file.drive,
+      handle: local_handle,
+    })
+  }
+}
+
+impl LogSink for FileSink {
+  fn write(&self, data: &[u8]) -> Result<usize, ()> {
+    let (_, instance) = DRIVES.get_drive_instance(&self.drive).ok_or(())?;
+    instance.write(self.handle, data)
+  }
+}
+
+impl Drop for FileSink {
+  fn drop(&mut self) {
+    if let Some((_, instance)) = DRIVES.get_drive_instance(&self.drive) {
+      let _ = instance.close(self.handle);
+    }
+  }
 }
 
 static DEVICE_DATA: RwLock<Vec<TTYDeviceData>> = RwLock::new(Vec::new());
@@ -118,6 +244,10 @@ pub struct TTYDeviceData {
   read_buffer: Arc<TTYReaderBuffer>,
   write_buffer: Arc<TTYWriterBuffer>,
   open_io: Arc<RwLock<SlotList<Descriptor>>>,
+  /// Optional copy of everything written to the TTY
+  log: Tee,
+  /// Number of lines kept for recall while editing a line of input
+  history_depth: AtomicUsize,
 }
 
 unsafe impl Send for TTYDeviceData {}
@@ -132,6 +262,8 @@ impl TTYDeviceData {
       read_buffer: Arc::new(read_buffer),
       write_buffer: Arc::new(TTYWriterBuffer::new()),
       open_io,
+      log: Tee::new(),
+      history_depth: AtomicUsize::new(DEFAULT_HISTORY_DEPTH),
     }
   }
 
@@ -156,35 +288,51 @@ impl TTYDeviceData {
     Ok(new_handle)
   }
 
+  /// Close a handle. If its process is blocked reading from it, the process
+  /// is woken so that the read can fail.
   pub fn close(&self, close_handle: IOHandle) -> Result<(), ()> {
-    let mut open_io = self.open_io.write();
-    let index = 0;
-    let mut to_close: Option<usize> = None;
-    while index < open_io.len() {
-      let entry = open_io.get(index);
-      if let Some(Descriptor { handle, .. }) = entry {
-        if *handle == close_handle {
-          to_close = Some(index);
-          break;
+    let closed = {
+      let mut open_io = self.open_io.write();
+      let mut index = 0;
+      let mut to_close: Option<usize> = None;
+      while index < open_io.len() {
+        let entry = open_io.get(index);
+        if let Some(Descriptor { handle, .. }) = entry {
+          if *handle == close_handle {
+            to_close = Some(index);
+            break;
+          }
         }
+        index += 1;
       }
-    }
-    if let Some(i) = to_close {
-      open_io.remove(i);
-      Ok(())
-    } else {
-      Err(())
+      to_close.and_then(|i| open_io.remove(i))
+    };
+    match closed {
+      Some(descriptor) => {
+        if let Some(lock) = crate::task::get_process(&descriptor.process) {
+          lock.write().io_resume();
+        }
+        Ok(())
+      },
+      None => Err(()),
     }
   }
 
   pub fn read(&self, handle: IOHandle, dest: &mut [u8]) -> Result<usize, ()> {
-    let bytes_read = self.read_buffer.read(handle, dest);
-    Ok(bytes_read)
+    self.read_buffer.read(handle, dest)
   }
 
-  pub fn write(&self, handle: IOHandle, buffer: &[u8]) -> Result<usize, ()> {
+  /// Write to the screen and copy the output to the log. A log that fails is
+  /// detached and returned, for the caller to close once it is safe to. The
+  /// TTY itself is unaffected.
+  pub fn write(&self, handle: IOHandle, buffer: &[u8]) -> Result<(usize, Option<Box<LogSinkType>>), ()> {
     let bytes_written = self.write_buffer.write(handle, buffer);
-    Ok(bytes_written)
+    let detached = self.log.forward(crate::task::get_current_id(), &buffer[..bytes_written]);
+    Ok((bytes_written, detached))
+  }
+
+  pub fn get_log(&self) -> &Tee {
+    &self.log
   }
 }
 
@@ -196,6 +344,10 @@ pub fn get_write_buffer(index: usize) -> Arc<TTYWriterBuffer> {
   DEVICE_DATA.read().get(index).unwrap().get_write_buffer()
 }
 
+pub fn get_history_depth(index: usize) -> usize {
+  DEVICE_DATA.read().get(index).map_or(0, |data| data.history_depth.load(Ordering::SeqCst))
+}
+
 pub fn create_tty() -> usize {
   let device_data = TTYDeviceData::new();
   let index = {
@@ -207,3 +359,105 @@ pub fn create_tty() -> usize {
   crate::devices::create_tty(index);
   index
 }
+
+#[cfg(test)]
+mod tests {
+  use alloc::boxed::Box;
+  use alloc::sync::Arc;
+  use alloc::vec::Vec;
+  use crate::devices::driver::IOHandle;
+  use crate::tty::tee::LogSink;
+  use crate::locks::Mutex;
+  use super::TTYDeviceData;
+
+  struct MemorySink {
+    data: Arc<Mutex<Vec<u8>>>,
+    fail: bool,
+  }
+
+  impl LogSink for MemorySink {
+    fn write(&self, data: &[u8]) -> Result<usize, ()> {
+      if self.fail {
+        return Err(());
+      }
+      self.data.lock().extend_from_slice(data);
+      Ok(data.len())
+    }
+  }
+
+  #[test]
+  fn writes_reach_screen_and_log() {
+    let tty = TTYDeviceData::new();
+    let logged = Arc::new(Mutex::new(Vec::new()));
+    tty.get_log().attach(Box::new(MemorySink { data: logged.clone(), fail: false }));
+    assert_eq!(tty.write(IOHandle::new(1), b"C:\\> dir\n").unwrap().0, 9);
+    let mut screen = [0; 16];
+    let read = tty.get_write_buffer().read(&mut screen);
+    assert_eq!(&screen[..read], b"C:\\> dir\n");
+    assert_eq!(&logged.lock()[..], b"C:\\> dir\n");
+  }
+
+  #[test]
+  fn failing_log_keeps_screen_alive() {
+    let tty = TTYDeviceData::new();
+    let logged = Arc::new(Mutex::new(Vec::new()));
+    tty.get_log().attach(Box::new(MemorySink { data: logged.clone(), fail: true }));
+    let (written, detached) = tty.write(IOHandle::new(1), b"abc").unwrap();
+    assert_eq!(written, 3);
+    assert!(detached.is_some());
+    assert!(!tty.get_log().is_attached());
+    assert_eq!(tty.write(IOHandle::new(1), b"def").unwrap().0, 3);
+    let mut screen = [0; 16];
+    let read = tty.get_write_buffer().read(&mut screen);
+    assert_eq!(&screen[..read], b"abcdef");
+  }
+
+  #[test]
+  fn stdin_terminal_or_pipe() {
+    use crate::devices::{driver::DeviceDriver, null::NullDriver};
+    use crate::fs::filesystem::KernelFileSystem;
+    use crate::pipes::{collection::PipeCollection, fs::PipeFileSystem};
+
+    let tty = super::TTYDevice::for_tty(super::create_tty());
+    assert!(tty.is_terminal());
+    assert!(!NullDriver::new().is_terminal());
+
+    let pipes = Arc::new(PipeCollection::new());
+    let pipe_fs = PipeFileSystem::new(&pipes);
+    let (stdin, _) = pipes.create().unwrap();
+    assert_eq!(pipe_fs.is_terminal(stdin), Ok(false));
+  }
+
+  #[test]
+  fn end_of_input() {
+    let tty = TTYDeviceData::new();
+    let handle = tty.open().unwrap();
+    let input = tty.get_read_buffer();
+    let mut buffer = [0; 16];
+
+    // Ending input partway through a line delivers the line without a newline
+    input.add_data(b"abc");
+    input.end_input();
+    assert!(input.has_line());
+    assert_eq!(tty.read(handle, &mut buffer), Ok(3));
+    assert_eq!(&buffer[..3], b"abc");
+    assert!(!input.has_data());
+
+    // On an empty line, the read returns nothing
+    input.end_input();
+    assert!(input.has_data());
+    assert_eq!(tty.read(handle, &mut buffer), Ok(0));
+    // The end of input is only seen once, so the terminal can still be read
+    input.add_data(b"more\n");
+    assert_eq!(tty.read(handle, &mut buffer), Ok(5));
+    assert_eq!(&buffer[..5], b"more\n");
+
+    // Input typed after the end is held back for the next read
+    input.add_data(b"x");
+    input.end_input();
+    input.add_data(b"y\n");
+    assert_eq!(tty.read(handle, &mut buffer), Ok(1));
+    assert_eq!(tty.read(handle, &mut buffer), Ok(2));
+    assert_eq!(&buffer[..2], b"y\n");
+  }
+}
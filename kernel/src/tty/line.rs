@@ -0,0 +1,358 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+/// Number of lines a TTY remembers unless configured otherwise. History is
+/// off by default, and enabled per TTY with TIOCSHISTORY.
+pub const DEFAULT_HISTORY_DEPTH: usize = 0;
+/// Upper limit on the configurable history depth
+pub const MAX_HISTORY_DEPTH: usize = 64;
+/// Ctrl+D ends input in canonical mode
+pub const END_OF_INPUT: u8 = 0x04;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum EscapeState {
+  None,
+  /// Received ESC
+  Escape,
+  /// Received ESC [
+  Bracket,
+}
+
+/// Canonical-mode line editor with command history. Keyboard input is
+/// collected into a line until Enter is pressed, and only the finished line is
+/// handed to readers. The Up and Down arrow keys replace the line being edited
+/// with earlier and later entries from the history.
+/// For each input byte, the editor appends the bytes that should be echoed to
+/// the screen. A replaced line is erased with one backspace per character
+/// before the new text is written, so no trailing characters from a longer
+/// line are left behind.
+pub struct LineEditor {
+  line: Vec<u8>,
+  /// Previously entered lines, newest first
+  history: VecDeque<Vec<u8>>,
+  depth: usize,
+  /// While browsing, the index of the history entry being shown
+  browsing: Option<usize>,
+  /// The line that was being typed before browsing began, restored by moving
+  /// back down past the newest entry
+  draft: Vec<u8>,
+  escape: EscapeState,
+}
+
+impl LineEditor {
+  pub fn new(depth: usize) -> LineEditor {
+    LineEditor {
+      line: Vec::new(),
+      history: VecDeque::new(),
+      depth: depth.min(MAX_HISTORY_DEPTH),
+      browsing: None,
+      draft: Vec::new(),
+      escape: EscapeState::None,
+    }
+  }
+
+  /// Throw away the line being typed, along with any partial escape sequence.
+  /// History is kept.
+  pub fn discard_line(&mut self) {
+    self.line.clear();
+    self.browsing = None;
+    self.draft.clear();
+    self.escape = EscapeState::None;
+  }
+
+  /// Change how many lines are remembered. Shrinking the depth drops the
+  /// oldest entries.
+  pub fn set_history_depth(&mut self, depth: usize) {
+    self.depth = depth.min(MAX_HISTORY_DEPTH);
+    self.history.truncate(self.depth);
+    if let Some(index) = self.browsing {
+      if index >= self.history.len() {
+        self.browsing = None;
+      }
+    }
+  }
+
+  pub fn get_history_depth(&self) -> usize {
+    self.depth
+  }
+
+  pub fn get_line(&self) -> &[u8] {
+    &self.line
+  }
+
+  /// Handle a single byte of keyboard input. Bytes to be drawn on screen are
+  /// appended to `echo`. Once a line is complete, it is returned, including
+  /// its trailing newline. Control characters other than backspace and
+  /// newline are passed through to readers immediately.
+  pub fn process(&mut self, ch: u8, echo: &mut Vec<u8>) -> Option<Vec<u8>> {
+    match self.escape {
+      EscapeState::Escape => {
+        if ch == b'[' {
+          self.escape = EscapeState::Bracket;
+          return None;
+        }
+        // Not the start of a sequence, so the ESC was a key of its own
+        self.escape = EscapeState::None;
+        let mut passed = Vec::with_capacity(2);
+        passed.push(0x1b);
+        if let Some(rest) = self.process(ch, echo) {
+          passed.extend_from_slice(&rest);
+        }
+        return Some(passed);
+      },
+      EscapeState::Bracket => {
+        self.escape = EscapeState::None;
+        match ch {
+          b'A' => self.history_up(echo),
+          b'B' => self.history_down(echo),
+          // Left and right movement within the line is not supported
+          _ => (),
+        }
+        return None;
+      },
+      EscapeState::None => (),
+    }
+
+    match ch {
+      0x1b => {
+        self.escape = EscapeState::Escape;
+        None
+      },
+      0x08 | 0x7f => {
+        if self.line.pop().is_some() {
+          echo.push(0x08);
+        }
+        None
+      },
+      b'\n' => {
+        echo.push(b'\n');
+        Some(self.finish_line())
+      },
+      END_OF_INPUT => {
+        // Whatever has been typed is handed over as-is, without a newline,
+        // and the caller ends the input after it. An empty line becomes EOF.
+        Some(self.take_line())
+      },
+      0..=0x1f => {
+        let mut passed = Vec::with_capacity(1);
+        passed.push(ch);
+        Some(passed)
+      },
+      _ => {
+        self.line.push(ch);
+        echo.push(ch);
+        None
+      },
+    }
+  }
+
+  /// Called once a complete chunk of keyboard input has been processed. Arrow
+  /// key sequences always arrive in a single chunk, so an escape sequence left
+  /// unfinished at that point was really a press of the ESC key, and is handed
+  /// to readers like any other control character.
+  pub fn flush_escape(&mut self) -> Option<Vec<u8>> {
+    let pending: &[u8] = match self.escape {
+      EscapeState::None => return None,
+      EscapeState::Escape => b"\x1b",
+      EscapeState::Bracket => b"\x1b[",
+    };
+    self.escape = EscapeState::None;
+    Some(pending.to_vec())
+  }
+
+  fn take_line(&mut self) -> Vec<u8> {
+    self.browsing = None;
+    self.draft.clear();
+    core::mem::replace(&mut self.line, Vec::new())
+  }
+
+  fn finish_line(&mut self) -> Vec<u8> {
+    let mut line = self.take_line();
+    let repeated = self.history.front().map_or(false, |newest| *newest == line);
+    if self.depth > 0 && !line.is_empty() && !repeated {
+      self.history.push_front(line.clone());
+      self.history.truncate(self.depth);
+    }
+    line.push(b'\n');
+    line
+  }
+
+  fn history_up(&mut self, echo: &mut Vec<u8>) {
+    let next = match self.browsing {
+      Some(index) => index + 1,
+      None => 0,
+    };
+    let entry = match self.history.get(next) {
+      Some(entry) => entry.clone(),
+      None => return,
+    };
+    if self.browsing.is_none() {
+      self.draft = self.line.clone();
+    }
+    self.browsing = Some(next);
+    self.replace_line(entry, echo);
+  }
+
+  fn history_down(&mut self, echo: &mut Vec<u8>) {
+    let entry = match self.browsing {
+      None => return,
+      Some(0) => {
+        self.browsing = None;
+        core::mem::replace(&mut self.draft, Vec::new())
+      },
+      Some(index) => {
+        self.browsing = Some(index - 1);
+        self.history[index - 1].clone()
+      },
+    };
+    self.replace_line(entry, echo);
+  }
+
+  fn replace_line(&mut self, entry: Vec<u8>, echo: &mut Vec<u8>) {
+    for _ in 0..self.line.len() {
+      echo.push(0x08);
+    }
+    echo.extend_from_slice(&entry);
+    self.line = entry;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+  use super::{LineEditor, END_OF_INPUT};
+
+  const UP: &[u8] = b"\x1b[A";
+  const DOWN: &[u8] = b"\x1b[B";
+
+  fn type_bytes(editor: &mut LineEditor, input: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let mut echo = Vec::new();
+    let mut submitted = Vec::new();
+    for ch in input {
+      if let Some(line) = editor.process(*ch, &mut echo) {
+        submitted.extend_from_slice(&line);
+      }
+    }
+    (echo, submitted)
+  }
+
+  #[test]
+  fn lines_are_submitted_on_newline() {
+    let mut editor = LineEditor::new(4);
+    let (echo, submitted) = type_bytes(&mut editor, b"dirr\x08");
+    assert_eq!(echo, b"dirr\x08");
+    assert!(submitted.is_empty());
+    assert_eq!(editor.get_line(), b"dir");
+    // Backspace on an empty line has nothing to erase
+    let (echo, submitted) = type_bytes(&mut editor, b"\n\x08");
+    assert_eq!(echo, b"\n");
+    assert_eq!(submitted, b"dir\n");
+    // Other control characters reach readers right away
+    let (_, submitted) = type_bytes(&mut editor, b"ab\x03");
+    assert_eq!(submitted, b"\x03");
+    assert_eq!(editor.get_line(), b"ab");
+  }
+
+  #[test]
+  fn navigate_history() {
+    let mut editor = LineEditor::new(4);
+    type_bytes(&mut editor, b"cd\n");
+    type_bytes(&mut editor, b"dir /w\n");
+    type_bytes(&mut editor, b"type readme.txt\n");
+    // Empty lines and immediate repeats aren't recorded
+    type_bytes(&mut editor, b"\ntype readme.txt\n");
+
+    let (_, _) = type_bytes(&mut editor, b"ec");
+    let (echo, _) = type_bytes(&mut editor, UP);
+    assert_eq!(echo, b"\x08\x08type readme.txt");
+    // A shorter entry erases every character of the longer line first
+    let (echo, _) = type_bytes(&mut editor, UP);
+    let mut expected = Vec::new();
+    expected.extend_from_slice(&[0x08; 15]);
+    expected.extend_from_slice(b"dir /w");
+    assert_eq!(echo, expected);
+    let (echo, _) = type_bytes(&mut editor, UP);
+    assert_eq!(echo, b"\x08\x08\x08\x08\x08\x08cd");
+    // Moving past the oldest entry does nothing
+    let (echo, _) = type_bytes(&mut editor, UP);
+    assert!(echo.is_empty());
+    assert_eq!(editor.get_line(), b"cd");
+
+    // Moving down past the newest entry restores the unfinished line
+    type_bytes(&mut editor, DOWN);
+    type_bytes(&mut editor, DOWN);
+    assert_eq!(editor.get_line(), b"type readme.txt");
+    let (echo, _) = type_bytes(&mut editor, DOWN);
+    assert_eq!(&echo[15..], b"ec");
+    assert_eq!(editor.get_line(), b"ec");
+    let (echo, _) = type_bytes(&mut editor, DOWN);
+    assert!(echo.is_empty());
+
+    // A recalled line can be edited before it is submitted
+    let (_, submitted) = type_bytes(&mut editor, b"\x08\x08\x1b[A\x1b[A\x08p\n");
+    assert_eq!(submitted, b"dir /p\n");
+    let (echo, _) = type_bytes(&mut editor, UP);
+    assert_eq!(echo, b"dir /p");
+  }
+
+  #[test]
+  fn history_depth_is_limited() {
+    let mut editor = LineEditor::new(2);
+    type_bytes(&mut editor, b"one\ntwo\nthree\n");
+    type_bytes(&mut editor, UP);
+    type_bytes(&mut editor, UP);
+    type_bytes(&mut editor, UP);
+    assert_eq!(editor.get_line(), b"two");
+    type_bytes(&mut editor, b"\n");
+
+    editor.set_history_depth(1);
+    type_bytes(&mut editor, UP);
+    type_bytes(&mut editor, UP);
+    assert_eq!(editor.get_line(), b"two");
+    type_bytes(&mut editor, b"\x08\x08\x08");
+
+    // With a depth of zero, nothing is remembered
+    editor.set_history_depth(0);
+    type_bytes(&mut editor, b"four\n");
+    let (echo, _) = type_bytes(&mut editor, UP);
+    assert!(echo.is_empty());
+    assert_eq!(editor.get_line(), b"");
+  }
+
+  #[test]
+  fn lone_escape_is_passed_through() {
+    let mut editor = LineEditor::new(4);
+    let mut echo = Vec::new();
+    type_bytes(&mut editor, b"ab");
+    assert_eq!(editor.process(0x1b, &mut echo), None);
+    assert_eq!(editor.flush_escape(), Some(b"\x1b".to_vec()));
+    assert_eq!(editor.flush_escape(), None);
+    // ESC followed by something other than a sequence keeps both bytes
+    let (echo, submitted) = type_bytes(&mut editor, b"\x1bc\x1b\x03");
+    assert_eq!(submitted, b"\x1b\x1b\x03");
+    assert_eq!(echo, b"c");
+    assert_eq!(editor.get_line(), b"abc");
+    // An arrow key is still consumed whole
+    let (_, submitted) = type_bytes(&mut editor, UP);
+    assert!(submitted.is_empty());
+    assert_eq!(editor.flush_escape(), None);
+  }
+
+  #[test]
+  fn end_of_input() {
+    let mut editor = LineEditor::new(4);
+    type_bytes(&mut editor, b"first\n");
+    let mut echo = Vec::new();
+    // On an empty line, Ctrl+D submits nothing at all, which readers see as
+    // the end of the file
+    assert_eq!(editor.process(END_OF_INPUT, &mut echo), Some(Vec::new()));
+    // Partway through a line, it submits the line without a newline
+    type_bytes(&mut editor, b"partial");
+    assert_eq!(editor.process(END_OF_INPUT, &mut echo), Some(b"partial".to_vec()));
+    assert!(echo.is_empty());
+    assert_eq!(editor.get_line(), b"");
+    // Lines ended that way aren't kept in the history
+    let (echo, _) = type_bytes(&mut editor, UP);
+    assert_eq!(echo, b"first");
+  }
+}
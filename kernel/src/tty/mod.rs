@@ -1,3 +1,6 @@
 pub mod buffers;
 pub mod device;
+pub mod line;
 pub mod parser;
+pub mod tee;
+pub mod utf8;
@@ -1,11 +1,19 @@
 use alloc::vec::Vec;
+use crate::hardware::vga::cp437::{glyph_for, REPLACEMENT_GLYPH};
 use crate::hardware::vga::text_mode::Color;
+use super::utf8::{Utf8Decoder, Utf8Step};
 
 /// A state machine that tracks the current parsing state of multi-byte ANSI
 /// codes.
 pub struct Parser {
   state: ParseState,
   csi_args: Vec<Option<u32>>,
+  /// Printed text is decoded as UTF-8, unless a DOS program is running.
+  /// DOS programs write code page bytes, which are drawn as-is.
+  utf8_enabled: bool,
+  utf8: Utf8Decoder,
+  /// A byte that cut a UTF-8 sequence short, and still needs to be processed
+  interrupted: Option<u8>,
 }
 
 /// Tracks the current state in the Parser state machine
@@ -23,6 +31,9 @@ pub enum ParseState {
 pub enum TTYAction {
   None,
   Print(u8),
+  /// Draw a glyph from the font, even one that shares its index with a
+  /// control code
+  Glyph(u8),
   NewLine,
   Backspace,
   Delete,
@@ -30,6 +41,7 @@ pub enum TTYAction {
   SetColumn(usize),
   SetPosition(usize, usize),
   ClearScreen,
+  ClearScrollback,
   ClearToBeginning,
   ClearToEnd,
   ClearRow,
@@ -42,6 +54,7 @@ pub enum TTYAction {
   ResetColors,
   SetFgColor(Color),
   SetBgColor(Color),
+  Reset,
 }
 
 impl Parser {
@@ -49,6 +62,35 @@ impl Parser {
     Self {
       state: ParseState::Ready,
       csi_args: Vec::new(),
+      utf8_enabled: true,
+      utf8: Utf8Decoder::new(),
+      interrupted: None,
+    }
+  }
+
+  /// Enable or disable UTF-8 decoding. Any partial sequence is dropped.
+  pub fn set_utf8(&mut self, enabled: bool) {
+    self.utf8_enabled = enabled;
+    self.utf8 = Utf8Decoder::new();
+    self.interrupted = None;
+  }
+
+  /// When a byte interrupts a UTF-8 sequence, the replacement glyph is
+  /// returned in its place. The byte itself needs to be passed back to
+  /// `process_character` afterwards.
+  pub fn take_interrupted(&mut self) -> Option<u8> {
+    self.interrupted.take()
+  }
+
+  fn decode_utf8(&mut self, ch: u8) -> TTYAction {
+    match self.utf8.push(ch) {
+      Utf8Step::Pending => TTYAction::None,
+      Utf8Step::Complete(decoded) => TTYAction::Glyph(glyph_for(decoded)),
+      Utf8Step::Invalid => TTYAction::Glyph(REPLACEMENT_GLYPH),
+      Utf8Step::Interrupted => {
+        self.interrupted = Some(ch);
+        TTYAction::Glyph(REPLACEMENT_GLYPH)
+      },
     }
   }
 
@@ -65,6 +107,9 @@ impl Parser {
   pub fn process_character(&mut self, ch: u8) -> TTYAction {
     match self.state {
       ParseState::Ready => {
+        if self.utf8_enabled && (ch >= 0x80 || self.utf8.in_sequence()) {
+          return self.decode_utf8(ch);
+        }
         match ch {
           0x08 => {
             return TTYAction::Backspace;
@@ -92,6 +137,11 @@ impl Parser {
             self.csi_args.push(None);
             return TTYAction::None;
           },
+          b'c' => { // Reset to Initial State
+            self.state = ParseState::Ready;
+            self.csi_args.clear();
+            return TTYAction::Reset;
+          },
           _ => {
             self.state = ParseState::Ready;
             return TTYAction::None;
@@ -158,7 +208,8 @@ impl Parser {
             let action = match direction {
               0 => TTYAction::ClearToEnd,
               1 => TTYAction::ClearToBeginning,
-              2 | 3 => TTYAction::ClearScreen,
+              2 => TTYAction::ClearScreen,
+              3 => TTYAction::ClearScrollback,
               _ => TTYAction::None,
             };
             (action, true)
@@ -242,3 +293,58 @@ impl Parser {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::{Parser, TTYAction};
+
+  fn process_all(parser: &mut Parser, input: &[u8]) -> TTYAction {
+    let mut action = TTYAction::None;
+    for ch in input {
+      action = parser.process_character(*ch);
+    }
+    action
+  }
+
+  #[test]
+  fn clear_scrollback() {
+    let mut parser = Parser::new();
+    assert!(matches!(process_all(&mut parser, b"\x1b[3J"), TTYAction::ClearScrollback));
+    assert!(matches!(process_all(&mut parser, b"\x1b[2J"), TTYAction::ClearScreen));
+    assert!(matches!(parser.process_character(b'a'), TTYAction::Print(b'a')));
+  }
+
+  #[test]
+  fn full_reset() {
+    let mut parser = Parser::new();
+    assert!(matches!(process_all(&mut parser, b"\x1bc"), TTYAction::Reset));
+    assert!(matches!(parser.process_character(b'c'), TTYAction::Print(b'c')));
+    // A reset in the middle of other output leaves the parser ready
+    assert!(matches!(process_all(&mut parser, b"\x1b[31m\x1bc"), TTYAction::Reset));
+    assert!(matches!(process_all(&mut parser, b"\x1b[1;1H"), TTYAction::SetPosition(1, 1)));
+  }
+
+  #[test]
+  fn utf8_text() {
+    let mut parser = Parser::new();
+    assert!(matches!(parser.process_character(0xe2), TTYAction::None));
+    assert!(matches!(parser.process_character(0x95), TTYAction::None));
+    assert!(matches!(parser.process_character(0x94), TTYAction::Glyph(0xc9)));
+    assert!(matches!(process_all(&mut parser, "é".as_bytes()), TTYAction::Glyph(0x82)));
+    // Characters missing from the font, and invalid bytes
+    assert!(matches!(process_all(&mut parser, "€".as_bytes()), TTYAction::Glyph(b'?')));
+    assert!(matches!(parser.process_character(0xff), TTYAction::Glyph(0xfe)));
+    assert!(parser.take_interrupted().is_none());
+
+    // An escape code cuts a partial character short, and still takes effect
+    assert!(matches!(parser.process_character(0xc3), TTYAction::None));
+    assert!(matches!(parser.process_character(0x1b), TTYAction::Glyph(0xfe)));
+    let interrupted = parser.take_interrupted().unwrap();
+    assert!(matches!(parser.process_character(interrupted), TTYAction::None));
+    assert!(matches!(process_all(&mut parser, b"[2J"), TTYAction::ClearScreen));
+
+    // DOS programs print code page bytes directly
+    parser.set_utf8(false);
+    assert!(matches!(parser.process_character(0x82), TTYAction::Print(0x82)));
+  }
+}
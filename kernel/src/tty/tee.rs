@@ -0,0 +1,227 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use crate::task::id::ProcessID;
+use crate::locks::{Mutex, RwLock};
+
+/// A secondary destination for everything written to a TTY, like a log file
+/// or a serial port
+pub trait LogSink {
+  fn write(&self, data: &[u8]) -> Result<usize, ()>;
+}
+
+pub type LogSinkType = dyn LogSink + Send + Sync;
+
+/// Most output that can wait for a running copy to finish. A sink that falls
+/// further behind than this is treated as full.
+pub const MAX_PENDING: usize = 4096;
+
+/// A Tee copies TTY output to an optional log sink, similar to script(1). The
+/// primary output is never affected by the sink: if the sink fails or fills up,
+/// it is detached and the TTY keeps working.
+pub struct Tee {
+  sink: RwLock<Option<Box<LogSinkType>>>,
+  /// The process copying data to the sink, plus one, or zero when no copy is
+  /// running. If the sink leads back to this same TTY, the nested write comes
+  /// from the same process and is not copied again, which would otherwise
+  /// recurse forever.
+  forwarder: AtomicU32,
+  /// Output written by other processes while a copy was running. The process
+  /// doing the copy sends it before it finishes.
+  pending: Mutex<Vec<u8>>,
+  /// Set when output had to be left out of the pending queue
+  overflowed: AtomicBool,
+}
+
+impl Tee {
+  pub const fn new() -> Self {
+    Self {
+      sink: RwLock::new(None),
+      forwarder: AtomicU32::new(0),
+      pending: Mutex::new(Vec::new()),
+      overflowed: AtomicBool::new(false),
+    }
+  }
+
+  /// Start copying output to a sink, returning the previous sink if one was
+  /// attached
+  pub fn attach(&self, sink: Box<LogSinkType>) -> Option<Box<LogSinkType>> {
+    self.sink.write().replace(sink)
+  }
+
+  pub fn detach(&self) -> Option<Box<LogSinkType>> {
+    self.sink.write().take()
+  }
+
+  pub fn is_attached(&self) -> bool {
+    self.sink.read().is_some()
+  }
+
+  /// Copy data that a process wrote to the TTY into the log sink. If another
+  /// process is already copying, the data is queued for it to send. If the
+  /// sink returns an error or doesn't accept all of the data, it is detached
+  /// and returned so that the caller can release it.
+  pub fn forward(&self, writer: ProcessID, data: &[u8]) -> Option<Box<LogSinkType>> {
+    if data.is_empty() {
+      return None;
+    }
+    let owner = writer.as_u32() + 1;
+    {
+      let mut pending = self.pending.lock();
+      if let Err(current) = self.forwarder.compare_exchange(0, owner, Ordering::SeqCst, Ordering::SeqCst) {
+        if current != owner {
+          let room = MAX_PENDING.saturating_sub(pending.len());
+          if room < data.len() {
+            self.overflowed.store(true, Ordering::SeqCst);
+          }
+          pending.extend_from_slice(&data[..room.min(data.len())]);
+        }
+        return None;
+      }
+    }
+    let mut healthy = self.send(data);
+    loop {
+      let queued = {
+        let mut pending = self.pending.lock();
+        if pending.is_empty() {
+          // Release the tee while the queue is locked, so that nothing can be
+          // queued without a process left to send it
+          self.forwarder.store(0, Ordering::SeqCst);
+          break;
+        }
+        core::mem::replace(&mut *pending, Vec::new())
+      };
+      if healthy {
+        healthy = self.send(&queued);
+      }
+    }
+    if self.overflowed.swap(false, Ordering::SeqCst) {
+      healthy = false;
+    }
+    if healthy {
+      None
+    } else {
+      self.detach()
+    }
+  }
+
+  fn send(&self, data: &[u8]) -> bool {
+    match &*self.sink.read() {
+      Some(sink) => match sink.write(data) {
+        Ok(written) => written == data.len(),
+        Err(_) => false,
+      },
+      None => true,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::boxed::Box;
+  use alloc::sync::Arc;
+  use alloc::vec::Vec;
+  use crate::task::id::ProcessID;
+  use crate::locks::Mutex;
+  use super::{LogSink, Tee, MAX_PENDING};
+
+  const WRITER: ProcessID = ProcessID::new(3);
+  const OTHER_WRITER: ProcessID = ProcessID::new(4);
+
+  struct MemorySink {
+    data: Arc<Mutex<Vec<u8>>>,
+    capacity: usize,
+  }
+
+  impl LogSink for MemorySink {
+    fn write(&self, data: &[u8]) -> Result<usize, ()> {
+      let mut stored = self.data.lock();
+      let room = self.capacity - stored.len();
+      let length = room.min(data.len());
+      stored.extend_from_slice(&data[..length]);
+      Ok(length)
+    }
+  }
+
+  /// A sink that writes back into the tee it is attached to
+  struct LoopSink {
+    tee: Arc<Tee>,
+    writes: Arc<Mutex<usize>>,
+  }
+
+  impl LogSink for LoopSink {
+    fn write(&self, data: &[u8]) -> Result<usize, ()> {
+      *self.writes.lock() += 1;
+      assert!(self.tee.forward(WRITER, data).is_none());
+      Ok(data.len())
+    }
+  }
+
+  /// A sink that is slow enough for another process to write to the TTY while
+  /// each copy is running
+  struct BusySink {
+    tee: Arc<Tee>,
+    data: Arc<Mutex<Vec<u8>>>,
+    interruption: &'static [u8],
+  }
+
+  impl LogSink for BusySink {
+    fn write(&self, data: &[u8]) -> Result<usize, ()> {
+      let first = self.data.lock().is_empty();
+      if first {
+        assert!(self.tee.forward(OTHER_WRITER, self.interruption).is_none());
+      }
+      self.data.lock().extend_from_slice(data);
+      Ok(data.len())
+    }
+  }
+
+  #[test]
+  fn full_sink_is_detached() {
+    let tee = Tee::new();
+    let data = Arc::new(Mutex::new(Vec::new()));
+    tee.attach(Box::new(MemorySink { data: data.clone(), capacity: 8 }));
+    assert!(tee.forward(WRITER, b"hello").is_none());
+    assert!(tee.is_attached());
+    // Only part of this fits, so the sink is dropped
+    assert!(tee.forward(WRITER, b" world").is_some());
+    assert!(!tee.is_attached());
+    assert!(tee.forward(WRITER, b"more").is_none());
+    assert_eq!(&data.lock()[..], b"hello wo");
+  }
+
+  #[test]
+  fn sink_leading_back_to_tty_does_not_recurse() {
+    let tee = Arc::new(Tee::new());
+    let writes = Arc::new(Mutex::new(0));
+    tee.attach(Box::new(LoopSink { tee: tee.clone(), writes: writes.clone() }));
+    assert!(tee.forward(WRITER, b"echo").is_none());
+    assert_eq!(*writes.lock(), 1);
+    assert!(tee.forward(WRITER, b"again").is_none());
+    assert_eq!(*writes.lock(), 2);
+    // Break the reference cycle
+    tee.detach();
+  }
+
+  #[test]
+  fn output_during_a_copy_is_queued() {
+    let tee = Arc::new(Tee::new());
+    let data = Arc::new(Mutex::new(Vec::new()));
+    tee.attach(Box::new(BusySink { tee: tee.clone(), data: data.clone(), interruption: b" world" }));
+    assert!(tee.forward(WRITER, b"hello").is_none());
+    assert!(tee.is_attached());
+    assert_eq!(&data.lock()[..], b"hello world");
+    tee.detach();
+  }
+
+  #[test]
+  fn overflowing_the_queue_detaches_the_sink() {
+    static FLOOD: [u8; MAX_PENDING + 1] = [b'x'; MAX_PENDING + 1];
+    let tee = Arc::new(Tee::new());
+    let data = Arc::new(Mutex::new(Vec::new()));
+    tee.attach(Box::new(BusySink { tee: tee.clone(), data: data.clone(), interruption: &FLOOD }));
+    assert!(tee.forward(WRITER, b"hello").is_some());
+    assert!(!tee.is_attached());
+    assert_eq!(data.lock().len(), 5 + MAX_PENDING);
+  }
+}
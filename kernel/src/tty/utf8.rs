@@ -0,0 +1,130 @@
+//! Programs write UTF-8 to the terminal, where a character can take up to
+//! four bytes. Output arrives in arbitrary chunks, so a character may be
+//! split across writes; the decoder keeps the partial sequence until the rest
+//! of it comes in.
+//! Bytes that can't be decoded become U+FFFD. That covers continuation bytes
+//! without a lead, lead bytes that are never valid, overlong encodings,
+//! surrogates, and codepoints past U+10FFFF. A sequence that is cut short by
+//! a byte that isn't a continuation also becomes U+FFFD, and the byte that
+//! interrupted it is left to be decoded on its own.
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Utf8Step {
+  /// The byte was part of a sequence that isn't complete yet
+  Pending,
+  Complete(char),
+  /// The byte, or the sequence it finished, isn't valid UTF-8
+  Invalid,
+  /// The byte cut a partial sequence short. The sequence is invalid, and the
+  /// byte still needs to be decoded.
+  Interrupted,
+}
+
+pub struct Utf8Decoder {
+  codepoint: u32,
+  /// Continuation bytes still expected
+  remaining: u8,
+  /// The lowest codepoint the sequence's length can encode. Anything below it
+  /// is an overlong encoding.
+  minimum: u32,
+}
+
+impl Utf8Decoder {
+  pub const fn new() -> Utf8Decoder {
+    Utf8Decoder {
+      codepoint: 0,
+      remaining: 0,
+      minimum: 0,
+    }
+  }
+
+  /// Whether a partial sequence is waiting for more bytes
+  pub fn in_sequence(&self) -> bool {
+    self.remaining > 0
+  }
+
+  pub fn push(&mut self, byte: u8) -> Utf8Step {
+    if self.remaining > 0 {
+      if byte & 0xc0 != 0x80 {
+        self.remaining = 0;
+        return Utf8Step::Interrupted;
+      }
+      self.codepoint = (self.codepoint << 6) | (byte & 0x3f) as u32;
+      self.remaining -= 1;
+      if self.remaining > 0 {
+        return Utf8Step::Pending;
+      }
+      if self.codepoint < self.minimum {
+        return Utf8Step::Invalid;
+      }
+      // Surrogates and codepoints past U+10FFFF aren't characters
+      return match core::char::from_u32(self.codepoint) {
+        Some(ch) => Utf8Step::Complete(ch),
+        None => Utf8Step::Invalid,
+      };
+    }
+    let (codepoint, remaining, minimum) = match byte {
+      0x00..=0x7f => return Utf8Step::Complete(byte as char),
+      0xc2..=0xdf => (byte & 0x1f, 1, 0x80),
+      0xe0..=0xef => (byte & 0x0f, 2, 0x800),
+      0xf0..=0xf4 => (byte & 0x07, 3, 0x10000),
+      // Continuation bytes without a lead, leads that can only start an
+      // overlong encoding, and leads past U+10FFFF
+      _ => return Utf8Step::Invalid,
+    };
+    self.codepoint = codepoint as u32;
+    self.remaining = remaining;
+    self.minimum = minimum;
+    Utf8Step::Pending
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+  use super::{Utf8Decoder, Utf8Step};
+
+  fn decode(bytes: &[u8]) -> Vec<Utf8Step> {
+    let mut decoder = Utf8Decoder::new();
+    bytes
+      .iter()
+      .map(|byte| decoder.push(*byte))
+      .filter(|step| *step != Utf8Step::Pending)
+      .collect()
+  }
+
+  #[test]
+  fn valid_sequences() {
+    assert_eq!(decode(b"a"), [Utf8Step::Complete('a')]);
+    assert_eq!(decode("°".as_bytes()), [Utf8Step::Complete('°')]);
+    assert_eq!(decode("╔═╗".as_bytes()), [Utf8Step::Complete('╔'), Utf8Step::Complete('═'), Utf8Step::Complete('╗')]);
+    assert_eq!(decode("\u{1f600}".as_bytes()), [Utf8Step::Complete('\u{1f600}')]);
+
+    // A character can be split across writes
+    let mut decoder = Utf8Decoder::new();
+    assert_eq!(decoder.push(0xe2), Utf8Step::Pending);
+    assert!(decoder.in_sequence());
+    assert_eq!(decoder.push(0x94), Utf8Step::Pending);
+    assert_eq!(decoder.push(0x80), Utf8Step::Complete('─'));
+    assert!(!decoder.in_sequence());
+  }
+
+  #[test]
+  fn invalid_sequences() {
+    // A continuation byte on its own
+    assert_eq!(decode(&[0x80, b'a']), [Utf8Step::Invalid, Utf8Step::Complete('a')]);
+    // Overlong encodings of '/'
+    assert_eq!(decode(&[0xc0, 0xaf]), [Utf8Step::Invalid, Utf8Step::Invalid]);
+    assert_eq!(decode(&[0xe0, 0x80, 0xaf]), [Utf8Step::Invalid]);
+    // A surrogate, and a codepoint past U+10FFFF
+    assert_eq!(decode(&[0xed, 0xa0, 0x80]), [Utf8Step::Invalid]);
+    assert_eq!(decode(&[0xf4, 0x90, 0x80, 0x80]), [Utf8Step::Invalid]);
+    assert_eq!(decode(&[0xff]), [Utf8Step::Invalid]);
+
+    // A sequence cut short leaves the next byte to be decoded again
+    let mut decoder = Utf8Decoder::new();
+    assert_eq!(decoder.push(0xc3), Utf8Step::Pending);
+    assert_eq!(decoder.push(b'\n'), Utf8Step::Interrupted);
+    assert_eq!(decoder.push(b'\n'), Utf8Step::Complete('\n'));
+  }
+}
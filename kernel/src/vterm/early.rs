@@ -0,0 +1,83 @@
+//! Console output normally goes through the vterm router, which isn't created
+//! until partway through boot. Until then, messages are written straight into
+//! the VGA text buffer, the same way kprint writes straight to the serial
+//! port, so that early diagnostics still reach the screen.
+//! Once the router is installed, it owns the screen. Handing off happens while
+//! the direct writer is locked, so a direct write is never in progress while
+//! the router draws.
+
+use crate::hardware::vga::text_mode::TextMode;
+use crate::memory::address::VirtualAddress;
+use crate::locks::Mutex;
+
+pub struct EarlyConsole {
+  /// Emptied once the router takes over
+  text: Mutex<Option<TextMode>>,
+}
+
+/// The text buffer is only touched while the lock is held
+unsafe impl Sync for EarlyConsole {}
+
+impl EarlyConsole {
+  pub const fn new(base: VirtualAddress) -> EarlyConsole {
+    EarlyConsole {
+      text: Mutex::new(Some(TextMode::new(base))),
+    }
+  }
+
+  /// Write directly to the text buffer. Returns false if the router has
+  /// already taken over, in which case nothing is written.
+  pub fn write(&self, s: &str) -> bool {
+    match self.text.lock().as_mut() {
+      Some(text) => {
+        text.write_string(s);
+        true
+      },
+      None => false,
+    }
+  }
+
+  /// Stop writing directly. `install` runs while direct writes are locked out,
+  /// so that it can put the router in place before anyone can fall back to
+  /// the direct path again.
+  pub fn hand_off<F>(&self, install: F)
+    where F: FnOnce() {
+    let mut text = self.text.lock();
+    install();
+    *text = None;
+  }
+
+  pub fn is_active(&self) -> bool {
+    self.text.lock().is_some()
+  }
+}
+
+pub static EARLY_CONSOLE: EarlyConsole = EarlyConsole::new(VirtualAddress::new(0xc00b8000));
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec;
+  use crate::memory::address::VirtualAddress;
+  use super::EarlyConsole;
+
+  #[test]
+  fn pre_init_messages_reach_buffer() {
+    let mut buffer = vec![0u8; 80 * 25 * 2];
+    let console = EarlyConsole::new(VirtualAddress::new(buffer.as_mut_ptr() as usize));
+    // Writing starts on the bottom row, and scrolls up with each line
+    assert!(console.write("Boot\n"));
+    assert!(console.write("OK"));
+    assert_eq!(buffer[23 * 160], b'B');
+    assert_eq!(buffer[23 * 160 + 6], b't');
+    assert_eq!(buffer[24 * 160], b'O');
+    assert_eq!(buffer[24 * 160 + 2], b'K');
+
+    let mut installed = false;
+    console.hand_off(|| installed = true);
+    assert!(installed);
+    assert!(!console.is_active());
+    // Once handed off, the buffer belongs to the router
+    assert!(!console.write("late"));
+    assert_eq!(buffer[24 * 160 + 4], b' ');
+  }
+}
@@ -1,4 +1,5 @@
-use crate::input::keyboard::{KeyAction, KeyCode, codes::US_LAYOUT};
+use crate::input::keyboard::{KeyAction, KeyCode};
+use crate::input::keyboard::layout::{self, DeadKey, KeyboardLayout};
 
 /// In order to apply meta keys like shift, control, and alt, the router needs
 /// to track when they are pressed and released. KeyState helps track this, and
@@ -6,16 +7,42 @@ use crate::input::keyboard::{KeyAction, KeyCode, codes::US_LAYOUT};
 /// each key press.
 pub struct KeyState {
   pub alt: bool,
+  pub alt_graph: bool,
   pub ctrl: bool,
   pub shift: bool,
+  layout: &'static KeyboardLayout,
+  /// A dead key that was pressed, waiting to be combined with the next key
+  pending_accent: Option<&'static DeadKey>,
 }
 
 impl KeyState {
   pub fn new() -> KeyState {
     KeyState {
       alt: false,
+      alt_graph: false,
       ctrl: false,
       shift: false,
+      layout: &layout::US,
+      pending_accent: None,
+    }
+  }
+
+  pub fn set_layout(&mut self, layout: &'static KeyboardLayout) {
+    self.layout = layout;
+    self.pending_accent = None;
+  }
+
+  pub fn get_layout(&self) -> &'static KeyboardLayout {
+    self.layout
+  }
+
+  /// Check whether a key press is the soft reset hotkey, Ctrl+Alt+R. If it is,
+  /// returns whether Shift is also held, which asks for the programs running
+  /// on the vterm to be interrupted as well.
+  pub fn soft_reset_requested(&self, action: KeyAction) -> Option<bool> {
+    match action {
+      KeyAction::Press(KeyCode::R) if self.ctrl && self.alt => Some(self.shift),
+      _ => None,
     }
   }
 
@@ -29,6 +56,14 @@ impl KeyState {
             self.alt = true;
             None
           },
+          KeyCode::AltGraph => {
+            if self.layout.has_alt_graph() {
+              self.alt_graph = true;
+            } else {
+              self.alt = true;
+            }
+            None
+          },
           KeyCode::Control => {
             self.ctrl = true;
             None
@@ -39,13 +74,21 @@ impl KeyState {
           },
           _ => {
             let len = self.key_code_to_ascii(code, buffer);
-            Some(len)
+            if len == 0 {
+              None
+            } else {
+              Some(len)
+            }
           },
         }
       },
       KeyAction::Release(code) => {
         match code {
           KeyCode::Alt => self.alt = false,
+          KeyCode::AltGraph => {
+            self.alt = false;
+            self.alt_graph = false;
+          },
           KeyCode::Control => self.ctrl = false,
           KeyCode::Shift => self.shift = false,
           _ => (),
@@ -55,9 +98,10 @@ impl KeyState {
     }
   }
 
-  /// Convert a KeyCode into a series of ASCII characters, placing them in the
-  /// buffer and returning the number of characters.
-  pub fn key_code_to_ascii(&self, input: KeyCode, buffer: &mut [u8]) -> usize {
+  /// Convert a KeyCode into a series of characters using the current layout,
+  /// placing them in the buffer and returning the number of characters.
+  /// Pressing a dead key produces no characters until the next key arrives.
+  pub fn key_code_to_ascii(&mut self, input: KeyCode, buffer: &mut [u8]) -> usize {
     if self.ctrl {
       match input {
         KeyCode::C => {
@@ -93,19 +137,125 @@ impl KeyState {
         3
       },
       _ => {
-        let index = input as usize;
-        let (normal, shifted) = if index < 0x60 {
-          US_LAYOUT[index]
-        } else {
-          (0, 0)
-        };
-        buffer[0] = if self.shift {
-          shifted
-        } else {
-          normal
-        };
-        1
+        if self.alt_graph {
+          buffer[0] = self.layout.get_alt_graph_char(input);
+          return 1;
+        }
+        if let Some(dead_key) = self.layout.get_dead_key(input, self.shift) {
+          self.pending_accent = Some(dead_key);
+          return 0;
+        }
+        let ch = self.layout.get_char(input, self.shift);
+        match self.pending_accent.take() {
+          Some(dead_key) => {
+            if let Some(composed) = dead_key.compose(ch) {
+              buffer[0] = composed;
+              return 1;
+            }
+            // Pressing space after a dead key produces the accent alone.
+            // Anything else that can't be combined is sent after the accent.
+            buffer[0] = dead_key.accent;
+            if input == KeyCode::Space {
+              return 1;
+            }
+            buffer[1] = ch;
+            2
+          },
+          None => {
+            buffer[0] = ch;
+            1
+          },
+        }
       }
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use crate::input::keyboard::{Keyboard, KeyAction, KeyCode};
+  use crate::input::keyboard::layout;
+  use super::KeyState;
+
+  fn type_scancode(state: &mut KeyState, scan_code: u8, buffer: &mut [u8]) -> Option<usize> {
+    let mut keyboard = Keyboard::new();
+    let action = keyboard.handle_raw_data(scan_code).unwrap();
+    state.process_key_action(action, buffer)
+  }
+
+  #[test]
+  fn layouts_translate_the_same_scancode() {
+    let mut buffer: [u8; 4] = [0; 4];
+    let mut us = KeyState::new();
+    let mut de = KeyState::new();
+    de.set_layout(&layout::DE);
+    // The key to the right of T
+    assert_eq!(type_scancode(&mut us, 0x15, &mut buffer), Some(1));
+    assert_eq!(buffer[0], b'y');
+    assert_eq!(type_scancode(&mut de, 0x15, &mut buffer), Some(1));
+    assert_eq!(buffer[0], b'z');
+
+    let mut uk = KeyState::new();
+    uk.set_layout(&layout::UK);
+    us.process_key_action(KeyAction::Press(KeyCode::Shift), &mut buffer);
+    uk.process_key_action(KeyAction::Press(KeyCode::Shift), &mut buffer);
+    // Shift + 2
+    assert_eq!(type_scancode(&mut us, 0x03, &mut buffer), Some(1));
+    assert_eq!(buffer[0], b'@');
+    assert_eq!(type_scancode(&mut uk, 0x03, &mut buffer), Some(1));
+    assert_eq!(buffer[0], b'"');
+  }
+
+  #[test]
+  fn alt_graph() {
+    let mut buffer: [u8; 4] = [0; 4];
+    let mut de = KeyState::new();
+    de.set_layout(&layout::DE);
+    de.process_key_action(KeyAction::Press(KeyCode::AltGraph), &mut buffer);
+    assert!(!de.alt);
+    assert_eq!(de.process_key_action(KeyAction::Press(KeyCode::Q), &mut buffer), Some(1));
+    assert_eq!(buffer[0], b'@');
+    de.process_key_action(KeyAction::Release(KeyCode::AltGraph), &mut buffer);
+    assert_eq!(de.process_key_action(KeyAction::Press(KeyCode::Q), &mut buffer), Some(1));
+    assert_eq!(buffer[0], b'q');
+
+    // Without AltGr, the right Alt key acts like the left one
+    let mut us = KeyState::new();
+    us.process_key_action(KeyAction::Press(KeyCode::AltGraph), &mut buffer);
+    assert!(us.alt);
+  }
+
+  #[test]
+  fn dead_keys() {
+    let mut buffer: [u8; 4] = [0; 4];
+    let mut de = KeyState::new();
+    de.set_layout(&layout::DE);
+    assert_eq!(de.process_key_action(KeyAction::Press(KeyCode::Backtick), &mut buffer), None);
+    assert_eq!(de.process_key_action(KeyAction::Press(KeyCode::E), &mut buffer), Some(1));
+    assert_eq!(buffer[0], 0x88);
+
+    de.process_key_action(KeyAction::Press(KeyCode::Backtick), &mut buffer);
+    assert_eq!(de.process_key_action(KeyAction::Press(KeyCode::Space), &mut buffer), Some(1));
+    assert_eq!(buffer[0], b'^');
+
+    de.process_key_action(KeyAction::Press(KeyCode::Backtick), &mut buffer);
+    assert_eq!(de.process_key_action(KeyAction::Press(KeyCode::K), &mut buffer), Some(2));
+    assert_eq!(&buffer[0..2], b"^k");
+  }
+
+  #[test]
+  fn soft_reset_hotkey() {
+    let mut buffer: [u8; 4] = [0; 4];
+    let mut state = KeyState::new();
+    let r = KeyAction::Press(KeyCode::R);
+    assert_eq!(state.soft_reset_requested(r), None);
+    state.process_key_action(KeyAction::Press(KeyCode::Control), &mut buffer);
+    assert_eq!(state.soft_reset_requested(r), None);
+    state.process_key_action(KeyAction::Press(KeyCode::Alt), &mut buffer);
+    assert_eq!(state.soft_reset_requested(r), Some(false));
+    assert_eq!(state.soft_reset_requested(KeyAction::Release(KeyCode::R)), None);
+    assert_eq!(state.soft_reset_requested(KeyAction::Press(KeyCode::T)), None);
+    state.process_key_action(KeyAction::Press(KeyCode::Shift), &mut buffer);
+    assert_eq!(state.soft_reset_requested(r), Some(true));
+  }
+}
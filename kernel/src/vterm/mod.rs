@@ -1,20 +1,24 @@
+pub mod early;
 pub mod keys;
 pub mod memory;
+pub mod mode;
 pub mod router;
+pub mod session;
 pub mod vterm;
 
+use alloc::vec::Vec;
 use crate::input::keyboard::KeyAction;
 use router::VTermRouter;
-use spin::RwLock;
+use crate::locks::RwLock;
 
 static mut ROUTER: Option<RwLock<VTermRouter>> = None;
 
 pub fn init_vterm() {
   let global_router = router::VTermRouter::new(5);
 
-  unsafe {
+  early::EARLY_CONSOLE.hand_off(|| unsafe {
     ROUTER = Some(RwLock::new(global_router));
-  }
+  });
   console_write(format_args!("\n\nVTerm system \x1b[92mready\x1b[m\n"));
 }
 
@@ -34,6 +38,10 @@ pub fn get_router() -> &'static RwLock<router::VTermRouter> {
 
 #[cfg(not(test))]
 fn change_video_mode_inner(mode: u8) {
+  if crate::hardware::vga::driver::VGA_DRIVER_PID.read().is_none() {
+    crate::klog!("Video driver is not running, can't set video mode {:X}\n", mode);
+    return;
+  }
   crate::hardware::vga::driver::request_mode_change_with_timeout(mode, 1000);
   let current_mode = crate::hardware::vga::driver::get_video_mode();
   if mode != current_mode {
@@ -44,23 +52,42 @@ fn change_video_mode_inner(mode: u8) {
 #[cfg(test)]
 fn change_video_mode_inner(_mode: u8) {}
 
-pub fn change_video_mode(index: usize, mode: u8) {
+/// Change the video mode of a vterm. If the vterm is on screen, the VGA card
+/// is reprogrammed immediately; returning to text mode also restores the text
+/// that was on screen before the vterm entered a graphics mode.
+pub fn change_video_mode(index: usize, mode: u8) -> Result<(), ()> {
   let needs_change = {
-    get_router().write().change_video_mode(index, mode)
+    get_router().write().change_video_mode(index, mode)?
   };
   if needs_change {
     change_video_mode_inner(mode);
+    if mode == mode::MODE_TEXT {
+      get_router().write().restore_text_device(index);
+    }
   }
+  Ok(())
+}
+
+pub fn get_video_mode(index: usize) -> Option<u8> {
+  get_router().read().get_video_mode(index)
+}
+
+/// Change the keyboard layout used to translate key presses for all vterms
+pub fn set_keyboard_layout(id: u32) -> Result<(), ()> {
+  let layout = crate::input::keyboard::layout::get_layout(id).ok_or(())?;
+  get_router().write().set_keyboard_layout(layout);
+  Ok(())
 }
 
 pub fn exit_dos_mode(index: usize) {
   let needs_change = {
     let mut router = get_router().write();
     router.exit_dos_mode(index);
-    router.change_video_mode(index, 0x03)
+    router.change_video_mode(index, mode::MODE_TEXT).unwrap_or(false)
   };
   if needs_change {
-    change_video_mode_inner(0x03);
+    change_video_mode_inner(mode::MODE_TEXT);
+    get_router().write().restore_text_device(index);
   }
 }
 
@@ -79,7 +106,61 @@ pub fn begin_session(tty: usize, program: &str) -> Result<(), ()> {
 
   // set foreground process for vterm here
 
-  crate::task::exec::exec(program, crate::loaders::InterpretationMode::Native).map_err(|_| ())
+  crate::task::exec::exec(program, alloc::vec::Vec::new(), crate::loaders::InterpretationMode::Native).map_err(|_| ())
+}
+
+/// Run the login loop for a vterm: start a session running the program, wait
+/// for it to exit, and decide whether to start it again. This never returns
+/// unless the supervisor decides the session is over.
+#[cfg(not(test))]
+pub fn supervise_session(tty: usize, program: &'static str, action: session::ExitAction) {
+  use crate::time::system::get_system_time;
+
+  let mut supervisor = session::SessionSupervisor::new(action);
+  loop {
+    let start_ms = get_system_time().in_ms();
+    let request = alloc::boxed::Box::new(SessionRequest { tty, program });
+    let child_id = crate::task::switching::kfork_with_arg(run_session, alloc::boxed::Box::into_raw(request) as usize);
+    let status = crate::task::wait(Some(child_id));
+    let exit = session::SessionExit::from_status(status);
+    let run_time_ms = get_system_time().in_ms() - start_ms;
+    match exit {
+      session::SessionExit::Exited(code) => {
+        crate::klog!("TTY{}: {} exited with code {}\n", tty, program, code);
+      },
+      session::SessionExit::Signaled(signal) => {
+        crate::klog!("TTY{}: {} was killed by signal {}\n", tty, program, signal);
+      },
+    }
+    match supervisor.program_exited(exit, run_time_ms) {
+      Some(delay) => {
+        if delay > 0 {
+          crate::task::sleep(delay as usize);
+        }
+      },
+      None => return,
+    }
+  }
+}
+
+/// What a newly forked session process needs to start its program. Each
+/// supervisor hands a request directly to the child it forks.
+#[cfg(not(test))]
+struct SessionRequest {
+  tty: usize,
+  program: &'static str,
+}
+
+/// Entry point of a forked session process, which becomes the foreground
+/// program of its vterm. It takes ownership of a boxed SessionRequest.
+#[cfg(not(test))]
+extern "C" fn run_session(request: usize) {
+  let request = unsafe { alloc::boxed::Box::from_raw(request as *mut SessionRequest) };
+  let SessionRequest { tty, program } = *request;
+  if let Err(_) = begin_session(tty, program) {
+    crate::klog!("TTY{}: failed to start {}\n", tty, program);
+  }
+  crate::task::exec::terminate(1);
 }
 
 #[inline(never)]
@@ -87,19 +168,60 @@ pub extern "C" fn vterm_process() {
   loop {
     // Check each TTY buffer for new data that we need to process
     let router = get_router();
-    match router.try_write() {
-      Some(mut r) => r.process_buffers(),
-      None => (),
+    let resets = match router.try_write() {
+      Some(mut r) => {
+        r.process_buffers();
+        r.take_pending_resets()
+      },
+      None => Vec::new(),
+    };
+    for reset in resets {
+      signal_after_reset(reset);
     }
     crate::task::yield_coop();
   }
 }
 
+/// Once a vterm has been soft reset, stop the programs that may have left it
+/// in a bad state. A DOS program owns the whole terminal, so it is killed and
+/// its VM torn down. Native programs are only interrupted if the user asked.
+#[cfg(not(test))]
+fn signal_after_reset(reset: router::SoftReset) {
+  use crate::task::signal::Signal;
+  use crate::task::vm::Subsystem;
+
+  if !reset.was_dos && !reset.interrupt {
+    return;
+  }
+  let mut targets = Vec::new();
+  crate::task::switching::for_each_process_mut(|p| {
+    let process = p.read();
+    if process.is_terminated() || process.get_vterm() != Some(reset.index) {
+      return;
+    }
+    match process.subsystem {
+      Subsystem::DOS(_) => targets.push((*process.get_id(), Signal::Kill)),
+      _ if reset.interrupt => targets.push((*process.get_id(), Signal::UserInterrupt)),
+      _ => (),
+    }
+  });
+  for (id, signal) in targets {
+    let _ = crate::task::exec::send_signal(Some(id), signal);
+  }
+}
+#[cfg(test)]
+fn signal_after_reset(_reset: router::SoftReset) {}
+
 /// Empty singleton-style struct to implement easy formatted writing
 pub struct Console();
 
 impl core::fmt::Write for Console {
   fn write_str(&mut self, s: &str) -> Result<(), core::fmt::Error> {
+    // Before the router exists, write straight to the screen. If the direct
+    // path has just been handed off, the router is ready.
+    if unsafe { ROUTER.is_none() } && early::EARLY_CONSOLE.write(s) {
+      return Ok(());
+    }
     let router = get_router();
     // Alternatively, this could be a try_read and push the data to the buffer.
     // That might be better...
@@ -0,0 +1,159 @@
+/// 80x25 color text, the default for most vterms
+pub const MODE_TEXT: u8 = 0x03;
+/// 320x200 with 256 colors
+pub const MODE_VGA_256: u8 = 0x13;
+
+/// Only modes the VGA driver knows how to set up (and the vterm knows how to
+/// back up) can be requested
+pub fn is_supported_mode(mode: u8) -> bool {
+  match mode {
+    MODE_TEXT | MODE_VGA_256 => true,
+    _ => false,
+  }
+}
+
+/// Describes what needs to happen to a vterm's text output when its video mode
+/// changes. Setting a mode through the VGA BIOS clears video memory, so text
+/// content is kept in the vterm's backup page while a graphics mode is active.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ModeTransition {
+  /// The mode did not change
+  Unchanged,
+  /// Leaving text mode. Text output needs to move to the backup page before
+  /// the card is reprogrammed.
+  LeaveText,
+  /// Returning to text mode. Once the card is back in text mode, the saved
+  /// text needs to be copied back to video memory.
+  EnterText,
+  /// Switching from one graphics mode to another
+  Graphics,
+}
+
+/// Tracks the video mode of a single vterm
+pub struct VideoModeState {
+  current: u8,
+  /// The mode the vterm was created with, restored on a full reset
+  default: u8,
+  /// Set when a reset or forced change needs the VGA card to be reprogrammed,
+  /// if this vterm is active
+  reset_pending: bool,
+}
+
+impl VideoModeState {
+  pub fn new(mode: u8) -> Self {
+    Self {
+      current: mode,
+      default: mode,
+      reset_pending: false,
+    }
+  }
+
+  pub fn current(&self) -> u8 {
+    self.current
+  }
+
+  pub fn is_text(&self) -> bool {
+    self.current == MODE_TEXT
+  }
+
+  /// Change the mode, returning what needs to happen to text output. Unknown
+  /// modes are rejected without changing anything.
+  pub fn set(&mut self, mode: u8) -> Result<ModeTransition, ()> {
+    if !is_supported_mode(mode) {
+      return Err(());
+    }
+    let transition = if mode == self.current {
+      ModeTransition::Unchanged
+    } else if mode == MODE_TEXT {
+      ModeTransition::EnterText
+    } else if self.current == MODE_TEXT {
+      ModeTransition::LeaveText
+    } else {
+      ModeTransition::Graphics
+    };
+    self.current = mode;
+    Ok(transition)
+  }
+
+  /// Return to the default mode, marking the change as pending so that the
+  /// VGA card can be updated later
+  pub fn reset(&mut self) -> ModeTransition {
+    let default = self.default;
+    let transition = self.set(default).unwrap_or(ModeTransition::Unchanged);
+    if transition != ModeTransition::Unchanged {
+      self.reset_pending = true;
+    }
+    transition
+  }
+
+  /// Force the vterm into a specific mode. Unlike `reset`, the change is
+  /// marked as pending even if the mode number stays the same, since a
+  /// program may have changed the card's registers without the vterm knowing.
+  pub fn force(&mut self, mode: u8) -> Result<ModeTransition, ()> {
+    let transition = self.set(mode)?;
+    self.reset_pending = true;
+    Ok(transition)
+  }
+
+  /// Check whether a reset changed the video mode since the last call
+  pub fn take_reset(&mut self) -> bool {
+    let pending = self.reset_pending;
+    self.reset_pending = false;
+    pending
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{ModeTransition, VideoModeState, MODE_TEXT, MODE_VGA_256};
+
+  #[test]
+  fn text_graphics_round_trip() {
+    let mut state = VideoModeState::new(MODE_TEXT);
+    assert!(state.is_text());
+    assert_eq!(state.set(MODE_TEXT), Ok(ModeTransition::Unchanged));
+    assert_eq!(state.set(MODE_VGA_256), Ok(ModeTransition::LeaveText));
+    assert_eq!(state.current(), MODE_VGA_256);
+    assert!(!state.is_text());
+    assert_eq!(state.set(MODE_VGA_256), Ok(ModeTransition::Unchanged));
+    assert_eq!(state.set(MODE_TEXT), Ok(ModeTransition::EnterText));
+    assert_eq!(state.current(), MODE_TEXT);
+    assert!(state.is_text());
+  }
+
+  #[test]
+  fn unknown_modes_are_rejected() {
+    let mut state = VideoModeState::new(MODE_TEXT);
+    assert_eq!(state.set(0x12), Err(()));
+    assert_eq!(state.set(0xff), Err(()));
+    assert_eq!(state.current(), MODE_TEXT);
+  }
+
+  #[test]
+  fn reset_restores_default() {
+    let mut state = VideoModeState::new(MODE_TEXT);
+    assert_eq!(state.reset(), ModeTransition::Unchanged);
+    assert!(!state.take_reset());
+    state.set(MODE_VGA_256).unwrap();
+    assert_eq!(state.reset(), ModeTransition::EnterText);
+    assert!(state.take_reset());
+    assert!(!state.take_reset());
+    assert_eq!(state.current(), MODE_TEXT);
+  }
+
+  #[test]
+  fn forced_mode_is_always_reprogrammed() {
+    let mut state = VideoModeState::new(MODE_VGA_256);
+    assert_eq!(state.force(MODE_TEXT), Ok(ModeTransition::EnterText));
+    assert!(state.is_text());
+    assert!(state.take_reset());
+    // Already in text mode, but the card still needs to be set up again
+    assert_eq!(state.force(MODE_TEXT), Ok(ModeTransition::Unchanged));
+    assert!(state.take_reset());
+    assert!(!state.take_reset());
+    assert_eq!(state.force(0x12), Err(()));
+    assert!(!state.take_reset());
+    // The vterm's own default is unchanged
+    assert_eq!(state.reset(), ModeTransition::LeaveText);
+  }
+}
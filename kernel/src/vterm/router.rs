@@ -1,10 +1,22 @@
 use alloc::vec::Vec;
 use crate::hardware::vga::text_mode::{Color, ColorCode};
-use crate::input::keyboard::{KeyAction, KeyCode};
+use crate::input::keyboard::{KeyAction, KeyCode, layout::KeyboardLayout};
 use crate::memory::address::PhysicalAddress;
 use super::keys::KeyState;
+use super::mode::ModeTransition;
 use super::vterm::VTerm;
 
+/// A soft reset that has been applied to a vterm, but whose processes still
+/// need to be signaled. That can't happen while the router is locked, so the
+/// vterm process takes care of it afterwards.
+pub struct SoftReset {
+  pub index: usize,
+  /// A DOS program was running, and its VM needs to be torn down
+  pub was_dos: bool,
+  /// The user also asked for native programs on the vterm to be interrupted
+  pub interrupt: bool,
+}
+
 /// The vterm router collects all input and delivers it to the correct process
 /// based on which vterm is currently "active."
 /// It also hooks into input and changes the active terminal based on specific
@@ -13,6 +25,7 @@ pub struct VTermRouter {
   vterm_list: Vec<VTerm>,
   active_vterm: usize,
   key_state: KeyState,
+  pending_resets: Vec<SoftReset>,
 }
 
 impl VTermRouter {
@@ -40,6 +53,7 @@ impl VTermRouter {
       vterm_list,
       active_vterm: 0,
       key_state: KeyState::new(),
+      pending_resets: Vec::new(),
     }
   }
 
@@ -55,7 +69,7 @@ impl VTermRouter {
       None => return,
     };
     self.active_vterm = active;
-    let video_mode = next_vterm.video_mode;
+    let video_mode = next_vterm.get_video_mode();
     // This will pause the calling process (likely the input process) until the
     // hardware request finishes.
     // If it fails to complete, it should time out after a second, unlocking the
@@ -88,17 +102,28 @@ impl VTermRouter {
 
   /// Change the internally-registered video mode for a specific vterm.
   /// Returns true if that vterm is active and the VGA card needs to be
-  /// updated immediately.
-  pub fn change_video_mode(&mut self, index: usize, mode: u8) -> bool {
-    let mut vterm = match self.vterm_list.get_mut(index) {
-      Some(v) => v,
-      None => return false,
-    };
-    if vterm.video_mode == mode {
-      return false;
+  /// updated immediately, or an error if the vterm or mode is unknown.
+  pub fn change_video_mode(&mut self, index: usize, mode: u8) -> Result<bool, ()> {
+    let vterm = self.vterm_list.get_mut(index).ok_or(())?;
+    match vterm.set_video_mode(mode)? {
+      ModeTransition::Unchanged => Ok(false),
+      _ => Ok(self.active_vterm == index),
     }
-    vterm.video_mode = mode;
-    self.active_vterm == index
+  }
+
+  pub fn get_video_mode(&self, index: usize) -> Option<u8> {
+    self.vterm_list.get(index).map(|vterm| vterm.get_video_mode())
+  }
+
+  /// Called after the VGA card has switched a vterm back to text mode
+  pub fn restore_text_device(&mut self, index: usize) {
+    if let Some(vterm) = self.vterm_list.get_mut(index) {
+      vterm.restore_text_device();
+    }
+  }
+
+  pub fn set_keyboard_layout(&mut self, layout: &'static KeyboardLayout) {
+    self.key_state.set_layout(layout);
   }
 
   pub fn enter_dos_mode(&mut self, index: usize) {
@@ -117,6 +142,21 @@ impl VTermRouter {
     vterm.exit_dos_mode();
   }
 
+  /// Return a vterm to a usable state without affecting any other vterm
+  pub fn soft_reset(&mut self, index: usize, interrupt: bool) {
+    let vterm = match self.vterm_list.get_mut(index) {
+      Some(v) => v,
+      None => return,
+    };
+    let was_dos = vterm.soft_reset();
+    self.pending_resets.push(SoftReset { index, was_dos, interrupt });
+  }
+
+  /// Collect the soft resets whose processes haven't been signaled yet
+  pub fn take_pending_resets(&mut self) -> Vec<SoftReset> {
+    core::mem::replace(&mut self.pending_resets, Vec::new())
+  }
+
   pub fn add_memory_backup(&mut self, index: usize, address: usize) -> PhysicalAddress {
     let vterm = match self.vterm_list.get_mut(index) {
       Some(v) => v,
@@ -127,6 +167,10 @@ impl VTermRouter {
   }
 
   pub fn send_key_action(&mut self, action: KeyAction) {
+    if let Some(interrupt) = self.key_state.soft_reset_requested(action) {
+      self.soft_reset(self.active_vterm, interrupt);
+      return;
+    }
     if self.key_state.alt {
       match action {
         KeyAction::Press(KeyCode::Num0) => {
@@ -185,7 +229,8 @@ impl VTermRouter {
 
   pub fn process_buffers(&mut self) {
     let mut data: [u8; 4] = [0; 4];
-    for vterm in self.vterm_list.iter_mut() {
+    let active = self.active_vterm;
+    for (index, vterm) in self.vterm_list.iter_mut().enumerate() {
       let tty_index = vterm.get_tty_index();
       let write_buffer = crate::tty::device::get_write_buffer(tty_index);
 
@@ -199,6 +244,11 @@ impl VTermRouter {
         };
         vterm.send_characters(&data[0..bytes_read]);
       }
+      if vterm.take_mode_reset() && index == active {
+        #[cfg(not(test))]
+        crate::hardware::vga::driver::request_mode_change_with_timeout(vterm.get_video_mode(), 1000);
+        vterm.restore_text_device();
+      }
     }
   }
 
@@ -0,0 +1,144 @@
+//! Each vterm runs a login session: a foreground program, typically the shell,
+//! that owns the terminal. When that program exits, the session supervisor
+//! reaps it and decides what to do next, so that a vterm is never left without
+//! a program reading from it.
+
+use syscall::signals::{EXIT_CODE_MASK, STATUS_SIGNALED};
+
+/// A program that runs for less than this long is considered to have failed
+/// to start, and restarting it again is delayed
+pub const MIN_HEALTHY_RUN_MS: u64 = 2000;
+/// Delay applied after the first rapid failure, doubled for each one after it
+pub const BASE_RESPAWN_DELAY_MS: u64 = 250;
+/// Upper bound on the delay between restarts
+pub const MAX_RESPAWN_DELAY_MS: u64 = 8000;
+
+/// How the foreground program of a session ended
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SessionExit {
+  /// The program called exit with a code
+  Exited(u32),
+  /// The program was terminated by a signal
+  Signaled(u32),
+}
+
+impl SessionExit {
+  /// Interpret the status reported when waiting on a child process. Only the
+  /// low bits hold the exit code or signal; the bits above are status flags.
+  pub fn from_status(status: u32) -> SessionExit {
+    let value = status & EXIT_CODE_MASK;
+    if status & STATUS_SIGNALED != 0 {
+      SessionExit::Signaled(value)
+    } else {
+      SessionExit::Exited(value)
+    }
+  }
+
+  pub fn is_clean(&self) -> bool {
+    *self == SessionExit::Exited(0)
+  }
+}
+
+/// What the supervisor does once the foreground program has exited
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExitAction {
+  /// Start the program again
+  Respawn,
+  /// Leave the vterm idle if the program exited cleanly, only restarting it
+  /// after a crash
+  RespawnOnFailure,
+}
+
+/// Tracks the history of a session's foreground program, and decides when it
+/// should be restarted
+pub struct SessionSupervisor {
+  action: ExitAction,
+  last_exit: Option<SessionExit>,
+  restarts: u32,
+  /// Number of consecutive runs that ended too quickly
+  rapid_failures: u32,
+}
+
+impl SessionSupervisor {
+  pub const fn new(action: ExitAction) -> SessionSupervisor {
+    SessionSupervisor {
+      action,
+      last_exit: None,
+      restarts: 0,
+      rapid_failures: 0,
+    }
+  }
+
+  pub fn get_last_exit(&self) -> Option<SessionExit> {
+    self.last_exit
+  }
+
+  pub fn get_restart_count(&self) -> u32 {
+    self.restarts
+  }
+
+  /// Record that the foreground program exited after running for a period of
+  /// time. If it should be started again, this returns the number of
+  /// milliseconds to wait before doing so.
+  pub fn program_exited(&mut self, exit: SessionExit, run_time_ms: u64) -> Option<u64> {
+    self.last_exit = Some(exit);
+    if self.action == ExitAction::RespawnOnFailure && exit.is_clean() {
+      return None;
+    }
+    self.restarts += 1;
+    if run_time_ms >= MIN_HEALTHY_RUN_MS {
+      self.rapid_failures = 0;
+      return Some(0);
+    }
+    let shift = self.rapid_failures.min(16);
+    self.rapid_failures += 1;
+    Some((BASE_RESPAWN_DELAY_MS << shift).min(MAX_RESPAWN_DELAY_MS))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use syscall::signals::{SEGFAULT, STATUS_SIGNALED};
+  use super::{ExitAction, SessionExit, SessionSupervisor, BASE_RESPAWN_DELAY_MS, MAX_RESPAWN_DELAY_MS};
+
+  #[test]
+  fn exit_status() {
+    assert_eq!(SessionExit::from_status(0), SessionExit::Exited(0));
+    assert_eq!(SessionExit::from_status(3), SessionExit::Exited(3));
+    assert_eq!(SessionExit::from_status(STATUS_SIGNALED | SEGFAULT), SessionExit::Signaled(SEGFAULT));
+    assert!(!SessionExit::Signaled(SEGFAULT).is_clean());
+  }
+
+  #[test]
+  fn respawn_with_backoff() {
+    let mut supervisor = SessionSupervisor::new(ExitAction::Respawn);
+    // A shell that has been running a while restarts immediately
+    assert_eq!(supervisor.program_exited(SessionExit::Exited(0), 60000), Some(0));
+    assert_eq!(supervisor.get_last_exit(), Some(SessionExit::Exited(0)));
+
+    // A shell that keeps crashing on startup backs off
+    let crash = SessionExit::Signaled(SEGFAULT);
+    assert_eq!(supervisor.program_exited(crash, 10), Some(BASE_RESPAWN_DELAY_MS));
+    assert_eq!(supervisor.program_exited(crash, 10), Some(BASE_RESPAWN_DELAY_MS * 2));
+    assert_eq!(supervisor.program_exited(crash, 10), Some(BASE_RESPAWN_DELAY_MS * 4));
+    for _ in 0..40 {
+      supervisor.program_exited(crash, 10);
+    }
+    assert_eq!(supervisor.program_exited(crash, 10), Some(MAX_RESPAWN_DELAY_MS));
+    assert_eq!(supervisor.get_last_exit(), Some(crash));
+
+    // Once it stays up, the backoff resets
+    assert_eq!(supervisor.program_exited(SessionExit::Exited(1), 5000), Some(0));
+    assert_eq!(supervisor.program_exited(crash, 10), Some(BASE_RESPAWN_DELAY_MS));
+    assert_eq!(supervisor.get_restart_count(), 47);
+  }
+
+  #[test]
+  fn respawn_only_on_failure() {
+    let mut supervisor = SessionSupervisor::new(ExitAction::RespawnOnFailure);
+    assert_eq!(supervisor.program_exited(SessionExit::Signaled(SEGFAULT), 60000), Some(0));
+    assert_eq!(supervisor.program_exited(SessionExit::Exited(2), 60000), Some(0));
+    assert_eq!(supervisor.program_exited(SessionExit::Exited(0), 60000), None);
+    assert_eq!(supervisor.get_restart_count(), 2);
+  }
+}
@@ -1,7 +1,13 @@
-use crate::hardware::vga::text_mode::TextMode;
+use alloc::vec::Vec;
+use crate::hardware::vga::text_mode::{set_hardware_cursor, set_hardware_cursor_visible, TextMode};
 use crate::memory::address::PhysicalAddress;
+use crate::tty::line::{LineEditor, DEFAULT_HISTORY_DEPTH, END_OF_INPUT};
 use crate::tty::parser::{Parser, TTYAction};
 use super::memory::MemoryBackup;
+use super::mode::{ModeTransition, VideoModeState, MODE_TEXT};
+
+/// Index of the backup for the text mode page at 0xb8000
+const TEXT_BACKUP_INDEX: usize = (0xb8000 - 0xa0000) / 0x1000;
 
 /// A vterm virtualizes access to the keyboard input and video output.
 /// This is how the operating system achieves multitasking from the user's
@@ -14,11 +20,15 @@ use super::memory::MemoryBackup;
 /// the video state at any time, and can track any changes that happen while
 /// inactive.
 pub struct VTerm {
-  pub video_mode: u8,
+  video_mode: VideoModeState,
+  /// Whether this vterm currently owns the screen
+  active: bool,
   memory_backups: [Option<MemoryBackup>; 32],
   text_mode_state: TextMode,
   ansi_parser: Parser,
   tty_index: usize,
+  /// Collects canonical-mode input, with history, when the TTY enables it
+  line_editor: LineEditor,
 
   // ==== mode flags
 
@@ -38,13 +48,19 @@ impl VTerm {
     // all vterms have a memory backup for the "text mode" page at 0xb8000
     let backup = MemoryBackup::allocate(PhysicalAddress::new(0xb8000));
     let backup_location = backup.mapped_to;
-    memory_backups[(0xb8000 - 0xa0000) / 0x1000] = Some(backup);
+    memory_backups[TEXT_BACKUP_INDEX] = Some(backup);
+    // Output is batched a row at a time, and flushed before the hardware
+    // cursor is moved to match it
+    let mut text_mode_state = TextMode::new(backup_location);
+    text_mode_state.set_buffered(true);
     Self {
-      video_mode: mode,
+      video_mode: VideoModeState::new(mode),
+      active: false,
       memory_backups,
-      text_mode_state: TextMode::new(backup_location),
+      text_mode_state,
       ansi_parser: Parser::new(),
       tty_index: 0,
+      line_editor: LineEditor::new(DEFAULT_HISTORY_DEPTH),
       echo_input_flag: true,
       raw_mode_flag: false,
       dos_mode_flag: false,
@@ -85,31 +101,61 @@ impl VTerm {
   /// Each active video memory area is copied back to physical memory. Depending
   /// on video state, some other IO ports may be set as well.
   pub fn make_active(&mut self) {
+    let is_text = self.video_mode.is_text();
     unsafe {
-      for backup in &self.memory_backups {
+      for (index, backup) in self.memory_backups.iter().enumerate() {
+        if index == TEXT_BACKUP_INDEX && !is_text {
+          continue;
+        }
         if let Some(b) = backup {
           b.copy_from_buffer();
         }
       }
     }
+    self.active = true;
     // When the terminal is active, write text mode content directly to video
-    self.text_mode_state.set_buffer_pointer(0xc00b8000);
+    if is_text {
+      self.text_mode_state.set_buffer_pointer(0xc00b8000);
+    }
+    set_hardware_cursor_visible(is_text);
+    self.sync_hardware_cursor();
   }
 
   pub fn make_initial(&mut self) {
+    self.active = true;
     self.text_mode_state.set_buffer_pointer(0xc00b8000);
+    self.sync_hardware_cursor();
+  }
+
+  /// While this vterm owns a text mode screen, the blinking hardware cursor
+  /// follows its logical cursor. Every batch of output ends here, so any
+  /// characters still buffered are written out first, and never show up
+  /// behind the cursor.
+  fn sync_hardware_cursor(&mut self) {
+    self.text_mode_state.flush();
+    if !self.active || !self.video_mode.is_text() {
+      return;
+    }
+    let (col, row) = self.text_mode_state.get_cursor();
+    set_hardware_cursor(col, row);
   }
 
   /// When a VTerm becomes inactive, it needs to store its current state. This
   /// involves copying all active video memory areas to their back buffers.
   pub fn make_inactive(&mut self) {
+    let is_text = self.video_mode.is_text();
     unsafe {
-      for backup in &self.memory_backups {
+      for (index, backup) in self.memory_backups.iter().enumerate() {
+        // In a graphics mode, the backup already holds the text content
+        if index == TEXT_BACKUP_INDEX && !is_text {
+          continue;
+        }
         if let Some(b) = backup {
           b.copy_to_buffer();
         }
       }
     }
+    self.active = false;
     let text_backup_addr = self.get_memory_backup(PhysicalAddress::new(0xb8000))
       .and_then(|backup| Some(backup.mapped_to.as_usize()));
     if let Some(addr) = text_backup_addr {
@@ -142,16 +188,58 @@ impl VTerm {
   /// and add them to the "read" side of the associated TTY device if there are
   /// any active readers.
   pub fn handle_input(&mut self, chars: &[u8]) {
-    for ch in chars {
-      if *ch == 0x08 && self.should_backspace() {
+    let history_depth = crate::tty::device::get_history_depth(self.tty_index);
+    if history_depth > 0 && self.should_backspace() {
+      self.edit_line(chars, history_depth);
+      return;
+    }
+    // find the matching TTY device and add these chars to the reader buffer
+    let read_buffer = crate::tty::device::get_read_buffer(self.tty_index);
+    let mut pending = 0;
+    for (index, ch) in chars.iter().enumerate() {
+      if *ch == END_OF_INPUT && self.should_backspace() {
+        // Ctrl+D isn't passed on in canonical mode. It ends the input after
+        // everything typed before it.
+        read_buffer.add_data(&chars[pending..index]);
+        read_buffer.end_input();
+        pending = index + 1;
+      } else if *ch == 0x08 && self.should_backspace() {
         self.text_mode_state.backspace();
       } else if self.should_echo() {
         self.write_character(*ch);
       }
     }
-    // find the matching TTY device and add these chars to the reader buffer
+    self.sync_hardware_cursor();
+    read_buffer.add_data(&chars[pending..]);
+  }
+
+  /// In canonical mode with history enabled, input is collected by the line
+  /// editor, and readers only receive each line once it is complete
+  fn edit_line(&mut self, chars: &[u8], history_depth: usize) {
+    self.line_editor.set_history_depth(history_depth);
     let read_buffer = crate::tty::device::get_read_buffer(self.tty_index);
-    read_buffer.add_data(chars);
+    let mut echo = Vec::new();
+    for ch in chars {
+      if let Some(line) = self.line_editor.process(*ch, &mut echo) {
+        read_buffer.add_data(&line);
+        if *ch == END_OF_INPUT {
+          read_buffer.end_input();
+        }
+      }
+    }
+    if let Some(escape) = self.line_editor.flush_escape() {
+      read_buffer.add_data(&escape);
+    }
+    if self.should_echo() {
+      for ch in echo {
+        if ch == 0x08 {
+          self.text_mode_state.backspace();
+        } else {
+          self.write_character(ch);
+        }
+      }
+      self.sync_hardware_cursor();
+    }
   }
 
   /// Takes a stream of character bytes to be handled by the terminal parser. It
@@ -159,60 +247,167 @@ impl VTerm {
   pub fn send_characters(&mut self, chars: &[u8]) {
     for ch in chars {
       let action = self.ansi_parser.process_character(*ch);
-      match action {
-        TTYAction::Print(print) => self.write_character(print),
-        TTYAction::NewLine => self.text_mode_state.newline(),
-        TTYAction::MoveCursor(dx, dy) => {
-          self.text_mode_state.move_cursor_relative(dx, dy);
-        },
-        TTYAction::SetColumn(col) => {
-
-        },
-        TTYAction::SetPosition(col, row) => {
-          self.text_mode_state.move_cursor(col as u8, row as u8);
-        },
-        TTYAction::ClearScreen => {
-          self.text_mode_state.clear_screen();
-        },
-        TTYAction::ClearToBeginning => {
-          self.text_mode_state.clear_screen_to_beginning();
-        },
-        TTYAction::ClearToEnd => {
-          self.text_mode_state.clear_screen_to_end();
-        },
-        TTYAction::ClearRow => {
-          self.text_mode_state.clear_row();
-        },
-        TTYAction::ClearRowToBeginning => {
-          self.text_mode_state.clear_row_to_beginning();
-        },
-        TTYAction::ClearRowToEnd => {
-          self.text_mode_state.clear_row_to_end();
-        },
-        TTYAction::NextLineStart(dist) => {
-
-        },
-        TTYAction::PrevLineStart(dist) => {
-
-        },
-        TTYAction::ScrollUp(lines) => {
-          self.text_mode_state.scroll(lines as u8);
-        },
-        TTYAction::ScrollDown(lines) => {
-
-        },
-        TTYAction::ResetColors => {
-          self.text_mode_state.reset_colors();
-        },
-        TTYAction::SetFgColor(fg) => {
-          self.text_mode_state.set_fg_color(fg);
-        },
-        TTYAction::SetBgColor(bg) => {
-          self.text_mode_state.set_bg_color(bg);
-        },
-        _ => (),
+      self.apply_action(action);
+      if let Some(interrupted) = self.ansi_parser.take_interrupted() {
+        let action = self.ansi_parser.process_character(interrupted);
+        self.apply_action(action);
       }
     }
+    self.sync_hardware_cursor();
+  }
+
+  fn apply_action(&mut self, action: TTYAction) {
+    match action {
+      TTYAction::Print(print) => self.write_character(print),
+      TTYAction::Glyph(glyph) => self.text_mode_state.write_glyph(glyph),
+      TTYAction::NewLine => self.text_mode_state.newline(),
+      TTYAction::MoveCursor(dx, dy) => {
+        self.text_mode_state.move_cursor_relative(dx, dy);
+      },
+      TTYAction::SetColumn(col) => {
+
+      },
+      TTYAction::SetPosition(col, row) => {
+        self.text_mode_state.move_cursor(col as u8, row as u8);
+      },
+      TTYAction::ClearScreen => {
+        self.text_mode_state.clear_screen();
+      },
+      TTYAction::ClearScrollback => {
+        // Text mode keeps no history beyond the visible screen, so clearing
+        // the scrollback means clearing everything that is visible
+        self.text_mode_state.clear_screen();
+      },
+      TTYAction::ClearToBeginning => {
+        self.text_mode_state.clear_screen_to_beginning();
+      },
+      TTYAction::ClearToEnd => {
+        self.text_mode_state.clear_screen_to_end();
+      },
+      TTYAction::ClearRow => {
+        self.text_mode_state.clear_row();
+      },
+      TTYAction::ClearRowToBeginning => {
+        self.text_mode_state.clear_row_to_beginning();
+      },
+      TTYAction::ClearRowToEnd => {
+        self.text_mode_state.clear_row_to_end();
+      },
+      TTYAction::NextLineStart(dist) => {
+
+      },
+      TTYAction::PrevLineStart(dist) => {
+
+      },
+      TTYAction::ScrollUp(lines) => {
+        self.text_mode_state.scroll(lines as u8);
+      },
+      TTYAction::ScrollDown(lines) => {
+
+      },
+      TTYAction::ResetColors => {
+        self.text_mode_state.reset_colors();
+      },
+      TTYAction::SetFgColor(fg) => {
+        self.text_mode_state.set_fg_color(fg);
+      },
+      TTYAction::SetBgColor(bg) => {
+        self.text_mode_state.set_bg_color(bg);
+      },
+      TTYAction::Reset => self.reset(),
+      _ => (),
+    }
+  }
+
+  /// Perform a full terminal reset, in response to the RIS escape code. Colors,
+  /// input modes, and the video mode return to their defaults, the screen is
+  /// cleared, and the cursor moves to the top left. Existing memory backups
+  /// are reused rather than reallocated.
+  pub fn reset(&mut self) {
+    self.text_mode_state.reset_colors();
+    self.text_mode_state.clear_screen();
+    self.text_mode_state.move_cursor(0, 0);
+    self.echo_input_flag = true;
+    self.raw_mode_flag = false;
+    if self.video_mode.reset() == ModeTransition::LeaveText {
+      self.detach_text_device();
+    }
+  }
+
+  /// Recover a vterm that a program left unusable, in response to the soft
+  /// reset hotkey. Unlike a full reset, the vterm always returns to 80x25
+  /// text regardless of its default mode, and leaves DOS mode. Any partly
+  /// typed line or escape sequence is dropped. Returns whether a DOS program
+  /// was running on the vterm.
+  pub fn soft_reset(&mut self) -> bool {
+    let was_dos = self.dos_mode_flag;
+    self.dos_mode_flag = false;
+    self.ansi_parser = Parser::new();
+    self.line_editor.discard_line();
+    if self.video_mode.is_text() {
+      // The card gets reprogrammed even if it's already in text mode, so text
+      // has to be kept in the backup page until that's done
+      self.detach_text_device();
+    }
+    let _ = self.video_mode.force(MODE_TEXT);
+    self.text_mode_state.reset_colors();
+    self.text_mode_state.clear_screen();
+    self.text_mode_state.move_cursor(0, 0);
+    self.echo_input_flag = true;
+    self.raw_mode_flag = false;
+    was_dos
+  }
+
+  /// Check whether a reset changed the video mode since the last call. The
+  /// router uses this to reprogram the VGA card for the active vterm.
+  pub fn take_mode_reset(&mut self) -> bool {
+    self.video_mode.take_reset()
+  }
+
+  pub fn get_video_mode(&self) -> u8 {
+    self.video_mode.current()
+  }
+
+  /// Change the video mode of this vterm. If it is leaving text mode, its text
+  /// content is saved to the backup page before the VGA card gets cleared by
+  /// the mode change. Unknown modes are rejected.
+  pub fn set_video_mode(&mut self, mode: u8) -> Result<ModeTransition, ()> {
+    let transition = self.video_mode.set(mode)?;
+    if transition == ModeTransition::LeaveText {
+      self.detach_text_device();
+    }
+    Ok(transition)
+  }
+
+  /// While a graphics mode is active, text output goes to the backup page
+  fn detach_text_device(&mut self) {
+    if !self.active {
+      // Inactive vterms already write text to their backup page
+      return;
+    }
+    if let Some(backup) = self.memory_backups[TEXT_BACKUP_INDEX] {
+      unsafe {
+        backup.copy_to_buffer();
+      }
+      self.text_mode_state.set_buffer_pointer(backup.mapped_to.as_usize());
+    }
+    set_hardware_cursor_visible(false);
+  }
+
+  /// Once the VGA card is back in text mode, copy the saved text back to
+  /// video memory and resume writing to it directly
+  pub fn restore_text_device(&mut self) {
+    if !self.active || !self.video_mode.is_text() {
+      return;
+    }
+    if let Some(backup) = self.memory_backups[TEXT_BACKUP_INDEX] {
+      unsafe {
+        backup.copy_from_buffer();
+      }
+    }
+    self.text_mode_state.set_buffer_pointer(0xc00b8000);
+    set_hardware_cursor_visible(true);
+    self.sync_hardware_cursor();
   }
 
   /// Scroll the text mode up by a specified number of rows
@@ -220,11 +415,14 @@ impl VTerm {
     self.text_mode_state.scroll(delta as u8);
   }
 
+  /// DOS programs write code page bytes rather than UTF-8
   pub fn enter_dos_mode(&mut self) {
     self.dos_mode_flag = true;
+    self.ansi_parser.set_utf8(false);
   }
 
   pub fn exit_dos_mode(&mut self) {
     self.dos_mode_flag = false;
+    self.ansi_parser.set_utf8(true);
   }
 }
\ No newline at end of file
@@ -10,6 +10,10 @@ impl Port {
     }
   }
 
+  pub fn get_number(&self) -> u16 {
+    self.number
+  }
+
   pub unsafe fn write_u8(&self, value: u8) {
     outb(self.number, value);
   }
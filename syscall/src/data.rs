@@ -20,4 +20,23 @@ impl StringPtr {
     let bytes = core::slice::from_raw_parts(self.get_starting_ptr(), self.length);
     core::str::from_utf8_unchecked(bytes)
   }
-}
\ No newline at end of file
+}
+
+/// The soft and hard values of a resource limit, used by `get_resource_limit`
+/// and `set_resource_limit`
+#[repr(C, packed)]
+pub struct ResourceLimit {
+  /// The value that is enforced
+  pub current: u32,
+  /// The highest value `current` can be raised to
+  pub maximum: u32,
+}
+
+impl ResourceLimit {
+  pub fn empty() -> Self {
+    Self {
+      current: 0,
+      maximum: 0,
+    }
+  }
+}
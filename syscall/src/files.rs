@@ -43,3 +43,114 @@ impl FileStatus {
     }
   }
 }
+
+/// Drive categories reported by `list_drives`
+pub const DRIVE_CATEGORY_KERNEL_SYNC: u8 = 0;
+pub const DRIVE_CATEGORY_KERNEL_ASYNC: u8 = 1;
+pub const DRIVE_CATEGORY_USERSPACE: u8 = 2;
+
+/// Longest drive or device name reported by the listing syscalls
+pub const LISTED_NAME_LENGTH: usize = 8;
+
+/// Describes a mounted drive, as returned by `list_drives`
+#[repr(C, packed)]
+pub struct DriveInfo {
+  pub name: [u8; LISTED_NAME_LENGTH],
+  pub name_length: u8,
+  pub category: u8,
+}
+
+impl DriveInfo {
+  pub fn empty() -> DriveInfo {
+    DriveInfo {
+      name: [0; LISTED_NAME_LENGTH],
+      name_length: 0,
+      category: 0,
+    }
+  }
+
+  pub fn name_bytes(&self) -> &[u8] {
+    &self.name[..(self.name_length as usize).min(LISTED_NAME_LENGTH)]
+  }
+}
+
+/// Describes an installed device, as returned by `list_devices`
+#[repr(C, packed)]
+pub struct DeviceInfo {
+  pub name: [u8; LISTED_NAME_LENGTH],
+  pub name_length: u8,
+}
+
+impl DeviceInfo {
+  pub fn empty() -> DeviceInfo {
+    DeviceInfo {
+      name: [0; LISTED_NAME_LENGTH],
+      name_length: 0,
+    }
+  }
+
+  pub fn name_bytes(&self) -> &[u8] {
+    &self.name[..(self.name_length as usize).min(LISTED_NAME_LENGTH)]
+  }
+}
+
+/// Block cache counters, as returned by `cache_stats`. Each counter wraps
+/// around rather than saturating.
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct CacheStats {
+  /// Sector lookups that were served from memory
+  pub hits: u32,
+  /// Sector lookups that had to go to the disk
+  pub misses: u32,
+  /// Sectors removed from a full cache to make room for another
+  pub evictions: u32,
+  /// Sectors written through to the disk
+  pub write_backs: u32,
+}
+
+impl CacheStats {
+  pub const fn empty() -> CacheStats {
+    CacheStats {
+      hits: 0,
+      misses: 0,
+      evictions: 0,
+      write_backs: 0,
+    }
+  }
+}
+
+/// Copy a name into a fixed-size listing field, truncating it if necessary.
+/// Returns the number of bytes copied.
+pub fn copy_listed_name(name: &str, dest: &mut [u8; LISTED_NAME_LENGTH]) -> u8 {
+  let length = name.len().min(LISTED_NAME_LENGTH);
+  dest[..length].copy_from_slice(&name.as_bytes()[..length]);
+  for byte in dest[length..].iter_mut() {
+    *byte = 0;
+  }
+  length as u8
+}
+
+/// Describes the graphics mode of DEV:\FB0
+#[repr(C, packed)]
+pub struct FramebufferInfo {
+  pub width: u32,
+  pub height: u32,
+  pub bits_per_pixel: u32,
+  /// Bytes from the start of one row to the start of the next
+  pub pitch: u32,
+  /// Bytes mapped by FBIOMAP
+  pub size: u32,
+}
+
+impl FramebufferInfo {
+  pub fn empty() -> Self {
+    Self {
+      width: 0,
+      height: 0,
+      bits_per_pixel: 0,
+      pitch: 0,
+      size: 0,
+    }
+  }
+}
@@ -1 +1,204 @@
-pub const FIONREAD: u32 = 0x400419ff;
\ No newline at end of file
+pub const FIONREAD: u32 = 0x400419ff;
+/// ioctl: write the state of the modem lines, as TIOCM_* bits, to a u32
+pub const TIOCMGET: u32 = 0x5415;
+/// ioctl: set the DTR and RTS modem lines from TIOCM_* bits read from a u32
+pub const TIOCMSET: u32 = 0x5418;
+/// ioctl: write the serial line status to a u32, clearing any pending
+/// LSR_* error bits
+pub const TIOCSERGETLSR: u32 = 0x5459;
+/// ioctl: copy everything written to a TTY into another open file handle,
+/// passed as the argument. The handle can be a file, a serial port, or another
+/// device. If writing to it fails, the log is detached.
+pub const TIOCSLOG: u32 = 0x54a0;
+/// ioctl: stop copying TTY output to a log handle
+pub const TIOCCLOG: u32 = 0x54a1;
+/// ioctl: write the number of received bytes a serial port has dropped since
+/// the last call to a u32, then reset the count. Bytes are dropped when the
+/// port's receive buffer fills up before a program reads it.
+pub const TIOCSERGETOVERRUN: u32 = 0x54a2;
+/// ioctl: set how many bytes (1, 4, 8, or 14) a serial port's hardware FIFO
+/// collects before raising an interrupt. Changing it clears the FIFO.
+pub const TIOCSERSETTRIGGER: u32 = 0x54a3;
+/// ioctl: set how many previously entered lines a TTY remembers for recall
+/// with the Up and Down arrow keys while reading a line. Zero disables the
+/// history. Returns the previous depth.
+pub const TIOCSHISTORY: u32 = 0x54a4;
+/// ioctl: choose what the high bit of a text attribute does on the VGA
+/// console. A non-zero argument turns it into a bright background, allowing
+/// all 16 background colors; zero makes the text blink instead. Returns 1 if
+/// bright backgrounds were previously enabled.
+pub const TIOCSBRIGHTBG: u32 = 0x54a5;
+/// ioctl: change one of the 16 VGA console text colors. The argument is
+/// 0xIIRRGGBB: the color index, then 8-bit red, green, and blue components,
+/// of which the hardware keeps the top 6 bits. Returns the previous color in
+/// the same format.
+pub const TIOCSPALETTE: u32 = 0x54a6;
+/// ioctl: change the shape of the VGA console's text cursor, shared by every
+/// TTY. The argument holds the last scan line the cursor covers in the low
+/// byte and the first one above it, counted from the top of the character
+/// cell. Lines below the cell are clamped to it. Setting CURSOR_HIDDEN hides
+/// the cursor until it is cleared again. Returns the previous shape in the
+/// same format.
+pub const TIOCSCURSOR: u32 = 0x54a7;
+/// ioctl: return the current cursor shape, in the format of TIOCSCURSOR
+pub const TIOCGCURSOR: u32 = 0x54a8;
+/// ioctl: return the FAT_ATTR_* bits of a file on a FAT drive
+pub const FAT_IOCTL_GET_ATTRIBUTES: u32 = 0x80047210;
+/// ioctl: replace the read-only, hidden, system, and archive bits of a file
+/// on a FAT drive with the argument, returning the previous bits. Including
+/// any other bit is an error.
+pub const FAT_IOCTL_SET_ATTRIBUTES: u32 = 0x40047211;
+/// ioctl on DEV:\KMSG: set the most detailed LOG_LEVEL_* the kernel prints,
+/// returning the previous level
+pub const KLOG_SET_LEVEL: u32 = 0x4b01;
+/// ioctl on DEV:\KMSG: set which LOG_CATEGORY_* bits the kernel prints
+/// messages for, returning the previous bits. Errors are printed regardless.
+pub const KLOG_SET_CATEGORIES: u32 = 0x4b02;
+
+/// Kernel log levels, from least to most detailed
+pub const LOG_LEVEL_ERROR: u32 = 0;
+pub const LOG_LEVEL_WARN: u32 = 1;
+pub const LOG_LEVEL_INFO: u32 = 2;
+pub const LOG_LEVEL_DEBUG: u32 = 3;
+
+/// Kernel log categories
+pub const LOG_CATEGORY_GENERAL: u32 = 0x01;
+pub const LOG_CATEGORY_MM: u32 = 0x02;
+pub const LOG_CATEGORY_SCHED: u32 = 0x04;
+pub const LOG_CATEGORY_FS: u32 = 0x08;
+pub const LOG_CATEGORY_DRV: u32 = 0x10;
+pub const LOG_CATEGORY_ALL: u32 = 0x1f;
+/// ioctl on DEV:\FB0: write the current mode to a FramebufferInfo
+pub const FBIOGET_INFO: u32 = 0x4600;
+/// ioctl on DEV:\FB0: switch to the mode closest to the width, height, and
+/// bits_per_pixel of a FramebufferInfo, then fill in the rest of it. Without
+/// VESA support, this falls back to 320x200 with 8 bits per pixel.
+pub const FBIOSET_MODE: u32 = 0x4601;
+/// ioctl on DEV:\FB0: map the framebuffer into the caller's memory,
+/// returning its address
+pub const FBIOMAP: u32 = 0x4602;
+
+/// Attribute bits of a FAT directory entry
+pub const FAT_ATTR_READ_ONLY: u8 = 0x01;
+pub const FAT_ATTR_HIDDEN: u8 = 0x02;
+pub const FAT_ATTR_SYSTEM: u8 = 0x04;
+pub const FAT_ATTR_VOLUME: u8 = 0x08;
+pub const FAT_ATTR_DIRECTORY: u8 = 0x10;
+/// Set by the filesystem whenever a file changes, and cleared by backup
+/// programs once they have copied it
+pub const FAT_ATTR_ARCHIVE: u8 = 0x20;
+
+/// Hides the console cursor, in the shape passed to TIOCSCURSOR
+pub const CURSOR_HIDDEN: u32 = 0x10000;
+
+/// Modem lines reported by TIOCMGET. Only DTR and RTS can be set.
+pub const TIOCM_DTR: u32 = 0x002;
+pub const TIOCM_RTS: u32 = 0x004;
+pub const TIOCM_CTS: u32 = 0x020;
+pub const TIOCM_CD: u32 = 0x040;
+pub const TIOCM_RI: u32 = 0x080;
+pub const TIOCM_DSR: u32 = 0x100;
+
+/// Line status bits reported by TIOCSERGETLSR. The error bits remain set
+/// until they have been reported once.
+pub const LSR_DATA_READY: u32 = 0x01;
+pub const LSR_OVERRUN_ERROR: u32 = 0x02;
+pub const LSR_PARITY_ERROR: u32 = 0x04;
+pub const LSR_FRAME_ERROR: u32 = 0x08;
+pub const LSR_BREAK: u32 = 0x10;
+/// Open flag: create the file if it does not exist
+pub const O_CREAT: u32 = 0x40;
+/// Open flag: with O_CREAT, fail with `AlreadyExists` instead of opening a
+/// file that is already there. The check and the creation are a single step,
+/// so of several processes creating the same path, exactly one succeeds.
+pub const O_EXCL: u32 = 0x80;
+/// Open flag: reads and writes that would block return `WouldBlock` instead
+pub const O_NONBLOCK: u32 = 0x800;
+/// dup3 flag: close the new handle on exec, like setting FD_CLOEXEC
+pub const O_CLOEXEC: u32 = 0x80000;
+
+/// access mode: only check that the path exists
+pub const F_OK: u32 = 0;
+/// access mode: check that the file can be written
+pub const W_OK: u32 = 2;
+/// access mode: check that the file can be read
+pub const R_OK: u32 = 4;
+
+/// fcntl command: get the descriptor flags (FD_*) of an open handle
+pub const F_GETFD: u32 = 1;
+/// fcntl command: replace the descriptor flags (FD_*) of an open handle
+pub const F_SETFD: u32 = 2;
+/// fcntl command: get the status flags of an open handle
+pub const F_GETFL: u32 = 3;
+/// fcntl command: replace the status flags of an open handle
+pub const F_SETFL: u32 = 4;
+/// fcntl command: add F_SEAL_* seals to a file, returning all of its seals
+pub const F_ADD_SEALS: u32 = 1033;
+/// fcntl command: get the F_SEAL_* seals of a file
+pub const F_GET_SEALS: u32 = 1034;
+
+/// File seal: the file can't be made shorter
+pub const F_SEAL_SHRINK: u32 = 2;
+/// File seal: the file can't be made longer
+pub const F_SEAL_GROW: u32 = 4;
+/// File seal: the file's contents can't be written. Files with this seal can
+/// be mapped read-only with `map_sealed`.
+pub const F_SEAL_WRITE: u32 = 8;
+
+/// Descriptor flag: close the handle on exec, even if it is stdin, stdout, or
+/// stderr
+pub const FD_CLOEXEC: u32 = 1;
+/// Descriptor flag: keep the handle open across exec. Only handles 0, 1, and 2
+/// survive exec by default; anything else needs this flag to be inherited.
+pub const FD_KEEPEXEC: u32 = 2;
+
+/// unmount flag: close any files still open on the drive instead of failing
+pub const UNMOUNT_FORCE: u32 = 1;
+
+/// rename flag: if the destination is an existing file, replace it instead of
+/// failing
+pub const RENAME_REPLACE: u32 = 1;
+
+/// flock operations. Exactly one of LOCK_SH, LOCK_EX, or LOCK_UN must be set;
+/// LOCK_NB can be added to fail instead of waiting for a lock.
+pub const LOCK_SH: u32 = 1;
+pub const LOCK_EX: u32 = 2;
+pub const LOCK_NB: u32 = 4;
+pub const LOCK_UN: u32 = 8;
+
+/// timerfd flag: expire only once, instead of after every interval
+pub const TFD_ONESHOT: u32 = 1;
+
+/// wait option: also report children that have been stopped by a signal
+pub const WUNTRACED: u32 = 2;
+/// wait option: also report stopped children that have been continued
+pub const WCONTINUED: u32 = 8;
+/// wait option: report a child without collecting it, so that a later wait
+/// reports it again
+pub const WNOWAIT: u32 = 0x01000000;
+
+/// waitid id type: wait for any child, ignoring the id
+pub const P_ALL: u32 = 0;
+/// waitid id type: wait for the child with this pid
+pub const P_PID: u32 = 1;
+/// waitid id type: wait for any child in this process group
+pub const P_PGID: u32 = 2;
+
+/// IPC page flag: share the pages, so that the sender and receiver both see
+/// the same memory. This is the default.
+pub const IPC_PAGES_SHARE: u32 = 0;
+/// IPC page flag: move the pages, removing them from the sender's memory
+pub const IPC_PAGES_MOVE: u32 = 1;
+
+/// Keyboard layouts accepted by `set_keyboard_layout`
+pub const KBD_LAYOUT_US: u32 = 0;
+pub const KBD_LAYOUT_UK: u32 = 1;
+pub const KBD_LAYOUT_DE: u32 = 2;
+
+/// Resources that can be limited with `set_resource_limit`: bytes of heap,
+/// bytes of user stack, and the number of open file handles
+pub const RLIMIT_DATA: u32 = 2;
+pub const RLIMIT_STACK: u32 = 3;
+pub const RLIMIT_NOFILE: u32 = 7;
+/// A resource limit that is never reached
+pub const RLIM_INFINITY: u32 = 0xffffffff;
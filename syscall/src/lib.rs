@@ -29,8 +29,12 @@ pub fn debug() -> u32 {
 }
 
 pub fn open(path: &'static str) -> u32 {
+  open_with_flags(path, 0)
+}
+
+pub fn open_with_flags(path: &'static str, flags: u32) -> u32 {
   let path_ptr = StringPtr::from_str(path);
-  syscall_inner(0x10, &path_ptr as *const StringPtr as u32, 0, 0)
+  syscall_inner(0x10, &path_ptr as *const StringPtr as u32, flags, 0)
 }
 
 pub fn read(handle: u32, buffer: *mut u8, length: usize) -> usize {
@@ -45,6 +49,45 @@ pub fn write_str(handle: u32, str: &str) -> usize {
   write(handle, str.as_ptr(), str.len())
 }
 
+/// Flush and remove a mounted drive. Fails if files are still open on it,
+/// unless `flags::UNMOUNT_FORCE` is set.
+pub fn unmount(drive_name: &'static str, flags: u32) -> u32 {
+  let name_ptr = StringPtr::from_str(drive_name);
+  syscall_inner(0x33, &name_ptr as *const StringPtr as u32, flags, 0)
+}
+
+/// Describe up to `count` mounted drives in the buffer. Returns the total
+/// number of drives; if that is larger than `count`, call again with a larger
+/// buffer to see them all.
+pub fn list_drives(buffer: *mut files::DriveInfo, count: usize) -> u32 {
+  syscall_inner(0x34, buffer as u32, count as u32, 0)
+}
+
+/// Describe up to `count` installed devices in the buffer, returning the total
+/// number of devices
+pub fn list_devices(buffer: *mut files::DeviceInfo, count: usize) -> u32 {
+  syscall_inner(0x35, buffer as u32, count as u32, 0)
+}
+
+/// Copy the block cache counters into `stats`. If `reset` is true, the
+/// counters start again from zero afterwards.
+pub fn cache_stats(stats: *mut files::CacheStats, reset: bool) -> u32 {
+  syscall_inner(0x36, stats as u32, reset as u32, 0)
+}
+
+/// Empty the block caches, so that following reads come from the disks. Only
+/// privileged processes are allowed to do this.
+pub fn drop_caches() -> u32 {
+  syscall_inner(0x37, 0, 0, 0)
+}
+
+/// Copy up to `count` bytes from `in_handle` to `out_handle` inside the
+/// kernel, starting at each handle's cursor. Returns the number of bytes
+/// copied, which is less than `count` once the input runs out.
+pub fn sendfile(out_handle: u32, in_handle: u32, count: usize) -> usize {
+  syscall_inner(0x38, out_handle, in_handle, count as u32) as usize
+}
+
 pub fn open_dir(path: &'static str) -> u32 {
   let path_ptr = StringPtr::from_str(path);
   syscall_inner(0x1a, &path_ptr as *const StringPtr as u32, 0, 0)
@@ -62,10 +105,209 @@ pub fn dup2(handle: u32, replace: u32) -> u32 {
   syscall_inner(0x1d, handle, replace, 0)
 }
 
+/// Like dup2, but fails with InvalidArgument if both handles are the same.
+/// Passing O_CLOEXEC in `flags` marks the new handle to be closed on exec.
+pub fn dup3(handle: u32, replace: u32, flags: u32) -> u32 {
+  syscall_inner(0x2c, handle, replace, flags)
+}
+
+/// Check whether a handle refers to a terminal rather than a file, pipe, or
+/// other device. Fails with BadFileDescriptor if the handle isn't open.
+pub fn isatty(handle: u32) -> Result<bool, result::SystemError> {
+  result::result_from_code(syscall_inner(0x2d, handle, 0, 0)).map(|value| value != 0)
+}
+
+/// Place or remove an advisory lock on an open file, using the LOCK_* flags.
+/// The lock belongs to the open file, so it is shared with duplicated and
+/// inherited handles, and released once all of them are closed.
+pub fn flock(handle: u32, operation: u32) -> Result<(), result::SystemError> {
+  result::result_from_code(syscall_inner(0x2e, handle, operation, 0)).map(|_| ())
+}
+
 pub fn ioctl(handle: u32, command: u32, arg: u32) -> u32 {
   syscall_inner(0x1e, handle, command, arg)
 }
 
+pub fn fcntl(handle: u32, command: u32, arg: u32) -> u32 {
+  syscall_inner(0x26, handle, command, arg)
+}
+
+/// Check whether a file exists and can be accessed without opening it. `mode`
+/// is F_OK, or a combination of R_OK and W_OK from `flags`. Returns 0 on
+/// success, or an error code like NoSuchEntity or PermissionDenied.
+pub fn access(path: &'static str, mode: u32) -> u32 {
+  let path_ptr = StringPtr::from_str(path);
+  syscall_inner(0x27, &path_ptr as *const StringPtr as u32, mode, 0)
+}
+
+/// Delete a file. Programs that already have the file open can keep reading
+/// it until they close it.
+pub fn unlink(path: &'static str) -> u32 {
+  let path_ptr = StringPtr::from_str(path);
+  syscall_inner(0x14, &path_ptr as *const StringPtr as u32, 0, 0)
+}
+
+/// Change the working directory on the drive named by `path`. The current
+/// drive stays the same.
+pub fn chdir(path: &'static str) -> u32 {
+  let path_ptr = StringPtr::from_str(path);
+  syscall_inner(0x24, &path_ptr as *const StringPtr as u32, 0, 0)
+}
+
+/// Change the working directory to a directory that is already open, on
+/// whichever drive it belongs to. The current drive stays the same. Fails with
+/// NotDirectory if the handle is not a directory, or NoSuchEntity if the
+/// directory was removed after it was opened.
+pub fn fchdir(handle: u32) -> u32 {
+  syscall_inner(0x2a, handle, 0, 0)
+}
+
+/// Read entries from an open directory until one matches a DOS wildcard
+/// pattern like `*.COM`, and copy it to `info`. Returns 1 if the directory has
+/// more entries to search. If nothing else matches, `info` is left empty.
+pub fn find_dir_entry(handle: u32, pattern: &'static str, info: *mut files::DirEntryInfo) -> u32 {
+  let pattern_ptr = StringPtr::from_str(pattern);
+  syscall_inner(0x2b, handle, &pattern_ptr as *const StringPtr as u32, info as u32)
+}
+
+/// Copy the working directory for a drive number into `buffer`, returning the
+/// full length of the path. The path is relative to the drive's root, so the
+/// root itself has a length of 0.
+pub fn getcwd(drive: u32, buffer: &mut [u8]) -> u32 {
+  syscall_inner(0x25, drive, buffer.as_mut_ptr() as u32, buffer.len() as u32)
+}
+
+/// Move or rename a file or directory. Both paths must be on the same drive.
+/// If the destination exists, the call fails with AlreadyExists unless
+/// `flags` contains RENAME_REPLACE.
+pub fn rename(old_path: &'static str, new_path: &'static str, flags: u32) -> u32 {
+  let old_ptr = StringPtr::from_str(old_path);
+  let new_ptr = StringPtr::from_str(new_path);
+  syscall_inner(0x28, &old_ptr as *const StringPtr as u32, &new_ptr as *const StringPtr as u32, flags)
+}
+
+/// Create an unnamed scratch file on the TMP: drive. There is no path to clean
+/// up: the file is deleted when its last handle is closed. Like other files,
+/// the handle only survives exec if it is given `flags::FD_KEEPEXEC`.
+pub fn tmpfile() -> u32 {
+  tmpfile_with_flags(0)
+}
+
+/// Create a scratch file with a set of FD_* descriptor flags
+pub fn tmpfile_with_flags(descriptor_flags: u32) -> u32 {
+  syscall_inner(0x29, descriptor_flags, 0, 0)
+}
+
+/// Truncate or extend an open file to `size` bytes
+pub fn ftruncate(handle: u32, size: u32) -> u32 {
+  syscall_inner(0x39, handle, size, 0)
+}
+
+/// Map a scratch file into memory, read-only. The file must already be
+/// sealed with `flags::F_SEAL_WRITE` through fcntl, so the mapping can never
+/// disagree with it. This makes it safe to hand data to another process: send
+/// it the handle, and it can map the contents knowing they won't change.
+/// Returns the address of the mapping, which is released with `munmap`.
+pub fn map_sealed(handle: u32) -> u32 {
+  syscall_inner(0x3a, handle, 0, 0)
+}
+
+/// Create a timer handle that becomes readable each time `interval_ms`
+/// elapses. Reading it returns the number of expirations since the last read,
+/// as a u32. With `flags::TFD_ONESHOT`, the timer only expires once, and
+/// reads after that expiration return 0 bytes instead of blocking.
+pub fn timerfd(interval_ms: u32, flags: u32) -> u32 {
+  syscall_inner(0x2f, interval_ms, flags, 0)
+}
+
+/// Run `handler` whenever a hardware IRQ fires, on the stack that ends at
+/// `stack_top`. The handler finishes by returning normally. If other drivers
+/// already hooked the same IRQ line, handlers run one after another in the
+/// order they were installed, so each one should check whether its own device
+/// raised the interrupt. Installing again replaces this process's handler.
+/// DOS programs can't hook interrupts, and the timer and keyboard IRQs
+/// belong to the kernel.
+pub fn install_irq_handler(irq: u32, handler: extern "C" fn(), stack_top: u32) -> Result<(), result::SystemError> {
+  result::result_from_code(syscall_inner(0x40, irq, handler as u32, stack_top)).map(|_| ())
+}
+
+/// Stop handling an IRQ. Handlers that other processes installed on the same
+/// line keep running.
+pub fn remove_irq_handler(irq: u32) -> Result<(), result::SystemError> {
+  result::result_from_code(syscall_inner(0x40, irq, 0, 0)).map(|_| ())
+}
+
+/// Claim `count` I/O ports starting at `start`, so that this process can
+/// access them through the port functions below. Grants last until the
+/// process exits or execs, and aren't passed on to children. DOS programs
+/// can't claim ports.
+pub fn grant_io_ports(start: u32, count: u32) -> Result<(), result::SystemError> {
+  result::result_from_code(syscall_inner(0x41, start, count, 0)).map(|_| ())
+}
+
+/// Stop every process and power off the system. Processes are sent TERM, and
+/// any that are still running after a grace period are killed. Once they
+/// have all exited, every drive is flushed and unmounted. If the machine
+/// can't be powered off, it is halted instead. Only fails if the caller isn't
+/// allowed to shut down, like a DOS program.
+pub fn shutdown() -> Result<(), result::SystemError> {
+  result::result_from_code(syscall_inner(0x44, 0, 0, 0)).map(|_| ())
+}
+
+fn port_read(port: u16, width: u32) -> Result<u32, result::SystemError> {
+  let mut value: u32 = 0;
+  result::result_from_code(syscall_inner(0x42, port as u32, width, &mut value as *mut u32 as u32))?;
+  Ok(value)
+}
+
+fn port_write(port: u16, width: u32, value: u32) -> Result<(), result::SystemError> {
+  result::result_from_code(syscall_inner(0x43, port as u32, width, value)).map(|_| ())
+}
+
+/// Read a byte from a granted I/O port. Ports that haven't been granted fail
+/// with PermissionDenied.
+pub fn inb(port: u16) -> Result<u8, result::SystemError> {
+  port_read(port, 1).map(|value| value as u8)
+}
+
+pub fn inw(port: u16) -> Result<u16, result::SystemError> {
+  port_read(port, 2).map(|value| value as u16)
+}
+
+pub fn inl(port: u16) -> Result<u32, result::SystemError> {
+  port_read(port, 4)
+}
+
+/// Write a byte to a granted I/O port
+pub fn outb(port: u16, value: u8) -> Result<(), result::SystemError> {
+  port_write(port, 1, value as u32)
+}
+
+pub fn outw(port: u16, value: u16) -> Result<(), result::SystemError> {
+  port_write(port, 2, value as u32)
+}
+
+pub fn outl(port: u16, value: u32) -> Result<(), result::SystemError> {
+  port_write(port, 4, value)
+}
+
+/// Set the video mode of the calling process's vterm. Only known modes, like
+/// 0x03 for text and 0x13 for 256-color graphics, are accepted.
+pub fn set_video_mode(mode: u8) -> u32 {
+  syscall_inner(0x50, mode as u32, 0, 0)
+}
+
+/// Get the current video mode of the calling process's vterm
+pub fn get_video_mode() -> u32 {
+  syscall_inner(0x52, 0, 0, 0)
+}
+
+/// Select the keyboard layout used to translate key presses, using one of the
+/// `flags::KBD_LAYOUT_*` values
+pub fn set_keyboard_layout(layout: u32) -> u32 {
+  syscall_inner(0x51, layout, 0, 0)
+}
+
 pub fn pipe(handles: &[u32; 2]) -> u32 {
   syscall_inner(0x1f, &handles[0] as *const u32 as u32, &handles[1] as *const u32 as u32, 0)
 }
@@ -78,10 +320,71 @@ pub fn seek_relative(handle: u32, offset: i32) -> u32 {
   syscall_inner(0x20, handle, 1, offset as u32)
 }
 
+/// Send a message of four values to another process
+pub fn ipc_send(to: u32, message: &[u32; 4]) -> u32 {
+  syscall_inner(0x60, to, message.as_ptr() as u32, 0)
+}
+
+/// Send a message along with a copy of an open file handle. The sender can
+/// close its own handle right away. The receiver gets a new handle to the same
+/// file, and finds its number in the last value of the message.
+pub fn ipc_send_handle(to: u32, message: &[u32; 4], handle: u32) -> u32 {
+  syscall_inner(0x61, to, message.as_ptr() as u32, handle)
+}
+
+/// Send a message along with a range of pages, without copying their
+/// contents. The range starts at the page-aligned `address` and is
+/// `page_count` pages long; these replace the last two values of the message.
+/// With `flags::IPC_PAGES_MOVE` the pages are removed from the caller's
+/// memory, otherwise the caller and receiver share them. The receiver finds
+/// the address where the pages were mapped in the third value of the message.
+pub fn ipc_send_pages(to: u32, message: &[u32; 2], address: u32, page_count: u32, flags: u32) -> u32 {
+  let full_message = [message[0], message[1], address, page_count];
+  syscall_inner(0x63, to, full_message.as_ptr() as u32, flags)
+}
+
+/// Block until a message arrives, returning the ID of the sender. If the
+/// process that was last sent a message exits before replying, the read fails
+/// with RecipientGone rather than blocking forever.
+pub fn ipc_read(message: &mut [u32; 4]) -> u32 {
+  syscall_inner(0x62, message.as_mut_ptr() as u32, 0, 0)
+}
+
+/// Claim a named port, so that other processes can send messages to this one
+/// without knowing its ID. Fails with AlreadyExists if another running
+/// process holds the name. The port is released when the process exits.
+pub fn register_port(name: &str) -> u32 {
+  let name_ptr = StringPtr::from_str(name);
+  syscall_inner(0x64, &name_ptr as *const StringPtr as u32, 0, 0)
+}
+
+/// Send a message to the process that registered a port. Fails with
+/// RecipientGone if that process has exited, or WouldBlock if the port's
+/// queue is full.
+pub fn send_to_port(name: &str, message: &[u32; 4]) -> u32 {
+  let name_ptr = StringPtr::from_str(name);
+  syscall_inner(0x65, &name_ptr as *const StringPtr as u32, message.as_ptr() as u32, 0)
+}
+
+/// Block until a message arrives on a port registered by this process,
+/// returning the ID of the sender
+pub fn recv_from_port(name: &str, message: &mut [u32; 4]) -> u32 {
+  let name_ptr = StringPtr::from_str(name);
+  syscall_inner(0x66, &name_ptr as *const StringPtr as u32, message.as_mut_ptr() as u32, 0)
+}
+
 pub fn fork() -> u32 {
   syscall_inner(0x01, 0, 0, 0)
 }
 
+/// Create a child process that shares the caller's address space, suspending
+/// the caller until the child calls `exec` or `exit`. The child runs on the
+/// parent's stack, so it must not return from the function that called `vfork`
+/// or modify any state besides preparing for `exec`.
+pub fn vfork() -> u32 {
+  syscall_inner(0x07, 0, 0, 0)
+}
+
 pub fn exec(path: &'static str) {
   let path_ptr = StringPtr::from_str(path);
   syscall_inner(0x02, &path_ptr as *const StringPtr as u32, 0, 0);
@@ -98,22 +401,145 @@ pub fn exec_format(path: &'static str, format: u32) {
   syscall_inner(0x02, &path_ptr as *const StringPtr as u32, 0, format);
 }
 
+/// Copy the full path of the current executable into a buffer, returning its
+/// length. If the buffer is too small, nothing is copied and the returned
+/// length can be used to size a new buffer.
+pub fn self_exe(buffer: *mut u8, length: usize) -> u32 {
+  syscall_inner(0x0a, buffer as u32, length as u32, 0)
+}
+
+/// Copy the current program's arguments into a buffer, starting with the path
+/// it was run as. Each argument is followed by a NUL byte. Returns the number
+/// of arguments and the number of bytes needed to hold them; if the buffer is
+/// too small, nothing is copied and the size can be used to retry.
+pub fn get_args(buffer: &mut [u8]) -> (u32, u32) {
+  let mut required = 0;
+  let count = syscall_inner(0x74, buffer.as_mut_ptr() as u32, buffer.len() as u32, &mut required as *mut u32 as u32);
+  (count, required)
+}
+
+/// Copy a listing of a process's memory regions into a buffer, like
+/// /proc/pid/maps, for debugging. A pid of 0 means the current process. Each
+/// line has an address range, permissions, the kind of region, and how many
+/// of its pages are backed by physical memory. Returns the size of the full
+/// listing; if the buffer is too small, nothing is copied and the size can be
+/// used to retry. Unprivileged processes can only list themselves and their
+/// children.
+pub fn memory_map(pid: u32, buffer: &mut [u8]) -> Result<u32, result::SystemError> {
+  result::result_from_code(syscall_inner(0x76, pid, buffer.as_mut_ptr() as u32, buffer.len() as u32))
+}
+
+/// Set an environment variable for the current process. Child processes
+/// inherit a copy of the environment.
+pub fn setenv(name: &str, value: &str) -> u32 {
+  let name_ptr = StringPtr::from_str(name);
+  let value_ptr = StringPtr::from_str(value);
+  syscall_inner(0x0b, &name_ptr as *const StringPtr as u32, &value_ptr as *const StringPtr as u32, 0)
+}
+
+/// Copy the value of an environment variable into a buffer, returning its
+/// length. Like `self_exe`, nothing is copied if the buffer is too small.
+pub fn getenv(name: &str, buffer: *mut u8, length: usize) -> u32 {
+  let name_ptr = StringPtr::from_str(name);
+  syscall_inner(0x0c, &name_ptr as *const StringPtr as u32, buffer as u32, length as u32)
+}
+
+pub fn unsetenv(name: &str) -> u32 {
+  let name_ptr = StringPtr::from_str(name);
+  syscall_inner(0x0d, &name_ptr as *const StringPtr as u32, 0, 0)
+}
+
+/// Move the end of the heap to an absolute address, returning the new end.
+/// New heap pages are zero-filled the first time they are touched.
 pub fn brk(addr: u32) -> u32 {
   syscall_inner(0x04, 0, addr, 0)
 }
 
+/// Grow or shrink the heap, returning the previous end. Memory released by a
+/// negative delta is returned to the system, and reads as zero if the heap
+/// grows over it again.
 pub fn sbrk(delta: i32) -> u32 {
   syscall_inner(0x04, 1, delta as u32, 0)
 }
 
+/// Unmap `length` bytes of memory starting at a page-aligned address. Pages in
+/// the range that were never mapped are ignored.
+pub fn munmap(addr: u32, length: u32) -> u32 {
+  syscall_inner(0x0e, addr, length, 0)
+}
+
+/// Lock `length` bytes of memory starting at `addr` into RAM. Every page in
+/// the range is loaded immediately, and stays resident so that accessing it
+/// never causes a page fault. Fails with InvalidArgument if any part of the
+/// range isn't mapped.
+pub fn mlock(addr: u32, length: u32) -> u32 {
+  syscall_inner(0x0f, addr, length, 1)
+}
+
+/// Undo `mlock` for a range of memory
+pub fn munlock(addr: u32, length: u32) -> u32 {
+  syscall_inner(0x0f, addr, length, 0)
+}
+
 pub fn yield_coop() {
   syscall_inner(0x06, 0, 0, 0);
 }
 
+/// Give up the CPU to a specific process, like a consumer waiting on data
+/// that was just produced. If that process is blocked or doesn't exist, this
+/// is the same as yield_coop. Only the immediate switch is affected.
+pub fn yield_to(pid: u32) {
+  syscall_inner(0x77, pid, 0, 0);
+}
+
+/// Change the scheduling priority of the current process by a relative
+/// amount, returning the new nice value. Values range from -20 to 19, and
+/// lower values get longer time slices. Only privileged processes can lower
+/// theirs.
+pub fn nice(increment: i32) -> Result<i32, result::SystemError> {
+  result::result_from_code(syscall_inner(0x78, increment as u32, 0, 0)).map(|offset| offset as i32 - 20)
+}
+
+/// Read the current limit on a resource, one of the RLIMIT_* flags
+pub fn get_resource_limit(resource: u32) -> Result<data::ResourceLimit, result::SystemError> {
+  let mut limit = data::ResourceLimit::empty();
+  let code = syscall_inner(0x79, resource, &mut limit as *mut data::ResourceLimit as u32, 0);
+  result::result_from_code(code).map(|_| limit)
+}
+
+/// Change the limit on a resource. Limits can always be lowered, and the
+/// current value can be raised up to the maximum. Raising the maximum is only
+/// allowed for privileged processes.
+pub fn set_resource_limit(resource: u32, limit: &data::ResourceLimit) -> Result<(), result::SystemError> {
+  let code = syscall_inner(0x7a, resource, limit as *const data::ResourceLimit as u32, 0);
+  result::result_from_code(code).map(|_| ())
+}
+
+/// Permanently give up the privileges inherited from init, like unmounting
+/// drives or raising hard limits. Children forked afterwards are unprivileged
+/// too.
+pub fn drop_privileges() {
+  syscall_inner(0x7b, 0, 0, 0);
+}
+
+/// The number of file handles the current process can have open at once
+pub fn getdtablesize() -> u32 {
+  match get_resource_limit(flags::RLIMIT_NOFILE) {
+    Ok(limit) => limit.current,
+    Err(_) => 0,
+  }
+}
+
 pub fn sleep(ms: u32) {
   syscall_inner(0x05, ms, 0, 0);
 }
 
+/// Sleep until the system clock reaches `timestamp`, in seconds since
+/// midnight on 1 January 1980. A time in the past returns immediately.
+pub fn sleep_until(timestamp: u32) {
+  syscall_inner(0x80, timestamp, 0, 0);
+}
+
 pub fn exit(code: u32) -> ! {
   syscall_inner(0, code, 0, 0);
   unsafe { core::intrinsics::unreachable() }
@@ -124,13 +550,37 @@ pub fn get_pid() -> u32 {
 }
 
 pub fn wait_pid(id: u32) -> (u32, u32) {
+  wait_pid_with_options(id, 0)
+}
+
+/// Wait for a child to exit. With `flags::WUNTRACED` or `flags::WCONTINUED`,
+/// a child that stops or continues also ends the wait, with a status of
+/// `signals::STATUS_STOPPED` or `signals::STATUS_CONTINUED`. Each stop or
+/// continue is only reported once.
+pub fn wait_pid_with_options(id: u32, options: u32) -> (u32, u32) {
   let mut status = 0;
-  let pid = syscall_inner(0x09, id, &mut status as *mut u32 as u32, 0);
+  let pid = syscall_inner(0x09, id, &mut status as *mut u32 as u32, options);
   (pid, status)
 }
 
+/// Wait for a child selected by `id_type`: any child with `flags::P_ALL`, the
+/// child `id` with `flags::P_PID`, or any child in process group `id` with
+/// `flags::P_PGID` (0 meaning the caller's group). Accepts the same options as
+/// wait_pid_with_options, plus `flags::WNOWAIT` to report a child without
+/// collecting it. Unlike wait_pid, the result says what happened to the child
+/// instead of packing it into a status. Fails with `NoSuchEntity` once no
+/// running child could end the wait, such as when every child has been
+/// reaped because SIGCHLD is ignored.
+pub fn waitid(id_type: u32, id: u32, options: u32) -> Result<signals::WaitInfo, result::SystemError> {
+  let mut info = signals::WaitInfo::empty();
+  let packed = (id_type << 28) | (options & 0x0fffffff);
+  let code = syscall_inner(0x75, id, &mut info as *mut signals::WaitInfo as u32, packed);
+  result::result_from_code(code).map(|_| info)
+}
+
 /**
- * Send a signal to a specific thread, equivalent to POSIX `kill`
+ * Send a signal to a specific thread, equivalent to POSIX `kill`. A negative
+ * pid, like `(-group as i32) as u32`, signals every process in that group.
  */
 pub fn send_signal(pid: u32, signal: u32) {
   syscall_inner(0x8, pid, signal, 0);
@@ -140,6 +590,58 @@ pub fn send_signal(pid: u32, signal: u32) {
  * Send a signal to the current thread
  */
 pub fn raise(signal: u32) {
-  syscall_inner(0x7, signal, 0, 0);
+  syscall_inner(0x8, 0, signal, 0);
+}
+
+/**
+ * Run a function when a signal arrives, instead of the signal's default
+ * action. The handler receives the signal number. When it returns, the code
+ * that was interrupted continues with all of its registers intact. A blocked
+ * call that the signal interrupts fails with `SystemError::Interrupted`.
+ */
+pub fn signal_handler(signal: u32, handler: extern "C" fn(u32)) -> u32 {
+  signal_handler_with_flags(signal, handler, 0)
+}
+
+/**
+ * Like signal_handler, but with `signals::SA_RESTART` in `flags`, a blocked
+ * call that the signal interrupts is restarted after the handler returns.
+ */
+pub fn signal_handler_with_flags(signal: u32, handler: extern "C" fn(u32), flags: u32) -> u32 {
+  syscall_inner(0x70, signal | flags, handler as u32, signal_return_trampoline as u32)
+}
+
+/**
+ * Restore the default action for a signal
+ */
+pub fn reset_signal_handler(signal: u32) -> u32 {
+  syscall_inner(0x70, signal, 0, 0)
+}
+
+/**
+ * Discard a signal whenever it arrives. Children of a process that ignores
+ * `signals::CHILD` are reaped as soon as they exit, and can't be waited on.
+ */
+pub fn ignore_signal(signal: u32) -> u32 {
+  syscall_inner(0x70, signal, signals::SIG_IGN, 0)
+}
+
+/// Move a process into a process group, for job control. A pid of 0 means the
+/// current process, and a group of 0 makes the process the leader of a new
+/// group with its own ID. Children start in their parent's group.
+pub fn setpgid(pid: u32, group: u32) -> u32 {
+  syscall_inner(0x72, pid, group, 0)
+}
+
+/// Get the process group of a process, or of the current one if pid is 0
+pub fn getpgid(pid: u32) -> u32 {
+  syscall_inner(0x73, pid, 0, 0)
+}
+
+/// Signal handlers return here. The sigreturn syscall restores the state saved
+/// when the signal arrived, so it only comes back if that state was corrupted.
+extern "C" fn signal_return_trampoline() -> ! {
+  syscall_inner(0x71, 0, 0, 0);
+  exit(signals::STATUS_SIGNALED | signals::SEGFAULT)
 }
 
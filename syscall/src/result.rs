@@ -28,6 +28,32 @@ pub enum SystemError {
   IOError = 10,
   /// The process cannot open any more file handles
   MaxFilesExceeded = 11,
+  /// The operation would block, and the handle is in non-blocking mode
+  WouldBlock = 12,
+  /// The resource is still in use, like a drive with open files
+  Busy = 13,
+  /// The calling process is not allowed to perform the operation
+  PermissionDenied = 14,
+  /// An argument was malformed, like a name containing illegal characters
+  InvalidArgument = 15,
+  /// There is not enough space left to store the data
+  NoSpace = 16,
+  /// The destination path already exists
+  AlreadyExists = 17,
+  /// The operation can't span two different drives
+  CrossDevice = 18,
+  /// The process on the other end of an IPC exchange exited before replying
+  RecipientGone = 19,
+  /// Something was nested too deeply, like interpreter scripts that name
+  /// other scripts as their interpreter
+  TooManyLevels = 20,
+  /// Waiting for a lock would never finish, because its holder is waiting on
+  /// a lock held by the caller
+  Deadlock = 21,
+  /// A signal arrived while the call was blocked, and its handler ran instead
+  Interrupted = 22,
+  /// The drive was mounted read-only, or its media is write-protected
+  ReadOnlyFileSystem = 23,
 }
 
 impl SystemError {
@@ -45,6 +71,18 @@ impl SystemError {
       9 => SystemError::UnsupportedCommand,
       10 => SystemError::IOError,
       11 => SystemError::MaxFilesExceeded,
+      12 => SystemError::WouldBlock,
+      13 => SystemError::Busy,
+      14 => SystemError::PermissionDenied,
+      15 => SystemError::InvalidArgument,
+      16 => SystemError::NoSpace,
+      17 => SystemError::AlreadyExists,
+      18 => SystemError::CrossDevice,
+      19 => SystemError::RecipientGone,
+      20 => SystemError::TooManyLevels,
+      21 => SystemError::Deadlock,
+      22 => SystemError::Interrupted,
+      23 => SystemError::ReadOnlyFileSystem,
 
       _ => SystemError::Unknown,
     }
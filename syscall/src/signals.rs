@@ -14,4 +14,73 @@ pub const TERM: u32 = 15;
 pub const CHILD: u32 = 17;
 pub const CONTINUE: u32 = 18;
 pub const STOP: u32 = 19;
-pub const TSTOP: u32 = 20;
\ No newline at end of file
+pub const TSTOP: u32 = 20;
+
+/// Combined with the signal number when installing a handler. A blocked call
+/// that the signal interrupts is started again once the handler returns,
+/// instead of failing with `SystemError::Interrupted`. Sleeps are never
+/// restarted.
+pub const SA_RESTART: u32 = 0x10000000;
+
+/// Installed in place of a handler, to discard a signal instead of running its
+/// default action. KILL and STOP can't be ignored. Ignoring CHILD also means
+/// children are reaped as soon as they exit, without waiting for a wait.
+pub const SIG_IGN: u32 = 1;
+
+/// Exit codes, and the signal numbers in a status, are kept to these bits so
+/// that they never overlap the STATUS_* flags
+pub const EXIT_CODE_MASK: u32 = 0xffff;
+
+/// The status reported by wait_pid for a process that was terminated by a
+/// signal has this bit set, with the signal number in the lower bits
+pub const STATUS_SIGNALED: u32 = 0x10000;
+/// Reported by wait_pid with WUNTRACED for a child that was stopped, with the
+/// stopping signal in the lower bits
+pub const STATUS_STOPPED: u32 = 0x20000;
+/// Reported by wait_pid with WCONTINUED for a stopped child that was continued
+pub const STATUS_CONTINUED: u32 = 0x40000;
+
+/// WaitInfo codes, describing what happened to the child
+pub const CLD_EXITED: u32 = 1;
+pub const CLD_KILLED: u32 = 2;
+pub const CLD_STOPPED: u32 = 5;
+pub const CLD_CONTINUED: u32 = 6;
+
+/// Describes the child that ended a `waitid`
+#[repr(C, packed)]
+pub struct WaitInfo {
+  pub pid: u32,
+  /// One of the CLD_* codes
+  pub code: u32,
+  /// The exit code for CLD_EXITED, otherwise the signal involved
+  pub status: u32,
+}
+
+impl WaitInfo {
+  pub fn empty() -> Self {
+    Self {
+      pid: 0,
+      code: 0,
+      status: 0,
+    }
+  }
+
+  /// Decode a status in the form reported by wait_pid
+  pub fn from_status(pid: u32, status: u32) -> Self {
+    let signal = status & EXIT_CODE_MASK;
+    let (code, status) = if status & STATUS_SIGNALED != 0 {
+      (CLD_KILLED, signal)
+    } else if status & STATUS_STOPPED != 0 {
+      (CLD_STOPPED, signal)
+    } else if status & STATUS_CONTINUED != 0 {
+      (CLD_CONTINUED, CONTINUE)
+    } else {
+      (CLD_EXITED, status)
+    };
+    Self {
+      pid,
+      code,
+      status,
+    }
+  }
+}
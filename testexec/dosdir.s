@@ -0,0 +1,86 @@
+.intel_syntax noprefix
+.code16
+.global start
+
+# Exercise the DOS directory calls on drive A: create a directory, change into
+# it, print the current directory, then clean up.
+
+start:
+  # select A: as the default drive
+  mov dl, 0
+  mov ah, 0x0e
+  int 0x21
+
+  mov dx, offset dir_name
+  mov ah, 0x39
+  int 0x21
+  jc failed_mkdir
+
+  mov dx, offset dir_name
+  mov ah, 0x3b
+  int 0x21
+  jc failed_chdir
+
+  # fetch the current directory of the default drive
+  mov dl, 0
+  mov si, offset cwd_buffer
+  mov ah, 0x47
+  int 0x21
+  jc failed_chdir
+
+  # replace the null terminator with '$' so it can be printed
+  mov bx, offset cwd_buffer
+find_end:
+  cmp byte ptr [bx], 0
+  je found_end
+  inc bx
+  jmp find_end
+found_end:
+  mov byte ptr [bx], '$'
+
+  mov dx, offset msg_cwd
+  mov ah, 0x09
+  int 0x21
+  mov dx, offset cwd_buffer
+  int 0x21
+  mov dx, offset msg_newline
+  int 0x21
+
+  # go back to the root and remove the directory
+  mov dx, offset root_name
+  mov ah, 0x3b
+  int 0x21
+  mov dx, offset dir_name
+  mov ah, 0x3a
+  int 0x21
+  jc failed_rmdir
+
+  mov dx, offset msg_ok
+  jmp done
+
+failed_mkdir:
+  mov dx, offset msg_mkdir
+  jmp done
+failed_chdir:
+  mov dx, offset msg_chdir
+  jmp done
+failed_rmdir:
+  mov dx, offset msg_rmdir
+
+done:
+  mov ah, 0x09
+  int 0x21
+  mov ah, 0x00
+  int 0x21
+
+  jmp $ # unreachable
+
+dir_name: .asciz "TESTDIR"
+root_name: .asciz "\\"
+msg_cwd: .ascii "Current directory: A:\\$"
+msg_newline: .ascii "\n$"
+msg_ok: .ascii "Directory test passed\n$"
+msg_mkdir: .ascii "Failed to create directory\n$"
+msg_chdir: .ascii "Failed to change directory\n$"
+msg_rmdir: .ascii "Failed to remove directory\n$"
+cwd_buffer: .space 64